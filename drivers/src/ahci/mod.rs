@@ -0,0 +1,122 @@
+//! AHCI (Advanced Host Controller Interface) SATA driver.
+//!
+//! Each implemented port with a SATA disk attached is exposed as a block
+//! device. Commands are queued with NCQ (`READ/WRITE FPDMA QUEUED`) if both
+//! the HBA and the disk support it, or with `READ/WRITE DMA EXT` otherwise,
+//! and are completed in the interrupt handler.
+
+mod port;
+
+pub use port::AhciPort;
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0c;
+const HBA_VS: usize = 0x10;
+const HBA_PORT_BASE: usize = 0x100;
+const HBA_PORT_SIZE: usize = 0x80;
+
+const CAP_S64A: u32 = 1 << 31;
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_AE: u32 = 1 << 31;
+const GHC_IE: u32 = 1 << 1;
+const GHC_HR: u32 = 1 << 0;
+
+/// Size of the register space of an HBA with 32 ports.
+pub const AHCI_BAR_SIZE: usize = HBA_PORT_BASE + HBA_PORT_SIZE * 32;
+
+const PAGE_SIZE: usize = 4096;
+/// Iterations to poll a register before giving up.
+const SPIN_TIMEOUT: usize = 1_000_000;
+
+extern "C" {
+    fn drivers_dma_alloc(pages: usize) -> PhysAddr;
+}
+
+/// Allocate zeroed DMA memory of `pages` pages.
+fn alloc_dma(pages: usize) -> (VirtAddr, PhysAddr) {
+    let paddr = unsafe { drivers_dma_alloc(pages) };
+    let vaddr = crate::bus::phys_to_virt(paddr);
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, pages * PAGE_SIZE) };
+    (vaddr, paddr)
+}
+
+/// Poll until `cond` holds, returns `false` on timeout.
+fn spin_wait(mut cond: impl FnMut() -> bool) -> bool {
+    for _ in 0..SPIN_TIMEOUT {
+        if cond() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Global registers of a host bus adapter.
+#[derive(Debug, Clone, Copy)]
+struct Hba {
+    base: VirtAddr,
+}
+
+impl Hba {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn port_base(&self, port: usize) -> VirtAddr {
+        self.base + HBA_PORT_BASE + port * HBA_PORT_SIZE
+    }
+}
+
+/// Reset the HBA whose registers (ABAR) are mapped at `base`, and create a
+/// block device for each port with a SATA disk attached.
+///
+/// All ports share the interrupt `irq`, and each of them handles its own
+/// bit in the interrupt status register.
+pub fn init(base: VirtAddr, irq: usize) -> DeviceResult<Vec<AhciPort>> {
+    let hba = Hba { base };
+    hba.write(HBA_GHC, GHC_AE);
+    hba.write(HBA_GHC, GHC_AE | GHC_HR);
+    if !spin_wait(|| hba.read(HBA_GHC) & GHC_HR == 0) {
+        warn!("ahci: HBA reset timed out");
+        return Err(DeviceError::NotReady);
+    }
+    hba.write(HBA_GHC, GHC_AE);
+
+    let cap = hba.read(HBA_CAP);
+    let ncq = cap & CAP_SNCQ != 0;
+    let slots = ((cap >> 8) & 0x1f) as usize + 1;
+    let ports_impl = hba.read(HBA_PI);
+    let vs = hba.read(HBA_VS);
+    info!(
+        "ahci: version {:x}.{:x}, {} ports {:#x}, {} command slots, NCQ: {}",
+        vs >> 16,
+        vs & 0xffff,
+        (cap & 0x1f) + 1,
+        ports_impl,
+        slots,
+        ncq
+    );
+
+    let mut disks = Vec::new();
+    for port in (0..32).filter(|p| ports_impl & (1 << p) != 0) {
+        match AhciPort::new(hba, port, irq, ncq, slots) {
+            Ok(Some(disk)) => disks.push(disk),
+            Ok(None) => {}
+            Err(e) => warn!("ahci: failed to initialize port {}: {:?}", port, e),
+        }
+    }
+    hba.write(HBA_IS, !0);
+    hba.write(HBA_GHC, GHC_AE | GHC_IE);
+    Ok(disks)
+}
@@ -0,0 +1,517 @@
+//! A SATA disk attached to a port of the HBA.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::{vec, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use lock::Mutex;
+
+use super::{alloc_dma, spin_wait, Hba, CAP_S64A, HBA_CAP, HBA_IS, PAGE_SIZE};
+use crate::scheme::{BlockFuture, BlockOp, BlockRequest, BlockScheme, Scheme};
+use crate::utils::{BlockRequestQueue, MergedRequest};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0c;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_SUD: u32 = 1 << 1;
+const CMD_POD: u32 = 1 << 2;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const TFD_ERR: u32 = 1 << 0;
+const TFD_DRQ: u32 = 1 << 3;
+const TFD_BSY: u32 = 1 << 7;
+
+/// Device to host register FIS, set device bits FIS (NCQ completion) and
+/// descriptor processed interrupts.
+const IS_COMPLETIONS: u32 = 1 << 0 | 1 << 3 | 1 << 5;
+/// Task file error, host bus fatal error, host bus data error and interface
+/// fatal error interrupts.
+const IS_ERRORS: u32 = 1 << 30 | 1 << 29 | 1 << 28 | 1 << 27;
+
+const SSTS_DET_PRESENT: u32 = 3;
+const SIG_SATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// Length of a register FIS in dwords.
+const FIS_REG_H2D_LEN: u16 = 5;
+const ATA_IDENTIFY: u8 = 0xec;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xea;
+const ATA_DEVICE_LBA: u8 = 1 << 6;
+
+const BLOCK_SIZE: usize = 512;
+/// Maximum transfer size of a command, which fits in one PRD entry.
+const MAX_TRANSFER: usize = PAGE_SIZE * 8;
+/// Offset of the received FIS area in the first page, after the command list
+/// of 32 command headers.
+const FIS_AREA_OFFSET: usize = 32 * 32;
+/// Size of a command table with one PRD entry, 128-byte aligned.
+const CMD_TABLE_SIZE: usize = 0x100;
+
+#[repr(C)]
+struct CmdHeader {
+    /// Command FIS length, and the write bit.
+    flags: u16,
+    /// Number of PRD entries.
+    prdtl: u16,
+    /// PRD byte count transferred.
+    prdbc: u32,
+    /// Physical address of the command table.
+    ctba: u64,
+    _rsvd: [u32; 4],
+}
+
+#[repr(C)]
+struct Prd {
+    dba: u64,
+    _rsvd: u32,
+    /// Byte count minus one, and the interrupt on completion bit.
+    dbc: u32,
+}
+
+#[repr(C)]
+struct CmdTable {
+    cfis: [u8; 64],
+    acmd: [u8; 16],
+    _rsvd: [u8; 48],
+    prdt: [Prd; 1],
+}
+
+/// Build a host to device register FIS of an LBA48 command.
+fn reg_h2d_fis(command: u8, lba: u64, count: u16, features: u16, device: u8) -> [u8; 64] {
+    let mut fis = [0; 64];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = 1 << 7; // command, not control
+    fis[2] = command;
+    fis[3] = features as u8;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = device;
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    fis[11] = (features >> 8) as u8;
+    fis[12] = count as u8;
+    fis[13] = (count >> 8) as u8;
+    fis
+}
+
+/// State of the commands issued to the port.
+struct IoState {
+    /// command slot -> request
+    in_flight: BTreeMap<usize, MergedRequest>,
+    /// free command slots, each slot owns a DMA buffer of `MAX_TRANSFER` bytes
+    free_slots: Vec<usize>,
+}
+
+pub struct AhciPort {
+    hba: Hba,
+    port: usize,
+    regs: VirtAddr,
+    irq: usize,
+    /// Whether commands are queued with NCQ.
+    ncq: bool,
+    /// Number of blocks of the disk.
+    capacity: usize,
+    /// The command list and the received FIS area.
+    cmd_list: VirtAddr,
+    cmd_tables: VirtAddr,
+    cmd_tables_pa: PhysAddr,
+    /// DMA buffers of all command slots
+    dma_va: VirtAddr,
+    dma_pa: PhysAddr,
+    /// pending requests, not yet issued to the device
+    queue: Mutex<BlockRequestQueue>,
+    io_state: Mutex<IoState>,
+}
+
+impl AhciPort {
+    /// Start the port `port` of the HBA, and identify the attached disk.
+    ///
+    /// Returns `None` if there is no SATA disk on the port.
+    pub(super) fn new(
+        hba: Hba,
+        port: usize,
+        irq: usize,
+        hba_ncq: bool,
+        hba_slots: usize,
+    ) -> DeviceResult<Option<Self>> {
+        let regs = hba.port_base(port);
+        let read = |reg: usize| unsafe { read_volatile((regs + reg) as *const u32) };
+        if read(PX_SSTS) & 0xf != SSTS_DET_PRESENT || read(PX_SIG) != SIG_SATA {
+            return Ok(None);
+        }
+
+        let (cmd_list, cmd_list_pa) = alloc_dma(1);
+        let (cmd_tables, cmd_tables_pa) = alloc_dma(32 * CMD_TABLE_SIZE / PAGE_SIZE);
+        let (dma_va, dma_pa) = alloc_dma(hba_slots * MAX_TRANSFER / PAGE_SIZE);
+        let high = (cmd_list_pa | cmd_tables_pa | dma_pa) as u64 >> 32;
+        if high != 0 && hba.read(HBA_CAP) & CAP_S64A == 0 {
+            warn!("ahci: 64-bit DMA is not supported by the HBA");
+            return Err(DeviceError::DmaError);
+        }
+        let mut disk = Self {
+            hba,
+            port,
+            regs,
+            irq,
+            ncq: false,
+            capacity: 0,
+            cmd_list,
+            cmd_tables,
+            cmd_tables_pa,
+            dma_va,
+            dma_pa,
+            queue: Mutex::new(BlockRequestQueue::new(
+                BLOCK_SIZE,
+                MAX_TRANSFER / BLOCK_SIZE,
+            )),
+            io_state: Mutex::new(IoState {
+                in_flight: BTreeMap::new(),
+                free_slots: Vec::new(),
+            }),
+        };
+
+        disk.stop()?;
+        let fis_pa = (cmd_list_pa + FIS_AREA_OFFSET) as u64;
+        disk.write(PX_CLB, cmd_list_pa as u32);
+        disk.write(PX_CLBU, (cmd_list_pa as u64 >> 32) as u32);
+        disk.write(PX_FB, fis_pa as u32);
+        disk.write(PX_FBU, (fis_pa >> 32) as u32);
+        disk.write(PX_CMD, disk.read(PX_CMD) | CMD_SUD | CMD_POD);
+        disk.start()?;
+
+        let id = disk.identify()?;
+        let word = |i: usize| u16::from_le_bytes([id[i * 2], id[i * 2 + 1]]);
+        disk.capacity = if word(83) & (1 << 10) != 0 {
+            (0..4).map(|i| (word(100 + i) as usize) << (i * 16)).sum()
+        } else {
+            word(60) as usize | (word(61) as usize) << 16
+        };
+        // the model number is in words 27..47, with bytes swapped in each word
+        let model: String = (27..47)
+            .flat_map(|i| word(i).to_be_bytes())
+            .map(|b| b as char)
+            .collect();
+        disk.ncq = hba_ncq && word(76) & (1 << 8) != 0;
+        let slots = if disk.ncq {
+            hba_slots.min((word(75) & 0x1f) as usize + 1)
+        } else {
+            hba_slots
+        };
+        disk.io_state.lock().free_slots = (0..slots).rev().collect();
+        info!(
+            "ahci: port {}: {}, {} blocks, {} slots, NCQ: {}",
+            port,
+            model.trim(),
+            disk.capacity,
+            slots,
+            disk.ncq
+        );
+
+        disk.write(PX_IS, !0);
+        disk.write(PX_IE, IS_COMPLETIONS | IS_ERRORS);
+        Ok(Some(disk))
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.regs + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.regs + reg) as *mut u32, value) }
+    }
+
+    /// Stop processing the command list and receiving FISes.
+    fn stop(&self) -> DeviceResult {
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_ST);
+        if !spin_wait(|| self.read(PX_CMD) & CMD_CR == 0) {
+            return Err(DeviceError::NotReady);
+        }
+        self.write(PX_CMD, self.read(PX_CMD) & !CMD_FRE);
+        if !spin_wait(|| self.read(PX_CMD) & CMD_FR == 0) {
+            return Err(DeviceError::NotReady);
+        }
+        Ok(())
+    }
+
+    /// Clear the errors, and start processing the command list once the
+    /// device is idle.
+    fn start(&self) -> DeviceResult {
+        self.write(PX_SERR, !0);
+        self.write(PX_IS, !0);
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_FRE);
+        if !spin_wait(|| self.read(PX_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+            warn!("ahci: port {}: device is busy", self.port);
+            return Err(DeviceError::NotReady);
+        }
+        self.write(PX_CMD, self.read(PX_CMD) | CMD_ST);
+        Ok(())
+    }
+
+    fn slot_buffer(&self, slot: usize) -> (VirtAddr, PhysAddr) {
+        let offset = slot * MAX_TRANSFER;
+        (self.dma_va + offset, self.dma_pa + offset)
+    }
+
+    /// Fill the command header and the command table of `slot`, with the
+    /// slot's DMA buffer as the data of `len` bytes.
+    fn build_command(&self, slot: usize, fis: [u8; 64], len: usize, write: bool) {
+        let table_pa = self.cmd_tables_pa + slot * CMD_TABLE_SIZE;
+        let table = (self.cmd_tables + slot * CMD_TABLE_SIZE) as *mut CmdTable;
+        let header = (self.cmd_list + slot * core::mem::size_of::<CmdHeader>()) as *mut CmdHeader;
+        let (_, buf_pa) = self.slot_buffer(slot);
+        unsafe {
+            write_volatile(
+                table,
+                CmdTable {
+                    cfis: fis,
+                    acmd: [0; 16],
+                    _rsvd: [0; 48],
+                    prdt: [Prd {
+                        dba: buf_pa as u64,
+                        _rsvd: 0,
+                        dbc: (len.max(1) - 1) as u32,
+                    }],
+                },
+            );
+            write_volatile(
+                header,
+                CmdHeader {
+                    flags: FIS_REG_H2D_LEN | (write as u16) << 6,
+                    prdtl: (len > 0) as u16,
+                    prdbc: 0,
+                    ctba: table_pa as u64,
+                    _rsvd: [0; 4],
+                },
+            );
+        }
+    }
+
+    /// Issue a non-queued command, and poll until it completes.
+    ///
+    /// No other commands may be outstanding.
+    fn exec_polling(&self, slot: usize, fis: [u8; 64], len: usize) -> DeviceResult {
+        self.build_command(slot, fis, len, false);
+        self.write(PX_CI, 1 << slot);
+        let done =
+            spin_wait(|| self.read(PX_CI) & (1 << slot) == 0 || self.read(PX_IS) & IS_ERRORS != 0);
+        let is = self.read(PX_IS);
+        self.write(PX_IS, is);
+        if !done || is & IS_ERRORS != 0 || self.read(PX_TFD) & TFD_ERR != 0 {
+            warn!(
+                "ahci: port {}: command {:#x} failed, IS={:#x}, TFD={:#x}",
+                self.port,
+                fis[2],
+                is,
+                self.read(PX_TFD)
+            );
+            self.restart();
+            return Err(DeviceError::IoError);
+        }
+        Ok(())
+    }
+
+    /// Read the 512-byte IDENTIFY DEVICE data.
+    fn identify(&self) -> DeviceResult<Vec<u8>> {
+        self.exec_polling(0, reg_h2d_fis(ATA_IDENTIFY, 0, 0, 0, 0), BLOCK_SIZE)?;
+        let (va, _) = self.slot_buffer(0);
+        Ok(unsafe { core::slice::from_raw_parts(va as *const u8, BLOCK_SIZE) }.to_vec())
+    }
+
+    /// Recover from errors by restarting the port.
+    fn restart(&self) {
+        if let Err(e) = self.stop().and_then(|_| self.start()) {
+            warn!("ahci: port {}: failed to restart: {:?}", self.port, e);
+        }
+    }
+
+    /// Issue pending requests to the port, as long as there are free slots.
+    fn dispatch(&self) {
+        let mut state = self.io_state.lock();
+        let mut queue = self.queue.lock();
+        while let Some(slot) = state.free_slots.pop() {
+            let req = match queue.pop() {
+                Some(req) => req,
+                None => {
+                    state.free_slots.push(slot);
+                    break;
+                }
+            };
+            let len = req.len();
+            let lba = req.block_id as u64;
+            let nblocks = req.nblocks as u16;
+            let write = req.op == BlockOp::Write;
+            if write {
+                let (va, _) = self.slot_buffer(slot);
+                req.gather(unsafe { core::slice::from_raw_parts_mut(va as *mut u8, len) });
+            }
+            let fis = if self.ncq {
+                // the tag is in the count field, and the block count is in
+                // the features field
+                let command = if write {
+                    ATA_WRITE_FPDMA_QUEUED
+                } else {
+                    ATA_READ_FPDMA_QUEUED
+                };
+                reg_h2d_fis(command, lba, (slot << 3) as u16, nblocks, ATA_DEVICE_LBA)
+            } else {
+                let command = if write {
+                    ATA_WRITE_DMA_EXT
+                } else {
+                    ATA_READ_DMA_EXT
+                };
+                reg_h2d_fis(command, lba, nblocks, 0, ATA_DEVICE_LBA)
+            };
+            self.build_command(slot, fis, len, write);
+            if self.ncq {
+                self.write(PX_SACT, 1 << slot);
+            }
+            self.write(PX_CI, 1 << slot);
+            state.in_flight.insert(slot, req);
+        }
+    }
+
+    /// Complete the requests whose command slots have been cleared by the
+    /// HBA, or fail all outstanding requests on errors.
+    fn process_completions(&self) {
+        let mut finished = Vec::new();
+        {
+            let mut state = self.io_state.lock();
+            let is = self.read(PX_IS);
+            self.write(PX_IS, is);
+            let failed = is & IS_ERRORS != 0;
+            if failed {
+                warn!(
+                    "ahci: port {}: I/O error, IS={:#x}, TFD={:#x}, SERR={:#x}",
+                    self.port,
+                    is,
+                    self.read(PX_TFD),
+                    self.read(PX_SERR)
+                );
+                self.restart();
+            }
+            let busy = if failed {
+                0
+            } else {
+                self.read(PX_CI) | self.read(PX_SACT)
+            };
+            let done: Vec<usize> = state
+                .in_flight
+                .keys()
+                .copied()
+                .filter(|slot| busy & (1 << slot) == 0)
+                .collect();
+            for slot in done {
+                let mut req = state.in_flight.remove(&slot).unwrap();
+                state.free_slots.push(slot);
+                let result = if failed {
+                    Err(DeviceError::IoError)
+                } else {
+                    Ok(())
+                };
+                if result.is_ok() && req.op == BlockOp::Read {
+                    let (va, _) = self.slot_buffer(slot);
+                    let buf = unsafe { core::slice::from_raw_parts(va as *const u8, req.len()) };
+                    req.scatter(buf);
+                }
+                finished.push((req, result));
+            }
+        }
+        for (req, result) in finished {
+            req.complete(result);
+        }
+        self.dispatch();
+    }
+
+    /// Do a block request synchronously.
+    fn block_io(&self, req: BlockRequest) -> DeviceResult<BlockRequest> {
+        let (req, res) = self.submit(req).wait_with(|| self.process_completions());
+        res.map(|_| req)
+    }
+
+    /// Number of blocks of the disk.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get_name_irq(&self) -> (String, usize) {
+        (alloc::format!("ahci{}", self.port), self.irq)
+    }
+}
+
+impl Scheme for AhciPort {
+    fn name(&self) -> &str {
+        "ahci"
+    }
+
+    fn handle_irq(&self, _irq: usize) {
+        if self.hba.read(HBA_IS) & (1 << self.port) == 0 {
+            return;
+        }
+        self.process_completions();
+        self.hba.write(HBA_IS, 1 << self.port);
+    }
+}
+
+impl BlockScheme for AhciPort {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
+        let req = self.block_io(BlockRequest::read(block_id, vec![vec![0; buf.len()]]))?;
+        buf.copy_from_slice(&req.bufs[0]);
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
+        self.block_io(BlockRequest::write(block_id, vec![buf.to_vec()]))?;
+        Ok(())
+    }
+
+    /// Wait for outstanding commands, then issue FLUSH CACHE EXT, which can't
+    /// be mixed with queued commands.
+    fn flush(&self) -> DeviceResult {
+        loop {
+            self.process_completions();
+            let state = self.io_state.lock();
+            if state.in_flight.is_empty() {
+                let slot = *state.free_slots.last().unwrap();
+                let fis = reg_h2d_fis(ATA_FLUSH_CACHE_EXT, 0, 0, 0, ATA_DEVICE_LBA);
+                return self.exec_polling(slot, fis, 0);
+            }
+            drop(state);
+            core::hint::spin_loop();
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn submit(&self, req: BlockRequest) -> BlockFuture {
+        let future = self.queue.lock().push(req);
+        self.dispatch();
+        future
+    }
+    fn poll_completions(&self) {
+        self.process_completions();
+    }
+}
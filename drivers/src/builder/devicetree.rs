@@ -24,22 +24,46 @@ use crate::{
     },
     Device, DeviceError, DeviceResult, PhysAddr, VirtAddr,
 };
-use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
 
 const MODULE: &str = "device-tree";
 
 type DevWithInterrupt = (Device, InterruptsProp);
 
+/// 将中断说明符转换为中断控制器的中断号
+type TranslateFn = fn(&[u32]) -> Option<usize>;
+
 /// 设备树中中断控制器特有的属性
 struct IntcProps {
     phandle: u32,
     interrupt_cells: u32,
+    translate: TranslateFn,
 }
 
 /// 查找表保存的中断控制器信息
 struct Intc {
     index: usize,
     cells: usize,
+    translate: TranslateFn,
+}
+
+/// The interrupt specifier of most controllers starts with the IRQ number.
+fn first_cell(specifier: &[u32]) -> Option<usize> {
+    specifier
+        .first()
+        .filter(|&&irq_num| irq_num != 0xffff_ffff)
+        .map(|&irq_num| irq_num as usize)
+}
+
+/// The interrupt specifier of ARM GIC is the type (0 for SPI, 1 for PPI), the
+/// number in its type and the flags.
+#[cfg(target_arch = "aarch64")]
+fn gic_irq_num(specifier: &[u32]) -> Option<usize> {
+    match specifier {
+        [0, num, ..] => Some(*num as usize + 32),
+        [1, num, ..] => Some(*num as usize + 16),
+        _ => None,
+    }
 }
 
 /// A builder to probe devices and create drivers from device tree.
@@ -118,45 +142,72 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
                         Intc {
                             index: dev_list.len(),
                             cells: intc.interrupt_cells as _,
+                            translate: intc.translate,
                         },
                     );
-                    dev
+                    vec![dev]
                 })
             } else {
                 // parse other device
                 match comp {
+                    // a virtio device may provide more than one device, e.g.
+                    // ports of a console
                     #[cfg(feature = "virtio")]
                     c if c.contains("virtio,mmio") => self.parse_virtio(node, props),
                     #[cfg(not(feature = "loopback"))]
                     c if c.contains("allwinner,sunxi-gmac") => {
-                        self.parse_ethernet(node, comp, props)
+                        self.parse_ethernet(node, comp, props).map(|dev| vec![dev])
+                    }
+                    c if c.contains("ns16550a")
+                        || c.contains("arm,pl011")
+                        || c.iter().any(|str| str.ends_with("uart")) =>
+                    {
+                        self.parse_uart(node, comp, props).map(|dev| vec![dev])
+                    }
+                    c if c.contains("arm,pl031") || c.contains("google,goldfish-rtc") => {
+                        self.parse_rtc(node, comp, props).map(|dev| vec![dev])
                     }
-                    c if c.contains("ns16550a") || c.iter().any(|str| str.ends_with("uart")) => {
-                        self.parse_uart(node, comp, props)
+                    #[cfg(feature = "allwinner")]
+                    c if c.contains("allwinner,sun20i-d1-rtc") => {
+                        self.parse_rtc(node, comp, props).map(|dev| vec![dev])
                     }
                     _ => Err(DeviceError::NotSupported),
                 }
             };
             match res {
-                Ok(dev) => dev_list.push(dev),
+                Ok(devs) => dev_list.extend(devs),
                 Err(DeviceError::NotSupported) => {}
                 Err(err) => warn!("{MODULE}: failed to parsing node {:?}: {err:?}", node.name),
             }
         });
 
         // 注册中断
-        for (device, interrupts_extended) in &dev_list {
+        for (dev_index, (device, interrupts_extended)) in dev_list.iter().enumerate() {
             let mut extended = interrupts_extended.as_slice();
             // 分解 interrupts_extended
-            while let [phandle, irq_num, ..] = extended {
-                if let Some(Intc { index, cells }) = intc_map.get(phandle) {
+            while let [phandle, specifier @ ..] = extended {
+                if let Some(Intc {
+                    index,
+                    cells,
+                    translate,
+                }) = intc_map.get(phandle)
+                {
                     let (intc, _) = &dev_list[*index];
-                    extended = &extended[1 + cells..];
+                    if specifier.len() < *cells {
+                        warn!("{MODULE}: too few cells in interrupts of {device:?}");
+                        return Err(DeviceError::InvalidParam);
+                    }
+                    let (specifier, rest) = specifier.split_at(*cells);
+                    extended = rest;
+                    // 中断控制器自身的中断（如 GIC 的维护中断）不注册到自己
+                    if *index == dev_index {
+                        continue;
+                    }
                     if let Device::Irq(irq) = intc {
-                        if *irq_num != 0xffff_ffff {
+                        if let Some(irq_num) = translate(specifier) {
                             info!("{MODULE}: register interrupts for {intc:?}: {device:?}, irq_num={irq_num}");
-                            if irq.register_device(*irq_num as _, device.inner()).is_ok() {
-                                irq.unmask(*irq_num as _)?;
+                            if irq.register_device(irq_num, device.inner()).is_ok() {
+                                irq.unmask(irq_num)?;
                             }
                         }
                     } else {
@@ -187,6 +238,7 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
 #[allow(unused_imports)]
 #[allow(unused_variables)]
 #[allow(unreachable_code)]
+#[allow(unused_mut)]
 impl<M: IoMapper> DevicetreeDriverBuilder<M> {
     /// Parse nodes for interrupt controllers.
     fn parse_intc(
@@ -204,7 +256,9 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
         let interrupts_extended = parse_interrupts(node, props)?;
         let base_vaddr =
             parse_reg(node, props).and_then(|(paddr, size)| self.mmap(paddr as _, size as _));
+        let mut translate: TranslateFn = first_cell;
         use crate::irq::*;
+        use crate::utils::devicetree::parse_reg_at;
         let dev = Device::Irq(match comp {
             #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
             c if c.contains("riscv,cpu-intc") => Arc::new(riscv::Intc::new()),
@@ -212,6 +266,41 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
             c if c.contains("riscv,plic0") => Arc::new(riscv::Plic::new(base_vaddr?)),
             #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))]
             c if c.contains("sifive,fu540-c000-plic") => Arc::new(riscv::Plic::new(base_vaddr?)),
+            #[cfg(target_arch = "aarch64")]
+            c if c.contains("arm,gic-v3") => {
+                translate = gic_irq_num;
+                // the redistributors of all CPUs
+                let (gicr_paddr, gicr_size) = parse_reg_at(node, props, 1)?;
+                let gicr_base = self.mmap(gicr_paddr as _, gicr_size as _)?;
+                // the ITS is a child node, for MSIs
+                let child_props = InheritProps {
+                    parent_address_cells: node.prop_u32("#address-cells").unwrap_or(0),
+                    parent_size_cells: node.prop_u32("#size-cells").unwrap_or(0),
+                    ..*props
+                };
+                let its = node
+                    .children
+                    .iter()
+                    .find(|child| {
+                        child
+                            .prop_str_list("compatible")
+                            .map_or(false, |comp| comp.contains("arm,gic-v3-its"))
+                    })
+                    .and_then(|its| parse_reg(its, &child_props).ok())
+                    .and_then(|(paddr, size)| {
+                        let vaddr = self.mmap(paddr as _, size as _).ok()?;
+                        Some((vaddr, paddr as _))
+                    });
+                Arc::new(gic_v3::init(base_vaddr?, gicr_base, gicr_size as _, its)?)
+            }
+            #[cfg(target_arch = "aarch64")]
+            c if c.contains("arm,gic-400") || c.contains("arm,cortex-a15-gic") => {
+                translate = gic_irq_num;
+                // the CPU interface
+                let gicc_base = parse_reg_at(node, props, 1)
+                    .and_then(|(paddr, size)| self.mmap(paddr as _, size as _))?;
+                Arc::new(gic_400::init(gicc_base, base_vaddr?))
+            }
             _ => return Err(DeviceError::NotSupported),
         });
 
@@ -220,13 +309,21 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
             IntcProps {
                 phandle,
                 interrupt_cells,
+                translate,
             },
         ))
     }
 
     /// Parse nodes for virtio devices over MMIO.
+    ///
+    /// Ports of a console are all returned, with interrupts registered for the
+    /// first one only.
     #[cfg(feature = "virtio")]
-    fn parse_virtio(&self, node: &Node, props: &InheritProps) -> DeviceResult<DevWithInterrupt> {
+    fn parse_virtio(
+        &self,
+        node: &Node,
+        props: &InheritProps,
+    ) -> DeviceResult<Vec<DevWithInterrupt>> {
         use crate::virtio::*;
         use virtio_drivers::{DeviceType, VirtIOHeader};
 
@@ -244,14 +341,39 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
         );
 
         let dev = match header.device_type() {
-            DeviceType::Block => Device::Block(Arc::new(VirtIoBlk::new(header)?)),
-            DeviceType::GPU => Device::Display(Arc::new(VirtIoGpu::new(header)?)),
+            DeviceType::Block => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                Device::Block(Arc::new(VirtIoBlk::new(transport)?))
+            }
+            DeviceType::GPU => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                Device::Display(Arc::new(VirtIoGpu::new(transport)?))
+            }
             DeviceType::Input => Device::Input(Arc::new(VirtIoInput::new(header)?)),
-            DeviceType::Console => Device::Uart(Arc::new(VirtIoConsole::new(header)?)),
+            DeviceType::Console => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                let mut ports = VirtIoConsole::new(transport)?.into_iter().map(Arc::new);
+                let first = ports.next().ok_or(DeviceError::NotSupported)?;
+                let mut devs = vec![(Device::Uart(first), interrupts_extended)];
+                devs.extend(ports.map(|port| (Device::Uart(port), Vec::new())));
+                return Ok(devs);
+            }
+            DeviceType::Network => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                Device::Net(Arc::new(VirtIoNet::new(transport, None)?))
+            }
+            DeviceType::EntropySource => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                Device::Rng(Arc::new(VirtIoRng::new(transport)?))
+            }
+            DeviceType::Socket => {
+                let transport = unsafe { Transport::mmio(base_vaddr)? };
+                Device::Vsock(Arc::new(VirtIoVsock::new(transport)?))
+            }
             _ => return Err(DeviceError::NotSupported),
         };
 
-        Ok((dev, interrupts_extended))
+        Ok(vec![(dev, interrupts_extended)])
     }
 
     /// Parse nodes for Ethernet devices.
@@ -306,6 +428,31 @@ impl<M: IoMapper> DevicetreeDriverBuilder<M> {
             c if c.contains("sifive,fu740-c000-uart") => {
                 Arc::new(unsafe { UartU740Mmio::<u32>::new(base_vaddr) })
             }
+            #[cfg(target_arch = "aarch64")]
+            c if c.contains("arm,pl011") => Arc::new(Pl011Uart::new(base_vaddr)),
+            _ => return Err(DeviceError::NotSupported),
+        });
+
+        Ok((dev, interrupts_extended))
+    }
+
+    /// Parse nodes for real-time clocks.
+    fn parse_rtc(
+        &self,
+        node: &Node,
+        comp: &StringList,
+        props: &InheritProps,
+    ) -> DeviceResult<DevWithInterrupt> {
+        let interrupts_extended = parse_interrupts(node, props)?;
+        let base_vaddr =
+            parse_reg(node, props).and_then(|(paddr, size)| self.mmap(paddr as _, size as _))?;
+
+        use crate::rtc::*;
+        let dev = Device::Rtc(match comp {
+            c if c.contains("arm,pl031") => Arc::new(Pl031Rtc::new(base_vaddr)),
+            c if c.contains("google,goldfish-rtc") => Arc::new(GoldfishRtc::new(base_vaddr)),
+            #[cfg(feature = "allwinner")]
+            c if c.contains("allwinner,sun20i-d1-rtc") => Arc::new(SunxiRtc::new(base_vaddr)),
             _ => return Err(DeviceError::NotSupported),
         });
 
@@ -1,10 +1,15 @@
 use super::{phys_to_virt, PAGE_SIZE};
 use crate::builder::IoMapper;
+use crate::scheme::IrqScheme;
 use crate::{Device, DeviceError, DeviceResult};
-use alloc::{format, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, format, sync::Arc, vec, vec::Vec};
+#[cfg(target_arch = "x86_64")]
+use core::sync::atomic::{AtomicUsize, Ordering};
 use pci::*;
 
 const PCI_COMMAND: u16 = 0x04;
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
 const BAR0: u16 = 0x10;
 const PCI_CAP_PTR: u16 = 0x34;
 const _PCI_INTERRUPT_LINE: u16 = 0x3c;
@@ -12,45 +17,91 @@ const _PCI_INTERRUPT_PIN: u16 = 0x3d;
 
 const PCI_MSI_CTRL_CAP: u16 = 0x00;
 const PCI_MSI_ADDR: u16 = 0x04;
-const _PCI_MSI_UPPER_ADDR: u16 = 0x08;
+const PCI_MSI_UPPER_ADDR: u16 = 0x08;
 const PCI_MSI_DATA_32: u16 = 0x08;
 const PCI_MSI_DATA_64: u16 = 0x0C;
 
 // const PCI_COMMAND_INTX_DISABLE:u16 = 0x400;
 
 const PCI_CAP_ID_MSI: u8 = 0x05;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// virtio-pci capability types
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
 
 struct PortOpsImpl;
 
 #[cfg(target_arch = "x86_64")]
 use x86_64::instructions::port::Port;
 
+/// Physical address of the PCIe enhanced configuration space (ECAM) of bus 0,
+/// used instead of the I/O ports if not 0.
+#[cfg(target_arch = "x86_64")]
+static ECAM_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Access the configuration space through the ECAM at `paddr` found in the
+/// ACPI MCFG, which covers all buses of segment 0.
+#[cfg(target_arch = "x86_64")]
+pub fn set_ecam_base(paddr: usize) {
+    ECAM_BASE.store(paddr, Ordering::Relaxed);
+}
+
+/// Offsets are in the ECAM if it's set, or I/O ports.
 #[cfg(target_arch = "x86_64")]
 impl PortOps for PortOpsImpl {
     unsafe fn read8(&self, port: u16) -> u8 {
-        Port::new(port).read()
+        match ecam_vaddr() {
+            Some(base) => read(base + port as usize),
+            None => Port::new(port).read(),
+        }
     }
     unsafe fn read16(&self, port: u16) -> u16 {
-        Port::new(port).read()
+        match ecam_vaddr() {
+            Some(base) => read(base + port as usize),
+            None => Port::new(port).read(),
+        }
     }
     unsafe fn read32(&self, port: u32) -> u32 {
-        Port::new(port as u16).read()
+        match ecam_vaddr() {
+            Some(base) => read(base + port as usize),
+            None => Port::new(port as u16).read(),
+        }
     }
     unsafe fn write8(&self, port: u16, val: u8) {
-        Port::new(port).write(val);
+        match ecam_vaddr() {
+            Some(base) => write(base + port as usize, val),
+            None => Port::new(port).write(val),
+        }
     }
     unsafe fn write16(&self, port: u16, val: u16) {
-        Port::new(port).write(val);
+        match ecam_vaddr() {
+            Some(base) => write(base + port as usize, val),
+            None => Port::new(port).write(val),
+        }
     }
     unsafe fn write32(&self, port: u32, val: u32) {
-        Port::new(port as u16).write(val);
+        match ecam_vaddr() {
+            Some(base) => write(base + port as usize, val),
+            None => Port::new(port as u16).write(val),
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn ecam_vaddr() -> Option<usize> {
+    match ECAM_BASE.load(Ordering::Relaxed) {
+        0 => None,
+        paddr => Some(phys_to_virt(paddr)),
     }
 }
 
 #[cfg(target_arch = "x86_64")]
 const PCI_BASE: usize = 0; //Fix me
 
-#[cfg(any(target_arch = "mips", target_arch = "riscv64"))]
+#[cfg(any(target_arch = "mips", target_arch = "riscv64", target_arch = "x86_64"))]
 use super::{read, write};
 
 #[cfg(feature = "board_malta")]
@@ -62,10 +113,18 @@ const PCI_BASE: usize = 0x30000000;
 const E1000_BASE: usize = 0x40000000;
 // riscv64 Qemu
 
+/// The method to access the configuration space with [`PortOpsImpl`].
 #[cfg(target_arch = "x86_64")]
-const PCI_ACCESS: CSpaceAccessMethod = CSpaceAccessMethod::IO;
+fn pci_access() -> CSpaceAccessMethod {
+    match ecam_vaddr() {
+        Some(base) => CSpaceAccessMethod::MemoryMapped(base as *mut u8),
+        None => CSpaceAccessMethod::IO,
+    }
+}
 #[cfg(not(target_arch = "x86_64"))]
-const PCI_ACCESS: CSpaceAccessMethod = CSpaceAccessMethod::MemoryMapped(PCI_BASE as *mut u8);
+fn pci_access() -> CSpaceAccessMethod {
+    CSpaceAccessMethod::MemoryMapped(PCI_BASE as *mut u8)
+}
 
 #[cfg(any(target_arch = "mips", target_arch = "riscv64"))]
 impl PortOps for PortOpsImpl {
@@ -94,7 +153,7 @@ impl PortOps for PortOpsImpl {
 unsafe fn enable(loc: Location, paddr: u64) -> Option<usize> {
     let ops = &PortOpsImpl;
     //let am = CSpaceAccessMethod::IO;
-    let am = PCI_ACCESS;
+    let am = pci_access();
 
     if paddr != 0 {
         // reveal PCI regs by setting paddr
@@ -163,7 +222,130 @@ unsafe fn enable(loc: Location, paddr: u64) -> Option<usize> {
     assigned_irq
 }
 
-pub fn init_driver(dev: &PCIDevice, mapper: &Option<Arc<dyn IoMapper>>) -> DeviceResult<Device> {
+/// Enable memory space decoding and bus mastering (DMA) of the device.
+unsafe fn enable_bus_master(loc: Location) {
+    let ops = &PortOpsImpl;
+    let am = pci_access();
+    let cmd = am.read16(ops, loc, PCI_COMMAND) | PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER;
+    am.write16(ops, loc, PCI_COMMAND, cmd);
+}
+
+/// Route the MSI of the function at `loc` to the handlers of `devs`, with a
+/// vector allocated by the interrupt controller `irq`.
+///
+/// Returns the vector, or `NotSupported` if the function has no MSI capability.
+unsafe fn route_msi(
+    loc: Location,
+    irq: &Arc<dyn IrqScheme>,
+    devs: &[Device],
+) -> DeviceResult<usize> {
+    let ops = &PortOpsImpl;
+    let am = pci_access();
+    let mut cap_ptr = am.read8(ops, loc, PCI_CAP_PTR) as u16;
+    while cap_ptr > 0 {
+        if am.read8(ops, loc, cap_ptr) == PCI_CAP_ID_MSI {
+            let block = irq.msi_alloc_block(1)?;
+            let vector = block.start;
+            let (addr, data) = irq.msi_target(block.clone(), 0)?;
+            let schemes = devs.iter().map(Device::inner).collect::<Vec<_>>();
+            irq.msi_register_handler(
+                block,
+                0,
+                Box::new(move || schemes.iter().for_each(|s| s.handle_irq(vector))),
+            )?;
+
+            let ctrl = am.read32(ops, loc, cap_ptr + PCI_MSI_CTRL_CAP);
+            am.write32(ops, loc, cap_ptr + PCI_MSI_ADDR, addr as u32);
+            if (ctrl >> 16) & (1 << 7) != 0 {
+                // 64bit
+                am.write32(ops, loc, cap_ptr + PCI_MSI_UPPER_ADDR, (addr >> 32) as u32);
+                am.write32(ops, loc, cap_ptr + PCI_MSI_DATA_64, data);
+            } else {
+                am.write32(ops, loc, cap_ptr + PCI_MSI_DATA_32, data);
+            }
+            // enable MSI with a single message
+            am.write32(
+                ops,
+                loc,
+                cap_ptr + PCI_MSI_CTRL_CAP,
+                (ctrl & !(0x7 << 20)) | 0x10000,
+            );
+            return Ok(vector);
+        }
+        cap_ptr = am.read8(ops, loc, cap_ptr + 1) as u16;
+    }
+    Err(DeviceError::NotSupported)
+}
+
+/// Locate the register blocks of a modern virtio-pci device by its
+/// vendor-specific capabilities.
+#[cfg(feature = "virtio")]
+fn virtio_pci_transport(
+    dev: &PCIDevice,
+    mapper: &Option<Arc<dyn IoMapper>>,
+) -> DeviceResult<crate::virtio::Transport> {
+    use crate::virtio::{PciRegions, Transport};
+    let ops = &PortOpsImpl;
+    let am = pci_access();
+    let region = |bar: u8, offset: u32| match dev.bars.get(bar as usize) {
+        Some(Some(BAR::Memory(addr, len, _, _))) if *addr != 0 => {
+            if let Some(m) = mapper {
+                m.query_or_map(*addr as usize, *len as usize);
+            }
+            Some(phys_to_virt(*addr as usize) + offset as usize)
+        }
+        _ => None,
+    };
+
+    let (mut common, mut notify, mut isr, mut device) = (None, None, None, None);
+    let mut notify_off_multiplier = 0;
+    let mut cap_ptr = unsafe { am.read8(ops, dev.loc, PCI_CAP_PTR) } as u16;
+    while cap_ptr > 0 {
+        let cap_id = unsafe { am.read8(ops, dev.loc, cap_ptr) };
+        if cap_id == PCI_CAP_ID_VNDR {
+            let (cfg_type, bar, offset) = unsafe {
+                (
+                    am.read8(ops, dev.loc, cap_ptr + 3),
+                    am.read8(ops, dev.loc, cap_ptr + 4),
+                    am.read32(ops, dev.loc, cap_ptr + 8),
+                )
+            };
+            // use the first capability of each type
+            match cfg_type {
+                VIRTIO_PCI_CAP_COMMON_CFG if common.is_none() => common = region(bar, offset),
+                VIRTIO_PCI_CAP_NOTIFY_CFG if notify.is_none() => {
+                    notify = region(bar, offset);
+                    notify_off_multiplier = unsafe { am.read32(ops, dev.loc, cap_ptr + 16) };
+                }
+                VIRTIO_PCI_CAP_ISR_CFG if isr.is_none() => isr = region(bar, offset),
+                VIRTIO_PCI_CAP_DEVICE_CFG if device.is_none() => device = region(bar, offset),
+                _ => {}
+            }
+        }
+        cap_ptr = unsafe { am.read8(ops, dev.loc, cap_ptr + 1) } as u16;
+    }
+
+    match (common, notify, isr, device) {
+        (Some(common), Some(notify), Some(isr), Some(device)) => Ok(Transport::Pci(PciRegions {
+            common,
+            notify,
+            notify_off_multiplier,
+            isr,
+            device,
+        })),
+        _ => {
+            warn!("virtio-pci: legacy-only devices are not supported");
+            Err(DeviceError::NotSupported)
+        }
+    }
+}
+
+/// Initialize the drivers of a PCI function, which may provide more than one
+/// device, e.g. disks on all ports of an AHCI controller.
+pub fn init_driver(
+    dev: &PCIDevice,
+    mapper: &Option<Arc<dyn IoMapper>>,
+) -> DeviceResult<Vec<Device>> {
     let name = format!("enp{}s{}f{}", dev.loc.bus, dev.loc.device, dev.loc.function);
     match (dev.id.vendor_id, dev.id.device_id) {
         (0x8086, 0x100e) | (0x8086, 0x100f) | (0x8086, 0x10d3) => {
@@ -174,19 +356,57 @@ pub fn init_driver(dev: &PCIDevice, mapper: &Option<Arc<dyn IoMapper>>) -> Devic
                 if let Some(m) = mapper {
                     m.query_or_map(addr as usize, PAGE_SIZE * 8);
                 }
-                let irq = unsafe { enable(dev.loc, addr) };
+                unsafe { enable(dev.loc, addr) };
                 let vaddr = phys_to_virt(addr as usize);
                 let dev = Device::Net(Arc::new(crate::net::e1000::init(
                     name,
-                    irq.unwrap_or(0),
                     vaddr,
                     len as usize,
                     0,
                 )?));
-                return Ok(dev);
+                return Ok(vec![dev]);
             }
         }
 
+        #[cfg(feature = "virtio")]
+        (0x1af4, 0x1000) | (0x1af4, 0x1041) => {
+            // virtio-net, transitional or modern
+            unsafe { enable(dev.loc, 0) };
+            let transport = virtio_pci_transport(dev, mapper)?;
+            info!("Found virtio-net dev {:?}", dev.loc);
+            let net = crate::virtio::VirtIoNet::new(transport, Some(name))?;
+            return Ok(vec![Device::Net(Arc::new(net))]);
+        }
+        #[cfg(feature = "virtio")]
+        (0x1af4, 0x1003) | (0x1af4, 0x1043) => {
+            // virtio-console, transitional or modern
+            unsafe { enable(dev.loc, 0) };
+            let transport = virtio_pci_transport(dev, mapper)?;
+            info!("Found virtio-console dev {:?}", dev.loc);
+            let ports = crate::virtio::VirtIoConsole::new(transport)?;
+            return Ok(ports
+                .into_iter()
+                .map(|port| Device::Uart(Arc::new(port)))
+                .collect());
+        }
+        #[cfg(feature = "virtio")]
+        (0x1af4, 0x1005) | (0x1af4, 0x1044) => {
+            // virtio-rng, transitional or modern
+            unsafe { enable(dev.loc, 0) };
+            let transport = virtio_pci_transport(dev, mapper)?;
+            info!("Found virtio-rng dev {:?}", dev.loc);
+            let rng = crate::virtio::VirtIoRng::new(transport)?;
+            return Ok(vec![Device::Rng(Arc::new(rng))]);
+        }
+        #[cfg(feature = "virtio")]
+        (0x1af4, 0x1053) => {
+            // virtio-vsock, modern only
+            unsafe { enable(dev.loc, 0) };
+            let transport = virtio_pci_transport(dev, mapper)?;
+            info!("Found virtio-vsock dev {:?}", dev.loc);
+            let vsock = crate::virtio::VirtIoVsock::new(transport)?;
+            return Ok(vec![Device::Vsock(Arc::new(vsock))]);
+        }
         (0x1b36, 0x10) => {
             if let Some(BAR::Memory(addr, _len, _, _)) = dev.bars[0] {
                 #[cfg(target_arch = "riscv64")]
@@ -202,7 +422,7 @@ pub fn init_driver(dev: &PCIDevice, mapper: &Option<Arc<dyn IoMapper>>) -> Devic
                 let blk = Arc::new(crate::nvme::NvmeInterface::new(vaddr, irq.unwrap_or(33))?);
 
                 let dev = Device::Block(blk);
-                return Ok(dev);
+                return Ok(vec![dev]);
             }
         }
         (0x8086, 0x10fb) => {
@@ -244,15 +464,31 @@ pub fn init_driver(dev: &PCIDevice, mapper: &Option<Arc<dyn IoMapper>>) -> Devic
         // SATA subclass
         if let Some(BAR::Memory(addr, _len, _, _)) = dev.bars[5] {
             info!("Found AHCI dev {:?} BAR5 {:x?}", dev, addr);
-            /*
-            let irq = unsafe { enable(dev.loc) };
-            assert!(len as usize <= PAGE_SIZE);
+            if let Some(m) = mapper {
+                m.query_or_map(addr as usize, crate::ahci::AHCI_BAR_SIZE);
+            }
+            let irq = unsafe { enable(dev.loc, 0) };
+            unsafe { enable_bus_master(dev.loc) };
             let vaddr = phys_to_virt(addr as usize);
-            if let Some(driver) = ahci::init(irq, vaddr, len as usize) {
-                PCI_DRIVERS.lock().insert(dev.loc, driver);
+            let disks = crate::ahci::init(vaddr, irq.unwrap_or(33))?;
+            return Ok(disks
+                .into_iter()
+                .map(|d| Device::Block(Arc::new(d)))
+                .collect());
+        }
+    }
+    if dev.id.class == 0x0c && dev.id.subclass == 0x03 && dev.id.prog_if == 0x30 {
+        // Serial bus controller
+        // USB subclass, xHCI
+        if let Some(BAR::Memory(addr, len, _, _)) = dev.bars[0] {
+            info!("Found xHCI dev {:?} BAR0 {:x?}", dev, addr);
+            if let Some(m) = mapper {
+                m.query_or_map(addr as usize, len as usize);
             }
-            */
-            return Err(DeviceError::NotSupported);
+            let irq = unsafe { enable(dev.loc, 0) };
+            unsafe { enable_bus_master(dev.loc) };
+            let vaddr = phys_to_virt(addr as usize);
+            return crate::usb::init(vaddr, irq.unwrap_or(33));
         }
     }
 
@@ -277,7 +513,15 @@ pub fn detach_driver(_loc: &Location) -> bool {
     false
 }
 
-pub fn init(mapper: Option<Arc<dyn IoMapper>>) -> DeviceResult<Vec<Device>> {
+/// Scan the PCI bus and initialize drivers of the devices found.
+///
+/// If the interrupt controller `irq` is given, MSIs of the devices are routed
+/// to their `handle_irq()`. Devices without MSI get no interrupt, and have to
+/// be polled by the caller.
+pub fn init(
+    mapper: Option<Arc<dyn IoMapper>>,
+    irq: Option<Arc<dyn IrqScheme>>,
+) -> DeviceResult<Vec<Device>> {
     let mapper_driver = if let Some(m) = mapper {
         m.query_or_map(PCI_BASE, PAGE_SIZE * 256 * 32 * 8);
         Some(m)
@@ -286,7 +530,7 @@ pub fn init(mapper: Option<Arc<dyn IoMapper>>) -> DeviceResult<Vec<Device>> {
     };
 
     let mut dev_list = Vec::new();
-    let pci_iter = unsafe { scan_bus(&PortOpsImpl, PCI_ACCESS) };
+    let pci_iter = unsafe { scan_bus(&PortOpsImpl, pci_access()) };
     info!("");
     info!("--------- PCI bus:device:function ---------");
     for dev in pci_iter {
@@ -304,7 +548,15 @@ pub fn init(mapper: Option<Arc<dyn IoMapper>>) -> DeviceResult<Vec<Device>> {
         );
         let res = init_driver(&dev, &mapper_driver);
         match res {
-            Ok(d) => dev_list.push(d),
+            Ok(d) => {
+                if let (Some(irq), false) = (&irq, d.is_empty()) {
+                    match unsafe { route_msi(dev.loc, irq, &d) } {
+                        Ok(vector) => info!("pci: {:?} MSI routed to vector {}", dev.loc, vector),
+                        Err(e) => warn!("pci: {:?} MSI not routed, polled: {:?}", dev.loc, e),
+                    }
+                }
+                dev_list.extend(d)
+            }
             Err(e) => warn!(
                 "{:?}, failed to initialize PCI device: {:04x}:{:04x}",
                 e, dev.id.vendor_id, dev.id.device_id
@@ -318,7 +570,7 @@ pub fn init(mapper: Option<Arc<dyn IoMapper>>) -> DeviceResult<Vec<Device>> {
 }
 
 pub fn find_device(vendor: u16, product: u16) -> Option<Location> {
-    let pci_iter = unsafe { scan_bus(&PortOpsImpl, PCI_ACCESS) };
+    let pci_iter = unsafe { scan_bus(&PortOpsImpl, pci_access()) };
     for dev in pci_iter {
         if dev.id.vendor_id == vendor && dev.id.device_id == product {
             return Some(dev.loc);
@@ -328,7 +580,7 @@ pub fn find_device(vendor: u16, product: u16) -> Option<Location> {
 }
 
 pub fn get_bar0_mem(loc: Location) -> Option<(usize, usize)> {
-    unsafe { probe_function(&PortOpsImpl, loc, PCI_ACCESS) }
+    unsafe { probe_function(&PortOpsImpl, loc, pci_access()) }
         .and_then(|dev| dev.bars[0])
         .map(|bar| match bar {
             BAR::Memory(addr, len, _, _) => (addr as usize, len as usize),
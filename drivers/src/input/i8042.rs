@@ -0,0 +1,492 @@
+//! Keyboard on the i8042 PS/2 controller.
+
+use lock::Mutex;
+
+use super::input_event_codes::{ev::*, key::*, syn::*};
+use crate::io::{Io, Pmio};
+use crate::prelude::{CapabilityType, InputCapability, InputEvent, InputEventType};
+use crate::scheme::{impl_event_scheme, InputScheme, Scheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult};
+
+const DATA_PORT: u16 = 0x60;
+const CMD_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The output is from the auxiliary (mouse) port.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_AUX: u8 = 0xa7;
+const CMD_TEST_KBD: u8 = 0xab;
+const CMD_DISABLE_KBD: u8 = 0xad;
+const CMD_ENABLE_KBD: u8 = 0xae;
+
+const CONFIG_KBD_INT: u8 = 1 << 0;
+const CONFIG_AUX_INT: u8 = 1 << 1;
+const CONFIG_KBD_CLOCK_DISABLED: u8 = 1 << 4;
+/// The controller translates scancode set 2 into set 1.
+const CONFIG_TRANSLATE: u8 = 1 << 6;
+
+const KBD_ENABLE_SCANNING: u8 = 0xf4;
+const KBD_ACK: u8 = 0xfa;
+
+/// Iterations to poll the status before giving up.
+const SPIN_TIMEOUT: usize = 100_000;
+
+/// Scancode sets of PS/2 keyboards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// Decodes scancode bytes into key codes of `input_event_codes`.
+pub struct ScancodeDecoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// Remaining bytes of the Pause sequence to skip.
+    skip: u8,
+}
+
+impl ScancodeDecoder {
+    pub fn new(set: ScancodeSet) -> Self {
+        Self {
+            set,
+            extended: false,
+            release: false,
+            skip: 0,
+        }
+    }
+
+    /// Feed a byte, returns the key code and whether it is pressed once a
+    /// key event is complete.
+    pub fn feed(&mut self, byte: u8) -> Option<(u16, bool)> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+        match (self.set, byte) {
+            (_, 0xe0) => {
+                self.extended = true;
+                return None;
+            }
+            (ScancodeSet::Set1, 0xe1) => {
+                self.skip = 5;
+                return None;
+            }
+            (ScancodeSet::Set2, 0xe1) => {
+                self.skip = 7;
+                return None;
+            }
+            (ScancodeSet::Set2, 0xf0) => {
+                self.release = true;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let (code, pressed) = match self.set {
+            ScancodeSet::Set1 => (set1_to_key(byte & 0x7f, extended), byte & 0x80 == 0),
+            ScancodeSet::Set2 => {
+                let release = core::mem::replace(&mut self.release, false);
+                (set2_to_key(byte, extended), !release)
+            }
+        };
+        code.map(|c| (c, pressed))
+    }
+}
+
+/// Key codes of extended scancodes, shared by both sets after translating
+/// set 2 into set 1.
+fn set1_extended_to_key(code: u8) -> Option<u16> {
+    Some(match code {
+        0x1c => KEY_KPENTER,
+        0x1d => KEY_RIGHTCTRL,
+        0x35 => KEY_KPSLASH,
+        0x37 => KEY_SYSRQ,
+        0x38 => KEY_RIGHTALT,
+        0x47 => KEY_HOME,
+        0x48 => KEY_UP,
+        0x49 => KEY_PAGEUP,
+        0x4b => KEY_LEFT,
+        0x4d => KEY_RIGHT,
+        0x4f => KEY_END,
+        0x50 => KEY_DOWN,
+        0x51 => KEY_PAGEDOWN,
+        0x52 => KEY_INSERT,
+        0x53 => KEY_DELETE,
+        0x5b => KEY_LEFTMETA,
+        0x5c => KEY_RIGHTMETA,
+        0x5d => KEY_COMPOSE,
+        // including the fake shifts around extended keys
+        _ => return None,
+    })
+}
+
+/// Key codes are the same as set 1 scancodes, up to `KEY_F12`.
+fn set1_to_key(code: u8, extended: bool) -> Option<u16> {
+    if extended {
+        set1_extended_to_key(code)
+    } else if code != 0 && code as u16 <= KEY_F12 {
+        Some(code as u16)
+    } else {
+        None
+    }
+}
+
+fn set2_to_key(code: u8, extended: bool) -> Option<u16> {
+    if extended {
+        let code = match code {
+            0x5a => 0x1c,
+            0x14 => 0x1d,
+            0x4a => 0x35,
+            0x7c => 0x37,
+            0x11 => 0x38,
+            0x6c => 0x47,
+            0x75 => 0x48,
+            0x7d => 0x49,
+            0x6b => 0x4b,
+            0x74 => 0x4d,
+            0x69 => 0x4f,
+            0x72 => 0x50,
+            0x7a => 0x51,
+            0x70 => 0x52,
+            0x71 => 0x53,
+            0x1f => 0x5b,
+            0x27 => 0x5c,
+            0x2f => 0x5d,
+            _ => return None,
+        };
+        return set1_extended_to_key(code);
+    }
+    Some(match code {
+        0x01 => KEY_F9,
+        0x03 => KEY_F5,
+        0x04 => KEY_F3,
+        0x05 => KEY_F1,
+        0x06 => KEY_F2,
+        0x07 => KEY_F12,
+        0x09 => KEY_F10,
+        0x0a => KEY_F8,
+        0x0b => KEY_F6,
+        0x0c => KEY_F4,
+        0x0d => KEY_TAB,
+        0x0e => KEY_GRAVE,
+        0x11 => KEY_LEFTALT,
+        0x12 => KEY_LEFTSHIFT,
+        0x14 => KEY_LEFTCTRL,
+        0x15 => KEY_Q,
+        0x16 => KEY_1,
+        0x1a => KEY_Z,
+        0x1b => KEY_S,
+        0x1c => KEY_A,
+        0x1d => KEY_W,
+        0x1e => KEY_2,
+        0x21 => KEY_C,
+        0x22 => KEY_X,
+        0x23 => KEY_D,
+        0x24 => KEY_E,
+        0x25 => KEY_4,
+        0x26 => KEY_3,
+        0x29 => KEY_SPACE,
+        0x2a => KEY_V,
+        0x2b => KEY_F,
+        0x2c => KEY_T,
+        0x2d => KEY_R,
+        0x2e => KEY_5,
+        0x31 => KEY_N,
+        0x32 => KEY_B,
+        0x33 => KEY_H,
+        0x34 => KEY_G,
+        0x35 => KEY_Y,
+        0x36 => KEY_6,
+        0x3a => KEY_M,
+        0x3b => KEY_J,
+        0x3c => KEY_U,
+        0x3d => KEY_7,
+        0x3e => KEY_8,
+        0x41 => KEY_COMMA,
+        0x42 => KEY_K,
+        0x43 => KEY_I,
+        0x44 => KEY_O,
+        0x45 => KEY_0,
+        0x46 => KEY_9,
+        0x49 => KEY_DOT,
+        0x4a => KEY_SLASH,
+        0x4b => KEY_L,
+        0x4c => KEY_SEMICOLON,
+        0x4d => KEY_P,
+        0x4e => KEY_MINUS,
+        0x52 => KEY_APOSTROPHE,
+        0x54 => KEY_LEFTBRACE,
+        0x55 => KEY_EQUAL,
+        0x58 => KEY_CAPSLOCK,
+        0x59 => KEY_RIGHTSHIFT,
+        0x5a => KEY_ENTER,
+        0x5b => KEY_RIGHTBRACE,
+        0x5d => KEY_BACKSLASH,
+        0x61 => KEY_102ND,
+        0x66 => KEY_BACKSPACE,
+        0x69 => KEY_KP1,
+        0x6b => KEY_KP4,
+        0x6c => KEY_KP7,
+        0x70 => KEY_KP0,
+        0x71 => KEY_KPDOT,
+        0x72 => KEY_KP2,
+        0x73 => KEY_KP5,
+        0x74 => KEY_KP6,
+        0x75 => KEY_KP8,
+        0x76 => KEY_ESC,
+        0x77 => KEY_NUMLOCK,
+        0x78 => KEY_F11,
+        0x79 => KEY_KPPLUS,
+        0x7a => KEY_KP3,
+        0x7b => KEY_KPMINUS,
+        0x7c => KEY_KPASTERISK,
+        0x7d => KEY_KP9,
+        0x7e => KEY_SCROLLLOCK,
+        0x83 => KEY_F7,
+        _ => return None,
+    })
+}
+
+struct I8042Inner {
+    data: Pmio<u8>,
+    cmd: Pmio<u8>,
+    decoder: ScancodeDecoder,
+    /// Pressed keys, to report auto-repeats.
+    pressed: [u64; 4],
+}
+
+impl I8042Inner {
+    fn wait_input_empty(&self) -> DeviceResult {
+        for _ in 0..SPIN_TIMEOUT {
+            if self.cmd.read() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(DeviceError::NotReady)
+    }
+
+    fn read_data(&self) -> DeviceResult<u8> {
+        for _ in 0..SPIN_TIMEOUT {
+            if self.cmd.read() & STATUS_OUTPUT_FULL != 0 {
+                return Ok(self.data.read());
+            }
+            core::hint::spin_loop();
+        }
+        Err(DeviceError::NotReady)
+    }
+
+    fn command(&mut self, cmd: u8) -> DeviceResult {
+        self.wait_input_empty()?;
+        self.cmd.write(cmd);
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: u8) -> DeviceResult {
+        self.wait_input_empty()?;
+        self.data.write(data);
+        Ok(())
+    }
+
+    fn flush_output(&self) {
+        for _ in 0..16 {
+            if self.cmd.read() & STATUS_OUTPUT_FULL == 0 {
+                break;
+            }
+            self.data.read();
+        }
+    }
+
+    /// Record the key state, returns the event value: 0 for release, 1 for
+    /// press, and 2 for auto-repeat.
+    fn update_pressed(&mut self, code: u16, pressed: bool) -> i32 {
+        let (idx, bit) = (code as usize / 64 % 4, 1 << (code % 64));
+        let was_pressed = self.pressed[idx] & bit != 0;
+        if pressed {
+            self.pressed[idx] |= bit;
+        } else {
+            self.pressed[idx] &= !bit;
+        }
+        match (pressed, was_pressed) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 2,
+        }
+    }
+}
+
+pub struct I8042Keyboard {
+    inner: Mutex<I8042Inner>,
+    listener: EventListener<InputEvent>,
+}
+
+impl_event_scheme!(I8042Keyboard, InputEvent);
+
+impl I8042Keyboard {
+    /// Initialize the controller with only the keyboard port and its
+    /// interrupt enabled.
+    pub fn new() -> DeviceResult<Self> {
+        let mut inner = I8042Inner {
+            data: Pmio::new(DATA_PORT),
+            cmd: Pmio::new(CMD_PORT),
+            decoder: ScancodeDecoder::new(ScancodeSet::Set1),
+            pressed: [0; 4],
+        };
+        // no controller, the bus floats high
+        if inner.cmd.read() == 0xff {
+            return Err(DeviceError::NotSupported);
+        }
+        inner.command(CMD_DISABLE_KBD)?;
+        inner.command(CMD_DISABLE_AUX)?;
+        inner.flush_output();
+
+        inner.command(CMD_READ_CONFIG)?;
+        let mut config = inner.read_data()?;
+        config &= !(CONFIG_KBD_INT | CONFIG_AUX_INT);
+        inner.command(CMD_WRITE_CONFIG)?;
+        inner.write_data(config)?;
+
+        inner.command(CMD_TEST_KBD)?;
+        let result = inner.read_data()?;
+        if result != 0 {
+            warn!("i8042: keyboard port test failed: {:#x}", result);
+            return Err(DeviceError::IoError);
+        }
+
+        inner.command(CMD_ENABLE_KBD)?;
+        inner.write_data(KBD_ENABLE_SCANNING)?;
+        match inner.read_data() {
+            Ok(KBD_ACK) => {}
+            res => {
+                warn!("i8042: no keyboard: {:x?}", res);
+                return Err(DeviceError::NotSupported);
+            }
+        }
+
+        config = (config | CONFIG_KBD_INT) & !CONFIG_KBD_CLOCK_DISABLED;
+        inner.command(CMD_WRITE_CONFIG)?;
+        inner.write_data(config)?;
+        let set = if config & CONFIG_TRANSLATE != 0 {
+            ScancodeSet::Set1
+        } else {
+            ScancodeSet::Set2
+        };
+        inner.decoder = ScancodeDecoder::new(set);
+        info!("i8042: keyboard found, scancode {:?}", set);
+        Ok(Self {
+            inner: Mutex::new(inner),
+            listener: EventListener::new(),
+        })
+    }
+
+    fn report_event(&self, event_type: InputEventType, code: u16, value: i32) {
+        self.listener.trigger(InputEvent {
+            event_type,
+            code,
+            value,
+        });
+    }
+}
+
+impl Scheme for I8042Keyboard {
+    fn name(&self) -> &str {
+        "i8042-keyboard"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        loop {
+            let key = {
+                let mut inner = self.inner.lock();
+                let status = inner.cmd.read();
+                if status & STATUS_OUTPUT_FULL == 0 {
+                    break;
+                }
+                let byte = inner.data.read();
+                if status & STATUS_AUX_DATA != 0 {
+                    continue;
+                }
+                inner
+                    .decoder
+                    .feed(byte)
+                    .map(|(code, pressed)| (code, inner.update_pressed(code, pressed)))
+            };
+            if let Some((code, value)) = key {
+                self.report_event(InputEventType::Key, code, value);
+                self.report_event(InputEventType::Syn, SYN_REPORT, 0);
+            }
+        }
+    }
+}
+
+impl InputScheme for I8042Keyboard {
+    fn capability(&self, cap_type: CapabilityType) -> InputCapability {
+        let mut cap = InputCapability::empty();
+        match cap_type {
+            CapabilityType::Event => cap.set_all(&[EV_SYN, EV_KEY]),
+            CapabilityType::Key => {
+                for code in 1..=0x7f {
+                    if let Some(key) = set1_to_key(code, false) {
+                        cap.set(key);
+                    }
+                    if let Some(key) = set1_extended_to_key(code) {
+                        cap.set(key);
+                    }
+                }
+            }
+            _ => {}
+        }
+        cap
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(set: ScancodeSet, bytes: &[u8]) -> alloc::vec::Vec<(u16, bool)> {
+        let mut decoder = ScancodeDecoder::new(set);
+        bytes.iter().filter_map(|&b| decoder.feed(b)).collect()
+    }
+
+    #[test]
+    fn scancode_set1() {
+        let keys = decode(ScancodeSet::Set1, &[0x1e, 0x9e, 0xe0, 0x48, 0xe0, 0xc8]);
+        assert_eq!(
+            keys,
+            [
+                (KEY_A, true),
+                (KEY_A, false),
+                (KEY_UP, true),
+                (KEY_UP, false)
+            ]
+        );
+        // Pause is skipped
+        let pause = [0xe1, 0x1d, 0x45, 0xe1, 0x9d, 0xc5, 0x1c];
+        assert_eq!(decode(ScancodeSet::Set1, &pause), [(KEY_ENTER, true)]);
+    }
+
+    #[test]
+    fn scancode_set2() {
+        let keys = decode(
+            ScancodeSet::Set2,
+            &[0x1c, 0xf0, 0x1c, 0xe0, 0x75, 0xe0, 0xf0, 0x75, 0x59],
+        );
+        assert_eq!(
+            keys,
+            [
+                (KEY_A, true),
+                (KEY_A, false),
+                (KEY_UP, true),
+                (KEY_UP, false),
+                (KEY_RIGHTSHIFT, true)
+            ]
+        );
+    }
+}
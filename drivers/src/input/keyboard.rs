@@ -0,0 +1,224 @@
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+use lock::Mutex;
+
+use crate::prelude::{CapabilityType, InputEvent, InputEventType};
+use crate::scheme::{impl_event_scheme, InputScheme};
+use crate::utils::EventListener;
+
+use super::input_event_codes::key::*;
+
+/// Number of key codes with characters, up to `KEY_KPDOT`.
+const KEYMAP_SIZE: usize = KEY_KPDOT as usize + 1;
+
+/// A keyboard layout, maps key codes to characters without and with Shift.
+pub struct Keymap {
+    pub plain: [u8; KEYMAP_SIZE],
+    pub shift: [u8; KEYMAP_SIZE],
+}
+
+impl Keymap {
+    /// The US layout.
+    pub const US: Self = Self {
+        plain: *b"\0\x1b1234567890-=\x7f\tqwertyuiop[]\r\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 \0\
+                  \0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.",
+        shift: *b"\0\x1b!@#$%^&*()_+\x7f\tQWERTYUIOP{}\r\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 \0\
+                  \0\0\0\0\0\0\0\0\0\0\0\0789-456+1230.",
+    };
+}
+
+bitflags::bitflags! {
+    #[derive(Default)]
+    pub struct KeyModifiers: u8 {
+        const LEFT_SHIFT = 1 << 0;
+        const RIGHT_SHIFT = 1 << 1;
+        const LEFT_CTRL = 1 << 2;
+        const RIGHT_CTRL = 1 << 3;
+        const LEFT_ALT = 1 << 4;
+        const RIGHT_ALT = 1 << 5;
+        const SHIFT = Self::LEFT_SHIFT.bits | Self::RIGHT_SHIFT.bits;
+        const CTRL = Self::LEFT_CTRL.bits | Self::RIGHT_CTRL.bits;
+        const ALT = Self::LEFT_ALT.bits | Self::RIGHT_ALT.bits;
+    }
+}
+
+struct KeyboardState {
+    keymap: &'static Keymap,
+    modifiers: KeyModifiers,
+    caps_lock: bool,
+}
+
+impl KeyboardState {
+    /// Terminal input sequences of keys without characters.
+    fn sequence(code: u16) -> Option<&'static [u8]> {
+        Some(match code {
+            KEY_UP => b"\x1b[A",
+            KEY_DOWN => b"\x1b[B",
+            KEY_RIGHT => b"\x1b[C",
+            KEY_LEFT => b"\x1b[D",
+            KEY_HOME => b"\x1b[H",
+            KEY_END => b"\x1b[F",
+            KEY_INSERT => b"\x1b[2~",
+            KEY_DELETE => b"\x1b[3~",
+            KEY_PAGEUP => b"\x1b[5~",
+            KEY_PAGEDOWN => b"\x1b[6~",
+            KEY_KPENTER => b"\r",
+            KEY_KPSLASH => b"/",
+            _ => return None,
+        })
+    }
+
+    /// Update modifiers by a key event, returns the terminal input of the key.
+    fn update(&mut self, e: &InputEvent) -> Vec<u8> {
+        if !matches!(e.event_type, InputEventType::Key) {
+            return Vec::new();
+        }
+        let modifier = match e.code {
+            KEY_LEFTSHIFT => KeyModifiers::LEFT_SHIFT,
+            KEY_RIGHTSHIFT => KeyModifiers::RIGHT_SHIFT,
+            KEY_LEFTCTRL => KeyModifiers::LEFT_CTRL,
+            KEY_RIGHTCTRL => KeyModifiers::RIGHT_CTRL,
+            KEY_LEFTALT => KeyModifiers::LEFT_ALT,
+            KEY_RIGHTALT => KeyModifiers::RIGHT_ALT,
+            _ => KeyModifiers::empty(),
+        };
+        if !modifier.is_empty() {
+            self.modifiers.set(modifier, e.value != 0);
+            return Vec::new();
+        }
+        // released
+        if e.value == 0 {
+            return Vec::new();
+        }
+        if e.code == KEY_CAPSLOCK {
+            if e.value == 1 {
+                self.caps_lock = !self.caps_lock;
+            }
+            return Vec::new();
+        }
+
+        let mut input = Vec::new();
+        if self.modifiers.intersects(KeyModifiers::ALT) {
+            input.push(0x1b);
+        }
+        if let Some(seq) = Self::sequence(e.code) {
+            input.extend_from_slice(seq);
+            return input;
+        }
+        let map = if self.modifiers.intersects(KeyModifiers::SHIFT) {
+            &self.keymap.shift
+        } else {
+            &self.keymap.plain
+        };
+        let mut c = match map.get(e.code as usize) {
+            Some(&c) if c != 0 => c,
+            _ => return Vec::new(),
+        };
+        if self.caps_lock && c.is_ascii_alphabetic() {
+            c ^= 0x20;
+        }
+        if self.modifiers.intersects(KeyModifiers::CTRL) {
+            c = match c {
+                b'a'..=b'z' | b'@'..=b'_' => c & 0x1f,
+                b' ' => 0,
+                _ => c,
+            };
+        }
+        input.push(c);
+        input
+    }
+}
+
+/// Translates key events of an input device into terminal input bytes.
+pub struct Keyboard {
+    listener: EventListener<u8>,
+    state: Mutex<KeyboardState>,
+}
+
+impl_event_scheme!(Keyboard, u8);
+
+impl Keyboard {
+    pub fn new(input: Arc<dyn InputScheme>, keymap: &'static Keymap) -> Arc<Self> {
+        let ret = Arc::new(Self {
+            listener: EventListener::new(),
+            state: Mutex::new(KeyboardState {
+                keymap,
+                modifiers: KeyModifiers::empty(),
+                caps_lock: false,
+            }),
+        });
+        let cloned = ret.clone();
+        input.subscribe(Box::new(move |e| cloned.handle_input_event(e)), false);
+        ret
+    }
+
+    fn handle_input_event(&self, e: &InputEvent) {
+        let input = self.state.lock().update(e);
+        for c in input {
+            self.listener.trigger(c);
+        }
+    }
+
+    pub fn compatible_with(input: &Arc<dyn InputScheme>) -> bool {
+        // A keyboard like device, with letters and the Enter key.
+        use super::input_event_codes::ev::*;
+        let ev = input.capability(CapabilityType::Event);
+        let key = input.capability(CapabilityType::Key);
+        ev.contains(EV_KEY) && key.contains_all(&[KEY_A, KEY_Z, KEY_ENTER])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn type_keys(keys: &[(u16, i32)]) -> Vec<u8> {
+        let mut state = KeyboardState {
+            keymap: &Keymap::US,
+            modifiers: KeyModifiers::empty(),
+            caps_lock: false,
+        };
+        keys.iter()
+            .flat_map(|&(code, value)| {
+                state.update(&InputEvent {
+                    event_type: InputEventType::Key,
+                    code,
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keymap() {
+        let us = Keymap::US;
+        assert_eq!(us.plain[KEY_Q as usize], b'q');
+        assert_eq!(us.shift[KEY_SLASH as usize], b'?');
+        assert_eq!(us.plain[KEY_KPDOT as usize], b'.');
+    }
+
+    #[test]
+    fn translate() {
+        let keys = [
+            (KEY_LEFTSHIFT, 1),
+            (KEY_H, 1),
+            (KEY_LEFTSHIFT, 0),
+            (KEY_I, 1),
+            (KEY_I, 2),
+            (KEY_I, 0),
+            (KEY_ENTER, 1),
+        ];
+        assert_eq!(type_keys(&keys), b"Hii\r");
+
+        let keys = [(KEY_CAPSLOCK, 1), (KEY_A, 1), (KEY_CAPSLOCK, 0), (KEY_1, 1)];
+        assert_eq!(type_keys(&keys), b"A1");
+
+        let keys = [
+            (KEY_RIGHTCTRL, 1),
+            (KEY_C, 1),
+            (KEY_RIGHTCTRL, 0),
+            (KEY_UP, 1),
+        ];
+        assert_eq!(type_keys(&keys), b"\x03\x1b[A");
+    }
+}
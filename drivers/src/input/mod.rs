@@ -1,7 +1,15 @@
-//! Only Mouse currently.
+//! Mouse and keyboard on input devices, and the PS/2 keyboard.
 
+mod keyboard;
 mod mouse;
 
+#[cfg(target_arch = "x86_64")]
+mod i8042;
+
 pub mod input_event_codes;
 
+pub use keyboard::{KeyModifiers, Keyboard, Keymap};
 pub use mouse::{Mouse, MouseFlags, MouseState};
+
+#[cfg(target_arch = "x86_64")]
+pub use i8042::I8042Keyboard;
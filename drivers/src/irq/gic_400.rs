@@ -13,6 +13,7 @@ static GICD_ICENABLER: u32 = 0x180;
 static GICD_IPRIORITY: u32 = 0x400;
 static GICD_ITARGETSR: u32 = 0x800;
 static GICD_ICFGR: u32 = 0xc00;
+static GICD_SGIR: u32 = 0xf00;
 static GICC_IAR: u32 = 0x000c;
 static GICC_EOIR: u32 = 0x0010;
 static GICC_CTLR: u32 = 0x0000;
@@ -21,7 +22,7 @@ static GICC_PMR: u32 = 0x0004;
 pub struct IntController {
     gicc: GicCpuIf,
     gicd: GicDistIf,
-    manager: Mutex<IrqManager<50>>,
+    manager: Mutex<IrqManager<1024>>,
 }
 
 struct GicDistIf {
@@ -43,7 +44,8 @@ impl IntController {
                 ncpus: 0,
                 nirqs: 0,
             },
-            manager: Mutex::new(IrqManager::new(0..50)),
+            // Interrupt IDs from 1020 to 1023 are special
+            manager: Mutex::new(IrqManager::new(0..1020)),
         }
     }
 
@@ -115,9 +117,11 @@ impl IntController {
         }
     }
 
+    /// Returns the value of `GICC_IAR`, which has the source CPU of SGIs in
+    /// bits [12:10] and must be written back to `GICC_EOIR` as is.
     pub fn pending_irq(&self) -> usize {
         let iar = unsafe { self.gicc.read(GICC_IAR) as usize };
-        if iar >= 0x3fe {
+        if iar & 0x3ff >= 0x3fe {
             usize::MAX
         } else {
             iar
@@ -132,9 +136,9 @@ impl Scheme for IntController {
 
     fn handle_irq(&self, irq_num: usize) {
         if irq_num != usize::MAX {
-            self.manager.lock().handle(irq_num).ok();
+            self.manager.lock().handle(irq_num & 0x3ff).ok();
+            self.irq_eoi(irq_num as u32);
         }
-        self.irq_eoi(irq_num as u32);
     }
 }
 
@@ -164,8 +168,30 @@ impl IrqScheme for IntController {
         Ok(())
     }
 
-    fn unregister(&self, _irq_num: usize) -> DeviceResult {
-        todo!()
+    fn unregister(&self, irq_num: usize) -> DeviceResult {
+        self.manager.lock().unregister_handler(irq_num)
+    }
+
+    fn pending_irq(&self) -> usize {
+        IntController::pending_irq(self)
+    }
+
+    fn send_sgi(&self, cpu_id: usize, irq_num: usize) -> DeviceResult {
+        // CPU interface numbers are the same as CPU IDs on QEMU `virt`
+        let target_list = 1 << (cpu_id & 0x7);
+        unsafe {
+            let sgir = target_list << 16 | (irq_num as u32 & 0xf);
+            self.gicd.write(GICD_SGIR, sgir);
+        }
+        Ok(())
+    }
+
+    fn init_hart(&self) {
+        unsafe {
+            // Enable the GIC interface of the current CPU and set its priority mask
+            self.gicc.write(GICC_CTLR, 1);
+            self.gicc.write(GICC_PMR, 0xff);
+        }
     }
 }
 
@@ -194,7 +220,3 @@ pub fn init(gicc_base: usize, gicd_base: usize) -> IntController {
     controller.init();
     controller
 }
-
-pub fn get_irq_num(gicc_base: usize, gicd_base: usize) -> usize {
-    IntController::new(gicc_base, gicd_base).pending_irq()
-}
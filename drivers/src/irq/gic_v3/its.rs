@@ -0,0 +1,230 @@
+//! GICv3 Interrupt Translation Service.
+//!
+//! A device triggers an MSI by writing its event ID to `GITS_TRANSLATER`, and
+//! the ITS translates the pair of its device ID (the PCI requester ID) and the
+//! event ID into an LPI, which is delivered to the redistributor of a CPU.
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use lock::Mutex;
+
+use crate::bus::phys_to_virt;
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_BASER: usize = 0x0100;
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+const GITS_CTLR_ENABLED: u32 = 1 << 0;
+const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+const GITS_TYPER_PTA: u64 = 1 << 19;
+const GITS_BASER_VALID: u64 = 1 << 63;
+const GITS_BASER_TYPE_DEVICE: u64 = 1;
+const GITS_BASER_TYPE_COLLECTION: u64 = 4;
+
+/// Inner write-back read/write-allocate, inner shareable, in the format of
+/// `GITS_CBASER` and `GITS_BASER<n>`.
+const GITS_BASER_CACHE_SHARE: u64 = 7 << 59 | 1 << 10;
+
+const CMD_QUEUE_SIZE: usize = PAGE_SIZE;
+const CMD_SIZE: usize = 32;
+const CMD_SYNC: u64 = 0x05;
+const CMD_MAPD: u64 = 0x08;
+const CMD_MAPC: u64 = 0x09;
+const CMD_MAPTI: u64 = 0x0a;
+const CMD_INVALL: u64 = 0x0d;
+const CMD_DISCARD: u64 = 0x0f;
+
+/// All LPIs are delivered to the collection of the boot CPU.
+const COLLECTION_ID: u64 = 0;
+/// Event ID bits in the translation table of each device, enough for MSI.
+const EVENT_ID_BITS: usize = 5;
+/// PCI requester IDs are 16-bit.
+const MAX_DEVICE_ID_BITS: usize = 16;
+
+const PAGE_SIZE: usize = 4096;
+
+extern "C" {
+    fn drivers_dma_alloc(pages: usize) -> PhysAddr;
+}
+
+/// Allocate zeroed physical memory of `size` bytes aligned to `align`.
+pub(super) fn alloc_table(size: usize, align: usize) -> (VirtAddr, PhysAddr) {
+    let align = align.max(PAGE_SIZE);
+    let pages = (size + align - PAGE_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+    let paddr = unsafe { drivers_dma_alloc(pages) };
+    let paddr = (paddr + align - 1) & !(align - 1);
+    let vaddr = phys_to_virt(paddr);
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+    (vaddr, paddr)
+}
+
+struct ItsInner {
+    cmd_write: usize,
+    /// Mapped device IDs and their interrupt translation tables.
+    devices: BTreeMap<u32, PhysAddr>,
+    /// The first LPI of mapped MSI blocks, and the device ID and the size.
+    blocks: BTreeMap<usize, (u32, usize)>,
+}
+
+pub struct Its {
+    base: VirtAddr,
+    translater: PhysAddr,
+    cmd_queue: VirtAddr,
+    itt_entry_size: usize,
+    inner: Mutex<ItsInner>,
+}
+
+impl Its {
+    /// Create the driver with the registers at `base`, which is mapped from
+    /// `paddr`, and set up the command queue and the tables in memory.
+    pub fn new(base: VirtAddr, paddr: PhysAddr) -> Self {
+        let (cmd_queue, cmd_paddr) = alloc_table(CMD_QUEUE_SIZE, PAGE_SIZE);
+        let its = Self {
+            base,
+            translater: paddr + GITS_TRANSLATER,
+            cmd_queue,
+            itt_entry_size: (Self::read64(base, GITS_TYPER) >> 4 & 0xf) as usize + 1,
+            inner: Mutex::new(ItsInner {
+                cmd_write: 0,
+                devices: BTreeMap::new(),
+                blocks: BTreeMap::new(),
+            }),
+        };
+        its.write(GITS_CTLR, 0);
+        while its.read(GITS_CTLR) & GITS_CTLR_QUIESCENT == 0 {
+            core::hint::spin_loop();
+        }
+
+        let cbaser = GITS_BASER_VALID
+            | GITS_BASER_CACHE_SHARE
+            | cmd_paddr as u64
+            | (CMD_QUEUE_SIZE / PAGE_SIZE - 1) as u64;
+        Self::write64(base, GITS_CBASER, cbaser);
+        Self::write64(base, GITS_CWRITER, 0);
+        its.init_tables();
+        its.write(GITS_CTLR, GITS_CTLR_ENABLED);
+        its
+    }
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    fn read64(base: VirtAddr, reg: usize) -> u64 {
+        unsafe { read_volatile((base + reg) as *const u64) }
+    }
+
+    fn write64(base: VirtAddr, reg: usize, value: u64) {
+        unsafe { write_volatile((base + reg) as *mut u64, value) }
+    }
+
+    /// Allocate flat tables for device IDs and collections in 4K pages.
+    fn init_tables(&self) {
+        let typer = Self::read64(self.base, GITS_TYPER);
+        let device_id_bits = ((typer >> 13 & 0x1f) as usize + 1).min(MAX_DEVICE_ID_BITS);
+        for n in 0..8 {
+            let reg = GITS_BASER + n * 8;
+            let baser = Self::read64(self.base, reg);
+            let entries = match baser >> 56 & 0x7 {
+                GITS_BASER_TYPE_DEVICE => 1 << device_id_bits,
+                GITS_BASER_TYPE_COLLECTION => 1,
+                _ => continue,
+            };
+            let entry_size = (baser >> 48 & 0x1f) as usize + 1;
+            let pages = ((entries * entry_size + PAGE_SIZE - 1) / PAGE_SIZE).min(256);
+            let (_, paddr) = alloc_table(pages * PAGE_SIZE, PAGE_SIZE);
+            let value = GITS_BASER_VALID
+                | GITS_BASER_CACHE_SHARE
+                | baser & (0x7 << 56 | 0x1f << 48)
+                | paddr as u64
+                | (pages - 1) as u64;
+            Self::write64(self.base, reg, value);
+            if Self::read64(self.base, reg) & (0x3 << 8) != 0 {
+                warn!("GICv3 ITS: 4K pages are not supported by GITS_BASER{}", n);
+            }
+        }
+    }
+
+    /// Map the collection to the redistributor of the current CPU, which is
+    /// at `rd_paddr` and has the processor number `processor`.
+    pub fn map_collection(&self, rd_paddr: PhysAddr, processor: usize) {
+        let typer = Self::read64(self.base, GITS_TYPER);
+        let rd_base = if typer & GITS_TYPER_PTA != 0 {
+            rd_paddr as u64 >> 16
+        } else {
+            processor as u64
+        };
+        let mut inner = self.inner.lock();
+        let dw2 = 1 << 63 | rd_base << 16 | COLLECTION_ID;
+        self.send(&mut inner, [CMD_MAPC, 0, dw2, 0]);
+        self.send(&mut inner, [CMD_SYNC, 0, rd_base << 16, 0]);
+    }
+
+    /// The address devices write to trigger MSIs.
+    pub fn translater(&self) -> PhysAddr {
+        self.translater
+    }
+
+    /// Translate the events from 0 of the device `device_id` into `lpis`.
+    pub fn map_events(&self, device_id: u32, lpis: Range<usize>) -> DeviceResult {
+        if lpis.len() > 1 << EVENT_ID_BITS {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mut inner = self.inner.lock();
+        if !inner.devices.contains_key(&device_id) {
+            let size = (1 << EVENT_ID_BITS) * self.itt_entry_size;
+            let (_, itt) = alloc_table(size, 256);
+            let dw0 = CMD_MAPD | (device_id as u64) << 32;
+            let dw1 = EVENT_ID_BITS as u64 - 1;
+            self.send(&mut inner, [dw0, dw1, 1 << 63 | itt as u64, 0]);
+            inner.devices.insert(device_id, itt);
+        }
+        for (event, lpi) in lpis.clone().enumerate() {
+            let dw0 = CMD_MAPTI | (device_id as u64) << 32;
+            let dw1 = event as u64 | (lpi as u64) << 32;
+            self.send(&mut inner, [dw0, dw1, COLLECTION_ID, 0]);
+        }
+        inner.blocks.insert(lpis.start, (device_id, lpis.len()));
+        Ok(())
+    }
+
+    /// Remove the translations into `lpis`, if any.
+    pub fn unmap_events(&self, lpis: Range<usize>) {
+        let mut inner = self.inner.lock();
+        if let Some((device_id, count)) = inner.blocks.remove(&lpis.start) {
+            for event in 0..count {
+                let dw0 = CMD_DISCARD | (device_id as u64) << 32;
+                self.send(&mut inner, [dw0, event as u64, 0, 0]);
+            }
+        }
+    }
+
+    /// Make the ITS reload the LPI configuration table.
+    pub fn invalidate_all(&self) {
+        let mut inner = self.inner.lock();
+        self.send(&mut inner, [CMD_INVALL, 0, COLLECTION_ID, 0]);
+    }
+
+    /// Write the command to the queue, and wait until the ITS has read it.
+    fn send(&self, inner: &mut ItsInner, cmd: [u64; 4]) {
+        let slot = (self.cmd_queue + inner.cmd_write) as *mut u64;
+        for (i, dw) in cmd.iter().enumerate() {
+            unsafe { write_volatile(slot.add(i), *dw) };
+        }
+        inner.cmd_write = (inner.cmd_write + CMD_SIZE) % CMD_QUEUE_SIZE;
+        unsafe { core::arch::asm!("dsb ishst") };
+        Self::write64(self.base, GITS_CWRITER, inner.cmd_write as u64);
+        while Self::read64(self.base, GITS_CREADR) as usize & 0xf_ffe0 != inner.cmd_write {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! ARM Generic Interrupt Controller version 3.
+//!
+//! The distributor routes SPIs with affinity routing, SGIs and PPIs are
+//! configured in the redistributor of each CPU, and the CPU interface is
+//! accessed through system registers. MSIs are translated into LPIs by the
+//! Interrupt Translation Service.
+//!
+//! Specification: <https://developer.arm.com/documentation/ihi0069/latest>.
+mod its;
+
+use self::its::{alloc_table, Its};
+use crate::bus::virt_to_phys;
+use crate::prelude::IrqHandler;
+use crate::scheme::{IrqScheme, Scheme};
+use crate::utils::IrqManager;
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+use alloc::collections::BTreeMap;
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use lock::Mutex;
+
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_IROUTER: usize = 0x6000;
+
+const GICD_CTLR_ENABLE_G0: u32 = 1 << 0;
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 1;
+const GICD_CTLR_ARE: u32 = 1 << 4;
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_TYPER_LPIS: u32 = 1 << 17;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_WAKER: usize = 0x0014;
+const GICR_TYPER: usize = 0x0008;
+const GICR_PROPBASER: usize = 0x0070;
+const GICR_PENDBASER: usize = 0x0078;
+const GICR_CTLR_ENABLE_LPIS: u32 = 1 << 0;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+/// Inner write-back read/write-allocate, inner shareable, in the format of
+/// `GICR_PROPBASER` and `GICR_PENDBASER`.
+const GICR_BASER_CACHE_SHARE: u64 = 7 << 7 | 1 << 10;
+const GICR_PENDBASER_PTZ: u64 = 1 << 62;
+
+/// Offset of the SGI and PPI frame in a redistributor.
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+
+/// Interrupt IDs from 1020 to 1023 are special.
+const MAX_IRQS: usize = 1020;
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// LPIs start from 8192, and those for MSIs are allocated from this range.
+const LPI_BASE: usize = 8192;
+const LPI_COUNT: usize = 1024;
+/// Interrupt ID bits, which cover `LPI_BASE..LPI_BASE + LPI_COUNT`.
+const LPI_ID_BITS: u32 = 14;
+const LPI_CONFIG_ENABLE: u8 = 1 << 0;
+
+/// LPIs translated from MSIs by the ITS.
+struct Lpi {
+    its: Its,
+    /// The configuration table shared by all redistributors, one byte per LPI.
+    config_table: VirtAddr,
+    config_paddr: PhysAddr,
+    manager: Mutex<IrqManager<LPI_COUNT>>,
+}
+
+pub struct IntController {
+    gicd_base: usize,
+    gicr_base: usize,
+    gicr_size: usize,
+    /// The redistributor of each initialized CPU, by affinity.
+    redists: Mutex<BTreeMap<u32, VirtAddr>>,
+    nirqs: usize,
+    manager: Mutex<IrqManager<1024>>,
+    lpi: Option<Lpi>,
+}
+
+impl IntController {
+    /// Create the driver with the distributor at `gicd_base` and the
+    /// redistributors of all CPUs in `gicr_base..gicr_base + gicr_size`.
+    ///
+    /// MSIs are supported if `its` gives the virtual and physical address of
+    /// an ITS, and the GIC supports LPIs.
+    pub fn new(
+        gicd_base: usize,
+        gicr_base: usize,
+        gicr_size: usize,
+        its: Option<(VirtAddr, PhysAddr)>,
+    ) -> Self {
+        let typer = unsafe { read_volatile((gicd_base + GICD_TYPER) as *const u32) };
+        let nirqs = (((typer & 0x1f) as usize + 1) * 32).min(MAX_IRQS);
+        let has_lpis = typer & GICD_TYPER_LPIS != 0 && (typer >> 19 & 0x1f) + 1 >= LPI_ID_BITS;
+        let lpi = match its {
+            Some((its_base, its_paddr)) if has_lpis => {
+                let (config_table, config_paddr) = alloc_table((1 << LPI_ID_BITS) - LPI_BASE, 0);
+                Some(Lpi {
+                    its: Its::new(its_base, its_paddr),
+                    config_table,
+                    config_paddr,
+                    manager: Mutex::new(IrqManager::new(0..LPI_COUNT)),
+                })
+            }
+            Some(_) => {
+                warn!("GICv3: LPIs are not supported, ignore the ITS");
+                None
+            }
+            None => None,
+        };
+        Self {
+            gicd_base,
+            gicr_base,
+            gicr_size,
+            redists: Mutex::new(BTreeMap::new()),
+            nirqs,
+            manager: Mutex::new(IrqManager::new(0..MAX_IRQS)),
+            lpi,
+        }
+    }
+
+    fn gicd_read(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.gicd_base + reg) as *const u32) }
+    }
+
+    fn gicd_write(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.gicd_base + reg) as *mut u32, value) }
+    }
+
+    fn gicd_wait_rwp(&self) {
+        while self.gicd_read(GICD_CTLR) & GICD_CTLR_RWP != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Find the redistributor of the CPU with `affinity` by scanning all frames.
+    fn find_gicr(&self, affinity: u32) -> DeviceResult<VirtAddr> {
+        let mut base = self.gicr_base;
+        while base < self.gicr_base + self.gicr_size {
+            let typer = unsafe { read_volatile((base + GICR_TYPER) as *const u64) };
+            if (typer >> 32) as u32 == affinity {
+                return Ok(base);
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                break;
+            }
+            // RD_base and SGI_base, plus VLPI_base and a reserved frame on GICv4
+            base += if typer & GICR_TYPER_VLPIS != 0 {
+                0x4_0000
+            } else {
+                0x2_0000
+            };
+        }
+        warn!("GICv3: no redistributor for affinity {:#x}", affinity);
+        Err(DeviceError::NotSupported)
+    }
+
+    /// The redistributor of the current CPU, cached by `init_cpu`.
+    fn gicr(&self) -> DeviceResult<VirtAddr> {
+        let affinity = cpu_affinity();
+        let redists = self.redists.lock();
+        redists.get(&affinity).copied().ok_or(DeviceError::NotReady)
+    }
+
+    fn gicr_read(gicr: VirtAddr, reg: usize) -> u32 {
+        unsafe { read_volatile((gicr + reg) as *const u32) }
+    }
+
+    fn gicr_write(gicr: VirtAddr, reg: usize, value: u32) {
+        unsafe { write_volatile((gicr + reg) as *mut u32, value) }
+    }
+
+    fn init_dist(&self) {
+        self.gicd_write(GICD_CTLR, 0);
+        self.gicd_wait_rwp();
+
+        let affinity = cpu_affinity() as u64;
+        let route = (affinity & 0xff_ffff) | (affinity & 0xff00_0000) << 8;
+        for irq in (32..self.nirqs).step_by(32) {
+            // Group 1, disabled
+            self.gicd_write(GICD_IGROUPR + irq / 8, 0xffff_ffff);
+            self.gicd_write(GICD_ICENABLER + irq / 8, 0xffff_ffff);
+        }
+        for irq in (32..self.nirqs).step_by(16) {
+            // Level triggered
+            self.gicd_write(GICD_ICFGR + irq / 4, 0);
+        }
+        for irq in 32..self.nirqs {
+            unsafe {
+                write_volatile(
+                    (self.gicd_base + GICD_IPRIORITYR + irq) as *mut u8,
+                    DEFAULT_PRIORITY,
+                );
+                write_volatile((self.gicd_base + GICD_IROUTER + irq * 8) as *mut u64, route);
+            }
+        }
+
+        self.gicd_write(GICD_CTLR, GICD_CTLR_ARE);
+        self.gicd_wait_rwp();
+        self.gicd_write(
+            GICD_CTLR,
+            GICD_CTLR_ARE | GICD_CTLR_ENABLE_G1 | GICD_CTLR_ENABLE_G0,
+        );
+        self.gicd_wait_rwp();
+    }
+
+    /// Initialize the redistributor and the CPU interface of the current CPU.
+    fn init_cpu(&self) -> DeviceResult {
+        let affinity = cpu_affinity();
+        let gicr = self.find_gicr(affinity)?;
+        self.redists.lock().insert(affinity, gicr);
+
+        // Wake up the redistributor
+        let waker = Self::gicr_read(gicr, GICR_WAKER);
+        Self::gicr_write(gicr, GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+        while Self::gicr_read(gicr, GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+            core::hint::spin_loop();
+        }
+
+        // SGIs and PPIs: group 1, disabled except SGIs
+        Self::gicr_write(gicr, GICR_IGROUPR0, 0xffff_ffff);
+        Self::gicr_write(gicr, GICR_ICENABLER0, 0xffff_0000);
+        Self::gicr_write(gicr, GICR_ISENABLER0, 0x0000_ffff);
+        for irq in 0..32 {
+            unsafe { write_volatile((gicr + GICR_IPRIORITYR + irq) as *mut u8, DEFAULT_PRIORITY) };
+        }
+        if let Some(lpi) = &self.lpi {
+            // the pending table is 64K aligned, with a bit per interrupt ID
+            let (_, pending_paddr) = alloc_table(1 << LPI_ID_BITS >> 3, 0x1_0000);
+            let propbaser =
+                lpi.config_paddr as u64 | GICR_BASER_CACHE_SHARE | (LPI_ID_BITS - 1) as u64;
+            let pendbaser = pending_paddr as u64 | GICR_BASER_CACHE_SHARE | GICR_PENDBASER_PTZ;
+            unsafe {
+                write_volatile((gicr + GICR_PROPBASER) as *mut u64, propbaser);
+                write_volatile((gicr + GICR_PENDBASER) as *mut u64, pendbaser);
+            }
+            let ctlr = Self::gicr_read(gicr, GICR_CTLR);
+            Self::gicr_write(gicr, GICR_CTLR, ctlr | GICR_CTLR_ENABLE_LPIS);
+        }
+
+        unsafe {
+            // ICC_SRE_EL1.SRE: use the system register interface
+            let sre: u64;
+            core::arch::asm!("mrs {}, S3_0_C12_C12_5", out(reg) sre);
+            core::arch::asm!("msr S3_0_C12_C12_5, {}", in(reg) sre | 1);
+            core::arch::asm!("isb");
+            // ICC_PMR_EL1: accept all priorities
+            core::arch::asm!("msr S3_0_C4_C6_0, {}", in(reg) 0xff_u64);
+            // ICC_BPR1_EL1: no preemption groups
+            core::arch::asm!("msr S3_0_C12_C12_3, {}", in(reg) 0_u64);
+            // ICC_IGRPEN1_EL1: enable group 1 interrupts
+            core::arch::asm!("msr S3_0_C12_C12_7, {}", in(reg) 1_u64);
+            core::arch::asm!("isb");
+        }
+        Ok(())
+    }
+
+    /// Route all LPIs to the current CPU.
+    fn init_its(&self) -> DeviceResult {
+        if let Some(lpi) = &self.lpi {
+            let gicr = self.gicr()?;
+            let typer = unsafe { read_volatile((gicr + GICR_TYPER) as *const u64) };
+            lpi.its
+                .map_collection(virt_to_phys(gicr), (typer >> 8 & 0xffff) as usize);
+        }
+        Ok(())
+    }
+
+    /// Set the enable bit of LPIs in the configuration table.
+    fn lpi_toggle(&self, lpi: &Lpi, irqs: Range<usize>, enable: bool) {
+        for irq in irqs {
+            let config = (lpi.config_table + irq - LPI_BASE) as *mut u8;
+            let value = if enable {
+                DEFAULT_PRIORITY | LPI_CONFIG_ENABLE
+            } else {
+                DEFAULT_PRIORITY
+            };
+            unsafe { write_volatile(config, value) };
+        }
+        unsafe { core::arch::asm!("dsb ishst") };
+        lpi.its.invalidate_all();
+    }
+
+    /// The LPI state, if `irqs` are all valid LPIs.
+    fn lpi_of(&self, irqs: &Range<usize>) -> DeviceResult<&Lpi> {
+        match &self.lpi {
+            Some(lpi) if irqs.start >= LPI_BASE && irqs.end <= LPI_BASE + LPI_COUNT => Ok(lpi),
+            _ => Err(DeviceError::InvalidParam),
+        }
+    }
+
+    pub fn irq_enable(&self, irq: u32) -> DeviceResult {
+        let irq = irq as usize;
+        if irq < 32 {
+            Self::gicr_write(self.gicr()?, GICR_ISENABLER0, 1 << irq);
+        } else {
+            self.gicd_write(GICD_ISENABLER + irq / 32 * 4, 1 << (irq % 32));
+        }
+        Ok(())
+    }
+
+    pub fn irq_disable(&self, irq: u32) -> DeviceResult {
+        let irq = irq as usize;
+        if irq < 32 {
+            Self::gicr_write(self.gicr()?, GICR_ICENABLER0, 1 << irq);
+        } else {
+            self.gicd_write(GICD_ICENABLER + irq / 32 * 4, 1 << (irq % 32));
+        }
+        Ok(())
+    }
+
+    pub fn irq_eoi(&self, irq: u32) {
+        // ICC_EOIR1_EL1
+        unsafe { core::arch::asm!("msr S3_0_C12_C12_1, {}", in(reg) irq as u64) };
+    }
+
+    pub fn pending_irq(&self) -> usize {
+        // ICC_IAR1_EL1
+        let iar: u64;
+        unsafe { core::arch::asm!("mrs {}, S3_0_C12_C12_0", out(reg) iar) };
+        let irq = (iar & 0xff_ffff) as usize;
+        if (MAX_IRQS..LPI_BASE).contains(&irq) {
+            usize::MAX
+        } else {
+            irq
+        }
+    }
+}
+
+/// Affinity of the current CPU in the format of `GICR_TYPER[63:32]`.
+fn cpu_affinity() -> u32 {
+    let mpidr: u64;
+    unsafe { core::arch::asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    ((mpidr & 0xff_ffff) | (mpidr >> 32 & 0xff) << 24) as u32
+}
+
+impl Scheme for IntController {
+    fn name(&self) -> &str {
+        "ARM Generic Interrupt Controller v3"
+    }
+
+    fn handle_irq(&self, irq_num: usize) {
+        if irq_num == usize::MAX {
+            return;
+        }
+        if irq_num >= LPI_BASE {
+            if let Some(lpi) = &self.lpi {
+                lpi.manager.lock().handle(irq_num - LPI_BASE).ok();
+            }
+        } else {
+            self.manager.lock().handle(irq_num).ok();
+        }
+        self.irq_eoi(irq_num as u32);
+    }
+}
+
+impl IrqScheme for IntController {
+    fn is_valid_irq(&self, irq_num: usize) -> bool {
+        irq_num < self.nirqs || self.lpi_of(&(irq_num..irq_num + 1)).is_ok()
+    }
+
+    fn mask(&self, irq_num: usize) -> DeviceResult {
+        if irq_num >= LPI_BASE {
+            let irqs = irq_num..irq_num + 1;
+            self.lpi_toggle(self.lpi_of(&irqs)?, irqs, false);
+        } else {
+            self.irq_disable(irq_num as u32)?;
+        }
+        Ok(())
+    }
+
+    fn unmask(&self, irq_num: usize) -> DeviceResult {
+        if irq_num >= LPI_BASE {
+            let irqs = irq_num..irq_num + 1;
+            self.lpi_toggle(self.lpi_of(&irqs)?, irqs, true);
+        } else {
+            self.irq_enable(irq_num as u32)?;
+        }
+        Ok(())
+    }
+
+    fn register_handler(&self, irq_num: usize, handler: IrqHandler) -> DeviceResult {
+        self.manager
+            .lock()
+            .register_handler(irq_num, handler)
+            .map(|_| ())
+    }
+
+    fn unregister(&self, irq_num: usize) -> DeviceResult {
+        self.manager.lock().unregister_handler(irq_num)
+    }
+
+    fn msi_alloc_block(&self, requested_irqs: usize) -> DeviceResult<Range<usize>> {
+        let lpi = self.lpi.as_ref().ok_or(DeviceError::NotSupported)?;
+        let alloc_size = requested_irqs.next_power_of_two();
+        let start = lpi.manager.lock().alloc_block(alloc_size)? + LPI_BASE;
+        Ok(start..start + alloc_size)
+    }
+
+    fn msi_free_block(&self, block: Range<usize>) -> DeviceResult {
+        let lpi = self.lpi_of(&block)?;
+        lpi.its.unmap_events(block.clone());
+        self.lpi_toggle(lpi, block.clone(), false);
+        lpi.manager
+            .lock()
+            .free_block(block.start - LPI_BASE, block.len())
+    }
+
+    fn msi_register_handler(
+        &self,
+        block: Range<usize>,
+        msi_id: usize,
+        handler: IrqHandler,
+    ) -> DeviceResult {
+        let lpi = self.lpi_of(&block)?;
+        if msi_id < block.len() {
+            lpi.manager
+                .lock()
+                .overwrite_handler(block.start - LPI_BASE + msi_id, handler)
+        } else {
+            Err(DeviceError::InvalidParam)
+        }
+    }
+
+    fn msi_target(&self, block: Range<usize>, requester_id: usize) -> DeviceResult<(u64, u32)> {
+        let lpi = self.lpi_of(&block)?;
+        lpi.its.map_events(requester_id as u32, block.clone())?;
+        self.lpi_toggle(lpi, block, true);
+        // the data is the event ID, which starts from 0 for each block
+        Ok((lpi.its.translater() as u64, 0))
+    }
+
+    fn pending_irq(&self) -> usize {
+        IntController::pending_irq(self)
+    }
+
+    fn send_sgi(&self, cpu_id: usize, irq_num: usize) -> DeviceResult {
+        // ICC_SGI1R_EL1: the INTID, and the target list of Aff0 in the Aff3.Aff2.Aff1 cluster
+        let cpu_id = cpu_id as u64;
+        let aff0 = cpu_id & 0xf;
+        let aff1 = cpu_id >> 8 & 0xff;
+        let aff2 = cpu_id >> 16 & 0xff;
+        let aff3 = cpu_id >> 32 & 0xff;
+        let intid = irq_num as u64 & 0xf;
+        let sgi1r = aff3 << 48 | aff2 << 32 | intid << 24 | aff1 << 16 | 1 << aff0;
+        unsafe {
+            core::arch::asm!("msr S3_0_C12_C11_5, {}", in(reg) sgi1r);
+            core::arch::asm!("isb");
+        }
+        Ok(())
+    }
+
+    fn init_hart(&self) {
+        if let Err(err) = self.init_cpu() {
+            warn!("GICv3: failed to initialize the current CPU: {:?}", err);
+        }
+    }
+}
+
+/// Initialize the distributor, and the redistributor and CPU interface of the
+/// current CPU. Other CPUs are initialized by [`IrqScheme::init_hart`].
+///
+/// LPIs from the ITS, if any, are delivered to the current CPU.
+pub fn init(
+    gicd_base: usize,
+    gicr_base: usize,
+    gicr_size: usize,
+    its: Option<(VirtAddr, PhysAddr)>,
+) -> DeviceResult<IntController> {
+    let controller = IntController::new(gicd_base, gicr_base, gicr_size, its);
+    controller.init_dist();
+    controller.init_cpu()?;
+    controller.init_its()?;
+    Ok(controller)
+}
@@ -20,5 +20,6 @@ cfg_if::cfg_if! {
         }
     } else if #[cfg(target_arch = "aarch64")] {
         pub mod gic_400;
+        pub mod gic_v3;
     }
 }
@@ -1,42 +1,11 @@
 use alloc::vec::Vec;
-use core::{fmt, ptr::NonNull};
+use core::fmt;
 
-use acpi::platform::interrupt::InterruptModel;
-use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
 use lock::Mutex;
 use x2apic::ioapic::{IoApic as IoApicInner, IrqFlags, IrqMode};
 
 use super::{IrqPolarity, IrqTriggerMode, Phys2VirtFn};
-
-const PAGE_SIZE: usize = 4096;
-
-#[derive(Clone)]
-struct AcpiMapHandler {
-    phys_to_virt: Phys2VirtFn,
-}
-
-impl AcpiHandler for AcpiMapHandler {
-    /// we just impl this function, `rdsp` crate will use it, and we not.
-    unsafe fn map_physical_region<T>(
-        &self,
-        physical_address: usize,
-        size: usize,
-    ) -> PhysicalMapping<Self, T> {
-        // address maybe not aligned, so we align it mannualy
-        let aligned_start = physical_address & !(PAGE_SIZE - 1);
-        let aligned_end = (physical_address + size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
-        PhysicalMapping::new(
-            physical_address,
-            NonNull::new_unchecked((self.phys_to_virt)(physical_address) as *mut T),
-            size,
-            aligned_end - aligned_start,
-            self.clone(),
-        )
-    }
-
-    /// we do nothing here
-    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
-}
+use crate::utils::acpi::AcpiIoApic;
 
 /// An I/O APIC structure.
 ///
@@ -132,7 +101,7 @@ impl IoApic {
         entry.set_dest(dest);
 
         let mut flags = IrqFlags::MASKED; // destination mode: physical
-        if matches!(tm, IrqTriggerMode::Edge) {
+        if matches!(tm, IrqTriggerMode::Level) {
             flags |= IrqFlags::LEVEL_TRIGGERED;
         }
         if matches!(pol, IrqPolarity::ActiveLow) {
@@ -145,27 +114,13 @@ impl IoApic {
 }
 
 impl IoApicList {
-    /// Probe all I/O APICs from the ACPI table represented by `acpi_rsdp`.
-    pub fn new(acpi_rsdp: usize, phys_to_virt: Phys2VirtFn) -> Self {
-        let handler = AcpiMapHandler { phys_to_virt };
-        // parse ACPI table by the physical address of the RSDP.
-        let tables = unsafe { AcpiTables::from_rsdp(handler, acpi_rsdp).unwrap() };
-        let io_apics =
-            if let InterruptModel::Apic(apic) = tables.platform_info().unwrap().interrupt_model {
-                apic.io_apics
-                    .iter()
-                    .map(|i| {
-                        IoApic::new(
-                            i.id,
-                            phys_to_virt(i.address as usize),
-                            i.global_system_interrupt_base,
-                        )
-                    })
-                    .collect()
-            } else {
-                // only legacy i8259 PIC is present
-                Vec::new()
-            };
+    /// Initialize all I/O APICs found in the ACPI MADT.
+    pub fn new(io_apics: &[AcpiIoApic], phys_to_virt: Phys2VirtFn) -> Self {
+        // if empty, only legacy i8259 PIC is present
+        let io_apics = io_apics
+            .iter()
+            .map(|i| IoApic::new(i.id, phys_to_virt(i.address), i.gsi_base))
+            .collect();
         Self { io_apics }
     }
 
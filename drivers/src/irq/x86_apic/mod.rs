@@ -7,7 +7,9 @@ use self::ioapic::{IoApic, IoApicList};
 use self::lapic::LocalApic;
 use crate::prelude::{IrqHandler, IrqPolarity, IrqTriggerMode};
 use crate::scheme::{IrqScheme, Scheme};
+use crate::utils::acpi::{AcpiIrqOverride, AcpiTables};
 use crate::{utils::IrqManager, DeviceError, DeviceResult, PhysAddr, VirtAddr};
+use alloc::vec::Vec;
 use core::ops::Range;
 use lock::Mutex;
 
@@ -19,18 +21,38 @@ type Phys2VirtFn = fn(paddr: PhysAddr) -> VirtAddr;
 /// Advanced Programmable Interrupt Controller
 pub struct Apic {
     ioapic_list: IoApicList,
+    irq_overrides: Vec<AcpiIrqOverride>,
     manager_ioapic: Mutex<IrqManager<256>>,
     manager_lapic: Mutex<IrqManager<16>>,
 }
 
 impl Apic {
-    /// Construct a new `Apic`.
-    pub fn new(acpi_rsdp: usize, phys_to_virt: Phys2VirtFn) -> Self {
-        Self {
-            ioapic_list: IoApicList::new(acpi_rsdp, phys_to_virt),
+    /// Construct a new `Apic` with I/O APICs and interrupt source overrides
+    /// in the ACPI MADT.
+    pub fn new(acpi: &AcpiTables, phys_to_virt: Phys2VirtFn) -> Self {
+        let apic = Self {
+            ioapic_list: IoApicList::new(&acpi.io_apics, phys_to_virt),
+            irq_overrides: acpi.irq_overrides.clone(),
             manager_ioapic: Mutex::new(IrqManager::new(IOAPIC_IRQ_RANGE)),
             manager_lapic: Mutex::new(IrqManager::new(LAPIC_IRQ_RANGE)),
+        };
+        for o in apic.irq_overrides.iter() {
+            // ISA IRQs are edge triggered and active high by default
+            if o.trigger_mode().is_some() || o.polarity().is_some() {
+                let tm = o.trigger_mode().unwrap_or(IrqTriggerMode::Edge);
+                let pol = o.polarity().unwrap_or(IrqPolarity::ActiveHigh);
+                apic.configure(o.gsi as _, tm, pol).ok();
+            }
         }
+        apic
+    }
+
+    /// Returns the GSI which the ISA IRQ is connected to.
+    pub fn isa_irq_to_gsi(&self, isa_irq: usize) -> usize {
+        self.irq_overrides
+            .iter()
+            .find(|o| o.isa_irq as usize == isa_irq)
+            .map_or(isa_irq, |o| o.gsi as usize)
     }
 
     fn with_ioapic<F>(&self, gsi: u32, op: F) -> DeviceResult
@@ -169,6 +191,11 @@ impl IrqScheme for Apic {
         }
     }
 
+    fn msi_target(&self, block: Range<usize>, _requester_id: usize) -> DeviceResult<(u64, u32)> {
+        // the local APIC of the BSP, in physical destination mode
+        Ok(((0xFEE0_0000 | 0x08) & !0x4, block.start as u32))
+    }
+
     fn apic_timer_enable(&self) {
         // SAFETY: this will called only once for every core
         Apic::local_apic().enable_timer();
@@ -20,6 +20,7 @@ pub mod mock;
 #[doc(cfg(feature = "virtio"))]
 pub mod virtio;
 
+pub mod ahci;
 pub mod builder;
 pub mod bus;
 pub mod display;
@@ -29,8 +30,10 @@ pub mod irq;
 pub mod net;
 pub mod nvme;
 pub mod prelude;
+pub mod rtc;
 pub mod scheme;
 pub mod uart;
+pub mod usb;
 pub mod utils;
 
 /// The error type for external device.
@@ -70,8 +73,14 @@ pub enum Device {
     Irq(Arc<dyn scheme::IrqScheme>),
     /// Network device
     Net(Arc<dyn scheme::NetScheme>),
+    /// Random number generator
+    Rng(Arc<dyn scheme::RngScheme>),
+    /// Real-time clock
+    Rtc(Arc<dyn scheme::RtcScheme>),
     /// Uart port
     Uart(Arc<dyn scheme::UartScheme>),
+    /// Virtual socket transport between the guest and the host
+    Vsock(Arc<dyn scheme::VsockScheme>),
 }
 
 impl Device {
@@ -83,7 +92,10 @@ impl Device {
             Self::Input(d) => d.clone().upcast(),
             Self::Irq(d) => d.clone().upcast(),
             Self::Net(d) => d.clone().upcast(),
+            Self::Rng(d) => d.clone().upcast(),
+            Self::Rtc(d) => d.clone().upcast(),
             Self::Uart(d) => d.clone().upcast(),
+            Self::Vsock(d) => d.clone().upcast(),
         }
     }
 }
@@ -96,7 +108,10 @@ impl fmt::Debug for Device {
             Self::Input(d) => write!(f, "InputDevice({:?})", d.name()),
             Self::Irq(d) => write!(f, "IrqDevice({:?})", d.name()),
             Self::Net(d) => write!(f, "NetDevice({:?})", d.name()),
+            Self::Rng(d) => write!(f, "RngDevice({:?})", d.name()),
+            Self::Rtc(d) => write!(f, "RtcDevice({:?})", d.name()),
             Self::Uart(d) => write!(f, "UartDevice({:?})", d.name()),
+            Self::Vsock(d) => write!(f, "VsockDevice({:?})", d.name()),
         }
     }
 }
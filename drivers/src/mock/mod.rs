@@ -1,7 +1,8 @@
-//! Mock devices, including display, input, uart and graphic.
+//! Mock devices, including display, input, uart, net and graphic.
 
 pub mod display;
 pub mod input;
+pub mod net;
 pub mod uart;
 
 #[cfg(any(feature = "graphic", doc))]
@@ -0,0 +1,281 @@
+//! Host networking for libos mode.
+//!
+//! A [`MockNet`] interface exchanges Ethernet frames with the host through a
+//! [`FrameBackend`]:
+//!
+//! - [`tap::TapBackend`]: a Linux TAP device, which needs `CAP_NET_ADMIN`, or
+//!   a persistent TAP device owned by the user.
+//! - [`slirp::SlirpBackend`]: an unprivileged userspace NAT in the style of
+//!   slirp, which proxies TCP and UDP through host sockets.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use alloc::sync::{Arc, Weak};
+use alloc::{format, string::String, vec, vec::Vec};
+use async_std::task;
+use lock::Mutex;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address};
+
+use crate::net::capture::{Capture, FrameSink};
+use crate::net::hooked::HookedDevice;
+use crate::net::{add_iface_route, del_iface_route};
+use crate::net::{get_sockets, iface_config, iface_ipv6_config, timer_now_as_micros};
+use crate::net::{iface_ipv4_gateway, set_iface_ip_addrs, set_iface_ipv4_gateway};
+use crate::net::{iface_ipv6_gateway, set_iface_ipv6_gateway};
+use crate::net::{join_iface_multicast_group, leave_iface_multicast_group};
+use crate::scheme::{NetScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+pub mod slirp;
+#[cfg(target_os = "linux")]
+pub mod tap;
+
+/// Maximum size of an Ethernet frame without FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// How often the interface is polled in the background.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Number of created interfaces, to name them and assign addresses.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// A way to exchange Ethernet frames with the host.
+pub trait FrameBackend: Send + Sync {
+    /// Take a frame for the interface, if any.
+    fn recv_frame(&self) -> Option<Vec<u8>>;
+
+    /// Send a frame from the interface.
+    fn send_frame(&self, frame: &[u8]) -> DeviceResult;
+
+    /// Do the work of the host side, called before every poll of the
+    /// interface.
+    fn poll(&self) {}
+}
+
+#[derive(Clone)]
+pub struct MockNetDriver(Arc<dyn FrameBackend>);
+
+pub struct MockNet {
+    iface: Mutex<Interface<'static, HookedDevice<MockNetDriver>>>,
+    driver: MockNetDriver,
+    capture: Arc<Capture>,
+    name: String,
+}
+
+impl MockNet {
+    /// Create an interface over `backend`, named `ethN`.
+    ///
+    /// The addresses are assigned by [`iface_config`](crate::net::iface_config) and
+    /// [`iface_ipv6_config`](crate::net::iface_ipv6_config).
+    pub fn new(backend: Arc<dyn FrameBackend>) -> DeviceResult<Self> {
+        let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        let name = format!("eth{}", index);
+        // the default MAC address of QEMU
+        let mac = EthernetAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56 + index as u8]);
+        let driver = MockNetDriver(backend);
+        let capture = Arc::new(Capture::default());
+
+        let (ip, gateway) = iface_config(index);
+        let (ipv6_addrs, ipv6_gateway) = iface_ipv6_config(index, mac);
+        let mut routes = Routes::new(BTreeMap::new());
+        if let Some(gateway) = gateway {
+            routes
+                .add_default_ipv4_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        if let Some(gateway) = ipv6_gateway {
+            routes
+                .add_default_ipv6_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        let mut ip_addrs = vec![ip];
+        ip_addrs.extend(ipv6_addrs);
+        let device = HookedDevice::new(driver.clone(), &name, capture.clone());
+        let iface = InterfaceBuilder::new(device)
+            .ethernet_addr(mac)
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .ip_addrs(ip_addrs)
+            .routes(routes)
+            .ipv4_multicast_groups(BTreeMap::new())
+            .finalize();
+        info!(
+            "mock-net interface {} up with mac {} addr {}",
+            name, mac, ip
+        );
+
+        Ok(Self {
+            iface: Mutex::new(iface),
+            driver,
+            capture,
+            name,
+        })
+    }
+
+    /// Call `irq_handler` periodically in the background, as the host has no
+    /// interrupts to tell frames have arrived.
+    pub fn start_irq_service(irq_handler: impl Fn() + Send + Sync + 'static) {
+        task::spawn(async move {
+            loop {
+                irq_handler();
+                task::sleep(POLL_INTERVAL).await;
+            }
+        });
+    }
+}
+
+impl Scheme for MockNet {
+    fn name(&self) -> &str {
+        "mock-net"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        self.poll().ok();
+    }
+}
+
+impl NetScheme for MockNet {
+    fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let frame = self.driver.0.recv_frame().ok_or(DeviceError::NotReady)?;
+        self.capture.deliver(&frame, false);
+        if frame.len() > buf.len() {
+            return Err(DeviceError::BufferTooSmall);
+        }
+        buf[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    fn send(&self, buf: &[u8]) -> DeviceResult<usize> {
+        if buf.len() > MAX_FRAME_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.driver.0.send_frame(buf)?;
+        self.capture.deliver(buf, true);
+        Ok(buf.len())
+    }
+
+    fn attach_capture(&self, sink: Weak<dyn FrameSink>) -> DeviceResult {
+        self.capture.attach(sink);
+        Ok(())
+    }
+
+    /// Backends pass all frames to the interface.
+    fn set_promiscuous(&self, _enable: bool) -> DeviceResult {
+        Ok(())
+    }
+
+    fn get_mac(&self) -> EthernetAddress {
+        self.iface.lock().ethernet_addr()
+    }
+
+    fn get_ifname(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_ip_address(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn set_ip_address(&self, addrs: Vec<IpCidr>) -> DeviceResult {
+        set_iface_ip_addrs(&mut self.iface.lock(), addrs);
+        Ok(())
+    }
+
+    fn get_ipv4_gateway(&self) -> Option<Ipv4Address> {
+        iface_ipv4_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv4_gateway(&self, gateway: Option<Ipv4Address>) -> DeviceResult {
+        set_iface_ipv4_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn get_ipv6_gateway(&self) -> Option<Ipv6Address> {
+        iface_ipv6_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv6_gateway(&self, gateway: Option<Ipv6Address>) -> DeviceResult {
+        set_iface_ipv6_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> DeviceResult {
+        add_iface_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> DeviceResult {
+        del_iface_route(&mut self.iface.lock(), dst)
+    }
+
+    fn join_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        join_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn leave_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        leave_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn poll(&self) -> DeviceResult {
+        // the host side must not run with the sockets locked, it may block
+        self.driver.0.poll();
+        let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+        let sockets = get_sockets();
+        let mut sockets = sockets.lock();
+        match self.iface.lock().poll(&mut sockets, timestamp) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                debug!("mock-net poll got err {}", err);
+                Err(DeviceError::IoError)
+            }
+        }
+    }
+}
+
+pub struct MockNetRxToken(Vec<u8>);
+pub struct MockNetTxToken(MockNetDriver);
+
+impl phy::Device<'_> for MockNetDriver {
+    type RxToken = MockNetRxToken;
+    type TxToken = MockNetTxToken;
+
+    fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.0.recv_frame()?;
+        Some((MockNetRxToken(frame), MockNetTxToken(self.clone())))
+    }
+
+    fn transmit(&mut self) -> Option<Self::TxToken> {
+        Some(MockNetTxToken(self.clone()))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_SIZE;
+        caps
+    }
+}
+
+impl phy::RxToken for MockNetRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for MockNetTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let result = f(&mut buffer[..len])?;
+        (self.0)
+            .0
+            .send_frame(&buffer[..len])
+            .map_err(|_| smoltcp::Error::Exhausted)?;
+        Ok(result)
+    }
+}
@@ -0,0 +1,542 @@
+//! Userspace NAT backend, in the style of slirp and the QEMU user network.
+//!
+//! Frames of the interface are handled by a second smoltcp stack, the NAT,
+//! which is the gateway `10.0.2.2` and takes every connection of the guest:
+//!
+//! - TCP connections are proxied by host `TcpStream`s. The SYN of the guest is
+//!   held until the host connection is made, and the connection is reset if
+//!   the host refuses it.
+//! - UDP datagrams are relayed by host `UdpSocket`s, one for each guest
+//!   endpoint and destination.
+//! - `10.0.2.2` is the loopback of the host, and `10.0.2.3:53` the first name
+//!   server in `/etc/resolv.conf`.
+//!
+//! No privilege is needed, but only IPv4 is supported, the interface must be
+//! in `10.0.2.0/24`, and ICMP echo requests are answered by the NAT itself.
+
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, UdpSocket as HostUdpSocket};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+
+use alloc::sync::Arc;
+use alloc::{vec, vec::Vec};
+use lock::Mutex;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::socket::{SocketHandle, SocketSet, TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, EthernetFrame, EthernetProtocol, IpAddress, IpCidr};
+use smoltcp::wire::{IpEndpoint, IpProtocol, Ipv4Address, Ipv4Cidr, Ipv4Packet};
+use smoltcp::wire::{TcpPacket, UdpPacket};
+
+use super::{FrameBackend, MockNetRxToken, MAX_FRAME_SIZE};
+use crate::net::timer_now_as_micros;
+use crate::{DeviceError, DeviceResult};
+
+/// The address of the NAT, mapped to the host loopback.
+const GATEWAY: Ipv4Address = Ipv4Address([10, 0, 2, 2]);
+/// The address of the DNS server, mapped to the name server of the host.
+const DNS: Ipv4Address = Ipv4Address([10, 0, 2, 3]);
+const DNS_PORT: u16 = 53;
+const PREFIX_LEN: u8 = 24;
+/// The MAC address of the NAT, as in QEMU.
+const NAT_MAC: EthernetAddress = EthernetAddress([0x52, 0x55, 0x0a, 0x00, 0x02, 0x02]);
+
+/// Maximum number of frames queued in each direction.
+const QUEUE_LEN: usize = 256;
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_METADATA_LEN: usize = 64;
+/// Maximum UDP payload in a frame, without IP fragmentation.
+const MAX_UDP_PAYLOAD: usize = MAX_FRAME_SIZE - 14 - 20 - 8;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// UDP relays are closed after this long without traffic.
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The userspace NAT.
+pub struct SlirpBackend {
+    /// Frames sent by the interface, not yet seen by the NAT.
+    from_guest: Mutex<VecDeque<Vec<u8>>>,
+    /// Frames sent by the NAT to the interface.
+    to_guest: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    nat: Mutex<Nat>,
+}
+
+impl SlirpBackend {
+    pub fn new() -> Self {
+        let to_guest = Arc::new(Mutex::new(VecDeque::new()));
+        Self {
+            from_guest: Mutex::new(VecDeque::new()),
+            nat: Mutex::new(Nat::new(to_guest.clone())),
+            to_guest,
+        }
+    }
+}
+
+impl Default for SlirpBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameBackend for SlirpBackend {
+    fn recv_frame(&self) -> Option<Vec<u8>> {
+        self.to_guest.lock().pop_front()
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> DeviceResult {
+        let mut queue = self.from_guest.lock();
+        if queue.len() >= QUEUE_LEN {
+            return Err(DeviceError::NotReady);
+        }
+        queue.push_back(frame.to_vec());
+        Ok(())
+    }
+
+    fn poll(&self) {
+        let mut nat = self.nat.lock();
+        // taken with the NAT locked, so that frames are handled in order
+        let frames = core::mem::take(&mut *self.from_guest.lock());
+        nat.poll(frames);
+    }
+}
+
+/// A TCP connection being made on the host.
+struct PendingTcp {
+    /// The SYN of the guest, passed to the NAT when the connection is made.
+    syn: Vec<u8>,
+    connected: Receiver<io::Result<TcpStream>>,
+    result: Option<io::Result<TcpStream>>,
+}
+
+/// A TCP connection of the guest proxied by a host connection.
+struct TcpFlow {
+    handle: SocketHandle,
+    guest: IpEndpoint,
+    dst: IpEndpoint,
+    stream: TcpStream,
+    /// The host closed the connection.
+    host_eof: bool,
+    /// The guest closed the connection.
+    guest_eof: bool,
+}
+
+/// UDP datagrams of the guest to a destination.
+struct UdpFlow {
+    /// The NAT socket bound to the destination.
+    handle: SocketHandle,
+    host: SocketAddr,
+    /// Host sockets for each guest endpoint.
+    relays: BTreeMap<IpEndpoint, UdpRelay>,
+}
+
+struct UdpRelay {
+    socket: HostUdpSocket,
+    last_used: std::time::Instant,
+}
+
+struct Nat {
+    iface: Interface<'static, Pipe>,
+    sockets: SocketSet<'static>,
+    /// Frames for the NAT stack.
+    to_host: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    /// (guest, destination) -> connection
+    pending: BTreeMap<(IpEndpoint, IpEndpoint), PendingTcp>,
+    tcp: Vec<TcpFlow>,
+    /// destination -> datagrams
+    udp: BTreeMap<IpEndpoint, UdpFlow>,
+    dns: Option<Ipv4Addr>,
+}
+
+impl Nat {
+    fn new(to_guest: Arc<Mutex<VecDeque<Vec<u8>>>>) -> Self {
+        let to_host = Arc::new(Mutex::new(VecDeque::new()));
+        let pipe = Pipe {
+            rx: to_host.clone(),
+            tx: to_guest,
+        };
+        // with `any_ip`, packets routed via the NAT itself are taken whatever
+        // their destination
+        let mut routes = Routes::new(BTreeMap::new());
+        routes.add_default_ipv4_route(GATEWAY).unwrap();
+        let iface = InterfaceBuilder::new(pipe)
+            .ethernet_addr(NAT_MAC)
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .ip_addrs(vec![
+                IpCidr::new(GATEWAY.into(), PREFIX_LEN),
+                IpCidr::new(DNS.into(), PREFIX_LEN),
+            ])
+            .routes(routes)
+            .any_ip(true)
+            .finalize();
+        let dns = host_name_server();
+        info!("slirp: gateway {} dns {} -> {:?}", GATEWAY, DNS, dns);
+        Self {
+            iface,
+            sockets: SocketSet::new(vec![]),
+            to_host,
+            pending: BTreeMap::new(),
+            tcp: Vec::new(),
+            udp: BTreeMap::new(),
+            dns,
+        }
+    }
+
+    fn poll(&mut self, frames: Vec<Vec<u8>>) {
+        self.connect_pending();
+        for frame in frames {
+            if self.filter(&frame) {
+                self.to_host.lock().push_back(frame);
+            }
+        }
+        self.poll_iface();
+        self.proxy_tcp();
+        self.relay_udp();
+        // send what the host has replied
+        self.poll_iface();
+    }
+
+    fn poll_iface(&mut self) {
+        let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+        if let Err(err) = self.iface.poll(&mut self.sockets, timestamp) {
+            debug!("slirp poll got err {}", err);
+        }
+    }
+
+    /// The host address of `endpoint`, `None` if it's not reachable.
+    fn host_addr(&self, endpoint: IpEndpoint) -> Option<SocketAddr> {
+        let addr = match endpoint.addr {
+            IpAddress::Ipv4(addr) => addr,
+            _ => return None,
+        };
+        let ip = if addr == GATEWAY {
+            Ipv4Addr::LOCALHOST
+        } else if addr == DNS && endpoint.port == DNS_PORT {
+            self.dns?
+        } else if addr.is_unicast() && !Ipv4Cidr::new(GATEWAY, PREFIX_LEN).contains_addr(&addr) {
+            Ipv4Addr::from(addr.0)
+        } else {
+            return None;
+        };
+        Some(SocketAddr::new(ip.into(), endpoint.port))
+    }
+
+    /// Prepare the NAT for a frame of the guest, returns whether to pass it to
+    /// the NAT stack now.
+    fn filter(&mut self, frame: &[u8]) -> bool {
+        let (protocol, guest, dst, syn) = match parse_frame(frame) {
+            Some(parsed) => parsed,
+            None => return true,
+        };
+        match protocol {
+            IpProtocol::Tcp if syn => {
+                let key = (guest, dst);
+                if self.tcp.iter().any(|flow| (flow.guest, flow.dst) == key) {
+                    // retransmitted to the NAT socket
+                    return true;
+                }
+                if self.pending.contains_key(&key) {
+                    return false;
+                }
+                let host = match self.host_addr(dst) {
+                    Some(host) => host,
+                    // reset by the NAT
+                    None => return true,
+                };
+                let (tx, rx) = mpsc::channel();
+                std::thread::spawn(move || {
+                    let result = TcpStream::connect_timeout(&host, CONNECT_TIMEOUT)
+                        .and_then(|stream| stream.set_nonblocking(true).map(|_| stream));
+                    tx.send(result).ok();
+                });
+                let pending = PendingTcp {
+                    syn: frame.to_vec(),
+                    connected: rx,
+                    result: None,
+                };
+                self.pending.insert(key, pending);
+                false
+            }
+            IpProtocol::Udp if !self.udp.contains_key(&dst) => {
+                if let Some(host) = self.host_addr(dst) {
+                    let mut socket = UdpSocket::new(
+                        UdpSocketBuffer::new(
+                            vec![UdpPacketMetadata::EMPTY; UDP_METADATA_LEN],
+                            vec![0; UDP_BUFFER_SIZE],
+                        ),
+                        UdpSocketBuffer::new(
+                            vec![UdpPacketMetadata::EMPTY; UDP_METADATA_LEN],
+                            vec![0; UDP_BUFFER_SIZE],
+                        ),
+                    );
+                    if socket.bind(dst).is_ok() {
+                        let flow = UdpFlow {
+                            handle: self.sockets.add(socket),
+                            host,
+                            relays: BTreeMap::new(),
+                        };
+                        self.udp.insert(dst, flow);
+                    }
+                }
+                true
+            }
+            _ => true,
+        }
+    }
+
+    /// Pass the SYNs of the guest to the NAT stack when the host connections
+    /// are made, with a listening socket to take them if succeeded.
+    fn connect_pending(&mut self) {
+        let mut listening = Vec::new();
+        let keys: Vec<_> = self.pending.keys().copied().collect();
+        for key in keys {
+            let pending = self.pending.get_mut(&key).unwrap();
+            if pending.result.is_none() {
+                pending.result = match pending.connected.try_recv() {
+                    Ok(result) => Some(result),
+                    Err(TryRecvError::Empty) => continue,
+                    Err(TryRecvError::Disconnected) => Some(Err(ErrorKind::Other.into())),
+                };
+            }
+            // one listening socket for each destination at a time, so that it
+            // takes the SYN of its connection
+            if listening.contains(&key.1) {
+                continue;
+            }
+            let pending = self.pending.remove(&key).unwrap();
+            match pending.result.unwrap() {
+                Ok(stream) => {
+                    let mut socket = TcpSocket::new(
+                        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                        TcpSocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                    );
+                    if socket.listen(key.1).is_ok() {
+                        self.tcp.push(TcpFlow {
+                            handle: self.sockets.add(socket),
+                            guest: key.0,
+                            dst: key.1,
+                            stream,
+                            host_eof: false,
+                            guest_eof: false,
+                        });
+                        listening.push(key.1);
+                    }
+                }
+                Err(e) => debug!("slirp: failed to connect to {}: {}", key.1, e),
+            }
+            self.to_host.lock().push_front(pending.syn);
+        }
+    }
+
+    /// Copy data between the NAT sockets and the host connections.
+    fn proxy_tcp(&mut self) {
+        let sockets = &mut self.sockets;
+        self.tcp.retain_mut(|flow| {
+            let mut socket = sockets.get::<TcpSocket>(flow.handle);
+            match socket.state() {
+                // the SYN has been taken by the NAT stack, if not the guest
+                // has gone
+                TcpState::Listen | TcpState::Closed | TcpState::TimeWait => {
+                    drop(socket);
+                    sockets.remove(flow.handle);
+                    return false;
+                }
+                _ => {}
+            }
+            if let Err(e) = flow.proxy(&mut *socket) {
+                debug!("slirp: connection to {} broken: {}", flow.dst, e);
+                socket.abort();
+            }
+            // removed when the reset is sent
+            true
+        });
+    }
+
+    /// Relay datagrams between the NAT sockets and the host sockets.
+    fn relay_udp(&mut self) {
+        let now = std::time::Instant::now();
+        let mut buf = vec![0; MAX_UDP_PAYLOAD];
+        for (dst, flow) in self.udp.iter_mut() {
+            let mut socket = self.sockets.get::<UdpSocket>(flow.handle);
+            while let Ok((data, guest)) = socket.recv() {
+                let relay = match flow.relays.entry(guest) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match udp_relay(flow.host) {
+                        Ok(socket) => entry.insert(UdpRelay {
+                            socket,
+                            last_used: now,
+                        }),
+                        Err(e) => {
+                            warn!("slirp: failed to relay UDP to {}: {}", dst, e);
+                            continue;
+                        }
+                    },
+                };
+                relay.socket.send(data).ok();
+                relay.last_used = now;
+            }
+            for (guest, relay) in flow.relays.iter_mut() {
+                while socket.can_send() {
+                    match relay.socket.recv(&mut buf) {
+                        Ok(len) => {
+                            socket.send_slice(&buf[..len], *guest).ok();
+                            relay.last_used = now;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+            flow.relays
+                .retain(|_, relay| now.duration_since(relay.last_used) < UDP_TIMEOUT);
+        }
+        let sockets = &mut self.sockets;
+        self.udp.retain(|_, flow| {
+            if flow.relays.is_empty() {
+                sockets.remove(flow.handle);
+            }
+            !flow.relays.is_empty()
+        });
+    }
+}
+
+impl TcpFlow {
+    fn proxy(&mut self, socket: &mut TcpSocket) -> io::Result<()> {
+        let stream = &mut self.stream;
+        // guest -> host
+        while socket.can_recv() {
+            let result = socket.recv(|data| match stream.write(data) {
+                Ok(len) => (len, Ok(len)),
+                Err(e) => (0, Err(e)),
+            });
+            match result {
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        if !self.guest_eof && !socket.may_recv() && !socket.can_recv() {
+            self.guest_eof = true;
+            stream.shutdown(Shutdown::Write).ok();
+        }
+        // host -> guest
+        while !self.host_eof && socket.can_send() {
+            let result = socket.send(|buf| match stream.read(buf) {
+                Ok(len) => (len, Ok(len)),
+                Err(e) => (0, Err(e)),
+            });
+            match result {
+                Ok(Ok(0)) => {
+                    self.host_eof = true;
+                    socket.close();
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) if e.kind() == ErrorKind::WouldBlock => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a TCP or UDP over IPv4 frame, as (protocol, source, destination,
+/// whether it's a TCP SYN without ACK).
+fn parse_frame(frame: &[u8]) -> Option<(IpProtocol, IpEndpoint, IpEndpoint, bool)> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    if frame.ethertype() != EthernetProtocol::Ipv4 {
+        return None;
+    }
+    let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+    if packet.more_frags() || packet.frag_offset() != 0 {
+        return None;
+    }
+    let (src, dst) = (packet.src_addr(), packet.dst_addr());
+    let (src_port, dst_port, syn) = match packet.protocol() {
+        IpProtocol::Tcp => {
+            let tcp = TcpPacket::new_checked(packet.payload()).ok()?;
+            (tcp.src_port(), tcp.dst_port(), tcp.syn() && !tcp.ack())
+        }
+        IpProtocol::Udp => {
+            let udp = UdpPacket::new_checked(packet.payload()).ok()?;
+            (udp.src_port(), udp.dst_port(), false)
+        }
+        _ => return None,
+    };
+    Some((
+        packet.protocol(),
+        IpEndpoint::new(src.into(), src_port),
+        IpEndpoint::new(dst.into(), dst_port),
+        syn,
+    ))
+}
+
+/// A host socket sending to and receiving from `host` only.
+fn udp_relay(host: SocketAddr) -> io::Result<HostUdpSocket> {
+    let socket = HostUdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(host)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// The first IPv4 name server of the host.
+fn host_name_server() -> Option<Ipv4Addr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf").ok()?;
+    conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// The link between the interface and the NAT stack.
+struct Pipe {
+    rx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    tx: Arc<Mutex<VecDeque<Vec<u8>>>>,
+}
+
+struct PipeTxToken(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl phy::Device<'_> for Pipe {
+    type RxToken = MockNetRxToken;
+    type TxToken = PipeTxToken;
+
+    fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.rx.lock().pop_front()?;
+        Some((MockNetRxToken(frame), PipeTxToken(self.tx.clone())))
+    }
+
+    fn transmit(&mut self) -> Option<Self::TxToken> {
+        if self.tx.lock().len() < QUEUE_LEN {
+            Some(PipeTxToken(self.tx.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_SIZE;
+        caps
+    }
+}
+
+impl phy::TxToken for PipeTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame)?;
+        self.0.lock().push_back(frame);
+        Ok(result)
+    }
+}
@@ -0,0 +1,98 @@
+//! Linux TAP device backend.
+//!
+//! The TAP device must be configured on the host, for example:
+//!
+//! ```sh
+//! sudo ip tuntap add dev tap0 mode tap user $USER
+//! sudo ip addr add 10.0.2.2/24 dev tap0
+//! sudo ip link set tap0 up
+//! ```
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+
+use alloc::vec::Vec;
+
+use super::{FrameBackend, MAX_FRAME_SIZE};
+use crate::{DeviceError, DeviceResult};
+
+const TUNSETIFF: u64 = 0x4004_54ca;
+const IFF_TAP: i16 = 0x0002;
+const IFF_NO_PI: i16 = 0x1000;
+const IFNAMSIZ: usize = 16;
+
+/// `struct ifreq` with `ifr_flags`.
+#[repr(C)]
+struct IfReq {
+    name: [u8; IFNAMSIZ],
+    flags: i16,
+    _pad: [u8; 22],
+}
+
+/// An opened TAP device.
+pub struct TapBackend {
+    file: File,
+}
+
+impl TapBackend {
+    /// Attach to the TAP device `ifname`, which is created if it doesn't
+    /// exist and the process is privileged.
+    pub fn open(ifname: &str) -> DeviceResult<Self> {
+        if ifname.is_empty() || ifname.len() >= IFNAMSIZ {
+            return Err(DeviceError::InvalidParam);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open("/dev/net/tun")
+            .map_err(|e| {
+                warn!("failed to open /dev/net/tun: {}", e);
+                DeviceError::IoError
+            })?;
+        let mut ifr = IfReq {
+            name: [0; IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            _pad: [0; 22],
+        };
+        ifr.name[..ifname.len()].copy_from_slice(ifname.as_bytes());
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut ifr) };
+        if ret < 0 {
+            warn!(
+                "failed to attach to TAP device {}: {}",
+                ifname,
+                std::io::Error::last_os_error()
+            );
+            return Err(DeviceError::IoError);
+        }
+        info!("attached to TAP device {}", ifname);
+        Ok(Self { file })
+    }
+}
+
+impl FrameBackend for TapBackend {
+    fn recv_frame(&self) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_FRAME_SIZE];
+        match (&self.file).read(&mut buf) {
+            Ok(len) => Some(buf[..len].to_vec()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            Err(e) => {
+                warn!("TAP read error: {}", e);
+                None
+            }
+        }
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> DeviceResult {
+        match (&self.file).write(frame) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Err(DeviceError::NotReady),
+            Err(e) => {
+                warn!("TAP write error: {}", e);
+                Err(DeviceError::IoError)
+            }
+        }
+    }
+}
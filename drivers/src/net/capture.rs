@@ -0,0 +1,48 @@
+//! Capture of frames for packet sockets.
+//!
+//! Every frame received from or sent to the device is delivered to the
+//! [`Capture`] of the interface, by
+//! [`HookedDevice`](super::hooked::HookedDevice) if the frame is handled by
+//! smoltcp, or by the driver for
+//! [`NetScheme::recv`](crate::scheme::NetScheme::recv) and
+//! [`NetScheme::send`](crate::scheme::NetScheme::send).
+//!
+//! Frames are delivered with the interface and smoltcp sockets locked, so
+//! sinks must not take these locks.
+
+use alloc::{sync::Weak, vec::Vec};
+use lock::Mutex;
+
+/// A receiver of captured frames.
+pub trait FrameSink: Send + Sync {
+    /// Receive a copy of `frame`, `outgoing` if it's sent by the interface.
+    fn deliver(&self, frame: &[u8], outgoing: bool);
+}
+
+/// The sinks attached to an interface.
+#[derive(Default)]
+pub struct Capture {
+    sinks: Mutex<Vec<Weak<dyn FrameSink>>>,
+}
+
+impl Capture {
+    /// Attach `sink`, which is detached when dropped.
+    pub fn attach(&self, sink: Weak<dyn FrameSink>) {
+        self.sinks.lock().push(sink);
+    }
+
+    /// Deliver `frame` to all sinks.
+    pub fn deliver(&self, frame: &[u8], outgoing: bool) {
+        let mut sinks = self.sinks.lock();
+        if sinks.is_empty() {
+            return;
+        }
+        sinks.retain(|sink| match sink.upgrade() {
+            Some(sink) => {
+                sink.deliver(frame, outgoing);
+                true
+            }
+            None => false,
+        });
+    }
+}
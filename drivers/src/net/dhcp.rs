@@ -0,0 +1,131 @@
+//! DHCPv4 client on smoltcp's DHCP socket.
+//!
+//! smoltcp hands incoming DHCP packets to the first DHCP socket, so only one
+//! interface can be configured by DHCP at a time. The client port is bound to
+//! the interface, so that its packets are only sent there.
+
+use alloc::{sync::Arc, vec::Vec};
+use lock::Mutex;
+use smoltcp::socket::{Dhcpv4Event, Dhcpv4Socket, SocketHandle};
+use smoltcp::wire::{IpCidr, IpProtocol, Ipv4Address, Ipv4Cidr};
+
+use super::route::DeviceBinding;
+use super::{get_sockets, set_dns_servers};
+use crate::scheme::NetScheme;
+use crate::DeviceResult;
+
+/// The UDP port of DHCP clients.
+const DHCP_CLIENT_PORT: u16 = 68;
+
+/// A DHCP client configuring an interface.
+pub struct DhcpClient {
+    iface: Arc<dyn NetScheme>,
+    handle: SocketHandle,
+    /// The leased address.
+    lease: Mutex<Option<Ipv4Cidr>>,
+    _binding: DeviceBinding,
+}
+
+impl DhcpClient {
+    fn new(iface: Arc<dyn NetScheme>) -> Self {
+        let handle = get_sockets().lock().add(Dhcpv4Socket::new());
+        let binding = DeviceBinding::new(IpProtocol::Udp, DHCP_CLIENT_PORT, &iface.get_ifname());
+        Self {
+            iface,
+            handle,
+            lease: Mutex::new(None),
+            _binding: binding,
+        }
+    }
+
+    /// The leased address.
+    pub fn lease(&self) -> Option<Ipv4Cidr> {
+        *self.lease.lock()
+    }
+
+    /// Apply the configuration to the interface if it's changed.
+    ///
+    /// Returns whether the interface is configured.
+    pub fn update(&self) -> bool {
+        let event = {
+            let sockets = get_sockets();
+            let mut sockets = sockets.lock();
+            let mut socket = sockets.get::<Dhcpv4Socket>(self.handle);
+            match socket.poll() {
+                Some(Dhcpv4Event::Configured(config)) => {
+                    Some(Some((config.address, config.router, config.dns_servers)))
+                }
+                Some(Dhcpv4Event::Deconfigured) => Some(None),
+                None => None,
+            }
+        };
+        let name = self.iface.get_ifname();
+        match event {
+            Some(Some((address, router, dns_servers))) => {
+                info!("dhcp: {} leased {} via {:?}", name, address, router);
+                let res = self
+                    .set_ipv4_address(IpCidr::Ipv4(address))
+                    .and_then(|_| self.iface.set_ipv4_gateway(router));
+                if let Err(e) = res {
+                    warn!("dhcp: failed to configure {}: {:?}", name, e);
+                }
+                let dns_servers: Vec<Ipv4Address> = dns_servers.iter().flatten().copied().collect();
+                if !dns_servers.is_empty() {
+                    set_dns_servers(dns_servers);
+                }
+                *self.lease.lock() = Some(address);
+            }
+            Some(None) => {
+                warn!("dhcp: {} lost its lease", name);
+                let unspecified = IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0);
+                self.set_ipv4_address(unspecified).ok();
+                self.iface.set_ipv4_gateway(None).ok();
+                *self.lease.lock() = None;
+            }
+            None => {}
+        }
+        self.lease().is_some()
+    }
+
+    /// Replace the IPv4 addresses of the interface, keeping the IPv6 ones.
+    fn set_ipv4_address(&self, address: IpCidr) -> DeviceResult {
+        let mut addrs = self.iface.get_ip_address();
+        addrs.retain(|addr| !matches!(addr, IpCidr::Ipv4(_)));
+        addrs.insert(0, address);
+        self.iface.set_ip_address(addrs)
+    }
+
+    /// Poll the interface, and apply the configuration if it's changed.
+    ///
+    /// Returns whether the interface is configured.
+    pub fn poll(&self) -> bool {
+        self.iface.poll().ok();
+        self.update()
+    }
+}
+
+impl Drop for DhcpClient {
+    fn drop(&mut self) {
+        get_sockets().lock().remove(self.handle);
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CLIENT: Mutex<Option<Arc<DhcpClient>>> = Mutex::new(None);
+}
+
+/// Start configuring `iface` by DHCP, replacing the previous client.
+pub fn start(iface: Arc<dyn NetScheme>) -> Arc<DhcpClient> {
+    info!("dhcp: start configuring {}", iface.get_ifname());
+    let client = Arc::new(DhcpClient::new(iface));
+    *CLIENT.lock() = Some(client.clone());
+    client
+}
+
+/// Apply renewed or lost leases, after the interfaces are polled.
+pub fn update() {
+    let client = CLIENT.lock().clone();
+    if let Some(client) = client {
+        client.update();
+    }
+}
@@ -3,7 +3,8 @@
 
 use alloc::collections::BTreeMap;
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
 use alloc::vec::Vec;
 
 use smoltcp::iface::*;
@@ -12,7 +13,13 @@ use smoltcp::time::Instant;
 use smoltcp::wire::*;
 use smoltcp::Result;
 
-use super::{timer_now_as_micros, ProviderImpl};
+use super::capture::{Capture, FrameSink};
+use super::hooked::HookedDevice;
+use super::{
+    add_iface_route, del_iface_route, iface_ipv4_gateway, iface_ipv6_config, iface_ipv6_gateway,
+    join_iface_multicast_group, leave_iface_multicast_group, set_iface_ip_addrs,
+    set_iface_ipv4_gateway, set_iface_ipv6_gateway, timer_now_as_micros, ProviderImpl,
+};
 use crate::net::get_sockets;
 use crate::scheme::{NetScheme, Scheme};
 use crate::{DeviceError, DeviceResult};
@@ -25,10 +32,10 @@ pub struct E1000Driver(Arc<Mutex<E1000<ProviderImpl>>>);
 
 #[derive(Clone)]
 pub struct E1000Interface {
-    iface: Arc<Mutex<Interface<'static, E1000Driver>>>,
+    iface: Arc<Mutex<Interface<'static, HookedDevice<E1000Driver>>>>,
     driver: E1000Driver,
+    capture: Arc<Capture>,
     name: String,
-    irq: usize,
 }
 
 impl Scheme for E1000Interface {
@@ -36,12 +43,8 @@ impl Scheme for E1000Interface {
         "e1000"
     }
 
-    fn handle_irq(&self, irq: usize) {
-        if irq != self.irq {
-            // not ours, skip it
-            return;
-        }
-
+    fn handle_irq(&self, _irq: usize) {
+        // the interrupt cause register is empty if it's not ours
         let data = self.driver.0.lock().handle_interrupt();
 
         if data {
@@ -75,6 +78,43 @@ impl NetScheme for E1000Interface {
         Vec::from(self.iface.lock().ip_addrs())
     }
 
+    fn set_ip_address(&self, addrs: Vec<IpCidr>) -> DeviceResult {
+        set_iface_ip_addrs(&mut self.iface.lock(), addrs);
+        Ok(())
+    }
+
+    fn get_ipv4_gateway(&self) -> Option<Ipv4Address> {
+        iface_ipv4_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv4_gateway(&self, gateway: Option<Ipv4Address>) -> DeviceResult {
+        set_iface_ipv4_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn get_ipv6_gateway(&self) -> Option<Ipv6Address> {
+        iface_ipv6_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv6_gateway(&self, gateway: Option<Ipv6Address>) -> DeviceResult {
+        set_iface_ipv6_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> DeviceResult {
+        add_iface_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> DeviceResult {
+        del_iface_route(&mut self.iface.lock(), dst)
+    }
+
+    fn join_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        join_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn leave_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        leave_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
     fn poll(&self) -> DeviceResult {
         let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
         let sockets = get_sockets();
@@ -94,7 +134,11 @@ impl NetScheme for E1000Interface {
 
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
         if let Some(vec_recv) = self.driver.0.lock().receive() {
-            buf.copy_from_slice(&vec_recv);
+            self.capture.deliver(&vec_recv, false);
+            if vec_recv.len() > buf.len() {
+                return Err(DeviceError::BufferTooSmall);
+            }
+            buf[..vec_recv.len()].copy_from_slice(&vec_recv);
             Ok(vec_recv.len())
         } else {
             Err(DeviceError::NotReady)
@@ -105,11 +149,17 @@ impl NetScheme for E1000Interface {
         if self.driver.0.lock().can_send() {
             let mut driver = self.driver.0.lock();
             driver.send(data);
+            self.capture.deliver(data, true);
             Ok(data.len())
         } else {
             Err(DeviceError::NotReady)
         }
     }
+
+    fn attach_capture(&self, sink: Weak<dyn FrameSink>) -> DeviceResult {
+        self.capture.attach(sink);
+        Ok(())
+    }
 }
 
 pub struct E1000RxToken(Vec<u8>);
@@ -120,10 +170,8 @@ impl phy::Device<'_> for E1000Driver {
     type TxToken = E1000TxToken;
 
     fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
-        self.0
-            .lock()
-            .receive()
-            .map(|vec_recv| (E1000RxToken(vec_recv), E1000TxToken(self.clone())))
+        let vec_recv = self.0.lock().receive()?;
+        Some((E1000RxToken(vec_recv), E1000TxToken(self.clone())))
     }
 
     fn transmit(&mut self) -> Option<Self::TxToken> {
@@ -157,19 +205,18 @@ impl phy::TxToken for E1000TxToken {
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
         let mut buffer = [0u8; 1536];
-        let result = f(&mut buffer[..len]);
+        let result = f(&mut buffer[..len])?;
 
         let mut driver = (self.0).0.lock();
         driver.send(&buffer[..len]);
 
-        result
+        Ok(result)
     }
 }
 
 // JudgeDuck-OS/kern/e1000.c
 pub fn init(
     name: String,
-    irq: usize,
     header: usize,
     size: usize,
     index: usize,
@@ -182,20 +229,27 @@ pub fn init(
     let e1000 = E1000::new(header, size, DriverEthernetAddress::from_bytes(&mac));
 
     let net_driver = E1000Driver(Arc::new(Mutex::new(e1000)));
+    let capture = Arc::new(Capture::default());
 
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
-    let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, 2, (15 + index) as u8), 24)];
+    let mut ip_addrs = vec![IpCidr::new(IpAddress::v4(10, 0, 2, (15 + index) as u8), 24)];
+    let (ipv6_addrs, default_v6_gw) = iface_ipv6_config(index, ethernet_addr);
+    ip_addrs.extend(ipv6_addrs);
     let default_v4_gw = Ipv4Address::new(10, 0, 2, 2); //Qemu user network gateway: 10.0.2.2
-    static mut ROUTES_STORAGE: [Option<(IpCidr, Route)>; 1] = [None; 1];
-    let mut routes = unsafe { Routes::new(&mut ROUTES_STORAGE[..]) };
+    let mut routes = Routes::new(BTreeMap::new());
     routes.add_default_ipv4_route(default_v4_gw).unwrap();
+    if let Some(gateway) = default_v6_gw {
+        routes.add_default_ipv6_route(gateway).unwrap();
+    }
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
 
-    let iface = InterfaceBuilder::new(net_driver.clone())
+    let device = HookedDevice::new(net_driver.clone(), &name, capture.clone());
+    let iface = InterfaceBuilder::new(device)
         .ethernet_addr(ethernet_addr)
         .neighbor_cache(neighbor_cache)
         .ip_addrs(ip_addrs)
         .routes(routes)
+        .ipv4_multicast_groups(BTreeMap::new())
         .finalize();
 
     info!(
@@ -206,8 +260,8 @@ pub fn init(
     let e1000_iface = E1000Interface {
         iface: Arc::new(Mutex::new(iface)),
         driver: net_driver,
+        capture,
         name,
-        irq,
     };
 
     Ok(e1000_iface)
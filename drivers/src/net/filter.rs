@@ -0,0 +1,51 @@
+//! Filtering of frames between the devices and smoltcp.
+//!
+//! [`HookedDevice`](super::hooked::HookedDevice) passes every frame received
+//! from the device to [`incoming`] before giving it to smoltcp, and every
+//! frame built by smoltcp to [`outgoing`] before sending it, so that a
+//! [`FrameFilter`] can drop or rewrite them.
+//! Frames sent and received by
+//! [`NetScheme::send`](crate::scheme::NetScheme::send) and
+//! [`NetScheme::recv`](crate::scheme::NetScheme::recv) are not filtered, as
+//! packet sockets bypass netfilter in Linux.
+//!
+//! Frames are filtered with the interface and smoltcp sockets locked, so
+//! filters must not take these locks.
+
+use alloc::sync::Arc;
+use lock::Mutex;
+
+/// A filter of frames.
+pub trait FrameFilter: Send + Sync {
+    /// Filter `frame`, which may be rewritten in place. Returns `false` to
+    /// drop it.
+    fn filter(&self, frame: &mut [u8], outgoing: bool) -> bool;
+}
+
+lazy_static::lazy_static! {
+    static ref FILTER: Mutex<Option<Arc<dyn FrameFilter>>> = Mutex::new(None);
+}
+
+/// Set the filter of all interfaces, or remove it if `None`.
+pub fn set_filter(filter: Option<Arc<dyn FrameFilter>>) {
+    *FILTER.lock() = filter;
+}
+
+fn filter(frame: &mut [u8], outgoing: bool) -> bool {
+    let filter = FILTER.lock().clone();
+    match filter {
+        Some(filter) => filter.filter(frame, outgoing),
+        None => true,
+    }
+}
+
+/// Filter a frame received from the device, returns whether to pass it to
+/// smoltcp.
+pub(crate) fn incoming(frame: &mut [u8]) -> bool {
+    filter(frame, false)
+}
+
+/// Filter a frame built by smoltcp, returns whether to send it.
+pub(crate) fn outgoing(frame: &mut [u8]) -> bool {
+    filter(frame, true)
+}
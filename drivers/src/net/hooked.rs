@@ -0,0 +1,130 @@
+//! A [`phy::Device`] wrapper running the hooks of the network stack on the
+//! frames between a device and smoltcp.
+//!
+//! Every frame received from the device is:
+//!
+//! 1. delivered to the [`Capture`] of the interface;
+//! 2. passed to [`filter::incoming`], and dropped if refused;
+//! 3. passed to [`isn::incoming`] to undo the sequence number offsets.
+//!
+//! Every frame built by smoltcp is:
+//!
+//! 1. checked by [`route::egress`], if it's built for a socket rather than in
+//!    reply to a received frame, and left in the socket for its interface if
+//!    it's routed to another one;
+//! 2. passed to [`isn::outgoing`] to apply the sequence number offsets;
+//! 3. passed to [`filter::outgoing`], and dropped if refused;
+//! 4. sent to the device, and delivered to the [`Capture`].
+//!
+//! Frames sent and received by
+//! [`NetScheme::send`](crate::scheme::NetScheme::send) and
+//! [`NetScheme::recv`](crate::scheme::NetScheme::recv) don't go through the
+//! wrapper, drivers deliver them to the capture themselves.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::time::Instant;
+
+use super::capture::Capture;
+use super::{filter, isn, route, timer_now_as_micros};
+
+/// A device with the hooks of the interface `name`.
+pub struct HookedDevice<D> {
+    inner: D,
+    name: Arc<str>,
+    capture: Arc<Capture>,
+}
+
+impl<D> HookedDevice<D> {
+    /// Wrap `inner`, the device of the interface `name`, whose frames are
+    /// delivered to `capture`.
+    pub fn new(inner: D, name: &str, capture: Arc<Capture>) -> Self {
+        Self {
+            inner,
+            name: Arc::from(name),
+            capture,
+        }
+    }
+}
+
+impl<'a, D> phy::Device<'a> for HookedDevice<D>
+where
+    D: for<'d> phy::Device<'d>,
+{
+    type RxToken = HookedRxToken;
+    type TxToken = HookedTxToken<'a, D>;
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        loop {
+            let (rx, _) = self.inner.receive()?;
+            let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+            let mut frame = rx.consume(timestamp, |frame| Ok(frame.to_vec())).ok()?;
+            self.capture.deliver(&frame, false);
+            if filter::incoming(&mut frame) {
+                isn::incoming(&mut frame);
+                let tx = HookedTxToken {
+                    dev: self,
+                    routed: false,
+                };
+                return Some((HookedRxToken(frame), tx));
+            }
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        self.inner.transmit()?;
+        Some(HookedTxToken {
+            dev: self,
+            routed: true,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+pub struct HookedRxToken(Vec<u8>);
+
+impl phy::RxToken for HookedRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+pub struct HookedTxToken<'a, D> {
+    dev: &'a mut HookedDevice<D>,
+    /// Built for a socket rather than in reply to a received frame.
+    routed: bool,
+}
+
+impl<D> phy::TxToken for HookedTxToken<'_, D>
+where
+    D: for<'d> phy::Device<'d>,
+{
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        if self.routed && !route::egress(&self.dev.name, &buffer) {
+            // unlike `Unaddressable`, this doesn't silence the socket
+            return Err(smoltcp::Error::Exhausted);
+        }
+        isn::outgoing(&mut buffer);
+        if !filter::outgoing(&mut buffer) {
+            return Ok(result);
+        }
+        let tx = self.dev.inner.transmit().ok_or(smoltcp::Error::Exhausted)?;
+        tx.consume(timestamp, len, |frame| {
+            frame.copy_from_slice(&buffer);
+            Ok(())
+        })?;
+        self.dev.capture.deliver(&buffer, true);
+        Ok(result)
+    }
+}
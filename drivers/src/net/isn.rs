@@ -0,0 +1,275 @@
+//! TCP initial sequence numbers chosen by the kernel rather than smoltcp.
+//!
+//! The pinned smoltcp picks the initial sequence number (ISN) of a connection
+//! itself. Like the sequence number modulation of packet filters,
+//! [`HookedDevice`](super::hooked::HookedDevice) passes TCP segments built by
+//! smoltcp to [`outgoing`], which adds an offset of the connection to the
+//! sequence number, so that the ISN on the wire is the one given by the
+//! [`IsnGenerator`]. Received segments are passed to [`incoming`], which
+//! subtracts the offset from the acknowledgment number and SACK blocks.
+//!
+//! Offsets are keyed by the local and remote endpoints, so a connection over
+//! the loopback interface has one for each end. They are created by SYNs sent
+//! by smoltcp, removed by resets, and dropped after [`IDLE_TIMEOUT`].
+//!
+//! Checksums are updated incrementally as in RFC 1624, so that errors in
+//! received segments are not hidden.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use lock::Mutex;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpAddress, IpEndpoint, IpProtocol};
+use smoltcp::wire::{Ipv4Packet, Ipv6Packet};
+
+use super::timer_now_as_micros;
+
+/// Offsets of connections idle for this long are dropped, in microseconds.
+const IDLE_TIMEOUT: u64 = 600_000_000;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const TCP_HEADER_LEN: usize = 20;
+const TCP_FLAG_SYN: u8 = 0x02;
+const TCP_FLAG_RST: u8 = 0x04;
+const TCP_FLAG_ACK: u8 = 0x10;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_SACK: u8 = 5;
+
+/// Generates the ISN of a connection from the local and remote endpoints.
+pub type IsnGenerator = Arc<dyn Fn(IpEndpoint, IpEndpoint) -> u32 + Send + Sync>;
+
+/// The offset of a connection.
+struct Offset {
+    /// The sequence number of the SYN sent by smoltcp.
+    syn_seq: u32,
+    /// Added to sequence numbers sent by smoltcp.
+    delta: u32,
+    last_used: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref GENERATOR: Mutex<Option<IsnGenerator>> = Mutex::new(None);
+    /// (local, remote) -> the offset of the connection
+    static ref OFFSETS: Mutex<BTreeMap<(IpEndpoint, IpEndpoint), Offset>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Set the generator of the ISNs of connections opened afterwards, or leave
+/// them to smoltcp if `None`.
+pub fn set_isn_generator(generator: Option<IsnGenerator>) {
+    *GENERATOR.lock() = generator;
+}
+
+/// A TCP segment in an Ethernet frame: the source and destination endpoints,
+/// and the offset of the TCP header in the frame.
+fn parse_frame(frame: &[u8]) -> Option<(IpEndpoint, IpEndpoint, usize)> {
+    let eth = EthernetFrame::new_checked(frame).ok()?;
+    let (src, dst, protocol, header_len) = match eth.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(eth.payload()).ok()?;
+            if packet.frag_offset() != 0 {
+                return None;
+            }
+            let src = IpAddress::Ipv4(packet.src_addr());
+            let dst = IpAddress::Ipv4(packet.dst_addr());
+            (src, dst, packet.protocol(), packet.header_len() as usize)
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(eth.payload()).ok()?;
+            let src = IpAddress::Ipv6(packet.src_addr());
+            let dst = IpAddress::Ipv6(packet.dst_addr());
+            (src, dst, packet.next_header(), packet.header_len())
+        }
+        _ => return None,
+    };
+    let offset = ETHERNET_HEADER_LEN + header_len;
+    if protocol != IpProtocol::Tcp || frame.len() < offset + TCP_HEADER_LEN {
+        return None;
+    }
+    let tcp = &frame[offset..];
+    let sport = u16::from_be_bytes([tcp[0], tcp[1]]);
+    let dport = u16::from_be_bytes([tcp[2], tcp[3]]);
+    Some((
+        IpEndpoint::new(src, sport),
+        IpEndpoint::new(dst, dport),
+        offset,
+    ))
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+/// Replace the 32-bit field at `pos` of the TCP segment `tcp` by
+/// `f(old value)`, and update the checksum incrementally.
+fn rewrite_u32(tcp: &mut [u8], pos: usize, f: impl FnOnce(u32) -> u32) {
+    let old = read_u32(tcp, pos);
+    let new = f(old);
+    tcp[pos..pos + 4].copy_from_slice(&new.to_be_bytes());
+    let checksum = u16::from_be_bytes([tcp[16], tcp[17]]);
+    let checksum = update_checksum(checksum, old, new);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// RFC 1624: `HC' = ~(~HC + ~m + m')`, for the two 16-bit words of a 32-bit
+/// field changed from `old` to `new`.
+fn update_checksum(checksum: u16, old: u32, new: u32) -> u16 {
+    let mut sum = !checksum as u32;
+    for (m, m1) in [(old >> 16, new >> 16), (old & 0xffff, new & 0xffff)] {
+        sum += (!(m as u16)) as u32 + m1;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Offsets of the SACK blocks in the options of the TCP segment `tcp`.
+fn sack_blocks(tcp: &[u8]) -> impl Iterator<Item = usize> {
+    let data_offset = ((tcp[12] >> 4) as usize * 4).min(tcp.len());
+    let mut blocks = [0; 4];
+    let mut count = 0;
+    let mut pos = TCP_HEADER_LEN;
+    while pos < data_offset {
+        match tcp[pos] {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => pos += 1,
+            kind => {
+                let len = match tcp.get(pos + 1) {
+                    Some(&len) if len >= 2 && pos + len as usize <= data_offset => len as usize,
+                    _ => break,
+                };
+                if kind == TCP_OPT_SACK {
+                    for block in (pos + 2..pos + len).step_by(8) {
+                        if block + 8 <= pos + len && count < blocks.len() {
+                            blocks[count] = block;
+                            count += 1;
+                        }
+                    }
+                }
+                pos += len;
+            }
+        }
+    }
+    IntoIterator::into_iter(blocks).take(count)
+}
+
+/// Add the offset of the connection to the sequence number of a TCP segment
+/// built by smoltcp, choosing the offset on a SYN.
+pub(crate) fn outgoing(frame: &mut [u8]) {
+    let generator = match GENERATOR.lock().clone() {
+        Some(generator) => generator,
+        None => return,
+    };
+    let (local, remote, offset) = match parse_frame(frame) {
+        Some(segment) => segment,
+        None => return,
+    };
+    let tcp = &mut frame[offset..];
+    let flags = tcp[13];
+    let seq = read_u32(tcp, 4);
+    let now = timer_now_as_micros();
+    let mut offsets = OFFSETS.lock();
+    let key = (local, remote);
+    if flags & TCP_FLAG_SYN != 0 && offsets.get(&key).map_or(true, |o| o.syn_seq != seq) {
+        // a new connection, rather than a retransmitted SYN
+        offsets.retain(|_, o| now.saturating_sub(o.last_used) < IDLE_TIMEOUT);
+        let delta = generator(local, remote).wrapping_sub(seq);
+        offsets.insert(
+            key,
+            Offset {
+                syn_seq: seq,
+                delta,
+                last_used: now,
+            },
+        );
+    }
+    if let Some(o) = offsets.get_mut(&key) {
+        o.last_used = now;
+        let delta = o.delta;
+        rewrite_u32(tcp, 4, |seq| seq.wrapping_add(delta));
+    }
+    if flags & TCP_FLAG_RST != 0 {
+        offsets.remove(&key);
+    }
+}
+
+/// Subtract the offset of the connection from the acknowledgment number and
+/// SACK blocks of a received TCP segment.
+pub(crate) fn incoming(frame: &mut [u8]) {
+    if GENERATOR.lock().is_none() {
+        return;
+    }
+    let (remote, local, offset) = match parse_frame(frame) {
+        Some(segment) => segment,
+        None => return,
+    };
+    let tcp = &mut frame[offset..];
+    let flags = tcp[13];
+    let mut offsets = OFFSETS.lock();
+    let key = (local, remote);
+    let delta = match offsets.get_mut(&key) {
+        Some(o) => {
+            o.last_used = timer_now_as_micros();
+            o.delta
+        }
+        None => return,
+    };
+    if flags & TCP_FLAG_ACK != 0 {
+        rewrite_u32(tcp, 8, |ack| ack.wrapping_sub(delta));
+    }
+    let blocks: alloc::vec::Vec<usize> = sack_blocks(tcp).collect();
+    for block in blocks {
+        rewrite_u32(tcp, block, |left| left.wrapping_sub(delta));
+        rewrite_u32(tcp, block + 4, |right| right.wrapping_sub(delta));
+    }
+    if flags & TCP_FLAG_RST != 0 {
+        offsets.remove(&key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The one's complement sum of `data`, as in the TCP checksum.
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for word in data.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    #[test]
+    fn incremental_checksum() {
+        let mut data = [0u8; 20];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i * 37) as u8;
+        }
+        data[16..18].fill(0);
+        let sum = checksum(&data);
+        data[16..18].copy_from_slice(&sum.to_be_bytes());
+        for delta in [1, 0xffff, 0x1_0000, 0x8000_0001, u32::MAX] {
+            let mut tcp = data;
+            rewrite_u32(&mut tcp, 4, |seq| seq.wrapping_add(delta));
+            // the checksum over the segment with its checksum field is zero
+            assert_eq!(checksum(&tcp), 0, "delta {:#x}", delta);
+        }
+    }
+
+    #[test]
+    fn sack_options() {
+        let mut tcp = [0u8; 40];
+        tcp[12] = 10 << 4;
+        // NOP, NOP, SACK with 2 blocks
+        tcp[20..24].copy_from_slice(&[TCP_OPT_NOP, TCP_OPT_NOP, TCP_OPT_SACK, 18]);
+        assert_eq!(sack_blocks(&tcp).collect::<alloc::vec::Vec<_>>(), [24, 32]);
+        // truncated option
+        tcp[23] = 30;
+        assert_eq!(sack_blocks(&tcp).count(), 0);
+    }
+}
@@ -1,8 +1,16 @@
 // smoltcp
-use smoltcp::{iface::Interface, phy::Loopback, time::Instant};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::{iface::Interface, time::Instant};
 
-use crate::net::get_sockets;
-use alloc::sync::Arc;
+use crate::net::capture::{Capture, FrameSink};
+use crate::net::hooked::HookedDevice;
+use crate::net::{add_iface_route, del_iface_route};
+use crate::net::{get_sockets, iface_ipv4_gateway, set_iface_ip_addrs, set_iface_ipv4_gateway};
+use crate::net::{iface_ipv6_gateway, set_iface_ipv6_gateway};
+use crate::net::{join_iface_multicast_group, leave_iface_multicast_group};
+use alloc::collections::VecDeque;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
 
 use alloc::string::String;
 use lock::Mutex;
@@ -12,17 +20,92 @@ use crate::{DeviceError, DeviceResult};
 
 use alloc::vec::Vec;
 use smoltcp::wire::EthernetAddress;
+use smoltcp::wire::IpAddress;
 use smoltcp::wire::IpCidr;
+use smoltcp::wire::Ipv4Address;
+use smoltcp::wire::Ipv6Address;
+
+/// Name of the loopback interface.
+const NAME: &str = "loopback";
+
+/// The loopback device, taking back the frames sent to it.
+///
+/// Wrapped in a [`HookedDevice`], frames built for sockets are only taken if
+/// they are routed to the loopback interface, so that packets to other hosts
+/// are left to Ethernet interfaces.
+#[derive(Debug, Default)]
+pub struct LoopbackDevice {
+    queue: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<'a> phy::Device<'a> for LoopbackDevice {
+    type RxToken = LoopbackRxToken;
+    type TxToken = LoopbackTxToken<'a>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = 65535;
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.queue.pop_front()?;
+        let tx = LoopbackTxToken {
+            queue: &mut self.queue,
+        };
+        Some((LoopbackRxToken(frame), tx))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(LoopbackTxToken {
+            queue: &mut self.queue,
+        })
+    }
+}
+
+pub struct LoopbackRxToken(Vec<u8>);
+
+impl phy::RxToken for LoopbackRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+pub struct LoopbackTxToken<'a> {
+    queue: &'a mut VecDeque<Vec<u8>>,
+}
+
+impl phy::TxToken for LoopbackTxToken<'_> {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        self.queue.push_back(buffer);
+        Ok(result)
+    }
+}
 
 #[derive(Clone)]
 pub struct LoopbackInterface {
-    pub iface: Arc<Mutex<Interface<'static, Loopback>>>,
+    pub iface: Arc<Mutex<Interface<'static, HookedDevice<LoopbackDevice>>>>,
+    pub capture: Arc<Capture>,
     pub name: String,
 }
 
 impl Scheme for LoopbackInterface {
     fn name(&self) -> &str {
-        "loopback"
+        NAME
     }
 
     fn handle_irq(&self, _cause: usize) {}
@@ -30,10 +113,15 @@ impl Scheme for LoopbackInterface {
 
 impl NetScheme for LoopbackInterface {
     fn recv(&self, _buf: &mut [u8]) -> DeviceResult<usize> {
-        unimplemented!()
+        Err(DeviceError::NotSupported)
     }
     fn send(&self, _buf: &[u8]) -> DeviceResult<usize> {
-        unimplemented!()
+        Err(DeviceError::NotSupported)
+    }
+
+    fn attach_capture(&self, sink: Weak<dyn FrameSink>) -> DeviceResult {
+        self.capture.attach(sink);
+        Ok(())
     }
     fn poll(&self) -> DeviceResult {
         let timestamp = Instant::from_millis(0);
@@ -59,4 +147,41 @@ impl NetScheme for LoopbackInterface {
     fn get_ip_address(&self) -> Vec<IpCidr> {
         Vec::from(self.iface.lock().ip_addrs())
     }
+
+    fn set_ip_address(&self, addrs: Vec<IpCidr>) -> DeviceResult {
+        set_iface_ip_addrs(&mut self.iface.lock(), addrs);
+        Ok(())
+    }
+
+    fn get_ipv4_gateway(&self) -> Option<Ipv4Address> {
+        iface_ipv4_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv4_gateway(&self, gateway: Option<Ipv4Address>) -> DeviceResult {
+        set_iface_ipv4_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn get_ipv6_gateway(&self) -> Option<Ipv6Address> {
+        iface_ipv6_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv6_gateway(&self, gateway: Option<Ipv6Address>) -> DeviceResult {
+        set_iface_ipv6_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> DeviceResult {
+        add_iface_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> DeviceResult {
+        del_iface_route(&mut self.iface.lock(), dst)
+    }
+
+    fn join_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        join_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn leave_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        leave_iface_multicast_group(&mut self.iface.lock(), group)
+    }
 }
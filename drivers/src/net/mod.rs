@@ -1,14 +1,28 @@
 //! LAN driver, only for Realtek currently.
 #![allow(unused)]
 
-use alloc::{sync::Arc, vec};
+use alloc::{sync::Arc, vec, vec::Vec};
 use lock::Mutex;
+use smoltcp::iface::{Interface, Route};
+use smoltcp::phy;
 use smoltcp::socket::SocketSet;
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr};
+use smoltcp::wire::{Ipv6Address, Ipv6Cidr};
 
+use crate::{DeviceError, DeviceResult};
+
+pub mod capture;
+pub mod dhcp;
 pub mod e1000;
+pub mod filter;
+pub mod hooked;
+pub mod isn;
 pub mod loopback;
+pub mod route;
+pub mod slaac;
 pub use isomorphic_drivers::provider::Provider;
-pub use loopback::LoopbackInterface;
+pub use loopback::{LoopbackDevice, LoopbackInterface};
 
 cfg_if::cfg_if! {
     if #[cfg(target_arch = "riscv64")] {
@@ -86,3 +100,306 @@ lazy_static::lazy_static! {
 pub fn get_sockets() -> Arc<Mutex<SocketSet<'static>>> {
     SOCKETS.clone()
 }
+
+/// Addresses assigned to Ethernet interfaces when they are probed.
+#[derive(Debug, Clone, Copy)]
+pub struct IfaceConfig {
+    /// Address of the first interface, the following interfaces get the
+    /// next addresses in the same subnet.
+    pub ip: Ipv4Cidr,
+    /// The default gateway.
+    pub gateway: Option<Ipv4Address>,
+    /// Leave the interfaces unconfigured, to be configured by DHCP.
+    pub dhcp: bool,
+    /// Static IPv6 address of the first interface, the following interfaces
+    /// get the next addresses in the same subnet.
+    pub ipv6: Option<Ipv6Cidr>,
+    /// The IPv6 default gateway.
+    pub ipv6_gateway: Option<Ipv6Address>,
+    /// Configure global IPv6 addresses by SLAAC.
+    pub slaac: bool,
+}
+
+impl Default for IfaceConfig {
+    /// The QEMU user network: 10.0.2.15/24 via 10.0.2.2, and IPv6 by SLAAC.
+    fn default() -> Self {
+        Self {
+            ip: Ipv4Cidr::new(Ipv4Address::new(10, 0, 2, 15), 24),
+            gateway: Some(Ipv4Address::new(10, 0, 2, 2)),
+            dhcp: false,
+            ipv6: None,
+            ipv6_gateway: None,
+            slaac: true,
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref IFACE_CONFIG: Mutex<IfaceConfig> = Mutex::new(IfaceConfig::default());
+    static ref DNS_SERVERS: Mutex<Vec<Ipv4Address>> = Mutex::new(Vec::new());
+    static ref DNS_HOOK: Mutex<Option<DnsHook>> = Mutex::new(None);
+}
+
+/// Called with the new DNS servers when they are changed.
+pub type DnsHook = Arc<dyn Fn(&[Ipv4Address]) + Send + Sync>;
+
+/// Set the addresses of interfaces probed afterwards.
+pub fn set_iface_config(config: IfaceConfig) {
+    *IFACE_CONFIG.lock() = config;
+}
+
+/// Get the configuration of interfaces probed afterwards.
+pub fn get_iface_config() -> IfaceConfig {
+    *IFACE_CONFIG.lock()
+}
+
+/// Get the address and the default gateway of the `index`-th interface.
+pub fn iface_config(index: usize) -> (IpCidr, Option<Ipv4Address>) {
+    let config = get_iface_config();
+    if config.dhcp {
+        return (IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0), None);
+    }
+    let addr = u32::from_be_bytes(config.ip.address().0) + index as u32;
+    let ip = Ipv4Cidr::new(
+        Ipv4Address::from_bytes(&addr.to_be_bytes()),
+        config.ip.prefix_len(),
+    );
+    (IpCidr::Ipv4(ip), config.gateway)
+}
+
+/// Get the IPv6 addresses and the IPv6 default gateway of the `index`-th
+/// interface, whose MAC address is `mac`.
+///
+/// Ethernet interfaces always have a link-local address, after the global
+/// one so that smoltcp prefers the global one as the source address.
+pub fn iface_ipv6_config(index: usize, mac: EthernetAddress) -> (Vec<IpCidr>, Option<Ipv6Address>) {
+    let config = get_iface_config();
+    let mut addrs = Vec::new();
+    if let Some(cidr) = config.ipv6 {
+        let mut bytes = cidr.address().0;
+        let mut low = [0; 8];
+        low.copy_from_slice(&bytes[8..]);
+        let low = u64::from_be_bytes(low) + index as u64;
+        bytes[8..].copy_from_slice(&low.to_be_bytes());
+        let addr = Ipv6Cidr::new(Ipv6Address(bytes), cidr.prefix_len());
+        addrs.push(IpCidr::Ipv6(addr));
+    }
+    addrs.push(IpCidr::Ipv6(ipv6_link_local(mac)));
+    (addrs, config.ipv6_gateway)
+}
+
+/// The address in the /64 `prefix` with the interface identifier derived from
+/// `mac` by modified EUI-64, as in RFC 4291.
+pub fn ipv6_eui64_address(prefix: Ipv6Address, mac: EthernetAddress) -> Ipv6Address {
+    let mut bytes = prefix.0;
+    bytes[8..11].copy_from_slice(&mac.0[..3]);
+    bytes[8] ^= 0x02;
+    bytes[11..13].copy_from_slice(&[0xff, 0xfe]);
+    bytes[13..].copy_from_slice(&mac.0[3..]);
+    Ipv6Address(bytes)
+}
+
+/// The link-local address of an Ethernet interface.
+pub fn ipv6_link_local(mac: EthernetAddress) -> Ipv6Cidr {
+    let prefix = Ipv6Address::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+    Ipv6Cidr::new(ipv6_eui64_address(prefix, mac), 64)
+}
+
+/// Set the DNS servers, from the command line or DHCP, and pass them to the
+/// hook if they are changed.
+pub fn set_dns_servers(servers: Vec<Ipv4Address>) {
+    let changed = {
+        let mut dns_servers = DNS_SERVERS.lock();
+        let changed = *dns_servers != servers;
+        *dns_servers = servers.clone();
+        changed
+    };
+    let hook = DNS_HOOK.lock().clone();
+    if let (true, Some(hook)) = (changed, hook) {
+        hook(&servers);
+    }
+}
+
+/// Set the hook called when the DNS servers are changed, e.g. by a DHCP
+/// lease, or remove it if `None`.
+pub fn set_dns_hook(hook: Option<DnsHook>) {
+    *DNS_HOOK.lock() = hook;
+}
+
+/// Get the DNS servers.
+pub fn dns_servers() -> Vec<Ipv4Address> {
+    DNS_SERVERS.lock().clone()
+}
+
+/// The destination of the IPv4 default route.
+fn default_ipv4_route() -> IpCidr {
+    IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)
+}
+
+/// The destination of the IPv6 default route.
+fn default_ipv6_route() -> IpCidr {
+    IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0)
+}
+
+/// Replace the IP addresses of `iface`.
+pub(crate) fn set_iface_ip_addrs<D>(iface: &mut Interface<'static, D>, addrs: Vec<IpCidr>)
+where
+    D: for<'d> phy::Device<'d>,
+{
+    iface.update_ip_addrs(|ip_addrs| *ip_addrs = addrs.into());
+    route::iface_changed();
+}
+
+/// Get the IPv4 default gateway of `iface`.
+pub(crate) fn iface_ipv4_gateway<D>(iface: &mut Interface<'static, D>) -> Option<Ipv4Address>
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let mut gateway = None;
+    iface.routes_mut().update(|routes| {
+        if let Some(Route {
+            via_router: IpAddress::Ipv4(addr),
+            ..
+        }) = routes.get(&default_ipv4_route())
+        {
+            gateway = Some(*addr);
+        }
+    });
+    gateway
+}
+
+/// Set the IPv4 default gateway of `iface`, or remove it if `None`.
+pub(crate) fn set_iface_ipv4_gateway<D>(
+    iface: &mut Interface<'static, D>,
+    gateway: Option<Ipv4Address>,
+) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let routes = iface.routes_mut();
+    match gateway {
+        Some(gateway) => {
+            routes
+                .add_default_ipv4_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        None => {
+            routes.remove_default_ipv4_route();
+        }
+    }
+    route::iface_changed();
+    Ok(())
+}
+
+/// Get the IPv6 default gateway of `iface`.
+pub(crate) fn iface_ipv6_gateway<D>(iface: &mut Interface<'static, D>) -> Option<Ipv6Address>
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let mut gateway = None;
+    iface.routes_mut().update(|routes| {
+        if let Some(Route {
+            via_router: IpAddress::Ipv6(addr),
+            ..
+        }) = routes.get(&default_ipv6_route())
+        {
+            gateway = Some(*addr);
+        }
+    });
+    gateway
+}
+
+/// Set the IPv6 default gateway of `iface`, or remove it if `None`.
+pub(crate) fn set_iface_ipv6_gateway<D>(
+    iface: &mut Interface<'static, D>,
+    gateway: Option<Ipv6Address>,
+) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let routes = iface.routes_mut();
+    match gateway {
+        Some(gateway) => {
+            routes
+                .add_default_ipv6_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        None => {
+            routes.remove_default_ipv6_route();
+        }
+    }
+    route::iface_changed();
+    Ok(())
+}
+
+/// Add a route to `dst` via `gateway` to `iface`, replacing the one to the
+/// same destination.
+pub(crate) fn add_iface_route<D>(
+    iface: &mut Interface<'static, D>,
+    dst: IpCidr,
+    gateway: IpAddress,
+) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let route = match gateway {
+        IpAddress::Ipv4(addr) => Route::new_ipv4_gateway(addr),
+        IpAddress::Ipv6(addr) => Route::new_ipv6_gateway(addr),
+        _ => return Err(DeviceError::InvalidParam),
+    };
+    let mut res = Ok(());
+    iface.routes_mut().update(|routes| {
+        if routes.insert(dst, route).is_err() {
+            res = Err(DeviceError::NoResources);
+        }
+    });
+    res
+}
+
+/// Remove the route to `dst` from `iface`.
+pub(crate) fn del_iface_route<D>(iface: &mut Interface<'static, D>, dst: IpCidr) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let mut removed = false;
+    iface
+        .routes_mut()
+        .update(|routes| removed = routes.remove(&dst).is_some());
+    if removed {
+        Ok(())
+    } else {
+        Err(DeviceError::InvalidParam)
+    }
+}
+
+/// Join the IPv4 multicast `group` on `iface`, announcing it by IGMP.
+pub(crate) fn join_iface_multicast_group<D>(
+    iface: &mut Interface<'static, D>,
+    group: Ipv4Address,
+) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+    // the group is added before the report is sent, a report which can't be
+    // sent now is sent again on the next IGMP query
+    if let Err(e) = iface.join_multicast_group(group, timestamp) {
+        warn!("IGMP report of {} not sent: {:?}", group, e);
+    }
+    Ok(())
+}
+
+/// Leave the IPv4 multicast `group` on `iface`.
+pub(crate) fn leave_iface_multicast_group<D>(
+    iface: &mut Interface<'static, D>,
+    group: Ipv4Address,
+) -> DeviceResult
+where
+    D: for<'d> phy::Device<'d>,
+{
+    let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+    if let Err(e) = iface.leave_multicast_group(group, timestamp) {
+        warn!("IGMP leave of {} not sent: {:?}", group, e);
+    }
+    Ok(())
+}
@@ -0,0 +1,586 @@
+//! The kernel routing table, choosing the egress interface of packets.
+//!
+//! smoltcp sockets are shared by all interfaces, and each interface sends
+//! whatever the sockets have to send when it's polled. So
+//! [`HookedDevice`](super::hooked::HookedDevice) passes every frame built for
+//! a socket to [`egress`] before sending it, which tells whether the
+//! interface is the one the packet is routed to. If not, the frame is refused
+//! with `Error::Exhausted`, smoltcp keeps the packet in the socket without
+//! marking its neighbor missing, and the interface it's routed to sends it
+//! when polled.
+//! Replies built by smoltcp while processing a received frame are sent back
+//! on the interface the frame came from, without a lookup.
+//!
+//! The egress interface of a packet is, in order:
+//!
+//! 1. The interface its socket is bound to by [`DeviceBinding`], i.e.
+//!    `SO_BINDTODEVICE`.
+//! 2. For link-local, multicast and broadcast destinations, the interface
+//!    owning the source address. Any interface if the source is unspecified.
+//! 3. The loopback interface for local destinations, including `127.0.0.0/8`,
+//!    `::1` and the addresses of other interfaces.
+//! 4. The interface of the route with the longest prefix matching the
+//!    destination, then the lowest metric, then the first interface. Routes
+//!    are those to the networks of interfaces, the static ones added by
+//!    [`add_route`], and the default routes of interfaces.
+//!
+//! Routes are looked up with the interface and smoltcp sockets locked, so they
+//! are resolved on a snapshot of the interfaces taken by [`update_ifaces`].
+//!
+//! smoltcp stops sending on an interface at a refused frame, so the sockets
+//! after it wait for another poll of the interface, see [`take_refused`].
+//! The next hop is resolved by the interface before the frame is checked,
+//! so an interface without the neighbor of its own route still asks for it,
+//! and the socket is silenced for a second there.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use lock::Mutex;
+use smoltcp::wire::{EthernetFrame, EthernetProtocol, IpAddress, IpCidr, IpProtocol};
+use smoltcp::wire::{Ipv4Address, Ipv4Packet, Ipv6Address, Ipv6Cidr, Ipv6Packet};
+
+use crate::scheme::NetScheme;
+use crate::{DeviceError, DeviceResult};
+
+/// Name of the loopback interface.
+const LOOPBACK: &str = "loopback";
+
+/// A static route.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// The destination network.
+    pub dst: IpCidr,
+    /// The next hop, `None` for destinations on the link.
+    pub gateway: Option<IpAddress>,
+    /// The egress interface.
+    pub ifname: String,
+    /// The priority among routes of the same prefix length, lower first.
+    pub metric: u32,
+}
+
+/// The addresses and default gateways of an interface.
+struct IfaceInfo {
+    name: String,
+    addrs: Vec<IpCidr>,
+    gateways: Vec<IpAddress>,
+}
+
+#[derive(Default)]
+struct Snapshot {
+    /// The interfaces it's taken from, by pointer.
+    ifaces: Vec<usize>,
+    /// The generation of interface configuration it's taken at.
+    generation: usize,
+    infos: Vec<IfaceInfo>,
+}
+
+lazy_static::lazy_static! {
+    static ref SNAPSHOT: Mutex<Snapshot> = Mutex::new(Snapshot::default());
+    static ref ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+    /// Interfaces bound by protocol and local port, with reference counts.
+    static ref BINDINGS: Mutex<BTreeMap<(u8, u16), (String, usize)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Bumped whenever addresses or gateways of an interface change.
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+/// Whether [`egress`] refused a frame since the last [`take_refused`].
+static REFUSED: AtomicBool = AtomicBool::new(false);
+
+/// Record that addresses or gateways of an interface are changed.
+pub(crate) fn iface_changed() {
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Take a snapshot of `ifaces` for looking up routes, if the interfaces or
+/// their configuration changed since the last one.
+///
+/// Interfaces must not be locked, e.g. call it between polls.
+pub fn update_ifaces(ifaces: &[Arc<dyn NetScheme>]) {
+    let generation = GENERATION.load(Ordering::Relaxed);
+    let ptrs: Vec<usize> = ifaces
+        .iter()
+        .map(|iface| Arc::as_ptr(iface) as *const () as usize)
+        .collect();
+    {
+        let snapshot = SNAPSHOT.lock();
+        if snapshot.generation == generation && snapshot.ifaces == ptrs {
+            return;
+        }
+    }
+    let infos = ifaces
+        .iter()
+        .map(|iface| {
+            let gateways = iface
+                .get_ipv4_gateway()
+                .map(IpAddress::Ipv4)
+                .into_iter()
+                .chain(iface.get_ipv6_gateway().map(IpAddress::Ipv6))
+                .collect();
+            IfaceInfo {
+                name: iface.get_ifname(),
+                addrs: iface.get_ip_address(),
+                gateways,
+            }
+        })
+        .collect();
+    *SNAPSHOT.lock() = Snapshot {
+        ifaces: ptrs,
+        generation,
+        infos,
+    };
+}
+
+/// Whether `addr` is of the same IP version as `cidr`.
+fn same_family(cidr: &IpCidr, addr: &IpAddress) -> bool {
+    matches!(
+        (cidr, addr),
+        (IpCidr::Ipv4(_), IpAddress::Ipv4(_)) | (IpCidr::Ipv6(_), IpAddress::Ipv6(_))
+    )
+}
+
+/// The default route of the IP version of `gateway`.
+fn default_route(gateway: &IpAddress) -> IpCidr {
+    match gateway {
+        IpAddress::Ipv6(_) => IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0),
+        _ => IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// The network of `cidr`, i.e. with the host bits cleared.
+pub fn network(cidr: IpCidr) -> IpCidr {
+    match cidr {
+        IpCidr::Ipv4(cidr) => IpCidr::Ipv4(cidr.network()),
+        IpCidr::Ipv6(cidr) => {
+            let host_bits = 128 - cidr.prefix_len() as u32;
+            let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+            let network = u128::from_be_bytes(cidr.address().0) & mask;
+            IpCidr::Ipv6(Ipv6Cidr::new(
+                Ipv6Address(network.to_be_bytes()),
+                cidr.prefix_len(),
+            ))
+        }
+        _ => cidr,
+    }
+}
+
+/// Whether `addr` is always on the loopback interface.
+fn is_loopback_addr(addr: &IpAddress) -> bool {
+    match addr {
+        IpAddress::Ipv4(addr) => addr.is_loopback(),
+        IpAddress::Ipv6(addr) => addr.is_loopback(),
+        _ => false,
+    }
+}
+
+/// A route borrowed from a snapshot and the static routes.
+struct RouteRef<'a> {
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    ifname: &'a str,
+    metric: u32,
+}
+
+impl RouteRef<'_> {
+    fn to_route(&self) -> Route {
+        Route {
+            dst: self.dst,
+            gateway: self.gateway,
+            ifname: self.ifname.to_string(),
+            metric: self.metric,
+        }
+    }
+}
+
+impl Snapshot {
+    /// The interface owning `addr`.
+    fn owner(&self, addr: &IpAddress) -> Option<&str> {
+        self.infos
+            .iter()
+            .find(|info| info.addrs.iter().any(|cidr| cidr.address() == *addr))
+            .map(|info| info.name.as_str())
+    }
+
+    /// Look up the route to `dst` among `routes` and those of interfaces.
+    fn lookup<'a>(&'a self, routes: &'a [Route], dst: &IpAddress) -> Option<RouteRef<'a>> {
+        if is_loopback_addr(dst) || self.owner(dst).is_some() {
+            return Some(RouteRef {
+                dst: IpCidr::new(
+                    *dst,
+                    if let IpAddress::Ipv6(_) = dst {
+                        128
+                    } else {
+                        32
+                    },
+                ),
+                gateway: None,
+                ifname: LOOPBACK,
+                metric: 0,
+            });
+        }
+        let connected = self.infos.iter().flat_map(|info| {
+            info.addrs
+                .iter()
+                .filter(|cidr| !cidr.address().is_unspecified())
+                .map(move |cidr| RouteRef {
+                    dst: network(*cidr),
+                    gateway: None,
+                    ifname: &info.name,
+                    metric: 0,
+                })
+        });
+        let statics = routes.iter().map(|route| RouteRef {
+            dst: route.dst,
+            gateway: route.gateway,
+            ifname: &route.ifname,
+            metric: route.metric,
+        });
+        let defaults = self.infos.iter().flat_map(|info| {
+            info.gateways.iter().map(move |gateway| RouteRef {
+                dst: default_route(gateway),
+                gateway: Some(*gateway),
+                ifname: &info.name,
+                metric: 0,
+            })
+        });
+        let index = |route: &RouteRef| {
+            self.infos
+                .iter()
+                .position(|info| info.name == route.ifname)
+                .unwrap_or(usize::MAX)
+        };
+        connected
+            .chain(statics)
+            .chain(defaults)
+            .filter(|route| same_family(&route.dst, dst) && route.dst.contains_addr(dst))
+            .filter(|route| route.ifname != LOOPBACK)
+            // `min_by_key` returns the first of equal ones
+            .min_by_key(|route| (u8::MAX - route.dst.prefix_len(), route.metric, index(route)))
+    }
+}
+
+/// Look up the route to `dst`, `None` if it's unreachable.
+pub fn lookup(dst: IpAddress) -> Option<Route> {
+    let routes = ROUTES.lock();
+    let snapshot = SNAPSHOT.lock();
+    snapshot.lookup(&routes, &dst).map(|route| route.to_route())
+}
+
+/// The static routes.
+pub fn routes() -> Vec<Route> {
+    ROUTES.lock().clone()
+}
+
+/// Add a static route to `dst` via `gateway` on `iface`, replacing the one to
+/// the same destination on the interface.
+///
+/// Routes without a gateway must be within a network of the interface, as
+/// smoltcp sends other packets only to gateways.
+pub fn add_route(
+    iface: &dyn NetScheme,
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    metric: u32,
+) -> DeviceResult {
+    if gateway.map_or(false, |gateway| !same_family(&dst, &gateway)) {
+        return Err(DeviceError::InvalidParam);
+    }
+    let dst = network(dst);
+    let ifname = iface.get_ifname();
+    match gateway {
+        Some(gateway) => iface.add_route(dst, gateway)?,
+        None => {
+            let on_link = iface.get_ip_address().iter().any(|cidr| {
+                same_family(cidr, &dst.address())
+                    && cidr.prefix_len() <= dst.prefix_len()
+                    && cidr.contains_addr(&dst.address())
+            });
+            if !on_link {
+                return Err(DeviceError::NotSupported);
+            }
+        }
+    }
+    let mut routes = ROUTES.lock();
+    routes.retain(|route| !(route.dst == dst && route.ifname == ifname));
+    routes.push(Route {
+        dst,
+        gateway,
+        ifname,
+        metric,
+    });
+    Ok(())
+}
+
+/// Remove the static route to `dst` on `iface`.
+pub fn del_route(iface: &dyn NetScheme, dst: IpCidr) -> DeviceResult<Route> {
+    let dst = network(dst);
+    let ifname = iface.get_ifname();
+    let route = {
+        let mut routes = ROUTES.lock();
+        let index = routes
+            .iter()
+            .position(|route| route.dst == dst && route.ifname == ifname)
+            .ok_or(DeviceError::InvalidParam)?;
+        routes.remove(index)
+    };
+    if route.gateway.is_some() {
+        iface.del_route(dst)?;
+    }
+    Ok(route)
+}
+
+/// Binds a local port of a protocol to an interface, until dropped.
+///
+/// Packets from the port are only sent on the interface. If the port is bound
+/// several times, the last interface takes effect.
+#[derive(Debug)]
+pub struct DeviceBinding {
+    protocol: u8,
+    port: u16,
+}
+
+impl DeviceBinding {
+    /// Bind `port` of `protocol` to the interface `ifname`.
+    pub fn new(protocol: IpProtocol, port: u16, ifname: &str) -> Self {
+        let protocol = protocol.into();
+        let mut bindings = BINDINGS.lock();
+        let entry = bindings
+            .entry((protocol, port))
+            .or_insert_with(|| (String::new(), 0));
+        entry.0 = ifname.to_string();
+        entry.1 += 1;
+        DeviceBinding { protocol, port }
+    }
+}
+
+impl Drop for DeviceBinding {
+    fn drop(&mut self) {
+        let key = (self.protocol, self.port);
+        let mut bindings = BINDINGS.lock();
+        if let Some((_, count)) = bindings.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                bindings.remove(&key);
+            }
+        }
+    }
+}
+
+/// The addresses of an IP packet in an Ethernet frame, with the protocol and
+/// the source port.
+fn parse_frame(frame: &[u8]) -> Option<(IpAddress, IpAddress, u8, Option<u16>)> {
+    let frame = EthernetFrame::new_checked(frame).ok()?;
+    let (src, dst, protocol, payload) = match frame.ethertype() {
+        EthernetProtocol::Ipv4 => {
+            let packet = Ipv4Packet::new_checked(frame.payload()).ok()?;
+            let src = IpAddress::Ipv4(packet.src_addr());
+            let dst = IpAddress::Ipv4(packet.dst_addr());
+            // only the first fragment has the ports
+            let payload = if packet.frag_offset() == 0 {
+                &frame.payload()[packet.header_len() as usize..]
+            } else {
+                &[][..]
+            };
+            (src, dst, packet.protocol(), payload)
+        }
+        EthernetProtocol::Ipv6 => {
+            let packet = Ipv6Packet::new_checked(frame.payload()).ok()?;
+            let src = IpAddress::Ipv6(packet.src_addr());
+            let dst = IpAddress::Ipv6(packet.dst_addr());
+            let payload = &frame.payload()[packet.header_len()..];
+            (src, dst, packet.next_header(), payload)
+        }
+        _ => return None,
+    };
+    let sport = match protocol {
+        IpProtocol::Tcp | IpProtocol::Udp if payload.len() >= 2 => {
+            Some(u16::from_be_bytes([payload[0], payload[1]]))
+        }
+        _ => None,
+    };
+    Some((src, dst, protocol.into(), sport))
+}
+
+/// Whether `addr` is only valid on a link, or addresses several hosts.
+fn is_link_scope(addr: &IpAddress) -> bool {
+    match addr {
+        IpAddress::Ipv4(addr) => addr.is_broadcast() || addr.is_multicast() || addr.is_link_local(),
+        IpAddress::Ipv6(addr) => addr.is_multicast() || addr.is_link_local(),
+        _ => false,
+    }
+}
+
+/// Whether a frame has been refused by [`egress`] since the last call, i.e.
+/// some interfaces stopped sending for sockets, and are to be polled again
+/// once the refused packets are sent by their interfaces.
+pub fn take_refused() -> bool {
+    REFUSED.swap(false, Ordering::Relaxed)
+}
+
+/// Whether `frame` built for a socket is to be sent on the interface `ifname`.
+///
+/// Frames other than IP packets are always sent, as are all frames before the
+/// first snapshot of interfaces.
+pub fn egress(ifname: &str, frame: &[u8]) -> bool {
+    let accepted = route_to(ifname, frame);
+    if !accepted {
+        REFUSED.store(true, Ordering::Relaxed);
+    }
+    accepted
+}
+
+fn route_to(ifname: &str, frame: &[u8]) -> bool {
+    let (src, dst, protocol, sport) = match parse_frame(frame) {
+        Some(packet) => packet,
+        None => return true,
+    };
+    if let Some(port) = sport {
+        if let Some((bound, _)) = BINDINGS.lock().get(&(protocol, port)) {
+            return bound == ifname;
+        }
+    }
+    // in the order of `lookup`
+    let routes = ROUTES.lock();
+    let snapshot = SNAPSHOT.lock();
+    if snapshot.infos.is_empty() {
+        return true;
+    }
+    let subnet_broadcast = snapshot.infos.iter().any(|info| {
+        info.addrs.iter().any(|cidr| match (cidr, &dst) {
+            (IpCidr::Ipv4(cidr), IpAddress::Ipv4(dst)) => cidr.broadcast() == Some(*dst),
+            _ => false,
+        })
+    });
+    if is_link_scope(&dst) || subnet_broadcast {
+        return match snapshot.owner(&src) {
+            Some(owner) => owner == ifname,
+            None => true,
+        };
+    }
+    match snapshot.lookup(&routes, &dst) {
+        Some(route) => route.ifname == ifname,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn cidr(s: &str) -> IpCidr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> IpAddress {
+        s.parse().unwrap()
+    }
+
+    fn snapshot() -> Snapshot {
+        let iface = |name: &str, addrs: &[&str], gateways: &[&str]| IfaceInfo {
+            name: name.to_string(),
+            addrs: addrs.iter().map(|s| cidr(s)).collect(),
+            gateways: gateways.iter().map(|s| addr(s)).collect(),
+        };
+        Snapshot {
+            ifaces: vec![],
+            generation: 1,
+            infos: vec![
+                iface(LOOPBACK, &["127.0.0.1/8", "::1/128"], &[]),
+                iface("eth0", &["10.0.0.2/24", "fd00::2/64"], &["10.0.0.1"]),
+                iface("eth1", &["10.1.0.2/16"], &[]),
+            ],
+        }
+    }
+
+    fn route(dst: &str, gateway: Option<&str>, ifname: &str, metric: u32) -> Route {
+        Route {
+            dst: cidr(dst),
+            gateway: gateway.map(addr),
+            ifname: ifname.to_string(),
+            metric,
+        }
+    }
+
+    fn ifname_to(routes: &[Route], dst: &str) -> Option<String> {
+        let snapshot = snapshot();
+        let route = snapshot.lookup(routes, &addr(dst))?;
+        Some(route.ifname.to_string())
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let routes = [
+            route("192.168.0.0/16", Some("10.1.0.1"), "eth1", 0),
+            route("192.168.1.0/24", Some("10.0.0.1"), "eth0", 100),
+            route("10.1.2.0/24", Some("10.0.0.1"), "eth0", 0),
+        ];
+        for (dst, ifname) in [
+            ("192.168.1.5", Some("eth0")),
+            ("192.168.2.5", Some("eth1")),
+            ("10.0.0.9", Some("eth0")),
+            ("10.1.3.9", Some("eth1")),
+            ("10.1.2.9", Some("eth0")),
+            ("8.8.8.8", Some("eth0")),
+            ("fd00::9", Some("eth0")),
+            // no IPv6 default route
+            ("2001:db8::1", None),
+        ] {
+            assert_eq!(ifname_to(&routes, dst).as_deref(), ifname, "{}", dst);
+        }
+        let snapshot = snapshot();
+        let default = snapshot.lookup(&routes, &addr("8.8.8.8")).unwrap();
+        assert_eq!(
+            default.to_route(),
+            route("0.0.0.0/0", Some("10.0.0.1"), "eth0", 0)
+        );
+    }
+
+    #[test]
+    fn metric() {
+        let routes = [
+            route("172.16.0.0/12", Some("10.0.0.1"), "eth0", 10),
+            route("172.16.0.0/12", Some("10.1.0.1"), "eth1", 5),
+            route("172.32.0.0/12", Some("10.1.0.1"), "eth1", 5),
+            route("172.32.0.0/12", Some("10.0.0.1"), "eth0", 5),
+            // connected routes are of metric 0
+            route("10.1.0.0/16", Some("10.0.0.1"), "eth0", 1),
+        ];
+        for (dst, ifname) in [
+            ("172.16.0.1", "eth1"),
+            // equal metrics, the first interface
+            ("172.32.0.1", "eth0"),
+            ("10.1.0.9", "eth1"),
+        ] {
+            assert_eq!(ifname_to(&routes, dst).as_deref(), Some(ifname), "{}", dst);
+        }
+    }
+
+    #[test]
+    fn loopback() {
+        // static routes on the loopback interface are ignored
+        let routes = [route("10.9.0.0/16", None, LOOPBACK, 0)];
+        let snapshot = snapshot();
+        for dst in [
+            "127.0.0.1",
+            "127.1.2.3",
+            "::1",
+            "10.0.0.2",
+            "10.1.0.2",
+            "fd00::2",
+        ] {
+            let route = snapshot.lookup(&routes, &addr(dst)).unwrap();
+            assert_eq!(route.ifname, LOOPBACK, "{}", dst);
+            assert_eq!(
+                route.dst,
+                IpCidr::new(addr(dst), if dst.contains(':') { 128 } else { 32 })
+            );
+            assert_eq!(route.gateway, None);
+        }
+        assert_eq!(ifname_to(&routes, "10.9.0.1").as_deref(), Some("eth0"));
+    }
+}
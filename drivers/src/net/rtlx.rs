@@ -1,6 +1,6 @@
 use alloc::collections::BTreeMap;
 use alloc::string::String;
-use alloc::sync::Arc;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use lock::Mutex;
 
@@ -11,6 +11,8 @@ use smoltcp::time::Instant;
 use smoltcp::wire::*;
 use smoltcp::Result;
 
+use super::capture::{Capture, FrameSink};
+use super::hooked::HookedDevice;
 use super::realtek::rtl8211f::{self, RTL8211F};
 use super::{timer_now_as_micros, ProviderImpl, PAGE_SIZE};
 
@@ -23,8 +25,9 @@ pub struct RTLxDriver(Arc<Mutex<RTL8211F<ProviderImpl>>>);
 
 #[derive(Clone)]
 pub struct RTLxInterface {
-    pub iface: Arc<Mutex<Interface<'static, RTLxDriver>>>,
+    pub iface: Arc<Mutex<Interface<'static, HookedDevice<RTLxDriver>>>>,
     pub driver: RTLxDriver,
+    pub capture: Arc<Capture>,
     pub name: String,
     pub irq: usize,
 }
@@ -95,6 +98,7 @@ impl NetScheme for RTLxInterface {
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
         if self.driver.0.lock().can_recv() {
             let (vec_recv, rxcount) = self.driver.0.lock().geth_recv(1);
+            self.capture.deliver(&vec_recv, false);
             buf.copy_from_slice(&vec_recv);
             Ok(rxcount as usize)
         } else {
@@ -105,11 +109,17 @@ impl NetScheme for RTLxInterface {
     fn send(&self, data: &[u8]) -> DeviceResult<usize> {
         if self.driver.0.lock().can_send() {
             self.driver.0.lock().geth_send(data).unwrap();
+            self.capture.deliver(data, true);
             Ok(data.len())
         } else {
             Err(DeviceError::NotReady)
         }
     }
+
+    fn attach_capture(&self, sink: Weak<dyn FrameSink>) -> DeviceResult {
+        self.capture.attach(sink);
+        Ok(())
+    }
 }
 
 pub struct RTLxRxToken(Vec<u8>);
@@ -161,11 +171,9 @@ impl phy::TxToken for RTLxTxToken {
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
         let mut buffer = [0u8; 1536];
-        let result = f(&mut buffer[..len]);
-        if result.is_ok() {
-            (self.0).0.lock().geth_send(&buffer[..len]).unwrap();
-        }
-        result
+        let result = f(&mut buffer[..len])?;
+        (self.0).0.lock().geth_send(&buffer[..len]).unwrap();
+        Ok(result)
     }
 }
 
@@ -185,7 +193,9 @@ pub fn rtlx_init<F: Fn(usize, usize) -> Option<usize>>(
     rtl8211f.set_rx_mode();
     rtl8211f.adjust_link().unwrap();
 
+    let name = String::from("rtl8211f");
     let net_driver = RTLxDriver(Arc::new(Mutex::new(rtl8211f)));
+    let capture = Arc::new(Capture::default());
 
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
     let ip_addrs = [IpCidr::new(IpAddress::v4(192, 168, 0, 123), 24)];
@@ -194,7 +204,8 @@ pub fn rtlx_init<F: Fn(usize, usize) -> Option<usize>>(
     let mut routes = unsafe { Routes::new(&mut ROUTES_STORAGE[..]) };
     routes.add_default_ipv4_route(default_gateway).unwrap();
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
-    let iface = InterfaceBuilder::new(net_driver.clone())
+    let device = HookedDevice::new(net_driver.clone(), &name, capture.clone());
+    let iface = InterfaceBuilder::new(device)
         .ethernet_addr(ethernet_addr)
         .neighbor_cache(neighbor_cache)
         .ip_addrs(ip_addrs)
@@ -206,7 +217,8 @@ pub fn rtlx_init<F: Fn(usize, usize) -> Option<usize>>(
     let rtl8211f_iface = RTLxInterface {
         iface: Arc::new(Mutex::new(iface)),
         driver: net_driver,
-        name: String::from("rtl8211f"),
+        capture,
+        name,
         irq,
     };
 
@@ -0,0 +1,260 @@
+//! IPv6 stateless address autoconfiguration (SLAAC), as in RFC 4862.
+//!
+//! Router advertisements are received on a raw ICMPv6 socket. Like DHCP,
+//! smoltcp sockets are shared by all interfaces, so only one interface can be
+//! configured by SLAAC at a time. Lifetimes of prefixes are not tracked, an
+//! address is kept until another prefix is advertised.
+
+use alloc::{sync::Arc, vec, vec::Vec};
+use lock::Mutex;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::{RawPacketMetadata, RawSocket, RawSocketBuffer, SocketHandle};
+use smoltcp::wire::{EthernetAddress, Icmpv6Packet, Icmpv6Repr, IpCidr, IpProtocol, IpVersion};
+use smoltcp::wire::{Ipv6Address, Ipv6Cidr, Ipv6Packet, Ipv6Repr};
+use smoltcp::wire::{NdiscPrefixInfoFlags, NdiscRepr};
+
+use super::{get_sockets, ipv6_eui64_address, timer_now_as_micros};
+use crate::scheme::NetScheme;
+
+/// Router solicitations sent before giving up, `MAX_RTR_SOLICITATIONS` in RFC 4861.
+const MAX_RTR_SOLICITATIONS: usize = 3;
+/// Interval between router solicitations in microseconds.
+const RTR_SOLICITATION_INTERVAL_US: u64 = 4_000_000;
+/// Hop limit of neighbor discovery messages.
+const NDISC_HOP_LIMIT: u8 = 255;
+
+/// A SLAAC client configuring an interface.
+pub struct SlaacClient {
+    iface: Arc<dyn NetScheme>,
+    handle: SocketHandle,
+    state: Mutex<SlaacState>,
+}
+
+struct SlaacState {
+    /// The configured address.
+    address: Option<Ipv6Cidr>,
+    /// The router used as the default gateway.
+    router: Option<Ipv6Address>,
+    /// Number of router solicitations sent.
+    solicitations: usize,
+    /// When to send the next router solicitation.
+    next_solicitation: u64,
+}
+
+/// The content of a router advertisement.
+struct RouterAdvert {
+    router: Ipv6Address,
+    /// Whether the router can be the default gateway.
+    default_router: bool,
+    /// The prefix for autoconfiguration.
+    prefix: Option<Ipv6Address>,
+}
+
+impl SlaacClient {
+    fn new(iface: Arc<dyn NetScheme>) -> Self {
+        let rx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 4], vec![0; 2048]);
+        let tx_buffer = RawSocketBuffer::new(vec![RawPacketMetadata::EMPTY; 4], vec![0; 512]);
+        let socket = RawSocket::new(IpVersion::Ipv6, IpProtocol::Icmpv6, rx_buffer, tx_buffer);
+        let handle = get_sockets().lock().add(socket);
+        Self {
+            iface,
+            handle,
+            state: Mutex::new(SlaacState {
+                address: None,
+                router: None,
+                solicitations: 0,
+                next_solicitation: 0,
+            }),
+        }
+    }
+
+    /// The configured address.
+    pub fn address(&self) -> Option<Ipv6Cidr> {
+        self.state.lock().address
+    }
+
+    /// Solicit routers, and apply received router advertisements.
+    pub fn update(&self) {
+        // the interface is locked before the sockets when polled
+        let src = self.link_local_address();
+        let mut adverts = Vec::new();
+        {
+            let sockets = get_sockets();
+            let mut sockets = sockets.lock();
+            let mut socket = sockets.get::<RawSocket>(self.handle);
+            while let Ok(packet) = socket.recv() {
+                if let Some(advert) = parse_router_advert(packet) {
+                    adverts.push(advert);
+                }
+            }
+            if let Some(src) = src {
+                self.solicit(&mut socket, src);
+            }
+        }
+        for advert in adverts {
+            self.apply(advert);
+        }
+    }
+
+    /// Send a router solicitation if no router is found yet.
+    fn solicit(&self, socket: &mut RawSocket, src: Ipv6Address) {
+        let mut state = self.state.lock();
+        let now = timer_now_as_micros();
+        if state.router.is_some()
+            || state.address.is_some()
+            || state.solicitations >= MAX_RTR_SOLICITATIONS
+            || now < state.next_solicitation
+        {
+            return;
+        }
+        let packet = router_solicit(src, self.iface.get_mac());
+        if socket.send_slice(&packet).is_ok() {
+            state.solicitations += 1;
+            state.next_solicitation = now + RTR_SOLICITATION_INTERVAL_US;
+        }
+    }
+
+    fn apply(&self, advert: RouterAdvert) {
+        let name = self.iface.get_ifname();
+        let mut state = self.state.lock();
+        if let Some(prefix) = advert.prefix {
+            let address = Ipv6Cidr::new(ipv6_eui64_address(prefix, self.iface.get_mac()), 64);
+            if state.address != Some(address) {
+                info!(
+                    "slaac: {} configured {} by {}",
+                    name, address, advert.router
+                );
+                let mut addrs = self.iface.get_ip_address();
+                if let Some(old) = state.address {
+                    addrs.retain(|addr| *addr != IpCidr::Ipv6(old));
+                }
+                // before the link-local address, to be preferred as the source address
+                let pos = addrs
+                    .iter()
+                    .position(|addr| matches!(addr, IpCidr::Ipv6(_)))
+                    .unwrap_or(addrs.len());
+                addrs.insert(pos, IpCidr::Ipv6(address));
+                match self.iface.set_ip_address(addrs) {
+                    Ok(_) => state.address = Some(address),
+                    Err(e) => warn!("slaac: failed to configure {}: {:?}", name, e),
+                }
+            }
+        }
+        let router = if advert.default_router {
+            Some(advert.router)
+        } else if state.router == Some(advert.router) {
+            // the router is no longer a default router
+            None
+        } else {
+            return;
+        };
+        if state.router != router {
+            info!("slaac: {} default router {:?}", name, router);
+            match self.iface.set_ipv6_gateway(router) {
+                Ok(_) => state.router = router,
+                Err(e) => warn!("slaac: failed to set the router of {}: {:?}", name, e),
+            }
+        }
+    }
+
+    fn link_local_address(&self) -> Option<Ipv6Address> {
+        self.iface
+            .get_ip_address()
+            .into_iter()
+            .find_map(|addr| match addr {
+                IpCidr::Ipv6(cidr) if cidr.address().is_link_local() => Some(cidr.address()),
+                _ => None,
+            })
+    }
+}
+
+impl Drop for SlaacClient {
+    fn drop(&mut self) {
+        get_sockets().lock().remove(self.handle);
+    }
+}
+
+/// Build a router solicitation from `src` to all routers.
+fn router_solicit(src: Ipv6Address, mac: EthernetAddress) -> Vec<u8> {
+    let dst = Ipv6Address::LINK_LOCAL_ALL_ROUTERS;
+    let icmp_repr = Icmpv6Repr::Ndisc(NdiscRepr::RouterSolicit { lladdr: Some(mac) });
+    let ip_repr = Ipv6Repr {
+        src_addr: src,
+        dst_addr: dst,
+        next_header: IpProtocol::Icmpv6,
+        payload_len: icmp_repr.buffer_len(),
+        hop_limit: NDISC_HOP_LIMIT,
+    };
+    let mut buf = vec![0; ip_repr.buffer_len() + icmp_repr.buffer_len()];
+    ip_repr.emit(&mut Ipv6Packet::new_unchecked(&mut buf));
+    let mut packet = Icmpv6Packet::new_unchecked(&mut buf[ip_repr.buffer_len()..]);
+    icmp_repr.emit(
+        &src.into(),
+        &dst.into(),
+        &mut packet,
+        &ChecksumCapabilities::default(),
+    );
+    buf
+}
+
+/// Parse an IPv6 packet if it's a valid router advertisement.
+fn parse_router_advert(packet: &[u8]) -> Option<RouterAdvert> {
+    let packet = Ipv6Packet::new_checked(packet).ok()?;
+    let ip_repr = Ipv6Repr::parse(&packet).ok()?;
+    // routers must be on the link, as in RFC 4861 section 6.1.2
+    if ip_repr.hop_limit != NDISC_HOP_LIMIT || !ip_repr.src_addr.is_link_local() {
+        return None;
+    }
+    let icmp = Icmpv6Packet::new_checked(packet.payload()).ok()?;
+    let icmp_repr = Icmpv6Repr::parse(
+        &ip_repr.src_addr.into(),
+        &ip_repr.dst_addr.into(),
+        &icmp,
+        &ChecksumCapabilities::default(),
+    )
+    .ok()?;
+    match icmp_repr {
+        Icmpv6Repr::Ndisc(NdiscRepr::RouterAdvert {
+            router_lifetime,
+            prefix_info,
+            ..
+        }) => {
+            let prefix = prefix_info
+                .filter(|info| {
+                    info.flags.contains(NdiscPrefixInfoFlags::ADDRCONF)
+                        && info.prefix_len == 64
+                        && info.valid_lifetime.total_millis() > 0
+                        && !info.prefix.is_link_local()
+                })
+                .map(|info| info.prefix);
+            Some(RouterAdvert {
+                router: ip_repr.src_addr,
+                default_router: router_lifetime.total_millis() > 0,
+                prefix,
+            })
+        }
+        _ => None,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref CLIENT: Mutex<Option<Arc<SlaacClient>>> = Mutex::new(None);
+}
+
+/// Start configuring `iface` by SLAAC, replacing the previous client.
+pub fn start(iface: Arc<dyn NetScheme>) -> Arc<SlaacClient> {
+    info!("slaac: start configuring {}", iface.get_ifname());
+    let client = Arc::new(SlaacClient::new(iface));
+    *CLIENT.lock() = Some(client.clone());
+    client.update();
+    client
+}
+
+/// Solicit routers and apply router advertisements, after the interfaces are
+/// polled.
+pub fn update() {
+    let client = CLIENT.lock().clone();
+    if let Some(client) = client {
+        client.update();
+    }
+}
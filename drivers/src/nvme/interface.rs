@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -5,8 +6,9 @@ use alloc::vec::Vec;
 use alloc::sync::Arc;
 use core::ptr::{read_volatile, write_volatile};
 
-use crate::scheme::{BlockScheme, Scheme};
-use crate::DeviceResult;
+use crate::scheme::{BlockFuture, BlockOp, BlockRequest, BlockScheme, Scheme};
+use crate::utils::{BlockRequestQueue, MergedRequest};
+use crate::{DeviceError, DeviceResult};
 
 use lock::Mutex;
 
@@ -22,6 +24,30 @@ pub struct NvmeInterface {
     bar: usize,
 
     irq: usize,
+
+    /// pending requests, not yet submitted to the device
+    queue: Mutex<BlockRequestQueue>,
+
+    io_state: Mutex<IoState>,
+
+    /// DMA buffers of all command slots
+    dma_va: usize,
+    dma_pa: usize,
+}
+
+/// Size of a logical block.
+const BLOCK_SIZE: usize = 512;
+/// Depth of the I/O submission and completion queues.
+const IO_QUEUE_DEPTH: usize = 64;
+/// Maximum transfer size of a command, which fits in PRP1 and PRP2.
+const MAX_TRANSFER: usize = PAGE_SIZE * 2;
+
+/// State of the commands submitted to the I/O queue.
+struct IoState {
+    /// command id -> request
+    in_flight: BTreeMap<u16, MergedRequest>,
+    /// free command ids, each command id owns a DMA buffer of `MAX_TRANSFER` bytes
+    free_slots: Vec<u16>,
 }
 
 impl NvmeInterface {
@@ -30,12 +56,24 @@ impl NvmeInterface {
 
         let io_queues = vec![Arc::new(Mutex::new(NvmeQueue::<ProviderImpl>::new(1, 0x8)))];
 
+        let (dma_va, dma_pa) = ProviderImpl::alloc_dma(MAX_TRANSFER * IO_QUEUE_DEPTH);
+
         let mut interface = NvmeInterface {
             name: String::from("nvme"),
             admin_queue,
             io_queues,
             bar,
             irq,
+            queue: Mutex::new(BlockRequestQueue::new(
+                BLOCK_SIZE,
+                MAX_TRANSFER / BLOCK_SIZE,
+            )),
+            io_state: Mutex::new(IoState {
+                in_flight: BTreeMap::new(),
+                free_slots: (0..IO_QUEUE_DEPTH as u16).rev().collect(),
+            }),
+            dma_va,
+            dma_pa,
         };
 
         interface.init();
@@ -125,7 +163,15 @@ impl NvmeInterface {
 
     pub fn nvme_alloc_io_queue(&mut self) {
         let mut admin_queue = self.admin_queue.lock();
-        // let io_queue = self.io_queues[0].lock();
+        let mut io_queue = self.io_queues[0].lock();
+
+        // clear the completion queue, valid entries are recognized by the phase tag
+        for i in 0..IO_QUEUE_DEPTH {
+            io_queue.cq[i].write(NvmeCompletion::default());
+        }
+        io_queue.cq_head = 0;
+        io_queue.cq_phase = 1;
+        io_queue.sq_tail = 0;
 
         let bar = self.bar;
         let dev_dbs = bar + NVME_REG_DBS;
@@ -158,9 +204,9 @@ impl NvmeInterface {
         cmd.opcode = 0x05;
         cmd.command_id = 0x3;
         cmd.nsid = 1;
-        cmd.prp1 = admin_queue.cq_pa as u64;
+        cmd.prp1 = io_queue.cq_pa as u64;
         cmd.cqid = 1;
-        cmd.qsize = 1023;
+        cmd.qsize = (IO_QUEUE_DEPTH - 1) as u16;
         cmd.cq_flags = NVME_QUEUE_PHYS_CONTIG | NVME_CQ_IRQ_ENABLED;
 
         // let mut cmd = NvmeCommonCommand::new();
@@ -190,9 +236,9 @@ impl NvmeInterface {
         cmd.opcode = 0x01;
         cmd.command_id = 0x4;
         cmd.nsid = 1;
-        cmd.prp1 = admin_queue.sq_pa as u64;
+        cmd.prp1 = io_queue.sq_pa as u64;
         cmd.sqid = 1;
-        cmd.qsize = 1023;
+        cmd.qsize = (IO_QUEUE_DEPTH - 1) as u16;
         cmd.sq_flags = 0x1;
         cmd.cqid = 0x1;
 
@@ -227,128 +273,160 @@ impl NvmeInterface {
     }
 }
 
+impl NvmeInterface {
+    fn slot_buffer(&self, slot: u16) -> (usize, usize) {
+        let offset = slot as usize * MAX_TRANSFER;
+        (self.dma_va + offset, self.dma_pa + offset)
+    }
+
+    /// Submit pending requests to the I/O submission queue, as long as there
+    /// are free command slots.
+    fn dispatch(&self) {
+        let mut io_queue = self.io_queues[0].lock();
+        let mut state = self.io_state.lock();
+        let mut queue = self.queue.lock();
+        let mut submitted = false;
+        while let Some(slot) = state.free_slots.pop() {
+            let req = match queue.pop() {
+                Some(req) => req,
+                None => {
+                    state.free_slots.push(slot);
+                    break;
+                }
+            };
+            let (va, pa) = self.slot_buffer(slot);
+            let len = req.len();
+            let mut cmd = match req.op {
+                BlockOp::Read => NvmeRWCommand::new_read_command(),
+                BlockOp::Write => {
+                    let buf = unsafe { core::slice::from_raw_parts_mut(va as *mut u8, len) };
+                    req.gather(buf);
+                    NvmeRWCommand::new_write_command()
+                }
+            };
+            // 每条命令至多两页, 只需要 prp1 和 prp2, 不需要 prp list
+            cmd.nsid = 1;
+            cmd.command_id = slot;
+            cmd.prp1 = pa as u64;
+            cmd.prp2 = if len > PAGE_SIZE {
+                (pa + PAGE_SIZE) as u64
+            } else {
+                0
+            };
+            cmd.slba = req.block_id as u64;
+            // NLB is 0's based
+            cmd.length = (req.nblocks - 1) as u16;
+            let common_cmd = unsafe { core::mem::transmute(cmd) };
+
+            let tail = io_queue.sq_tail;
+            io_queue.sq[tail].write(common_cmd);
+            io_queue.sq_tail = (tail + 1) % IO_QUEUE_DEPTH;
+            state.in_flight.insert(slot, req);
+            submitted = true;
+        }
+        if submitted {
+            // write doorbell register
+            let db = self.bar + NVME_REG_DBS + io_queue.db_offset;
+            unsafe { write_volatile(db as *mut u32, io_queue.sq_tail as u32) }
+        }
+    }
+
+    /// Handle new entries in the I/O completion queue.
+    fn process_completions(&self) {
+        let mut finished = Vec::new();
+        {
+            let mut io_queue = self.io_queues[0].lock();
+            let mut state = self.io_state.lock();
+            let mut processed = false;
+            loop {
+                let head = io_queue.cq_head;
+                let cqe = io_queue.cq[head].read();
+                if (cqe.status & 1) as usize != io_queue.cq_phase {
+                    break;
+                }
+                processed = true;
+                io_queue.cq_head = (head + 1) % IO_QUEUE_DEPTH;
+                if io_queue.cq_head == 0 {
+                    io_queue.cq_phase ^= 1;
+                }
+                let slot = cqe.command_id;
+                let mut req = match state.in_flight.remove(&slot) {
+                    Some(req) => req,
+                    None => {
+                        warn!("nvme: unknown command id {}", slot);
+                        continue;
+                    }
+                };
+                state.free_slots.push(slot);
+                let result = if cqe.status >> 1 == 0 {
+                    Ok(())
+                } else {
+                    warn!("nvme: command failed, status {:#x}", cqe.status >> 1);
+                    Err(DeviceError::IoError)
+                };
+                if result.is_ok() && req.op == BlockOp::Read {
+                    let (va, _) = self.slot_buffer(slot);
+                    let buf = unsafe { core::slice::from_raw_parts(va as *const u8, req.len()) };
+                    req.scatter(buf);
+                }
+                finished.push((req, result));
+            }
+            if processed {
+                // write completion queue head doorbell
+                let db = self.bar + NVME_REG_DBS + io_queue.db_offset + 0x4;
+                unsafe { write_volatile(db as *mut u32, io_queue.cq_head as u32) }
+            }
+        }
+        for (req, result) in finished {
+            req.complete(result);
+        }
+        self.dispatch();
+    }
+
+    /// Do a block request synchronously.
+    fn block_io(&self, req: BlockRequest) -> DeviceResult<BlockRequest> {
+        let (req, res) = self.submit(req).wait_with(|| self.process_completions());
+        res.map(|_| req)
+    }
+}
+
 impl BlockScheme for NvmeInterface {
     // 每个NVMe命令中有两个域：PRP1和PRP2，Host就是通过这两个域告诉SSD数据在内存中的位置或者数据需要写入的地址
     // 首先对prp1进行读写，如果数据还没完，就看数据量是不是在一个page内，在的话，只需要读写prp2内存地址就可以了，数据量大于1个page，就需要读出prp list
 
-    // 由于只读一块, 小于一页, 所以只需要prp1
-    // prp1 = dma_addr
-    // prp2 = 0
-
     // prp设置
     // uboot中对应实现 nvme_setup_prps
     // linux中对应实现 nvme_pci_setup_prps
 
     // SLBA = start logical block address
-    // length = 1 = 512B
     // 1 SLBA = 512B
     fn read_block(&self, block_id: usize, read_buf: &mut [u8]) -> DeviceResult {
-        let io_queue = self.io_queues[0].lock();
-        let db_offset = io_queue.db_offset;
-        let mut admin_queue = self.admin_queue.lock();
-
-        let bar = self.bar;
-
-        let dbs = bar + NVME_REG_DBS;
-        // let db_offset = io_queue.db_offset;
-
-        // 这里dma addr 就是buffer的地址
-        let ptr = read_buf.as_mut_ptr();
-        let addr = virt_to_phys(ptr as usize);
-
-        // build nvme read command
-        let mut cmd = NvmeRWCommand::new_read_command();
-        cmd.nsid = 1;
-        cmd.prp1 = addr as u64;
-        cmd.command_id = 101;
-        cmd.length = 1;
-        cmd.slba = block_id as u64;
-
-        //transfer to common command
-        let common_cmd = unsafe { core::mem::transmute(cmd) };
-
-        let tail = admin_queue.sq_tail;
-
-        // write command to sq
-        admin_queue.sq[tail].write(common_cmd);
-        admin_queue.sq_tail += 1;
-
-        // write doorbell register
-        unsafe { write_volatile((dbs + db_offset) as *mut u32, (tail + 1) as u32) }
-
-        // wait for command complete
-        loop {
-            let status = admin_queue.cq[tail].read();
-            if status.status != 0 {
-                // warn!("nvme cq :{:#x?}", status);
-
-                // write doorbell
-                unsafe { write_volatile((dbs + db_offset + 0x4) as *mut u32, (tail + 1) as u32) }
-                break;
-            }
-        }
-
-        // admin_queue.cq_head = admin_queue.sq_tail;
-
+        let req = self.block_io(BlockRequest::read(block_id, vec![vec![0; read_buf.len()]]))?;
+        read_buf.copy_from_slice(&req.bufs[0]);
         Ok(())
     }
 
-    // prp1 = write_buf physical address
-    // prp2 = 0
-    // SLBA = start logical block address
-    // length = 1 = 512B
     fn write_block(&self, block_id: usize, write_buf: &[u8]) -> DeviceResult {
-        // warn!("write block");
-        let io_queue = self.io_queues[0].lock();
-        let db_offset = io_queue.db_offset;
-        let mut admin_queue = self.admin_queue.lock();
-        let bar = self.bar;
-        let dbs = bar + NVME_REG_DBS;
-
-        let ptr = write_buf.as_ptr();
-
-        let addr = virt_to_phys(ptr as usize);
-
-        // build nvme write command
-        let mut cmd = NvmeRWCommand::new_write_command();
-        cmd.nsid = 1;
-        cmd.prp1 = addr as u64;
-        cmd.length = 1;
-        cmd.command_id = 100;
-        cmd.slba = block_id as u64;
-
-        // transmute to common command
-        let common_cmd = unsafe { core::mem::transmute(cmd) };
-
-        let mut tail = admin_queue.sq_tail;
-        if tail > 1023 {
-            tail = 0;
-        }
-
-        // push command to sq
-        admin_queue.sq[tail].write(common_cmd);
-        admin_queue.sq_tail += 1;
-
-        // write doorbell register
-        unsafe { write_volatile((dbs + db_offset) as *mut u32, (tail + 1) as u32) }
-
-        // wait for command complete
-        loop {
-            let status = admin_queue.cq[tail].read();
-            if status.status != 0 {
-                // warn!("nvme cq :{:#x?}", status);
-
-                // write doorbell
-                unsafe { write_volatile((dbs + db_offset + 0x4) as *mut u32, (tail + 1) as u32) }
-                break;
-            }
-        }
+        self.block_io(BlockRequest::write(block_id, vec![write_buf.to_vec()]))?;
         Ok(())
     }
 
     fn flush(&self) -> DeviceResult {
         Ok(())
     }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn submit(&self, req: BlockRequest) -> BlockFuture {
+        let future = self.queue.lock().push(req);
+        self.dispatch();
+        future
+    }
+    fn poll_completions(&self) {
+        self.process_completions();
+    }
 }
 
 impl Scheme for NvmeInterface {
@@ -356,8 +434,8 @@ impl Scheme for NvmeInterface {
         "nvme"
     }
 
-    fn handle_irq(&self, irq: usize) {
-        warn!("nvme device irq {}", irq);
+    fn handle_irq(&self, _irq: usize) {
+        self.process_completions();
     }
 }
 
@@ -1,5 +1,6 @@
 //! Re-export most commonly used driver types.
 
+pub use crate::scheme::block::{BlockOp, BlockRequest};
 pub use crate::scheme::display::{ColorFormat, DisplayInfo, FrameBuffer, Rectangle, RgbColor};
 pub use crate::scheme::input::{CapabilityType, InputCapability, InputEvent, InputEventType};
 pub use crate::scheme::irq::{IrqHandler, IrqPolarity, IrqTriggerMode};
@@ -7,5 +8,5 @@ pub use crate::{Device, DeviceError, DeviceResult};
 
 /// Re-export types from [`input`](crate::input).
 pub mod input {
-    pub use crate::input::{Mouse, MouseFlags, MouseState};
+    pub use crate::input::{KeyModifiers, Keyboard, Keymap, Mouse, MouseFlags, MouseState};
 }
@@ -0,0 +1,21 @@
+//! Real-time clock device driver.
+
+mod rtc_goldfish;
+mod rtc_pl031;
+mod time;
+
+#[cfg(target_arch = "x86_64")]
+mod rtc_cmos;
+
+#[cfg(feature = "allwinner")]
+mod rtc_sunxi;
+
+pub use rtc_goldfish::GoldfishRtc;
+pub use rtc_pl031::Pl031Rtc;
+pub use time::RtcTime;
+
+#[cfg(target_arch = "x86_64")]
+pub use rtc_cmos::CmosRtc;
+
+#[cfg(feature = "allwinner")]
+pub use rtc_sunxi::SunxiRtc;
@@ -0,0 +1,193 @@
+//! CMOS real-time clock of the PC, accessed through ports 0x70 and 0x71.
+use super::RtcTime;
+use crate::io::{Io, Pmio};
+use crate::scheme::{RtcScheme, Scheme};
+use crate::DeviceResult;
+use lock::Mutex;
+
+const CMOS_ADDR_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+/// Century register, as reported by the ACPI FADT on most machines.
+const REG_CENTURY: u8 = 0x32;
+
+/// Update in progress, the time registers are not consistent.
+const STATUS_A_UIP: u8 = 1 << 7;
+/// Halts updates while the time is being set.
+const STATUS_B_SET: u8 = 1 << 7;
+/// Binary instead of BCD values.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// 24-hour instead of 12-hour format.
+const STATUS_B_24H: u8 = 1 << 1;
+/// PM flag of the hours register in 12-hour format.
+const HOURS_PM: u8 = 1 << 7;
+
+/// Registers of the time, in the order of `REGS`.
+type RawTime = [u8; 7];
+
+const REGS: RawTime = [
+    REG_SECONDS,
+    REG_MINUTES,
+    REG_HOURS,
+    REG_DAY,
+    REG_MONTH,
+    REG_YEAR,
+    REG_CENTURY,
+];
+
+struct CmosRtcInner {
+    addr: Pmio<u8>,
+    data: Pmio<u8>,
+}
+
+impl CmosRtcInner {
+    fn read(&mut self, reg: u8) -> u8 {
+        self.addr.write(reg);
+        self.data.read()
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        self.addr.write(reg);
+        self.data.write(value);
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        while self.read(REG_STATUS_A) & STATUS_A_UIP != 0 {
+            core::hint::spin_loop();
+        }
+        let mut raw = RawTime::default();
+        for (v, &reg) in raw.iter_mut().zip(REGS.iter()) {
+            *v = self.read(reg);
+        }
+        raw
+    }
+
+    fn read_time(&mut self) -> RtcTime {
+        // read until two reads agree, in case an update happened in between
+        let mut raw = self.read_raw();
+        loop {
+            let again = self.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        let status = self.read(REG_STATUS_B);
+        let decode = |v: u8| {
+            if status & STATUS_B_BINARY != 0 {
+                v as u32
+            } else {
+                (v >> 4) as u32 * 10 + (v & 0xf) as u32
+            }
+        };
+        let [second, minute, hour, day, month, year, century] = raw;
+        let mut hours = decode(hour & !HOURS_PM);
+        if status & STATUS_B_24H == 0 {
+            hours %= 12;
+            if hour & HOURS_PM != 0 {
+                hours += 12;
+            }
+        }
+        let century = match decode(century) {
+            0 => 20,
+            c => c,
+        };
+        RtcTime {
+            year: century * 100 + decode(year),
+            month: decode(month),
+            day: decode(day),
+            hour: hours,
+            minute: decode(minute),
+            second: decode(second),
+        }
+    }
+
+    fn write_time(&mut self, time: &RtcTime) {
+        let status = self.read(REG_STATUS_B);
+        let encode = |v: u32| {
+            let v = v as u8;
+            if status & STATUS_B_BINARY != 0 {
+                v
+            } else {
+                (v / 10) << 4 | v % 10
+            }
+        };
+        let hour = if status & STATUS_B_24H != 0 {
+            encode(time.hour)
+        } else {
+            let pm = if time.hour >= 12 { HOURS_PM } else { 0 };
+            match time.hour % 12 {
+                0 => encode(12) | pm,
+                h => encode(h) | pm,
+            }
+        };
+        let raw: RawTime = [
+            encode(time.second),
+            encode(time.minute),
+            hour,
+            encode(time.day),
+            encode(time.month),
+            encode(time.year % 100),
+            encode(time.year / 100),
+        ];
+
+        self.write(REG_STATUS_B, status | STATUS_B_SET);
+        for (&v, &reg) in raw.iter().zip(REGS.iter()) {
+            self.write(reg, v);
+        }
+        self.write(REG_STATUS_B, status & !STATUS_B_SET);
+    }
+}
+
+pub struct CmosRtc {
+    inner: Mutex<CmosRtcInner>,
+}
+
+impl CmosRtc {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(CmosRtcInner {
+                addr: Pmio::new(CMOS_ADDR_PORT),
+                data: Pmio::new(CMOS_DATA_PORT),
+            }),
+        }
+    }
+}
+
+impl Default for CmosRtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheme for CmosRtc {
+    fn name(&self) -> &str {
+        "rtc-cmos"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // reading status C acknowledges the interrupt
+        self.inner.lock().read(REG_STATUS_C);
+    }
+}
+
+impl RtcScheme for CmosRtc {
+    fn read_epoch(&self) -> DeviceResult<u64> {
+        Ok(self.inner.lock().read_time().to_epoch())
+    }
+
+    fn write_epoch(&self, secs: u64) -> DeviceResult {
+        self.inner.lock().write_time(&RtcTime::from_epoch(secs));
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+//! Goldfish real-time clock, as on the QEMU RISC-V `virt` machine.
+use crate::scheme::{RtcScheme, Scheme};
+use crate::DeviceResult;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Low 32 bits of the time in nanoseconds, reading it latches `TIME_HIGH`.
+const TIME_LOW: usize = 0x00;
+/// High 32 bits of the time in nanoseconds, writing it sets the time with
+/// the following write of `TIME_LOW`.
+const TIME_HIGH: usize = 0x04;
+/// Enables the alarm interrupt.
+const IRQ_ENABLED: usize = 0x10;
+/// Clears the alarm.
+const CLEAR_ALARM: usize = 0x14;
+/// Clears the interrupt.
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+pub struct GoldfishRtc {
+    base: usize,
+}
+
+impl GoldfishRtc {
+    /// Create the driver with registers at `base`, with the alarm interrupt
+    /// disabled.
+    pub fn new(base: usize) -> Self {
+        let rtc = Self { base };
+        rtc.write_reg(IRQ_ENABLED, 0);
+        rtc.write_reg(CLEAR_ALARM, 1);
+        rtc
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+}
+
+impl Scheme for GoldfishRtc {
+    fn name(&self) -> &str {
+        "rtc-goldfish"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // alarm, not used yet
+        self.write_reg(CLEAR_INTERRUPT, 1);
+    }
+}
+
+impl RtcScheme for GoldfishRtc {
+    fn read_epoch(&self) -> DeviceResult<u64> {
+        let low = self.read_reg(TIME_LOW) as u64;
+        let high = self.read_reg(TIME_HIGH) as u64;
+        Ok((high << 32 | low) / NSEC_PER_SEC)
+    }
+
+    fn write_epoch(&self, secs: u64) -> DeviceResult {
+        let nsecs = secs * NSEC_PER_SEC;
+        self.write_reg(TIME_HIGH, (nsecs >> 32) as u32);
+        self.write_reg(TIME_LOW, nsecs as u32);
+        Ok(())
+    }
+}
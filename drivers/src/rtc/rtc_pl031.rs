@@ -0,0 +1,61 @@
+//! PL031 ARM PrimeCell real-time clock.
+use crate::scheme::{RtcScheme, Scheme};
+use crate::DeviceResult;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Data register, the current time in seconds.
+const RTCDR: usize = 0x00;
+/// Load register, sets the current time.
+const RTCLR: usize = 0x08;
+/// Control register, bit 0 starts the counter.
+const RTCCR: usize = 0x0c;
+/// Interrupt mask set or clear register.
+const RTCIMSC: usize = 0x10;
+/// Interrupt clear register.
+const RTCICR: usize = 0x1c;
+
+pub struct Pl031Rtc {
+    base: usize,
+}
+
+impl Pl031Rtc {
+    /// Create the driver with registers at `base`, and start the counter
+    /// with the alarm interrupt masked.
+    pub fn new(base: usize) -> Self {
+        let rtc = Self { base };
+        rtc.write_reg(RTCIMSC, 0);
+        rtc.write_reg(RTCICR, 1);
+        rtc.write_reg(RTCCR, 1);
+        rtc
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+}
+
+impl Scheme for Pl031Rtc {
+    fn name(&self) -> &str {
+        "rtc-pl031"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // alarm, not used yet
+        self.write_reg(RTCICR, 1);
+    }
+}
+
+impl RtcScheme for Pl031Rtc {
+    fn read_epoch(&self) -> DeviceResult<u64> {
+        Ok(self.read_reg(RTCDR) as u64)
+    }
+
+    fn write_epoch(&self, secs: u64) -> DeviceResult {
+        self.write_reg(RTCLR, secs as u32);
+        Ok(())
+    }
+}
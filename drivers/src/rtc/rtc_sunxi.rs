@@ -0,0 +1,86 @@
+//! Allwinner real-time clock of the D1, with a linear day counter.
+use crate::scheme::{RtcScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+use core::ptr::{read_volatile, write_volatile};
+
+/// Low-speed oscillator control register, with the busy bits of writes.
+const LOSC_CTRL: usize = 0x00;
+/// Date register, the number of days since the Unix epoch.
+const DATE: usize = 0x10;
+/// Time register, hours, minutes and seconds of the day.
+const TIME: usize = 0x14;
+
+/// A write to the date or time register is in progress.
+const LOSC_CTRL_ACC_MASK: u32 = 0b111 << 7;
+const DATE_DAY_MASK: u32 = 0xffff;
+
+const SECS_PER_DAY: u64 = 86400;
+const BUSY_TIMEOUT: usize = 0x10_0000;
+
+pub struct SunxiRtc {
+    base: usize,
+}
+
+impl SunxiRtc {
+    /// Create the driver with registers at `base`.
+    pub fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    fn read_reg(&self, reg: usize) -> u32 {
+        unsafe { read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write_reg(&self, reg: usize, value: u32) {
+        unsafe { write_volatile((self.base + reg) as *mut u32, value) }
+    }
+
+    /// Wait for the previous write of the date or time to be applied.
+    fn wait_idle(&self) -> DeviceResult {
+        for _ in 0..BUSY_TIMEOUT {
+            if self.read_reg(LOSC_CTRL) & LOSC_CTRL_ACC_MASK == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(DeviceError::IoError)
+    }
+}
+
+impl Scheme for SunxiRtc {
+    fn name(&self) -> &str {
+        "rtc-sunxi"
+    }
+}
+
+impl RtcScheme for SunxiRtc {
+    fn read_epoch(&self) -> DeviceResult<u64> {
+        // read the date again in case of midnight in between
+        let (date, time) = loop {
+            let date = self.read_reg(DATE);
+            let time = self.read_reg(TIME);
+            if date == self.read_reg(DATE) {
+                break (date, time);
+            }
+        };
+        let days = (date & DATE_DAY_MASK) as u64;
+        let hour = (time >> 16 & 0x1f) as u64;
+        let minute = (time >> 8 & 0x3f) as u64;
+        let second = (time & 0x3f) as u64;
+        Ok(days * SECS_PER_DAY + hour * 3600 + minute * 60 + second)
+    }
+
+    fn write_epoch(&self, secs: u64) -> DeviceResult {
+        let days = secs / SECS_PER_DAY;
+        if days > DATE_DAY_MASK as u64 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let rem = secs % SECS_PER_DAY;
+        let time = (rem / 3600) << 16 | (rem / 60 % 60) << 8 | rem % 60;
+        self.wait_idle()?;
+        self.write_reg(TIME, time as u32);
+        self.wait_idle()?;
+        self.write_reg(DATE, days as u32);
+        self.wait_idle()
+    }
+}
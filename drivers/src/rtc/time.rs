@@ -0,0 +1,123 @@
+//! Conversion between calendar time and seconds since the Unix epoch.
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// A calendar time in UTC, as kept by RTCs with date registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    /// Full year, e.g. 2024.
+    pub year: u32,
+    /// Month of the year, 1 to 12.
+    pub month: u32,
+    /// Day of the month, 1 to 31.
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: u32, month: u32, day: u32) -> i64 {
+    let y = year as i64 - (month <= 2) as i64;
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl RtcTime {
+    /// The calendar time of `secs` seconds since the Unix epoch.
+    pub fn from_epoch(secs: u64) -> Self {
+        let days = (secs / SECS_PER_DAY) as i64 + 719468;
+        let rem = secs % SECS_PER_DAY;
+        let era = days.div_euclid(146097);
+        let doe = days - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as i64;
+        Self {
+            year: year as u32,
+            month: month as u32,
+            day: day as u32,
+            hour: (rem / 3600) as u32,
+            minute: (rem / 60 % 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+
+    /// Seconds since the Unix epoch, saturated to zero for earlier times.
+    pub fn to_epoch(&self) -> u64 {
+        let days = days_from_civil(self.year, self.month, self.day);
+        let secs = days * SECS_PER_DAY as i64
+            + self.hour as i64 * 3600
+            + self.minute as i64 * 60
+            + self.second as i64;
+        secs.max(0) as u64
+    }
+
+    /// Day of the week, 0 for Sunday.
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 is a Thursday.
+        (days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7) as u32
+    }
+
+    /// Day of the year, 0 for January 1st.
+    pub fn yearday(&self) -> u32 {
+        (days_from_civil(self.year, self.month, self.day) - days_from_civil(self.year, 1, 1)) as u32
+    }
+
+    /// Whether all fields are in range, including the day in its month.
+    pub fn is_valid(&self) -> bool {
+        let leap = self.year % 4 == 0 && (self.year % 100 != 0 || self.year % 400 == 0);
+        let days_in_month = match self.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap => 29,
+            2 => 28,
+            _ => return false,
+        };
+        (1..=days_in_month).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn epoch_conversion() {
+        let t = RtcTime::from_epoch(0);
+        assert_eq!((t.year, t.month, t.day, t.hour), (1970, 1, 1, 0));
+        assert_eq!(t.weekday(), 4);
+
+        // 2024-02-29 12:34:56, a Thursday
+        let t = RtcTime::from_epoch(1709210096);
+        assert_eq!(
+            t,
+            RtcTime {
+                year: 2024,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 34,
+                second: 56,
+            }
+        );
+        assert_eq!(t.weekday(), 4);
+        assert_eq!(t.yearday(), 59);
+        assert!(t.is_valid());
+        assert_eq!(t.to_epoch(), 1709210096);
+
+        for secs in (0..4_102_444_800).step_by(86400 * 37 + 3601) {
+            assert_eq!(RtcTime::from_epoch(secs).to_epoch(), secs);
+        }
+    }
+}
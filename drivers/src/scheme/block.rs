@@ -1,8 +1,193 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use lock::Mutex;
+
 use super::Scheme;
 use crate::DeviceResult;
 
+/// Direction of a block I/O request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockOp {
+    Read,
+    Write,
+}
+
+/// A multi-block I/O request.
+///
+/// The request starts at `block_id`, and the segments of the scatter-gather
+/// buffer `bufs` are transferred in order. The total length of `bufs` must be
+/// a multiple of the block size.
+pub struct BlockRequest {
+    pub op: BlockOp,
+    pub block_id: usize,
+    pub bufs: Vec<Vec<u8>>,
+}
+
+impl BlockRequest {
+    /// Create a request to read into `bufs` from `block_id`.
+    pub fn read(block_id: usize, bufs: Vec<Vec<u8>>) -> Self {
+        Self {
+            op: BlockOp::Read,
+            block_id,
+            bufs,
+        }
+    }
+
+    /// Create a request to write `bufs` to `block_id`.
+    pub fn write(block_id: usize, bufs: Vec<Vec<u8>>) -> Self {
+        Self {
+            op: BlockOp::Write,
+            block_id,
+            bufs,
+        }
+    }
+
+    /// Total length of the buffer in bytes.
+    pub fn len(&self) -> usize {
+        self.bufs.iter().map(|b| b.len()).sum()
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Default)]
+struct CompletionInner {
+    result: Option<(BlockRequest, DeviceResult)>,
+    waker: Option<Waker>,
+}
+
+/// The completion state of a submitted [`BlockRequest`].
+///
+/// Drivers complete it (usually in the interrupt handler), which wakes up the
+/// associated [`BlockFuture`].
+#[derive(Default)]
+pub struct BlockCompletion {
+    inner: Mutex<CompletionInner>,
+}
+
+impl BlockCompletion {
+    /// Create a new pending completion.
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Mark the request as completed with `result`.
+    pub fn complete(&self, req: BlockRequest, result: DeviceResult) {
+        let waker = {
+            let mut inner = self.inner.lock();
+            inner.result = Some((req, result));
+            inner.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Whether the request has been completed.
+    pub fn is_completed(&self) -> bool {
+        self.inner.lock().result.is_some()
+    }
+}
+
+/// A future resolved when a submitted [`BlockRequest`] completes, which
+/// returns the request with its buffers back.
+pub struct BlockFuture {
+    completion: Arc<BlockCompletion>,
+}
+
+impl BlockFuture {
+    /// Create a future waiting for `completion`.
+    pub fn new(completion: Arc<BlockCompletion>) -> Self {
+        Self { completion }
+    }
+
+    /// Create a future that is already completed.
+    pub fn ready(req: BlockRequest, result: DeviceResult) -> Self {
+        let completion = BlockCompletion::new();
+        completion.complete(req, result);
+        Self { completion }
+    }
+
+    /// Wait for the completion synchronously, calling `poll` repeatedly until
+    /// the request completes.
+    ///
+    /// It's used when there is no executor to run the future. `poll` may
+    /// process completions itself if the interrupt may be unavailable (e.g.
+    /// interrupts are disabled), or wait for the next interrupt.
+    pub fn wait_with(self, mut poll: impl FnMut()) -> (BlockRequest, DeviceResult) {
+        loop {
+            if let Some(ret) = self.completion.inner.lock().result.take() {
+                return ret;
+            }
+            poll();
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Future for BlockFuture {
+    type Output = (BlockRequest, DeviceResult);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.completion.inner.lock();
+        match inner.result.take() {
+            Some(ret) => Poll::Ready(ret),
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
 pub trait BlockScheme: Scheme {
+    /// Read a block synchronously, busy-polling for the completion.
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult;
+    /// Write a block synchronously, busy-polling for the completion.
     fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult;
     fn flush(&self) -> DeviceResult;
+
+    /// Size of a block in bytes.
+    fn block_size(&self) -> usize {
+        512
+    }
+
+    /// Submit a multi-block request, returns a future resolved when the
+    /// request completes.
+    ///
+    /// The default implementation does the I/O synchronously with
+    /// [`read_block`](Self::read_block) and [`write_block`](Self::write_block).
+    fn submit(&self, mut req: BlockRequest) -> BlockFuture {
+        let block_size = self.block_size();
+        if req.len() % block_size != 0 {
+            return BlockFuture::ready(req, Err(crate::DeviceError::InvalidParam));
+        }
+        let mut block_id = req.block_id;
+        let mut result = Ok(());
+        'outer: for buf in req.bufs.iter_mut() {
+            for chunk in buf.chunks_mut(block_size) {
+                result = match req.op {
+                    BlockOp::Read => self.read_block(block_id, chunk),
+                    BlockOp::Write => self.write_block(block_id, chunk),
+                };
+                if result.is_err() {
+                    break 'outer;
+                }
+                block_id += 1;
+            }
+        }
+        BlockFuture::ready(req, result)
+    }
+
+    /// Complete the finished requests without the interrupt, e.g. when it is
+    /// not delivered because interrupts are disabled.
+    ///
+    /// Drivers completing requests in [`submit`](Self::submit) don't need it.
+    fn poll_completions(&self) {}
 }
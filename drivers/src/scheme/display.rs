@@ -1,5 +1,5 @@
 use super::Scheme;
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
 
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,7 +14,7 @@ pub enum ColorFormat {
     ARGB8888,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rectangle {
     pub x: u32,
     pub y: u32,
@@ -86,6 +86,22 @@ impl ColorFormat {
     }
 }
 
+impl Rectangle {
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(&self, other: &Self) -> Self {
+        let left = self.x.min(other.x);
+        let top = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
+}
+
 impl<'a> FrameBuffer<'a> {
     /// # Safety
     ///
@@ -170,9 +186,18 @@ pub trait DisplayScheme: Scheme {
     #[inline]
     fn draw_pixel(&self, x: u32, y: u32, color: RgbColor) {
         let info = self.info();
+        if x >= info.width || y >= info.height {
+            return;
+        }
         let offset = (x + y * info.width) as usize * info.format.bytes() as usize;
         if offset < info.fb_size {
             unsafe { self.fb().write_color(offset, color, info.format) };
+            self.mark_dirty(&Rectangle {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
         }
     }
 
@@ -183,11 +208,22 @@ pub trait DisplayScheme: Scheme {
         let right = (left + rect.width).min(info.width);
         let top = rect.y.min(info.height);
         let bottom = (top + rect.height).min(info.height);
+        let bytes = info.format.bytes() as usize;
+        let mut fb = self.fb();
         for j in top..bottom {
             for i in left..right {
-                self.draw_pixel(i, j, color);
+                let offset = (i + j * info.width) as usize * bytes;
+                if offset < info.fb_size {
+                    unsafe { fb.write_color(offset, color, info.format) };
+                }
             }
         }
+        self.mark_dirty(&Rectangle {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        });
     }
 
     /// Clear the screen with `color`.
@@ -210,9 +246,38 @@ pub trait DisplayScheme: Scheme {
         false
     }
 
-    /// Flush framebuffer to screen.
+    /// Mark a rectangle of the framebuffer as modified, to be pushed to
+    /// screen by the next [`flush`](Self::flush).
+    #[inline]
+    fn mark_dirty(&self, _rect: &Rectangle) {}
+
+    /// Whether [`flush`](Self::flush) pushes only the marked rectangles, which
+    /// is the default, or the whole framebuffer, e.g. when it's mapped to user
+    /// space, where writes are not tracked. Tracking is enabled again once the
+    /// framebuffer is no longer mapped.
+    #[inline]
+    fn track_dirty(&self, _enable: bool) {}
+
+    /// Flush the modified parts of the framebuffer to screen.
     #[inline]
     fn flush(&self) -> DeviceResult {
         Ok(())
     }
+
+    /// Change the visible resolution. The framebuffer may be reallocated, so
+    /// [`info`](Self::info) should be queried again.
+    fn set_mode(&self, _width: u32, _height: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Set the image of the hardware cursor, 64x64 pixels in ARGB8888, with
+    /// the hot spot at (`hot_x`, `hot_y`).
+    fn set_cursor(&self, _image: &[u8], _hot_x: u32, _hot_y: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Move the hot spot of the hardware cursor to (`x`, `y`).
+    fn move_cursor(&self, _x: u32, _y: u32) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
 }
@@ -71,12 +71,32 @@ pub trait IrqScheme: Scheme {
         unimplemented!()
     }
 
+    /// Get the address and data of the MSI message that the PCI device with
+    /// `requester_id` writes to trigger the first IRQ of `block`, which is
+    /// allocated by msi_alloc_block(). Other IRQs of the block are triggered by
+    /// adding the MSI ID to the data.
+    fn msi_target(&self, _block: Range<usize>, _requester_id: usize) -> DeviceResult<(u64, u32)> {
+        unimplemented!()
+    }
+
     /// Init irq for current cpu.
     /// Some IRQ hardware requires per-CPU initialization.
     fn init_hart(&self) {
         unimplemented!()
     }
 
+    /// [for aarch64] Acknowledge the highest priority pending interrupt of the
+    /// current CPU, returns its IRQ number or `usize::MAX` if it's spurious.
+    fn pending_irq(&self) -> usize {
+        unimplemented!()
+    }
+
+    /// [for aarch64] Send the software generated interrupt `irq_num` to the
+    /// CPU whose affinity in `MPIDR_EL1` is `cpu_id`.
+    fn send_sgi(&self, _cpu_id: usize, _irq_num: usize) -> DeviceResult {
+        unimplemented!()
+    }
+
     /// [for x86_64] enable apic timer
     fn apic_timer_enable(&self) {
         unimplemented!()
@@ -10,7 +10,10 @@ pub(super) mod display;
 pub(super) mod input;
 pub(super) mod irq;
 pub(super) mod net;
+pub(super) mod rng;
+pub(super) mod rtc;
 pub(super) mod uart;
+pub(super) mod vsock;
 
 #[macro_use]
 pub(super) mod event;
@@ -18,13 +21,16 @@ pub(super) use impl_event_scheme;
 
 use alloc::sync::Arc;
 
-pub use block::BlockScheme;
+pub use block::{BlockCompletion, BlockFuture, BlockOp, BlockRequest, BlockScheme};
 pub use display::DisplayScheme;
 pub use event::EventScheme;
 pub use input::InputScheme;
 pub use irq::IrqScheme;
 pub use net::NetScheme;
+pub use rng::RngScheme;
+pub use rtc::RtcScheme;
 pub use uart::UartScheme;
+pub use vsock::{VsockHeader, VsockOp, VsockScheme, VSOCK_HOST_CID, VSOCK_TYPE_STREAM};
 
 /// Common of all device drivers.
 ///
@@ -1,8 +1,10 @@
 use super::Scheme;
-use crate::DeviceResult;
+use crate::net::capture::FrameSink;
+use crate::{DeviceError, DeviceResult};
 use alloc::string::String;
+use alloc::sync::Weak;
 use alloc::vec::Vec;
-use smoltcp::wire::{EthernetAddress, IpCidr};
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address};
 
 pub trait NetScheme: Scheme {
     fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize>;
@@ -11,4 +13,61 @@ pub trait NetScheme: Scheme {
     fn get_ifname(&self) -> String;
     fn get_ip_address(&self) -> Vec<IpCidr>;
     fn poll(&self) -> DeviceResult;
+
+    /// Replace the IP addresses of the interface.
+    fn set_ip_address(&self, _addrs: Vec<IpCidr>) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Get the IPv4 default gateway.
+    fn get_ipv4_gateway(&self) -> Option<Ipv4Address> {
+        None
+    }
+
+    /// Set the IPv4 default gateway, or remove it if `None`.
+    fn set_ipv4_gateway(&self, _gateway: Option<Ipv4Address>) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Get the IPv6 default gateway.
+    fn get_ipv6_gateway(&self) -> Option<Ipv6Address> {
+        None
+    }
+
+    /// Set the IPv6 default gateway, or remove it if `None`.
+    fn set_ipv6_gateway(&self, _gateway: Option<Ipv6Address>) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Add a route to `dst` via `gateway`, replacing the one to the same
+    /// destination.
+    fn add_route(&self, _dst: IpCidr, _gateway: IpAddress) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Remove the route to `dst`.
+    fn del_route(&self, _dst: IpCidr) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Deliver frames received and sent by the interface to `sink`, until it's
+    /// dropped.
+    fn attach_capture(&self, _sink: Weak<dyn FrameSink>) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Join the IPv4 multicast `group`.
+    fn join_multicast_group(&self, _group: Ipv4Address) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Leave the IPv4 multicast `group`.
+    fn leave_multicast_group(&self, _group: Ipv4Address) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
+
+    /// Enable or disable the promiscuous mode.
+    fn set_promiscuous(&self, _enable: bool) -> DeviceResult {
+        Err(DeviceError::NotSupported)
+    }
 }
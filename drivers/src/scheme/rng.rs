@@ -0,0 +1,8 @@
+use super::Scheme;
+use crate::DeviceResult;
+
+pub trait RngScheme: Scheme {
+    /// Fill `buf` with random bytes from the device, returns the number of
+    /// bytes filled, which may be less than its length.
+    fn fill(&self, buf: &mut [u8]) -> DeviceResult<usize>;
+}
@@ -0,0 +1,10 @@
+use super::Scheme;
+use crate::DeviceResult;
+
+pub trait RtcScheme: Scheme {
+    /// Returns the wall-clock time in seconds since the Unix epoch.
+    fn read_epoch(&self) -> DeviceResult<u64>;
+
+    /// Set the wall-clock time in seconds since the Unix epoch.
+    fn write_epoch(&self, secs: u64) -> DeviceResult;
+}
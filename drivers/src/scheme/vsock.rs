@@ -0,0 +1,64 @@
+use alloc::vec::Vec;
+
+use super::Scheme;
+use crate::DeviceResult;
+
+/// The context ID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// Type of stream sockets in [`VsockHeader::socket_type`].
+pub const VSOCK_TYPE_STREAM: u16 = 1;
+
+numeric_enum_macro::numeric_enum! {
+    #[repr(u16)]
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    /// Operations of vsock packets.
+    pub enum VsockOp {
+        /// Connect to a listening port.
+        Request = 1,
+        /// Accept a connection.
+        Response = 2,
+        /// Reset a connection, or refuse to connect.
+        Rst = 3,
+        /// No more data will be received or sent, given in the flags.
+        Shutdown = 4,
+        /// Data in the payload.
+        Rw = 5,
+        /// Tell the peer about the receive buffer.
+        CreditUpdate = 6,
+        /// Ask the peer for a credit update.
+        CreditRequest = 7,
+    }
+}
+
+/// The header of a vsock packet, `struct virtio_vsock_hdr`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VsockHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    /// Length of the payload.
+    pub len: u32,
+    pub socket_type: u16,
+    pub op: u16,
+    pub flags: u32,
+    /// Size of the receive buffer of the sender.
+    pub buf_alloc: u32,
+    /// Bytes taken from the receive buffer by the sender.
+    pub fwd_cnt: u32,
+}
+
+/// Transport of packets between the guest and the host, while connections
+/// are managed by the socket layer.
+pub trait VsockScheme: Scheme {
+    /// Returns the context ID of the guest.
+    fn guest_cid(&self) -> u64;
+
+    /// Send a packet, whose `len` and source CID are filled by the driver.
+    fn send(&self, hdr: &VsockHeader, payload: &[u8]) -> DeviceResult;
+
+    /// Take a received packet and its payload, returns `None` if there is no
+    /// more.
+    fn recv(&self) -> DeviceResult<Option<(VsockHeader, Vec<u8>)>>;
+}
@@ -0,0 +1,233 @@
+//! Standard USB requests and descriptors.
+
+use alloc::vec::Vec;
+
+pub const REQ_DIR_IN: u8 = 0x80;
+pub const REQ_TYPE_CLASS: u8 = 0x20;
+pub const REQ_RECIPIENT_INTERFACE: u8 = 0x01;
+
+pub const REQ_GET_DESCRIPTOR: u8 = 6;
+pub const REQ_SET_CONFIGURATION: u8 = 9;
+
+pub const DESC_DEVICE: u8 = 1;
+pub const DESC_CONFIGURATION: u8 = 2;
+pub const DESC_INTERFACE: u8 = 4;
+pub const DESC_ENDPOINT: u8 = 5;
+
+pub const TRANSFER_BULK: u8 = 2;
+pub const TRANSFER_INTERRUPT: u8 = 3;
+
+/// The setup packet of a control transfer.
+#[derive(Debug, Clone, Copy)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Whether the data stage is from the device to the host.
+    pub fn is_in(&self) -> bool {
+        self.request_type & REQ_DIR_IN != 0
+    }
+
+    pub fn to_u64(self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::SIZE || buf[1] != DESC_DEVICE {
+            return None;
+        }
+        Some(Self {
+            usb_version: le16(buf, 2),
+            class: buf[4],
+            subclass: buf[5],
+            protocol: buf[6],
+            max_packet_size0: buf[7],
+            vendor_id: le16(buf, 8),
+            product_id: le16(buf, 10),
+            num_configurations: buf[17],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn number(&self) -> u8 {
+        self.address & 0xf
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0x3
+    }
+
+    /// Device context index of the endpoint in xHCI.
+    pub fn dci(&self) -> u8 {
+        self.number() * 2 + self.is_in() as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alt_setting: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+impl InterfaceDescriptor {
+    /// Find the first endpoint of the transfer type and the direction.
+    pub fn find_endpoint(&self, transfer_type: u8, is_in: bool) -> Option<EndpointDescriptor> {
+        self.endpoints
+            .iter()
+            .find(|ep| ep.transfer_type() == transfer_type && ep.is_in() == is_in)
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDescriptor {
+    pub value: u8,
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigDescriptor {
+    pub const HEADER_SIZE: usize = 9;
+
+    /// Total length of the configuration with all its descriptors.
+    pub fn total_length(header: &[u8]) -> usize {
+        le16(header, 2) as usize
+    }
+
+    /// Parse the configuration descriptor followed by the interface and
+    /// endpoint descriptors, other descriptors are skipped.
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < Self::HEADER_SIZE || buf[1] != DESC_CONFIGURATION {
+            return None;
+        }
+        let mut config = Self {
+            value: buf[5],
+            interfaces: Vec::new(),
+        };
+        let mut pos = buf[0] as usize;
+        while pos + 2 <= buf.len() {
+            let len = buf[pos] as usize;
+            if len < 2 || pos + len > buf.len() {
+                break;
+            }
+            let desc = &buf[pos..pos + len];
+            match desc[1] {
+                DESC_INTERFACE if len >= 9 => config.interfaces.push(InterfaceDescriptor {
+                    number: desc[2],
+                    alt_setting: desc[3],
+                    class: desc[5],
+                    subclass: desc[6],
+                    protocol: desc[7],
+                    endpoints: Vec::new(),
+                }),
+                DESC_ENDPOINT if len >= 7 => {
+                    if let Some(iface) = config.interfaces.last_mut() {
+                        iface.endpoints.push(EndpointDescriptor {
+                            address: desc[2],
+                            attributes: desc[3],
+                            max_packet_size: le16(desc, 4) & 0x7ff,
+                            interval: desc[6],
+                        });
+                    }
+                }
+                _ => {}
+            }
+            pos += len;
+        }
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        // a boot keyboard with a HID descriptor between the interface and
+        // the endpoint, then a bulk-only mass storage interface
+        #[rustfmt::skip]
+        let buf = [
+            9, 2, 57, 0, 2, 1, 0, 0xa0, 50,
+            9, 4, 0, 0, 1, 3, 1, 1, 0,
+            9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0,
+            7, 5, 0x81, 3, 8, 0, 10,
+            9, 4, 1, 0, 2, 8, 6, 0x50, 0,
+            7, 5, 0x82, 2, 0, 2, 0,
+            7, 5, 0x02, 2, 0, 2, 0,
+        ];
+        assert_eq!(ConfigDescriptor::total_length(&buf), buf.len());
+        let config = ConfigDescriptor::parse(&buf).unwrap();
+        assert_eq!(config.value, 1);
+        assert_eq!(config.interfaces.len(), 2);
+
+        let kbd = &config.interfaces[0];
+        assert_eq!((kbd.class, kbd.subclass, kbd.protocol), (3, 1, 1));
+        let ep = kbd.find_endpoint(TRANSFER_INTERRUPT, true).unwrap();
+        assert_eq!((ep.dci(), ep.max_packet_size, ep.interval), (3, 8, 10));
+
+        let msc = &config.interfaces[1];
+        assert_eq!(msc.endpoints.len(), 2);
+        assert_eq!(msc.find_endpoint(TRANSFER_BULK, true).unwrap().dci(), 5);
+        assert_eq!(msc.find_endpoint(TRANSFER_BULK, false).unwrap().dci(), 4);
+        assert_eq!(msc.find_endpoint(TRANSFER_INTERRUPT, false), None);
+    }
+
+    #[test]
+    fn setup_packet() {
+        let setup = SetupPacket {
+            request_type: REQ_DIR_IN,
+            request: REQ_GET_DESCRIPTOR,
+            value: (DESC_DEVICE as u16) << 8,
+            index: 0,
+            length: 18,
+        };
+        assert!(setup.is_in());
+        assert_eq!(setup.to_u64(), 0x0012_0000_0100_0680);
+    }
+}
@@ -0,0 +1,183 @@
+//! A USB device attached to a root hub port.
+
+use alloc::{sync::Arc, vec::Vec};
+
+use lock::Mutex;
+
+use super::descriptor::*;
+use super::xhci::*;
+use super::{alloc_dma, PAGE_SIZE};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+pub struct UsbDevice {
+    xhci: Arc<Xhci>,
+    slot: u8,
+    port: usize,
+    speed: u8,
+    /// DMA buffer of control transfers, of one page.
+    ctrl_buf: Mutex<(VirtAddr, PhysAddr)>,
+}
+
+impl UsbDevice {
+    /// Reset the port, then address the device and read its descriptor.
+    pub fn enumerate(xhci: Arc<Xhci>, port: usize) -> DeviceResult<Self> {
+        let speed = xhci.reset_port(port)?;
+        let max_packet_size0 = match speed {
+            SPEED_LOW | SPEED_FULL => 8,
+            SPEED_HIGH => 64,
+            SPEED_SUPER => 512,
+            _ => return Err(DeviceError::NotSupported),
+        };
+        let slot = xhci.address_device(port, speed, max_packet_size0)?;
+        let dev = Self {
+            xhci,
+            slot,
+            port,
+            speed,
+            ctrl_buf: Mutex::new(alloc_dma(PAGE_SIZE, PAGE_SIZE)),
+        };
+        // full-speed devices may have a larger control endpoint, which is in
+        // the first 8 bytes of the device descriptor
+        if speed == SPEED_FULL {
+            let head = dev.get_descriptor(DESC_DEVICE, 0, 8)?;
+            if head.len() == 8 && head[7] as u16 != max_packet_size0 {
+                dev.xhci.update_ep0(slot, head[7] as u16)?;
+            }
+        }
+        let buf = dev.get_descriptor(DESC_DEVICE, 0, DeviceDescriptor::SIZE as u16)?;
+        let desc = DeviceDescriptor::parse(&buf).ok_or(DeviceError::IoError)?;
+        info!(
+            "usb: port {}: device {:04x}:{:04x}, USB {:x}.{:02x}, slot {}",
+            port,
+            desc.vendor_id,
+            desc.product_id,
+            desc.usb_version >> 8,
+            desc.usb_version & 0xff,
+            slot
+        );
+        Ok(dev)
+    }
+
+    pub fn xhci(&self) -> &Arc<Xhci> {
+        &self.xhci
+    }
+
+    pub fn port(&self) -> usize {
+        self.port
+    }
+
+    /// Do a control transfer which reads `setup.length` bytes from the device.
+    pub fn control_in(&self, setup: SetupPacket) -> DeviceResult<Vec<u8>> {
+        let len = setup.length as usize;
+        if len > PAGE_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        let buf = self.ctrl_buf.lock();
+        let n = self.xhci.control_transfer(self.slot, setup, buf.1, len)?;
+        Ok(unsafe { core::slice::from_raw_parts(buf.0 as *const u8, n) }.to_vec())
+    }
+
+    /// Do a control transfer which writes `data` to the device.
+    pub fn control_out(&self, setup: SetupPacket, data: &[u8]) -> DeviceResult {
+        if data.len() > PAGE_SIZE || data.len() != setup.length as usize {
+            return Err(DeviceError::InvalidParam);
+        }
+        let buf = self.ctrl_buf.lock();
+        let dst = unsafe { core::slice::from_raw_parts_mut(buf.0 as *mut u8, data.len()) };
+        dst.copy_from_slice(data);
+        self.xhci
+            .control_transfer(self.slot, setup, buf.1, data.len())?;
+        Ok(())
+    }
+
+    pub fn get_descriptor(&self, desc_type: u8, index: u8, len: u16) -> DeviceResult<Vec<u8>> {
+        self.control_in(SetupPacket {
+            request_type: REQ_DIR_IN,
+            request: REQ_GET_DESCRIPTOR,
+            value: (desc_type as u16) << 8 | index as u16,
+            index: 0,
+            length: len,
+        })
+    }
+
+    /// Read the first configuration with all its interfaces and endpoints.
+    pub fn get_configuration(&self) -> DeviceResult<ConfigDescriptor> {
+        let header = self.get_descriptor(DESC_CONFIGURATION, 0, 9)?;
+        if header.len() < ConfigDescriptor::HEADER_SIZE {
+            return Err(DeviceError::IoError);
+        }
+        let len = ConfigDescriptor::total_length(&header);
+        let buf = self.get_descriptor(DESC_CONFIGURATION, 0, len as u16)?;
+        ConfigDescriptor::parse(&buf).ok_or(DeviceError::IoError)
+    }
+
+    pub fn set_configuration(&self, value: u8) -> DeviceResult {
+        let setup = SetupPacket {
+            request_type: 0,
+            request: REQ_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        };
+        self.control_out(setup, &[])
+    }
+
+    /// Add the bulk and interrupt endpoints to the device slot.
+    pub fn configure_endpoints(&self, eps: &[EndpointDescriptor]) -> DeviceResult {
+        let mut configs = Vec::new();
+        for ep in eps {
+            let (ep_type, interval) = match (ep.transfer_type(), ep.is_in()) {
+                (TRANSFER_BULK, true) => (EP_TYPE_BULK_IN, 0),
+                (TRANSFER_BULK, false) => (EP_TYPE_BULK_OUT, 0),
+                (TRANSFER_INTERRUPT, true) => (EP_TYPE_INTERRUPT_IN, self.interval(ep)),
+                (TRANSFER_INTERRUPT, false) => (EP_TYPE_INTERRUPT_OUT, self.interval(ep)),
+                _ => return Err(DeviceError::NotSupported),
+            };
+            configs.push(EndpointConfig {
+                dci: ep.dci(),
+                ep_type,
+                max_packet_size: ep.max_packet_size,
+                interval,
+            });
+        }
+        self.xhci.configure_endpoints(self.slot, &configs)
+    }
+
+    /// The service interval of an interrupt endpoint, as the exponent of 125us.
+    fn interval(&self, ep: &EndpointDescriptor) -> u8 {
+        match self.speed {
+            SPEED_HIGH | SPEED_SUPER => ep.interval.max(1).min(16) - 1,
+            _ => {
+                // in frames of 1ms, rounded down to a power of 2
+                let microframes = ep.interval.max(1) as u32 * 8;
+                (31 - microframes.leading_zeros()) as u8
+            }
+        }
+    }
+
+    /// Transfer `len` bytes at `paddr` on a bulk or interrupt endpoint
+    /// synchronously, returns the number of bytes transferred.
+    pub fn transfer(
+        &self,
+        ep: &EndpointDescriptor,
+        paddr: PhysAddr,
+        len: usize,
+    ) -> DeviceResult<usize> {
+        self.xhci.transfer(self.slot, ep.dci(), paddr, len)
+    }
+
+    /// Queue a transfer on the endpoint, which is completed by its handler.
+    pub fn queue_transfer(
+        &self,
+        ep: &EndpointDescriptor,
+        paddr: PhysAddr,
+        len: usize,
+    ) -> DeviceResult {
+        self.xhci.queue_transfer(self.slot, ep.dci(), paddr, len)
+    }
+
+    /// Handle the transfer events of the endpoint with `handler`.
+    pub fn set_handler(&self, ep: &EndpointDescriptor, handler: Arc<dyn EndpointHandler>) {
+        self.xhci.set_handler(self.slot, ep.dci(), handler)
+    }
+}
@@ -0,0 +1,234 @@
+//! HID class driver of boot protocol keyboards and mice.
+
+use alloc::sync::Arc;
+
+use lock::Mutex;
+
+use super::descriptor::*;
+use super::device::UsbDevice;
+use super::xhci::{EndpointHandler, Trb, COMPLETION_SHORT_PACKET, COMPLETION_SUCCESS};
+use super::{alloc_dma, HID_PROTOCOL_KEYBOARD, PAGE_SIZE};
+use crate::input::input_event_codes::{ev::*, key::*, rel::*, syn::*};
+use crate::prelude::{CapabilityType, InputCapability, InputEvent, InputEventType};
+use crate::scheme::{impl_event_scheme, InputScheme, Scheme};
+use crate::utils::EventListener;
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const HID_REQ_SET_IDLE: u8 = 0x0a;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0b;
+const HID_BOOT_PROTOCOL: u16 = 0;
+
+/// Size of boot keyboard reports.
+const KBD_REPORT_SIZE: usize = 8;
+/// Key code of keyboard rollover errors, reported in all key slots.
+const KBD_ERROR_ROLLOVER: u8 = 0x01;
+
+/// Key codes of the modifier bits in the first byte of boot keyboard reports.
+const MODIFIER_KEYS: [u16; 8] = [
+    KEY_LEFTCTRL,
+    KEY_LEFTSHIFT,
+    KEY_LEFTALT,
+    KEY_LEFTMETA,
+    KEY_RIGHTCTRL,
+    KEY_RIGHTSHIFT,
+    KEY_RIGHTALT,
+    KEY_RIGHTMETA,
+];
+
+/// Linux key codes of HID keyboard usages, as `usb_kbd_keycode` in Linux.
+#[rustfmt::skip]
+const USAGE_TO_KEY: [u8; 160] = [
+      0,  0,  0,  0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38,
+     50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44,  2,  3,
+      4,  5,  6,  7,  8,  9, 10, 11, 28,  1, 14, 15, 57, 12, 13, 26,
+     27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64,
+     65, 66, 67, 68, 87, 88, 99, 70,119,110,102,104,111,107,109,106,
+    105,108,103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71,
+     72, 73, 82, 83, 86,127,116,117,183,184,185,186,187,188,189,190,
+    191,192,193,194,134,138,130,132,128,129,131,137,133,135,136,113,
+    115,114,  0,  0,  0,121,  0, 89, 93,124, 92, 94, 95,  0,  0,  0,
+    122,123, 90, 91, 85,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,  0,
+];
+
+fn usage_to_key(usage: u8) -> Option<u16> {
+    match USAGE_TO_KEY.get(usage as usize) {
+        Some(&key) if key != 0 => Some(key as u16),
+        _ => None,
+    }
+}
+
+pub struct UsbHid {
+    dev: Arc<UsbDevice>,
+    ep: EndpointDescriptor,
+    is_keyboard: bool,
+    /// DMA buffer of reports.
+    buf: (VirtAddr, PhysAddr),
+    /// The previous report, to find pressed and released keys or buttons.
+    last_report: Mutex<[u8; KBD_REPORT_SIZE]>,
+    listener: EventListener<InputEvent>,
+}
+
+impl UsbHid {
+    /// Switch the interface to the boot protocol, then start polling its
+    /// interrupt IN endpoint.
+    pub fn new(dev: Arc<UsbDevice>, iface: &InterfaceDescriptor) -> DeviceResult<Arc<Self>> {
+        let ep = iface
+            .find_endpoint(TRANSFER_INTERRUPT, true)
+            .ok_or(DeviceError::NotSupported)?;
+        let class_request = |request, value| SetupPacket {
+            request_type: REQ_TYPE_CLASS | REQ_RECIPIENT_INTERFACE,
+            request,
+            value,
+            index: iface.number as u16,
+            length: 0,
+        };
+        dev.control_out(class_request(HID_REQ_SET_PROTOCOL, HID_BOOT_PROTOCOL), &[])?;
+        // report only on changes, which is optional for mice
+        if let Err(e) = dev.control_out(class_request(HID_REQ_SET_IDLE, 0), &[]) {
+            debug!("usb-hid: SET_IDLE failed: {:?}", e);
+        }
+        dev.configure_endpoints(&[ep])?;
+
+        let hid = Arc::new(Self {
+            dev: dev.clone(),
+            ep,
+            is_keyboard: iface.protocol == HID_PROTOCOL_KEYBOARD,
+            buf: alloc_dma(PAGE_SIZE, PAGE_SIZE),
+            last_report: Mutex::new([0; KBD_REPORT_SIZE]),
+            listener: EventListener::new(),
+        });
+        info!("usb-hid: port {}: {} found", dev.port(), hid.name());
+        dev.set_handler(&ep, hid.clone());
+        hid.queue_report()?;
+        Ok(hid)
+    }
+
+    fn queue_report(&self) -> DeviceResult {
+        let len = self.ep.max_packet_size as usize;
+        self.dev.queue_transfer(&self.ep, self.buf.1, len)
+    }
+
+    fn report_event(&self, event_type: InputEventType, code: u16, value: i32) {
+        self.listener.trigger(InputEvent {
+            event_type,
+            code,
+            value,
+        });
+    }
+
+    fn handle_keyboard_report(&self, report: &[u8]) {
+        if report.len() < KBD_REPORT_SIZE || report[2] == KBD_ERROR_ROLLOVER {
+            return;
+        }
+        let mut last = self.last_report.lock();
+        for (i, &key) in MODIFIER_KEYS.iter().enumerate() {
+            let (old, new) = ((last[0] >> i) & 1, (report[0] >> i) & 1);
+            if old != new {
+                self.report_event(InputEventType::Key, key, new as i32);
+            }
+        }
+        for &usage in &last[2..] {
+            if !report[2..KBD_REPORT_SIZE].contains(&usage) {
+                if let Some(key) = usage_to_key(usage) {
+                    self.report_event(InputEventType::Key, key, 0);
+                }
+            }
+        }
+        for &usage in &report[2..KBD_REPORT_SIZE] {
+            if !last[2..].contains(&usage) {
+                if let Some(key) = usage_to_key(usage) {
+                    self.report_event(InputEventType::Key, key, 1);
+                }
+            }
+        }
+        last.copy_from_slice(&report[..KBD_REPORT_SIZE]);
+        self.report_event(InputEventType::Syn, SYN_REPORT, 0);
+    }
+
+    fn handle_mouse_report(&self, report: &[u8]) {
+        if report.len() < 3 {
+            return;
+        }
+        let last_buttons = {
+            let mut last = self.last_report.lock();
+            let buttons = last[0];
+            last[0] = report[0];
+            buttons
+        };
+        for (i, &btn) in [BTN_LEFT, BTN_RIGHT, BTN_MIDDLE].iter().enumerate() {
+            let (old, new) = ((last_buttons >> i) & 1, (report[0] >> i) & 1);
+            if old != new {
+                self.report_event(InputEventType::Key, btn, new as i32);
+            }
+        }
+        let (dx, dy) = (report[1] as i8, report[2] as i8);
+        if dx != 0 {
+            self.report_event(InputEventType::RelAxis, REL_X, dx as i32);
+        }
+        if dy != 0 {
+            self.report_event(InputEventType::RelAxis, REL_Y, dy as i32);
+        }
+        if let Some(&wheel) = report.get(3) {
+            if wheel != 0 {
+                self.report_event(InputEventType::RelAxis, REL_WHEEL, wheel as i8 as i32);
+            }
+        }
+        self.report_event(InputEventType::Syn, SYN_REPORT, 0);
+    }
+}
+
+impl EndpointHandler for UsbHid {
+    fn complete(&self, event: &Trb) {
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                let len = (self.ep.max_packet_size as usize).saturating_sub(event.residual());
+                let report = unsafe { core::slice::from_raw_parts(self.buf.0 as *const u8, len) };
+                if self.is_keyboard {
+                    self.handle_keyboard_report(report);
+                } else {
+                    self.handle_mouse_report(report);
+                }
+            }
+            code => warn!("usb-hid: report transfer failed with code {}", code),
+        }
+        if let Err(e) = self.queue_report() {
+            warn!("usb-hid: failed to queue report transfer: {:?}", e);
+        }
+    }
+}
+
+impl_event_scheme!(UsbHid, InputEvent);
+
+impl Scheme for UsbHid {
+    fn name(&self) -> &str {
+        if self.is_keyboard {
+            "usb-keyboard"
+        } else {
+            "usb-mouse"
+        }
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        self.dev.xhci().handle_irq();
+    }
+}
+
+impl InputScheme for UsbHid {
+    fn capability(&self, cap_type: CapabilityType) -> InputCapability {
+        let mut cap = InputCapability::empty();
+        match (cap_type, self.is_keyboard) {
+            (CapabilityType::Event, true) => cap.set_all(&[EV_SYN, EV_KEY]),
+            (CapabilityType::Key, true) => {
+                cap.set_all(&MODIFIER_KEYS);
+                for &key in USAGE_TO_KEY.iter().filter(|&&k| k != 0) {
+                    cap.set(key as u16);
+                }
+            }
+            (CapabilityType::Event, false) => cap.set_all(&[EV_SYN, EV_KEY, EV_REL]),
+            (CapabilityType::Key, false) => cap.set_all(&[BTN_LEFT, BTN_RIGHT, BTN_MIDDLE]),
+            (CapabilityType::RelAxis, false) => cap.set_all(&[REL_X, REL_Y, REL_WHEEL]),
+            _ => {}
+        }
+        cap
+    }
+}
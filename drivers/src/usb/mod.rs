@@ -0,0 +1,107 @@
+//! USB host stack: the xHCI host controller driver, device enumeration, and
+//! class drivers of HID boot keyboards/mice and bulk-only mass storage.
+
+pub mod descriptor;
+
+mod device;
+mod hid;
+mod storage;
+mod xhci;
+
+pub use hid::UsbHid;
+pub use storage::UsbMassStorage;
+
+use alloc::{sync::Arc, vec::Vec};
+
+use self::descriptor::SetupPacket;
+use self::device::UsbDevice;
+use self::xhci::Xhci;
+use crate::{Device, DeviceResult, PhysAddr, VirtAddr};
+
+const CLASS_HID: u8 = 3;
+const HID_SUBCLASS_BOOT: u8 = 1;
+const HID_PROTOCOL_KEYBOARD: u8 = 1;
+const HID_PROTOCOL_MOUSE: u8 = 2;
+const CLASS_MASS_STORAGE: u8 = 8;
+const MSC_SUBCLASS_SCSI: u8 = 6;
+const MSC_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+const PAGE_SIZE: usize = 4096;
+/// Iterations to poll a register before giving up.
+const SPIN_TIMEOUT: usize = 1_000_000;
+
+extern "C" {
+    fn drivers_dma_alloc(pages: usize) -> PhysAddr;
+}
+
+/// Allocate zeroed DMA memory of `size` bytes aligned to `align`.
+fn alloc_dma(size: usize, align: usize) -> (VirtAddr, PhysAddr) {
+    let align = align.max(PAGE_SIZE);
+    let pages = (size + align - PAGE_SIZE + PAGE_SIZE - 1) / PAGE_SIZE;
+    let paddr = unsafe { drivers_dma_alloc(pages) };
+    let paddr = (paddr + align - 1) & !(align - 1);
+    let vaddr = crate::bus::phys_to_virt(paddr);
+    unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+    (vaddr, paddr)
+}
+
+/// Poll until `cond` holds, returns `false` on timeout.
+fn spin_wait(mut cond: impl FnMut() -> bool) -> bool {
+    for _ in 0..SPIN_TIMEOUT {
+        if cond() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
+}
+
+/// Start the xHCI controller whose registers are mapped at `base`, enumerate
+/// the devices on its root hub ports, and create drivers of the supported
+/// interfaces.
+pub fn init(base: VirtAddr, irq: usize) -> DeviceResult<Vec<Device>> {
+    let xhci = Arc::new(Xhci::new(base, irq)?);
+    let mut devices = Vec::new();
+    for port in 1..=xhci.max_ports() {
+        if !xhci.port_connected(port) {
+            continue;
+        }
+        match probe_port(&xhci, port) {
+            Ok(mut devs) => devices.append(&mut devs),
+            Err(e) => warn!("usb: failed to probe port {}: {:?}", port, e),
+        }
+    }
+    Ok(devices)
+}
+
+fn probe_port(xhci: &Arc<Xhci>, port: usize) -> DeviceResult<Vec<Device>> {
+    let dev = Arc::new(UsbDevice::enumerate(xhci.clone(), port)?);
+    let config = dev.get_configuration()?;
+    dev.set_configuration(config.value)?;
+    let mut devices = Vec::new();
+    for iface in config.interfaces.iter().filter(|i| i.alt_setting == 0) {
+        let res = match (iface.class, iface.subclass, iface.protocol) {
+            (CLASS_HID, HID_SUBCLASS_BOOT, HID_PROTOCOL_KEYBOARD | HID_PROTOCOL_MOUSE) => {
+                UsbHid::new(dev.clone(), iface).map(|hid| Device::Input(hid))
+            }
+            (CLASS_MASS_STORAGE, MSC_SUBCLASS_SCSI, MSC_PROTOCOL_BULK_ONLY) => {
+                UsbMassStorage::new(dev.clone(), iface).map(|msc| Device::Block(Arc::new(msc)))
+            }
+            (class, subclass, protocol) => {
+                info!(
+                    "usb: port {}: unsupported interface {}, class {:#x}:{:#x}:{:#x}",
+                    port, iface.number, class, subclass, protocol
+                );
+                continue;
+            }
+        };
+        match res {
+            Ok(d) => devices.push(d),
+            Err(e) => warn!(
+                "usb: port {}: failed to initialize interface {}: {:?}",
+                port, iface.number, e
+            ),
+        }
+    }
+    Ok(devices)
+}
@@ -0,0 +1,262 @@
+//! Mass storage class driver of the bulk-only transport with SCSI commands.
+
+use alloc::sync::Arc;
+
+use lock::Mutex;
+
+use super::descriptor::*;
+use super::device::UsbDevice;
+use super::{alloc_dma, PAGE_SIZE};
+use crate::scheme::{BlockScheme, Scheme};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CBW_SIZE: usize = 31;
+const CBW_FLAG_IN: u8 = 0x80;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+const CSW_SIZE: usize = 13;
+const CSW_STATUS_PASSED: u8 = 0;
+
+const SCSI_TEST_UNIT_READY: u8 = 0x00;
+const SCSI_REQUEST_SENSE: u8 = 0x03;
+const SCSI_INQUIRY: u8 = 0x12;
+const SCSI_READ_CAPACITY_10: u8 = 0x25;
+const SCSI_READ_10: u8 = 0x28;
+const SCSI_WRITE_10: u8 = 0x2a;
+const SCSI_SYNCHRONIZE_CACHE_10: u8 = 0x35;
+
+const INQUIRY_SIZE: usize = 36;
+const SENSE_SIZE: usize = 18;
+const READ_CAPACITY_SIZE: usize = 8;
+/// Times to test if the unit is ready, as it may report a unit attention
+/// after reset.
+const READY_RETRIES: usize = 8;
+
+/// Size of the data buffer, the largest transfer without crossing a 64K
+/// boundary.
+const DATA_BUF_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    None,
+    In,
+    Out,
+}
+
+struct StorageInner {
+    tag: u32,
+    /// DMA buffer of CBWs and CSWs.
+    cmd_buf: (VirtAddr, PhysAddr),
+    /// DMA buffer of the data stage.
+    data_buf: (VirtAddr, PhysAddr),
+}
+
+impl StorageInner {
+    fn data(&mut self, len: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.data_buf.0 as *mut u8, len) }
+    }
+}
+
+pub struct UsbMassStorage {
+    dev: Arc<UsbDevice>,
+    bulk_in: EndpointDescriptor,
+    bulk_out: EndpointDescriptor,
+    block_size: usize,
+    num_blocks: usize,
+    inner: Mutex<StorageInner>,
+}
+
+impl UsbMassStorage {
+    /// Configure the bulk endpoints, then wait the unit to be ready and read
+    /// its capacity.
+    pub fn new(dev: Arc<UsbDevice>, iface: &InterfaceDescriptor) -> DeviceResult<Self> {
+        let bulk_in = iface
+            .find_endpoint(TRANSFER_BULK, true)
+            .ok_or(DeviceError::NotSupported)?;
+        let bulk_out = iface
+            .find_endpoint(TRANSFER_BULK, false)
+            .ok_or(DeviceError::NotSupported)?;
+        dev.configure_endpoints(&[bulk_in, bulk_out])?;
+
+        let mut msc = Self {
+            dev,
+            bulk_in,
+            bulk_out,
+            block_size: 0,
+            num_blocks: 0,
+            inner: Mutex::new(StorageInner {
+                tag: 0,
+                cmd_buf: alloc_dma(PAGE_SIZE, PAGE_SIZE),
+                data_buf: alloc_dma(DATA_BUF_SIZE, DATA_BUF_SIZE),
+            }),
+        };
+
+        let mut inner = msc.inner.lock();
+        let cb = [SCSI_INQUIRY, 0, 0, 0, INQUIRY_SIZE as u8, 0];
+        msc.command(&mut inner, &cb, Direction::In, INQUIRY_SIZE)?;
+        let inquiry = inner.data(INQUIRY_SIZE);
+        let vendor = core::str::from_utf8(&inquiry[8..16]).unwrap_or("?").trim();
+        let product = core::str::from_utf8(&inquiry[16..32]).unwrap_or("?").trim();
+        info!(
+            "usb-storage: port {}: {} {}",
+            msc.dev.port(),
+            vendor,
+            product
+        );
+
+        msc.wait_ready(&mut inner)?;
+        let cb = [SCSI_READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        msc.command(&mut inner, &cb, Direction::In, READ_CAPACITY_SIZE)?;
+        let cap = inner.data(READ_CAPACITY_SIZE);
+        let last_lba = u32::from_be_bytes([cap[0], cap[1], cap[2], cap[3]]) as usize;
+        let block_size = u32::from_be_bytes([cap[4], cap[5], cap[6], cap[7]]) as usize;
+        drop(inner);
+        if block_size == 0 || block_size > DATA_BUF_SIZE {
+            return Err(DeviceError::NotSupported);
+        }
+        msc.block_size = block_size;
+        msc.num_blocks = last_lba + 1;
+        info!(
+            "usb-storage: {} blocks of {} bytes",
+            msc.num_blocks, msc.block_size
+        );
+        Ok(msc)
+    }
+
+    fn wait_ready(&self, inner: &mut StorageInner) -> DeviceResult {
+        let cb = [SCSI_TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        for _ in 0..READY_RETRIES {
+            if self.command(inner, &cb, Direction::None, 0).is_ok() {
+                return Ok(());
+            }
+            // clear the unit attention or learn why it is not ready
+            let sense_cb = [SCSI_REQUEST_SENSE, 0, 0, 0, SENSE_SIZE as u8, 0];
+            self.command(inner, &sense_cb, Direction::In, SENSE_SIZE)?;
+            let sense = inner.data(SENSE_SIZE);
+            debug!(
+                "usb-storage: not ready, sense key {:#x}, ASC {:#x}, ASCQ {:#x}",
+                sense[2] & 0xf,
+                sense[12],
+                sense[13]
+            );
+        }
+        Err(DeviceError::NotReady)
+    }
+
+    /// Run a SCSI command with the data stage of `len` bytes in the data
+    /// buffer, returns the number of bytes transferred.
+    fn command(
+        &self,
+        inner: &mut StorageInner,
+        cb: &[u8],
+        dir: Direction,
+        len: usize,
+    ) -> DeviceResult<usize> {
+        inner.tag = inner.tag.wrapping_add(1);
+        let tag = inner.tag;
+        let cbw = unsafe { core::slice::from_raw_parts_mut(inner.cmd_buf.0 as *mut u8, CBW_SIZE) };
+        cbw.fill(0);
+        cbw[0..4].copy_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw[4..8].copy_from_slice(&tag.to_le_bytes());
+        cbw[8..12].copy_from_slice(&(len as u32).to_le_bytes());
+        cbw[12] = if dir == Direction::In { CBW_FLAG_IN } else { 0 };
+        cbw[14] = cb.len() as u8;
+        cbw[15..15 + cb.len()].copy_from_slice(cb);
+        self.dev
+            .transfer(&self.bulk_out, inner.cmd_buf.1, CBW_SIZE)?;
+
+        let transferred = match dir {
+            Direction::None => 0,
+            Direction::In => self.dev.transfer(&self.bulk_in, inner.data_buf.1, len)?,
+            Direction::Out => self.dev.transfer(&self.bulk_out, inner.data_buf.1, len)?,
+        };
+
+        self.dev
+            .transfer(&self.bulk_in, inner.cmd_buf.1, CSW_SIZE)?;
+        let csw = unsafe { core::slice::from_raw_parts(inner.cmd_buf.0 as *const u8, CSW_SIZE) };
+        let signature = u32::from_le_bytes([csw[0], csw[1], csw[2], csw[3]]);
+        let csw_tag = u32::from_le_bytes([csw[4], csw[5], csw[6], csw[7]]);
+        if signature != CSW_SIGNATURE || csw_tag != tag {
+            warn!("usb-storage: invalid CSW for command {:#x}", cb[0]);
+            return Err(DeviceError::IoError);
+        }
+        if csw[12] != CSW_STATUS_PASSED {
+            debug!(
+                "usb-storage: command {:#x} failed with status {}",
+                cb[0], csw[12]
+            );
+            return Err(DeviceError::IoError);
+        }
+        Ok(transferred)
+    }
+
+    /// Build a READ(10) or WRITE(10) command.
+    fn rw_command(opcode: u8, lba: usize, count: usize) -> [u8; 10] {
+        let lba = (lba as u32).to_be_bytes();
+        let count = (count as u16).to_be_bytes();
+        [
+            opcode, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ]
+    }
+
+    fn check_range(&self, block_id: usize, len: usize) -> DeviceResult {
+        if len % self.block_size != 0 || block_id + len / self.block_size > self.num_blocks {
+            return Err(DeviceError::InvalidParam);
+        }
+        Ok(())
+    }
+}
+
+impl Scheme for UsbMassStorage {
+    fn name(&self) -> &str {
+        "usb-storage"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        self.dev.xhci().handle_irq();
+    }
+}
+
+impl BlockScheme for UsbMassStorage {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
+        self.check_range(block_id, buf.len())?;
+        let mut inner = self.inner.lock();
+        let mut lba = block_id;
+        for chunk in buf.chunks_mut(DATA_BUF_SIZE / self.block_size * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            let cb = Self::rw_command(SCSI_READ_10, lba, count);
+            if self.command(&mut inner, &cb, Direction::In, chunk.len())? != chunk.len() {
+                return Err(DeviceError::IoError);
+            }
+            chunk.copy_from_slice(inner.data(chunk.len()));
+            lba += count;
+        }
+        Ok(())
+    }
+
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
+        self.check_range(block_id, buf.len())?;
+        let mut inner = self.inner.lock();
+        let mut lba = block_id;
+        for chunk in buf.chunks(DATA_BUF_SIZE / self.block_size * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            inner.data(chunk.len()).copy_from_slice(chunk);
+            let cb = Self::rw_command(SCSI_WRITE_10, lba, count);
+            if self.command(&mut inner, &cb, Direction::Out, chunk.len())? != chunk.len() {
+                return Err(DeviceError::IoError);
+            }
+            lba += count;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> DeviceResult {
+        let cb = [SCSI_SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        self.command(&mut self.inner.lock(), &cb, Direction::None, 0)?;
+        Ok(())
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+}
@@ -0,0 +1,561 @@
+//! xHCI (eXtensible Host Controller Interface) USB host controller driver.
+//!
+//! Only devices attached directly to the root hub ports are supported.
+
+mod ring;
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+
+use lock::Mutex;
+
+use self::ring::*;
+use super::{alloc_dma, spin_wait, SetupPacket, PAGE_SIZE};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+
+pub(super) use self::ring::{Trb, COMPLETION_SHORT_PACKET, COMPLETION_SUCCESS};
+
+const CAP_CAPLENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC_BASE: usize = 0x400;
+
+const USBCMD_RS: u32 = 1 << 0;
+const USBCMD_HCRST: u32 = 1 << 1;
+const USBCMD_INTE: u32 = 1 << 2;
+const USBSTS_HCH: u32 = 1 << 0;
+const USBSTS_CNR: u32 = 1 << 11;
+
+const PORTSC_CCS: u32 = 1 << 0;
+const PORTSC_PED: u32 = 1 << 1;
+const PORTSC_PR: u32 = 1 << 4;
+const PORTSC_PP: u32 = 1 << 9;
+/// Connect, enable, warm reset, over-current, reset, link state and config
+/// error change bits, which are cleared by writing 1.
+const PORTSC_CHANGE_BITS: u32 = 0x7f << 17;
+const PORTSC_PRC: u32 = 1 << 21;
+
+/// Interrupter 0 in the runtime registers.
+const IR0_IMAN: usize = 0x20;
+const IR0_IMOD: usize = 0x24;
+const IR0_ERSTSZ: usize = 0x28;
+const IR0_ERSTBA: usize = 0x30;
+const IR0_ERDP: usize = 0x38;
+
+const IMAN_IP: u32 = 1 << 0;
+const IMAN_IE: u32 = 1 << 1;
+const ERDP_EHB: u64 = 1 << 3;
+
+/// USB legacy support extended capability.
+const XCAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+
+pub(super) const SPEED_FULL: u8 = 1;
+pub(super) const SPEED_LOW: u8 = 2;
+pub(super) const SPEED_HIGH: u8 = 3;
+pub(super) const SPEED_SUPER: u8 = 4;
+
+pub(super) const EP_TYPE_BULK_OUT: u32 = 2;
+pub(super) const EP_TYPE_INTERRUPT_OUT: u32 = 3;
+pub(super) const EP_TYPE_CONTROL: u32 = 4;
+pub(super) const EP_TYPE_BULK_IN: u32 = 6;
+pub(super) const EP_TYPE_INTERRUPT_IN: u32 = 7;
+
+/// Device context index of the default control endpoint.
+pub(super) const DCI_EP0: u8 = 1;
+
+/// Iterations to poll for a command or a transfer before giving up.
+const EVENT_TIMEOUT: usize = 10_000_000;
+
+/// Handles transfer events of an endpoint asynchronously, e.g. reports of
+/// interrupt IN endpoints.
+pub trait EndpointHandler: Send + Sync {
+    fn complete(&self, event: &Trb);
+}
+
+/// The input context of a slot, used to configure the device context.
+struct SlotContexts {
+    input: VirtAddr,
+    input_paddr: PhysAddr,
+    /// Number of valid endpoint contexts, i.e. the largest device context index.
+    entries: u8,
+}
+
+struct XhciInner {
+    cmd_ring: Ring,
+    event_ring: EventRing,
+    /// Command completion events by the address of the command TRB.
+    cmd_events: BTreeMap<u64, Trb>,
+    /// Transfer events of synchronous transfers by (slot, endpoint).
+    xfer_events: BTreeMap<(u8, u8), Trb>,
+    transfer_rings: BTreeMap<(u8, u8), Ring>,
+    handlers: BTreeMap<(u8, u8), Arc<dyn EndpointHandler>>,
+    slots: BTreeMap<u8, SlotContexts>,
+}
+
+/// Configuration of a non-control endpoint.
+pub struct EndpointConfig {
+    pub dci: u8,
+    pub ep_type: u32,
+    pub max_packet_size: u16,
+    /// Service interval, as the exponent of 125us.
+    pub interval: u8,
+}
+
+pub struct Xhci {
+    op: VirtAddr,
+    rt: VirtAddr,
+    db: VirtAddr,
+    max_ports: usize,
+    /// Size of a context, 32 or 64 bytes.
+    ctx_size: usize,
+    dcbaa: VirtAddr,
+    inner: Mutex<XhciInner>,
+}
+
+fn read32(addr: VirtAddr) -> u32 {
+    unsafe { read_volatile(addr as *const u32) }
+}
+
+fn write32(addr: VirtAddr, value: u32) {
+    unsafe { write_volatile(addr as *mut u32, value) }
+}
+
+fn write64(addr: VirtAddr, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+impl Xhci {
+    /// Reset and start the controller whose registers are mapped at `base`.
+    pub fn new(base: VirtAddr, irq: usize) -> DeviceResult<Self> {
+        let caplength = read32(base + CAP_CAPLENGTH) & 0xff;
+        let hcsparams1 = read32(base + CAP_HCSPARAMS1);
+        let hcsparams2 = read32(base + CAP_HCSPARAMS2);
+        let hccparams1 = read32(base + CAP_HCCPARAMS1);
+        let max_slots = hcsparams1 & 0xff;
+        let op = base + caplength as usize;
+        Self::take_ownership(base, hccparams1);
+
+        // stop and reset the controller
+        write32(op + OP_USBCMD, read32(op + OP_USBCMD) & !USBCMD_RS);
+        if !spin_wait(|| read32(op + OP_USBSTS) & USBSTS_HCH != 0) {
+            return Err(DeviceError::NotReady);
+        }
+        write32(op + OP_USBCMD, USBCMD_HCRST);
+        let reset_done = || {
+            read32(op + OP_USBCMD) & USBCMD_HCRST == 0 && read32(op + OP_USBSTS) & USBSTS_CNR == 0
+        };
+        if !spin_wait(reset_done) {
+            warn!("xhci: controller reset timed out");
+            return Err(DeviceError::NotReady);
+        }
+
+        // the device context base address array, with the scratchpad buffer
+        // array at entry 0
+        let (dcbaa, dcbaa_paddr) = alloc_dma((max_slots as usize + 1) * 8, 64);
+        let scratchpads = ((hcsparams2 >> 21) & 0x1f) << 5 | (hcsparams2 >> 27);
+        if scratchpads > 0 {
+            let (array, array_paddr) = alloc_dma(scratchpads as usize * 8, 64);
+            for i in 0..scratchpads as usize {
+                let (_, page) = alloc_dma(PAGE_SIZE, PAGE_SIZE);
+                unsafe { write_volatile((array as *mut u64).add(i), page as u64) };
+            }
+            unsafe { write_volatile(dcbaa as *mut u64, array_paddr as u64) };
+        }
+        let xhci = Self {
+            op,
+            rt: base + (read32(base + CAP_RTSOFF) & !0x1f) as usize,
+            db: base + (read32(base + CAP_DBOFF) & !0x3) as usize,
+            max_ports: (hcsparams1 >> 24) as usize,
+            ctx_size: if hccparams1 & (1 << 2) != 0 { 64 } else { 32 },
+            dcbaa,
+            inner: Mutex::new(XhciInner {
+                cmd_ring: Ring::new(),
+                event_ring: EventRing::new(),
+                cmd_events: BTreeMap::new(),
+                xfer_events: BTreeMap::new(),
+                transfer_rings: BTreeMap::new(),
+                handlers: BTreeMap::new(),
+                slots: BTreeMap::new(),
+            }),
+        };
+        write32(op + OP_CONFIG, max_slots);
+        write64(op + OP_DCBAAP, dcbaa_paddr as u64);
+        {
+            let inner = xhci.inner.lock();
+            write64(op + OP_CRCR, inner.cmd_ring.paddr() as u64 | 1);
+            let rt = xhci.rt;
+            write32(rt + IR0_ERSTSZ, 1);
+            write64(rt + IR0_ERDP, inner.event_ring.dequeue_paddr());
+            write64(rt + IR0_ERSTBA, inner.event_ring.erst_paddr() as u64);
+            // moderate interrupts to at most one per 1ms
+            write32(rt + IR0_IMOD, 4000);
+            write32(rt + IR0_IMAN, IMAN_IE | IMAN_IP);
+        }
+        write32(op + OP_USBCMD, USBCMD_RS | USBCMD_INTE);
+        if !spin_wait(|| read32(op + OP_USBSTS) & USBSTS_HCH == 0) {
+            return Err(DeviceError::NotReady);
+        }
+        let version = read32(base + CAP_CAPLENGTH) >> 16;
+        info!(
+            "xhci: version {:x}.{:02x}, irq {}, {} slots, {} ports, {}-byte contexts",
+            version >> 8,
+            version & 0xff,
+            irq,
+            max_slots,
+            xhci.max_ports,
+            xhci.ctx_size
+        );
+        Ok(xhci)
+    }
+
+    /// Take the controller over from the firmware by the USB legacy support
+    /// capability, if present.
+    fn take_ownership(base: VirtAddr, hccparams1: u32) {
+        let mut offset = ((hccparams1 >> 16) as usize) << 2;
+        while offset != 0 {
+            let cap = base + offset;
+            let value = read32(cap);
+            if value & 0xff == XCAP_LEGACY {
+                write32(cap, value | LEGACY_OS_OWNED);
+                if !spin_wait(|| read32(cap) & LEGACY_BIOS_OWNED == 0) {
+                    warn!("xhci: the firmware does not release the controller");
+                }
+                // disable SMIs
+                write32(cap + 4, read32(cap + 4) & 0x000e_1fee);
+                return;
+            }
+            offset += (((value >> 8) & 0xff) as usize) << 2;
+            if (value >> 8) & 0xff == 0 {
+                break;
+            }
+        }
+    }
+
+    pub fn max_ports(&self) -> usize {
+        self.max_ports
+    }
+
+    fn portsc(&self, port: usize) -> VirtAddr {
+        self.op + OP_PORTSC_BASE + (port - 1) * 0x10
+    }
+
+    /// Whether a device is connected to the root hub port (1-based).
+    pub fn port_connected(&self, port: usize) -> bool {
+        read32(self.portsc(port)) & PORTSC_CCS != 0
+    }
+
+    /// Reset the port if it's not enabled yet, returns the speed of the device.
+    pub fn reset_port(&self, port: usize) -> DeviceResult<u8> {
+        let portsc = self.portsc(port);
+        // USB3 ports are enabled after link training, USB2 ports need a reset
+        if read32(portsc) & PORTSC_PED == 0 {
+            let value = read32(portsc) & !(PORTSC_CHANGE_BITS | PORTSC_PED);
+            write32(portsc, value | PORTSC_PP | PORTSC_PR);
+            if !spin_wait(|| read32(portsc) & PORTSC_PRC != 0) {
+                warn!("xhci: port {} reset timed out", port);
+                return Err(DeviceError::NotReady);
+            }
+        }
+        let value = read32(portsc);
+        write32(portsc, value & !PORTSC_PED | PORTSC_CHANGE_BITS);
+        if value & PORTSC_PED == 0 {
+            return Err(DeviceError::NotReady);
+        }
+        Ok(((value >> 10) & 0xf) as u8)
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        write32(self.db + slot as usize * 4, target as u32);
+    }
+
+    /// Handle all pending events, and call the endpoint handlers of transfer
+    /// events after the lock is released.
+    pub fn handle_irq(&self) {
+        let mut completed = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            let mut handled = false;
+            while let Some(trb) = inner.event_ring.pop() {
+                handled = true;
+                match trb.trb_type() {
+                    TRB_COMMAND_COMPLETION => {
+                        inner.cmd_events.insert(trb.param, trb);
+                    }
+                    TRB_TRANSFER_EVENT => {
+                        let key = (trb.slot_id(), trb.endpoint_id());
+                        match inner.handlers.get(&key) {
+                            Some(h) => completed.push((h.clone(), trb)),
+                            None => {
+                                inner.xfer_events.insert(key, trb);
+                            }
+                        }
+                    }
+                    TRB_PORT_STATUS_CHANGE => {
+                        debug!("xhci: port {} status changed", trb.param >> 24);
+                    }
+                    t => debug!("xhci: unhandled event type {}", t),
+                }
+            }
+            if handled {
+                let erdp = inner.event_ring.dequeue_paddr() | ERDP_EHB;
+                write64(self.rt + IR0_ERDP, erdp);
+            }
+            write32(self.rt + IR0_IMAN, IMAN_IE | IMAN_IP);
+        }
+        for (handler, trb) in completed {
+            handler.complete(&trb);
+        }
+    }
+
+    /// Poll the event ring until `f` finds the expected event.
+    fn wait_event(&self, mut f: impl FnMut(&mut XhciInner) -> Option<Trb>) -> DeviceResult<Trb> {
+        for _ in 0..EVENT_TIMEOUT {
+            self.handle_irq();
+            if let Some(trb) = f(&mut self.inner.lock()) {
+                return Ok(trb);
+            }
+            core::hint::spin_loop();
+        }
+        Err(DeviceError::NotReady)
+    }
+
+    /// Issue a command, and wait for its completion.
+    fn command(&self, trb: Trb) -> DeviceResult<Trb> {
+        let addr = self.inner.lock().cmd_ring.push(trb);
+        self.ring_doorbell(0, 0);
+        let event = self.wait_event(|inner| inner.cmd_events.remove(&addr))?;
+        if event.completion_code() != COMPLETION_SUCCESS {
+            warn!(
+                "xhci: command {} failed with code {}",
+                trb.trb_type(),
+                event.completion_code()
+            );
+            return Err(DeviceError::IoError);
+        }
+        Ok(event)
+    }
+
+    /// Address of the context `index` in the input context of the slot, where
+    /// 0 is the input control context, 1 is the slot context, and the others
+    /// are endpoint contexts by device context index plus 1.
+    fn input_ctx(&self, ctx: &SlotContexts, index: usize) -> *mut u32 {
+        (ctx.input + index * self.ctx_size) as *mut u32
+    }
+
+    fn write_ctx(&self, ctx: &SlotContexts, index: usize, dwords: &[u32]) {
+        let base = self.input_ctx(ctx, index);
+        for i in 0..self.ctx_size / 4 {
+            let value = dwords.get(i).copied().unwrap_or(0);
+            unsafe { write_volatile(base.add(i), value) };
+        }
+    }
+
+    /// Enable a device slot, and address the device on the root hub port
+    /// `port` with the default control endpoint.
+    pub fn address_device(&self, port: usize, speed: u8, max_packet_size: u16) -> DeviceResult<u8> {
+        let event = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0))?;
+        let slot = event.slot_id();
+        let (input, input_paddr) = alloc_dma(self.ctx_size * 33, 64);
+        let (_, output_paddr) = alloc_dma(self.ctx_size * 32, 64);
+        unsafe {
+            write_volatile(
+                (self.dcbaa as *mut u64).add(slot as usize),
+                output_paddr as u64,
+            )
+        };
+
+        let ring = Ring::new();
+        let ctx = SlotContexts {
+            input,
+            input_paddr,
+            entries: DCI_EP0,
+        };
+        // add the slot context and the default control endpoint
+        self.write_ctx(&ctx, 0, &[0, 0b11]);
+        let slot_dw0 = (DCI_EP0 as u32) << 27 | (speed as u32) << 20;
+        self.write_ctx(&ctx, 1, &[slot_dw0, (port as u32) << 16]);
+        let ep0 = [
+            0,
+            (max_packet_size as u32) << 16 | EP_TYPE_CONTROL << 3 | 3 << 1,
+            ring.paddr() as u32 | ring.cycle() as u32,
+            (ring.paddr() as u64 >> 32) as u32,
+            8,
+        ];
+        self.write_ctx(&ctx, DCI_EP0 as usize + 1, &ep0);
+
+        {
+            let mut inner = self.inner.lock();
+            inner.transfer_rings.insert((slot, DCI_EP0), ring);
+            inner.slots.insert(slot, ctx);
+        }
+        let trb = Trb::new(
+            TRB_ADDRESS_DEVICE,
+            input_paddr as u64,
+            0,
+            (slot as u32) << 24,
+        );
+        self.command(trb)?;
+        Ok(slot)
+    }
+
+    /// Update the maximum packet size of the default control endpoint.
+    pub fn update_ep0(&self, slot: u8, max_packet_size: u16) -> DeviceResult {
+        let input_paddr = {
+            let inner = self.inner.lock();
+            let ctx = inner.slots.get(&slot).ok_or(DeviceError::InvalidParam)?;
+            self.write_ctx(ctx, 0, &[0, 1 << DCI_EP0]);
+            let ep0 = self.input_ctx(ctx, DCI_EP0 as usize + 1);
+            unsafe {
+                let dw1 = read_volatile(ep0.add(1)) & 0xffff | (max_packet_size as u32) << 16;
+                write_volatile(ep0.add(1), dw1);
+            }
+            ctx.input_paddr
+        };
+        let flags = (slot as u32) << 24;
+        self.command(Trb::new(TRB_EVALUATE_CONTEXT, input_paddr as u64, 0, flags))?;
+        Ok(())
+    }
+
+    /// Add endpoints to the slot, each with a new transfer ring.
+    pub fn configure_endpoints(&self, slot: u8, eps: &[EndpointConfig]) -> DeviceResult {
+        let input_paddr = {
+            let mut inner = self.inner.lock();
+            let mut rings = Vec::new();
+            let ctx = inner
+                .slots
+                .get_mut(&slot)
+                .ok_or(DeviceError::InvalidParam)?;
+            let mut add_flags = 1;
+            for ep in eps {
+                let ring = Ring::new();
+                let mps = ep.max_packet_size as u32;
+                // the max ESIT payload of interrupt endpoints, and the
+                // average TRB length
+                let dw4 = if ep.ep_type == EP_TYPE_INTERRUPT_IN {
+                    mps << 16 | mps
+                } else {
+                    mps
+                };
+                let dwords = [
+                    (ep.interval as u32) << 16,
+                    mps << 16 | ep.ep_type << 3 | 3 << 1,
+                    ring.paddr() as u32 | ring.cycle() as u32,
+                    (ring.paddr() as u64 >> 32) as u32,
+                    dw4,
+                ];
+                self.write_ctx(ctx, ep.dci as usize + 1, &dwords);
+                add_flags |= 1 << ep.dci;
+                ctx.entries = ctx.entries.max(ep.dci);
+                rings.push((ep.dci, ring));
+            }
+            self.write_ctx(ctx, 0, &[0, add_flags]);
+            let slot_ctx = self.input_ctx(ctx, 1);
+            unsafe {
+                let dw0 = read_volatile(slot_ctx) & !(0x1f << 27) | (ctx.entries as u32) << 27;
+                write_volatile(slot_ctx, dw0);
+            }
+            let input_paddr = ctx.input_paddr;
+            for (dci, ring) in rings {
+                inner.transfer_rings.insert((slot, dci), ring);
+            }
+            input_paddr
+        };
+        let flags = (slot as u32) << 24;
+        self.command(Trb::new(
+            TRB_CONFIGURE_ENDPOINT,
+            input_paddr as u64,
+            0,
+            flags,
+        ))?;
+        Ok(())
+    }
+
+    fn push_trbs(&self, slot: u8, dci: u8, trbs: &[Trb]) -> DeviceResult {
+        {
+            let mut inner = self.inner.lock();
+            let ring = inner
+                .transfer_rings
+                .get_mut(&(slot, dci))
+                .ok_or(DeviceError::InvalidParam)?;
+            for trb in trbs {
+                ring.push(*trb);
+            }
+        }
+        self.ring_doorbell(slot, dci);
+        Ok(())
+    }
+
+    /// Wait for the transfer event of the endpoint, returns the number of
+    /// bytes transferred of `len` bytes.
+    fn wait_transfer(&self, slot: u8, dci: u8, len: usize) -> DeviceResult<usize> {
+        let event = self.wait_event(|inner| inner.xfer_events.remove(&(slot, dci)))?;
+        match event.completion_code() {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(len - event.residual().min(len)),
+            code => {
+                warn!(
+                    "xhci: transfer on slot {} endpoint {} failed with code {}",
+                    slot, dci, code
+                );
+                Err(DeviceError::IoError)
+            }
+        }
+    }
+
+    /// Do a control transfer on the default control endpoint, with the data
+    /// stage of `len` bytes at `paddr`.
+    pub fn control_transfer(
+        &self,
+        slot: u8,
+        setup: SetupPacket,
+        paddr: PhysAddr,
+        len: usize,
+    ) -> DeviceResult<usize> {
+        let is_in = setup.is_in();
+        let trt = match (len, is_in) {
+            (0, _) => 0,
+            (_, false) => 2,
+            (_, true) => 3,
+        };
+        let mut trbs = alloc::vec![Trb::new(TRB_SETUP, setup.to_u64(), 8, TRB_IDT | trt << 16)];
+        if len > 0 {
+            let dir = if is_in { TRB_DIR_IN } else { 0 };
+            trbs.push(Trb::new(TRB_DATA, paddr as u64, len as u32, dir));
+        }
+        // the status stage is in the opposite direction of the data stage
+        let dir = if len > 0 && is_in { 0 } else { TRB_DIR_IN };
+        trbs.push(Trb::new(TRB_STATUS, 0, 0, dir | TRB_IOC));
+        self.push_trbs(slot, DCI_EP0, &trbs)?;
+        self.wait_transfer(slot, DCI_EP0, len)
+    }
+
+    /// Do a bulk or interrupt transfer of `len` bytes at `paddr`
+    /// synchronously. The buffer must not cross a 64K boundary.
+    pub fn transfer(&self, slot: u8, dci: u8, paddr: PhysAddr, len: usize) -> DeviceResult<usize> {
+        self.queue_transfer(slot, dci, paddr, len)?;
+        self.wait_transfer(slot, dci, len)
+    }
+
+    /// Queue a bulk or interrupt transfer, which is completed by the handler
+    /// set by [`Self::set_handler`], or waited synchronously.
+    pub fn queue_transfer(&self, slot: u8, dci: u8, paddr: PhysAddr, len: usize) -> DeviceResult {
+        let trb = Trb::new(TRB_NORMAL, paddr as u64, len as u32, TRB_ISP | TRB_IOC);
+        self.push_trbs(slot, dci, &[trb])
+    }
+
+    /// Handle the transfer events of the endpoint with `handler`.
+    pub fn set_handler(&self, slot: u8, dci: u8, handler: Arc<dyn EndpointHandler>) {
+        self.inner.lock().handlers.insert((slot, dci), handler);
+    }
+}
@@ -0,0 +1,183 @@
+//! TRBs, the command/transfer rings and the event ring.
+
+use core::ptr::{addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use super::super::{alloc_dma, PAGE_SIZE};
+use crate::{PhysAddr, VirtAddr};
+
+/// Number of TRBs in a ring segment of one page.
+const RING_SIZE: usize = PAGE_SIZE / core::mem::size_of::<Trb>();
+
+pub const TRB_CYCLE: u32 = 1 << 0;
+pub const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+pub const TRB_ISP: u32 = 1 << 2;
+pub const TRB_IOC: u32 = 1 << 5;
+pub const TRB_IDT: u32 = 1 << 6;
+/// Direction of data and status stages.
+pub const TRB_DIR_IN: u32 = 1 << 16;
+
+pub const TRB_NORMAL: u32 = 1;
+pub const TRB_SETUP: u32 = 2;
+pub const TRB_DATA: u32 = 3;
+pub const TRB_STATUS: u32 = 4;
+pub const TRB_LINK: u32 = 6;
+pub const TRB_ENABLE_SLOT: u32 = 9;
+pub const TRB_ADDRESS_DEVICE: u32 = 11;
+pub const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+pub const TRB_EVALUATE_CONTEXT: u32 = 13;
+pub const TRB_TRANSFER_EVENT: u32 = 32;
+pub const TRB_COMMAND_COMPLETION: u32 = 33;
+pub const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+pub const COMPLETION_SUCCESS: u8 = 1;
+pub const COMPLETION_SHORT_PACKET: u8 = 13;
+
+/// Transfer request block.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Trb {
+    pub param: u64,
+    pub status: u32,
+    pub control: u32,
+}
+
+impl Trb {
+    pub fn new(trb_type: u32, param: u64, status: u32, flags: u32) -> Self {
+        Self {
+            param,
+            status,
+            control: trb_type << 10 | flags,
+        }
+    }
+
+    pub fn trb_type(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    pub fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    /// Number of bytes not transferred, of transfer events.
+    pub fn residual(&self) -> usize {
+        (self.status & 0xff_ffff) as usize
+    }
+
+    pub fn slot_id(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Device context index of the endpoint, of transfer events.
+    pub fn endpoint_id(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+}
+
+/// A producer ring (the command ring or a transfer ring) of one segment,
+/// closed by a Link TRB.
+pub struct Ring {
+    trbs: VirtAddr,
+    paddr: PhysAddr,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    pub fn new() -> Self {
+        let (trbs, paddr) = alloc_dma(PAGE_SIZE, PAGE_SIZE);
+        Self {
+            trbs,
+            paddr,
+            enqueue: 0,
+            cycle: true,
+        }
+    }
+
+    pub fn paddr(&self) -> PhysAddr {
+        self.paddr
+    }
+
+    /// Whether the consumer cycle state is set at the start of the ring.
+    pub fn cycle(&self) -> bool {
+        self.cycle
+    }
+
+    fn write(&mut self, trb: Trb) {
+        let slot = (self.trbs as *mut Trb).wrapping_add(self.enqueue);
+        let control = trb.control & !TRB_CYCLE | self.cycle as u32;
+        unsafe {
+            write_volatile(addr_of_mut!((*slot).param), trb.param);
+            write_volatile(addr_of_mut!((*slot).status), trb.status);
+            // hand over the TRB by the cycle bit, after the other fields
+            fence(Ordering::SeqCst);
+            write_volatile(addr_of_mut!((*slot).control), control);
+        }
+    }
+
+    /// Enqueue a TRB, returns its physical address.
+    pub fn push(&mut self, trb: Trb) -> u64 {
+        let addr = (self.paddr + self.enqueue * core::mem::size_of::<Trb>()) as u64;
+        self.write(trb);
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            let link = Trb::new(TRB_LINK, self.paddr as u64, 0, TRB_TOGGLE_CYCLE);
+            self.write(link);
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        fence(Ordering::SeqCst);
+        addr
+    }
+}
+
+/// The event ring of an interrupter, of one segment.
+pub struct EventRing {
+    trbs: VirtAddr,
+    paddr: PhysAddr,
+    /// Event ring segment table of one entry.
+    erst_paddr: PhysAddr,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    pub fn new() -> Self {
+        let (trbs, paddr) = alloc_dma(PAGE_SIZE, PAGE_SIZE);
+        let (erst, erst_paddr) = alloc_dma(64, 64);
+        unsafe {
+            write_volatile(erst as *mut u64, paddr as u64);
+            write_volatile((erst + 8) as *mut u32, RING_SIZE as u32);
+        }
+        Self {
+            trbs,
+            paddr,
+            erst_paddr,
+            dequeue: 0,
+            cycle: true,
+        }
+    }
+
+    pub fn erst_paddr(&self) -> PhysAddr {
+        self.erst_paddr
+    }
+
+    /// Physical address of the next event to handle.
+    pub fn dequeue_paddr(&self) -> u64 {
+        (self.paddr + self.dequeue * core::mem::size_of::<Trb>()) as u64
+    }
+
+    /// Dequeue an event, if the controller has produced one.
+    pub fn pop(&mut self) -> Option<Trb> {
+        let trb = unsafe { read_volatile((self.trbs as *const Trb).add(self.dequeue)) };
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+}
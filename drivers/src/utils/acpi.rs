@@ -0,0 +1,413 @@
+//! Parse ACPI tables to enumerate devices and control the power on x86.
+//!
+//! Only the tables used by the kernel are parsed: MADT for processors and
+//! I/O APICs, MCFG for PCIe ECAM, HPET, and FADT with the `\_S5` object in
+//! DSDT for power-off and reset.
+//!
+//! Specification: <https://uefi.org/specs/ACPI/6.4/>.
+
+use crate::io::{Io, Pmio};
+use crate::prelude::{IrqPolarity, IrqTriggerMode};
+use crate::{DeviceError, DeviceResult, PhysAddr, VirtAddr};
+use alloc::{vec, vec::Vec};
+use core::ops::Range;
+use core::ptr::{read_unaligned, write_volatile};
+
+type Phys2VirtFn = fn(paddr: PhysAddr) -> VirtAddr;
+
+const SDT_HEADER_SIZE: usize = 36;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/// The address of the local APIC and the I/O APIC of a PC without ACPI.
+const LEGACY_LOCAL_APIC_ADDRESS: PhysAddr = 0xfee0_0000;
+const LEGACY_IO_APIC_ADDRESS: PhysAddr = 0xfec0_0000;
+
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8 = 1;
+
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+fn read<T: Copy>(vaddr: VirtAddr) -> T {
+    unsafe { read_unaligned(vaddr as *const T) }
+}
+
+/// A processor in MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiProcessor {
+    /// The ACPI processor UID.
+    pub uid: u32,
+    /// The local APIC ID, or the local x2APIC ID.
+    pub apic_id: u32,
+}
+
+/// An I/O APIC in MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiIoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first GSI handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not identity mapped to a GSI, or is not edge triggered
+/// and active high.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiIrqOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags.
+    flags: u16,
+}
+
+impl AcpiIrqOverride {
+    /// The polarity, if it is not the default of the ISA bus.
+    pub fn polarity(&self) -> Option<IrqPolarity> {
+        match self.flags & 0b11 {
+            0b01 => Some(IrqPolarity::ActiveHigh),
+            0b11 => Some(IrqPolarity::ActiveLow),
+            _ => None,
+        }
+    }
+
+    /// The trigger mode, if it is not the default of the ISA bus.
+    pub fn trigger_mode(&self) -> Option<IrqTriggerMode> {
+        match self.flags >> 2 & 0b11 {
+            0b01 => Some(IrqTriggerMode::Edge),
+            0b11 => Some(IrqTriggerMode::Level),
+            _ => None,
+        }
+    }
+}
+
+/// A PCIe enhanced configuration space in MCFG.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiEcamRegion {
+    /// The physical address of bus 0, even if `bus_start` is not 0.
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+/// The event timer block in HPET.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiHpet {
+    pub address: PhysAddr,
+    /// The minimum clock ticks in periodic mode.
+    pub min_tick: u16,
+}
+
+/// Generic Address Structure.
+#[derive(Debug, Clone, Copy)]
+struct GenericAddress {
+    space_id: u8,
+    address: u64,
+}
+
+impl GenericAddress {
+    fn parse(vaddr: VirtAddr) -> Self {
+        Self {
+            space_id: read(vaddr),
+            address: read(vaddr + 4),
+        }
+    }
+}
+
+/// Power management registers in FADT.
+#[derive(Debug)]
+pub struct AcpiPower {
+    smi_cmd: u16,
+    acpi_enable: u8,
+    pm1a_cnt: u16,
+    pm1b_cnt: u16,
+    /// `SLP_TYPa` and `SLP_TYPb` of the S5 state in DSDT.
+    s5_slp_typ: Option<(u16, u16)>,
+    reset: Option<(GenericAddress, u8)>,
+    phys_to_virt: Phys2VirtFn,
+}
+
+impl AcpiPower {
+    /// Switch from legacy mode to ACPI mode if the firmware has not.
+    fn enable_acpi(&self) {
+        if self.smi_cmd == 0 || Pmio::<u16>::new(self.pm1a_cnt).read() & PM1_CNT_SCI_EN != 0 {
+            return;
+        }
+        Pmio::<u8>::new(self.smi_cmd).write(self.acpi_enable);
+        while Pmio::<u16>::new(self.pm1a_cnt).read() & PM1_CNT_SCI_EN == 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Enter the S5 soft-off state.
+    pub fn power_off(&self) -> DeviceResult {
+        let (slp_typa, slp_typb) = self.s5_slp_typ.ok_or(DeviceError::NotSupported)?;
+        self.enable_acpi();
+        for &(port, slp_typ) in [(self.pm1a_cnt, slp_typa), (self.pm1b_cnt, slp_typb)].iter() {
+            if port != 0 {
+                let mut pm1_cnt = Pmio::<u16>::new(port);
+                let value = pm1_cnt.read() & !(0x7 << PM1_CNT_SLP_TYP_SHIFT);
+                pm1_cnt.write(value | slp_typ << PM1_CNT_SLP_TYP_SHIFT | PM1_CNT_SLP_EN);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset the system with the reset register.
+    pub fn reset(&self) -> DeviceResult {
+        let (reg, value) = self.reset.ok_or(DeviceError::NotSupported)?;
+        match reg.space_id {
+            GAS_SYSTEM_MEMORY => unsafe {
+                write_volatile((self.phys_to_virt)(reg.address as _) as *mut u8, value)
+            },
+            GAS_SYSTEM_IO => Pmio::<u8>::new(reg.address as _).write(value),
+            _ => return Err(DeviceError::NotSupported),
+        }
+        Ok(())
+    }
+}
+
+/// A system description table.
+struct Sdt {
+    vaddr: VirtAddr,
+    length: usize,
+}
+
+impl Sdt {
+    /// Check the table at `paddr` with its checksum.
+    fn new(paddr: PhysAddr, phys_to_virt: Phys2VirtFn) -> Option<Self> {
+        let vaddr = phys_to_virt(paddr);
+        let length = read::<u32>(vaddr + 4) as usize;
+        if length < SDT_HEADER_SIZE {
+            return None;
+        }
+        let bytes = unsafe { core::slice::from_raw_parts(vaddr as *const u8, length) };
+        let sum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        if sum != 0 {
+            warn!("ACPI: invalid checksum of table @ {:#x}", paddr);
+            return None;
+        }
+        Some(Self { vaddr, length })
+    }
+
+    fn signature(&self) -> [u8; 4] {
+        read(self.vaddr)
+    }
+
+    /// The table content after the header.
+    fn body(&self) -> Range<VirtAddr> {
+        self.vaddr + SDT_HEADER_SIZE..self.vaddr + self.length
+    }
+}
+
+/// Tables found from the RSDP.
+#[derive(Debug, Default)]
+pub struct AcpiTables {
+    /// Enabled processors.
+    pub processors: Vec<AcpiProcessor>,
+    pub local_apic_address: PhysAddr,
+    pub io_apics: Vec<AcpiIoApic>,
+    pub irq_overrides: Vec<AcpiIrqOverride>,
+    pub ecam_regions: Vec<AcpiEcamRegion>,
+    pub hpet: Option<AcpiHpet>,
+    pub power: Option<AcpiPower>,
+}
+
+impl AcpiTables {
+    /// The defaults of a PC without usable ACPI tables: one I/O APIC at its
+    /// usual address, whose GSIs are the ISA IRQs.
+    pub fn legacy() -> Self {
+        Self {
+            local_apic_address: LEGACY_LOCAL_APIC_ADDRESS,
+            io_apics: vec![AcpiIoApic {
+                id: 0,
+                address: LEGACY_IO_APIC_ADDRESS,
+                gsi_base: 0,
+            }],
+            ..Self::default()
+        }
+    }
+
+    /// Parse the tables listed in the RSDT or XSDT of the RSDP at `rsdp`.
+    pub fn new(rsdp: PhysAddr, phys_to_virt: Phys2VirtFn) -> DeviceResult<Self> {
+        let rsdp_vaddr = phys_to_virt(rsdp);
+        if read::<[u8; 8]>(rsdp_vaddr) != *b"RSD PTR " {
+            warn!("ACPI: no RSDP @ {:#x}", rsdp);
+            return Err(DeviceError::InvalidParam);
+        }
+        let revision: u8 = read(rsdp_vaddr + 15);
+        let xsdt: u64 = if revision >= 2 {
+            read(rsdp_vaddr + 24)
+        } else {
+            0
+        };
+        let (root, entry_size) = if xsdt != 0 {
+            (xsdt as usize, 8)
+        } else {
+            (read::<u32>(rsdp_vaddr + 16) as usize, 4)
+        };
+        let root = Sdt::new(root, phys_to_virt).ok_or(DeviceError::InvalidParam)?;
+
+        let mut tables = Self::default();
+        for entry in root.body().step_by(entry_size) {
+            let paddr = if entry_size == 8 {
+                read::<u64>(entry) as usize
+            } else {
+                read::<u32>(entry) as usize
+            };
+            let sdt = match Sdt::new(paddr, phys_to_virt) {
+                Some(sdt) => sdt,
+                None => continue,
+            };
+            match &sdt.signature() {
+                b"APIC" => tables.parse_madt(&sdt),
+                b"MCFG" => tables.parse_mcfg(&sdt),
+                b"HPET" => tables.parse_hpet(&sdt),
+                b"FACP" => tables.power = parse_fadt(&sdt, phys_to_virt),
+                _ => {}
+            }
+        }
+        Ok(tables)
+    }
+
+    fn parse_madt(&mut self, sdt: &Sdt) {
+        let body = sdt.body();
+        self.local_apic_address = read::<u32>(body.start) as usize;
+        let mut entry = body.start + 8;
+        while entry + 2 <= body.end {
+            let length = read::<u8>(entry + 1) as usize;
+            if length < 2 {
+                break;
+            }
+            match read::<u8>(entry) {
+                MADT_LOCAL_APIC if read::<u32>(entry + 4) & MADT_PROCESSOR_ENABLED != 0 => {
+                    self.processors.push(AcpiProcessor {
+                        uid: read::<u8>(entry + 2) as u32,
+                        apic_id: read::<u8>(entry + 3) as u32,
+                    })
+                }
+                MADT_LOCAL_X2APIC if read::<u32>(entry + 8) & MADT_PROCESSOR_ENABLED != 0 => {
+                    self.processors.push(AcpiProcessor {
+                        uid: read(entry + 12),
+                        apic_id: read(entry + 4),
+                    })
+                }
+                MADT_IO_APIC => self.io_apics.push(AcpiIoApic {
+                    id: read(entry + 2),
+                    address: read::<u32>(entry + 4) as usize,
+                    gsi_base: read(entry + 8),
+                }),
+                MADT_INTERRUPT_OVERRIDE => self.irq_overrides.push(AcpiIrqOverride {
+                    isa_irq: read(entry + 3),
+                    gsi: read(entry + 4),
+                    flags: read(entry + 8),
+                }),
+                MADT_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    self.local_apic_address = read::<u64>(entry + 4) as usize
+                }
+                _ => {}
+            }
+            entry += length;
+        }
+    }
+
+    fn parse_mcfg(&mut self, sdt: &Sdt) {
+        let body = sdt.body();
+        // 8 reserved bytes, then 16 bytes per entry
+        for entry in (body.start + 8..body.end).step_by(16) {
+            if entry + 16 > body.end {
+                break;
+            }
+            self.ecam_regions.push(AcpiEcamRegion {
+                base: read::<u64>(entry) as usize,
+                segment: read(entry + 8),
+                bus_start: read(entry + 10),
+                bus_end: read(entry + 11),
+            });
+        }
+    }
+
+    fn parse_hpet(&mut self, sdt: &Sdt) {
+        let body = sdt.body();
+        if body.len() < 20 {
+            return;
+        }
+        let address = GenericAddress::parse(body.start + 4);
+        self.hpet = Some(AcpiHpet {
+            address: address.address as usize,
+            min_tick: read(body.start + 17),
+        });
+    }
+}
+
+fn parse_fadt(sdt: &Sdt, phys_to_virt: Phys2VirtFn) -> Option<AcpiPower> {
+    let vaddr = sdt.vaddr;
+    if sdt.length < 116 {
+        return None;
+    }
+    let flags: u32 = read(vaddr + 112);
+    let reset = if sdt.length >= 129 && flags & FADT_RESET_REG_SUP != 0 {
+        Some((GenericAddress::parse(vaddr + 116), read(vaddr + 128)))
+    } else {
+        None
+    };
+    let x_dsdt: u64 = if sdt.length >= 148 {
+        read(vaddr + 140)
+    } else {
+        0
+    };
+    let dsdt = if x_dsdt != 0 {
+        x_dsdt as usize
+    } else {
+        read::<u32>(vaddr + 40) as usize
+    };
+    let s5_slp_typ = Sdt::new(dsdt, phys_to_virt).and_then(|dsdt| find_s5(&dsdt));
+    if s5_slp_typ.is_none() {
+        warn!("ACPI: no \\_S5 object in DSDT");
+    }
+    Some(AcpiPower {
+        smi_cmd: read::<u32>(vaddr + 48) as u16,
+        acpi_enable: read(vaddr + 52),
+        pm1a_cnt: read::<u32>(vaddr + 64) as u16,
+        pm1b_cnt: read::<u32>(vaddr + 68) as u16,
+        s5_slp_typ,
+        reset,
+        phys_to_virt,
+    })
+}
+
+/// Find `Name (_S5, Package () { SLP_TYPa, SLP_TYPb, ... })` in the AML code
+/// of DSDT, without an AML interpreter.
+fn find_s5(dsdt: &Sdt) -> Option<(u16, u16)> {
+    let body = dsdt.body();
+    let aml = unsafe { core::slice::from_raw_parts(body.start as *const u8, body.len()) };
+    let pos = aml.windows(4).position(|name| name == b"_S5_")?;
+    let is_name = (pos >= 1 && aml[pos - 1] == AML_NAME_OP)
+        || (pos >= 2 && aml[pos - 2] == AML_NAME_OP && aml[pos - 1] == b'\\');
+    if !is_name || *aml.get(pos + 4)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // skip PkgLength, whose bits [7:6] of the lead byte are the number of
+    // following bytes, and NumElements
+    let pkg_length_bytes = (*aml.get(pos + 5)? >> 6) as usize + 1;
+    let mut elements = aml.get(pos + 5 + pkg_length_bytes + 1..)?.iter();
+    let mut next_integer = || match *elements.next()? {
+        AML_BYTE_PREFIX => elements.next().map(|&b| b as u16),
+        // ZeroOp and OneOp
+        b @ (0 | 1) => Some(b as u16),
+        _ => None,
+    };
+    Some((next_integer()?, next_integer()?))
+}
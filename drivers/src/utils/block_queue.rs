@@ -0,0 +1,197 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+
+use crate::scheme::{BlockCompletion, BlockFuture, BlockOp, BlockRequest};
+use crate::{DeviceError, DeviceResult};
+
+/// Pending block requests, sorted by block id and merged before dispatching.
+///
+/// Requests are dispatched in the order of an elevator (C-SCAN): starting
+/// from the position of the last dispatched request, and wrapping around
+/// at the end of the device. Adjacent requests of the same direction are
+/// merged into one device command.
+pub struct BlockRequestQueue {
+    /// (block id, sequence number) -> request
+    pending: BTreeMap<(usize, u64), (BlockRequest, Arc<BlockCompletion>)>,
+    seq: u64,
+    /// The block next to the last dispatched request.
+    head: usize,
+    block_size: usize,
+    max_blocks: usize,
+}
+
+/// Requests merged into one device command.
+pub struct MergedRequest {
+    pub op: BlockOp,
+    pub block_id: usize,
+    pub nblocks: usize,
+    parts: Vec<(BlockRequest, Arc<BlockCompletion>)>,
+}
+
+impl BlockRequestQueue {
+    /// Create a new queue, which merges at most `max_blocks` blocks into one command.
+    pub fn new(block_size: usize, max_blocks: usize) -> Self {
+        Self {
+            pending: BTreeMap::new(),
+            seq: 0,
+            head: 0,
+            block_size,
+            max_blocks,
+        }
+    }
+
+    /// Enqueue a request, returns a future resolved on completion.
+    pub fn push(&mut self, req: BlockRequest) -> BlockFuture {
+        let len = req.len();
+        if len == 0 || len % self.block_size != 0 || len / self.block_size > self.max_blocks {
+            return BlockFuture::ready(req, Err(DeviceError::InvalidParam));
+        }
+        let completion = BlockCompletion::new();
+        self.seq += 1;
+        self.pending
+            .insert((req.block_id, self.seq), (req, completion.clone()));
+        BlockFuture::new(completion)
+    }
+
+    /// Whether there are no pending requests.
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn nblocks(&self, req: &BlockRequest) -> usize {
+        req.len() / self.block_size
+    }
+
+    /// Dequeue the next request, merged with its adjacent requests.
+    pub fn pop(&mut self) -> Option<MergedRequest> {
+        let key = *self
+            .pending
+            .range((self.head, 0)..)
+            .next()
+            .or_else(|| self.pending.iter().next())?
+            .0;
+        let (req, completion) = self.pending.remove(&key).unwrap();
+        let mut merged = MergedRequest {
+            op: req.op,
+            block_id: req.block_id,
+            nblocks: self.nblocks(&req),
+            parts: vec![(req, completion)],
+        };
+        loop {
+            let end = merged.block_id + merged.nblocks;
+            let next = match self.pending.range((end, 0)..).next() {
+                Some((&key, (req, _)))
+                    if key.0 == end
+                        && req.op == merged.op
+                        && merged.nblocks + self.nblocks(req) <= self.max_blocks =>
+                {
+                    key
+                }
+                _ => break,
+            };
+            let (req, completion) = self.pending.remove(&next).unwrap();
+            merged.nblocks += self.nblocks(&req);
+            merged.parts.push((req, completion));
+        }
+        self.head = merged.block_id + merged.nblocks;
+        Some(merged)
+    }
+}
+
+impl MergedRequest {
+    /// Total length in bytes.
+    pub fn len(&self) -> usize {
+        self.parts.iter().map(|(req, _)| req.len()).sum()
+    }
+
+    /// Whether the request is empty.
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn segments(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.parts.iter().flat_map(|(req, _)| req.bufs.iter())
+    }
+
+    /// Gather the data to write into a contiguous buffer `dst`.
+    pub fn gather(&self, dst: &mut [u8]) {
+        let mut pos = 0;
+        for seg in self.segments() {
+            dst[pos..pos + seg.len()].copy_from_slice(seg);
+            pos += seg.len();
+        }
+    }
+
+    /// Scatter the data read from a contiguous buffer `src`.
+    pub fn scatter(&mut self, src: &[u8]) {
+        let mut pos = 0;
+        for seg in self
+            .parts
+            .iter_mut()
+            .flat_map(|(req, _)| req.bufs.iter_mut())
+        {
+            let len = seg.len();
+            seg.copy_from_slice(&src[pos..pos + len]);
+            pos += len;
+        }
+    }
+
+    /// Complete all merged requests with `result`.
+    pub fn complete(self, result: DeviceResult) {
+        for (req, completion) in self.parts {
+            let result = match &result {
+                Ok(()) => Ok(()),
+                Err(_) => Err(DeviceError::IoError),
+            };
+            completion.complete(req, result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block_ids(queue: &mut BlockRequestQueue) -> Vec<(usize, usize)> {
+        let mut ret = Vec::new();
+        while let Some(req) = queue.pop() {
+            ret.push((req.block_id, req.nblocks));
+        }
+        ret
+    }
+
+    #[test]
+    fn merge_adjacent() {
+        let mut queue = BlockRequestQueue::new(512, 8);
+        let _f1 = queue.push(BlockRequest::read(2, vec![vec![0; 512]]));
+        let _f2 = queue.push(BlockRequest::read(0, vec![vec![0; 512], vec![0; 512]]));
+        let _f3 = queue.push(BlockRequest::write(3, vec![vec![0; 512]]));
+        let _f4 = queue.push(BlockRequest::write(4, vec![vec![0; 1024]]));
+        assert_eq!(block_ids(&mut queue), vec![(0, 3), (3, 3)]);
+    }
+
+    #[test]
+    fn elevator_order() {
+        let mut queue = BlockRequestQueue::new(512, 1);
+        for id in [5, 1, 9] {
+            let _f = queue.push(BlockRequest::read(id, vec![vec![0; 512]]));
+        }
+        assert_eq!(queue.pop().map(|r| r.block_id), Some(1));
+        let _f = queue.push(BlockRequest::read(0, vec![vec![0; 512]]));
+        assert_eq!(block_ids(&mut queue), vec![(5, 1), (9, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn scatter_gather() {
+        let mut queue = BlockRequestQueue::new(4, 8);
+        let _f1 = queue.push(BlockRequest::write(0, vec![vec![1; 4], vec![2; 4]]));
+        let _f2 = queue.push(BlockRequest::write(2, vec![vec![3; 4]]));
+        let mut merged = queue.pop().unwrap();
+        let mut buf = vec![0; merged.len()];
+        merged.gather(&mut buf);
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+        merged.scatter(&[9; 12]);
+        assert!(merged.segments().all(|seg| seg.iter().all(|&b| b == 9)));
+    }
+}
@@ -94,6 +94,32 @@ impl Devicetree {
         Some(start..end)
     }
 
+    /// Returns the `method` property in the `/psci` node, the conduit (`"hvc"`
+    /// or `"smc"`) used to call the PSCI firmware.
+    pub fn psci_method(&self) -> Option<&str> {
+        self.0.find("/psci")?.prop_str("method").ok()
+    }
+
+    /// Returns the `reg` property of enabled CPUs in the `/cpus` node, as their
+    /// hardware IDs.
+    pub fn cpu_ids(&self) -> Vec<u64> {
+        let cpus = match self.0.find("/cpus") {
+            Some(cpus) => cpus,
+            None => return Vec::new(),
+        };
+        let props = InheritProps {
+            parent_address_cells: cpus.prop_u32("#address-cells").unwrap_or(1),
+            ..Default::default()
+        };
+        cpus.children
+            .iter()
+            .filter(|node| node.prop_str("device_type").unwrap_or_default() == "cpu")
+            .filter(|node| node.prop_str("status").map_or(true, |s| s == "okay"))
+            .filter_map(|node| parse_reg(node, &props).ok())
+            .map(|(id, _)| id)
+            .collect()
+    }
+
     /// Returns the physical memory regions specified in the `/memory` nodes.
     pub fn memory_regions(&self) -> DeviceResult<Vec<Range<PhysAddr>>> {
         let props = InheritProps {
@@ -129,8 +155,18 @@ fn from_cells(cells: &[u32], cell_num: u32) -> DeviceResult<u64> {
 
 /// Parse the `reg` property, about `reg`: <https://elinux.org/Device_Tree_Usage#How_Addressing_Works>.
 pub fn parse_reg(node: &Node, props: &InheritProps) -> DeviceResult<(u64, u64)> {
+    parse_reg_at(node, props, 0)
+}
+
+/// Parse the `index`-th address region in the `reg` property, for devices
+/// with several register blocks.
+pub fn parse_reg_at(node: &Node, props: &InheritProps, index: usize) -> DeviceResult<(u64, u64)> {
     let cells = node.prop_cells("reg")?;
-    let addr = from_cells(&cells, props.parent_address_cells)?;
+    let stride = (props.parent_address_cells + props.parent_size_cells) as usize;
+    let cells = cells
+        .get(stride * index..)
+        .ok_or(DeviceError::InvalidParam)?;
+    let addr = from_cells(cells, props.parent_address_cells)?;
     let size = from_cells(
         &cells[props.parent_address_cells as usize..],
         props.parent_size_cells,
@@ -1,5 +1,6 @@
-//! Event handler and device tree.
+//! Event handler, device tree and ACPI tables.
 
+mod block_queue;
 mod event_listener;
 mod id_allocator;
 mod irq_manager;
@@ -9,6 +10,10 @@ mod graphic_console;
 
 pub mod devicetree;
 
+#[cfg(target_arch = "x86_64")]
+pub mod acpi;
+
+pub(super) use block_queue::{BlockRequestQueue, MergedRequest};
 pub(super) use id_allocator::IdAllocator;
 pub(super) use irq_manager::IrqManager;
 
@@ -1,43 +1,203 @@
+//! virtio-blk device, which transfers each merged request with a single
+//! descriptor chain.
+
+use alloc::{collections::BTreeMap, vec, vec::Vec};
+
 use lock::Mutex;
-use virtio_drivers::{VirtIOBlk as InnerDriver, VirtIOHeader};
 
-use crate::scheme::{BlockScheme, Scheme};
-use crate::DeviceResult;
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::scheme::{BlockFuture, BlockOp, BlockRequest, BlockScheme, Scheme};
+use crate::utils::{BlockRequestQueue, MergedRequest};
+use crate::{DeviceError, DeviceResult};
+
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: u16 = 64;
+
+const BLOCK_SIZE: usize = 512;
+/// Maximum number of blocks merged into one request.
+const MAX_MERGE_BLOCKS: usize = 64;
+/// Maximum number of requests in the virtqueue, each of them takes a buffer
+/// slot and 3 descriptors.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// Layout of a buffer slot: `struct virtio_blk_req` without the data, the
+/// status written by the device, and the data at the next block.
+const HDR_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HDR_SIZE;
+const DATA_OFFSET: usize = BLOCK_SIZE;
+const SLOT_SIZE: usize = DATA_OFFSET + MAX_MERGE_BLOCKS * BLOCK_SIZE;
+
+// request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
 
-pub struct VirtIoBlk<'a> {
-    inner: Mutex<InnerDriver<'a>>,
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+// device configuration
+const CONFIG_CAPACITY: usize = 0;
+
+struct BlkInner {
+    transport: Transport,
+    queue: VirtQueue,
+    slots: Vec<DmaRegion>,
+    free_slots: Vec<usize>,
+    /// token -> (buffer slot, request)
+    in_flight: BTreeMap<u16, (usize, MergedRequest)>,
 }
 
-impl<'a> VirtIoBlk<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
+pub struct VirtIoBlk {
+    inner: Mutex<BlkInner>,
+    queue: Mutex<BlockRequestQueue>,
+}
+
+impl VirtIoBlk {
+    pub fn new(transport: Transport) -> DeviceResult<Self> {
+        transport.begin_init(0)?;
+        let capacity = transport.config_read32(CONFIG_CAPACITY) as u64
+            | (transport.config_read32(CONFIG_CAPACITY + 4) as u64) << 32;
+        let queue = VirtQueue::new(&transport, QUEUE_REQUEST, QUEUE_SIZE)?;
+        let nr_slots = MAX_IN_FLIGHT.min(queue.size() / 3);
+        let slots = (0..nr_slots)
+            .map(|_| DmaRegion::new(SLOT_SIZE))
+            .collect::<DeviceResult<Vec<_>>>()?;
+        transport.finish_init();
+        info!("virtio-blk: initialized with {} blocks", capacity);
         Ok(Self {
-            inner: Mutex::new(InnerDriver::new(header)?),
+            inner: Mutex::new(BlkInner {
+                transport,
+                queue,
+                slots,
+                free_slots: (0..nr_slots).collect(),
+                in_flight: BTreeMap::new(),
+            }),
+            queue: Mutex::new(BlockRequestQueue::new(BLOCK_SIZE, MAX_MERGE_BLOCKS)),
         })
     }
+
+    /// Submit pending requests while there are free buffer slots.
+    fn dispatch(&self) {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let mut notify = false;
+        while let Some(&slot) = inner.free_slots.last() {
+            let req = match self.queue.lock().pop() {
+                Some(req) => req,
+                None => break,
+            };
+            let len = req.len();
+            let buf = &mut inner.slots[slot];
+            let type_ = match req.op {
+                BlockOp::Read => VIRTIO_BLK_T_IN,
+                BlockOp::Write => VIRTIO_BLK_T_OUT,
+            };
+            let hdr = buf.as_mut_slice(0, HDR_SIZE);
+            hdr[0..4].copy_from_slice(&type_.to_le_bytes());
+            hdr[4..8].fill(0);
+            hdr[8..16].copy_from_slice(&(req.block_id as u64).to_le_bytes());
+            buf.as_mut_slice(STATUS_OFFSET, 1)[0] = !VIRTIO_BLK_S_OK;
+            if req.op == BlockOp::Write {
+                req.gather(buf.as_mut_slice(DATA_OFFSET, len));
+            }
+            let hdr = (buf.paddr(0), HDR_SIZE);
+            let data = (buf.paddr(DATA_OFFSET), len);
+            let status = (buf.paddr(STATUS_OFFSET), 1);
+            let res = match req.op {
+                BlockOp::Read => inner.queue.add_chain(&[hdr], &[data, status]),
+                BlockOp::Write => inner.queue.add_chain(&[hdr, data], &[status]),
+            };
+            match res {
+                Ok(token) => {
+                    inner.free_slots.pop();
+                    inner.in_flight.insert(token, (slot, req));
+                    notify = true;
+                }
+                Err(e) => req.complete(Err(e)),
+            }
+        }
+        if notify {
+            inner.transport.notify(inner.queue.index());
+        }
+    }
+
+    /// Handle the used buffers in the virtqueue, and complete the finished requests.
+    fn process_completions(&self) {
+        let mut finished = Vec::new();
+        {
+            let mut inner = self.inner.lock();
+            let inner = &mut *inner;
+            while let Some((token, _)) = inner.queue.pop_used() {
+                let (slot, mut req) = match inner.in_flight.remove(&token) {
+                    Some(t) => t,
+                    None => {
+                        warn!("virtio-blk: unknown token {}", token);
+                        continue;
+                    }
+                };
+                let buf = &inner.slots[slot];
+                let result = if buf.as_slice(STATUS_OFFSET, 1)[0] == VIRTIO_BLK_S_OK {
+                    if req.op == BlockOp::Read {
+                        req.scatter(buf.as_slice(DATA_OFFSET, req.len()));
+                    }
+                    Ok(())
+                } else {
+                    Err(DeviceError::IoError)
+                };
+                inner.free_slots.push(slot);
+                finished.push((req, result));
+            }
+        }
+        for (req, result) in finished {
+            req.complete(result);
+        }
+        self.dispatch();
+    }
+
+    /// Do a block request synchronously.
+    fn block_io(&self, req: BlockRequest) -> DeviceResult<BlockRequest> {
+        let (req, res) = self.submit(req).wait_with(|| self.process_completions());
+        res.map(|_| req)
+    }
 }
 
-impl<'a> Scheme for VirtIoBlk<'a> {
+impl Scheme for VirtIoBlk {
     fn name(&self) -> &str {
         "virtio-blk"
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.inner.lock().ack_interrupt();
+        self.inner.lock().transport.ack_interrupt();
+        self.process_completions();
     }
 }
 
-impl<'a> BlockScheme for VirtIoBlk<'a> {
+impl BlockScheme for VirtIoBlk {
     fn read_block(&self, block_id: usize, buf: &mut [u8]) -> DeviceResult {
-        self.inner.lock().read_block(block_id, buf)?;
+        let req = self.block_io(BlockRequest::read(block_id, vec![vec![0; buf.len()]]))?;
+        buf.copy_from_slice(&req.bufs[0]);
         Ok(())
     }
 
     fn write_block(&self, block_id: usize, buf: &[u8]) -> DeviceResult {
-        self.inner.lock().write_block(block_id, buf)?;
+        self.block_io(BlockRequest::write(block_id, vec![buf.to_vec()]))?;
         Ok(())
     }
 
     fn flush(&self) -> DeviceResult {
         Ok(())
     }
+
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn submit(&self, req: BlockRequest) -> BlockFuture {
+        let future = self.queue.lock().push(req);
+        self.dispatch();
+        future
+    }
+
+    fn poll_completions(&self) {
+        self.process_completions();
+    }
 }
@@ -1,54 +1,428 @@
-use core::fmt::{Result, Write};
+//! virtio-console with multiple ports, each of which is a [`UartScheme`] device.
+//!
+//! Ports are announced by the device on the control queues after the driver
+//! is ready, those added while probing are exposed, up to [`MAX_PORTS`].
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::{str, vec, vec::Vec};
 
 use lock::Mutex;
-use virtio_drivers::{VirtIOConsole as InnerDriver, VirtIOHeader};
 
-use crate::prelude::DeviceResult;
-use crate::scheme::{impl_event_scheme, Scheme, UartScheme};
-use crate::utils::EventListener;
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::scheme::{EventScheme, Scheme, UartScheme};
+use crate::utils::{EventHandler, EventListener};
+use crate::{DeviceError, DeviceResult};
+
+const QUEUE_SIZE: u16 = 16;
+/// Size of each buffer.
+const BUF_SIZE: usize = 512;
+/// Maximum number of bytes received by a port and not yet taken, the device
+/// waits for more buffers beyond it.
+const RX_CAPACITY: usize = 4096;
+
+/// Maximum number of ports driven.
+const MAX_PORTS: usize = 8;
+
+/// The device has the control queues and more than one port.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+
+// device configuration
+const CONFIG_MAX_NR_PORTS: usize = 4;
+
+// control queues
+const QUEUE_CONTROL_RECEIVE: u16 = 2;
+const QUEUE_CONTROL_TRANSMIT: u16 = 3;
+
+/// Size of `struct virtio_console_control`, followed by the name in
+/// `PORT_NAME` messages.
+const CONTROL_MSG_SIZE: usize = 8;
+/// The port ID of messages about the device.
+const BAD_ID: u32 = !0;
+
+// control events
+const DEVICE_READY: u16 = 0;
+const DEVICE_ADD: u16 = 1;
+const DEVICE_REMOVE: u16 = 2;
+const PORT_READY: u16 = 3;
+const CONSOLE_PORT: u16 = 4;
+const PORT_OPEN: u16 = 6;
+const PORT_NAME: u16 = 7;
+
+/// Spins to wait for the device.
+const SPIN_TIMEOUT: usize = 0x100_0000;
+/// Spins without control messages to consider all ports are added.
+const SPIN_IDLE: usize = 0x10_0000;
 
-pub struct VirtIoConsole<'a> {
-    inner: Mutex<InnerDriver<'a>>,
-    listener: EventListener,
+/// Indexes of the receive and transmit queues of the port `id`.
+fn port_queues(id: usize) -> (u16, u16) {
+    match id {
+        0 => (0, 1),
+        id => (2 * id as u16 + 2, 2 * id as u16 + 3),
+    }
+}
+
+/// A pair of receive and transmit queues and their buffers.
+///
+/// Every receive buffer is kept available to the device.
+struct Channel {
+    rx: VirtQueue,
+    tx: VirtQueue,
+    rx_bufs: DmaRegion,
+    tx_bufs: DmaRegion,
+    /// token -> buffer slot
+    rx_slots: Vec<usize>,
+    tx_slots: Vec<usize>,
+    /// Transmit buffer slots not in use.
+    tx_free: Vec<usize>,
 }
 
-impl_event_scheme!(VirtIoConsole<'_>);
+impl Channel {
+    /// Set up the queues, with all receive buffers available to the device,
+    /// which is not notified yet.
+    fn new(transport: &Transport, rx_index: u16, tx_index: u16) -> DeviceResult<Self> {
+        let rx = VirtQueue::new(transport, rx_index, QUEUE_SIZE)?;
+        let tx = VirtQueue::new(transport, tx_index, QUEUE_SIZE)?;
+        let mut channel = Self {
+            rx_bufs: DmaRegion::new(rx.size() * BUF_SIZE)?,
+            tx_bufs: DmaRegion::new(tx.size() * BUF_SIZE)?,
+            rx_slots: vec![0; rx.size()],
+            tx_slots: vec![0; tx.size()],
+            tx_free: (0..tx.size()).collect(),
+            rx,
+            tx,
+        };
+        for slot in 0..channel.rx.size() {
+            channel.post_rx(slot)?;
+        }
+        Ok(channel)
+    }
 
-impl<'a> VirtIoConsole<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
-        Ok(Self {
-            inner: Mutex::new(InnerDriver::new(header)?),
-            listener: EventListener::new(),
-        })
+    fn post_rx(&mut self, slot: usize) -> DeviceResult {
+        let token = self
+            .rx
+            .add(self.rx_bufs.paddr(slot * BUF_SIZE), BUF_SIZE, true)?;
+        self.rx_slots[token as usize] = slot;
+        Ok(())
     }
+
+    /// Take a buffer filled by the device, and give it back after `f` handles
+    /// the data.
+    fn recv_with<R>(&mut self, transport: &Transport, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let (token, len) = self.rx.pop_used()?;
+        let slot = self.rx_slots[token as usize];
+        let ret = f(self.rx_bufs.as_slice(slot * BUF_SIZE, len.min(BUF_SIZE)));
+        match self.post_rx(slot) {
+            Ok(()) => transport.notify(self.rx.index()),
+            Err(e) => warn!("virtio-console: failed to post receive buffer: {:?}", e),
+        }
+        Some(ret)
+    }
+
+    /// Send `data` of at most [`BUF_SIZE`] bytes, waits if all buffers are in
+    /// use.
+    fn send(&mut self, transport: &Transport, data: &[u8]) -> DeviceResult {
+        if data.len() > BUF_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        let mut spins = 0;
+        let slot = loop {
+            while let Some((token, _)) = self.tx.pop_used() {
+                self.tx_free.push(self.tx_slots[token as usize]);
+            }
+            if let Some(slot) = self.tx_free.pop() {
+                break slot;
+            }
+            spins += 1;
+            if spins > SPIN_TIMEOUT {
+                return Err(DeviceError::NotReady);
+            }
+            core::hint::spin_loop();
+        };
+        self.tx_bufs
+            .as_mut_slice(slot * BUF_SIZE, data.len())
+            .copy_from_slice(data);
+        match self
+            .tx
+            .add(self.tx_bufs.paddr(slot * BUF_SIZE), data.len(), false)
+        {
+            Ok(token) => self.tx_slots[token as usize] = slot,
+            Err(e) => {
+                self.tx_free.push(slot);
+                return Err(e);
+            }
+        }
+        transport.notify(self.tx.index());
+        Ok(())
+    }
+}
+
+struct Port {
+    channel: Channel,
+    /// Received bytes not yet taken.
+    received: VecDeque<u8>,
+    /// The port is added by the device.
+    added: bool,
 }
 
-impl<'a> Scheme for VirtIoConsole<'a> {
+struct ConsoleInner {
+    transport: Transport,
+    ports: Vec<Port>,
+    /// Only with `VIRTIO_CONSOLE_F_MULTIPORT`.
+    control: Option<Channel>,
+}
+
+impl ConsoleInner {
+    fn control_send(&mut self, id: u32, event: u16, value: u16) -> DeviceResult {
+        let mut msg = [0; CONTROL_MSG_SIZE];
+        msg[..4].copy_from_slice(&id.to_le_bytes());
+        msg[4..6].copy_from_slice(&event.to_le_bytes());
+        msg[6..].copy_from_slice(&value.to_le_bytes());
+        let control = self.control.as_mut().ok_or(DeviceError::NotSupported)?;
+        control.send(&self.transport, &msg)
+    }
+
+    /// Handle received control messages, returns whether there was any.
+    fn handle_control(&mut self) -> bool {
+        let mut handled = false;
+        loop {
+            let msg = match &mut self.control {
+                Some(control) => control.recv_with(&self.transport, |data| {
+                    if data.len() < CONTROL_MSG_SIZE {
+                        return None;
+                    }
+                    let id = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    let event = u16::from_le_bytes([data[4], data[5]]);
+                    let value = u16::from_le_bytes([data[6], data[7]]);
+                    if event == PORT_NAME {
+                        let name = str::from_utf8(&data[CONTROL_MSG_SIZE..]).unwrap_or("?");
+                        info!("virtio-console: port {} is named {:?}", id, name);
+                    }
+                    Some((id as usize, event, value))
+                }),
+                None => None,
+            };
+            match msg {
+                Some(Some(msg)) => self.handle_control_msg(msg),
+                Some(None) => {}
+                None => return handled,
+            }
+            handled = true;
+        }
+    }
+
+    fn handle_control_msg(&mut self, (id, event, value): (usize, u16, u16)) {
+        let result = match event {
+            DEVICE_ADD if id < self.ports.len() => {
+                self.ports[id].added = true;
+                // the port is opened at once, or the device doesn't send data
+                self.control_send(id as u32, PORT_READY, 1)
+                    .and_then(|_| self.control_send(id as u32, PORT_OPEN, 1))
+            }
+            DEVICE_ADD => {
+                warn!("virtio-console: too many ports, port {} is not added", id);
+                self.control_send(id as u32, PORT_READY, 0)
+            }
+            DEVICE_REMOVE => {
+                if let Some(port) = self.ports.get_mut(id) {
+                    port.added = false;
+                }
+                Ok(())
+            }
+            CONSOLE_PORT => {
+                info!("virtio-console: port {} is a console", id);
+                Ok(())
+            }
+            PORT_OPEN => {
+                debug!(
+                    "virtio-console: port {} is opened by the host: {}",
+                    id, value
+                );
+                Ok(())
+            }
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("virtio-console: failed to reply event {}: {:?}", event, e);
+        }
+    }
+
+    /// Take received data of added ports, returns whether the port `id` has
+    /// received data, as bits.
+    fn collect(&mut self) -> usize {
+        let transport = &self.transport;
+        let mut received_ports = 0;
+        for (id, port) in self.ports.iter_mut().enumerate() {
+            let Port {
+                channel,
+                received,
+                added,
+            } = port;
+            if !*added {
+                continue;
+            }
+            while received.len() < RX_CAPACITY
+                && channel
+                    .recv_with(transport, |data| received.extend(data))
+                    .is_some()
+            {}
+            if !received.is_empty() {
+                received_ports |= 1 << id;
+            }
+        }
+        received_ports
+    }
+}
+
+struct ConsoleShared {
+    inner: Mutex<ConsoleInner>,
+    /// Listeners of each port.
+    listeners: Vec<EventListener>,
+}
+
+impl ConsoleShared {
+    fn handle_irq(&self) {
+        let received_ports = {
+            let mut inner = self.inner.lock();
+            if !inner.transport.ack_interrupt() {
+                // a shared interrupt of other devices
+                return;
+            }
+            inner.handle_control();
+            inner.collect()
+        };
+        for (id, listener) in self.listeners.iter().enumerate() {
+            if received_ports & (1 << id) != 0 {
+                listener.trigger(());
+            }
+        }
+    }
+}
+
+/// A port of a virtio-console device.
+pub struct VirtIoConsole {
+    shared: Arc<ConsoleShared>,
+    id: usize,
+}
+
+impl VirtIoConsole {
+    /// Initialize the device over `transport`, and create drivers of its ports.
+    ///
+    /// Interrupts of all ports can be handled by any one of them.
+    pub fn new(transport: Transport) -> DeviceResult<Vec<Self>> {
+        let features = transport.begin_init(VIRTIO_CONSOLE_F_MULTIPORT)?;
+        let multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        let nr_ports = if multiport {
+            (transport.config_read32(CONFIG_MAX_NR_PORTS) as usize).clamp(1, MAX_PORTS)
+        } else {
+            1
+        };
+        let mut ports = Vec::with_capacity(nr_ports);
+        for id in 0..nr_ports {
+            let (rx, tx) = port_queues(id);
+            ports.push(Port {
+                channel: Channel::new(&transport, rx, tx)?,
+                received: VecDeque::new(),
+                added: !multiport,
+            });
+        }
+        let control = if multiport {
+            Some(Channel::new(
+                &transport,
+                QUEUE_CONTROL_RECEIVE,
+                QUEUE_CONTROL_TRANSMIT,
+            )?)
+        } else {
+            None
+        };
+        transport.finish_init();
+        for port in ports.iter() {
+            transport.notify(port.channel.rx.index());
+        }
+        if let Some(control) = &control {
+            transport.notify(control.rx.index());
+        }
+
+        let mut inner = ConsoleInner {
+            transport,
+            ports,
+            control,
+        };
+        if multiport {
+            // ports are added in reply
+            inner.control_send(BAD_ID, DEVICE_READY, 1)?;
+            let mut idle = 0;
+            while idle < SPIN_IDLE {
+                if inner.handle_control() {
+                    idle = 0;
+                } else {
+                    idle += 1;
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        let ids: Vec<usize> = (0..nr_ports).filter(|&id| inner.ports[id].added).collect();
+        info!(
+            "virtio-console: initialized with ports {:?} of {}",
+            ids, nr_ports
+        );
+
+        let shared = Arc::new(ConsoleShared {
+            inner: Mutex::new(inner),
+            listeners: (0..nr_ports).map(|_| EventListener::new()).collect(),
+        });
+        Ok(ids
+            .into_iter()
+            .map(|id| Self {
+                shared: shared.clone(),
+                id,
+            })
+            .collect())
+    }
+}
+
+impl Scheme for VirtIoConsole {
     fn name(&self) -> &str {
         "virtio-console"
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.inner.lock().ack_interrupt().unwrap();
-        self.listener.trigger(());
+        self.shared.handle_irq();
+    }
+}
+
+impl EventScheme for VirtIoConsole {
+    type Event = ();
+
+    fn trigger(&self, event: ()) {
+        self.shared.listeners[self.id].trigger(event);
+    }
+
+    fn subscribe(&self, handler: EventHandler, once: bool) {
+        self.shared.listeners[self.id].subscribe(handler, once);
     }
 }
 
-impl<'a> UartScheme for VirtIoConsole<'a> {
+impl UartScheme for VirtIoConsole {
     fn try_recv(&self) -> DeviceResult<Option<u8>> {
-        Ok(self.inner.lock().recv(true)?)
+        let mut inner = self.shared.inner.lock();
+        if inner.ports[self.id].received.is_empty() {
+            // interrupts may be not routed
+            inner.collect();
+        }
+        Ok(inner.ports[self.id].received.pop_front())
     }
 
     fn send(&self, ch: u8) -> DeviceResult {
-        self.inner.lock().send(ch)?;
-        Ok(())
+        let inner = &mut *self.shared.inner.lock();
+        inner.ports[self.id].channel.send(&inner.transport, &[ch])
     }
-}
 
-impl<'a> Write for VirtIoConsole<'a> {
-    fn write_str(&mut self, s: &str) -> Result {
-        for b in s.bytes() {
-            self.send(b).unwrap()
+    fn write_str(&self, s: &str) -> DeviceResult {
+        let inner = &mut *self.shared.inner.lock();
+        for chunk in s.as_bytes().chunks(BUF_SIZE) {
+            inner.ports[self.id].channel.send(&inner.transport, chunk)?;
         }
         Ok(())
     }
@@ -1,68 +1,350 @@
-use lock::Mutex;
-use virtio_drivers::{VirtIOGpu as InnerDriver, VirtIOHeader};
+//! virtio-gpu 2D device, whose framebuffer is flushed by dirty rectangles,
+//! with a hardware cursor, and mode changes on host resize events.
 
-use crate::prelude::{ColorFormat, DisplayInfo, FrameBuffer};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use lock::{Mutex, RwLock};
+
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::prelude::{ColorFormat, DisplayInfo, FrameBuffer, Rectangle};
 use crate::scheme::{DisplayScheme, Scheme};
-use crate::DeviceResult;
+use crate::{DeviceError, DeviceResult};
 
-pub struct VirtIoGpu<'a> {
-    info: DisplayInfo,
-    inner: Mutex<InnerDriver<'a>>,
-}
+const QUEUE_CONTROL: u16 = 0;
+const QUEUE_CURSOR: u16 = 1;
+const QUEUE_SIZE: u16 = 16;
+
+// device configuration
+const CONFIG_EVENTS_READ: usize = 0;
+const CONFIG_EVENTS_CLEAR: usize = 4;
+/// The display configuration has changed, e.g. the host window is resized.
+const EVENT_DISPLAY: u32 = 1;
+
+// 2D commands
+const CMD_GET_DISPLAY_INFO: u32 = 0x0100;
+const CMD_RESOURCE_CREATE_2D: u32 = 0x0101;
+const CMD_RESOURCE_UNREF: u32 = 0x0102;
+const CMD_SET_SCANOUT: u32 = 0x0103;
+const CMD_RESOURCE_FLUSH: u32 = 0x0104;
+const CMD_TRANSFER_TO_HOST_2D: u32 = 0x0105;
+const CMD_RESOURCE_ATTACH_BACKING: u32 = 0x0106;
+// cursor commands
+const CMD_UPDATE_CURSOR: u32 = 0x0300;
+const CMD_MOVE_CURSOR: u32 = 0x0301;
+// responses
+const RESP_OK_NODATA: u32 = 0x1100;
+const RESP_OK_DISPLAY_INFO: u32 = 0x1101;
+
+/// Size of `struct virtio_gpu_ctrl_hdr`.
+const HDR_SIZE: usize = 24;
+/// Size of the response of `CMD_GET_DISPLAY_INFO`, for 16 scanouts.
+const DISPLAY_INFO_SIZE: usize = HDR_SIZE + 16 * 24;
+/// Offset of responses in the command buffer.
+const RESP_OFFSET: usize = 2048;
 
+/// Pixels in bytes B, G, R, A, i.e. ARGB8888 in little endian.
+const FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const BYTES_PER_PIXEL: u32 = 4;
+
+const SCANOUT_ID: u32 = 0;
+const RESOURCE_CURSOR: u32 = 1;
+const CURSOR_SIZE: u32 = 64;
 const CURSOR_HOT_X: u32 = 13;
 const CURSOR_HOT_Y: u32 = 11;
 static CURSOR_IMG: &[u8] = include_bytes!("../display/resource/cursor.bin"); // 64 x 64 x 4
 
-impl<'a> VirtIoGpu<'a> {
-    pub fn new(header: &'static mut VirtIOHeader) -> DeviceResult<Self> {
-        let mut gpu = InnerDriver::new(header)?;
-        let fb = gpu.setup_framebuffer()?;
-        let fb_base_vaddr = fb.as_ptr() as usize;
-        let fb_size = fb.len();
-        let (width, height) = gpu.resolution();
+/// Used if the host doesn't report the size of the scanout.
+const DEFAULT_WIDTH: u32 = 1280;
+const DEFAULT_HEIGHT: u32 = 800;
+
+const SPIN_TIMEOUT: usize = 0x100_0000;
+
+struct GpuInner {
+    transport: Transport,
+    control: VirtQueue,
+    cursor: VirtQueue,
+    /// Request and response of the command being sent, one at a time.
+    cmd_buf: DmaRegion,
+    cursor_cmd_buf: DmaRegion,
+    cursor_img: Option<DmaRegion>,
+    /// The framebuffer and its resource.
+    fb: Option<(DmaRegion, u32)>,
+    next_resource: u32,
+    /// Framebuffers of previous modes, which may still be mapped to user space,
+    /// freed once dirty tracking is enabled again.
+    retired: Vec<DmaRegion>,
+}
+
+impl GpuInner {
+    /// Wait for the device to use the buffer `token` in `queue`.
+    fn wait_used(queue: &mut VirtQueue, token: u16) -> DeviceResult {
+        for _ in 0..SPIN_TIMEOUT {
+            match queue.pop_used() {
+                Some((t, _)) if t == token => return Ok(()),
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(DeviceError::IoError)
+    }
+
+    /// Send a control command with the given fields after the header, and wait
+    /// for a response of `resp_len` bytes of the type `resp_type`.
+    fn command(&mut self, cmd: u32, args: &[u32], resp_type: u32, resp_len: usize) -> DeviceResult {
+        let req_len = HDR_SIZE + args.len() * 4;
+        let buf = self.cmd_buf.as_mut_slice(0, RESP_OFFSET + resp_len);
+        buf.fill(0);
+        buf[..4].copy_from_slice(&cmd.to_le_bytes());
+        for (i, arg) in args.iter().enumerate() {
+            let off = HDR_SIZE + i * 4;
+            buf[off..off + 4].copy_from_slice(&arg.to_le_bytes());
+        }
+        let token = self.control.add_chain(
+            &[(self.cmd_buf.paddr(0), req_len)],
+            &[(self.cmd_buf.paddr(RESP_OFFSET), resp_len)],
+        )?;
+        self.transport.notify(QUEUE_CONTROL);
+        Self::wait_used(&mut self.control, token)?;
+        match self.resp_u32(0) {
+            t if t == resp_type => Ok(()),
+            t => {
+                warn!("virtio-gpu: command {:#x} failed with {:#x}", cmd, t);
+                Err(DeviceError::IoError)
+            }
+        }
+    }
+
+    fn command_nodata(&mut self, cmd: u32, args: &[u32]) -> DeviceResult {
+        self.command(cmd, args, RESP_OK_NODATA, HDR_SIZE)
+    }
+
+    /// Read a 32-bit field of the response at byte `offset`.
+    fn resp_u32(&self, offset: usize) -> u32 {
+        let buf = self.cmd_buf.as_slice(RESP_OFFSET + offset, 4);
+        u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+    }
+
+    /// Send a cursor command with the position of the cursor, and the given
+    /// fields after it.
+    fn cursor_command(&mut self, cmd: u32, x: u32, y: u32, args: &[u32]) -> DeviceResult {
+        let mut words = [0u32; 14];
+        words[0] = cmd;
+        words[6..9].copy_from_slice(&[SCANOUT_ID, x, y]);
+        words[10..10 + args.len()].copy_from_slice(args);
+        let buf = self.cursor_cmd_buf.as_mut_slice(0, words.len() * 4);
+        for (dst, w) in buf.chunks_exact_mut(4).zip(words.iter()) {
+            dst.copy_from_slice(&w.to_le_bytes());
+        }
+        let token = self
+            .cursor
+            .add(self.cursor_cmd_buf.paddr(0), words.len() * 4, false)?;
+        self.transport.notify(QUEUE_CURSOR);
+        Self::wait_used(&mut self.cursor, token)
+    }
+
+    /// Size of the first scanout reported by the host.
+    fn display_size(&mut self) -> DeviceResult<(u32, u32)> {
+        self.command(
+            CMD_GET_DISPLAY_INFO,
+            &[],
+            RESP_OK_DISPLAY_INFO,
+            DISPLAY_INFO_SIZE,
+        )?;
+        let off = HDR_SIZE + SCANOUT_ID as usize * 24;
+        let (width, height) = (self.resp_u32(off + 8), self.resp_u32(off + 12));
+        if width == 0 || height == 0 {
+            Ok((DEFAULT_WIDTH, DEFAULT_HEIGHT))
+        } else {
+            Ok((width, height))
+        }
+    }
+
+    /// Create a 2D resource backed by the memory at `paddr`.
+    fn create_resource(&mut self, id: u32, width: u32, height: u32, paddr: usize) -> DeviceResult {
+        let len = width * height * BYTES_PER_PIXEL;
+        self.command_nodata(
+            CMD_RESOURCE_CREATE_2D,
+            &[id, FORMAT_B8G8R8A8_UNORM, width, height],
+        )?;
+        self.command_nodata(
+            CMD_RESOURCE_ATTACH_BACKING,
+            &[id, 1, paddr as u32, (paddr >> 32) as u32, len, 0],
+        )
+    }
+
+    /// Copy a rectangle of the resource `id` from the guest memory to the host.
+    fn transfer(&mut self, id: u32, pitch: u32, rect: &Rectangle) -> DeviceResult {
+        let offset = rect.y * pitch + rect.x * BYTES_PER_PIXEL;
+        let r = [rect.x, rect.y, rect.width, rect.height];
+        self.command_nodata(
+            CMD_TRANSFER_TO_HOST_2D,
+            &[r[0], r[1], r[2], r[3], offset, 0, id, 0],
+        )
+    }
+
+    /// Push a rectangle of the framebuffer to screen.
+    fn flush_rect(&mut self, width: u32, rect: &Rectangle) -> DeviceResult {
+        let id = self.fb.as_ref().ok_or(DeviceError::NotReady)?.1;
+        self.transfer(id, width * BYTES_PER_PIXEL, rect)?;
+        let r = [rect.x, rect.y, rect.width, rect.height];
+        self.command_nodata(CMD_RESOURCE_FLUSH, &[r[0], r[1], r[2], r[3], id, 0])
+    }
+
+    /// Create a framebuffer of the size, scan it out, and release the previous
+    /// one. The memory is reused unless it's too small, and the old memory is
+    /// kept if it's `mapped` to user space.
+    fn set_mode(&mut self, width: u32, height: u32, mapped: bool) -> DeviceResult<DisplayInfo> {
+        if width == 0 || height == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        let size = (width * height * BYTES_PER_PIXEL) as usize;
+        let (mut region, old_id) = match self.fb.take() {
+            Some((region, id)) if region.size() >= size => (region, Some(id)),
+            Some((region, id)) => {
+                if mapped {
+                    self.retired.push(region);
+                }
+                (DmaRegion::new(size)?, Some(id))
+            }
+            None => (DmaRegion::new(size)?, None),
+        };
+        region.as_mut_slice(0, size).fill(0);
+
+        let id = self.next_resource;
+        self.next_resource += 1;
+        let paddr = region.paddr(0);
         let info = DisplayInfo {
             width,
             height,
             format: ColorFormat::ARGB8888,
-            fb_base_vaddr,
-            fb_size,
+            fb_base_vaddr: region.vaddr(0),
+            fb_size: size,
         };
-        gpu.setup_cursor(
-            CURSOR_IMG,
-            width / 2,
-            height / 2,
-            CURSOR_HOT_X,
-            CURSOR_HOT_Y,
-        )?;
+        self.fb = Some((region, id));
+        self.create_resource(id, width, height, paddr)?;
+        self.command_nodata(CMD_SET_SCANOUT, &[0, 0, width, height, SCANOUT_ID, id])?;
+        if let Some(old_id) = old_id {
+            self.command_nodata(CMD_RESOURCE_UNREF, &[old_id, 0])?;
+        }
+        Ok(info)
+    }
+
+    fn set_cursor(&mut self, image: &[u8], hot_x: u32, hot_y: u32) -> DeviceResult {
+        let size = (CURSOR_SIZE * CURSOR_SIZE * BYTES_PER_PIXEL) as usize;
+        if image.len() != size {
+            return Err(DeviceError::InvalidParam);
+        }
+        if self.cursor_img.is_none() {
+            let region = DmaRegion::new(size)?;
+            self.create_resource(RESOURCE_CURSOR, CURSOR_SIZE, CURSOR_SIZE, region.paddr(0))?;
+            self.cursor_img = Some(region);
+        }
+        if let Some(region) = self.cursor_img.as_mut() {
+            region.as_mut_slice(0, size).copy_from_slice(image);
+        }
+        let rect = Rectangle {
+            x: 0,
+            y: 0,
+            width: CURSOR_SIZE,
+            height: CURSOR_SIZE,
+        };
+        self.transfer(RESOURCE_CURSOR, CURSOR_SIZE * BYTES_PER_PIXEL, &rect)?;
+        self.cursor_command(CMD_UPDATE_CURSOR, 0, 0, &[RESOURCE_CURSOR, hot_x, hot_y])
+    }
+}
+
+pub struct VirtIoGpu {
+    inner: Mutex<GpuInner>,
+    info: RwLock<DisplayInfo>,
+    dirty: Mutex<Option<Rectangle>>,
+    /// Flush only the dirty rectangle, rather than the whole framebuffer.
+    track_dirty: AtomicBool,
+    /// The host changed the display configuration, to be handled on flush.
+    resize_pending: AtomicBool,
+}
+
+impl VirtIoGpu {
+    /// Initialize the device over `transport`, with a framebuffer of the size
+    /// of the host display, and the default cursor at the center.
+    pub fn new(transport: Transport) -> DeviceResult<Self> {
+        transport.begin_init(0)?;
+        let control = VirtQueue::new(&transport, QUEUE_CONTROL, QUEUE_SIZE)?;
+        let cursor = VirtQueue::new(&transport, QUEUE_CURSOR, QUEUE_SIZE)?;
+        transport.finish_init();
+
+        let mut inner = GpuInner {
+            transport,
+            control,
+            cursor,
+            cmd_buf: DmaRegion::new(RESP_OFFSET + DISPLAY_INFO_SIZE)?,
+            cursor_cmd_buf: DmaRegion::new(64)?,
+            cursor_img: None,
+            fb: None,
+            next_resource: RESOURCE_CURSOR + 1,
+            retired: Vec::new(),
+        };
+        let (width, height) = inner.display_size()?;
+        let info = inner.set_mode(width, height, false)?;
+        inner.set_cursor(CURSOR_IMG, CURSOR_HOT_X, CURSOR_HOT_Y)?;
+        inner.cursor_command(CMD_MOVE_CURSOR, width / 2, height / 2, &[])?;
+        info!("virtio-gpu: {}x{} framebuffer", width, height);
+
         Ok(Self {
-            info,
-            inner: Mutex::new(gpu),
+            inner: Mutex::new(inner),
+            info: RwLock::new(info),
+            dirty: Mutex::new(None),
+            track_dirty: AtomicBool::new(true),
+            resize_pending: AtomicBool::new(false),
         })
     }
+
+    fn set_mode_locked(&self, inner: &mut GpuInner, width: u32, height: u32) -> DeviceResult {
+        let mapped = !self.track_dirty.load(Ordering::Relaxed);
+        let info = inner.set_mode(width, height, mapped)?;
+        *self.info.write() = info;
+        *self.dirty.lock() = Some(Rectangle {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        });
+        info!("virtio-gpu: mode changed to {}x{}", width, height);
+        Ok(())
+    }
 }
 
-impl<'a> Scheme for VirtIoGpu<'a> {
+impl Scheme for VirtIoGpu {
     fn name(&self) -> &str {
         "virtio-gpu"
     }
 
     fn handle_irq(&self, _irq_num: usize) {
-        self.inner.lock().ack_interrupt();
+        let inner = self.inner.lock();
+        if !inner.transport.ack_interrupt() {
+            return;
+        }
+        let events = inner.transport.config_read32(CONFIG_EVENTS_READ);
+        if events & EVENT_DISPLAY != 0 {
+            inner
+                .transport
+                .config_write32(CONFIG_EVENTS_CLEAR, EVENT_DISPLAY);
+            self.resize_pending.store(true, Ordering::Release);
+        }
     }
 }
 
-impl<'a> DisplayScheme for VirtIoGpu<'a> {
+impl DisplayScheme for VirtIoGpu {
     #[inline]
     fn info(&self) -> DisplayInfo {
-        self.info
+        *self.info.read()
     }
 
     #[inline]
     fn fb(&self) -> FrameBuffer {
-        unsafe {
-            FrameBuffer::from_raw_parts_mut(self.info.fb_base_vaddr as *mut u8, self.info.fb_size)
-        }
+        let info = self.info();
+        unsafe { FrameBuffer::from_raw_parts_mut(info.fb_base_vaddr as *mut u8, info.fb_size) }
     }
 
     #[inline]
@@ -70,8 +352,71 @@ impl<'a> DisplayScheme for VirtIoGpu<'a> {
         true
     }
 
+    fn mark_dirty(&self, rect: &Rectangle) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let mut dirty = self.dirty.lock();
+        *dirty = Some(match dirty.as_ref() {
+            Some(d) => d.union(rect),
+            None => *rect,
+        });
+    }
+
+    fn track_dirty(&self, enable: bool) {
+        self.track_dirty.store(enable, Ordering::Relaxed);
+        if enable {
+            // nothing is mapped to user space any more
+            self.inner.lock().retired.clear();
+        }
+    }
+
     fn flush(&self) -> DeviceResult {
-        self.inner.lock().flush()?;
-        Ok(())
+        let mut inner = self.inner.lock();
+        if self.resize_pending.swap(false, Ordering::Acquire) {
+            let (width, height) = inner.display_size()?;
+            let info = self.info();
+            if (width, height) != (info.width, info.height) {
+                self.set_mode_locked(&mut inner, width, height)?;
+            }
+        }
+        let info = self.info();
+        let dirty = self.dirty.lock().take();
+        let rect = match dirty {
+            _ if !self.track_dirty.load(Ordering::Relaxed) => Rectangle {
+                x: 0,
+                y: 0,
+                width: info.width,
+                height: info.height,
+            },
+            Some(rect) => rect,
+            None => return Ok(()),
+        };
+        // clip to the current mode, marked before a mode change
+        let x = rect.x.min(info.width);
+        let y = rect.y.min(info.height);
+        let rect = Rectangle {
+            x,
+            y,
+            width: (rect.x + rect.width).min(info.width) - x,
+            height: (rect.y + rect.height).min(info.height) - y,
+        };
+        if rect.width == 0 || rect.height == 0 {
+            return Ok(());
+        }
+        inner.flush_rect(info.width, &rect)
+    }
+
+    fn set_mode(&self, width: u32, height: u32) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        self.set_mode_locked(&mut inner, width, height)
+    }
+
+    fn set_cursor(&self, image: &[u8], hot_x: u32, hot_y: u32) -> DeviceResult {
+        self.inner.lock().set_cursor(image, hot_x, hot_y)
+    }
+
+    fn move_cursor(&self, x: u32, y: u32) -> DeviceResult {
+        self.inner.lock().cursor_command(CMD_MOVE_CURSOR, x, y, &[])
     }
 }
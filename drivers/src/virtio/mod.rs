@@ -1,15 +1,25 @@
-//! Packaging of [`virtio-drivers` library](https://github.com/rcore-os/virtio-drivers).
+//! Packaging of [`virtio-drivers` library](https://github.com/rcore-os/virtio-drivers),
+//! and in-tree drivers over our own transports and virtqueues.
 
 mod blk;
 mod console;
 mod gpu;
 mod input;
+mod net;
+mod queue;
+mod rng;
+mod transport;
+mod vsock;
 
 pub use blk::VirtIoBlk;
 pub use console::VirtIoConsole;
 pub use gpu::VirtIoGpu;
 pub use input::VirtIoInput;
+pub use net::VirtIoNet;
+pub use rng::VirtIoRng;
+pub use transport::{PciRegions, Transport};
 pub use virtio_drivers::VirtIOHeader;
+pub use vsock::VirtIoVsock;
 
 use crate::DeviceError;
 use core::convert::From;
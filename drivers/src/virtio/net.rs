@@ -0,0 +1,394 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::{format, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use lock::Mutex;
+use smoltcp::iface::{Interface, InterfaceBuilder, NeighborCache, Routes};
+use smoltcp::phy::{self, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address};
+
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::net::capture::{Capture, FrameSink};
+use crate::net::hooked::HookedDevice;
+use crate::net::{add_iface_route, del_iface_route};
+use crate::net::{get_sockets, iface_config, iface_ipv6_config, timer_now_as_micros};
+use crate::net::{iface_ipv4_gateway, set_iface_ip_addrs, set_iface_ipv4_gateway};
+use crate::net::{iface_ipv6_gateway, set_iface_ipv6_gateway};
+use crate::net::{join_iface_multicast_group, leave_iface_multicast_group};
+use crate::scheme::{NetScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+const QUEUE_SIZE: u16 = 64;
+
+/// Size of each buffer, enough for the header and an Ethernet frame.
+const BUF_SIZE: usize = 2048;
+/// Maximum size of an Ethernet frame without FCS.
+const MAX_FRAME_SIZE: usize = 1514;
+
+/// The device has a given MAC address.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+/// Size of `struct virtio_net_hdr`, whose `num_buffers` field is omitted by
+/// legacy devices without `VIRTIO_NET_F_MRG_RXBUF`.
+const NET_HDR_SIZE: usize = 12;
+const NET_HDR_SIZE_LEGACY: usize = 10;
+
+/// Number of probed devices, to name them and assign addresses.
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// The virtqueues and their buffers.
+///
+/// Every receive buffer is kept available to the device, and the used ones are
+/// collected on interrupts, so that frames are not lost between polls.
+struct Rings {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    rx_bufs: DmaRegion,
+    tx_bufs: DmaRegion,
+    /// token -> buffer slot
+    rx_slots: Vec<usize>,
+    tx_slots: Vec<usize>,
+    /// Received frames as (slot, length), not yet taken by the stack.
+    received: VecDeque<(usize, usize)>,
+    /// Transmit buffer slots not in use.
+    tx_free: Vec<usize>,
+    hdr_len: usize,
+}
+
+impl Rings {
+    fn post_rx(&mut self, slot: usize) -> DeviceResult {
+        let token = self
+            .rx
+            .add(self.rx_bufs.paddr(slot * BUF_SIZE), BUF_SIZE, true)?;
+        self.rx_slots[token as usize] = slot;
+        Ok(())
+    }
+
+    /// Collect the buffers used by the device.
+    fn collect(&mut self) {
+        let mut reposted = false;
+        while let Some((token, len)) = self.rx.pop_used() {
+            let slot = self.rx_slots[token as usize];
+            if len > self.hdr_len {
+                self.received.push_back((slot, len - self.hdr_len));
+            } else if self.post_rx(slot).is_ok() {
+                reposted = true;
+            }
+        }
+        if reposted {
+            self.transport.notify(self.rx.index());
+        }
+        while let Some((token, _)) = self.tx.pop_used() {
+            self.tx_free.push(self.tx_slots[token as usize]);
+        }
+    }
+
+    fn can_send(&mut self) -> bool {
+        self.collect();
+        !self.tx_free.is_empty()
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.collect();
+        let (slot, len) = self.received.pop_front()?;
+        let frame = self
+            .rx_bufs
+            .as_slice(slot * BUF_SIZE + self.hdr_len, len)
+            .to_vec();
+        match self.post_rx(slot) {
+            Ok(()) => self.transport.notify(self.rx.index()),
+            Err(e) => warn!("virtio-net: failed to post receive buffer: {:?}", e),
+        }
+        Some(frame)
+    }
+
+    fn send(&mut self, frame: &[u8]) -> DeviceResult {
+        if frame.len() + self.hdr_len > BUF_SIZE {
+            return Err(DeviceError::InvalidParam);
+        }
+        self.collect();
+        let slot = self.tx_free.pop().ok_or(DeviceError::NotReady)?;
+        let len = self.hdr_len + frame.len();
+        let buf = self.tx_bufs.as_mut_slice(slot * BUF_SIZE, len);
+        let (hdr, data) = buf.split_at_mut(self.hdr_len);
+        hdr.fill(0);
+        data.copy_from_slice(frame);
+        match self.tx.add(self.tx_bufs.paddr(slot * BUF_SIZE), len, false) {
+            Ok(token) => self.tx_slots[token as usize] = slot,
+            Err(e) => {
+                self.tx_free.push(slot);
+                return Err(e);
+            }
+        }
+        self.transport.notify(self.tx.index());
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct VirtIoNetDriver(Arc<Mutex<Rings>>);
+
+pub struct VirtIoNet {
+    iface: Mutex<Interface<'static, HookedDevice<VirtIoNetDriver>>>,
+    driver: VirtIoNetDriver,
+    capture: Arc<Capture>,
+    name: String,
+}
+
+impl VirtIoNet {
+    /// Initialize the device over `transport` and create its interface, named
+    /// `name` or `ethN` if not given.
+    ///
+    /// The addresses are assigned by [`iface_config`](crate::net::iface_config) and
+    /// [`iface_ipv6_config`](crate::net::iface_ipv6_config).
+    pub fn new(transport: Transport, name: Option<String>) -> DeviceResult<Self> {
+        let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        let name = name.unwrap_or_else(|| format!("eth{}", index));
+        let features = transport.begin_init(VIRTIO_NET_F_MAC)?;
+        let hdr_len = if transport.is_legacy() {
+            NET_HDR_SIZE_LEGACY
+        } else {
+            NET_HDR_SIZE
+        };
+        // locally administered, used if the device doesn't provide one
+        let mut mac = [0x52, 0x54, 0x00, 0x12, 0x34, index as u8];
+        if features & VIRTIO_NET_F_MAC != 0 {
+            for (i, byte) in mac.iter_mut().enumerate() {
+                *byte = transport.config_read8(i);
+            }
+        }
+
+        let rx = VirtQueue::new(&transport, QUEUE_RECEIVE, QUEUE_SIZE)?;
+        let tx = VirtQueue::new(&transport, QUEUE_TRANSMIT, QUEUE_SIZE)?;
+        let rx_bufs = DmaRegion::new(rx.size() * BUF_SIZE)?;
+        let tx_bufs = DmaRegion::new(tx.size() * BUF_SIZE)?;
+        transport.finish_init();
+
+        let mut rings = Rings {
+            rx_slots: vec![0; rx.size()],
+            tx_slots: vec![0; tx.size()],
+            received: VecDeque::new(),
+            tx_free: (0..tx.size()).collect(),
+            transport,
+            rx,
+            tx,
+            rx_bufs,
+            tx_bufs,
+            hdr_len,
+        };
+        for slot in 0..rings.rx.size() {
+            rings.post_rx(slot)?;
+        }
+        rings.transport.notify(QUEUE_RECEIVE);
+        let driver = VirtIoNetDriver(Arc::new(Mutex::new(rings)));
+        let capture = Arc::new(Capture::default());
+
+        let (ip, gateway) = iface_config(index);
+        let (ipv6_addrs, ipv6_gateway) = iface_ipv6_config(index, EthernetAddress(mac));
+        let mut routes = Routes::new(BTreeMap::new());
+        if let Some(gateway) = gateway {
+            routes
+                .add_default_ipv4_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        if let Some(gateway) = ipv6_gateway {
+            routes
+                .add_default_ipv6_route(gateway)
+                .map_err(|_| DeviceError::NoResources)?;
+        }
+        let mut ip_addrs = vec![ip];
+        ip_addrs.extend(ipv6_addrs);
+        let device = HookedDevice::new(driver.clone(), &name, capture.clone());
+        let iface = InterfaceBuilder::new(device)
+            .ethernet_addr(EthernetAddress(mac))
+            .neighbor_cache(NeighborCache::new(BTreeMap::new()))
+            .ip_addrs(ip_addrs)
+            .routes(routes)
+            .ipv4_multicast_groups(BTreeMap::new())
+            .finalize();
+        info!(
+            "virtio-net interface {} up with mac {} addr {}",
+            name,
+            EthernetAddress(mac),
+            ip
+        );
+
+        Ok(Self {
+            iface: Mutex::new(iface),
+            driver,
+            capture,
+            name,
+        })
+    }
+}
+
+impl Scheme for VirtIoNet {
+    fn name(&self) -> &str {
+        "virtio-net"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let received = {
+            let mut rings = self.driver.0.lock();
+            if !rings.transport.ack_interrupt() {
+                // a shared interrupt of other devices
+                return;
+            }
+            rings.collect();
+            !rings.received.is_empty()
+        };
+        if received {
+            self.poll().ok();
+        }
+    }
+}
+
+impl NetScheme for VirtIoNet {
+    fn recv(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let frame = self.driver.0.lock().recv().ok_or(DeviceError::NotReady)?;
+        self.capture.deliver(&frame, false);
+        if frame.len() > buf.len() {
+            return Err(DeviceError::BufferTooSmall);
+        }
+        buf[..frame.len()].copy_from_slice(&frame);
+        Ok(frame.len())
+    }
+
+    fn send(&self, buf: &[u8]) -> DeviceResult<usize> {
+        self.driver.0.lock().send(buf)?;
+        self.capture.deliver(buf, true);
+        Ok(buf.len())
+    }
+
+    fn attach_capture(&self, sink: Weak<dyn FrameSink>) -> DeviceResult {
+        self.capture.attach(sink);
+        Ok(())
+    }
+
+    /// Without `VIRTIO_NET_F_CTRL_RX`, the receive filter can't be configured,
+    /// and devices deliver all frames.
+    fn set_promiscuous(&self, _enable: bool) -> DeviceResult {
+        Ok(())
+    }
+
+    fn get_mac(&self) -> EthernetAddress {
+        self.iface.lock().ethernet_addr()
+    }
+
+    fn get_ifname(&self) -> String {
+        self.name.clone()
+    }
+
+    fn get_ip_address(&self) -> Vec<IpCidr> {
+        Vec::from(self.iface.lock().ip_addrs())
+    }
+
+    fn set_ip_address(&self, addrs: Vec<IpCidr>) -> DeviceResult {
+        set_iface_ip_addrs(&mut self.iface.lock(), addrs);
+        Ok(())
+    }
+
+    fn get_ipv4_gateway(&self) -> Option<Ipv4Address> {
+        iface_ipv4_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv4_gateway(&self, gateway: Option<Ipv4Address>) -> DeviceResult {
+        set_iface_ipv4_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn get_ipv6_gateway(&self) -> Option<Ipv6Address> {
+        iface_ipv6_gateway(&mut self.iface.lock())
+    }
+
+    fn set_ipv6_gateway(&self, gateway: Option<Ipv6Address>) -> DeviceResult {
+        set_iface_ipv6_gateway(&mut self.iface.lock(), gateway)
+    }
+
+    fn add_route(&self, dst: IpCidr, gateway: IpAddress) -> DeviceResult {
+        add_iface_route(&mut self.iface.lock(), dst, gateway)
+    }
+
+    fn del_route(&self, dst: IpCidr) -> DeviceResult {
+        del_iface_route(&mut self.iface.lock(), dst)
+    }
+
+    fn join_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        join_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn leave_multicast_group(&self, group: Ipv4Address) -> DeviceResult {
+        leave_iface_multicast_group(&mut self.iface.lock(), group)
+    }
+
+    fn poll(&self) -> DeviceResult {
+        let timestamp = Instant::from_micros(timer_now_as_micros() as i64);
+        let sockets = get_sockets();
+        let mut sockets = sockets.lock();
+        match self.iface.lock().poll(&mut sockets, timestamp) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                debug!("virtio-net poll got err {}", err);
+                Err(DeviceError::IoError)
+            }
+        }
+    }
+}
+
+pub struct VirtIoNetRxToken(Vec<u8>);
+pub struct VirtIoNetTxToken(VirtIoNetDriver);
+
+impl phy::Device<'_> for VirtIoNetDriver {
+    type RxToken = VirtIoNetRxToken;
+    type TxToken = VirtIoNetTxToken;
+
+    fn receive(&mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let frame = self.0.lock().recv()?;
+        Some((VirtIoNetRxToken(frame), VirtIoNetTxToken(self.clone())))
+    }
+
+    fn transmit(&mut self) -> Option<Self::TxToken> {
+        if self.0.lock().can_send() {
+            Some(VirtIoNetTxToken(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = MAX_FRAME_SIZE;
+        caps.max_burst_size = Some(QUEUE_SIZE as usize);
+        caps
+    }
+}
+
+impl phy::RxToken for VirtIoNetRxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for VirtIoNetTxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> smoltcp::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> smoltcp::Result<R>,
+    {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let result = f(&mut buffer[..len])?;
+        self.0
+             .0
+            .lock()
+            .send(&buffer[..len])
+            .map_err(|_| smoltcp::Error::Exhausted)?;
+        Ok(result)
+    }
+}
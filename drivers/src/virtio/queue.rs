@@ -0,0 +1,208 @@
+//! Split virtqueues, and DMA memory of in-tree virtio drivers.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use super::transport::Transport;
+use crate::bus::{read, write, PAGE_SIZE};
+use crate::net::{Provider, ProviderImpl};
+use crate::{DeviceError, DeviceResult};
+
+/// The buffer continues via the `next` field.
+const DESC_F_NEXT: u16 = 1;
+/// The buffer is write-only for the device.
+const DESC_F_WRITE: u16 = 2;
+
+const fn align_up(size: usize) -> usize {
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Physically contiguous and zeroed memory for DMA.
+pub struct DmaRegion {
+    vaddr: usize,
+    paddr: usize,
+    size: usize,
+}
+
+impl DmaRegion {
+    pub fn new(size: usize) -> DeviceResult<Self> {
+        let size = align_up(size);
+        let (vaddr, paddr) = ProviderImpl::alloc_dma(size);
+        if paddr == 0 {
+            return Err(DeviceError::DmaError);
+        }
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, size) };
+        Ok(Self { vaddr, paddr, size })
+    }
+
+    pub fn vaddr(&self, offset: usize) -> usize {
+        self.vaddr + offset
+    }
+
+    pub fn paddr(&self, offset: usize) -> usize {
+        self.paddr + offset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_slice(&self, offset: usize, len: usize) -> &[u8] {
+        assert!(offset + len <= self.size);
+        unsafe { core::slice::from_raw_parts((self.vaddr + offset) as *const u8, len) }
+    }
+
+    pub fn as_mut_slice(&mut self, offset: usize, len: usize) -> &mut [u8] {
+        assert!(offset + len <= self.size);
+        unsafe { core::slice::from_raw_parts_mut((self.vaddr + offset) as *mut u8, len) }
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        ProviderImpl::dealloc_dma(self.vaddr, self.size);
+    }
+}
+
+/// A split virtqueue, where each buffer takes a single descriptor, or a chain
+/// of them for devices which take the request and the response separately.
+///
+/// The layout also works for legacy devices: the available ring follows the
+/// descriptor table, and the used ring starts at the next page.
+pub struct VirtQueue {
+    region: DmaRegion,
+    index: u16,
+    size: u16,
+    avail_offset: usize,
+    used_offset: usize,
+    /// Free descriptors.
+    free: Vec<u16>,
+    avail_idx: u16,
+    last_used_idx: u16,
+}
+
+impl VirtQueue {
+    /// Create and set up the virtqueue `index` with at most `size` entries,
+    /// which must be a power of 2.
+    pub fn new(transport: &Transport, index: u16, size: u16) -> DeviceResult<Self> {
+        debug_assert!(size.is_power_of_two());
+        let max_size = transport.max_queue_size(index);
+        if max_size == 0 {
+            return Err(DeviceError::NotSupported);
+        }
+        let size = size.min(max_size);
+        let n = size as usize;
+        let avail_offset = 16 * n;
+        let used_offset = align_up(avail_offset + 6 + 2 * n);
+        let region = DmaRegion::new(used_offset + 6 + 8 * n)?;
+        transport.setup_queue(
+            index,
+            size,
+            region.paddr(0),
+            region.paddr(avail_offset),
+            region.paddr(used_offset),
+        );
+        Ok(Self {
+            region,
+            index,
+            size,
+            avail_offset,
+            used_offset,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used_idx: 0,
+        })
+    }
+
+    /// Index of the virtqueue in the device.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Number of entries.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Make the buffer at `paddr` available to the device, returns a token to
+    /// identify it when it's used, which is less than the queue size.
+    ///
+    /// The device writes into the buffer if `writable`, otherwise reads it.
+    pub fn add(&mut self, paddr: usize, len: usize, writable: bool) -> DeviceResult<u16> {
+        if writable {
+            self.add_chain(&[], &[(paddr, len)])
+        } else {
+            self.add_chain(&[(paddr, len)], &[])
+        }
+    }
+
+    /// Make a chain of buffers available to the device, given as `(paddr, len)`
+    /// pairs, the device reads `inputs` and then writes `outputs`. Returns a
+    /// token as [`add`](Self::add).
+    pub fn add_chain(
+        &mut self,
+        inputs: &[(usize, usize)],
+        outputs: &[(usize, usize)],
+    ) -> DeviceResult<u16> {
+        let n = inputs.len() + outputs.len();
+        if n == 0 {
+            return Err(DeviceError::InvalidParam);
+        }
+        if n > self.free.len() {
+            return Err(DeviceError::NotReady);
+        }
+        let mut head = None;
+        let mut prev: Option<usize> = None;
+        for (i, &(paddr, len)) in inputs.iter().chain(outputs).enumerate() {
+            let id = self.free.pop().unwrap();
+            let desc = self.region.vaddr(16 * id as usize);
+            write(desc, paddr as u64);
+            write(desc + 8, len as u32);
+            write(desc + 12, if i < inputs.len() { 0 } else { DESC_F_WRITE });
+            write(desc + 14, 0u16);
+            if let Some(prev) = prev {
+                write(prev + 12, read::<u16>(prev + 12) | DESC_F_NEXT);
+                write(prev + 14, id);
+            }
+            head.get_or_insert(id);
+            prev = Some(desc);
+        }
+        let id = head.unwrap();
+
+        let slot = (self.avail_idx % self.size) as usize;
+        write(self.region.vaddr(self.avail_offset + 4 + 2 * slot), id);
+        // the entry must be visible before the index
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        write(self.region.vaddr(self.avail_offset + 2), self.avail_idx);
+        fence(Ordering::SeqCst);
+        Ok(id)
+    }
+
+    /// Take a buffer used by the device, returns its token and the number of
+    /// bytes written into it.
+    pub fn pop_used(&mut self) -> Option<(u16, usize)> {
+        let used_idx = read::<u16>(self.region.vaddr(self.used_offset + 2));
+        if used_idx == self.last_used_idx {
+            return None;
+        }
+        // read the entry after the index
+        fence(Ordering::SeqCst);
+        let slot = (self.last_used_idx % self.size) as usize;
+        let elem = self.region.vaddr(self.used_offset + 4 + 8 * slot);
+        let id = read::<u32>(elem) as u16;
+        let len = read::<u32>(elem + 4) as usize;
+        self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        // free the whole chain
+        let mut next = id;
+        loop {
+            self.free.push(next);
+            let desc = self.region.vaddr(16 * next as usize);
+            if read::<u16>(desc + 12) & DESC_F_NEXT == 0 {
+                break;
+            }
+            next = read(desc + 14);
+        }
+        Some((id, len))
+    }
+}
@@ -0,0 +1,79 @@
+//! virtio-rng entropy source.
+
+use lock::Mutex;
+
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::scheme::{RngScheme, Scheme};
+use crate::{DeviceError, DeviceResult};
+
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: u16 = 4;
+
+/// Size of the buffer given to the device for each request.
+const BUF_SIZE: usize = 256;
+
+/// Spins to wait for the device.
+const SPIN_TIMEOUT: usize = 0x100_0000;
+
+struct RngInner {
+    transport: Transport,
+    queue: VirtQueue,
+    buf: DmaRegion,
+}
+
+pub struct VirtIoRng {
+    inner: Mutex<RngInner>,
+}
+
+impl VirtIoRng {
+    pub fn new(transport: Transport) -> DeviceResult<Self> {
+        transport.begin_init(0)?;
+        let queue = VirtQueue::new(&transport, QUEUE_REQUEST, QUEUE_SIZE)?;
+        let buf = DmaRegion::new(BUF_SIZE)?;
+        transport.finish_init();
+        info!("virtio-rng: initialized");
+        Ok(Self {
+            inner: Mutex::new(RngInner {
+                transport,
+                queue,
+                buf,
+            }),
+        })
+    }
+}
+
+impl Scheme for VirtIoRng {
+    fn name(&self) -> &str {
+        "virtio-rng"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        // requests are completed synchronously
+        self.inner.lock().transport.ack_interrupt();
+    }
+}
+
+impl RngScheme for VirtIoRng {
+    fn fill(&self, buf: &mut [u8]) -> DeviceResult<usize> {
+        let len = buf.len().min(BUF_SIZE);
+        if len == 0 {
+            return Ok(0);
+        }
+        let inner = &mut *self.inner.lock();
+        let token = inner.queue.add(inner.buf.paddr(0), len, true)?;
+        inner.transport.notify(inner.queue.index());
+        for _ in 0..SPIN_TIMEOUT {
+            match inner.queue.pop_used() {
+                Some((t, written)) if t == token => {
+                    let written = written.min(len);
+                    buf[..written].copy_from_slice(inner.buf.as_slice(0, written));
+                    return Ok(written);
+                }
+                Some(_) => {}
+                None => core::hint::spin_loop(),
+            }
+        }
+        Err(DeviceError::IoError)
+    }
+}
@@ -0,0 +1,304 @@
+//! Transports of virtio devices driven by in-tree drivers.
+//!
+//! - virtio-mmio, both the legacy (version 1) and the modern (version 2) interface.
+//! - modern virtio-pci, whose register blocks are located by PCI capabilities.
+
+use crate::bus::{read, write, PAGE_SIZE};
+use crate::{DeviceError, DeviceResult};
+
+bitflags::bitflags! {
+    /// The device status field.
+    pub struct DeviceStatus: u8 {
+        const ACKNOWLEDGE = 1;
+        const DRIVER = 2;
+        const DRIVER_OK = 4;
+        const FEATURES_OK = 8;
+        const DEVICE_NEEDS_RESET = 0x40;
+        const FAILED = 0x80;
+    }
+}
+
+/// The device complies with the modern interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// "virt" in little endian.
+const MMIO_MAGIC: u32 = 0x7472_6976;
+
+// virtio-mmio registers
+const MMIO_MAGIC_VALUE: usize = 0x000;
+const MMIO_VERSION: usize = 0x004;
+const MMIO_DEVICE_FEATURES: usize = 0x010;
+const MMIO_DEVICE_FEATURES_SEL: usize = 0x014;
+const MMIO_DRIVER_FEATURES: usize = 0x020;
+const MMIO_DRIVER_FEATURES_SEL: usize = 0x024;
+const MMIO_GUEST_PAGE_SIZE: usize = 0x028;
+const MMIO_QUEUE_SEL: usize = 0x030;
+const MMIO_QUEUE_NUM_MAX: usize = 0x034;
+const MMIO_QUEUE_NUM: usize = 0x038;
+const MMIO_QUEUE_ALIGN: usize = 0x03c;
+const MMIO_QUEUE_PFN: usize = 0x040;
+const MMIO_QUEUE_READY: usize = 0x044;
+const MMIO_QUEUE_NOTIFY: usize = 0x050;
+const MMIO_INTERRUPT_STATUS: usize = 0x060;
+const MMIO_INTERRUPT_ACK: usize = 0x064;
+const MMIO_STATUS: usize = 0x070;
+const MMIO_QUEUE_DESC: usize = 0x080;
+const MMIO_QUEUE_DRIVER: usize = 0x090;
+const MMIO_QUEUE_DEVICE: usize = 0x0a0;
+const MMIO_CONFIG: usize = 0x100;
+
+// virtio-pci common configuration
+const PCI_DEVICE_FEATURE_SELECT: usize = 0x00;
+const PCI_DEVICE_FEATURE: usize = 0x04;
+const PCI_DRIVER_FEATURE_SELECT: usize = 0x08;
+const PCI_DRIVER_FEATURE: usize = 0x0c;
+const PCI_DEVICE_STATUS: usize = 0x14;
+const PCI_QUEUE_SELECT: usize = 0x16;
+const PCI_QUEUE_SIZE: usize = 0x18;
+const PCI_QUEUE_ENABLE: usize = 0x1c;
+const PCI_QUEUE_NOTIFY_OFF: usize = 0x1e;
+const PCI_QUEUE_DESC: usize = 0x20;
+const PCI_QUEUE_DRIVER: usize = 0x28;
+const PCI_QUEUE_DEVICE: usize = 0x30;
+
+/// Virtual addresses of the register blocks of a modern virtio-pci device.
+#[derive(Debug, Clone, Copy)]
+pub struct PciRegions {
+    /// Common configuration
+    pub common: usize,
+    /// Base of queue notification addresses
+    pub notify: usize,
+    /// Multiplier of the queue notification offsets
+    pub notify_off_multiplier: u32,
+    /// ISR status
+    pub isr: usize,
+    /// Device-specific configuration
+    pub device: usize,
+}
+
+/// The interface to access a virtio device.
+#[derive(Debug)]
+pub enum Transport {
+    /// virtio-mmio registers at `base`.
+    Mmio { base: usize, version: u32 },
+    /// Modern virtio-pci.
+    Pci(PciRegions),
+}
+
+/// Write a 64-bit register as two 32-bit halves, low half first.
+fn write64(addr: usize, value: u64) {
+    write(addr, value as u32);
+    write(addr + 4, (value >> 32) as u32);
+}
+
+impl Transport {
+    /// Create a virtio-mmio transport, checking the magic value and the version.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be the mapped virtual address of virtio-mmio registers.
+    pub unsafe fn mmio(base: usize) -> DeviceResult<Self> {
+        if read::<u32>(base + MMIO_MAGIC_VALUE) != MMIO_MAGIC {
+            return Err(DeviceError::NotSupported);
+        }
+        match read::<u32>(base + MMIO_VERSION) {
+            version @ (1 | 2) => Ok(Transport::Mmio { base, version }),
+            _ => Err(DeviceError::NotSupported),
+        }
+    }
+
+    /// Whether the device uses the legacy interface.
+    pub fn is_legacy(&self) -> bool {
+        matches!(self, Transport::Mmio { version: 1, .. })
+    }
+
+    fn set_status(&self, status: DeviceStatus) {
+        match self {
+            Transport::Mmio { base, .. } => write(base + MMIO_STATUS, status.bits() as u32),
+            Transport::Pci(regs) => write(regs.common + PCI_DEVICE_STATUS, status.bits()),
+        }
+    }
+
+    fn status(&self) -> DeviceStatus {
+        let bits = match self {
+            Transport::Mmio { base, .. } => read::<u32>(base + MMIO_STATUS) as u8,
+            Transport::Pci(regs) => read::<u8>(regs.common + PCI_DEVICE_STATUS),
+        };
+        DeviceStatus::from_bits_truncate(bits)
+    }
+
+    fn device_features(&self) -> u64 {
+        let (sel, features) = match self {
+            Transport::Mmio { base, .. } => {
+                (base + MMIO_DEVICE_FEATURES_SEL, base + MMIO_DEVICE_FEATURES)
+            }
+            Transport::Pci(regs) => (
+                regs.common + PCI_DEVICE_FEATURE_SELECT,
+                regs.common + PCI_DEVICE_FEATURE,
+            ),
+        };
+        write(sel, 0u32);
+        let low = read::<u32>(features) as u64;
+        write(sel, 1u32);
+        let high = read::<u32>(features) as u64;
+        (high << 32) | low
+    }
+
+    fn set_driver_features(&self, value: u64) {
+        let (sel, features) = match self {
+            Transport::Mmio { base, .. } => {
+                (base + MMIO_DRIVER_FEATURES_SEL, base + MMIO_DRIVER_FEATURES)
+            }
+            Transport::Pci(regs) => (
+                regs.common + PCI_DRIVER_FEATURE_SELECT,
+                regs.common + PCI_DRIVER_FEATURE,
+            ),
+        };
+        write(sel, 0u32);
+        write(features, value as u32);
+        write(sel, 1u32);
+        write(features, (value >> 32) as u32);
+    }
+
+    /// Reset the device and negotiate features, returns the features accepted
+    /// by both the driver (in `supported`) and the device.
+    ///
+    /// Virtqueues should be set up after it, then call [`finish_init`](Self::finish_init).
+    pub fn begin_init(&self, supported: u64) -> DeviceResult<u64> {
+        self.set_status(DeviceStatus::empty());
+        let mut status = DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER;
+        self.set_status(status);
+
+        let device_features = self.device_features();
+        let mut features = device_features & supported;
+        if self.is_legacy() {
+            self.set_driver_features(features);
+            if let Transport::Mmio { base, .. } = self {
+                write(base + MMIO_GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            }
+            return Ok(features);
+        }
+
+        if device_features & VIRTIO_F_VERSION_1 == 0 {
+            warn!("virtio: the device doesn't support the modern interface");
+            self.set_status(DeviceStatus::FAILED);
+            return Err(DeviceError::NotSupported);
+        }
+        features |= VIRTIO_F_VERSION_1;
+        self.set_driver_features(features);
+        status |= DeviceStatus::FEATURES_OK;
+        self.set_status(status);
+        if !self.status().contains(DeviceStatus::FEATURES_OK) {
+            warn!("virtio: features {:#x} are not accepted", features);
+            self.set_status(DeviceStatus::FAILED);
+            return Err(DeviceError::NotSupported);
+        }
+        Ok(features)
+    }
+
+    /// Tell the device that the driver is ready.
+    pub fn finish_init(&self) {
+        let status = self.status() | DeviceStatus::DRIVER_OK;
+        self.set_status(status);
+    }
+
+    /// Get the maximum size of the virtqueue `index`, 0 if it's unavailable.
+    pub fn max_queue_size(&self, index: u16) -> u16 {
+        match self {
+            Transport::Mmio { base, .. } => {
+                write(base + MMIO_QUEUE_SEL, index as u32);
+                read::<u32>(base + MMIO_QUEUE_NUM_MAX) as u16
+            }
+            Transport::Pci(regs) => {
+                write(regs.common + PCI_QUEUE_SELECT, index);
+                read(regs.common + PCI_QUEUE_SIZE)
+            }
+        }
+    }
+
+    /// Set up the virtqueue `index`, given physical addresses of its parts.
+    ///
+    /// Legacy devices only take the address of the descriptor table, so the
+    /// available ring must follow it, and the used ring must be on the next
+    /// page boundary.
+    pub fn setup_queue(&self, index: u16, size: u16, desc: usize, avail: usize, used: usize) {
+        match self {
+            Transport::Mmio { base, version: 1 } => {
+                write(base + MMIO_QUEUE_SEL, index as u32);
+                write(base + MMIO_QUEUE_NUM, size as u32);
+                write(base + MMIO_QUEUE_ALIGN, PAGE_SIZE as u32);
+                write(base + MMIO_QUEUE_PFN, (desc / PAGE_SIZE) as u32);
+            }
+            Transport::Mmio { base, .. } => {
+                write(base + MMIO_QUEUE_SEL, index as u32);
+                write(base + MMIO_QUEUE_NUM, size as u32);
+                write64(base + MMIO_QUEUE_DESC, desc as u64);
+                write64(base + MMIO_QUEUE_DRIVER, avail as u64);
+                write64(base + MMIO_QUEUE_DEVICE, used as u64);
+                write(base + MMIO_QUEUE_READY, 1u32);
+            }
+            Transport::Pci(regs) => {
+                write(regs.common + PCI_QUEUE_SELECT, index);
+                write(regs.common + PCI_QUEUE_SIZE, size);
+                write64(regs.common + PCI_QUEUE_DESC, desc as u64);
+                write64(regs.common + PCI_QUEUE_DRIVER, avail as u64);
+                write64(regs.common + PCI_QUEUE_DEVICE, used as u64);
+                write(regs.common + PCI_QUEUE_ENABLE, 1u16);
+            }
+        }
+    }
+
+    /// Notify the device of new buffers in the virtqueue `index`.
+    pub fn notify(&self, index: u16) {
+        match self {
+            Transport::Mmio { base, .. } => write(base + MMIO_QUEUE_NOTIFY, index as u32),
+            Transport::Pci(regs) => {
+                write(regs.common + PCI_QUEUE_SELECT, index);
+                let off = read::<u16>(regs.common + PCI_QUEUE_NOTIFY_OFF) as usize;
+                write(
+                    regs.notify + off * regs.notify_off_multiplier as usize,
+                    index,
+                );
+            }
+        }
+    }
+
+    /// Acknowledge the interrupt, returns whether it's raised by the device.
+    pub fn ack_interrupt(&self) -> bool {
+        match self {
+            Transport::Mmio { base, .. } => {
+                let status = read::<u32>(base + MMIO_INTERRUPT_STATUS);
+                if status != 0 {
+                    write(base + MMIO_INTERRUPT_ACK, status);
+                }
+                status != 0
+            }
+            // reading the ISR status clears it
+            Transport::Pci(regs) => read::<u8>(regs.isr) != 0,
+        }
+    }
+
+    /// Read a byte at `offset` of the device-specific configuration.
+    pub fn config_read8(&self, offset: usize) -> u8 {
+        match self {
+            Transport::Mmio { base, .. } => read(base + MMIO_CONFIG + offset),
+            Transport::Pci(regs) => read(regs.device + offset),
+        }
+    }
+
+    /// Read a 32-bit field at `offset` of the device-specific configuration.
+    pub fn config_read32(&self, offset: usize) -> u32 {
+        match self {
+            Transport::Mmio { base, .. } => read(base + MMIO_CONFIG + offset),
+            Transport::Pci(regs) => read(regs.device + offset),
+        }
+    }
+
+    /// Write a 32-bit field at `offset` of the device-specific configuration.
+    pub fn config_write32(&self, offset: usize, value: u32) {
+        match self {
+            Transport::Mmio { base, .. } => write(base + MMIO_CONFIG + offset, value),
+            Transport::Pci(regs) => write(regs.device + offset, value),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! virtio-vsock device, which carries packets of stream sockets between the
+//! guest and the host.
+
+use alloc::collections::VecDeque;
+use alloc::{vec, vec::Vec};
+
+use lock::Mutex;
+
+use super::queue::{DmaRegion, VirtQueue};
+use super::transport::Transport;
+use crate::scheme::{Scheme, VsockHeader, VsockScheme};
+use crate::{DeviceError, DeviceResult};
+
+const QUEUE_RECEIVE: u16 = 0;
+const QUEUE_TRANSMIT: u16 = 1;
+const QUEUE_EVENT: u16 = 2;
+const QUEUE_SIZE: u16 = 64;
+const EVENT_QUEUE_SIZE: u16 = 8;
+
+/// Size of `struct virtio_vsock_hdr`.
+const HDR_SIZE: usize = 44;
+/// Size of each buffer, for the header and the payload.
+const BUF_SIZE: usize = 4096;
+const MAX_PAYLOAD: usize = BUF_SIZE - HDR_SIZE;
+/// Size of each buffer of the event queue, for `struct virtio_vsock_event`.
+const EVENT_BUF_SIZE: usize = 8;
+/// Maximum number of packets received and not yet taken, the device waits
+/// for more buffers beyond it.
+const RX_QUEUE_LEN: usize = 256;
+
+// device configuration
+const CONFIG_GUEST_CID: usize = 0;
+
+/// Communication with the device is interrupted, e.g. the guest is migrated,
+/// and all connections are reset.
+const EVENT_TRANSPORT_RESET: u32 = 0;
+
+/// Spins to wait for the device.
+const SPIN_TIMEOUT: usize = 0x100_0000;
+
+fn read_header(buf: &[u8]) -> VsockHeader {
+    let u16_at = |i: usize| u16::from_le_bytes([buf[i], buf[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+    let u64_at = |i: usize| u32_at(i) as u64 | (u32_at(i + 4) as u64) << 32;
+    VsockHeader {
+        src_cid: u64_at(0),
+        dst_cid: u64_at(8),
+        src_port: u32_at(16),
+        dst_port: u32_at(20),
+        len: u32_at(24),
+        socket_type: u16_at(28),
+        op: u16_at(30),
+        flags: u32_at(32),
+        buf_alloc: u32_at(36),
+        fwd_cnt: u32_at(40),
+    }
+}
+
+fn write_header(buf: &mut [u8], hdr: &VsockHeader) {
+    buf[0..8].copy_from_slice(&hdr.src_cid.to_le_bytes());
+    buf[8..16].copy_from_slice(&hdr.dst_cid.to_le_bytes());
+    buf[16..20].copy_from_slice(&hdr.src_port.to_le_bytes());
+    buf[20..24].copy_from_slice(&hdr.dst_port.to_le_bytes());
+    buf[24..28].copy_from_slice(&hdr.len.to_le_bytes());
+    buf[28..30].copy_from_slice(&hdr.socket_type.to_le_bytes());
+    buf[30..32].copy_from_slice(&hdr.op.to_le_bytes());
+    buf[32..36].copy_from_slice(&hdr.flags.to_le_bytes());
+    buf[36..40].copy_from_slice(&hdr.buf_alloc.to_le_bytes());
+    buf[40..44].copy_from_slice(&hdr.fwd_cnt.to_le_bytes());
+}
+
+/// The virtqueues and their buffers.
+///
+/// Every receive and event buffer is kept available to the device, and the
+/// used ones are collected on interrupts and polls.
+struct VsockInner {
+    transport: Transport,
+    rx: VirtQueue,
+    tx: VirtQueue,
+    event: VirtQueue,
+    rx_bufs: DmaRegion,
+    tx_bufs: DmaRegion,
+    event_bufs: DmaRegion,
+    /// token -> buffer slot
+    rx_slots: Vec<usize>,
+    tx_slots: Vec<usize>,
+    event_slots: Vec<usize>,
+    /// Transmit buffer slots not in use.
+    tx_free: Vec<usize>,
+    received: VecDeque<(VsockHeader, Vec<u8>)>,
+    guest_cid: u64,
+}
+
+impl VsockInner {
+    fn read_guest_cid(transport: &Transport) -> u64 {
+        transport.config_read32(CONFIG_GUEST_CID) as u64
+            | (transport.config_read32(CONFIG_GUEST_CID + 4) as u64) << 32
+    }
+
+    fn post_rx(&mut self, slot: usize) -> DeviceResult {
+        let token = self
+            .rx
+            .add(self.rx_bufs.paddr(slot * BUF_SIZE), BUF_SIZE, true)?;
+        self.rx_slots[token as usize] = slot;
+        Ok(())
+    }
+
+    fn post_event(&mut self, slot: usize) -> DeviceResult {
+        let paddr = self.event_bufs.paddr(slot * EVENT_BUF_SIZE);
+        let token = self.event.add(paddr, EVENT_BUF_SIZE, true)?;
+        self.event_slots[token as usize] = slot;
+        Ok(())
+    }
+
+    /// Collect the buffers used by the device.
+    fn collect(&mut self) {
+        let mut reposted = false;
+        while self.received.len() < RX_QUEUE_LEN {
+            let (token, len) = match self.rx.pop_used() {
+                Some(used) => used,
+                None => break,
+            };
+            let slot = self.rx_slots[token as usize];
+            if len >= HDR_SIZE {
+                let buf = self.rx_bufs.as_slice(slot * BUF_SIZE, len.min(BUF_SIZE));
+                let hdr = read_header(buf);
+                let end = (HDR_SIZE + hdr.len as usize).min(buf.len());
+                self.received.push_back((hdr, buf[HDR_SIZE..end].to_vec()));
+            }
+            match self.post_rx(slot) {
+                Ok(()) => reposted = true,
+                Err(e) => warn!("virtio-vsock: failed to post receive buffer: {:?}", e),
+            }
+        }
+        if reposted {
+            self.transport.notify(self.rx.index());
+        }
+        while let Some((token, _)) = self.tx.pop_used() {
+            self.tx_free.push(self.tx_slots[token as usize]);
+        }
+        let mut reposted = false;
+        while let Some((token, len)) = self.event.pop_used() {
+            let slot = self.event_slots[token as usize];
+            let buf = self.event_bufs.as_slice(slot * EVENT_BUF_SIZE, 4);
+            let id = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            if len >= 4 && id == EVENT_TRANSPORT_RESET {
+                // the peers reset their connections as well
+                self.guest_cid = Self::read_guest_cid(&self.transport);
+                warn!(
+                    "virtio-vsock: transport reset, guest CID {}",
+                    self.guest_cid
+                );
+            }
+            if self.post_event(slot).is_ok() {
+                reposted = true;
+            }
+        }
+        if reposted {
+            self.transport.notify(self.event.index());
+        }
+    }
+
+    /// Send a packet of at most [`MAX_PAYLOAD`] bytes, waits if all buffers
+    /// are in use.
+    fn send_packet(&mut self, hdr: &VsockHeader, payload: &[u8]) -> DeviceResult {
+        let mut spins = 0;
+        let slot = loop {
+            self.collect();
+            if let Some(slot) = self.tx_free.pop() {
+                break slot;
+            }
+            spins += 1;
+            if spins > SPIN_TIMEOUT {
+                return Err(DeviceError::NotReady);
+            }
+            core::hint::spin_loop();
+        };
+        let len = HDR_SIZE + payload.len();
+        let buf = self.tx_bufs.as_mut_slice(slot * BUF_SIZE, len);
+        write_header(&mut buf[..HDR_SIZE], hdr);
+        buf[HDR_SIZE..].copy_from_slice(payload);
+        match self.tx.add(self.tx_bufs.paddr(slot * BUF_SIZE), len, false) {
+            Ok(token) => self.tx_slots[token as usize] = slot,
+            Err(e) => {
+                self.tx_free.push(slot);
+                return Err(e);
+            }
+        }
+        self.transport.notify(self.tx.index());
+        Ok(())
+    }
+}
+
+pub struct VirtIoVsock {
+    inner: Mutex<VsockInner>,
+}
+
+impl VirtIoVsock {
+    pub fn new(transport: Transport) -> DeviceResult<Self> {
+        transport.begin_init(0)?;
+        let guest_cid = VsockInner::read_guest_cid(&transport);
+        let rx = VirtQueue::new(&transport, QUEUE_RECEIVE, QUEUE_SIZE)?;
+        let tx = VirtQueue::new(&transport, QUEUE_TRANSMIT, QUEUE_SIZE)?;
+        let event = VirtQueue::new(&transport, QUEUE_EVENT, EVENT_QUEUE_SIZE)?;
+        let rx_bufs = DmaRegion::new(rx.size() * BUF_SIZE)?;
+        let tx_bufs = DmaRegion::new(tx.size() * BUF_SIZE)?;
+        let event_bufs = DmaRegion::new(event.size() * EVENT_BUF_SIZE)?;
+        transport.finish_init();
+
+        let mut inner = VsockInner {
+            rx_slots: vec![0; rx.size()],
+            tx_slots: vec![0; tx.size()],
+            event_slots: vec![0; event.size()],
+            tx_free: (0..tx.size()).collect(),
+            received: VecDeque::new(),
+            transport,
+            rx,
+            tx,
+            event,
+            rx_bufs,
+            tx_bufs,
+            event_bufs,
+            guest_cid,
+        };
+        for slot in 0..inner.rx.size() {
+            inner.post_rx(slot)?;
+        }
+        for slot in 0..inner.event.size() {
+            inner.post_event(slot)?;
+        }
+        inner.transport.notify(QUEUE_RECEIVE);
+        inner.transport.notify(QUEUE_EVENT);
+        info!("virtio-vsock: initialized with guest CID {}", guest_cid);
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
+    }
+}
+
+impl Scheme for VirtIoVsock {
+    fn name(&self) -> &str {
+        "virtio-vsock"
+    }
+
+    fn handle_irq(&self, _irq_num: usize) {
+        let mut inner = self.inner.lock();
+        if inner.transport.ack_interrupt() {
+            inner.collect();
+        }
+    }
+}
+
+impl VsockScheme for VirtIoVsock {
+    fn guest_cid(&self) -> u64 {
+        self.inner.lock().guest_cid
+    }
+
+    /// Payloads larger than a buffer are sent in more packets.
+    fn send(&self, hdr: &VsockHeader, payload: &[u8]) -> DeviceResult {
+        let mut inner = self.inner.lock();
+        let mut hdr = *hdr;
+        hdr.src_cid = inner.guest_cid;
+        if payload.is_empty() {
+            hdr.len = 0;
+            return inner.send_packet(&hdr, payload);
+        }
+        for chunk in payload.chunks(MAX_PAYLOAD) {
+            hdr.len = chunk.len() as u32;
+            inner.send_packet(&hdr, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn recv(&self) -> DeviceResult<Option<(VsockHeader, Vec<u8>)>> {
+        let mut inner = self.inner.lock();
+        inner.collect();
+        Ok(inner.received.pop_front())
+    }
+}
@@ -13,8 +13,12 @@ pub struct KernelConfig {
     pub uart_base: usize,
     /// GIC base address
     pub gic_base: usize,
+    /// Device tree blob physical address
+    pub dtb_paddr: usize,
     /// phystovirt offset
     pub phys_to_virt_offset: usize,
+    /// Entry of secondary CPUs
+    pub ap_fn: fn() -> !,
 }
 
 pub const PHYS_MEMORY_BASE: usize = 0x4000_0000;
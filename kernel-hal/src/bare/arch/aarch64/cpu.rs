@@ -6,7 +6,8 @@ use tock_registers::interfaces::Readable;
 hal_fn_impl! {
     impl mod crate::hal_fn::cpu {
         fn cpu_id() -> u8 {
-            let id = MPIDR_EL1.get() & 0x3;
+            // Aff0, CPUs are in one cluster on QEMU `virt`
+            let id = MPIDR_EL1.get() & 0xff;
             id as u8
         }
 
@@ -16,14 +17,12 @@ hal_fn_impl! {
 
         fn reset() -> ! {
             info!("shutdown...");
-            let psci_system_off = 0x8400_0008_usize;
-            unsafe {
-                core::arch::asm!(
-                    "hvc #0",
-                    in("x0") psci_system_off
-                );
-            }
-            unreachable!()
+            super::psci::system_off()
+        }
+
+        fn reboot() -> ! {
+            info!("reboot...");
+            super::psci::system_reset()
         }
     }
 }
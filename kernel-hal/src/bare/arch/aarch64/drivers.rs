@@ -1,38 +1,135 @@
-use crate::arch::timer::set_next_trigger;
-use crate::drivers;
-use crate::hal_fn::mem::phys_to_virt;
-use crate::imp::config::VIRTIO_BASE;
-use crate::KCONFIG;
 use alloc::boxed::Box;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use zcore_drivers::builder::{DevicetreeDriverBuilder, IoMapper};
 use zcore_drivers::irq::gic_400;
 use zcore_drivers::scheme::IrqScheme;
 use zcore_drivers::uart::{BufferedUart, Pl011Uart};
-use zcore_drivers::virtio::{VirtIOHeader, VirtIoBlk};
-use zcore_drivers::Device;
+use zcore_drivers::virtio::{Transport, VirtIoBlk};
+use zcore_drivers::{Device, DeviceResult};
+
+use super::config::{UART_SIZE, VIRTIO_BASE, VIRTIO_SIZE};
+use super::trap::{super_soft, super_timer, IPI_IRQ};
+use crate::common::vm::GenericPageTable;
+use crate::{drivers, mem::phys_to_virt, MMUFlags, PhysAddr, VirtAddr, KCONFIG};
+
+struct IoMapperImpl;
 
-pub fn init_early() {
-    let uart = Pl011Uart::new(phys_to_virt(KCONFIG.uart_base));
-    let uart = Arc::new(uart);
+impl IoMapper for IoMapperImpl {
+    fn query_or_map(&self, paddr: PhysAddr, size: usize) -> Option<VirtAddr> {
+        let vaddr = phys_to_virt(paddr);
+        let mut pt = super::vm::kernel_page_table().lock();
+        if let Ok((paddr_mapped, _, _)) = pt.query(vaddr) {
+            if paddr_mapped == paddr {
+                Some(vaddr)
+            } else {
+                warn!(
+                    "IoMapper::query_or_map: not linear mapping: vaddr={:#x}, paddr={:#x}",
+                    vaddr, paddr_mapped
+                );
+                None
+            }
+        } else {
+            // several devices may share a page, e.g. virtio-mmio slots
+            let start = crate::addr::align_down(vaddr);
+            let size = crate::addr::align_up(vaddr + size) - start;
+            let flags = MMUFlags::READ | MMUFlags::WRITE | MMUFlags::DEVICE;
+            if let Err(err) = pt.map_cont(start, size, crate::addr::align_down(paddr), flags) {
+                warn!(
+                    "IoMapper::query_or_map: failed to map {:#x?} => {:#x}, flags={:?}: {:?}",
+                    start..start + size,
+                    paddr,
+                    flags,
+                    err
+                );
+                None
+            } else {
+                Some(vaddr)
+            }
+        }
+    }
+}
+
+/// The fixed devices of QEMU `virt`, used if no valid DTB is found.
+fn fixed_devices() -> DeviceResult<Vec<Device>> {
+    let map = |paddr, size| {
+        IoMapperImpl
+            .query_or_map(paddr, size)
+            .ok_or(zcore_drivers::DeviceError::NoResources)
+    };
     let gic = gic_400::init(
-        phys_to_virt(KCONFIG.gic_base + 0x1_0000),
-        phys_to_virt(KCONFIG.gic_base),
+        map(KCONFIG.gic_base + 0x1_0000, gic_400::GICC_SIZE)?,
+        map(KCONFIG.gic_base, gic_400::GICD_SIZE)?,
     );
-    gic.irq_enable(30);
-    gic.irq_enable(33);
-    gic.register_handler(33, Box::new(handle_uart_irq)).ok();
-    gic.register_handler(30, Box::new(set_next_trigger)).ok();
-    drivers::add_device(Device::Irq(Arc::new(gic)));
-    drivers::add_device(Device::Uart(BufferedUart::new(uart)));
+    let uart = Arc::new(Pl011Uart::new(map(KCONFIG.uart_base, UART_SIZE)?));
+    // SPI 1
+    gic.register_device(33, uart.clone())?;
+    gic.unmask(33)?;
+    let header = map(VIRTIO_BASE, VIRTIO_SIZE)?;
+    let virtio_blk = VirtIoBlk::new(unsafe { Transport::mmio(header)? })?;
+    Ok(alloc::vec![
+        Device::Irq(Arc::new(gic)),
+        Device::Uart(uart),
+        Device::Block(Arc::new(virtio_blk)),
+    ])
 }
 
-pub fn init() {
-    let virtio_blk = Arc::new(
-        VirtIoBlk::new(unsafe { &mut *(phys_to_virt(VIRTIO_BASE) as *mut VirtIOHeader) }).unwrap(),
-    );
-    drivers::add_device(Device::Block(virtio_blk));
+/// Initialize device drivers.
+pub(super) fn init() -> DeviceResult {
+    crate::net::init_config();
+    // prase DTB and probe devices
+    let dtb = phys_to_virt(KCONFIG.dtb_paddr);
+    let dev_list = match DevicetreeDriverBuilder::new(dtb, IoMapperImpl) {
+        Ok(builder) => builder.build()?,
+        Err(_) => {
+            warn!("no valid DTB, use the fixed devices of QEMU virt");
+            fixed_devices()?
+        }
+    };
+    // add drivers
+    for dev in dev_list.into_iter() {
+        if let Device::Uart(uart) = dev {
+            drivers::add_device(Device::Uart(BufferedUart::new(uart)));
+        } else {
+            drivers::add_device(dev);
+        }
+    }
+
+    intc_init()?;
+    crate::net::init_dhcp();
+    crate::net::init_slaac();
+
+    #[cfg(feature = "graphic")]
+    if let Some(display) = drivers::all_display().first() {
+        crate::console::init_graphic_console(display.clone());
+        if display.need_flush() {
+            crate::thread::spawn(crate::common::future::DisplayFlushFuture::new(display, 30));
+        }
+    }
+
+    #[cfg(feature = "loopback")]
+    {
+        use crate::net;
+        net::init();
+    }
+
+    info!("Drivers init end.");
+    Ok(())
+}
+
+pub(super) fn intc_init() -> DeviceResult {
+    let irq = drivers::all_irq().first_unwrap();
+    // register timer and IPI handlers, shared by all CPUs
+    irq.register_handler(super::timer_interrupt_vector(), Box::new(super_timer))?;
+    irq.register_handler(IPI_IRQ, Box::new(super_soft))?;
+    intc_init_hart()
 }
 
-fn handle_uart_irq() {
-    crate::drivers::all_uart().first_unwrap().handle_irq(0);
+/// Enable the per-CPU interrupts of the current CPU.
+pub(super) fn intc_init_hart() -> DeviceResult {
+    let irq = drivers::all_irq().first_unwrap();
+    irq.unmask(super::timer_interrupt_vector())?;
+    irq.unmask(IPI_IRQ)?;
+    Ok(())
 }
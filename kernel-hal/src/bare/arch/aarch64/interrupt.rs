@@ -1,6 +1,9 @@
 //! Interrupts management.
-use crate::HalResult;
+use crate::drivers::all_irq;
+use crate::drivers::prelude::IrqHandler;
+use crate::{HalError, HalResult};
 use alloc::vec::Vec;
+use core::ops::Range;
 use cortex_a::asm::wfi;
 
 hal_fn_impl! {
@@ -12,11 +15,27 @@ hal_fn_impl! {
         }
 
         fn handle_irq(vector: usize) {
-            // TODO: timer and other devices with GIC interrupt controller
-            crate::drivers::all_irq().first_unwrap().handle_irq(vector);
-            if vector == 30 {
-                debug!("Timer");
-            }
+            all_irq().first_unwrap().handle_irq(vector);
+        }
+
+        fn msi_alloc_block(requested_irqs: usize) -> HalResult<Range<usize>> {
+            Ok(all_irq().first_unwrap().msi_alloc_block(requested_irqs)?)
+        }
+
+        fn msi_free_block(block: Range<usize>) -> HalResult {
+            Ok(all_irq().first_unwrap().msi_free_block(block)?)
+        }
+
+        fn msi_register_handler(
+            block: Range<usize>,
+            msi_id: usize,
+            handler: IrqHandler,
+        ) -> HalResult {
+            Ok(all_irq().first_unwrap().msi_register_handler(block, msi_id, handler)?)
+        }
+
+        fn msi_target(block: Range<usize>, requester_id: usize) -> HalResult<(u64, u32)> {
+            Ok(all_irq().first_unwrap().msi_target(block, requester_id)?)
         }
 
         fn intr_off() {
@@ -39,11 +58,16 @@ hal_fn_impl! {
 
         fn send_ipi(cpuid: usize, reason: usize) -> HalResult {
             trace!("ipi [{}] => [{}]: {:x}", super::cpu::cpu_id(), cpuid, reason);
-            panic!("send_ipi unsupported for aarch64");
+            let queue = crate::common::ipi::ipi_queue(cpuid);
+            let idx = queue.alloc_entry().ok_or(HalError)?;
+            *queue.entry_at(idx) = reason;
+            queue.commit_entry(idx);
+            all_irq().first_unwrap().send_sgi(cpuid, super::trap::IPI_IRQ)?;
+            Ok(())
         }
 
         fn ipi_reason() -> Vec<usize> {
-            panic!("ipi_reason unsupported for aarch64");
+            crate::common::ipi::ipi_reason()
         }
     }
 }
@@ -3,6 +3,8 @@ pub mod cpu;
 pub mod drivers;
 pub mod interrupt;
 pub mod mem;
+mod psci;
+mod smp;
 pub mod timer;
 pub mod trap;
 pub mod vm;
@@ -11,8 +13,26 @@ use crate::KCONFIG;
 use crate::{mem::phys_to_virt, utils::init_once::InitOnce, PhysAddr};
 use alloc::string::{String, ToString};
 use core::ops::Range;
+use zcore_drivers::utils::devicetree::Devicetree;
 
-hal_fn_impl_default!(crate::hal_fn::console);
+hal_fn_impl! {
+    impl mod crate::hal_fn::console {
+        fn console_write_early(s: &str) {
+            // the PL011 UART given by the bootloader, before drivers are probed
+            let base = match KCONFIG.try_get() {
+                Some(config) => phys_to_virt(config.uart_base),
+                None => return,
+            };
+            for c in s.bytes() {
+                unsafe {
+                    // wait while UARTFR.TXFF is set
+                    while core::ptr::read_volatile((base + 0x18) as *const u32) & (1 << 5) != 0 {}
+                    core::ptr::write_volatile(base as *mut u32, c as u32);
+                }
+            }
+        }
+    }
+}
 
 static INITRD_REGION: InitOnce<Option<Range<PhysAddr>>> = InitOnce::new_with_default(None);
 static CMDLINE: InitOnce<String> = InitOnce::new_with_default(String::new());
@@ -29,18 +49,25 @@ pub fn init_ram_disk() -> Option<&'static mut [u8]> {
 
 pub fn primary_init_early() {
     CMDLINE.init_once_by(KCONFIG.cmdline.to_string());
-    drivers::init_early();
 }
 
 pub fn primary_init() {
     vm::init();
-    drivers::init();
+    drivers::init().unwrap();
+    // pick the PSCI conduit and start other CPUs listed in the DTB
+    if let Ok(dt) = Devicetree::from(phys_to_virt(KCONFIG.dtb_paddr)) {
+        psci::init(dt.psci_method());
+        smp::start_secondary_cpus(&dt.cpu_ids());
+    }
 }
 
 pub fn secondary_init() {
-    unimplemented!()
+    vm::init();
+    crate::drivers::all_irq().first_unwrap().init_hart();
+    drivers::intc_init_hart().unwrap();
 }
 
+/// The non-secure EL1 physical timer, PPI 14.
 pub const fn timer_interrupt_vector() -> usize {
     30
 }
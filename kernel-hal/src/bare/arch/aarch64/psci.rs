@@ -0,0 +1,64 @@
+//! Power State Coordination Interface, called with `hvc` or `smc` as given
+//! by the `method` property of the `/psci` node in the DTB.
+//!
+//! Specification: <https://developer.arm.com/documentation/den0022/latest>.
+
+use crate::PhysAddr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const PSCI_SYSTEM_OFF: usize = 0x8400_0008;
+const PSCI_SYSTEM_RESET: usize = 0x8400_0009;
+const PSCI_CPU_ON: usize = 0xc400_0003;
+
+/// Whether the firmware is called with `smc` rather than `hvc`. Defaults to
+/// `hvc` as on QEMU `virt` until [`init`] reads the DTB.
+static USE_SMC: AtomicBool = AtomicBool::new(false);
+
+/// Select the conduit from the `method` property of the `/psci` node.
+pub(super) fn init(method: Option<&str>) {
+    match method {
+        Some("smc") => USE_SMC.store(true, Ordering::Relaxed),
+        Some("hvc") | None => USE_SMC.store(false, Ordering::Relaxed),
+        Some(other) => warn!("PSCI: unknown conduit {:?}, using hvc", other),
+    }
+}
+
+fn psci_call(func: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
+    let ret;
+    unsafe {
+        if USE_SMC.load(Ordering::Relaxed) {
+            core::arch::asm!(
+                "smc #0",
+                inlateout("x0") func => ret,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+            );
+        } else {
+            core::arch::asm!(
+                "hvc #0",
+                inlateout("x0") func => ret,
+                in("x1") arg0,
+                in("x2") arg1,
+                in("x3") arg2,
+            );
+        }
+    }
+    ret
+}
+
+/// Power up the CPU `target` (its affinity in `MPIDR_EL1`), which starts at
+/// `entry` with the MMU off and `context` in `x0`.
+pub(super) fn cpu_on(target: usize, entry: PhysAddr, context: usize) -> isize {
+    psci_call(PSCI_CPU_ON, target, entry, context)
+}
+
+pub(super) fn system_off() -> ! {
+    psci_call(PSCI_SYSTEM_OFF, 0, 0, 0);
+    unreachable!()
+}
+
+pub(super) fn system_reset() -> ! {
+    psci_call(PSCI_SYSTEM_RESET, 0, 0, 0);
+    unreachable!()
+}
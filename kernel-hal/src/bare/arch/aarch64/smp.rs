@@ -0,0 +1,110 @@
+//! Start secondary CPUs with PSCI `CPU_ON`.
+
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
+use cortex_a::{asm::barrier, registers::*};
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::mem::{phys_to_virt, virt_to_phys};
+use crate::{PhysAddr, KCONFIG, PAGE_SIZE};
+
+const STACK_SIZE: usize = 0x8000;
+const CACHE_LINE_SIZE: usize = 64;
+
+/// System registers of the primary CPU, loaded by `__secondary_entry` in
+/// this order to turn on the MMU.
+#[repr(C)]
+struct SecondaryBoot {
+    mair: u64,
+    tcr_boot: u64,
+    tcr: u64,
+    ttbr: u64,
+    sctlr: u64,
+    stack_top: u64,
+    entry: u64,
+}
+
+// The kernel page table maps the kernel image at `phys_to_virt_offset`, whose
+// low 48 bits are zero, so it also maps it at the physical address when used
+// in `TTBR0_EL1`.
+core::arch::global_asm!(
+    "
+    .section .text
+    .global __secondary_entry
+__secondary_entry:
+    // x0: physical address of `SecondaryBoot`, the MMU is off
+    mov     x1, #(3 << 20)
+    msr     cpacr_el1, x1
+    ldr     x1, [x0, #0]
+    msr     mair_el1, x1
+    ldr     x1, [x0, #8]
+    msr     tcr_el1, x1
+    ldr     x1, [x0, #24]
+    msr     ttbr0_el1, x1
+    msr     ttbr1_el1, x1
+    isb
+    tlbi    vmalle1
+    dsb     nsh
+    ldr     x1, [x0, #32]
+    msr     sctlr_el1, x1
+    isb
+    ldr     x1, [x0, #40]
+    mov     sp, x1
+    ldr     x1, [x0, #48]
+    br      x1
+"
+);
+
+extern "C" {
+    fn __secondary_entry();
+}
+
+extern "C" fn secondary_rust_main(boot: PhysAddr) -> ! {
+    let boot = unsafe { &*(phys_to_virt(boot) as *const SecondaryBoot) };
+    TCR_EL1.set(boot.tcr);
+    unsafe { barrier::isb(barrier::SY) };
+    (KCONFIG.ap_fn)()
+}
+
+/// Write `SecondaryBoot` back to memory, where it is read with the MMU off.
+fn clean_dcache(start: usize, size: usize) {
+    for line in (start & !(CACHE_LINE_SIZE - 1)..start + size).step_by(CACHE_LINE_SIZE) {
+        unsafe { core::arch::asm!("dc civac, {}", in(reg) line) };
+    }
+    unsafe { barrier::dsb(barrier::SY) };
+}
+
+/// Start CPUs of the affinities `cpu_ids` other than the current one.
+pub(super) fn start_secondary_cpus(cpu_ids: &[u64]) {
+    let current = MPIDR_EL1.get() & 0xff_00ff_ffff;
+    let tcr = TCR_EL1.get();
+    // enable TTBR0 walks with the size, granule and cacheability of TTBR1
+    let tcr_boot = tcr & !0xffff | (tcr >> 16 & 0x3f) | (tcr >> 16 & 0x3f00);
+    for &cpu in cpu_ids.iter().filter(|&&cpu| cpu != current) {
+        let layout = Layout::from_size_align(STACK_SIZE, PAGE_SIZE).unwrap();
+        let stack = unsafe { alloc(layout) } as usize;
+        if stack == 0 {
+            warn!("failed to allocate the stack of CPU {:#x}", cpu);
+            break;
+        }
+        let boot = Box::leak(Box::new(SecondaryBoot {
+            mair: MAIR_EL1.get(),
+            tcr_boot,
+            tcr,
+            ttbr: TTBR1_EL1.get(),
+            sctlr: SCTLR_EL1.get(),
+            stack_top: (stack + STACK_SIZE) as u64,
+            entry: secondary_rust_main as usize as u64,
+        }));
+        let boot_vaddr = boot as *const SecondaryBoot as usize;
+        clean_dcache(boot_vaddr, core::mem::size_of::<SecondaryBoot>());
+        let ret = super::psci::cpu_on(
+            cpu as usize,
+            virt_to_phys(__secondary_entry as usize),
+            virt_to_phys(boot_vaddr),
+        );
+        if ret != 0 {
+            warn!("failed to start CPU {:#x}: PSCI error {}", cpu, ret);
+        }
+    }
+}
@@ -1,9 +1,31 @@
 use crate::context::TrapReason;
-use crate::{Info, Kind, Source, KCONFIG};
+use crate::thread::{get_current_thread, set_current_thread};
+use crate::{Info, IpiReason, Kind, Source};
+use alloc::vec::Vec;
 use cortex_a::registers::FAR_EL1;
 use tock_registers::interfaces::Readable;
 use trapframe::TrapFrame;
-use zcore_drivers::irq::gic_400::get_irq_num;
+
+/// The software generated interrupt used for IPIs.
+pub(super) const IPI_IRQ: usize = 1;
+
+pub(super) fn super_timer() {
+    super::timer::set_next_trigger();
+    crate::timer::timer_tick();
+}
+
+pub(super) fn super_soft() {
+    let reasons: Vec<IpiReason> = crate::interrupt::ipi_reason()
+        .iter()
+        .map(|x| IpiReason::from(*x))
+        .collect();
+    debug!("Interrupt::SGI, reason = {:?}", reasons);
+    for reason in reasons {
+        if let IpiReason::TlbShutdown { vpn } = reason {
+            crate::vm::flush_tlb(Some(vpn << 12));
+        }
+    }
+}
 
 #[no_mangle]
 pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
@@ -17,11 +39,15 @@ pub extern "C" fn trap_handler(tf: &mut TrapFrame) {
             sync_handler(tf);
         }
         Kind::Irq => {
-            use crate::hal_fn::mem::phys_to_virt;
-            crate::interrupt::handle_irq(get_irq_num(
-                phys_to_virt(KCONFIG.gic_base + 0x1_0000),
-                phys_to_virt(KCONFIG.gic_base),
-            ));
+            let vector = crate::drivers::all_irq().first_unwrap().pending_irq();
+            crate::interrupt::handle_irq(vector);
+            // GIC-400 reports the source CPU of SGIs in bits [12:10]
+            if vector & 0x3ff == super::timer_interrupt_vector() {
+                let current_thread = get_current_thread();
+                set_current_thread(None);
+                executor::handle_timeout();
+                set_current_thread(current_thread);
+            }
         }
         _ => {
             panic!(
@@ -1,13 +1,14 @@
 use crate::hal_fn::mem::phys_to_virt;
 use crate::imp::config::*;
 use crate::utils::page_table::{GenericPTE, PageTableImpl, PageTableLevel4};
-use crate::MMUFlags;
+use crate::{MMUFlags, PAGE_SIZE};
 use crate::{PhysAddr, VirtAddr, KCONFIG};
 use core::fmt::{Debug, Formatter, Result};
 use cortex_a::registers::*;
 use lock::Mutex;
 use tock_registers::interfaces::{Readable, Writeable};
-use zcore_drivers::irq::gic_400::{GICC_SIZE, GICD_SIZE};
+
+const DTB_MAGIC: u32 = 0xd00d_feed;
 
 lazy_static! {
     static ref KERNEL_PT: Mutex<PageTable> = Mutex::new(init_kernel_page_table().unwrap());
@@ -66,23 +67,16 @@ fn init_kernel_page_table() -> PagingResult<PageTable> {
         phys_to_virt(KCONFIG.uart_base) + UART_SIZE,
         MMUFlags::READ | MMUFlags::WRITE | MMUFlags::DEVICE,
     )?;
-    // gic
-    map_range(
-        phys_to_virt(KCONFIG.gic_base + 0x1_0000),
-        phys_to_virt(KCONFIG.gic_base + 0x1_0000) + GICC_SIZE,
-        MMUFlags::READ | MMUFlags::WRITE | MMUFlags::DEVICE,
-    )?;
-    map_range(
-        phys_to_virt(KCONFIG.gic_base),
-        phys_to_virt(KCONFIG.gic_base) + GICD_SIZE,
-        MMUFlags::READ | MMUFlags::WRITE | MMUFlags::DEVICE,
-    )?;
-    // virtio_drivers
-    map_range(
-        phys_to_virt(VIRTIO_BASE),
-        phys_to_virt(VIRTIO_BASE) + VIRTIO_SIZE,
-        MMUFlags::READ | MMUFlags::WRITE | MMUFlags::DEVICE,
-    )?;
+    // DTB, whose size is in its header
+    let dtb = phys_to_virt(KCONFIG.dtb_paddr);
+    map_range(dtb, dtb + PAGE_SIZE, MMUFlags::READ)?;
+    let header = unsafe { core::slice::from_raw_parts(dtb as *const u32, 2) };
+    if u32::from_be(header[0]) == DTB_MAGIC {
+        let size = u32::from_be(header[1]) as usize;
+        if size > PAGE_SIZE {
+            map_range(dtb + PAGE_SIZE, dtb + size, MMUFlags::READ)?;
+        }
+    }
     // physical frames
     for r in crate::mem::free_pmem_regions() {
         map_range(
@@ -95,6 +89,10 @@ fn init_kernel_page_table() -> PagingResult<PageTable> {
     Ok(pt)
 }
 
+pub(super) fn kernel_page_table() -> &'static Mutex<PageTable> {
+    &KERNEL_PT
+}
+
 pub fn init() {
     let mut pt = KERNEL_PT.lock();
     info!("initialized kernel page table @ {:#x}", pt.table_phys());
@@ -20,5 +20,11 @@ hal_fn_impl! {
             sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::NoReason);
             unreachable!()
         }
+
+        fn reboot() -> ! {
+            info!("reboot...");
+            sbi_rt::system_reset(sbi_rt::ColdReboot, sbi_rt::NoReason);
+            unreachable!()
+        }
     }
 }
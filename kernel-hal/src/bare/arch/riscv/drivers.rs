@@ -55,6 +55,7 @@ impl IoMapper for IoMapperImpl {
 
 /// Initialize device drivers.
 pub(super) fn init() -> DeviceResult {
+    crate::net::init_config();
     // prase DTB and probe devices
     let dev_list =
         DevicetreeDriverBuilder::new(phys_to_virt(crate::KCONFIG.dtb_paddr), IoMapperImpl)?
@@ -72,13 +73,15 @@ pub(super) fn init() -> DeviceResult {
     {
         use alloc::sync::Arc;
         use zcore_drivers::bus::pci;
-        let pci_devs = pci::init(Some(Arc::new(IoMapperImpl)))?;
+        let pci_devs = pci::init(Some(Arc::new(IoMapperImpl)), None)?;
         for d in pci_devs.into_iter() {
             drivers::add_device(d);
         }
     }
 
     intc_init()?;
+    crate::net::init_dhcp();
+    crate::net::init_slaac();
 
     #[cfg(feature = "graphic")]
     if let Some(display) = drivers::all_display().first() {
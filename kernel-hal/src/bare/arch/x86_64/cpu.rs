@@ -1,6 +1,53 @@
 //! CPU information.
 
+use core::sync::atomic::{AtomicU16, Ordering};
+
 use raw_cpuid::CpuId;
+use zcore_drivers::utils::acpi::AcpiHpet;
+
+/// The TSC frequency in MHz measured against the HPET, 0 if not measured.
+static TSC_FREQ_MHZ: AtomicU16 = AtomicU16::new(0);
+
+const HPET_CAPABILITIES: usize = 0x0;
+const HPET_CONFIG: usize = 0x10;
+const HPET_MAIN_COUNTER: usize = 0xf0;
+const HPET_COUNT_SIZE_64: u64 = 1 << 13;
+const HPET_ENABLE: u64 = 1 << 0;
+/// The maximum period of the main counter in femtoseconds.
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// Measure the TSC frequency against the main counter of the HPET, instead of
+/// trusting the base frequency in CPUID.
+pub(super) fn calibrate_tsc(hpet: &AcpiHpet) {
+    const CALIBRATION_US: u64 = 10_000;
+    let base = crate::mem::phys_to_virt(hpet.address);
+    let reg = |offset: usize| (base + offset) as *mut u64;
+    unsafe {
+        use core::ptr::{read_volatile, write_volatile};
+        let caps = read_volatile(reg(HPET_CAPABILITIES));
+        let period_fs = caps >> 32;
+        if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+            warn!("HPET: invalid counter period {} fs", period_fs);
+            return;
+        }
+        let mask = if caps & HPET_COUNT_SIZE_64 != 0 {
+            u64::MAX
+        } else {
+            u32::MAX as u64
+        };
+        let config = read_volatile(reg(HPET_CONFIG));
+        write_volatile(reg(HPET_CONFIG), config | HPET_ENABLE);
+        let ticks = CALIBRATION_US * 1_000_000_000 / period_fs;
+        let start = read_volatile(reg(HPET_MAIN_COUNTER));
+        let tsc_start = core::arch::x86_64::_rdtsc();
+        while read_volatile(reg(HPET_MAIN_COUNTER)).wrapping_sub(start) & mask < ticks {
+            core::hint::spin_loop();
+        }
+        let mhz = (core::arch::x86_64::_rdtsc() - tsc_start) / CALIBRATION_US;
+        info!("HPET: TSC frequency {} MHz", mhz);
+        TSC_FREQ_MHZ.store(mhz.min(u16::MAX as u64) as u16, Ordering::Relaxed);
+    }
+}
 
 hal_fn_impl! {
     impl mod crate::hal_fn::cpu {
@@ -13,23 +60,48 @@ hal_fn_impl! {
 
         fn cpu_frequency() -> u16 {
             static CPU_FREQ_MHZ: spin::Once<u16> = spin::Once::new();
-            *CPU_FREQ_MHZ.call_once(|| {
-                const DEFAULT: u16 = 4000;
-                CpuId::new()
-                    .get_processor_frequency_info()
-                    .map(|info| info.processor_base_frequency())
-                    .unwrap_or(DEFAULT)
-                    .max(DEFAULT)
-            })
+            match TSC_FREQ_MHZ.load(Ordering::Relaxed) {
+                0 => *CPU_FREQ_MHZ.call_once(|| {
+                    const DEFAULT: u16 = 4000;
+                    CpuId::new()
+                        .get_processor_frequency_info()
+                        .map(|info| info.processor_base_frequency())
+                        .unwrap_or(DEFAULT)
+                        .max(DEFAULT)
+                }),
+                measured => measured,
+            }
         }
 
         fn reset() -> ! {
             info!("shutdown...");
+            if let Some(power) = super::special::acpi_tables().and_then(|t| t.power.as_ref()) {
+                if let Err(err) = power.power_off() {
+                    warn!("ACPI power off failed: {:?}", err);
+                }
+            }
+            // fall back to the QEMU ACPI port
             loop {
                 use zcore_drivers::io::{Io, Pmio};
                 Pmio::<u16>::new(0x604).write(0x2000);
                 super::interrupt::wait_for_interrupt();
             }
         }
+
+        fn reboot() -> ! {
+            info!("reboot...");
+            if let Some(power) = super::special::acpi_tables().and_then(|t| t.power.as_ref()) {
+                if let Err(err) = power.reset() {
+                    warn!("ACPI reset failed: {:?}", err);
+                }
+            }
+            // fall back to the reset control register and the keyboard controller
+            loop {
+                use zcore_drivers::io::{Io, Pmio};
+                Pmio::<u8>::new(0xcf9).write(0x06);
+                Pmio::<u8>::new(0x64).write(0xfe);
+                super::interrupt::wait_for_interrupt();
+            }
+        }
     }
 }
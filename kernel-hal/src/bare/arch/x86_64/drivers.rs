@@ -1,12 +1,15 @@
 use alloc::{boxed::Box, sync::Arc};
 
+use zcore_drivers::input::I8042Keyboard;
 use zcore_drivers::irq::x86::Apic;
+use zcore_drivers::rtc::CmosRtc;
 use zcore_drivers::scheme::IrqScheme;
 use zcore_drivers::uart::{BufferedUart, Uart16550Pmio};
 use zcore_drivers::{Device, DeviceResult};
 
+use super::special::{AcpiTables, ACPI_TABLES};
 use super::trap;
-use crate::drivers;
+use crate::{drivers, mem::phys_to_virt, KCONFIG};
 
 pub(super) fn init_early() -> DeviceResult {
     let uart = Arc::new(Uart16550Pmio::new(0x3F8));
@@ -16,22 +19,70 @@ pub(super) fn init_early() -> DeviceResult {
     Ok(())
 }
 
+/// Parse the ACPI tables, or use the defaults of a PC if they are unusable.
+fn init_acpi() -> &'static AcpiTables {
+    let acpi = match AcpiTables::new(KCONFIG.acpi_rsdp as usize, phys_to_virt) {
+        Ok(acpi) => acpi,
+        Err(e) => {
+            warn!("ACPI: tables not parsed, using legacy defaults: {:?}", e);
+            AcpiTables::legacy()
+        }
+    };
+    info!(
+        "ACPI: {} processors, {} I/O APICs, {} interrupt overrides",
+        acpi.processors.len(),
+        acpi.io_apics.len(),
+        acpi.irq_overrides.len()
+    );
+    #[cfg(not(feature = "no-pci"))]
+    {
+        match acpi.ecam_regions.iter().find(|r| r.segment == 0) {
+            Some(r) if r.bus_start == 0 && r.bus_end == 0xff => {
+                info!("ACPI: PCIe ECAM at {:#x}", r.base);
+                zcore_drivers::bus::pci::set_ecam_base(r.base);
+            }
+            Some(r) => warn!(
+                "ACPI: PCIe ECAM of buses {}..={} not used",
+                r.bus_start, r.bus_end
+            ),
+            None => {}
+        }
+    }
+    if let Some(hpet) = &acpi.hpet {
+        info!("ACPI: HPET at {:#x}", hpet.address);
+        super::cpu::calibrate_tsc(hpet);
+    }
+    ACPI_TABLES.init_once_by(acpi);
+    &ACPI_TABLES
+}
+
 pub(super) fn init() -> DeviceResult {
-    Apic::init_local_apic_bsp(crate::mem::phys_to_virt);
-    let irq = Arc::new(Apic::new(
-        super::special::pc_firmware_tables().0 as usize,
-        crate::mem::phys_to_virt,
-    ));
+    Apic::init_local_apic_bsp(phys_to_virt);
+    let irq = Arc::new(Apic::new(init_acpi(), phys_to_virt));
     let uarts = drivers::all_uart();
     if let Some(u) = uarts.try_get(0) {
-        irq.register_device(trap::X86_ISA_IRQ_COM1, u.clone().upcast())?;
-        irq.unmask(trap::X86_ISA_IRQ_COM1)?;
+        let gsi = irq.isa_irq_to_gsi(trap::X86_ISA_IRQ_COM1);
+        irq.register_device(gsi, u.clone().upcast())?;
+        irq.unmask(gsi)?;
 
         if let Some(u) = uarts.try_get(1) {
-            irq.register_device(trap::X86_ISA_IRQ_COM2, u.clone().upcast())?;
-            irq.unmask(trap::X86_ISA_IRQ_COM2)?;
+            let gsi = irq.isa_irq_to_gsi(trap::X86_ISA_IRQ_COM2);
+            irq.register_device(gsi, u.clone().upcast())?;
+            irq.unmask(gsi)?;
+        }
+    }
+
+    match I8042Keyboard::new() {
+        Ok(kbd) => {
+            let kbd = Arc::new(kbd);
+            let gsi = irq.isa_irq_to_gsi(trap::X86_ISA_IRQ_KEYBOARD);
+            irq.register_device(gsi, kbd.clone())?;
+            irq.unmask(gsi)?;
+            drivers::add_device(Device::Input(kbd));
         }
+        Err(e) => warn!("PS/2 keyboard not found: {:?}", e),
     }
+    drivers::add_device(Device::Rtc(Arc::new(CmosRtc::new())));
 
     use x2apic::lapic::{TimerDivide, TimerMode};
 
@@ -45,21 +96,28 @@ pub(super) fn init() -> DeviceResult {
     Apic::local_apic().set_timer_initial(cycles as u32);
     Apic::local_apic().disable_timer();
 
-    drivers::add_device(Device::Irq(irq));
+    drivers::add_device(Device::Irq(irq.clone()));
 
+    crate::net::init_config();
     #[cfg(not(feature = "no-pci"))]
     {
         // PCI scan
         use zcore_drivers::bus::pci;
-        let pci_devs = pci::init(None)?;
+        let pci_devs = pci::init(None, Some(irq.clone() as Arc<dyn IrqScheme>))?;
         for d in pci_devs.into_iter() {
+            // MSIs are routed to the devices, poll USB input devices which may lack them
+            if let Device::Input(i) = &d {
+                let dev = i.clone().upcast();
+                crate::thread::spawn(crate::common::future::IrqPollFuture::new(dev, 100));
+            }
             drivers::add_device(d);
         }
     }
+    crate::net::init_dhcp();
+    crate::net::init_slaac();
 
     #[cfg(feature = "graphic")]
     {
-        use crate::KCONFIG;
         use zcore_drivers::display::UefiDisplay;
         use zcore_drivers::prelude::{ColorFormat, DisplayInfo};
 
@@ -68,7 +126,7 @@ pub(super) fn init() -> DeviceResult {
             width: width as _,
             height: height as _,
             format: ColorFormat::ARGB8888, // uefi::proto::console::gop::PixelFormat::Bgr
-            fb_base_vaddr: crate::mem::phys_to_virt(KCONFIG.fb_addr as usize),
+            fb_base_vaddr: phys_to_virt(KCONFIG.fb_addr as usize),
             fb_size: KCONFIG.fb_size as usize,
         }));
         crate::drivers::add_device(Device::Display(display.clone()));
@@ -74,6 +74,10 @@ hal_fn_impl! {
             Ok(all_irq().first_unwrap().msi_register_handler(block, msi_id, handler)?)
         }
 
+        fn msi_target(block: Range<usize>, requester_id: usize) -> HalResult<(u64, u32)> {
+            Ok(all_irq().first_unwrap().msi_target(block, requester_id)?)
+        }
+
         fn send_ipi(cpuid: usize, reason: usize) -> HalResult {
             trace!("ipi [{}] => [{}]: {:x}", super::cpu::cpu_id(), cpuid, reason);
             panic!("send_ipi unsupported for x86_64");
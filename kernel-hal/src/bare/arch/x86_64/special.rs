@@ -1,8 +1,18 @@
 //! Functions only available on x86 platforms.
 
 pub use zcore_drivers::io::{Io, Pmio};
+pub use zcore_drivers::utils::acpi::AcpiTables;
+
+use crate::utils::init_once::InitOnce;
+
+pub(super) static ACPI_TABLES: InitOnce<AcpiTables> = InitOnce::new();
 
 /// Get physical address of `acpi_rsdp` and `smbios` on x86_64.
 pub fn pc_firmware_tables() -> (u64, u64) {
     (crate::KCONFIG.acpi_rsdp, crate::KCONFIG.smbios)
 }
+
+/// Get the ACPI tables parsed at boot, if any.
+pub fn acpi_tables() -> Option<&'static AcpiTables> {
+    ACPI_TABLES.try_get()
+}
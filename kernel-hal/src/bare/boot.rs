@@ -23,6 +23,8 @@ hal_fn_impl! {
             info!("Primary CPU {} init...", crate::cpu::cpu_id());
             unsafe { trapframe::init() };
             super::arch::primary_init();
+            crate::common::timer::init_realtime();
+            crate::common::rand::init_random();
         }
 
         fn secondary_init() {
@@ -1,8 +1,7 @@
 // May need move to drivers
 use smoltcp::{
-    iface::{InterfaceBuilder, NeighborCache, Route, Routes},
-    phy::{Loopback, Medium},
-    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address},
+    iface::{InterfaceBuilder, NeighborCache, Routes},
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr},
 };
 
 use alloc::collections::BTreeMap;
@@ -11,12 +10,20 @@ use alloc::vec::Vec;
 // use zcore_drivers::net::get_sockets;
 use alloc::sync::Arc;
 
+use alloc::boxed::Box;
 use alloc::string::String;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
 use lock::Mutex;
 
 use crate::drivers::add_device;
 use crate::drivers::all_net;
-use zcore_drivers::net::LoopbackInterface;
+use crate::timer;
+use zcore_drivers::net::dhcp::{self, DhcpClient};
+use zcore_drivers::net::{capture::Capture, hooked::HookedDevice};
+use zcore_drivers::net::{slaac, IfaceConfig, LoopbackDevice, LoopbackInterface};
 use zcore_drivers::scheme::NetScheme;
 use zcore_drivers::Device;
 
@@ -28,7 +35,7 @@ pub fn init() {
 
     // 网络 设备
     // 默认 loopback
-    let loopback = Loopback::new(Medium::Ethernet);
+    let loopback = LoopbackDevice::new();
 
     // 为 设备 分配 网络 身份
 
@@ -36,29 +43,37 @@ pub fn init() {
     let mac: [u8; 6] = [0x52, 0x54, 0x98, 0x76, 0x54, 0x32];
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
     // ip 地址
-    let ip_addrs = [IpCidr::new(IpAddress::v4(127, 0, 0, 1), 24)];
-    // qemu
-    // let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24)];
-    // 路由
-    let default_gateway = Ipv4Address::new(127, 0, 0, 1);
-    // qemu route
-    // let default_gateway = Ipv4Address::new(10, 0, 2, 2);
-    static mut ROUTES_STORAGE: [Option<(IpCidr, Route)>; 1] = [None; 1];
-    let mut routes = unsafe { Routes::new(&mut ROUTES_STORAGE[..]) };
-    routes.add_default_ipv4_route(default_gateway).unwrap();
+    let ip_addrs = [
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+        IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 128),
+    ];
+    // 路由: with `any_ip`, packets to addresses of other interfaces, which
+    // are routed to loopback, are taken as well
+    let mut routes = Routes::new(BTreeMap::new());
+    routes
+        .add_default_ipv4_route(Ipv4Address::new(127, 0, 0, 1))
+        .unwrap();
+    routes
+        .add_default_ipv6_route(Ipv6Address::LOOPBACK)
+        .unwrap();
     // arp缓存
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
 
     // 设置 主要 设置 iface
-    let iface = InterfaceBuilder::new(loopback)
+    let capture = Arc::new(Capture::default());
+    let device = HookedDevice::new(loopback, &name, capture.clone());
+    let iface = InterfaceBuilder::new(device)
         .ethernet_addr(ethernet_addr)
         .ip_addrs(ip_addrs)
         .routes(routes)
+        .ipv4_multicast_groups(BTreeMap::new())
+        .any_ip(true)
         .neighbor_cache(neighbor_cache)
         .finalize();
 
     let loopback_iface = LoopbackInterface {
         iface: Arc::new(Mutex::new(iface)),
+        capture,
         name,
     };
     // loopback_iface
@@ -69,3 +84,174 @@ pub fn init() {
 pub fn get_net_device() -> Vec<Arc<dyn NetScheme>> {
     all_net().as_vec().clone()
 }
+
+/// Parse the network options of the kernel command line, before Ethernet
+/// interfaces are probed:
+///
+/// - `ip=dhcp`, or `ip=ADDR[/PREFIX]` for a static address.
+/// - `gw=ADDR`: the default gateway.
+/// - `dns=ADDR[,ADDR...]`: the DNS servers.
+/// - `ip6=auto` for SLAAC, `ip6=off`, or `ip6=ADDR[/PREFIX]` for a static
+///   address.
+/// - `gw6=ADDR`: the IPv6 default gateway.
+///
+/// Options are separated by ':' or whitespace, a ':' inside IPv6 addresses
+/// doesn't end the option.
+///
+/// Without `ip=`, interfaces are configured for the QEMU user network.
+/// Without `ip6=`, global IPv6 addresses are configured by SLAAC.
+pub(crate) fn init_config() {
+    let cmdline = crate::boot::cmdline();
+    let mut config = IfaceConfig::default();
+    let mut gateway = None;
+    for opt in cmdline.split_whitespace().flat_map(split_options) {
+        let (key, value) = match opt.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        match key {
+            "ip" if value == "dhcp" => config.dhcp = true,
+            "ip" => match parse_cidr(value) {
+                Some(ip) => {
+                    config.ip = ip;
+                    // the default gateway is for the QEMU user network
+                    config.gateway = None;
+                }
+                None => warn!("invalid ip option: {:?}", value),
+            },
+            "gw" => match value.parse() {
+                Ok(gw) => gateway = Some(gw),
+                Err(_) => warn!("invalid gw option: {:?}", value),
+            },
+            "ip6" if value == "auto" => config.slaac = true,
+            "ip6" if value == "off" => config.slaac = false,
+            "ip6" => match parse_cidr6(value) {
+                Some(ip) => {
+                    config.ipv6 = Some(ip);
+                    config.slaac = false;
+                }
+                None => warn!("invalid ip6 option: {:?}", value),
+            },
+            "gw6" => match value.parse() {
+                Ok(gw) => config.ipv6_gateway = Some(gw),
+                Err(_) => warn!("invalid gw6 option: {:?}", value),
+            },
+            "dns" => {
+                let servers = value.split(',').filter_map(|s| s.parse().ok()).collect();
+                zcore_drivers::net::set_dns_servers(servers);
+            }
+            _ => {}
+        }
+    }
+    if gateway.is_some() {
+        config.gateway = gateway;
+    }
+    info!("network config: {:?}", config);
+    zcore_drivers::net::set_iface_config(config);
+}
+
+/// Split `s` on ':', unless the part after ':' is not a `KEY=VALUE` option,
+/// e.g. in `ip6=fd00::2/64:gw6=fd00::1`.
+fn split_options(s: &str) -> Vec<&str> {
+    let mut opts = Vec::new();
+    let mut start = 0;
+    for (i, _) in s.match_indices(':') {
+        let next = s[i + 1..].split(':').next().unwrap_or_default();
+        if next.contains('=') {
+            opts.push(&s[start..i]);
+            start = i + 1;
+        }
+    }
+    opts.push(&s[start..]);
+    opts
+}
+
+/// Parse `ADDR[/PREFIX]`, the prefix length is 24 by default.
+fn parse_cidr(s: &str) -> Option<Ipv4Cidr> {
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, prefix_len)) => (addr, prefix_len.parse().ok().filter(|&len| len <= 32)?),
+        None => (s, 24),
+    };
+    Some(Ipv4Cidr::new(addr.parse().ok()?, prefix_len))
+}
+
+/// Parse `ADDR[/PREFIX]`, the prefix length is 64 by default.
+fn parse_cidr6(s: &str) -> Option<Ipv6Cidr> {
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, prefix_len)) => (addr, prefix_len.parse().ok().filter(|&len| len <= 128)?),
+        None => (s, 64),
+    };
+    Some(Ipv6Cidr::new(addr.parse().ok()?, prefix_len))
+}
+
+/// How often to poll the interface configured by DHCP until it gets a lease.
+const DHCP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Polls the interface configured by DHCP until it gets the first lease, or
+/// the client is replaced. Renewals are applied when interfaces are polled
+/// for sockets.
+struct DhcpFuture {
+    client: Arc<DhcpClient>,
+    next_poll_time: Duration,
+}
+
+impl Future for DhcpFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the last reference, the client has been replaced
+        if Arc::strong_count(&self.client) == 1 {
+            return Poll::Ready(());
+        }
+        let now = timer::timer_now();
+        if now >= self.next_poll_time {
+            if self.client.poll() {
+                return Poll::Ready(());
+            }
+            self.next_poll_time = now + DHCP_POLL_INTERVAL;
+            let waker = cx.waker().clone();
+            timer::timer_set(self.next_poll_time, Box::new(move |_| waker.wake_by_ref()));
+        }
+        Poll::Pending
+    }
+}
+
+/// Start configuring the first Ethernet interface by DHCP if `ip=dhcp`. The
+/// lease is waited for in the background, without blocking the boot.
+pub(crate) fn init_dhcp() {
+    if !zcore_drivers::net::get_iface_config().dhcp {
+        return;
+    }
+    let iface = all_net()
+        .as_vec()
+        .iter()
+        .find(|iface| iface.get_ifname() != "loopback")
+        .cloned();
+    match iface {
+        Some(iface) => crate::thread::spawn(DhcpFuture {
+            client: dhcp::start(iface),
+            next_poll_time: Duration::default(),
+        }),
+        None => warn!("no Ethernet interface to configure by DHCP"),
+    }
+}
+
+/// Start configuring the first Ethernet interface by SLAAC, unless `ip6=` is
+/// a static address or `off`. Router advertisements are handled in the
+/// background.
+pub(crate) fn init_slaac() {
+    if !zcore_drivers::net::get_iface_config().slaac {
+        return;
+    }
+    let iface = all_net()
+        .as_vec()
+        .iter()
+        .find(|iface| iface.get_ifname() != "loopback")
+        .cloned();
+    match iface {
+        Some(iface) => {
+            slaac::start(iface);
+        }
+        None => warn!("no Ethernet interface to configure by SLAAC"),
+    }
+}
@@ -1,6 +1,7 @@
 //! Console input and output.
 
 use crate::drivers;
+use alloc::sync::Arc;
 use core::fmt::{Arguments, Result, Write};
 use lock::Mutex;
 
@@ -33,15 +34,19 @@ impl Write for DebugWriter {
 cfg_if! {
     if #[cfg(feature = "graphic")] {
         use crate::utils::init_once::InitOnce;
-        use alloc::sync::Arc;
-        use zcore_drivers::{scheme::DisplayScheme, utils::GraphicConsole};
+        use alloc::{boxed::Box, vec::Vec};
+        use zcore_drivers::input::{Keyboard, Keymap, Mouse};
+        use zcore_drivers::scheme::{DisplayScheme, EventScheme};
+        use zcore_drivers::utils::GraphicConsole;
 
         static GRAPHIC_CONSOLE: InitOnce<Mutex<GraphicConsole>> = InitOnce::new();
+        static GRAPHIC_CONSOLE_KEYBOARDS: InitOnce<Vec<Arc<Keyboard>>> = InitOnce::new();
+        static GRAPHIC_CONSOLE_MICE: InitOnce<Vec<Arc<Mouse>>> = InitOnce::new();
         static CONSOLE_WIN_SIZE: InitOnce<ConsoleWinSize> = InitOnce::new();
 
         pub(crate) fn init_graphic_console(display: Arc<dyn DisplayScheme>) {
             let info = display.info();
-            let cons = GraphicConsole::new(display);
+            let cons = GraphicConsole::new(display.clone());
             let winsz = ConsoleWinSize {
                 ws_row: cons.rows() as u16,
                 ws_col: cons.columns() as u16,
@@ -50,6 +55,38 @@ cfg_if! {
             };
             CONSOLE_WIN_SIZE.init_once_by(winsz);
             GRAPHIC_CONSOLE.init_once_by(Mutex::new(cons));
+
+            let keyboards = drivers::all_input()
+                .as_vec()
+                .iter()
+                .filter(|i| Keyboard::compatible_with(i))
+                .map(|i| Keyboard::new(i.clone(), &Keymap::US))
+                .collect();
+            GRAPHIC_CONSOLE_KEYBOARDS.init_once_by(keyboards);
+
+            // move the hardware cursor, if any, starting from the center
+            let cursor = Arc::new(Mutex::new((info.width as i32 / 2, info.height as i32 / 2)));
+            let mice = drivers::all_input()
+                .as_vec()
+                .iter()
+                .filter(|i| Mouse::compatible_with(i))
+                .map(|i| {
+                    let mouse = Mouse::new(i.clone());
+                    let (display, cursor) = (display.clone(), cursor.clone());
+                    mouse.subscribe(
+                        Box::new(move |m| {
+                            let info = display.info();
+                            let mut pos = cursor.lock();
+                            pos.0 = (pos.0 + m.dx).clamp(0, info.width as i32 - 1);
+                            pos.1 = (pos.1 - m.dy).clamp(0, info.height as i32 - 1);
+                            display.move_cursor(pos.0 as u32, pos.1 as u32).ok();
+                        }),
+                        false,
+                    );
+                    mouse
+                })
+                .collect();
+            GRAPHIC_CONSOLE_MICE.init_once_by(mice);
         }
     }
 }
@@ -92,6 +129,19 @@ pub fn graphic_console_write_fmt(fmt: Arguments) {
     }
 }
 
+/// Subscribes to the input of the graphic console, i.e. characters typed on
+/// its keyboards, translated by the keymap.
+#[allow(unused_variables)]
+pub fn graphic_console_subscribe(handler: Arc<dyn Fn(u8) + Send + Sync>) {
+    #[cfg(feature = "graphic")]
+    if let Some(keyboards) = GRAPHIC_CONSOLE_KEYBOARDS.try_get() {
+        for k in keyboards.iter() {
+            let handler = handler.clone();
+            k.subscribe(Box::new(move |&c| handler(c)), false);
+        }
+    }
+}
+
 /// Writes a string slice into the serial, and the graphic console if it exists.
 pub fn console_write_str(s: &str) {
     serial_write_str(s);
@@ -143,12 +143,7 @@ impl TrapReason {
             Kind::Irq => Self::Interrupt(
                 #[cfg(not(feature = "libos"))]
                 {
-                    use crate::hal_fn::mem::phys_to_virt;
-                    use crate::KCONFIG;
-                    zcore_drivers::irq::gic_400::get_irq_num(
-                        phys_to_virt(KCONFIG.gic_base + 0x1_0000),
-                        phys_to_virt(KCONFIG.gic_base),
-                    )
+                    crate::drivers::all_irq().first_unwrap().pending_irq()
                 },
                 #[cfg(feature = "libos")]
                 {
@@ -2,7 +2,7 @@ use alloc::{boxed::Box, sync::Arc};
 use core::task::{Context, Poll};
 use core::time::Duration;
 use core::{future::Future, pin::Pin};
-use zcore_drivers::scheme::DisplayScheme;
+use zcore_drivers::scheme::{DisplayScheme, Scheme};
 
 use crate::timer;
 
@@ -123,3 +123,37 @@ impl Future for DisplayFlushFuture {
         Poll::Pending
     }
 }
+
+/// Calls the interrupt handler of a device periodically, for devices whose
+/// interrupts are not delivered, e.g. PCI devices on x86.
+pub(crate) struct IrqPollFuture {
+    next_poll_time: Duration,
+    interval: Duration,
+    dev: Arc<dyn Scheme>,
+}
+
+impl IrqPollFuture {
+    #[allow(dead_code)]
+    pub fn new(dev: Arc<dyn Scheme>, poll_rate: usize) -> Self {
+        Self {
+            next_poll_time: Duration::default(),
+            interval: Duration::from_millis(1000 / poll_rate as u64),
+            dev,
+        }
+    }
+}
+
+impl Future for IrqPollFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let now = timer::timer_now();
+        if now >= self.next_poll_time {
+            self.dev.handle_irq(0);
+            self.next_poll_time = now + self.interval;
+            let waker = cx.waker().clone();
+            timer::timer_set(self.next_poll_time, Box::new(move |_| waker.wake_by_ref()));
+        }
+        Poll::Pending
+    }
+}
@@ -1,7 +1,34 @@
 use alloc::vec::Vec;
+use lock::Mutex;
 
 use crate::{PhysAddr, KHANDLER, PAGE_SIZE};
 
+/// A callback that tries to release at least `pages` physical frames, and
+/// returns the number of frames actually released.
+pub type FrameReclaimer = fn(pages: usize) -> usize;
+
+static RECLAIMERS: Mutex<Vec<FrameReclaimer>> = Mutex::new(Vec::new());
+
+/// Register a callback which will be invoked when the frame allocator runs
+/// out of memory, e.g. to evict clean pages from a cache.
+pub fn register_frame_reclaimer(reclaimer: FrameReclaimer) {
+    RECLAIMERS.lock().push(reclaimer);
+}
+
+/// Ask all registered reclaimers to release `pages` frames.
+fn reclaim_frames(pages: usize) -> usize {
+    // copy out the callbacks, reclaimers may allocate or free memory themselves
+    let reclaimers = RECLAIMERS.lock().clone();
+    let mut freed = 0;
+    for reclaimer in reclaimers {
+        if freed >= pages {
+            break;
+        }
+        freed += reclaimer(pages - freed);
+    }
+    freed
+}
+
 /// A 4K size physical frame.
 #[derive(Debug)]
 pub struct PhysFrame {
@@ -12,10 +39,19 @@ pub struct PhysFrame {
 impl PhysFrame {
     /// Allocate one physical frame.
     pub fn new() -> Option<Self> {
-        KHANDLER.frame_alloc().map(|paddr| Self {
-            paddr,
-            allocated: true,
-        })
+        KHANDLER
+            .frame_alloc()
+            .or_else(|| {
+                if reclaim_frames(1) > 0 {
+                    KHANDLER.frame_alloc()
+                } else {
+                    None
+                }
+            })
+            .map(|paddr| Self {
+                paddr,
+                allocated: true,
+            })
     }
 
     /// Allocate one physical frame and fill with zero.
@@ -27,7 +63,16 @@ impl PhysFrame {
     }
 
     fn alloc_contiguous_base(frame_count: usize, align_log2: usize) -> Option<PhysAddr> {
-        KHANDLER.frame_alloc_contiguous(frame_count, align_log2)
+        KHANDLER
+            .frame_alloc_contiguous(frame_count, align_log2)
+            .or_else(|| {
+                // freed frames may not be contiguous, but it's worth a retry
+                if reclaim_frames(frame_count) > 0 {
+                    KHANDLER.frame_alloc_contiguous(frame_count, align_log2)
+                } else {
+                    None
+                }
+            })
     }
 
     /// Allocate contiguous physical frames.
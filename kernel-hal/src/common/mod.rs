@@ -1,7 +1,9 @@
 pub(super) mod defs;
 pub(super) mod future;
 pub(super) mod mem;
+pub(super) mod rand;
 pub(super) mod thread;
+pub(super) mod timer;
 pub(super) mod vdso;
 pub(super) mod vm;
 
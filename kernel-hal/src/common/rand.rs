@@ -0,0 +1,148 @@
+//! The random number generator of the kernel, ChaCha20 with fast key erasure,
+//! seeded by hardware random number generators at boot.
+
+use lock::Mutex;
+
+use crate::drivers;
+use crate::hal_fn::timer::timer_now;
+
+const KEY_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 64;
+
+/// "expand 32-byte k"
+const SIGMA: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+static KEY: Mutex<[u8; KEY_SIZE]> = Mutex::new([0; KEY_SIZE]);
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+/// The ChaCha20 block of `counter`, with a zero nonce.
+fn chacha20_block(key: &[u8; KEY_SIZE], counter: u64) -> [u8; BLOCK_SIZE] {
+    chacha20_block_raw(key, [counter as u32, (counter >> 32) as u32, 0, 0])
+}
+
+/// The ChaCha20 block with the last four words of the input, i.e. the counter
+/// and the nonce.
+fn chacha20_block_raw(key: &[u8; KEY_SIZE], input: [u32; 4]) -> [u8; BLOCK_SIZE] {
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&SIGMA);
+    for (i, word) in key.chunks_exact(4).enumerate() {
+        state[4 + i] = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    state[12..].copy_from_slice(&input);
+
+    let mut x = state;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 1, 5, 9, 13);
+        quarter_round(&mut x, 2, 6, 10, 14);
+        quarter_round(&mut x, 3, 7, 11, 15);
+        quarter_round(&mut x, 0, 5, 10, 15);
+        quarter_round(&mut x, 1, 6, 11, 12);
+        quarter_round(&mut x, 2, 7, 8, 13);
+        quarter_round(&mut x, 3, 4, 9, 14);
+    }
+    let mut block = [0; BLOCK_SIZE];
+    for (i, bytes) in block.chunks_exact_mut(4).enumerate() {
+        bytes.copy_from_slice(&x[i].wrapping_add(state[i]).to_le_bytes());
+    }
+    block
+}
+
+/// Replace the key by the first block, so that the output before can't be
+/// recovered from the key.
+fn rekey(key: &mut [u8; KEY_SIZE]) {
+    let block = chacha20_block(key, 0);
+    key.copy_from_slice(&block[..KEY_SIZE]);
+}
+
+/// Fill `buf` with random bytes.
+pub(crate) fn generate(buf: &mut [u8]) {
+    let mut key = KEY.lock();
+    for (i, chunk) in buf.chunks_mut(BLOCK_SIZE).enumerate() {
+        let block = chacha20_block(&key, i as u64 + 1);
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+    rekey(&mut key);
+}
+
+/// Mix `data` into the state of the random number generator.
+pub fn add_entropy(data: &[u8]) {
+    let mut key = KEY.lock();
+    for chunk in data.chunks(KEY_SIZE) {
+        for (k, b) in key.iter_mut().zip(chunk) {
+            *k ^= b;
+        }
+        rekey(&mut key);
+    }
+}
+
+/// Seed the random number generator with the time and all hardware random
+/// number generators.
+pub(crate) fn init_random() {
+    add_entropy(&(timer_now().as_nanos() as u64).to_le_bytes());
+    let mut seeded = false;
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        let mut seed = [0; KEY_SIZE];
+        let mut len = 0;
+        for word in seed.chunks_exact_mut(8) {
+            let mut r = 0;
+            if unsafe { core::arch::x86_64::_rdrand64_step(&mut r) } == 1 {
+                word.copy_from_slice(&r.to_le_bytes());
+                len += 8;
+            }
+        }
+        add_entropy(&seed[..len]);
+        seeded |= len > 0;
+    }
+
+    for rng in drivers::all_rng().as_vec().iter() {
+        let mut seed = [0; KEY_SIZE];
+        match rng.fill(&mut seed) {
+            Ok(len) => {
+                info!("random: seeded with {} bytes from {}", len, rng.name());
+                add_entropy(&seed[..len]);
+                seeded |= len > 0;
+            }
+            Err(e) => warn!("random: failed to read from {}: {:?}", rng.name(), e),
+        }
+    }
+    if !seeded {
+        warn!("random: no hardware random number generator, seeded with the time only");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The test vector of the ChaCha20 block function in RFC 8439 §2.3.2.
+    #[test]
+    fn chacha20_test_vector() {
+        let mut key = [0; KEY_SIZE];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        // block count 1, nonce 00:00:00:09:00:00:00:4a:00:00:00:00
+        let block = chacha20_block_raw(&key, [1, 0x0900_0000, 0x4a00_0000, 0]);
+        let expected: [u8; BLOCK_SIZE] = [
+            0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20,
+            0x71, 0xc4, 0xc7, 0xd1, 0xf4, 0xc7, 0x33, 0xc0, 0x68, 0x03, 0x04, 0x22, 0xaa, 0x9a,
+            0xc3, 0xd4, 0x6c, 0x4e, 0xd2, 0x82, 0x64, 0x46, 0x07, 0x9f, 0xaa, 0x09, 0x14, 0xc2,
+            0xd7, 0x05, 0xd9, 0x8b, 0x02, 0xa2, 0xb5, 0x12, 0x9c, 0xd1, 0xde, 0x16, 0x4e, 0xb9,
+            0xcb, 0xd0, 0x83, 0xe8, 0xa2, 0x50, 0x3c, 0x4e,
+        ];
+        assert_eq!(block, expected);
+    }
+}
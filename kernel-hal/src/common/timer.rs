@@ -0,0 +1,45 @@
+//! Wall-clock time, kept as an offset from the monotonic [`timer_now`].
+
+use core::sync::atomic::{AtomicI64, Ordering};
+use core::time::Duration;
+
+use crate::drivers;
+use crate::hal_fn::timer::timer_now;
+
+/// Nanoseconds to add to [`timer_now`] to get the wall-clock time.
+static REALTIME_OFFSET: AtomicI64 = AtomicI64::new(0);
+
+fn set_realtime_offset(time: Duration) {
+    let offset = time.as_nanos() as i64 - timer_now().as_nanos() as i64;
+    REALTIME_OFFSET.store(offset, Ordering::Relaxed);
+}
+
+/// Get the wall-clock time since the Unix epoch.
+pub fn timer_realtime() -> Duration {
+    let nanos = timer_now().as_nanos() as i64 + REALTIME_OFFSET.load(Ordering::Relaxed);
+    Duration::from_nanos(nanos.max(0) as u64)
+}
+
+/// Set the wall-clock time since the Unix epoch, and write it to the first
+/// real-time clock if exists.
+pub fn timer_set_realtime(time: Duration) {
+    set_realtime_offset(time);
+    if let Some(rtc) = drivers::all_rtc().first() {
+        if let Err(e) = rtc.write_epoch(time.as_secs()) {
+            warn!("failed to set time of {}: {:?}", rtc.name(), e);
+        }
+    }
+}
+
+/// Seed the wall-clock time from the first real-time clock.
+pub(crate) fn init_realtime() {
+    if let Some(rtc) = drivers::all_rtc().first() {
+        match rtc.read_epoch() {
+            Ok(secs) => {
+                info!("{}: {} seconds since epoch", rtc.name(), secs);
+                set_realtime_offset(Duration::from_secs(secs));
+            }
+            Err(e) => warn!("failed to read time of {}: {:?}", rtc.name(), e),
+        }
+    }
+}
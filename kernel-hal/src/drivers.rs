@@ -6,7 +6,8 @@ use core::convert::From;
 use lock::{RwLock, RwLockReadGuard};
 
 use zcore_drivers::scheme::{
-    BlockScheme, DisplayScheme, InputScheme, IrqScheme, NetScheme, Scheme, UartScheme,
+    BlockScheme, DisplayScheme, InputScheme, IrqScheme, NetScheme, RngScheme, RtcScheme, Scheme,
+    UartScheme, VsockScheme,
 };
 use zcore_drivers::{Device, DeviceError};
 
@@ -65,7 +66,10 @@ struct AllDeviceList {
     input: DeviceList<dyn InputScheme>,
     irq: DeviceList<dyn IrqScheme>,
     net: DeviceList<dyn NetScheme>,
+    rng: DeviceList<dyn RngScheme>,
+    rtc: DeviceList<dyn RtcScheme>,
     uart: DeviceList<dyn UartScheme>,
+    vsock: DeviceList<dyn VsockScheme>,
 }
 
 impl AllDeviceList {
@@ -76,7 +80,10 @@ impl AllDeviceList {
             Device::Input(d) => self.input.add(d),
             Device::Irq(d) => self.irq.add(d),
             Device::Net(d) => self.net.add(d),
+            Device::Rng(d) => self.rng.add(d),
+            Device::Rtc(d) => self.rtc.add(d),
             Device::Uart(d) => self.uart.add(d),
+            Device::Vsock(d) => self.vsock.add(d),
         }
     }
 }
@@ -114,11 +121,26 @@ pub fn all_net() -> &'static DeviceList<dyn NetScheme> {
     &DEVICES.net
 }
 
+/// Returns all devices which implement the [`RngScheme`].
+pub fn all_rng() -> &'static DeviceList<dyn RngScheme> {
+    &DEVICES.rng
+}
+
+/// Returns all devices which implement the [`RtcScheme`].
+pub fn all_rtc() -> &'static DeviceList<dyn RtcScheme> {
+    &DEVICES.rtc
+}
+
 /// Returns all devices which implement the [`UartScheme`].
 pub fn all_uart() -> &'static DeviceList<dyn UartScheme> {
     &DEVICES.uart
 }
 
+/// Returns all devices which implement the [`VsockScheme`].
+pub fn all_vsock() -> &'static DeviceList<dyn VsockScheme> {
+    &DEVICES.vsock
+}
+
 impl From<DeviceError> for crate::HalError {
     fn from(err: DeviceError) -> Self {
         warn!("{:?}", err);
@@ -37,6 +37,9 @@ hal_fn_def! {
 
         /// Shutdown/reboot the machine.
         pub fn reset() -> !;
+
+        /// Restart the machine.
+        pub fn reboot() -> !;
     }
 
     /// Physical memory operations.
@@ -135,6 +138,10 @@ hal_fn_def! {
         /// block.
         pub fn msi_register_handler(block: Range<usize>, msi_id: usize, handler: IrqHandler) -> HalResult;
 
+        /// Get the address and data of the MSI message that the PCI device with `requester_id`
+        /// writes to trigger the first IRQ of the block.
+        pub fn msi_target(block: Range<usize>, requester_id: usize) -> HalResult<(u64, u32)>;
+
         pub fn send_ipi(cpuid: usize, reason: usize) -> HalResult;
 
         pub fn ipi_reason() -> Vec<usize>;
@@ -157,7 +164,7 @@ hal_fn_def! {
     }
 
     /// Time and clock functions.
-    pub mod timer {
+    pub mod timer: common::timer {
         /// Set the first time interrupt
         pub fn timer_enable();
 
@@ -179,29 +186,10 @@ hal_fn_def! {
     }
 
     /// Random number generator.
-    pub mod rand {
+    pub mod rand: common::rand {
         /// Fill random bytes to the buffer
-        #[allow(unused_variables)]
         pub fn fill_random(buf: &mut [u8]) {
-            cfg_if! {
-                if #[cfg(target_arch = "x86_64")] {
-                    // TODO: optimize
-                    for x in buf.iter_mut() {
-                        let mut r = 0;
-                        unsafe { core::arch::x86_64::_rdrand16_step(&mut r) };
-                        *x = r as _;
-                    }
-                } else {
-                    static mut SEED: u64 = 0xdead_beef_cafe_babe;
-                    for x in buf.iter_mut() {
-                        unsafe {
-                            // from musl
-                            SEED = SEED.wrapping_mul(0x5851_f42d_4c95_7f2d);
-                            *x = (SEED >> 33) as u8;
-                        }
-                    }
-                }
-            }
+            generate(buf)
         }
     }
 
@@ -12,6 +12,7 @@ hal_fn_impl! {
 
         fn primary_init() {
             super::drivers::init();
+            crate::common::rand::init_random();
 
             #[cfg(target_os = "macos")]
             unsafe {
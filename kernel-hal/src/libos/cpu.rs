@@ -10,5 +10,10 @@ hal_fn_impl! {
             info!("shutdown...");
             std::process::exit(0);
         }
+
+        fn reboot() -> ! {
+            info!("reboot...");
+            std::process::exit(0);
+        }
     }
 }
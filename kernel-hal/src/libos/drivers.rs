@@ -51,4 +51,6 @@ pub(super) fn init() {
         use crate::net;
         net::init();
     }
+
+    crate::net::init_host();
 }
@@ -1,8 +1,7 @@
 // May need move to drivers
 use smoltcp::{
-    iface::{InterfaceBuilder, NeighborCache, Route, Routes},
-    phy::{Loopback, Medium},
-    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address},
+    iface::{InterfaceBuilder, NeighborCache, Routes},
+    wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address},
 };
 
 use alloc::collections::BTreeMap;
@@ -16,8 +15,10 @@ use lock::Mutex;
 
 use crate::drivers::add_device;
 use crate::drivers::all_net;
-use zcore_drivers::net::LoopbackInterface;
-use zcore_drivers::scheme::NetScheme;
+use zcore_drivers::mock::net::{slirp::SlirpBackend, FrameBackend, MockNet};
+use zcore_drivers::net::{capture::Capture, hooked::HookedDevice};
+use zcore_drivers::net::{LoopbackDevice, LoopbackInterface};
+use zcore_drivers::scheme::{NetScheme, Scheme};
 use zcore_drivers::Device;
 
 pub fn init() {
@@ -28,7 +29,7 @@ pub fn init() {
 
     // 网络 设备
     // 默认 loopback
-    let loopback = Loopback::new(Medium::Ethernet);
+    let loopback = LoopbackDevice::new();
 
     // 为 设备 分配 网络 身份
 
@@ -36,29 +37,37 @@ pub fn init() {
     let mac: [u8; 6] = [0x52, 0x54, 0x98, 0x76, 0x54, 0x32];
     let ethernet_addr = EthernetAddress::from_bytes(&mac);
     // ip 地址
-    let ip_addrs = [IpCidr::new(IpAddress::v4(127, 0, 0, 1), 24)];
-    // qemu
-    // let ip_addrs = [IpCidr::new(IpAddress::v4(10, 0, 2, 15), 24)];
-    // 路由
-    let default_gateway = Ipv4Address::new(127, 0, 0, 1);
-    // qemu route
-    // let default_gateway = Ipv4Address::new(10, 0, 2, 2);
-    static mut ROUTES_STORAGE: [Option<(IpCidr, Route)>; 1] = [None; 1];
-    let mut routes = unsafe { Routes::new(&mut ROUTES_STORAGE[..]) };
-    routes.add_default_ipv4_route(default_gateway).unwrap();
+    let ip_addrs = [
+        IpCidr::new(IpAddress::v4(127, 0, 0, 1), 8),
+        IpCidr::new(IpAddress::v6(0, 0, 0, 0, 0, 0, 0, 1), 128),
+    ];
+    // 路由: with `any_ip`, packets to addresses of other interfaces, which
+    // are routed to loopback, are taken as well
+    let mut routes = Routes::new(BTreeMap::new());
+    routes
+        .add_default_ipv4_route(Ipv4Address::new(127, 0, 0, 1))
+        .unwrap();
+    routes
+        .add_default_ipv6_route(Ipv6Address::LOOPBACK)
+        .unwrap();
     // arp缓存
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
 
     // 设置 主要 设置 iface
-    let iface = InterfaceBuilder::new(loopback)
+    let capture = Arc::new(Capture::default());
+    let device = HookedDevice::new(loopback, &name, capture.clone());
+    let iface = InterfaceBuilder::new(device)
         .ethernet_addr(ethernet_addr)
         .ip_addrs(ip_addrs)
         .routes(routes)
+        .ipv4_multicast_groups(BTreeMap::new())
+        .any_ip(true)
         .neighbor_cache(neighbor_cache)
         .finalize();
 
     let loopback_iface = LoopbackInterface {
         iface: Arc::new(Mutex::new(iface)),
+        capture,
         name,
     };
     // loopback_iface
@@ -66,6 +75,92 @@ pub fn init() {
     add_device(dev);
 }
 
+/// Create the host network interface selected by the `NET` environment
+/// variable, in the form of the QEMU `-netdev` option:
+///
+/// - `NET=user`: the userspace NAT of [`SlirpBackend`], with the DNS server
+///   `10.0.2.3`.
+/// - `NET=tap[,ifname=NAME][,ip=ADDR[/PREFIX]][,gw=ADDR][,dns=ADDR]`: the
+///   TAP device `NAME`, `tap0` by default.
+///
+/// The interface is configured for the QEMU user network by default,
+/// `10.0.2.15/24` via `10.0.2.2`.
+pub fn init_host() {
+    let net = match std::env::var("NET") {
+        Ok(net) if !net.is_empty() => net,
+        _ => return,
+    };
+    let mut opts = net.split(',');
+    let backend: Arc<dyn FrameBackend> = match opts.next() {
+        Some("user") => {
+            zcore_drivers::net::set_dns_servers(vec![Ipv4Address::new(10, 0, 2, 3)]);
+            Arc::new(SlirpBackend::new())
+        }
+        #[cfg(target_os = "linux")]
+        Some("tap") => {
+            use zcore_drivers::{mock::net::tap::TapBackend, net::IfaceConfig};
+            let mut ifname = "tap0";
+            let mut config = IfaceConfig::default();
+            let mut gateway = None;
+            for opt in opts {
+                match opt.split_once('=') {
+                    Some(("ifname", value)) => ifname = value,
+                    Some(("ip", value)) => match parse_cidr(value) {
+                        Some(ip) => {
+                            config.ip = ip;
+                            // the default gateway is for the QEMU user network
+                            config.gateway = None;
+                        }
+                        None => warn!("invalid ip option: {:?}", value),
+                    },
+                    Some(("gw", value)) => match value.parse() {
+                        Ok(gw) => gateway = Some(gw),
+                        Err(_) => warn!("invalid gw option: {:?}", value),
+                    },
+                    Some(("dns", value)) => match value.parse() {
+                        Ok(dns) => zcore_drivers::net::set_dns_servers(vec![dns]),
+                        Err(_) => warn!("invalid dns option: {:?}", value),
+                    },
+                    _ => warn!("unknown NET option: {:?}", opt),
+                }
+            }
+            if gateway.is_some() {
+                config.gateway = gateway;
+            }
+            zcore_drivers::net::set_iface_config(config);
+            match TapBackend::open(ifname) {
+                Ok(tap) => Arc::new(tap),
+                Err(e) => {
+                    warn!("failed to open TAP device {}: {:?}", ifname, e);
+                    return;
+                }
+            }
+        }
+        _ => {
+            warn!("unsupported NET: {:?}", net);
+            return;
+        }
+    };
+    match MockNet::new(backend) {
+        Ok(iface) => {
+            let iface = Arc::new(iface);
+            add_device(Device::Net(iface.clone()));
+            MockNet::start_irq_service(move || iface.handle_irq(0));
+        }
+        Err(e) => warn!("failed to create host network interface: {:?}", e),
+    }
+}
+
+/// Parse `ADDR[/PREFIX]`, the prefix length is 24 by default.
+#[cfg(target_os = "linux")]
+fn parse_cidr(s: &str) -> Option<smoltcp::wire::Ipv4Cidr> {
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, prefix_len)) => (addr, prefix_len.parse().ok().filter(|&len| len <= 32)?),
+        None => (s, 24),
+    };
+    Some(smoltcp::wire::Ipv4Cidr::new(addr.parse().ok()?, prefix_len))
+}
+
 pub fn get_net_device() -> Vec<Arc<dyn NetScheme>> {
     all_net().as_vec().clone()
 }
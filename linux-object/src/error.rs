@@ -11,7 +11,7 @@ pub type SysResult = LxResult<usize>;
 /// Linux error codes defination
 #[allow(dead_code)]
 #[repr(isize)]
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum LxError {
     /// Undefined
     EUNDEF = 0,
@@ -97,14 +97,28 @@ pub enum LxError {
     ELOOP = 40,
     /// Identifier removed
     EIDRM = 43,
+    /// No data available
+    ENODATA = 61,
     /// Socket operation on non-socket
     ENOTSOCK = 88,
+    /// Message too long
+    EMSGSIZE = 90,
     /// Protocol not available
     ENOPROTOOPT = 92,
+    /// Socket type not supported
+    ESOCKTNOSUPPORT = 94,
+    /// Operation not supported on transport endpoint
+    EOPNOTSUPP = 95,
     /// Protocol family not supported
     EPFNOSUPPORT = 96,
     /// Address family not supported by protocol
     EAFNOSUPPORT = 97,
+    /// Address already in use
+    EADDRINUSE = 98,
+    /// Cannot assign requested address
+    EADDRNOTAVAIL = 99,
+    /// Network is unreachable
+    ENETUNREACH = 101,
     /// No buffer space available
     ENOBUFS = 105,
     /// Transport endpoint is already connected
@@ -115,6 +129,10 @@ pub enum LxError {
     ETIMEDOUT = 110,
     /// Connection refused
     ECONNREFUSED = 111,
+    /// Operation already in progress
+    EALREADY = 114,
+    /// Operation now in progress
+    EINPROGRESS = 115,
 }
 
 #[allow(non_snake_case)]
@@ -163,14 +181,23 @@ impl fmt::Display for LxError {
             ENOTEMPTY => "Directory not empty",
             ELOOP => "Too many symbolic links encountered",
             EIDRM => "Identifier removed",
+            ENODATA => "No data available",
             ENOTSOCK => "Socket operation on non-socket",
+            EMSGSIZE => "Message too long",
             ENOPROTOOPT => "Protocol not available",
+            ESOCKTNOSUPPORT => "Socket type not supported",
+            EOPNOTSUPP => "Operation not supported on transport endpoint",
             EPFNOSUPPORT => "Protocol family not supported",
             EAFNOSUPPORT => "Address family not supported by protocol",
+            EADDRINUSE => "Address already in use",
+            EADDRNOTAVAIL => "Cannot assign requested address",
+            ENETUNREACH => "Network is unreachable",
             ENOBUFS => "No buffer space available",
             EISCONN => "Transport endpoint is already connected",
             ENOTCONN => "Transport endpoint is not connected",
             ECONNREFUSED => "Connection refused",
+            EALREADY => "Operation already in progress",
+            EINPROGRESS => "Operation now in progress",
             _ => "Unknown error",
         };
         write!(f, "{}", explain)
@@ -1,21 +1,29 @@
 //! Implement INode for framebuffer
 
-use alloc::sync::Arc;
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{any::Any, convert::From};
 
-use kernel_hal::drivers::prelude::{ColorFormat, DisplayInfo};
+use kernel_hal::drivers::prelude::{ColorFormat, DisplayInfo, Rectangle};
 use kernel_hal::drivers::scheme::DisplayScheme;
 use kernel_hal::vm::{GenericPageTable, PageTable};
+use lock::Mutex;
 use rcore_fs::vfs::*;
 use rcore_fs_devfs::DevFS;
+use zircon_object::object::{KernelObject, Signal};
 use zircon_object::vm::{page_aligned, pages, VmObject};
 
 use crate::error::{LxError, LxResult};
 
 // IOCTLs
 const FBIOGET_VSCREENINFO: u32 = 0x4600;
+const FBIOPUT_VSCREENINFO: u32 = 0x4601;
 const FBIOGET_FSCREENINFO: u32 = 0x4602;
 
+/// Mask of the activation mode in `FbVarScreeninfo::activate`.
+const FB_ACTIVATE_MASK: u32 = 0xf;
+/// Only check the mode, don't set it.
+const FB_ACTIVATE_TEST: u32 = 2;
+
 /// no hardware accelerator
 const FB_ACCEL_NONE: u32 = 0;
 
@@ -259,6 +267,10 @@ impl From<DisplayInfo> for FbVarScreeninfo {
 pub struct FbDev {
     display: Arc<dyn DisplayScheme>,
     inode_id: usize,
+    /// VMOs of the framebuffers mapped to user space, by physical address and
+    /// size. Each mapping is a slice of one, which is removed when all its
+    /// slices are unmapped.
+    mapped: Arc<Mutex<BTreeMap<(usize, usize), Arc<VmObject>>>>,
 }
 
 impl FbDev {
@@ -266,6 +278,7 @@ impl FbDev {
         Self {
             display,
             inode_id: DevFS::new_inode_id(),
+            mapped: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 
@@ -279,7 +292,32 @@ impl FbDev {
             return Err(LxError::ENOMEM);
         }
         let len = len.min(info.fb_size - offset);
-        Ok(VmObject::new_physical(paddr as usize + offset, pages(len)))
+        let key = (paddr as usize, info.fb_size);
+        let fb = self
+            .mapped
+            .lock()
+            .entry(key)
+            .or_insert_with(|| VmObject::new_physical(key.0, pages(key.1)))
+            .clone();
+        let vmo = fb.create_slice(offset, len)?;
+        // writes through the mapping are not tracked until it's unmapped
+        self.display.track_dirty(false);
+        let display = self.display.clone();
+        let mapped = Arc::downgrade(&self.mapped);
+        fb.add_signal_callback(Box::new(move |signal| {
+            if !signal.contains(Signal::VMO_ZERO_CHILDREN) {
+                return false;
+            }
+            if let Some(mapped) = mapped.upgrade() {
+                let mut mapped = mapped.lock();
+                mapped.remove(&key);
+                if mapped.is_empty() {
+                    display.track_dirty(true);
+                }
+            }
+            true
+        }));
+        Ok(vmo)
     }
 }
 
@@ -317,6 +355,18 @@ impl INode for FbDev {
         let len = buf.len().min(info.fb_size - offset);
         let mut fb = self.display.fb();
         fb[offset..offset + len].copy_from_slice(&buf[..len]);
+        if len > 0 {
+            // the rows written
+            let pitch = info.pitch() as usize;
+            let top = offset / pitch;
+            let bottom = (offset + len - 1) / pitch + 1;
+            self.display.mark_dirty(&Rectangle {
+                x: 0,
+                y: top as u32,
+                width: info.width,
+                height: (bottom - top) as u32,
+            });
+        }
         Ok(len)
     }
 
@@ -361,6 +411,29 @@ impl INode for FbDev {
                 *dst = self.display.info().into();
                 Ok(0)
             }
+            FBIOPUT_VSCREENINFO => {
+                let var = unsafe { &mut *(data as *mut FbVarScreeninfo) };
+                let info = self.display.info();
+                // only the resolution can be changed
+                if var.bits_per_pixel != info.format.depth() as u32
+                    || var.xres == 0
+                    || var.yres == 0
+                {
+                    return Err(FsError::InvalidParam);
+                }
+                if var.activate & FB_ACTIVATE_MASK == FB_ACTIVATE_TEST {
+                    return Ok(0);
+                }
+                if (var.xres, var.yres) != (info.width, info.height) {
+                    self.display
+                        .set_mode(var.xres, var.yres)
+                        .map_err(|_| FsError::InvalidParam)?;
+                }
+                let activate = var.activate;
+                *var = self.display.info().into();
+                var.activate = activate;
+                Ok(0)
+            }
             _ => {
                 warn!("use never support ioctl !");
                 Err(FsError::NotSupported)
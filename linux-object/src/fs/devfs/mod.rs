@@ -1,9 +1,13 @@
 mod fbdev;
 mod input;
+mod netfilter;
 mod random;
+mod rtc;
 mod uartdev;
 
 pub use fbdev::FbDev;
 pub use input::{EventDev, MiceDev};
+pub use netfilter::NetfilterINode;
 pub use random::RandomINode;
+pub use rtc::RtcDev;
 pub use uartdev::UartDev;
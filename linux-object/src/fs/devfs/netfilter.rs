@@ -0,0 +1,88 @@
+//! Implement INode for the netfilter configuration device
+
+use core::any::Any;
+
+use rcore_fs::vfs::*;
+use rcore_fs_devfs::DevFS;
+
+use crate::error::LxError;
+use crate::net::netfilter;
+
+/// `/dev/netfilter`: reading lists the rules as iptables commands, and each
+/// line written is executed as an iptables command, e.g.
+/// `-t nat -A POSTROUTING -s 10.0.0.0/24 -j SNAT --to-source 192.168.0.2`.
+pub struct NetfilterINode {
+    inode_id: usize,
+}
+
+impl NetfilterINode {
+    /// create a netfilter INode
+    pub fn new() -> Self {
+        Self {
+            inode_id: DevFS::new_inode_id(),
+        }
+    }
+}
+
+impl Default for NetfilterINode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl INode for NetfilterINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let rules = netfilter::rules();
+        let rules = rules.as_bytes();
+        if offset >= rules.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(rules.len() - offset);
+        buf[..len].copy_from_slice(&rules[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let text = core::str::from_utf8(buf).map_err(|_| FsError::InvalidParam)?;
+        for line in text.lines() {
+            netfilter::execute(line).map_err(|err| match err {
+                LxError::ENOENT => FsError::EntryNotFound,
+                LxError::EEXIST => FsError::EntryExist,
+                _ => FsError::InvalidParam,
+            })?;
+        }
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: self.inode_id,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::CharDevice,
+            mode: 0o600, // owner read & write
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            // a misc device
+            rdev: make_rdev(10, 240),
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
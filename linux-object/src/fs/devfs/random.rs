@@ -48,14 +48,16 @@ impl INode for RandomINode {
         Ok(buf.len())
     }
 
-    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
-        Err(FsError::NotSupported)
+    /// Data written is mixed into the kernel random number generator.
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        kernel_hal::rand::add_entropy(buf);
+        Ok(buf.len())
     }
 
     fn poll(&self) -> Result<PollStatus> {
         Ok(PollStatus {
             read: true,
-            write: false,
+            write: true,
             error: false,
         })
     }
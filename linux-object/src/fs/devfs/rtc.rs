@@ -0,0 +1,154 @@
+//! Implement INode for real-time clocks
+
+use alloc::sync::Arc;
+use core::any::Any;
+
+use rcore_fs::vfs::*;
+use rcore_fs_devfs::DevFS;
+use zcore_drivers::rtc::RtcTime;
+use zcore_drivers::scheme::RtcScheme;
+
+// IOCTLs
+/// _IOR('p', 0x09, struct rtc_time)
+const RTC_RD_TIME: u32 = 0x8024_7009;
+/// _IOW('p', 0x0a, struct rtc_time)
+const RTC_SET_TIME: u32 = 0x4024_700a;
+
+/// The `rtc_time` structure of RTC ioctls, same as `struct tm`.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct RtcTimeC {
+    tm_sec: i32,
+    tm_min: i32,
+    tm_hour: i32,
+    /// day of the month, 1 to 31
+    tm_mday: i32,
+    /// month, 0 to 11
+    tm_mon: i32,
+    /// years since 1900
+    tm_year: i32,
+    /// day of the week, 0 for Sunday
+    tm_wday: i32,
+    /// day of the year, 0 to 365
+    tm_yday: i32,
+    tm_isdst: i32,
+}
+
+impl From<RtcTime> for RtcTimeC {
+    fn from(t: RtcTime) -> Self {
+        Self {
+            tm_sec: t.second as _,
+            tm_min: t.minute as _,
+            tm_hour: t.hour as _,
+            tm_mday: t.day as _,
+            tm_mon: t.month as i32 - 1,
+            tm_year: t.year as i32 - 1900,
+            tm_wday: t.weekday() as _,
+            tm_yday: t.yearday() as _,
+            tm_isdst: 0,
+        }
+    }
+}
+
+impl RtcTimeC {
+    /// Convert to [`RtcTime`], or `None` if any field is out of range or the
+    /// time is before the Unix epoch.
+    fn to_rtc_time(self) -> Option<RtcTime> {
+        if self.tm_year < 70 || self.tm_mon < 0 {
+            return None;
+        }
+        let t = RtcTime {
+            year: self.tm_year as u32 + 1900,
+            month: self.tm_mon as u32 + 1,
+            day: self.tm_mday as _,
+            hour: self.tm_hour as _,
+            minute: self.tm_min as _,
+            second: self.tm_sec as _,
+        };
+        Some(t).filter(RtcTime::is_valid)
+    }
+}
+
+/// Real-time clock device, at `/dev/rtcX`.
+pub struct RtcDev {
+    index: usize,
+    rtc: Arc<dyn RtcScheme>,
+    inode_id: usize,
+}
+
+impl RtcDev {
+    /// Create the device of the `index`-th real-time clock.
+    pub fn new(index: usize, rtc: Arc<dyn RtcScheme>) -> Self {
+        Self {
+            index,
+            rtc,
+            inode_id: DevFS::new_inode_id(),
+        }
+    }
+}
+
+impl INode for RtcDev {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        // reading waits for RTC interrupts, which are not supported
+        Err(FsError::NotSupported)
+    }
+
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Result<usize> {
+        Err(FsError::NotSupported)
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: false,
+            write: false,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 1,
+            inode: self.inode_id,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::CharDevice,
+            mode: 0o600, // owner read & write
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            // RTCs get a dynamic major number on Linux, 248 is the usual one
+            rdev: make_rdev(248, self.index),
+        })
+    }
+
+    fn io_control(&self, cmd: u32, data: usize) -> Result<usize> {
+        match cmd {
+            RTC_RD_TIME => {
+                let secs = self.rtc.read_epoch().map_err(|_| FsError::DeviceError)?;
+                let dst = unsafe { &mut *(data as *mut RtcTimeC) };
+                *dst = RtcTime::from_epoch(secs).into();
+                Ok(0)
+            }
+            RTC_SET_TIME => {
+                let src = unsafe { *(data as *const RtcTimeC) };
+                let time = src.to_rtc_time().ok_or(FsError::InvalidParam)?;
+                self.rtc
+                    .write_epoch(time.to_epoch())
+                    .map_err(|_| FsError::DeviceError)?;
+                Ok(0)
+            }
+            _ => {
+                warn!("rtc ioctl {:#x} unimplemented", cmd);
+                Err(FsError::NotSupported)
+            }
+        }
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
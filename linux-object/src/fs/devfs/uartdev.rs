@@ -4,9 +4,15 @@ use rcore_fs::vfs::{make_rdev, FileType, FsError, INode, Metadata, PollStatus, R
 use rcore_fs_devfs::DevFS;
 use zcore_drivers::{scheme::UartScheme, DeviceError};
 
+/// Major device number of serial ports, `/dev/ttyS*`.
+const TTY_MAJOR: usize = 4;
+/// Major device number of hypervisor consoles, `/dev/hvc*`.
+const HVC_MAJOR: usize = 229;
+
 /// Uart device.
 pub struct UartDev {
     index: usize,
+    major: usize,
     port: Arc<dyn UartScheme>,
     inode_id: usize,
 }
@@ -15,10 +21,19 @@ impl UartDev {
     pub fn new(index: usize, port: Arc<dyn UartScheme>) -> Self {
         Self {
             index,
+            major: TTY_MAJOR,
             port,
             inode_id: DevFS::new_inode_id(),
         }
     }
+
+    /// Create a hypervisor console device, e.g. a port of virtio-console.
+    pub fn new_hvc(index: usize, port: Arc<dyn UartScheme>) -> Self {
+        Self {
+            major: HVC_MAJOR,
+            ..Self::new(index, port)
+        }
+    }
 }
 
 impl INode for UartDev {
@@ -80,7 +95,7 @@ impl INode for UartDev {
             nlinks: 1,
             uid: 0,
             gid: 0,
-            rdev: make_rdev(4, self.index),
+            rdev: make_rdev(self.major, self.index),
         })
     }
 
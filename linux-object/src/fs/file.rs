@@ -1,6 +1,6 @@
 //! File handle for process
 
-use alloc::{boxed::Box, string::String, sync::Arc};
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
 
 use async_trait::async_trait;
 use lock::RwLock;
@@ -9,7 +9,7 @@ use rcore_fs::vfs::{FileType, FsError, INode, Metadata, PollStatus};
 use zircon_object::object::*;
 use zircon_object::vm::{pages, VmObject};
 
-use super::FileLike;
+use super::{page_cache::page_cache, sysctl::SysctlINode, FileLike, PipeBuffer};
 use crate::error::{LxError, LxResult};
 
 use zircon_object::vm::PAGE_SIZE_LOG2;
@@ -98,6 +98,8 @@ struct FileInner {
     flags: OpenFlags,
     /// file INode
     inode: Arc<dyn INode>,
+    /// whether the content goes through the page cache
+    cached: bool,
 }
 
 /// file implement struct
@@ -125,6 +127,9 @@ impl FileInner {
         if !self.flags.readable() {
             return Err(LxError::EBADF);
         }
+        if self.cached {
+            return page_cache().read_at(&self.inode, offset as usize, buf);
+        }
         if !self.flags.non_block() {
             // block
             loop {
@@ -158,6 +163,9 @@ impl FileInner {
         if !self.flags.writable() {
             return Err(LxError::EBADF);
         }
+        if self.cached {
+            return page_cache().write_at(&self.inode, offset as usize, buf);
+        }
         let len = self.inode.write_at(offset as usize, buf)?;
         Ok(len)
     }
@@ -166,6 +174,8 @@ impl FileInner {
 impl File {
     /// create a file struct
     pub fn new(inode: Arc<dyn INode>, flags: OpenFlags, path: String) -> Arc<Self> {
+        let cached = matches!(inode.metadata(), Ok(m) if m.type_ == FileType::File)
+            && !inode.as_any_ref().is::<SysctlINode>();
         Arc::new(File {
             base: KObjectBase::new(),
             path,
@@ -173,6 +183,7 @@ impl File {
                 offset: 0,
                 flags,
                 inode,
+                cached,
             }),
         })
     }
@@ -199,19 +210,30 @@ impl File {
         if !inner.flags.writable() {
             return Err(LxError::EBADF);
         }
+        if inner.cached {
+            page_cache().truncate(&inner.inode, len as usize)?;
+        }
         inner.inode.resize(len as usize)?;
         Ok(())
     }
 
     /// Sync all data and metadata
     pub fn sync_all(&self) -> LxResult {
-        self.inner.read().inode.sync_all()?;
+        let inner = self.inner.read();
+        if inner.cached {
+            page_cache().flush(inner.inode.as_ref())?;
+        }
+        inner.inode.sync_all()?;
         Ok(())
     }
 
     /// Sync data (not include metadata)
     pub fn sync_data(&self) -> LxResult {
-        self.inner.read().inode.sync_data()?;
+        let inner = self.inner.read();
+        if inner.cached {
+            page_cache().flush(inner.inode.as_ref())?;
+        }
+        inner.inode.sync_data()?;
         Ok(())
     }
 
@@ -241,6 +263,58 @@ impl File {
     pub fn inode(&self) -> Arc<dyn INode> {
         self.inner.read().inode.clone()
     }
+
+    /// Whether the content goes through the page cache, so that it can be
+    /// transferred with [`read_pages`](Self::read_pages) and
+    /// [`write_pages`](Self::write_pages) without copying.
+    pub fn is_cached(&self) -> bool {
+        self.inner.read().cached
+    }
+
+    /// Read up to `len` bytes as references to the cached pages.
+    ///
+    /// Reads at `offset` if given, or at the file offset and advances it.
+    pub fn read_pages(&self, offset: Option<u64>, len: usize) -> LxResult<Vec<PipeBuffer>> {
+        let mut inner = self.inner.write();
+        if !inner.flags.readable() {
+            return Err(LxError::EBADF);
+        }
+        if !inner.cached {
+            return Err(LxError::EINVAL);
+        }
+        let pos = offset.unwrap_or(inner.offset);
+        let bufs = page_cache().get_pages(&inner.inode, pos as usize, len)?;
+        if offset.is_none() {
+            inner.offset += bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
+        }
+        Ok(bufs)
+    }
+
+    /// Write page references to the file, sharing whole pages with the page cache.
+    ///
+    /// Writes at `offset` if given, or at the file offset and advances it.
+    pub fn write_pages(&self, offset: Option<u64>, bufs: &[PipeBuffer]) -> LxResult<usize> {
+        let mut inner = self.inner.write();
+        if !inner.flags.writable() {
+            return Err(LxError::EBADF);
+        }
+        if !inner.cached {
+            return Err(LxError::EINVAL);
+        }
+        let start = match offset {
+            Some(offset) => offset,
+            None if inner.flags.is_append() => inner.inode.metadata()?.size as u64,
+            None => inner.offset,
+        };
+        let mut pos = start;
+        for buf in bufs {
+            pos += page_cache().write_page(&inner.inode, pos as usize, buf)? as u64;
+        }
+        if offset.is_none() {
+            inner.offset = pos;
+        }
+        Ok((pos - start) as usize)
+    }
 }
 
 #[async_trait]
@@ -302,7 +376,7 @@ impl FileLike for File {
             FileType::File => {
                 let vmo = VmObject::new_contiguous(pages(len), PAGE_SIZE_LOG2)?;
                 let (guard, buf) = vmo.as_mut_buf()?;
-                inner.inode.read_at(offset, buf)?;
+                page_cache().read_at(&inner.inode, offset, buf)?;
                 drop(guard);
                 vmo.unset_contiguous();
                 Ok(vmo)
@@ -0,0 +1,492 @@
+//! Implement inotify: monitoring file system events
+#![deny(missing_docs)]
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use kernel_hal::user::UserOutPtr;
+use lazy_static::lazy_static;
+use lock::Mutex;
+use rcore_fs::vfs::{FileType, INode, PollStatus};
+use zircon_object::object::*;
+
+use super::{inode_key, ioctl::FIONREAD, File, FileLike, InodeKey, OpenFlags, PollEvents};
+use crate::error::{LxError, LxResult};
+use crate::sync::{wait_for_event, Event, EventBus};
+
+bitflags::bitflags! {
+    /// inotify event mask
+    pub struct InotifyMask: u32 {
+        /// File was accessed
+        const ACCESS = 0x1;
+        /// File was modified
+        const MODIFY = 0x2;
+        /// Metadata changed
+        const ATTRIB = 0x4;
+        /// Writable file was closed
+        const CLOSE_WRITE = 0x8;
+        /// Unwritable file closed
+        const CLOSE_NOWRITE = 0x10;
+        /// File was opened
+        const OPEN = 0x20;
+        /// File was moved from X
+        const MOVED_FROM = 0x40;
+        /// File was moved to Y
+        const MOVED_TO = 0x80;
+        /// Subfile was created
+        const CREATE = 0x100;
+        /// Subfile was deleted
+        const DELETE = 0x200;
+        /// Self was deleted
+        const DELETE_SELF = 0x400;
+        /// Self was moved
+        const MOVE_SELF = 0x800;
+        /// Backing fs was unmounted
+        const UNMOUNT = 0x2000;
+        /// Event queue overflowed
+        const Q_OVERFLOW = 0x4000;
+        /// File was ignored
+        const IGNORED = 0x8000;
+        /// Only watch the path if it is a directory
+        const ONLYDIR = 0x0100_0000;
+        /// Don't follow a sym link
+        const DONT_FOLLOW = 0x0200_0000;
+        /// Exclude events on unlinked objects
+        const EXCL_UNLINK = 0x0400_0000;
+        /// Only create watches
+        const MASK_CREATE = 0x1000_0000;
+        /// Add to the mask of an already existing watch
+        const MASK_ADD = 0x2000_0000;
+        /// Event occurred against dir
+        const ISDIR = 0x4000_0000;
+        /// Only send event once
+        const ONESHOT = 0x8000_0000;
+
+        /// All events which a program can wait on
+        const ALL_EVENTS = 0xfff;
+    }
+}
+
+/// Maximum number of queued events of an inotify instance.
+const MAX_QUEUED_EVENTS: usize = 16384;
+
+/// Size of `struct inotify_event` without the name.
+const EVENT_HEADER_SIZE: usize = 16;
+
+/// A watch of an inotify instance.
+struct Watch {
+    key: InodeKey,
+    mask: InotifyMask,
+}
+
+struct InotifyInner {
+    flags: OpenFlags,
+    /// watch descriptor -> watch
+    watches: BTreeMap<i32, Watch>,
+    next_wd: i32,
+    /// serialized `struct inotify_event`s
+    events: VecDeque<Vec<u8>>,
+}
+
+/// An inotify instance
+pub struct Inotify {
+    base: KObjectBase,
+    inner: Mutex<InotifyInner>,
+    eventbus: Arc<Mutex<EventBus>>,
+}
+
+impl_kobject!(Inotify);
+
+/// Watchers of an inode: (inotify instance, watch descriptor)
+type Watchers = Vec<(Weak<Inotify>, i32)>;
+
+lazy_static! {
+    /// inode -> watchers
+    static ref WATCHES: Mutex<BTreeMap<InodeKey, Watchers>> = Mutex::new(BTreeMap::new());
+    /// child inode -> (parent directory, name), recorded for watched directories
+    static ref PARENTS: Mutex<BTreeMap<InodeKey, (InodeKey, String)>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Number of watches in the system, used to skip the lookup if nothing is watched.
+static NR_WATCHES: AtomicUsize = AtomicUsize::new(0);
+static NEXT_COOKIE: AtomicU32 = AtomicU32::new(1);
+
+impl Inotify {
+    /// Create a new inotify instance.
+    pub fn new(flags: OpenFlags) -> Arc<Self> {
+        Arc::new(Inotify {
+            base: KObjectBase::new(),
+            inner: Mutex::new(InotifyInner {
+                flags,
+                watches: BTreeMap::new(),
+                next_wd: 1,
+                events: VecDeque::new(),
+            }),
+            eventbus: EventBus::new(),
+        })
+    }
+
+    /// Add a watch of `inode`, or modify the existing one. Returns the watch descriptor.
+    pub fn add_watch(self: &Arc<Self>, inode: &Arc<dyn INode>, mask: InotifyMask) -> LxResult<i32> {
+        if (mask & InotifyMask::ALL_EVENTS).is_empty() {
+            return Err(LxError::EINVAL);
+        }
+        if mask.contains(InotifyMask::ONLYDIR) && inode.metadata()?.type_ != FileType::Dir {
+            return Err(LxError::ENOTDIR);
+        }
+        let key = inode_key(inode.as_ref())?;
+        let mut inner = self.inner.lock();
+        if let Some((&wd, watch)) = inner.watches.iter_mut().find(|(_, w)| w.key == key) {
+            if mask.contains(InotifyMask::MASK_CREATE) {
+                return Err(LxError::EEXIST);
+            }
+            if mask.contains(InotifyMask::MASK_ADD) {
+                watch.mask |= mask;
+            } else {
+                watch.mask = mask;
+            }
+            return Ok(wd);
+        }
+        let wd = inner.next_wd;
+        inner.next_wd += 1;
+        inner.watches.insert(wd, Watch { key, mask });
+        WATCHES
+            .lock()
+            .entry(key)
+            .or_default()
+            .push((Arc::downgrade(self), wd));
+        NR_WATCHES.fetch_add(1, Ordering::SeqCst);
+        Ok(wd)
+    }
+
+    /// Remove the watch `wd`.
+    pub fn rm_watch(&self, wd: i32) -> LxResult {
+        let watch = self
+            .inner
+            .lock()
+            .watches
+            .remove(&wd)
+            .ok_or(LxError::EINVAL)?;
+        self.unregister(wd, watch.key);
+        self.push_event(wd, InotifyMask::IGNORED, 0, None);
+        Ok(())
+    }
+
+    fn unregister(&self, wd: i32, key: InodeKey) {
+        let mut watches = WATCHES.lock();
+        if let Some(watchers) = watches.get_mut(&key) {
+            let this = self as *const Self;
+            watchers.retain(|(w, d)| !(w.as_ptr() == this && *d == wd));
+            if watchers.is_empty() {
+                watches.remove(&key);
+                PARENTS.lock().retain(|_, (parent, _)| *parent != key);
+            }
+        }
+        NR_WATCHES.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn push_event(&self, wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) {
+        let mut inner = self.inner.lock();
+        if inner.events.len() >= MAX_QUEUED_EVENTS {
+            if inner.events.len() == MAX_QUEUED_EVENTS {
+                let overflow = serialize_event(-1, InotifyMask::Q_OVERFLOW, 0, None);
+                inner.events.push_back(overflow);
+            }
+            return;
+        }
+        let event = serialize_event(wd, mask, cookie, name);
+        // coalesce identical consecutive events
+        if inner.events.back() == Some(&event) {
+            return;
+        }
+        inner.events.push_back(event);
+        drop(inner);
+        self.eventbus.lock().set(Event::READABLE);
+    }
+
+    /// Deliver an event to the watch `wd`, returns whether the watch is still alive.
+    fn deliver(&self, wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) -> bool {
+        let watch_mask = match self.inner.lock().watches.get(&wd) {
+            Some(watch) => watch.mask,
+            None => return false,
+        };
+        let events = mask & InotifyMask::ALL_EVENTS;
+        if (watch_mask & events).is_empty() {
+            return true;
+        }
+        self.push_event(
+            wd,
+            (watch_mask & events) | (mask - InotifyMask::ALL_EVENTS),
+            cookie,
+            name,
+        );
+        if watch_mask.contains(InotifyMask::ONESHOT) {
+            let _ = self.rm_watch(wd);
+            return false;
+        }
+        true
+    }
+
+    fn can_read(&self) -> bool {
+        !self.inner.lock().events.is_empty()
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        let watches = core::mem::take(&mut self.inner.lock().watches);
+        for (wd, watch) in watches {
+            self.unregister(wd, watch.key);
+        }
+    }
+}
+
+fn serialize_event(wd: i32, mask: InotifyMask, cookie: u32, name: Option<&str>) -> Vec<u8> {
+    // the name is null-terminated and padded to the size of the header
+    let len = name.map_or(0, |name| {
+        (name.len() + 1 + EVENT_HEADER_SIZE - 1) / EVENT_HEADER_SIZE * EVENT_HEADER_SIZE
+    });
+    let mut buf = Vec::with_capacity(EVENT_HEADER_SIZE + len);
+    buf.extend_from_slice(&wd.to_ne_bytes());
+    buf.extend_from_slice(&mask.bits().to_ne_bytes());
+    buf.extend_from_slice(&cookie.to_ne_bytes());
+    buf.extend_from_slice(&(len as u32).to_ne_bytes());
+    if let Some(name) = name {
+        buf.extend_from_slice(name.as_bytes());
+    }
+    buf.resize(EVENT_HEADER_SIZE + len, 0);
+    buf
+}
+
+/// Send `mask` to all watchers of `key`.
+fn notify_key(key: InodeKey, mask: InotifyMask, cookie: u32, name: Option<&str>) {
+    let watchers = match WATCHES.lock().get(&key) {
+        Some(watchers) => watchers.clone(),
+        None => return,
+    };
+    for (inotify, wd) in watchers {
+        if let Some(inotify) = inotify.upgrade() {
+            inotify.deliver(wd, mask, cookie, name);
+        }
+    }
+}
+
+fn is_dir(inode: &dyn INode) -> bool {
+    matches!(inode.metadata(), Ok(m) if m.type_ == FileType::Dir)
+}
+
+/// Report an event on `inode` itself, and on its parent directory if it's watched.
+pub fn notify(inode: &dyn INode, mut mask: InotifyMask) {
+    if !has_watches() {
+        return;
+    }
+    let key = match inode_key(inode) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    if is_dir(inode) {
+        mask |= InotifyMask::ISDIR;
+    }
+    notify_key(key, mask, 0, None);
+    let parent = PARENTS.lock().get(&key).cloned();
+    if let Some((parent, name)) = parent {
+        notify_key(parent, mask, 0, Some(&name));
+    }
+}
+
+/// Report an event on the inode of `file`, if it's a [`File`].
+pub fn notify_file(file: &dyn FileLike, mask: InotifyMask) {
+    if !has_watches() {
+        return;
+    }
+    if let Some(file) = file.downcast_ref::<File>() {
+        notify(file.inode().as_ref(), mask);
+    }
+}
+
+/// Whether any inode is being watched.
+pub fn has_watches() -> bool {
+    NR_WATCHES.load(Ordering::SeqCst) != 0
+}
+
+/// Report an event on the entry `name` of directory `dir`, e.g. `CREATE` and `DELETE`.
+pub fn notify_child(dir: &dyn INode, name: &str, child: &dyn INode, mask: InotifyMask) {
+    notify_child_with_cookie(dir, name, child, mask, 0)
+}
+
+fn notify_child_with_cookie(
+    dir: &dyn INode,
+    name: &str,
+    child: &dyn INode,
+    mut mask: InotifyMask,
+    cookie: u32,
+) {
+    let dir_key = match inode_key(dir) {
+        Ok(key) => key,
+        Err(_) => return,
+    };
+    if is_dir(child) {
+        mask |= InotifyMask::ISDIR;
+    }
+    notify_key(dir_key, mask, cookie, Some(name));
+}
+
+/// Called when `child` is opened as the entry `name` of directory `dir`.
+///
+/// If `dir` is watched, later events on `child` are also reported to the watchers of `dir`.
+pub fn notify_open(dir: &dyn INode, name: &str, child: &dyn INode, created: bool) {
+    if !has_watches() {
+        return;
+    }
+    if let (Ok(dir_key), Ok(child_key)) = (inode_key(dir), inode_key(child)) {
+        if WATCHES.lock().contains_key(&dir_key) {
+            PARENTS.lock().insert(child_key, (dir_key, name.into()));
+        }
+    }
+    if created {
+        notify_child(dir, name, child, InotifyMask::CREATE);
+    }
+    notify(child, InotifyMask::OPEN);
+}
+
+/// Called after the entry `name` of directory `dir` is removed, `last_link`
+/// if it was the last link to `child`.
+pub fn notify_unlink(dir: &dyn INode, name: &str, child: &dyn INode, last_link: bool) {
+    if !has_watches() {
+        return;
+    }
+    notify_child(dir, name, child, InotifyMask::DELETE);
+    if let (true, Ok(key)) = (last_link, inode_key(child)) {
+        PARENTS.lock().remove(&key);
+        notify_key(key, InotifyMask::DELETE_SELF, 0, None);
+        // the watches are removed as the inode is gone
+        let watchers = WATCHES.lock().get(&key).cloned().unwrap_or_default();
+        for (inotify, wd) in watchers {
+            if let Some(inotify) = inotify.upgrade() {
+                let _ = inotify.rm_watch(wd);
+            }
+        }
+    }
+}
+
+/// Called after the entry `old_name` of `old_dir` is moved to `new_name` of `new_dir`.
+pub fn notify_rename(
+    old_dir: &dyn INode,
+    old_name: &str,
+    new_dir: &dyn INode,
+    new_name: &str,
+    child: &dyn INode,
+) {
+    if !has_watches() {
+        return;
+    }
+    let cookie = NEXT_COOKIE.fetch_add(1, Ordering::SeqCst);
+    notify_child_with_cookie(old_dir, old_name, child, InotifyMask::MOVED_FROM, cookie);
+    notify_child_with_cookie(new_dir, new_name, child, InotifyMask::MOVED_TO, cookie);
+    if let Ok(key) = inode_key(child) {
+        notify_key(key, InotifyMask::MOVE_SELF, 0, None);
+        let new_parent = inode_key(new_dir)
+            .ok()
+            .filter(|dir_key| WATCHES.lock().contains_key(dir_key));
+        let mut parents = PARENTS.lock();
+        parents.remove(&key);
+        if let Some(dir_key) = new_parent {
+            parents.insert(key, (dir_key, new_name.into()));
+        }
+    }
+}
+
+#[async_trait]
+impl FileLike for Inotify {
+    fn flags(&self) -> OpenFlags {
+        self.inner.lock().flags
+    }
+
+    fn set_flags(&self, f: OpenFlags) -> LxResult {
+        let flags = &mut self.inner.lock().flags;
+        flags.set(OpenFlags::NON_BLOCK, f.contains(OpenFlags::NON_BLOCK));
+        flags.set(OpenFlags::CLOEXEC, f.contains(OpenFlags::CLOEXEC));
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> LxResult<usize> {
+        loop {
+            {
+                let mut inner = self.inner.lock();
+                if let Some(first) = inner.events.front() {
+                    if first.len() > buf.len() {
+                        return Err(LxError::EINVAL);
+                    }
+                    // return as many complete events as possible
+                    let mut len = 0;
+                    while let Some(event) = inner.events.front() {
+                        if len + event.len() > buf.len() {
+                            break;
+                        }
+                        buf[len..len + event.len()].copy_from_slice(event);
+                        len += event.len();
+                        inner.events.pop_front();
+                    }
+                    if inner.events.is_empty() {
+                        drop(inner);
+                        self.eventbus.lock().clear(Event::READABLE);
+                    }
+                    return Ok(len);
+                }
+                if inner.flags.non_block() {
+                    return Err(LxError::EAGAIN);
+                }
+            }
+            wait_for_event(self.eventbus.clone(), Event::READABLE).await;
+        }
+    }
+
+    fn write(&self, _buf: &[u8]) -> LxResult<usize> {
+        Err(LxError::EINVAL)
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
+        Err(LxError::ESPIPE)
+    }
+
+    fn poll(&self, _events: PollEvents) -> LxResult<PollStatus> {
+        Ok(PollStatus {
+            read: self.can_read(),
+            write: false,
+            error: false,
+        })
+    }
+
+    async fn async_poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        if !self.can_read() {
+            wait_for_event(self.eventbus.clone(), Event::READABLE).await;
+        }
+        self.poll(events)
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, _arg2: usize, _arg3: usize) -> LxResult<usize> {
+        match request {
+            FIONREAD => {
+                let len: usize = self.inner.lock().events.iter().map(|e| e.len()).sum();
+                UserOutPtr::<i32>::from(arg1).write(len as i32)?;
+                Ok(0)
+            }
+            _ => Err(LxError::ENOTTY),
+        }
+    }
+}
+
+impl Inotify {
+    /// Cast a file to an inotify instance.
+    pub fn from_file_like(file: Arc<dyn FileLike>) -> LxResult<Arc<Self>> {
+        file.downcast_arc::<Self>().map_err(|_| LxError::EINVAL)
+    }
+}
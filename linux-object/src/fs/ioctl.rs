@@ -37,6 +37,11 @@ pub const FIOCLEX: usize = 0x5451;
 #[cfg(target_arch = "mips")]
 pub const FIOCLEX: usize = 0x6601;
 
+#[cfg(not(target_arch = "mips"))]
+pub const FIONREAD: usize = 0x541B;
+#[cfg(target_arch = "mips")]
+pub const FIONREAD: usize = 0x467F;
+
 // rustc using pipe and ioctl pipe file with this request id
 // for non-blocking/blocking IO control setting
 pub const FIONBIO: usize = 0x5421;
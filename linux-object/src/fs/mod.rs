@@ -2,11 +2,15 @@
 
 mod devfs;
 mod file;
-mod ioctl;
+pub mod inotify;
+pub(crate) mod ioctl;
+mod page_cache;
 mod pipe;
 mod pseudo;
 pub mod rcore_fs_wrapper;
 mod stdio;
+mod sysctl;
+pub mod xattr;
 
 #[cfg(feature = "mock-disk")]
 pub mod mock;
@@ -23,20 +27,21 @@ pub fn mock_block() -> mock::MockBlock {
     mock::MockBlock::new()
 }
 
-use alloc::{boxed::Box, string::ToString, sync::Arc, vec::Vec};
+use alloc::{boxed::Box, string::String, string::ToString, sync::Arc, vec::Vec};
 use core::convert::TryFrom;
 
 use async_trait::async_trait;
 use downcast_rs::impl_downcast;
 
 use kernel_hal::drivers;
-use rcore_fs::vfs::{FileSystem, FileType, INode, Result};
+use rcore_fs::vfs::{FileSystem, FileType, FsError, INode, Result};
 use rcore_fs_devfs::{
     special::{NullINode, ZeroINode},
     DevFS,
 };
 use rcore_fs_mountfs::MountFS;
 use rcore_fs_ramfs::RamFS;
+use smoltcp::wire::Ipv4Address;
 use zircon_object::{object::KernelObject, vm::VmObject};
 
 use crate::error::{LxError, LxResult};
@@ -44,9 +49,12 @@ use crate::net::Socket;
 use crate::process::LinuxProcess;
 use devfs::RandomINode;
 use pseudo::Pseudo;
+use sysctl::SysctlINode;
 
 pub use file::{File, OpenFlags, PollEvents, SeekFrom};
-pub use pipe::Pipe;
+pub use inotify::{Inotify, InotifyMask};
+pub use page_cache::{page_cache, PageCache, PageCacheStats};
+pub use pipe::{Pipe, PipeBuffer};
 pub use rcore_fs::vfs::{self, PollStatus};
 pub use stdio::{STDIN, STDOUT};
 
@@ -56,6 +64,7 @@ pub use stdio::{STDIN, STDOUT};
 /// - Normal file, Directory
 /// - Socket
 /// - Epoll instance
+/// - Inotify instance
 pub trait FileLike: KernelObject {
     /// Returns open flags.
     fn flags(&self) -> OpenFlags;
@@ -159,6 +168,9 @@ pub fn create_root_fs(rootfs: Arc<dyn FileSystem>) -> Arc<dyn INode> {
     devfs_root
         .add("shm", Arc::new(RandomINode::new(true)))
         .expect("failed to mknod /dev/shm");
+    devfs_root
+        .add("netfilter", Arc::new(devfs::NetfilterINode::new()))
+        .expect("failed to mknod /dev/netfilter");
     if let Some(display) = drivers::all_display().first() {
         use devfs::{EventDev, FbDev, MiceDev};
 
@@ -188,14 +200,35 @@ pub fn create_root_fs(rootfs: Arc<dyn FileSystem>) -> Arc<dyn INode> {
         }
     }
 
-    // Add uart devices at `/dev/ttyS{i}`
-    for (i, uart) in drivers::all_uart().as_vec().iter().enumerate() {
+    // Add uart devices at `/dev/ttyS{i}`, except ports of virtio-console
+    let uarts = drivers::all_uart().as_vec();
+    let is_hvc =
+        |uart: &&Arc<dyn drivers::scheme::UartScheme>| uart.name().starts_with("virtio-console");
+    for (i, uart) in uarts.iter().filter(|u| !is_hvc(u)).enumerate() {
         let fname = format!("ttyS{}", i);
         if let Err(e) = devfs_root.add(&fname, Arc::new(devfs::UartDev::new(i, uart.clone()))) {
             warn!("failed to mknod /dev/{}: {:?}", &fname, e);
         }
     }
 
+    // Add ports of virtio-console at `/dev/hvc{i}`
+    for (i, uart) in uarts.iter().filter(is_hvc).enumerate() {
+        let fname = format!("hvc{}", i);
+        let dev = devfs::UartDev::new_hvc(i, uart.clone());
+        if let Err(e) = devfs_root.add(&fname, Arc::new(dev)) {
+            warn!("failed to mknod /dev/{}: {:?}", &fname, e);
+        }
+    }
+    drop(uarts);
+
+    // Add real-time clock devices at `/dev/rtc{i}`
+    for (i, rtc) in drivers::all_rtc().as_vec().iter().enumerate() {
+        let fname = format!("rtc{}", i);
+        if let Err(e) = devfs_root.add(&fname, Arc::new(devfs::RtcDev::new(i, rtc.clone()))) {
+            warn!("failed to mknod /dev/{}: {:?}", &fname, e);
+        }
+    }
+
     // mount DevFS at /dev
     let dev = root.find(true, "dev").unwrap_or_else(|_| {
         root.create("dev", FileType::Dir, 0o666)
@@ -211,9 +244,54 @@ pub fn create_root_fs(rootfs: Arc<dyn FileSystem>) -> Arc<dyn INode> {
     });
     tmp.mount(ramfs).expect("failed to mount RamFS");
 
+    // extended attributes of RamFS, which lose them on restart like its files;
+    // the root file system can't store them
+    match root.find(true, "tmp") {
+        Ok(tmp) => xattr::register_store(tmp.as_ref(), xattr::XattrStore::new()),
+        Err(e) => warn!("failed to find /tmp: {:?}", e),
+    }
+
+    // DNS servers from the command line, and those from DHCP leases later
+    let hook_root = root.clone();
+    zcore_drivers::net::set_dns_hook(Some(Arc::new(move |servers: &[Ipv4Address]| {
+        update_resolv_conf(&hook_root, servers)
+    })));
+    update_resolv_conf(&root, &zcore_drivers::net::dns_servers());
+
     root
 }
 
+fn update_resolv_conf(root: &Arc<dyn INode>, servers: &[Ipv4Address]) {
+    if let Err(e) = write_resolv_conf(root, servers) {
+        warn!("failed to write /etc/resolv.conf: {:?}", e);
+    }
+}
+
+/// Write the DNS servers to `/etc/resolv.conf` through the page cache, leave
+/// it unchanged if there are none.
+fn write_resolv_conf(root: &Arc<dyn INode>, servers: &[Ipv4Address]) -> LxResult {
+    if servers.is_empty() {
+        return Ok(());
+    }
+    let mut content = String::new();
+    for server in servers {
+        content += &format!("nameserver {}\n", server);
+    }
+    let etc = match root.find(true, "etc") {
+        Ok(etc) => etc,
+        Err(_) => root.create("etc", FileType::Dir, 0o755)?,
+    };
+    let file = match etc.find(true, "resolv.conf") {
+        Ok(file) => file,
+        Err(_) => etc.create("resolv.conf", FileType::File, 0o644)?,
+    };
+    page_cache().truncate(&file, 0)?;
+    file.resize(0)?;
+    page_cache().write_at(&file, 0, content.as_bytes())?;
+    // visible to the host at once, e.g. on HostFS
+    page_cache().flush(file.as_ref())
+}
+
 /// extension for INode
 pub trait INodeExt {
     /// similar to read, but return a u8 vector
@@ -223,7 +301,12 @@ pub trait INodeExt {
 impl INodeExt for dyn INode {
     #[allow(unsafe_code, clippy::uninit_vec)]
     fn read_as_vec(&self) -> Result<Vec<u8>> {
-        let size = self.metadata()?.size;
+        let metadata = self.metadata()?;
+        let size = metadata.size;
+        if metadata.type_ == FileType::File {
+            // make sure the content written through the page cache is visible
+            page_cache().flush(self).map_err(|_| FsError::DeviceError)?;
+        }
         let mut buf = Vec::with_capacity(size);
         unsafe {
             buf.set_len(size);
@@ -271,6 +354,9 @@ impl LinuxProcess {
             let file = self.get_file(fd)?;
             return Ok(Arc::new(Pseudo::new(file.path(), FileType::SymLink)));
         }
+        if let Some(sysctl) = SysctlINode::lookup(path) {
+            return Ok(Arc::new(sysctl));
+        }
 
         let follow_max_depth = if follow { FOLLOW_MAX_DEPTH } else { 0 };
         if dirfd == FileDesc::CWD {
@@ -292,6 +378,16 @@ impl LinuxProcess {
     }
 }
 
+/// Identifies an inode: the address of its file system and the inode number.
+pub(crate) type InodeKey = (usize, usize);
+
+/// Returns the identity of `inode`, which is the same for all `INode` objects
+/// referring to the same file.
+pub(crate) fn inode_key(inode: &dyn INode) -> LxResult<InodeKey> {
+    let fs = Arc::as_ptr(&inode.fs()) as *const () as usize;
+    Ok((fs, inode.metadata()?.inode))
+}
+
 /// Split a `path` str to `(base_path, file_name)`
 pub fn split_path(path: &str) -> (&str, &str) {
     let mut split = path.trim_end_matches('/').rsplitn(2, '/');
@@ -0,0 +1,645 @@
+//! Global page cache for regular files.
+//!
+//! Pages are keyed by `(inode, page index)` and backed by single-page
+//! [`VmObject`]s, so the cache is shared by every open file of the same inode.
+//!
+//! - Reads go through the cache, with a growing readahead window for
+//!   sequential accesses.
+//! - Writes only dirty the cached pages (write-behind). Dirty pages are written
+//!   back periodically, on `fsync`, or when too many of them pile up.
+//! - Clean pages are evicted in LRU order when the cache grows too large, or
+//!   when the physical frame allocator runs out of memory.
+//! - Pages can be lent to pipes and other files for zero-copy transfers. A
+//!   shared page is copied before it's modified.
+
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use lazy_static::lazy_static;
+use lock::Mutex;
+use rcore_fs::vfs::INode;
+use zircon_object::vm::{VmObject, PAGE_SIZE};
+
+use super::{inode_key, InodeKey, PipeBuffer};
+use crate::error::LxResult;
+
+/// Maximum number of pages kept in the cache before LRU eviction kicks in.
+const MAX_CACHED_PAGES: usize = 0x4000; // 64 MiB
+/// Number of dirty pages that triggers a synchronous write-back.
+const MAX_DIRTY_PAGES: usize = 0x400; // 4 MiB
+/// Initial readahead window in pages.
+const READAHEAD_MIN: usize = 4;
+/// Maximum readahead window in pages.
+const READAHEAD_MAX: usize = 32;
+/// Interval of the periodic write-back.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A dirty page taken for write-back:
+/// `(inode, inode key, page index, page, valid length)`.
+type DirtyPage = (Arc<dyn INode>, InodeKey, usize, Arc<VmObject>, usize);
+
+/// A cached page of a file.
+struct CachedPage {
+    vmo: Arc<VmObject>,
+    /// Number of valid bytes in the page.
+    len: usize,
+    dirty: bool,
+    /// The page is being written back, so it's pinned in the cache.
+    writeback: bool,
+    /// Last access time, the key of the page in the LRU list.
+    stamp: u64,
+}
+
+impl CachedPage {
+    /// Copy the page if it's shared, so that it can be modified.
+    fn make_exclusive(&mut self) -> LxResult {
+        if Arc::strong_count(&self.vmo) > 1 {
+            let vmo = VmObject::new_paged(1);
+            let mut buf = [0u8; PAGE_SIZE];
+            self.vmo.read(0, &mut buf[..self.len])?;
+            vmo.write(0, &buf[..self.len])?;
+            self.vmo = vmo;
+        }
+        Ok(())
+    }
+}
+
+/// Cached pages of an inode.
+struct CachedInode {
+    inode: Arc<dyn INode>,
+    pages: BTreeMap<usize, CachedPage>,
+    /// The page index expected by the next sequential read.
+    next_index: usize,
+    /// Current readahead window in pages.
+    ra_window: usize,
+}
+
+#[derive(Default)]
+struct PageCacheInner {
+    inodes: BTreeMap<InodeKey, CachedInode>,
+    /// LRU list of all pages: access stamp -> (inode, page index).
+    lru: BTreeMap<u64, (InodeKey, usize)>,
+    stamp: u64,
+    nr_pages: usize,
+    nr_dirty: usize,
+}
+
+/// Statistics of the page cache, in bytes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PageCacheStats {
+    /// Memory used by cached pages.
+    pub cached: usize,
+    /// Memory used by dirty pages waiting for write-back.
+    pub dirty: usize,
+}
+
+/// The global page cache.
+pub struct PageCache {
+    inner: Mutex<PageCacheInner>,
+}
+
+lazy_static! {
+    static ref PAGE_CACHE: PageCache = {
+        kernel_hal::mem::register_frame_reclaimer(|pages| PAGE_CACHE.shrink(pages));
+        PageCache {
+            inner: Mutex::new(PageCacheInner::default()),
+        }
+    };
+}
+
+static FLUSHER_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Get the global page cache.
+pub fn page_cache() -> &'static PageCache {
+    if !FLUSHER_STARTED.swap(true, Ordering::SeqCst) {
+        kernel_hal::thread::spawn(async {
+            loop {
+                kernel_hal::thread::sleep_until(kernel_hal::timer::deadline_after(FLUSH_INTERVAL))
+                    .await;
+                if let Err(e) = PAGE_CACHE.flush_all() {
+                    warn!("page cache: periodic write-back failed: {:?}", e);
+                }
+            }
+        });
+    }
+    &PAGE_CACHE
+}
+
+impl PageCacheInner {
+    fn next_stamp(&mut self) -> u64 {
+        self.stamp += 1;
+        self.stamp
+    }
+
+    /// Mark a page as recently used.
+    fn touch(&mut self, key: InodeKey, index: usize) {
+        let stamp = self.next_stamp();
+        if let Some(page) = self
+            .inodes
+            .get_mut(&key)
+            .and_then(|c| c.pages.get_mut(&index))
+        {
+            self.lru.remove(&page.stamp);
+            page.stamp = stamp;
+            self.lru.insert(stamp, (key, index));
+        }
+    }
+
+    fn get(&mut self, key: InodeKey, index: usize) -> Option<(Arc<VmObject>, usize)> {
+        let page = self.inodes.get(&key)?.pages.get(&index)?;
+        let ret = (page.vmo.clone(), page.len);
+        self.touch(key, index);
+        Some(ret)
+    }
+
+    /// Insert a page, unless someone else has done it, and returns the page
+    /// in the cache.
+    ///
+    /// Clean pages are evicted first if the cache is full, so the new page is
+    /// never evicted right away.
+    fn insert(
+        &mut self,
+        inode: &Arc<dyn INode>,
+        key: InodeKey,
+        index: usize,
+        vmo: Arc<VmObject>,
+        len: usize,
+        dirty: bool,
+    ) -> (Arc<VmObject>, usize) {
+        if let Some(page) = self.get(key, index) {
+            return page;
+        }
+        if self.nr_pages >= MAX_CACHED_PAGES {
+            let excess = self.nr_pages + 1 - MAX_CACHED_PAGES;
+            self.evict(excess);
+        }
+        let stamp = self.next_stamp();
+        let cached = self.inodes.entry(key).or_insert_with(|| CachedInode {
+            inode: inode.clone(),
+            pages: BTreeMap::new(),
+            next_index: 0,
+            ra_window: READAHEAD_MIN,
+        });
+        cached.pages.insert(
+            index,
+            CachedPage {
+                vmo: vmo.clone(),
+                len,
+                dirty,
+                writeback: false,
+                stamp,
+            },
+        );
+        self.lru.insert(stamp, (key, index));
+        self.nr_pages += 1;
+        if dirty {
+            self.nr_dirty += 1;
+        }
+        (vmo, len)
+    }
+
+    fn remove(&mut self, key: InodeKey, index: usize) -> Option<CachedPage> {
+        let cached = self.inodes.get_mut(&key)?;
+        let page = cached.pages.remove(&index)?;
+        if cached.pages.is_empty() {
+            self.inodes.remove(&key);
+        }
+        self.lru.remove(&page.stamp);
+        self.nr_pages -= 1;
+        if page.dirty {
+            self.nr_dirty -= 1;
+        }
+        Some(page)
+    }
+
+    /// Evict at most `count` clean pages in LRU order, returns the number of evicted pages.
+    fn evict(&mut self, count: usize) -> usize {
+        let victims: Vec<_> = self
+            .lru
+            .values()
+            .copied()
+            .filter(|(key, index)| {
+                let page = &self.inodes[key].pages[index];
+                !page.dirty && !page.writeback
+            })
+            .take(count)
+            .collect();
+        for &(key, index) in victims.iter() {
+            self.remove(key, index);
+        }
+        victims.len()
+    }
+
+    /// Collect dirty pages of an inode (or all inodes) for write-back, and pin
+    /// them until [`finish_write_back`](Self::finish_write_back).
+    ///
+    /// Pages already being written back are skipped.
+    fn take_dirty(&mut self, key: Option<InodeKey>) -> Vec<DirtyPage> {
+        let mut dirty = Vec::new();
+        for (&k, cached) in self
+            .inodes
+            .iter_mut()
+            .filter(|(k, _)| key.map_or(true, |key| **k == key))
+        {
+            for (&index, page) in cached.pages.iter_mut() {
+                if page.dirty && !page.writeback {
+                    page.writeback = true;
+                    let vmo = page.vmo.clone();
+                    dirty.push((cached.inode.clone(), k, index, vmo, page.len));
+                }
+            }
+        }
+        dirty
+    }
+
+    /// Unpin a page taken for write-back, and mark it clean if `vmo` has been
+    /// written successfully and the page has not been modified since.
+    ///
+    /// The page is modified in a copy while it's being written back, see
+    /// [`CachedPage::make_exclusive`].
+    fn finish_write_back(&mut self, key: InodeKey, index: usize, vmo: &Arc<VmObject>, ok: bool) {
+        let page = match self
+            .inodes
+            .get_mut(&key)
+            .and_then(|c| c.pages.get_mut(&index))
+        {
+            Some(page) => page,
+            // truncated in the meantime
+            None => return,
+        };
+        page.writeback = false;
+        if ok && page.dirty && Arc::ptr_eq(&page.vmo, vmo) {
+            page.dirty = false;
+            self.nr_dirty -= 1;
+        }
+    }
+}
+
+impl PageCache {
+    /// Load a page from the inode into a new [`VmObject`].
+    fn load_page(
+        inode: &Arc<dyn INode>,
+        index: usize,
+        size: usize,
+    ) -> LxResult<(Arc<VmObject>, usize)> {
+        let vmo = VmObject::new_paged(1);
+        let mut buf = [0u8; PAGE_SIZE];
+        let len = size.saturating_sub(index * PAGE_SIZE).min(PAGE_SIZE);
+        let len = inode.read_at(index * PAGE_SIZE, &mut buf[..len])?;
+        vmo.write(0, &buf[..len])?;
+        Ok((vmo, len))
+    }
+
+    /// Get a cached page, or load it from the inode.
+    fn get_or_load(
+        &self,
+        inode: &Arc<dyn INode>,
+        key: InodeKey,
+        index: usize,
+        size: usize,
+    ) -> LxResult<(Arc<VmObject>, usize)> {
+        if let Some(page) = self.inner.lock().get(key, index) {
+            return Ok(page);
+        }
+        // do the I/O without holding the lock
+        let (vmo, len) = Self::load_page(inode, index, size)?;
+        Ok(self.inner.lock().insert(inode, key, index, vmo, len, false))
+    }
+
+    /// Read pages after `last` into the cache if `first..=last` continues a
+    /// sequential read, so that the following reads hit the cache.
+    ///
+    /// The pages are read synchronously, right after the read they follow.
+    fn readahead(
+        &self,
+        inode: &Arc<dyn INode>,
+        key: InodeKey,
+        first: usize,
+        last: usize,
+        size: usize,
+    ) {
+        let (start, end) = {
+            let mut inner = self.inner.lock();
+            let cached = match inner.inodes.get_mut(&key) {
+                Some(cached) => cached,
+                None => return,
+            };
+            let sequential = first == cached.next_index;
+            cached.next_index = last + 1;
+            if !sequential {
+                cached.ra_window = READAHEAD_MIN;
+                return;
+            }
+            let window = cached.ra_window;
+            cached.ra_window = (window * 2).min(READAHEAD_MAX);
+            let end = (last + 1 + window).min((size + PAGE_SIZE - 1) / PAGE_SIZE);
+            (last + 1, end)
+        };
+        for index in start..end {
+            if self.get_or_load(inode, key, index, size).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Read from `inode` at `offset` through the cache.
+    pub fn read_at(
+        &self,
+        inode: &Arc<dyn INode>,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> LxResult<usize> {
+        let key = inode_key(inode.as_ref())?;
+        let size = inode.metadata()?.size;
+        if offset >= size || buf.is_empty() {
+            return Ok(0);
+        }
+        let end = size.min(offset + buf.len());
+        let (first, last) = (offset / PAGE_SIZE, (end - 1) / PAGE_SIZE);
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / PAGE_SIZE;
+            let page_off = pos % PAGE_SIZE;
+            // bytes beyond the valid length of a page are zero, as on the inode
+            let (vmo, _) = self.get_or_load(inode, key, index, size)?;
+            let n = (end - pos).min(PAGE_SIZE - page_off);
+            let dst = &mut buf[pos - offset..pos - offset + n];
+            vmo.read(page_off, dst)?;
+            pos += n;
+        }
+        self.readahead(inode, key, first, last, size);
+        Ok(pos - offset)
+    }
+
+    /// Write to `inode` at `offset` through the cache.
+    ///
+    /// Data is written back to the inode later, but the file size is updated at once.
+    pub fn write_at(&self, inode: &Arc<dyn INode>, offset: usize, buf: &[u8]) -> LxResult<usize> {
+        let key = inode_key(inode.as_ref())?;
+        let size = inode.metadata()?.size;
+        let end = offset + buf.len();
+        let mut pos = offset;
+        while pos < end {
+            let index = pos / PAGE_SIZE;
+            let page_off = pos % PAGE_SIZE;
+            let n = (end - pos).min(PAGE_SIZE - page_off);
+            // partially overwritten pages must be loaded first
+            self.get_or_load(inode, key, index, size)?;
+            let mut inner = self.inner.lock();
+            let page = match inner
+                .inodes
+                .get_mut(&key)
+                .and_then(|c| c.pages.get_mut(&index))
+            {
+                Some(page) => page,
+                // evicted in the meantime, try again
+                None => continue,
+            };
+            page.make_exclusive()?;
+            page.vmo
+                .write(page_off, &buf[pos - offset..pos - offset + n])?;
+            page.len = page.len.max(page_off + n);
+            if !page.dirty {
+                page.dirty = true;
+                inner.nr_dirty += 1;
+            }
+            inner.touch(key, index);
+            pos += n;
+        }
+        if end > size {
+            inode.resize(end)?;
+        }
+        if self.inner.lock().nr_dirty > MAX_DIRTY_PAGES {
+            self.flush_all()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Get references to the cached pages of `inode` at `offset`, for zero-copy
+    /// transfers of up to `len` bytes.
+    pub fn get_pages(
+        &self,
+        inode: &Arc<dyn INode>,
+        offset: usize,
+        len: usize,
+    ) -> LxResult<Vec<PipeBuffer>> {
+        let key = inode_key(inode.as_ref())?;
+        let size = inode.metadata()?.size;
+        if offset >= size || len == 0 {
+            return Ok(Vec::new());
+        }
+        let end = size.min(offset + len);
+        let (first, last) = (offset / PAGE_SIZE, (end - 1) / PAGE_SIZE);
+        let mut bufs = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let page_off = pos % PAGE_SIZE;
+            let (vmo, _) = self.get_or_load(inode, key, pos / PAGE_SIZE, size)?;
+            let n = (end - pos).min(PAGE_SIZE - page_off);
+            bufs.push(PipeBuffer::new(vmo, page_off, n));
+            pos += n;
+        }
+        self.readahead(inode, key, first, last, size);
+        Ok(bufs)
+    }
+
+    /// Write a page reference to `inode` at `offset`.
+    ///
+    /// A whole page at a page-aligned offset is shared with the cache instead
+    /// of being copied.
+    pub fn write_page(
+        &self,
+        inode: &Arc<dyn INode>,
+        offset: usize,
+        buf: &PipeBuffer,
+    ) -> LxResult<usize> {
+        if offset % PAGE_SIZE != 0 || buf.offset() != 0 || buf.len() != PAGE_SIZE {
+            return self.write_at(inode, offset, buf.as_slice()?);
+        }
+        let key = inode_key(inode.as_ref())?;
+        let size = inode.metadata()?.size;
+        let index = offset / PAGE_SIZE;
+        {
+            let mut inner = self.inner.lock();
+            // the old page is overwritten entirely
+            inner.remove(key, index);
+            inner.insert(inode, key, index, buf.page().clone(), PAGE_SIZE, true);
+        }
+        if offset + PAGE_SIZE > size {
+            inode.resize(offset + PAGE_SIZE)?;
+        }
+        if self.inner.lock().nr_dirty > MAX_DIRTY_PAGES {
+            self.flush_all()?;
+        }
+        Ok(PAGE_SIZE)
+    }
+
+    /// Write back pages taken by [`PageCacheInner::take_dirty`], pages failed
+    /// to be written stay dirty. Returns the first error.
+    fn write_back(&self, dirty: Vec<DirtyPage>) -> LxResult {
+        let mut buf = [0u8; PAGE_SIZE];
+        let mut ret = Ok(());
+        for (inode, key, index, vmo, len) in dirty {
+            let res = Self::write_page_back(&inode, index, &vmo, &mut buf[..len]);
+            self.inner
+                .lock()
+                .finish_write_back(key, index, &vmo, res.is_ok());
+            if ret.is_ok() {
+                ret = res;
+            }
+        }
+        ret
+    }
+
+    fn write_page_back(
+        inode: &Arc<dyn INode>,
+        index: usize,
+        vmo: &VmObject,
+        buf: &mut [u8],
+    ) -> LxResult {
+        vmo.read(0, buf)?;
+        inode.write_at(index * PAGE_SIZE, buf)?;
+        Ok(())
+    }
+
+    /// Write back all dirty pages of `inode`.
+    pub fn flush(&self, inode: &dyn INode) -> LxResult {
+        let key = inode_key(inode)?;
+        let dirty = self.inner.lock().take_dirty(Some(key));
+        self.write_back(dirty)
+    }
+
+    /// Write back all dirty pages in the cache.
+    pub fn flush_all(&self) -> LxResult {
+        let dirty = self.inner.lock().take_dirty(None);
+        self.write_back(dirty)
+    }
+
+    /// Write back and drop all cached pages of `inode`, e.g. when its last
+    /// link is removed, so that a new file reusing the inode number doesn't
+    /// see them.
+    pub fn forget(&self, inode: &dyn INode) -> LxResult {
+        self.flush(inode)?;
+        let key = inode_key(inode)?;
+        let mut inner = self.inner.lock();
+        let indices: Vec<usize> = match inner.inodes.get(&key) {
+            Some(cached) => cached.pages.keys().copied().collect(),
+            None => return Ok(()),
+        };
+        for index in indices {
+            inner.remove(key, index);
+        }
+        Ok(())
+    }
+
+    /// Drop cached data of `inode` beyond `len`, e.g. on truncation.
+    pub fn truncate(&self, inode: &Arc<dyn INode>, len: usize) -> LxResult {
+        let key = inode_key(inode.as_ref())?;
+        let mut inner = self.inner.lock();
+        let indices: Vec<usize> = match inner.inodes.get(&key) {
+            Some(cached) => cached
+                .pages
+                .range(len / PAGE_SIZE..)
+                .map(|(&i, _)| i)
+                .collect(),
+            None => return Ok(()),
+        };
+        for index in indices {
+            let page_off = len.saturating_sub(index * PAGE_SIZE);
+            if page_off == 0 {
+                inner.remove(key, index);
+            } else if let Some(page) = inner
+                .inodes
+                .get_mut(&key)
+                .and_then(|c| c.pages.get_mut(&index))
+            {
+                if page.len > page_off {
+                    page.make_exclusive()?;
+                    page.vmo.zero(page_off, page.len - page_off)?;
+                    page.len = page_off;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evict up to `pages` clean pages, returns the number of evicted pages.
+    ///
+    /// Called by the frame allocator under memory pressure, so never blocks.
+    pub fn shrink(&self, pages: usize) -> usize {
+        match self.inner.try_lock() {
+            Some(mut inner) => inner.evict(pages),
+            None => 0,
+        }
+    }
+
+    /// Returns statistics of the page cache.
+    pub fn stats(&self) -> PageCacheStats {
+        let inner = self.inner.lock();
+        PageCacheStats {
+            cached: inner.nr_pages * PAGE_SIZE,
+            dirty: inner.nr_dirty * PAGE_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcore_fs::vfs::FileType;
+    use rcore_fs_ramfs::RamFS;
+
+    /// A file in a new RamFS, returned with the file system to keep it alive.
+    fn new_file() -> (Arc<RamFS>, Arc<dyn INode>, InodeKey) {
+        let fs = RamFS::new();
+        let file = fs
+            .root_inode()
+            .create("file", FileType::File, 0o644)
+            .unwrap();
+        let key = inode_key(file.as_ref()).unwrap();
+        (fs, file, key)
+    }
+
+    #[test]
+    fn insert_existing() {
+        let (_fs, file, key) = new_file();
+        let mut inner = PageCacheInner::default();
+        let vmo = VmObject::new_paged(1);
+        inner.insert(&file, key, 0, vmo.clone(), 1, false);
+        let (page, len) = inner.insert(&file, key, 0, VmObject::new_paged(1), 2, false);
+        assert!(Arc::ptr_eq(&page, &vmo));
+        assert_eq!(len, 1);
+        assert_eq!(inner.nr_pages, 1);
+    }
+
+    #[test]
+    fn evict_all_dirty() {
+        let (_fs, file, key) = new_file();
+        let mut inner = PageCacheInner::default();
+        for index in 0..MAX_CACHED_PAGES {
+            inner.insert(&file, key, index, VmObject::new_paged(1), PAGE_SIZE, true);
+        }
+        assert_eq!(inner.evict(1), 0);
+        assert_eq!(inner.nr_dirty, MAX_CACHED_PAGES);
+
+        // no page can be evicted, the cache grows beyond the limit
+        let cache = PageCache {
+            inner: Mutex::new(inner),
+        };
+        let (vmo, len) = cache.get_or_load(&file, key, MAX_CACHED_PAGES, 0).unwrap();
+        assert_eq!(len, 0);
+        let mut inner = cache.inner.lock();
+        assert_eq!(inner.nr_pages, MAX_CACHED_PAGES + 1);
+        let (page, _) = inner.get(key, MAX_CACHED_PAGES).unwrap();
+        assert!(Arc::ptr_eq(&page, &vmo));
+
+        // the clean page is evicted for the next one
+        let vmo = VmObject::new_paged(1);
+        inner.insert(&file, key, MAX_CACHED_PAGES + 1, vmo, PAGE_SIZE, true);
+        assert!(inner.get(key, MAX_CACHED_PAGES).is_none());
+        assert!(inner.get(key, MAX_CACHED_PAGES + 1).is_some());
+        assert_eq!(inner.nr_pages, MAX_CACHED_PAGES + 1);
+        assert_eq!(inner.nr_dirty, MAX_CACHED_PAGES + 1);
+    }
+}
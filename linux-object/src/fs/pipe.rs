@@ -1,16 +1,131 @@
 //! Implement INode for Pipe
 #![deny(missing_docs)]
 
+use crate::error::{LxError, LxResult};
 use crate::{sync::Event, sync::EventBus};
-use alloc::{boxed::Box, collections::vec_deque::VecDeque, sync::Arc};
+use alloc::{boxed::Box, collections::vec_deque::VecDeque, sync::Arc, vec::Vec};
 use core::{any::Any, cmp::min};
 use core::{
     future::Future,
     pin::Pin,
     task::{Context, Poll},
 };
-use lock::Mutex;
+use kernel_hal::mem::phys_to_virt;
+use lock::{Mutex, MutexGuard};
 use rcore_fs::vfs::*;
+use zircon_object::vm::{MMUFlags, VmObject, PAGE_SIZE};
+
+/// A reference to a range of a page, the unit of data in a pipe.
+///
+/// The page may be shared with the page cache or other pipes, so data can be
+/// moved by passing the references around instead of copying. A shared page
+/// is never modified: writers copy it first.
+#[derive(Clone)]
+pub struct PipeBuffer {
+    page: Arc<VmObject>,
+    offset: usize,
+    len: usize,
+}
+
+impl PipeBuffer {
+    /// Create a reference to `len` bytes at `offset` of a single-page `page`.
+    pub fn new(page: Arc<VmObject>, offset: usize, len: usize) -> Self {
+        assert!(offset + len <= PAGE_SIZE);
+        PipeBuffer { page, offset, len }
+    }
+
+    /// Allocate an empty buffer on a new page.
+    pub fn alloc() -> Self {
+        Self::new(VmObject::new_paged(1), 0, 0)
+    }
+
+    /// The page referred to.
+    pub fn page(&self) -> &Arc<VmObject> {
+        &self.page
+    }
+
+    /// Offset of the data in the page.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Length of the data in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the page is referred only by this buffer, so it can be modified.
+    pub fn is_exclusive(&self) -> bool {
+        Arc::strong_count(&self.page) == 1
+    }
+
+    /// Remaining space in the page after the data.
+    fn room(&self) -> usize {
+        PAGE_SIZE - self.offset - self.len
+    }
+
+    /// Returns the data without copying.
+    pub fn as_slice(&self) -> LxResult<&[u8]> {
+        let paddr = self.page.commit_page(0, MMUFlags::READ)?;
+        let vaddr = phys_to_virt(paddr) + self.offset;
+        // the page is kept alive by `self.page`
+        Ok(unsafe { core::slice::from_raw_parts(vaddr as *const u8, self.len) })
+    }
+
+    /// Returns the data and the remaining space of the page, for filling the buffer in place.
+    ///
+    /// Call [`set_len`](Self::set_len) to update the length after filling.
+    pub fn as_mut_slice(&mut self) -> LxResult<&mut [u8]> {
+        if !self.is_exclusive() {
+            return Err(LxError::EBUSY);
+        }
+        let paddr = self.page.commit_page(0, MMUFlags::WRITE)?;
+        let vaddr = phys_to_virt(paddr) + self.offset;
+        let len = PAGE_SIZE - self.offset;
+        Ok(unsafe { core::slice::from_raw_parts_mut(vaddr as *mut u8, len) })
+    }
+
+    /// Set the length of the data.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(self.offset + len <= PAGE_SIZE);
+        self.len = len;
+    }
+
+    /// Copy the data to `buf`, returns the number of bytes copied.
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        let len = min(buf.len(), self.len);
+        self.page
+            .read(self.offset, &mut buf[..len])
+            .map_err(|_| FsError::NoDeviceSpace)?;
+        Ok(len)
+    }
+
+    /// Append `buf` to the data, returns the number of bytes appended.
+    fn append(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = min(buf.len(), self.room());
+        self.page
+            .write(self.offset + self.len, &buf[..len])
+            .map_err(|_| FsError::NoDeviceSpace)?;
+        self.len += len;
+        Ok(len)
+    }
+
+    /// Drop the first `len` bytes of data.
+    fn advance(&mut self, len: usize) {
+        self.offset += len;
+        self.len -= len;
+    }
+
+    /// Returns a buffer referring to the first `len` bytes of data.
+    fn head(&self, len: usize) -> Self {
+        Self::new(self.page.clone(), self.offset, min(len, self.len))
+    }
+}
 
 #[derive(Clone, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -24,14 +139,55 @@ pub enum PipeEnd {
 
 /// Pipe inner data
 pub struct PipeData {
-    /// pipe buffer
-    buf: VecDeque<u8>,
+    /// pipe buffer, a queue of page references
+    buf: VecDeque<PipeBuffer>,
+    /// number of bytes in the buffer
+    len: usize,
     /// event bus for pipe
     eventbus: EventBus,
     /// number of pipe ends
     end_cnt: i32,
 }
 
+impl PipeData {
+    /// Returns references to the first `len` bytes.
+    fn peek(&self, len: usize) -> Vec<PipeBuffer> {
+        let mut bufs = Vec::new();
+        let mut total = 0;
+        for buf in self.buf.iter() {
+            if total == len {
+                break;
+            }
+            let head = buf.head(len - total);
+            total += head.len;
+            bufs.push(head);
+        }
+        bufs
+    }
+
+    /// Remove the first `len` bytes, returns the number of bytes removed.
+    fn consume(&mut self, len: usize) -> usize {
+        let mut consumed = 0;
+        while consumed < len {
+            let front = match self.buf.front_mut() {
+                Some(front) => front,
+                None => break,
+            };
+            let n = min(len - consumed, front.len);
+            front.advance(n);
+            if front.is_empty() {
+                self.buf.pop_front();
+            }
+            consumed += n;
+        }
+        self.len -= consumed;
+        if self.len == 0 {
+            self.eventbus.clear(Event::READABLE);
+        }
+        consumed
+    }
+}
+
 /// pipe struct
 #[derive(Clone)]
 pub struct Pipe {
@@ -54,6 +210,7 @@ impl Pipe {
     pub fn create_pair() -> (Pipe, Pipe) {
         let inner = PipeData {
             buf: VecDeque::new(),
+            len: 0,
             eventbus: EventBus::default(),
             end_cnt: 2, // one read, one write
         };
@@ -74,12 +231,80 @@ impl Pipe {
         if let PipeEnd::Read = self.direction {
             // true
             let data = self.data.lock();
-            !data.buf.is_empty() || data.end_cnt < 2 // other end closed
+            data.len != 0 || data.end_cnt < 2 // other end closed
         } else {
             false
         }
     }
 
+    /// Lock the buffer of the read end, fails if there's nothing to read.
+    fn lock_readable(&self) -> Result<MutexGuard<'_, PipeData>> {
+        if self.direction != PipeEnd::Read {
+            return Err(FsError::InvalidParam);
+        }
+        let data = self.data.lock();
+        if data.len == 0 && data.end_cnt == 2 {
+            return Err(FsError::Again);
+        }
+        Ok(data)
+    }
+
+    /// Take up to `len` bytes from the read end as page references.
+    ///
+    /// Returns [`FsError::Again`] if the pipe is empty and the write end is open.
+    pub fn take_buffers(&self, len: usize) -> Result<Vec<PipeBuffer>> {
+        let mut data = self.lock_readable()?;
+        let bufs = data.peek(len);
+        data.consume(bufs.iter().map(|buf| buf.len).sum());
+        Ok(bufs)
+    }
+
+    /// Get up to `len` bytes from the read end as page references, without
+    /// removing them from the pipe.
+    ///
+    /// Returns [`FsError::Again`] if the pipe is empty and the write end is open.
+    pub fn peek_buffers(&self, len: usize) -> Result<Vec<PipeBuffer>> {
+        Ok(self.lock_readable()?.peek(len))
+    }
+
+    /// Pass up to `len` bytes of the read end to `f` as page references, and
+    /// remove the number of bytes it returns.
+    ///
+    /// The pipe is locked until the bytes are removed, so no other reader can
+    /// get the same data. Returns [`FsError::Again`] if the pipe is empty and
+    /// the write end is open.
+    pub fn read_buffers_with<E: From<FsError>>(
+        &self,
+        len: usize,
+        f: impl FnOnce(&[PipeBuffer]) -> core::result::Result<usize, E>,
+    ) -> core::result::Result<usize, E> {
+        let mut data = self.lock_readable()?;
+        let bufs = data.peek(len);
+        let n = f(&bufs)?;
+        Ok(data.consume(n))
+    }
+
+    /// Whether `self` and `other` are ends of the same pipe.
+    pub fn is_same_pipe(&self, other: &Pipe) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+
+    /// Append page references to the write end, returns the number of bytes.
+    pub fn push_buffers(&self, bufs: Vec<PipeBuffer>) -> Result<usize> {
+        if self.direction != PipeEnd::Write {
+            return Err(FsError::InvalidParam);
+        }
+        let mut data = self.data.lock();
+        let len = bufs.iter().map(|buf| buf.len).sum();
+        data.buf
+            .extend(bufs.into_iter().filter(|buf| !buf.is_empty()));
+        data.len += len;
+        if len != 0 {
+            data.eventbus.set(Event::READABLE);
+        }
+        Ok(len)
+    }
+
     /// whether the pipe struct is writeable
     fn can_write(&self) -> bool {
         if let PipeEnd::Write = self.direction {
@@ -98,16 +323,17 @@ impl INode for Pipe {
         }
         if let PipeEnd::Read = self.direction {
             let mut data = self.data.lock();
-            if data.buf.is_empty() && data.end_cnt == 2 {
+            if data.len == 0 && data.end_cnt == 2 {
                 Err(FsError::Again)
             } else {
-                let len = min(buf.len(), data.buf.len());
-                for item in buf.iter_mut().take(len) {
-                    *item = data.buf.pop_front().unwrap();
-                }
-                if data.buf.is_empty() {
-                    data.eventbus.clear(Event::READABLE);
+                let mut len = 0;
+                for front in data.buf.iter() {
+                    if len == buf.len() {
+                        break;
+                    }
+                    len += front.read(&mut buf[len..])?;
                 }
+                data.consume(len);
                 Ok(len)
             }
         } else {
@@ -119,11 +345,19 @@ impl INode for Pipe {
     fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
         if let PipeEnd::Write = self.direction {
             let mut data = self.data.lock();
-            for c in buf {
-                data.buf.push_back(*c);
+            let mut len = 0;
+            while len < buf.len() {
+                // fill the last page if it's not shared
+                match data.buf.back_mut() {
+                    Some(back) if back.is_exclusive() && back.room() != 0 => {
+                        len += back.append(&buf[len..])?;
+                    }
+                    _ => data.buf.push_back(PipeBuffer::alloc()),
+                }
             }
+            data.len += len;
             data.eventbus.set(Event::READABLE);
-            Ok(buf.len())
+            Ok(len)
         } else {
             Ok(0)
         }
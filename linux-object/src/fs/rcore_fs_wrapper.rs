@@ -1,11 +1,11 @@
 //! Device wrappers that implement `rcore_fs::dev::Device`, which can loaded
 //! file systems on (e.g. `rcore_fs_sfs::SimpleFileSystem::open()`).
 
-use alloc::sync::Arc;
+use alloc::{sync::Arc, vec, vec::Vec};
 
 extern crate rcore_fs;
 
-use kernel_hal::drivers::scheme::BlockScheme;
+use kernel_hal::drivers::scheme::{BlockRequest, BlockScheme};
 use lock::RwLock;
 use rcore_fs::dev::{BlockDevice, DevError, Device, Result};
 
@@ -41,6 +41,9 @@ impl Device for MemBuf {
 }
 
 /// Block device implements [`BlockScheme`].
+///
+/// Requests are submitted to the device queue, and the CPU sleeps until an
+/// interrupt while waiting for the completion, instead of spinning.
 pub struct Block(Arc<dyn BlockScheme>);
 
 impl Block {
@@ -48,17 +51,31 @@ impl Block {
     pub fn new(block: Arc<dyn BlockScheme>) -> Self {
         Self(block)
     }
+
+    fn io(&self, req: BlockRequest) -> Result<Vec<Vec<u8>>> {
+        let dev = &self.0;
+        let (req, res) = dev.submit(req).wait_with(|| {
+            // woken up by the completion, or the next timer interrupt if the
+            // completion came before sleeping
+            kernel_hal::interrupt::wait_for_interrupt();
+            dev.poll_completions();
+        });
+        res.map(|_| req.bufs).map_err(|_| DevError)
+    }
 }
 
 impl BlockDevice for Block {
     const BLOCK_SIZE_LOG2: u8 = 9; // 512
 
     fn read_at(&self, block_id: usize, buf: &mut [u8]) -> Result<()> {
-        self.0.read_block(block_id, buf).map_err(|_| DevError)
+        let bufs = self.io(BlockRequest::read(block_id, vec![vec![0; buf.len()]]))?;
+        buf.copy_from_slice(&bufs[0]);
+        Ok(())
     }
 
     fn write_at(&self, block_id: usize, buf: &[u8]) -> Result<()> {
-        self.0.write_block(block_id, buf).map_err(|_| DevError)
+        self.io(BlockRequest::write(block_id, vec![buf.to_vec()]))?;
+        Ok(())
     }
 
     fn sync(&self) -> Result<()> {
@@ -29,6 +29,8 @@ lazy_static! {
                 false,
             );
         }
+        let cloned = stdin.clone();
+        console::graphic_console_subscribe(Arc::new(move |c| cloned.push(c as char)));
         stdin
     };
     /// STDOUT global reference
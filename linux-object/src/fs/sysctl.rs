@@ -0,0 +1,99 @@
+//! Files in `/proc/sys`, which read and write kernel parameters
+
+use alloc::{format, string::String};
+use core::any::Any;
+
+use rcore_fs::vfs::*;
+
+use crate::error::{LxError, LxResult};
+use crate::net::port;
+
+/// A kernel parameter: its path under `/proc/sys`, and the functions which
+/// format and parse its value.
+struct Sysctl {
+    path: &'static str,
+    read: fn() -> String,
+    write: fn(&str) -> LxResult,
+}
+
+const SYSCTLS: &[Sysctl] = &[Sysctl {
+    path: "net/ipv4/ip_local_port_range",
+    read: || {
+        let (low, high) = port::ip_local_port_range();
+        format!("{}\t{}\n", low, high)
+    },
+    write: |value| {
+        let mut ports = value.split_whitespace().map(str::parse::<u16>);
+        match (ports.next(), ports.next(), ports.next()) {
+            (Some(Ok(low)), Some(Ok(high)), None) => port::set_ip_local_port_range(low, high),
+            _ => Err(LxError::EINVAL),
+        }
+    },
+}];
+
+/// The file of a kernel parameter.
+///
+/// It doesn't go through the page cache, as the value is generated on each
+/// read.
+pub struct SysctlINode {
+    sysctl: &'static Sysctl,
+}
+
+impl SysctlINode {
+    /// The file of the kernel parameter at `path`, if any.
+    pub fn lookup(path: &str) -> Option<Self> {
+        let path = path.strip_prefix("/proc/sys/")?;
+        let sysctl = SYSCTLS.iter().find(|sysctl| sysctl.path == path)?;
+        Some(SysctlINode { sysctl })
+    }
+}
+
+impl INode for SysctlINode {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let value = (self.sysctl.read)();
+        let value = value.as_bytes();
+        if offset >= value.len() {
+            return Ok(0);
+        }
+        let len = buf.len().min(value.len() - offset);
+        buf[..len].copy_from_slice(&value[offset..offset + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        let value = core::str::from_utf8(buf).map_err(|_| FsError::InvalidParam)?;
+        (self.sysctl.write)(value).map_err(|_| FsError::InvalidParam)?;
+        Ok(buf.len())
+    }
+
+    fn poll(&self) -> Result<PollStatus> {
+        Ok(PollStatus {
+            read: true,
+            write: true,
+            error: false,
+        })
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        Ok(Metadata {
+            dev: 0,
+            inode: 0,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_: FileType::File,
+            mode: 0o644,
+            nlinks: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+        })
+    }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,385 @@
+//! Extended attributes of files
+//!
+//! The underlying file systems don't support extended attributes, so they are
+//! kept in memory by an [`XattrStore`] registered for each file system, and
+//! lost when the system is restarted. Stores are only registered for file
+//! systems in memory, such as the RamFS at `/tmp`.
+//!
+//! File systems without a registered store, including the root file system
+//! whose files persist, don't support extended attributes: the calls fail
+//! with `EOPNOTSUPP`.
+#![deny(missing_docs)]
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+
+use lazy_static::lazy_static;
+use lock::Mutex;
+use rcore_fs::vfs::{FileType, INode, Metadata};
+
+use crate::error::{LxError, LxResult};
+use crate::process::Credentials;
+
+/// Maximum length of an attribute name.
+pub const XATTR_NAME_MAX: usize = 255;
+/// Maximum size of an attribute value.
+pub const XATTR_SIZE_MAX: usize = 65536;
+/// Maximum size of the attribute name list.
+pub const XATTR_LIST_MAX: usize = 65536;
+
+bitflags::bitflags! {
+    /// Flags of `setxattr`
+    pub struct XattrFlags: usize {
+        /// Fail if the attribute already exists
+        const CREATE = 1;
+        /// Fail if the attribute does not exist
+        const REPLACE = 2;
+    }
+}
+
+/// Namespaces of extended attributes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XattrNamespace {
+    /// `user.*`: arbitrary attributes of regular files and directories
+    User,
+    /// `trusted.*`: visible and accessible only to privileged processes
+    Trusted,
+    /// `security.*`: used by security modules, writable by privileged processes
+    Security,
+}
+
+impl XattrNamespace {
+    /// Parse the namespace of an attribute name.
+    pub fn from_name(name: &str) -> LxResult<Self> {
+        if name.is_empty() || name.len() > XATTR_NAME_MAX {
+            return Err(LxError::ERANGE);
+        }
+        let (prefix, suffix) = name.split_once('.').ok_or(LxError::EOPNOTSUPP)?;
+        if suffix.is_empty() {
+            return Err(LxError::EINVAL);
+        }
+        match prefix {
+            "user" => Ok(Self::User),
+            "trusted" => Ok(Self::Trusted),
+            "security" => Ok(Self::Security),
+            // `system.*` is used for POSIX ACLs, which are not supported
+            _ => Err(LxError::EOPNOTSUPP),
+        }
+    }
+
+    /// Check whether a process with `cred` can read or write an attribute in
+    /// the namespace.
+    ///
+    /// A privileged process has `CAP_SYS_ADMIN`, which also bypasses file
+    /// permissions.
+    pub fn check_access(self, metadata: &Metadata, write: bool, cred: &Credentials) -> LxResult {
+        let privileged = cred.is_privileged();
+        match self {
+            Self::User => {
+                // user attributes on other files are reserved
+                if !matches!(metadata.type_, FileType::File | FileType::Dir) {
+                    return Err(if write {
+                        LxError::EPERM
+                    } else {
+                        LxError::ENODATA
+                    });
+                }
+                let access = if write { 2 } else { 4 };
+                if !cred.can_access(metadata, access) {
+                    return Err(LxError::EACCES);
+                }
+                Ok(())
+            }
+            Self::Trusted if !privileged => Err(LxError::EPERM),
+            Self::Security if write && !privileged => Err(LxError::EPERM),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether attributes in the namespace are listed to a process with `cred`.
+    pub fn is_visible(self, cred: &Credentials) -> bool {
+        self != Self::Trusted || cred.is_privileged()
+    }
+}
+
+/// The part of `data` copied to a user buffer of `size` bytes: `None` if
+/// `size` is zero, which queries the length of `data`.
+///
+/// Fails with `ERANGE` if the buffer is too small.
+pub fn fit_buffer(data: &[u8], size: usize) -> LxResult<Option<&[u8]>> {
+    match size {
+        0 => Ok(None),
+        _ if data.len() > size => Err(LxError::ERANGE),
+        _ => Ok(Some(data)),
+    }
+}
+
+/// The list of attribute `names` visible to a process with `cred`, as
+/// null-terminated strings.
+///
+/// Fails with `E2BIG` if the list is longer than [`XATTR_LIST_MAX`].
+pub fn name_list(names: &[String], cred: &Credentials) -> LxResult<Vec<u8>> {
+    let mut list = Vec::new();
+    for name in names {
+        match XattrNamespace::from_name(name) {
+            Ok(ns) if ns.is_visible(cred) => {
+                list.extend_from_slice(name.as_bytes());
+                list.push(0);
+            }
+            _ => {}
+        }
+    }
+    if list.len() > XATTR_LIST_MAX {
+        return Err(LxError::E2BIG);
+    }
+    Ok(list)
+}
+
+/// name -> value
+type Attributes = BTreeMap<String, Vec<u8>>;
+
+/// Extended attributes of all inodes of a file system.
+pub struct XattrStore {
+    /// inode number -> attributes
+    attrs: Mutex<BTreeMap<usize, Attributes>>,
+}
+
+impl XattrStore {
+    /// Create an empty store.
+    pub fn new() -> Arc<Self> {
+        Arc::new(XattrStore {
+            attrs: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Get the value of attribute `name` of inode `ino`.
+    pub fn get(&self, ino: usize, name: &str) -> LxResult<Vec<u8>> {
+        let attrs = self.attrs.lock();
+        let value = attrs.get(&ino).and_then(|a| a.get(name));
+        value.cloned().ok_or(LxError::ENODATA)
+    }
+
+    /// Set the value of attribute `name` of inode `ino`.
+    pub fn set(&self, ino: usize, name: &str, value: &[u8], flags: XattrFlags) -> LxResult {
+        let mut attrs = self.attrs.lock();
+        let exists = attrs.get(&ino).map_or(false, |a| a.contains_key(name));
+        if exists && flags.contains(XattrFlags::CREATE) {
+            return Err(LxError::EEXIST);
+        }
+        if !exists && flags.contains(XattrFlags::REPLACE) {
+            return Err(LxError::ENODATA);
+        }
+        attrs
+            .entry(ino)
+            .or_default()
+            .insert(name.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    /// List the names of attributes of inode `ino`.
+    pub fn list(&self, ino: usize) -> Vec<String> {
+        let attrs = self.attrs.lock();
+        attrs
+            .get(&ino)
+            .map_or_else(Vec::new, |a| a.keys().cloned().collect())
+    }
+
+    /// Remove attribute `name` of inode `ino`.
+    pub fn remove(&self, ino: usize, name: &str) -> LxResult {
+        let mut attrs = self.attrs.lock();
+        let inode_attrs = attrs.get_mut(&ino).ok_or(LxError::ENODATA)?;
+        inode_attrs.remove(name).ok_or(LxError::ENODATA)?;
+        if inode_attrs.is_empty() {
+            attrs.remove(&ino);
+        }
+        Ok(())
+    }
+
+    /// Remove all attributes of inode `ino`, e.g. when it's deleted.
+    pub fn remove_all(&self, ino: usize) {
+        self.attrs.lock().remove(&ino);
+    }
+}
+
+lazy_static! {
+    /// file system -> store
+    static ref STORES: Mutex<BTreeMap<usize, Arc<XattrStore>>> = Mutex::new(BTreeMap::new());
+}
+
+fn fs_key(inode: &dyn INode) -> usize {
+    Arc::as_ptr(&inode.fs()) as *const () as usize
+}
+
+/// Register `store` for the file system of `inode`.
+pub fn register_store(inode: &dyn INode, store: Arc<XattrStore>) {
+    STORES.lock().insert(fs_key(inode), store);
+}
+
+/// Get the store and the inode number of `inode`.
+///
+/// Fails with `EOPNOTSUPP` if its file system doesn't support extended attributes.
+pub fn store_of(inode: &dyn INode) -> LxResult<(Arc<XattrStore>, usize)> {
+    let store = STORES
+        .lock()
+        .get(&fs_key(inode))
+        .cloned()
+        .ok_or(LxError::EOPNOTSUPP)?;
+    Ok((store, inode.metadata()?.inode))
+}
+
+/// Drop the attributes of `inode` after its last link is removed.
+pub fn forget(inode: &dyn INode) {
+    if let Ok((store, ino)) = store_of(inode) {
+        store.remove_all(ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcore_fs::vfs::Timespec;
+
+    const ROOT: Credentials = Credentials {
+        uid: 0,
+        gid: 0,
+        euid: 0,
+        egid: 0,
+    };
+    const USER: Credentials = Credentials {
+        uid: 1000,
+        gid: 100,
+        euid: 1000,
+        egid: 100,
+    };
+
+    fn metadata(type_: FileType, mode: u16, uid: usize, gid: usize) -> Metadata {
+        Metadata {
+            dev: 0,
+            inode: 1,
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: Timespec { sec: 0, nsec: 0 },
+            mtime: Timespec { sec: 0, nsec: 0 },
+            ctime: Timespec { sec: 0, nsec: 0 },
+            type_,
+            mode,
+            nlinks: 1,
+            uid,
+            gid,
+            rdev: 0,
+        }
+    }
+
+    #[test]
+    fn namespace() {
+        let long = "user.".to_string() + &"a".repeat(XATTR_NAME_MAX);
+        for (name, ns) in [
+            ("user.foo", Ok(XattrNamespace::User)),
+            ("user.foo.bar", Ok(XattrNamespace::User)),
+            ("trusted.foo", Ok(XattrNamespace::Trusted)),
+            ("security.selinux", Ok(XattrNamespace::Security)),
+            ("system.posix_acl_access", Err(LxError::EOPNOTSUPP)),
+            ("foo", Err(LxError::EOPNOTSUPP)),
+            ("user.", Err(LxError::EINVAL)),
+            ("", Err(LxError::ERANGE)),
+            (long.as_str(), Err(LxError::ERANGE)),
+        ] {
+            assert_eq!(XattrNamespace::from_name(name), ns, "{}", name);
+        }
+    }
+
+    #[test]
+    fn user_access() {
+        use XattrNamespace::User;
+        // owner, group and other bits
+        for (mode, uid, gid, read, write) in [
+            (0o600, 1000, 0, true, true),
+            (0o060, 1000, 0, false, false),
+            (0o060, 0, 100, true, true),
+            (0o640, 0, 100, true, false),
+            (0o604, 0, 0, true, false),
+            (0o660, 0, 0, false, false),
+        ] {
+            let meta = metadata(FileType::File, mode, uid, gid);
+            let res = |write| User.check_access(&meta, write, &USER);
+            assert_eq!(res(false).is_ok(), read, "read {:o} {} {}", mode, uid, gid);
+            assert_eq!(res(true).is_ok(), write, "write {:o} {} {}", mode, uid, gid);
+            assert!(User.check_access(&meta, true, &ROOT).is_ok());
+        }
+        let link = metadata(FileType::SymLink, 0o777, 1000, 100);
+        assert_eq!(
+            User.check_access(&link, false, &ROOT),
+            Err(LxError::ENODATA)
+        );
+        assert_eq!(User.check_access(&link, true, &ROOT), Err(LxError::EPERM));
+        let file = metadata(FileType::File, 0o600, 0, 0);
+        assert_eq!(User.check_access(&file, false, &USER), Err(LxError::EACCES));
+    }
+
+    #[test]
+    fn privileged_namespaces() {
+        use XattrNamespace::{Security, Trusted};
+        let file = metadata(FileType::File, 0o666, 1000, 100);
+        assert_eq!(
+            Trusted.check_access(&file, false, &USER),
+            Err(LxError::EPERM)
+        );
+        assert!(Trusted.check_access(&file, true, &ROOT).is_ok());
+        assert!(Security.check_access(&file, false, &USER).is_ok());
+        assert_eq!(
+            Security.check_access(&file, true, &USER),
+            Err(LxError::EPERM)
+        );
+        assert!(Security.check_access(&file, true, &ROOT).is_ok());
+
+        let names = ["trusted.a".to_string(), "user.b".to_string()];
+        assert_eq!(name_list(&names, &USER).unwrap(), b"user.b\0");
+        assert_eq!(name_list(&names, &ROOT).unwrap(), b"trusted.a\0user.b\0");
+    }
+
+    #[test]
+    fn buffer_size() {
+        let data = b"value";
+        assert_eq!(fit_buffer(data, 0), Ok(None));
+        assert_eq!(fit_buffer(data, 4), Err(LxError::ERANGE));
+        assert_eq!(fit_buffer(data, 5), Ok(Some(&data[..])));
+        assert_eq!(fit_buffer(data, 64), Ok(Some(&data[..])));
+        assert_eq!(fit_buffer(b"", 0), Ok(None));
+        assert_eq!(fit_buffer(b"", 1), Ok(Some(&b""[..])));
+
+        let names: Vec<String> = (0..XATTR_LIST_MAX / 8)
+            .map(|i| alloc::format!("user.{:02}", i % 100))
+            .collect();
+        assert_eq!(name_list(&names, &ROOT).map(|_| ()), Ok(()));
+        let names: Vec<String> = (0..XATTR_LIST_MAX / 8 + 1)
+            .map(|i| alloc::format!("user.{:02}", i % 100))
+            .collect();
+        assert_eq!(name_list(&names, &ROOT), Err(LxError::E2BIG));
+    }
+
+    #[test]
+    fn store_flags() {
+        let store = XattrStore::new();
+        assert_eq!(store.get(1, "user.a"), Err(LxError::ENODATA));
+        assert_eq!(
+            store.set(1, "user.a", b"1", XattrFlags::REPLACE),
+            Err(LxError::ENODATA)
+        );
+        store.set(1, "user.a", b"1", XattrFlags::CREATE).unwrap();
+        assert_eq!(
+            store.set(1, "user.a", b"2", XattrFlags::CREATE),
+            Err(LxError::EEXIST)
+        );
+        store.set(1, "user.a", b"2", XattrFlags::REPLACE).unwrap();
+        assert_eq!(store.get(1, "user.a").unwrap(), b"2");
+        assert_eq!(store.list(1), ["user.a"]);
+        store.remove(1, "user.a").unwrap();
+        assert_eq!(store.remove(1, "user.a"), Err(LxError::ENODATA));
+        assert!(store.list(1).is_empty());
+    }
+}
@@ -0,0 +1,505 @@
+//! Classic BPF socket filters
+//!
+//! Programs attached by `SO_ATTACH_FILTER` are checked like Linux on attach,
+//! and run on every packet to decide how many bytes of it are kept, 0 to drop
+//! it. Linux extensions at negative offsets (`SKF_AD_OFF`) are not supported,
+//! loading from them drops the packet.
+
+use crate::error::{LxError, LxResult};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use kernel_hal::user::UserInPtr;
+
+/// Maximum number of instructions, `BPF_MAXINSNS` in Linux.
+const BPF_MAXINSNS: usize = 4096;
+/// Number of scratch memory words.
+const BPF_MEMWORDS: usize = 16;
+
+// instruction classes
+const BPF_LD: u16 = 0x00;
+const BPF_LDX: u16 = 0x01;
+const BPF_ST: u16 = 0x02;
+const BPF_STX: u16 = 0x03;
+const BPF_ALU: u16 = 0x04;
+const BPF_JMP: u16 = 0x05;
+const BPF_RET: u16 = 0x06;
+const BPF_MISC: u16 = 0x07;
+
+// load sizes
+const BPF_W: u16 = 0x00;
+const BPF_H: u16 = 0x08;
+const BPF_B: u16 = 0x10;
+
+// load modes
+const BPF_IMM: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_IND: u16 = 0x40;
+const BPF_MEM: u16 = 0x60;
+const BPF_LEN: u16 = 0x80;
+const BPF_MSH: u16 = 0xa0;
+
+// ALU operations
+const BPF_ADD: u16 = 0x00;
+const BPF_SUB: u16 = 0x10;
+const BPF_MUL: u16 = 0x20;
+const BPF_DIV: u16 = 0x30;
+const BPF_OR: u16 = 0x40;
+const BPF_AND: u16 = 0x50;
+const BPF_LSH: u16 = 0x60;
+const BPF_RSH: u16 = 0x70;
+const BPF_NEG: u16 = 0x80;
+const BPF_MOD: u16 = 0x90;
+const BPF_XOR: u16 = 0xa0;
+
+// jumps
+const BPF_JA: u16 = 0x00;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGT: u16 = 0x20;
+const BPF_JGE: u16 = 0x30;
+const BPF_JSET: u16 = 0x40;
+
+// operand sources
+const BPF_K: u16 = 0x00;
+const BPF_X: u16 = 0x08;
+const BPF_A: u16 = 0x10;
+
+// misc operations
+const BPF_TAX: u16 = 0x00;
+const BPF_TXA: u16 = 0x80;
+
+/// `struct sock_filter`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// `struct sock_fprog`
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: usize,
+}
+
+/// A checked classic BPF program.
+#[derive(Debug, Clone)]
+pub struct BpfProgram {
+    insns: Vec<SockFilter>,
+}
+
+impl BpfProgram {
+    /// Read and check the program of a `struct sock_fprog` option.
+    pub fn from_sockopt(data: &[u8]) -> LxResult<Self> {
+        if data.len() < size_of::<SockFprog>() {
+            return Err(LxError::EINVAL);
+        }
+        #[allow(unsafe_code)]
+        let fprog = unsafe { (data.as_ptr() as *const SockFprog).read_unaligned() };
+        let len = fprog.len as usize;
+        if len == 0 || len > BPF_MAXINSNS {
+            return Err(LxError::EINVAL);
+        }
+        Self::new(UserInPtr::<SockFilter>::from(fprog.filter).read_array(len)?)
+    }
+
+    /// Check `insns` like `bpf_check_classic` in Linux: jumps must be
+    /// forward and in the program, scratch memory must be in range, and the
+    /// program must end with a return.
+    pub fn new(insns: Vec<SockFilter>) -> LxResult<Self> {
+        for (pc, insn) in insns.iter().enumerate() {
+            let rest = insns.len() - pc - 1;
+            let valid = match insn.code & 0x07 {
+                BPF_LD => match insn.code & 0xe0 {
+                    BPF_ABS | BPF_IND => insn.code & 0x18 != 0x18,
+                    BPF_MEM => (insn.k as usize) < BPF_MEMWORDS,
+                    BPF_IMM | BPF_LEN => true,
+                    _ => false,
+                },
+                BPF_LDX => match insn.code & 0xe0 {
+                    BPF_MEM => (insn.k as usize) < BPF_MEMWORDS,
+                    BPF_MSH => insn.code == BPF_LDX | BPF_B | BPF_MSH,
+                    BPF_IMM | BPF_LEN => true,
+                    _ => false,
+                },
+                BPF_ST | BPF_STX => (insn.k as usize) < BPF_MEMWORDS,
+                BPF_ALU => match insn.code & 0xf0 {
+                    BPF_DIV | BPF_MOD => insn.code & BPF_X != 0 || insn.k != 0,
+                    BPF_ADD | BPF_SUB | BPF_MUL | BPF_OR | BPF_AND | BPF_LSH | BPF_RSH
+                    | BPF_NEG | BPF_XOR => true,
+                    _ => false,
+                },
+                BPF_JMP => match insn.code & 0xf0 {
+                    BPF_JA => (insn.k as usize) < rest,
+                    BPF_JEQ | BPF_JGT | BPF_JGE | BPF_JSET => {
+                        (insn.jt as usize) < rest && (insn.jf as usize) < rest
+                    }
+                    _ => false,
+                },
+                BPF_RET => matches!(insn.code & 0x18, BPF_K | BPF_A),
+                BPF_MISC => matches!(insn.code & 0xf8, BPF_TAX | BPF_TXA),
+                _ => false,
+            };
+            if !valid {
+                return Err(LxError::EINVAL);
+            }
+        }
+        match insns.last() {
+            Some(insn) if insn.code & 0x07 == BPF_RET => Ok(BpfProgram { insns }),
+            _ => Err(LxError::EINVAL),
+        }
+    }
+
+    /// Run the program on `packet`, returns the number of bytes to keep.
+    pub fn run(&self, packet: &[u8]) -> u32 {
+        let load = |offset: u32, size: u16| -> Option<u32> {
+            let offset = offset as usize;
+            let bytes = match size {
+                BPF_W => packet.get(offset..offset.checked_add(4)?)?,
+                BPF_H => packet.get(offset..offset.checked_add(2)?)?,
+                _ => packet.get(offset..offset.checked_add(1)?)?,
+            };
+            Some(bytes.iter().fold(0, |value, &b| (value << 8) | b as u32))
+        };
+        let (mut a, mut x) = (0u32, 0u32);
+        let mut mem = [0u32; BPF_MEMWORDS];
+        let mut pc = 0;
+        // jumps are forward only, so the program always ends
+        while let Some(insn) = self.insns.get(pc) {
+            pc += 1;
+            let k = insn.k;
+            let src = if insn.code & BPF_X != 0 { x } else { k };
+            match insn.code & 0x07 {
+                BPF_LD => {
+                    a = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_ABS => match load(k, insn.code & 0x18) {
+                            Some(value) => value,
+                            None => return 0,
+                        },
+                        BPF_IND => match load(x.wrapping_add(k), insn.code & 0x18) {
+                            Some(value) => value,
+                            None => return 0,
+                        },
+                        BPF_MEM => mem[k as usize],
+                        _ => packet.len() as u32,
+                    }
+                }
+                BPF_LDX => {
+                    x = match insn.code & 0xe0 {
+                        BPF_IMM => k,
+                        BPF_MEM => mem[k as usize],
+                        BPF_LEN => packet.len() as u32,
+                        // the IPv4 header length
+                        _ => match load(k, BPF_B) {
+                            Some(value) => (value & 0xf) << 2,
+                            None => return 0,
+                        },
+                    }
+                }
+                BPF_ST => mem[k as usize] = a,
+                BPF_STX => mem[k as usize] = x,
+                BPF_ALU => {
+                    a = match insn.code & 0xf0 {
+                        BPF_ADD => a.wrapping_add(src),
+                        BPF_SUB => a.wrapping_sub(src),
+                        BPF_MUL => a.wrapping_mul(src),
+                        BPF_DIV if src == 0 => return 0,
+                        BPF_DIV => a / src,
+                        BPF_MOD if src == 0 => return 0,
+                        BPF_MOD => a % src,
+                        BPF_OR => a | src,
+                        BPF_AND => a & src,
+                        BPF_LSH => a.checked_shl(src).unwrap_or(0),
+                        BPF_RSH => a.checked_shr(src).unwrap_or(0),
+                        BPF_NEG => a.wrapping_neg(),
+                        _ => a ^ src,
+                    }
+                }
+                BPF_JMP => {
+                    let taken = match insn.code & 0xf0 {
+                        BPF_JA => {
+                            pc += k as usize;
+                            continue;
+                        }
+                        BPF_JEQ => a == src,
+                        BPF_JGT => a > src,
+                        BPF_JGE => a >= src,
+                        _ => a & src != 0,
+                    };
+                    pc += if taken { insn.jt } else { insn.jf } as usize;
+                }
+                BPF_RET => return if insn.code & BPF_A != 0 { a } else { k },
+                _ => {
+                    if insn.code & 0xf8 == BPF_TXA {
+                        a = x;
+                    } else {
+                        x = a;
+                    }
+                }
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    const RET_A: u16 = BPF_RET | BPF_A;
+    const RET_K: u16 = BPF_RET | BPF_K;
+
+    #[test]
+    fn check() {
+        let ret = stmt(RET_K, 0);
+        for (name, insns, valid) in [
+            ("empty", vec![], false),
+            ("no return", vec![stmt(BPF_LD | BPF_IMM, 1)], false),
+            ("return", vec![ret], true),
+            // jumps must land in the program, after the jump
+            ("ja in bounds", vec![stmt(BPF_JMP | BPF_JA, 0), ret], true),
+            (
+                "ja past the end",
+                vec![stmt(BPF_JMP | BPF_JA, 1), ret],
+                false,
+            ),
+            (
+                "jeq in bounds",
+                vec![jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0), ret, ret],
+                true,
+            ),
+            (
+                "jeq true past the end",
+                vec![jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 2, 0), ret, ret],
+                false,
+            ),
+            (
+                "jgt false past the end",
+                vec![jump(BPF_JMP | BPF_JGT | BPF_K, 0, 0, 2), ret, ret],
+                false,
+            ),
+            ("jump as last", vec![ret, stmt(BPF_JMP | BPF_JA, 0)], false),
+            // constant divisors must not be zero
+            (
+                "div by k",
+                vec![stmt(BPF_ALU | BPF_DIV | BPF_K, 2), ret],
+                true,
+            ),
+            (
+                "div by 0",
+                vec![stmt(BPF_ALU | BPF_DIV | BPF_K, 0), ret],
+                false,
+            ),
+            (
+                "mod by 0",
+                vec![stmt(BPF_ALU | BPF_MOD | BPF_K, 0), ret],
+                false,
+            ),
+            (
+                "div by x",
+                vec![stmt(BPF_ALU | BPF_DIV | BPF_X, 0), ret],
+                true,
+            ),
+            // scratch memory has 16 words
+            ("st 15", vec![stmt(BPF_ST, 15), ret], true),
+            ("st 16", vec![stmt(BPF_ST, 16), ret], false),
+            ("stx 16", vec![stmt(BPF_STX, 16), ret], false),
+            ("ld mem 16", vec![stmt(BPF_LD | BPF_MEM, 16), ret], false),
+            ("ldx mem 16", vec![stmt(BPF_LDX | BPF_MEM, 16), ret], false),
+            // invalid encodings
+            (
+                "ld abs size 0x18",
+                vec![stmt(BPF_LD | BPF_ABS | 0x18, 0), ret],
+                false,
+            ),
+            (
+                "ldx msh w",
+                vec![stmt(BPF_LDX | BPF_W | BPF_MSH, 0), ret],
+                false,
+            ),
+            ("ret x", vec![stmt(BPF_RET | BPF_X, 0)], false),
+        ] {
+            assert_eq!(BpfProgram::new(insns).is_ok(), valid, "{}", name);
+        }
+    }
+
+    #[test]
+    fn run() {
+        let packet = [0x45, 0x00, 0x12, 0x34, 0xab];
+        for (name, insns, expected) in [
+            ("ret k", vec![stmt(RET_K, 0xffff)], 0xffff),
+            ("len", vec![stmt(BPF_LD | BPF_LEN, 0), stmt(RET_A, 0)], 5),
+            (
+                "ld w",
+                vec![stmt(BPF_LD | BPF_W | BPF_ABS, 0), stmt(RET_A, 0)],
+                0x4500_1234,
+            ),
+            (
+                "ld h",
+                vec![stmt(BPF_LD | BPF_H | BPF_ABS, 2), stmt(RET_A, 0)],
+                0x1234,
+            ),
+            (
+                "ld b",
+                vec![stmt(BPF_LD | BPF_B | BPF_ABS, 4), stmt(RET_A, 0)],
+                0xab,
+            ),
+            // loads beyond the packet drop it
+            (
+                "ld w past the end",
+                vec![stmt(BPF_LD | BPF_W | BPF_ABS, 2), stmt(RET_K, 1)],
+                0,
+            ),
+            (
+                "ld h past the end",
+                vec![stmt(BPF_LD | BPF_H | BPF_ABS, 4), stmt(RET_K, 1)],
+                0,
+            ),
+            (
+                "ld b past the end",
+                vec![stmt(BPF_LD | BPF_B | BPF_ABS, 5), stmt(RET_K, 1)],
+                0,
+            ),
+            (
+                "ld at u32::MAX",
+                vec![stmt(BPF_LD | BPF_W | BPF_ABS, !0), stmt(RET_K, 1)],
+                0,
+            ),
+            (
+                "ld ind",
+                vec![
+                    stmt(BPF_LDX | BPF_IMM, 3),
+                    stmt(BPF_LD | BPF_B | BPF_IND, 1),
+                    stmt(RET_A, 0),
+                ],
+                0xab,
+            ),
+            (
+                "ld ind past the end",
+                vec![
+                    stmt(BPF_LDX | BPF_IMM, 4),
+                    stmt(BPF_LD | BPF_H | BPF_IND, 0),
+                    stmt(RET_K, 1),
+                ],
+                0,
+            ),
+            (
+                "ldx msh",
+                vec![
+                    stmt(BPF_LDX | BPF_B | BPF_MSH, 0),
+                    stmt(BPF_MISC | BPF_TXA, 0),
+                    stmt(RET_A, 0),
+                ],
+                20,
+            ),
+            (
+                "ldx msh past the end",
+                vec![stmt(BPF_LDX | BPF_B | BPF_MSH, 5), stmt(RET_K, 1)],
+                0,
+            ),
+            // division by a zero register drops the packet
+            (
+                "div by x = 0",
+                vec![
+                    stmt(BPF_LD | BPF_IMM, 10),
+                    stmt(BPF_ALU | BPF_DIV | BPF_X, 0),
+                    stmt(RET_K, 1),
+                ],
+                0,
+            ),
+            (
+                "mod by x = 0",
+                vec![
+                    stmt(BPF_LD | BPF_IMM, 10),
+                    stmt(BPF_ALU | BPF_MOD | BPF_X, 0),
+                    stmt(RET_K, 1),
+                ],
+                0,
+            ),
+            (
+                "div by x",
+                vec![
+                    stmt(BPF_LD | BPF_IMM, 10),
+                    stmt(BPF_LDX | BPF_IMM, 3),
+                    stmt(BPF_ALU | BPF_DIV | BPF_X, 0),
+                    stmt(RET_A, 0),
+                ],
+                3,
+            ),
+            // scratch memory
+            (
+                "st and ld mem",
+                vec![
+                    stmt(BPF_LD | BPF_IMM, 7),
+                    stmt(BPF_ST, 15),
+                    stmt(BPF_LD | BPF_IMM, 0),
+                    stmt(BPF_LD | BPF_MEM, 15),
+                    stmt(RET_A, 0),
+                ],
+                7,
+            ),
+            (
+                "stx and ldx mem",
+                vec![
+                    stmt(BPF_LDX | BPF_IMM, 9),
+                    stmt(BPF_STX, 0),
+                    stmt(BPF_LDX | BPF_MEM, 1),
+                    stmt(BPF_LDX | BPF_MEM, 0),
+                    stmt(BPF_MISC | BPF_TXA, 0),
+                    stmt(RET_A, 0),
+                ],
+                9,
+            ),
+            // jumps
+            (
+                "jeq taken",
+                vec![
+                    stmt(BPF_LD | BPF_B | BPF_ABS, 0),
+                    jump(BPF_JMP | BPF_JEQ | BPF_K, 0x45, 0, 1),
+                    stmt(RET_K, 100),
+                    stmt(RET_K, 200),
+                ],
+                100,
+            ),
+            (
+                "jgt not taken",
+                vec![
+                    stmt(BPF_LD | BPF_B | BPF_ABS, 0),
+                    jump(BPF_JMP | BPF_JGT | BPF_K, 0x45, 0, 1),
+                    stmt(RET_K, 100),
+                    stmt(RET_K, 200),
+                ],
+                200,
+            ),
+            (
+                "ja",
+                vec![
+                    stmt(BPF_JMP | BPF_JA, 1),
+                    stmt(RET_K, 100),
+                    stmt(RET_K, 200),
+                ],
+                200,
+            ),
+        ] {
+            let prog = BpfProgram::new(insns).unwrap();
+            assert_eq!(prog.run(&packet), expected, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,840 @@
+//! Network interface configuration
+//!
+//! - `SIOCGIFxxx`/`SIOCSIFxxx` ioctls on `struct ifreq`
+//! - `SIOCGIFCONF` on `struct ifconf`, listing interfaces with IPv4 addresses
+//! - IPv6 addresses and default routes, configured by netlink
+//! - `SIOCADDRT`/`SIOCDELRT` ioctls on `struct rtentry`, of IPv4 routes
+//! - Static routes, kept in the kernel routing table of
+//!   [`zcore_drivers::net::route`] which chooses the egress interface of
+//!   packets
+//!
+//! Interface indexes are 1-based in the order the interfaces are probed,
+//! 0 means no interface as in Linux.
+//!
+//! Interfaces are always up, and changes of addresses and routes are
+//! notified to netlink sockets.
+
+use crate::error::{LxError, LxResult};
+use crate::net::{netlink, AddressFamily, SockAddrPlaceholder, SysResult};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::mem::size_of;
+use kernel_hal::net::get_net_device;
+use kernel_hal::user::{UserInOutPtr, UserInPtr, UserOutPtr};
+use lazy_static::lazy_static;
+use lock::Mutex;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv4Cidr, Ipv6Address, Ipv6Cidr};
+use zcore_drivers::net::route;
+use zcore_drivers::scheme::{NetScheme, Scheme};
+use zcore_drivers::DeviceError;
+
+const SIOCADDRT: usize = 0x890b;
+const SIOCDELRT: usize = 0x890c;
+const SIOCGIFNAME: usize = 0x8910;
+const SIOCGIFCONF: usize = 0x8912;
+const SIOCGIFFLAGS: usize = 0x8913;
+const SIOCSIFFLAGS: usize = 0x8914;
+const SIOCGIFADDR: usize = 0x8915;
+const SIOCSIFADDR: usize = 0x8916;
+const SIOCGIFBRDADDR: usize = 0x8919;
+const SIOCGIFNETMASK: usize = 0x891b;
+const SIOCSIFNETMASK: usize = 0x891c;
+const SIOCGIFMETRIC: usize = 0x891d;
+const SIOCGIFMTU: usize = 0x8921;
+const SIOCSIFMTU: usize = 0x8922;
+const SIOCGIFHWADDR: usize = 0x8927;
+const SIOCGIFINDEX: usize = 0x8933;
+const SIOCGIFTXQLEN: usize = 0x8942;
+const SIOCGIFMAP: usize = 0x8970;
+
+/// Maximum length of interface names, including the terminating NUL.
+pub const IFNAMSIZ: usize = 16;
+
+/// Hardware type of Ethernet, in `sa_family` of hardware addresses.
+pub const ARPHRD_ETHER: u16 = 1;
+/// Hardware type of loopback devices.
+pub const ARPHRD_LOOPBACK: u16 = 772;
+
+/// Interface is up.
+pub const IFF_UP: u32 = 0x1;
+/// Broadcast address is valid.
+pub const IFF_BROADCAST: u32 = 0x2;
+/// Interface is a loopback device.
+pub const IFF_LOOPBACK: u32 = 0x8;
+/// Resources are allocated.
+pub const IFF_RUNNING: u32 = 0x40;
+/// Supports multicast.
+pub const IFF_MULTICAST: u32 = 0x1000;
+/// The link is up, only reported by netlink.
+pub const IFF_LOWER_UP: u32 = 0x10000;
+
+/// Length of transmit queues reported to the user.
+pub const TX_QUEUE_LEN: u32 = 1000;
+
+/// The route is usable.
+const RTF_UP: u16 = 0x1;
+/// The destination is reached via a gateway.
+const RTF_GATEWAY: u16 = 0x2;
+/// The destination is a host rather than a network.
+const RTF_HOST: u16 = 0x4;
+
+/// `struct ifreq`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct IfReq {
+    /// Interface name, NUL terminated
+    pub ifr_name: [u8; IFNAMSIZ],
+    /// A socket address, or the interface index in the first 4 bytes
+    pub ifr_addr: SockAddrPlaceholder,
+    _pad: [u8; 8],
+}
+
+impl IfReq {
+    /// The interface name.
+    pub fn name(&self) -> LxResult<&str> {
+        let len = self
+            .ifr_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(IFNAMSIZ);
+        core::str::from_utf8(&self.ifr_name[..len]).map_err(|_| LxError::EINVAL)
+    }
+
+    fn set_name(&mut self, name: &str) {
+        let len = name.len().min(IFNAMSIZ - 1);
+        self.ifr_name = [0; IFNAMSIZ];
+        self.ifr_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+
+    fn set_ipv4(&mut self, addr: Ipv4Address) {
+        self.ifr_addr = sockaddr_in(addr);
+    }
+
+    /// An integer in the union, such as `ifr_ifindex` and `ifr_mtu`.
+    fn int(&self) -> i32 {
+        // it overlaps `sa_family` and the beginning of `sa_data`
+        let family = self.ifr_addr.family.to_ne_bytes();
+        let data = &self.ifr_addr.data;
+        i32::from_ne_bytes([family[0], family[1], data[0], data[1]])
+    }
+
+    fn set_int(&mut self, value: i32) {
+        let bytes = value.to_ne_bytes();
+        self.ifr_addr.family = u16::from_ne_bytes([bytes[0], bytes[1]]);
+        self.ifr_addr.data = [0; 14];
+        self.ifr_addr.data[..2].copy_from_slice(&bytes[2..]);
+    }
+
+    /// `ifr_flags`, which overlaps `sa_family`.
+    fn flags(&self) -> u32 {
+        self.ifr_addr.family as u32
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        self.ifr_addr.family = flags as u16;
+        self.ifr_addr.data = [0; 14];
+    }
+}
+
+/// `struct ifconf`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct IfConf {
+    /// Size of the buffer in bytes
+    ifc_len: i32,
+    /// An array of `struct ifreq`
+    ifc_buf: usize,
+}
+
+/// `struct rtentry`
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RtEntry {
+    rt_pad1: usize,
+    rt_dst: SockAddrPlaceholder,
+    rt_gateway: SockAddrPlaceholder,
+    rt_genmask: SockAddrPlaceholder,
+    rt_flags: u16,
+    rt_pad2: i16,
+    rt_pad3: usize,
+    rt_pad4: usize,
+    rt_metric: i16,
+    rt_dev: usize,
+    rt_mtu: usize,
+    rt_window: usize,
+    rt_irtt: u16,
+}
+
+fn sockaddr_in(addr: Ipv4Address) -> SockAddrPlaceholder {
+    let mut data = [0; 14];
+    // sin_port is left 0
+    data[2..6].copy_from_slice(addr.as_bytes());
+    SockAddrPlaceholder {
+        family: AddressFamily::Internet.into(),
+        data,
+    }
+}
+
+fn sockaddr_in_addr(addr: &SockAddrPlaceholder) -> LxResult<Ipv4Address> {
+    match AddressFamily::from(addr.family) {
+        AddressFamily::Internet => Ok(Ipv4Address::from_bytes(&addr.data[2..6])),
+        _ => Err(LxError::EAFNOSUPPORT),
+    }
+}
+
+/// Convert the errors of interface operations.
+pub(crate) fn device_error(e: DeviceError) -> LxError {
+    match e {
+        DeviceError::NotSupported => LxError::EOPNOTSUPP,
+        DeviceError::NoResources => LxError::ENOBUFS,
+        DeviceError::InvalidParam => LxError::EINVAL,
+        _ => LxError::EIO,
+    }
+}
+
+/// Find the interface named `name`, returns its index and itself.
+pub fn iface_by_name(name: &str) -> LxResult<(usize, Arc<dyn NetScheme>)> {
+    get_net_device()
+        .into_iter()
+        .enumerate()
+        .find(|(_, iface)| iface.get_ifname() == name)
+        .map(|(i, iface)| (i + 1, iface))
+        .ok_or(LxError::ENODEV)
+}
+
+/// Find the interface at `index`.
+pub fn iface_by_index(index: usize) -> LxResult<Arc<dyn NetScheme>> {
+    let ifaces = get_net_device();
+    match index {
+        0 => Err(LxError::ENODEV),
+        _ => ifaces.get(index - 1).cloned().ok_or(LxError::ENODEV),
+    }
+}
+
+/// The index of `iface`.
+pub fn iface_index(iface: &Arc<dyn NetScheme>) -> usize {
+    get_net_device()
+        .iter()
+        .position(|i| Arc::ptr_eq(i, iface))
+        .map_or(0, |i| i + 1)
+}
+
+/// Whether `iface` is a loopback device.
+pub fn is_loopback(iface: &dyn NetScheme) -> bool {
+    iface.name() == "loopback"
+}
+
+/// The MTU of `iface`.
+pub fn mtu(iface: &dyn NetScheme) -> u32 {
+    if is_loopback(iface) {
+        65536
+    } else {
+        1500
+    }
+}
+
+/// The `IFF_*` flags of `iface`.
+pub fn link_flags(iface: &dyn NetScheme) -> u32 {
+    let flags = IFF_UP | IFF_RUNNING | IFF_LOWER_UP;
+    if is_loopback(iface) {
+        flags | IFF_LOOPBACK
+    } else {
+        flags | IFF_BROADCAST | IFF_MULTICAST
+    }
+}
+
+/// Change the `IFF_*` flags of `iface`, only keeping it up is supported.
+pub fn set_link_flags(iface: &Arc<dyn NetScheme>, flags: u32) -> LxResult {
+    if flags & IFF_UP == 0 {
+        warn!("interface {} can't be brought down", iface.get_ifname());
+        return Err(LxError::EOPNOTSUPP);
+    }
+    netlink::notify_link(iface);
+    Ok(())
+}
+
+/// Change the MTU of `iface`, only keeping it unchanged is supported.
+pub fn set_mtu(iface: &dyn NetScheme, value: u32) -> LxResult {
+    if value != mtu(iface) {
+        warn!("MTU of interface {} can't be changed", iface.get_ifname());
+        return Err(LxError::EOPNOTSUPP);
+    }
+    Ok(())
+}
+
+lazy_static! {
+    /// Interface index -> number of users of the promiscuous mode
+    static ref PROMISCUOUS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+    /// (interface index, group) -> number of sockets which joined the group
+    static ref MULTICAST: Mutex<BTreeMap<(usize, Ipv4Address), usize>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Enable or disable the promiscuous mode of the interface at `index` for a
+/// user, it's kept enabled until all users disable it.
+pub fn set_promiscuous(index: usize, enable: bool) -> LxResult {
+    let iface = iface_by_index(index)?;
+    let mut users = PROMISCUOUS.lock();
+    let count = users.get(&index).copied().unwrap_or(0);
+    if enable {
+        if count == 0 {
+            iface.set_promiscuous(true).map_err(device_error)?;
+        }
+        users.insert(index, count + 1);
+    } else if count > 0 {
+        if count == 1 {
+            iface.set_promiscuous(false).map_err(device_error)?;
+            users.remove(&index);
+        } else {
+            users.insert(index, count - 1);
+        }
+    }
+    Ok(())
+}
+
+/// The index of the interface to join the multicast `group` on, given by
+/// the `ifindex` or the `addr` of `struct ip_mreqn`, or the interface the group
+/// is routed to if both are unset.
+pub fn multicast_iface(group: Ipv4Address, addr: Ipv4Address, ifindex: usize) -> LxResult<usize> {
+    if ifindex != 0 {
+        iface_by_index(ifindex)?;
+        return Ok(ifindex);
+    }
+    let ifaces = get_net_device();
+    let found = if !addr.is_unspecified() {
+        ifaces
+            .iter()
+            .position(|iface| ipv4_addr(iface.as_ref()).map(|cidr| cidr.address()) == Some(addr))
+    } else {
+        routed_iface(IpAddress::Ipv4(group))
+            .and_then(|routed| ifaces.iter().position(|iface| Arc::ptr_eq(iface, &routed)))
+            .or_else(|| ifaces.iter().position(|iface| !is_loopback(iface.as_ref())))
+            .or_else(|| (!ifaces.is_empty()).then(|| 0))
+    };
+    found.map(|i| i + 1).ok_or(LxError::ENODEV)
+}
+
+/// Join the multicast `group` on the interface at `index` for a socket, the
+/// interface stays in the group until all sockets leave it.
+pub fn join_multicast_group(index: usize, group: Ipv4Address) -> LxResult {
+    let iface = iface_by_index(index)?;
+    let mut users = MULTICAST.lock();
+    let count = users.get(&(index, group)).copied().unwrap_or(0);
+    if count == 0 {
+        iface.join_multicast_group(group).map_err(device_error)?;
+    }
+    users.insert((index, group), count + 1);
+    Ok(())
+}
+
+/// Leave the multicast `group` on the interface at `index` for a socket.
+pub fn leave_multicast_group(index: usize, group: Ipv4Address) -> LxResult {
+    let iface = iface_by_index(index)?;
+    let mut users = MULTICAST.lock();
+    match users.get_mut(&(index, group)) {
+        Some(count) if *count > 1 => *count -= 1,
+        Some(_) => {
+            users.remove(&(index, group));
+            iface.leave_multicast_group(group).map_err(device_error)?;
+        }
+        None => return Err(LxError::EADDRNOTAVAIL),
+    }
+    Ok(())
+}
+
+/// The IPv4 address of `iface`, ignoring the unspecified one of interfaces
+/// waiting for DHCP.
+pub fn ipv4_addr(iface: &dyn NetScheme) -> Option<Ipv4Cidr> {
+    iface.get_ip_address().into_iter().find_map(|ip| match ip {
+        IpCidr::Ipv4(cidr) if !cidr.address().is_unspecified() => Some(cidr),
+        _ => None,
+    })
+}
+
+/// Replace the IPv4 address of `iface`, or remove it if `None`, keeping the
+/// addresses of other families.
+pub fn set_ipv4_addr(iface: &Arc<dyn NetScheme>, cidr: Option<Ipv4Cidr>) -> LxResult {
+    let old = ipv4_addr(iface.as_ref());
+    let mut addrs: Vec<IpCidr> = iface
+        .get_ip_address()
+        .into_iter()
+        .filter(|ip| !matches!(ip, IpCidr::Ipv4(_)))
+        .collect();
+    match cidr {
+        Some(cidr) => addrs.insert(0, IpCidr::Ipv4(cidr)),
+        // smoltcp needs an address to bind DHCP sockets on
+        None => addrs.insert(0, IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0)),
+    }
+    iface.set_ip_address(addrs).map_err(device_error)?;
+    if old != cidr {
+        if let Some(old) = old {
+            netlink::notify_addr(iface, IpCidr::Ipv4(old), false);
+        }
+        if let Some(cidr) = cidr {
+            netlink::notify_addr(iface, IpCidr::Ipv4(cidr), true);
+        }
+    }
+    Ok(())
+}
+
+/// Set the IPv4 default gateway of `iface`, or remove it if `None`.
+pub fn set_ipv4_gateway(iface: &Arc<dyn NetScheme>, gateway: Option<Ipv4Address>) -> LxResult {
+    let old = iface.get_ipv4_gateway();
+    iface.set_ipv4_gateway(gateway).map_err(device_error)?;
+    if old != gateway {
+        if let Some(old) = old {
+            netlink::notify_route(iface, None, Some(old.into()), false);
+        }
+        if let Some(gateway) = gateway {
+            netlink::notify_route(iface, None, Some(gateway.into()), true);
+        }
+    }
+    Ok(())
+}
+
+/// IPv6 addresses of `iface`.
+pub fn ipv6_addrs(iface: &dyn NetScheme) -> Vec<Ipv6Cidr> {
+    iface
+        .get_ip_address()
+        .into_iter()
+        .filter_map(|ip| match ip {
+            IpCidr::Ipv6(cidr) => Some(cidr),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Add the IPv6 address `cidr` to `iface`.
+pub fn add_ipv6_addr(iface: &Arc<dyn NetScheme>, cidr: Ipv6Cidr) -> LxResult {
+    let mut addrs = iface.get_ip_address();
+    if addrs.contains(&IpCidr::Ipv6(cidr)) {
+        return Err(LxError::EEXIST);
+    }
+    // smoltcp takes the first address as the source address, so global
+    // addresses go before link-local ones
+    let first_ipv6 = addrs.iter().position(|ip| matches!(ip, IpCidr::Ipv6(_)));
+    let pos = if cidr.address().is_link_local() {
+        addrs.len()
+    } else {
+        first_ipv6.unwrap_or(addrs.len())
+    };
+    addrs.insert(pos, IpCidr::Ipv6(cidr));
+    iface.set_ip_address(addrs).map_err(device_error)?;
+    netlink::notify_addr(iface, IpCidr::Ipv6(cidr), true);
+    Ok(())
+}
+
+/// Delete the IPv6 address `addr` of `iface`.
+pub fn del_ipv6_addr(iface: &Arc<dyn NetScheme>, addr: Ipv6Address) -> LxResult {
+    let cidr = ipv6_addrs(iface.as_ref())
+        .into_iter()
+        .find(|cidr| cidr.address() == addr)
+        .ok_or(LxError::EADDRNOTAVAIL)?;
+    let mut addrs = iface.get_ip_address();
+    addrs.retain(|ip| *ip != IpCidr::Ipv6(cidr));
+    iface.set_ip_address(addrs).map_err(device_error)?;
+    netlink::notify_addr(iface, IpCidr::Ipv6(cidr), false);
+    Ok(())
+}
+
+/// Set the IPv6 default gateway of `iface`, or remove it if `None`.
+pub fn set_ipv6_gateway(iface: &Arc<dyn NetScheme>, gateway: Option<Ipv6Address>) -> LxResult {
+    let old = iface.get_ipv6_gateway();
+    iface.set_ipv6_gateway(gateway).map_err(device_error)?;
+    if old != gateway {
+        if let Some(old) = old {
+            netlink::notify_route(iface, None, Some(old.into()), false);
+        }
+        if let Some(gateway) = gateway {
+            netlink::notify_route(iface, None, Some(gateway.into()), true);
+        }
+    }
+    Ok(())
+}
+
+/// Add a static route to `dst` via `gateway` on `iface`, replacing the one to
+/// the same destination on the interface.
+pub fn add_route(
+    iface: &Arc<dyn NetScheme>,
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    metric: u32,
+) -> LxResult {
+    info!(
+        "add route to {} via {:?} on {}",
+        dst,
+        gateway,
+        iface.get_ifname()
+    );
+    route::add_route(iface.as_ref(), dst, gateway, metric).map_err(|e| match e {
+        // smoltcp only sends packets off the networks of interfaces to gateways
+        DeviceError::NotSupported => LxError::ENETUNREACH,
+        e => device_error(e),
+    })?;
+    netlink::notify_route(iface, Some(dst), gateway, true);
+    Ok(())
+}
+
+/// Delete the static route to `dst`, on `dev` if given.
+pub fn del_route(dst: IpCidr, dev: Option<&Arc<dyn NetScheme>>) -> LxResult {
+    let dst = route::network(dst);
+    let found = route::routes()
+        .into_iter()
+        .find(|route| route.dst == dst && dev.map_or(true, |dev| dev.get_ifname() == route.ifname));
+    let route = found.ok_or(LxError::ESRCH)?;
+    let (_, iface) = iface_by_name(&route.ifname)?;
+    info!("delete route to {} on {}", dst, route.ifname);
+    route::del_route(iface.as_ref(), dst).map_err(device_error)?;
+    netlink::notify_route(&iface, Some(dst), route.gateway, false);
+    Ok(())
+}
+
+/// The interface that packets to `dst` are routed to, unless it's loopback.
+fn routed_iface(dst: IpAddress) -> Option<Arc<dyn NetScheme>> {
+    let route = route::lookup(dst)?;
+    iface_by_name(&route.ifname)
+        .ok()
+        .map(|(_, iface)| iface)
+        .filter(|iface| !is_loopback(iface.as_ref()))
+}
+
+/// The IPv4 source address to send to `dst` from: `dst` itself if it's local,
+/// the address of the interface it's routed to, the address on the same
+/// network, or the one of the first Ethernet interface.
+pub fn ipv4_source_addr(dst: Ipv4Address) -> Option<Ipv4Address> {
+    if dst.is_loopback() {
+        return Some(Ipv4Address::new(127, 0, 0, 1));
+    }
+    let addrs: Vec<_> = get_net_device()
+        .iter()
+        .filter(|iface| !is_loopback(iface.as_ref()))
+        .filter_map(|iface| ipv4_addr(iface.as_ref()))
+        .collect();
+    if addrs.iter().any(|cidr| cidr.address() == dst) {
+        return Some(dst);
+    }
+    if let Some(cidr) = routed_iface(dst.into()).and_then(|iface| ipv4_addr(iface.as_ref())) {
+        return Some(cidr.address());
+    }
+    addrs
+        .iter()
+        .find(|cidr| cidr.contains_addr(&dst))
+        .or_else(|| addrs.first())
+        .map(|cidr| cidr.address())
+}
+
+/// Whether `addr` is only valid on a link, i.e. link-local unicast or
+/// link-local multicast.
+fn is_link_scope(addr: Ipv6Address) -> bool {
+    addr.is_link_local() || (addr.is_multicast() && addr.0[1] & 0x0f == 2)
+}
+
+/// The scope id of `addr` in `sockaddr_in6`: the index of the interface for
+/// link-scope addresses, 0 otherwise.
+///
+/// Link-local addresses of other hosts are taken as on the first Ethernet
+/// interface.
+pub fn ipv6_scope_id(addr: Ipv6Address) -> u32 {
+    if !is_link_scope(addr) {
+        return 0;
+    }
+    let ifaces = get_net_device();
+    let owner = ifaces.iter().position(|iface| {
+        ipv6_addrs(iface.as_ref())
+            .iter()
+            .any(|cidr| cidr.address() == addr)
+    });
+    let index = owner.or_else(|| {
+        ifaces.iter().position(|iface| {
+            !is_loopback(iface.as_ref())
+                && ipv6_addrs(iface.as_ref())
+                    .iter()
+                    .any(|cidr| cidr.address().is_link_local())
+        })
+    });
+    index.map_or(0, |i| i as u32 + 1)
+}
+
+/// The source address to send to `dst` from: the loopback address to itself,
+/// a link-local address to link-scope addresses, or a global address,
+/// preferably of the interface `dst` is routed to.
+pub fn ipv6_source_addr(dst: Ipv6Address) -> Option<Ipv6Address> {
+    if dst.is_loopback() {
+        return Some(Ipv6Address::LOOPBACK);
+    }
+    let link_scope = is_link_scope(dst);
+    if !link_scope {
+        let routed = routed_iface(dst.into()).and_then(|iface| {
+            ipv6_addrs(iface.as_ref())
+                .into_iter()
+                .map(|cidr| cidr.address())
+                .find(|addr| !addr.is_link_local())
+        });
+        if routed.is_some() {
+            return routed;
+        }
+    }
+    get_net_device()
+        .iter()
+        .filter(|iface| !is_loopback(iface.as_ref()))
+        .flat_map(|iface| ipv6_addrs(iface.as_ref()))
+        .map(|cidr| cidr.address())
+        .find(|addr| addr.is_link_local() == link_scope)
+}
+
+/// The prefix length of a netmask, `None` if it's not contiguous.
+fn netmask_prefix_len(mask: Ipv4Address) -> Option<u8> {
+    let mask = u32::from_be_bytes(mask.0);
+    let len = mask.leading_ones();
+    if mask.checked_shl(len).unwrap_or(0) == 0 {
+        Some(len as u8)
+    } else {
+        None
+    }
+}
+
+/// The prefix length of the address class, used when setting an address
+/// without a netmask.
+fn classful_prefix_len(addr: Ipv4Address) -> LxResult<u8> {
+    match addr.0[0] {
+        _ if addr.is_unspecified() => Ok(0),
+        0..=127 => Ok(8),
+        128..=191 => Ok(16),
+        192..=223 => Ok(24),
+        _ => Err(LxError::EINVAL),
+    }
+}
+
+/// Handle the ioctl `request` on interfaces, returns `None` if it's not one
+/// of them.
+pub fn ioctl(request: usize, arg: usize) -> Option<SysResult> {
+    let res = match request {
+        SIOCGIFNAME => ifname_ioctl(arg.into()),
+        SIOCGIFCONF => ifconf_ioctl(arg.into()),
+        SIOCGIFFLAGS | SIOCSIFFLAGS | SIOCGIFADDR | SIOCSIFADDR | SIOCGIFBRDADDR
+        | SIOCGIFNETMASK | SIOCSIFNETMASK | SIOCGIFMETRIC | SIOCGIFMTU | SIOCSIFMTU
+        | SIOCGIFHWADDR | SIOCGIFINDEX | SIOCGIFTXQLEN | SIOCGIFMAP => {
+            ifreq_ioctl(request, arg.into())
+        }
+        SIOCADDRT | SIOCDELRT => route_ioctl(request, arg.into()),
+        _ => return None,
+    };
+    Some(res)
+}
+
+/// Get the name of the interface at `ifr_ifindex`.
+fn ifname_ioctl(mut ptr: UserInOutPtr<IfReq>) -> SysResult {
+    let mut req = ptr.read()?;
+    let iface = iface_by_index(req.int().max(0) as usize)?;
+    req.set_name(&iface.get_ifname());
+    ptr.write(req)?;
+    Ok(0)
+}
+
+/// List the addresses of interfaces, only the length needed is returned if
+/// `ifc_buf` is null.
+fn ifconf_ioctl(mut ptr: UserInOutPtr<IfConf>) -> SysResult {
+    let mut conf = ptr.read()?;
+    let reqs: Vec<IfReq> = get_net_device()
+        .iter()
+        .filter_map(|iface| {
+            let cidr = ipv4_addr(iface.as_ref())?;
+            let mut req = IfReq {
+                ifr_name: [0; IFNAMSIZ],
+                ifr_addr: sockaddr_in(cidr.address()),
+                _pad: [0; 8],
+            };
+            req.set_name(&iface.get_ifname());
+            Some(req)
+        })
+        .collect();
+    let count = if conf.ifc_buf == 0 {
+        reqs.len()
+    } else {
+        let count = reqs
+            .len()
+            .min(conf.ifc_len.max(0) as usize / size_of::<IfReq>());
+        UserOutPtr::<IfReq>::from(conf.ifc_buf).write_array(&reqs[..count])?;
+        count
+    };
+    conf.ifc_len = (count * size_of::<IfReq>()) as i32;
+    ptr.write(conf)?;
+    Ok(0)
+}
+
+fn ifreq_ioctl(request: usize, mut ptr: UserInOutPtr<IfReq>) -> SysResult {
+    let mut req = ptr.read()?;
+    let (index, iface) = iface_by_name(req.name()?)?;
+    match request {
+        SIOCGIFFLAGS => req.set_flags(link_flags(iface.as_ref())),
+        SIOCSIFFLAGS => {
+            set_link_flags(&iface, req.flags())?;
+            return Ok(0);
+        }
+        SIOCGIFADDR => {
+            let cidr = ipv4_addr(iface.as_ref()).ok_or(LxError::EADDRNOTAVAIL)?;
+            req.set_ipv4(cidr.address());
+        }
+        SIOCSIFADDR => {
+            let addr = sockaddr_in_addr(&req.ifr_addr)?;
+            let cidr = Ipv4Cidr::new(addr, classful_prefix_len(addr)?);
+            info!("ioctl: set address of {} to {}", iface.get_ifname(), cidr);
+            let cidr = if addr.is_unspecified() {
+                None
+            } else {
+                Some(cidr)
+            };
+            set_ipv4_addr(&iface, cidr)?;
+            return Ok(0);
+        }
+        SIOCGIFBRDADDR => {
+            let cidr = ipv4_addr(iface.as_ref()).ok_or(LxError::EADDRNOTAVAIL)?;
+            let broadcast = cidr.broadcast().unwrap_or_else(|| cidr.address());
+            req.set_ipv4(broadcast);
+        }
+        SIOCGIFNETMASK => {
+            let cidr = ipv4_addr(iface.as_ref()).ok_or(LxError::EADDRNOTAVAIL)?;
+            req.set_ipv4(cidr.netmask());
+        }
+        SIOCSIFNETMASK => {
+            let mask = sockaddr_in_addr(&req.ifr_addr)?;
+            let prefix_len = netmask_prefix_len(mask).ok_or(LxError::EINVAL)?;
+            let cidr = ipv4_addr(iface.as_ref()).ok_or(LxError::EADDRNOTAVAIL)?;
+            info!("ioctl: set netmask of {} to {}", iface.get_ifname(), mask);
+            set_ipv4_addr(&iface, Some(Ipv4Cidr::new(cidr.address(), prefix_len)))?;
+            return Ok(0);
+        }
+        SIOCGIFMETRIC => req.set_int(0),
+        SIOCGIFMTU => req.set_int(mtu(iface.as_ref()) as i32),
+        SIOCSIFMTU => {
+            set_mtu(iface.as_ref(), req.int().max(0) as u32)?;
+            return Ok(0);
+        }
+        SIOCGIFHWADDR => {
+            let mut data = [0; 14];
+            let family = if is_loopback(iface.as_ref()) {
+                ARPHRD_LOOPBACK
+            } else {
+                data[..6].copy_from_slice(iface.get_mac().as_bytes());
+                ARPHRD_ETHER
+            };
+            req.ifr_addr = SockAddrPlaceholder { family, data };
+        }
+        SIOCGIFINDEX => req.set_int(index as i32),
+        SIOCGIFTXQLEN => req.set_int(TX_QUEUE_LEN as i32),
+        // no memory or I/O resources to report
+        SIOCGIFMAP => {
+            req.ifr_addr = SockAddrPlaceholder {
+                family: 0,
+                data: [0; 14],
+            };
+            req._pad = [0; 8];
+        }
+        _ => unreachable!(),
+    }
+    ptr.write(req)?;
+    Ok(0)
+}
+
+fn route_ioctl(request: usize, ptr: UserInOutPtr<RtEntry>) -> SysResult {
+    let entry = ptr.read()?;
+    let dst = sockaddr_in_addr(&entry.rt_dst)?;
+    let prefix_len = if entry.rt_flags & RTF_HOST != 0 {
+        32
+    } else {
+        let genmask = sockaddr_in_addr(&entry.rt_genmask).unwrap_or(Ipv4Address::UNSPECIFIED);
+        netmask_prefix_len(genmask).ok_or(LxError::EINVAL)?
+    };
+    let dev = if entry.rt_dev != 0 {
+        let name = UserInPtr::<u8>::from(entry.rt_dev).as_c_str()?;
+        Some(iface_by_name(name)?.1)
+    } else {
+        None
+    };
+    if prefix_len != 0 {
+        let dst = IpCidr::Ipv4(Ipv4Cidr::new(dst, prefix_len));
+        return match request {
+            SIOCADDRT => {
+                if entry.rt_flags & RTF_UP == 0 {
+                    return Err(LxError::EINVAL);
+                }
+                let gateway = if entry.rt_flags & RTF_GATEWAY != 0 {
+                    Some(sockaddr_in_addr(&entry.rt_gateway)?)
+                } else {
+                    None
+                };
+                let iface = match (dev, gateway) {
+                    (Some(iface), _) => iface,
+                    (None, Some(gateway)) => gateway_iface(gateway).ok_or(LxError::ENETUNREACH)?,
+                    (None, None) => return Err(LxError::ENETUNREACH),
+                };
+                // the metric is passed plus 1 as in route(8)
+                let metric = (entry.rt_metric.max(1) - 1) as u32;
+                add_route(&iface, dst, gateway.map(IpAddress::Ipv4), metric)?;
+                Ok(0)
+            }
+            SIOCDELRT => {
+                del_route(dst, dev.as_ref())?;
+                Ok(0)
+            }
+            _ => unreachable!(),
+        };
+    }
+    match request {
+        SIOCADDRT => {
+            if entry.rt_flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+                return Err(LxError::EINVAL);
+            }
+            let gateway = sockaddr_in_addr(&entry.rt_gateway)?;
+            let iface = match dev {
+                Some(iface) => iface,
+                None => gateway_iface(gateway).ok_or(LxError::ENETUNREACH)?,
+            };
+            info!(
+                "ioctl: add default route via {} on {}",
+                gateway,
+                iface.get_ifname()
+            );
+            set_ipv4_gateway(&iface, Some(gateway))?;
+        }
+        SIOCDELRT => {
+            let gateway = sockaddr_in_addr(&entry.rt_gateway).ok();
+            let iface = get_net_device()
+                .into_iter()
+                .filter(|iface| dev.as_ref().map_or(true, |dev| Arc::ptr_eq(dev, iface)))
+                .find(|iface| match iface.get_ipv4_gateway() {
+                    Some(gw) => gateway.map_or(true, |addr| addr.is_unspecified() || addr == gw),
+                    None => false,
+                })
+                .ok_or(LxError::ESRCH)?;
+            info!("ioctl: delete default route on {}", iface.get_ifname());
+            set_ipv4_gateway(&iface, None)?;
+        }
+        _ => unreachable!(),
+    }
+    Ok(0)
+}
+
+/// The interface on the same network as `gateway`.
+pub fn gateway_iface(gateway: Ipv4Address) -> Option<Arc<dyn NetScheme>> {
+    get_net_device()
+        .into_iter()
+        .find(|iface| ipv4_addr(iface.as_ref()).map_or(false, |cidr| cidr.contains_addr(&gateway)))
+}
+
+/// The interface to reach the IPv6 `gateway` on: the one on the same network,
+/// or the first Ethernet interface for link-local gateways.
+pub fn ipv6_gateway_iface(gateway: Ipv6Address) -> Option<Arc<dyn NetScheme>> {
+    let ifaces = get_net_device();
+    let on_network = ifaces.iter().find(|iface| {
+        ipv6_addrs(iface.as_ref())
+            .iter()
+            .any(|cidr| !cidr.address().is_link_local() && cidr.contains_addr(&gateway))
+    });
+    match on_network {
+        Some(iface) => Some(iface.clone()),
+        None if gateway.is_link_local() => ifaces
+            .into_iter()
+            .find(|iface| !is_loopback(iface.as_ref())),
+        None => None,
+    }
+}
@@ -24,6 +24,23 @@ pub use raw::*;
 pub mod netlink;
 pub use netlink::*;
 
+pub mod packet;
+pub use packet::*;
+
+pub mod bpf;
+
+pub mod iface;
+
+pub mod sockopt;
+
+pub mod port;
+pub use port::{ip_local_port_range, set_ip_local_port_range};
+
+pub mod netfilter;
+
+pub mod vsock;
+pub use vsock::VsockSocketState;
+
 /// missing documentation
 // pub mod icmp;
 // pub use icmp::*;
@@ -108,6 +125,8 @@ numeric_enum! {
         AF_NETLINK = 16,
     /// Low-level packet interface
     AF_PACKET = 17,
+        /// Virtual sockets between the guest and the host
+        AF_VSOCK = 40,
     }
 }
 
@@ -175,6 +194,10 @@ numeric_enum! {
         SOL_SOCKET = 1,
         /// ipproto tcp
         IPPROTO_TCP = 6,
+        /// ipproto ipv6
+        IPPROTO_IPV6 = 41,
+        /// sol packet
+        SOL_PACKET = 263,
     }
 }
 
@@ -183,12 +206,36 @@ numeric_enum! {
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     /// Generic musl socket optname.
     pub enum SolOptname {
+        /// reuseaddr
+        REUSEADDR = 2,
+        /// type
+        TYPE = 3,
+        /// error
+        ERROR = 4,
+        /// broadcast
+        BROADCAST = 6,
         /// sndbuf
         SNDBUF = 7,  // 获取发送缓冲区长度
         /// rcvbuf
         RCVBUF = 8,  // 获取接收缓冲区长度
+        /// keepalive
+        KEEPALIVE = 9,
         /// linger
         LINGER = 13,
+        /// reuseport
+        REUSEPORT = 15,
+        /// rcvtimeo
+        RCVTIMEO = 20,
+        /// sndtimeo
+        SNDTIMEO = 21,
+        /// bindtodevice
+        BINDTODEVICE = 25,
+        /// attach filter
+        ATTACH_FILTER = 26,
+        /// detach filter
+        DETACH_FILTER = 27,
+        /// acceptconn
+        ACCEPTCONN = 30,
     }
 }
 
@@ -197,6 +244,16 @@ numeric_enum! {
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     /// Generic musl socket optname.
     pub enum TcpOptname {
+        /// nodelay
+        NODELAY = 1,
+        /// maxseg
+        MAXSEG = 2,
+        /// keepidle
+        KEEPIDLE = 4,
+        /// keepintvl
+        KEEPINTVL = 5,
+        /// keepcnt
+        KEEPCNT = 6,
         /// congestion
         CONGESTION = 13,
     }
@@ -207,8 +264,30 @@ numeric_enum! {
     #[derive(Debug, PartialEq, Eq, Clone, Copy)]
     /// Generic musl socket optname.
     pub enum IpOptname {
+        /// tos
+        TOS = 1,
+        /// ttl
+        TTL = 2,
         /// hdrincl
         HDRINCL = 3,
+        /// multicast ttl
+        MULTICAST_TTL = 33,
+        /// multicast loop
+        MULTICAST_LOOP = 34,
+        /// add membership
+        ADD_MEMBERSHIP = 35,
+        /// drop membership
+        DROP_MEMBERSHIP = 36,
+    }
+}
+
+numeric_enum! {
+    #[repr(usize)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    /// Generic musl socket optname.
+    pub enum Ipv6Optname {
+        /// v6only
+        V6ONLY = 26,
     }
 }
 
@@ -247,44 +326,39 @@ use kernel_hal::net::get_net_device;
 
 /// miss doc
 fn poll_ifaces() {
-    for iface in get_net_device().iter() {
-        match iface.poll() {
-            Ok(_) => {}
-            Err(e) => {
-                warn!("error : {:?}", e)
+    let ifaces = get_net_device();
+    // interfaces may be added or reconfigured at any time
+    zcore_drivers::net::route::update_ifaces(&ifaces);
+    // an interface stops sending at a packet routed to another one, poll again
+    // for the sockets after it once the other interface has sent the packet
+    for _ in 0..2 {
+        for iface in ifaces.iter() {
+            match iface.poll() {
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("error : {:?}", e)
+                }
             }
         }
+        if !zcore_drivers::net::route::take_refused() {
+            break;
+        }
     }
+    // apply renewed DHCP leases and router advertisements
+    zcore_drivers::net::dhcp::update();
+    zcore_drivers::net::slaac::update();
+    netfilter::update();
 }
 
 // ============= SocketHandle =============
 
 // ============= Rand Port =============
 
-/// !!!! need riscv rng
+/// A random number for the network stack.
 pub fn rand() -> u64 {
-    // use core::arch::x86_64::_rdtsc;
-    // rdrand is not implemented in QEMU
-    // so use rdtsc instead
-    10000
-}
-
-#[allow(unsafe_code)]
-/// missing documentation
-fn get_ephemeral_port() -> u16 {
-    // TODO selects non-conflict high port
-    static mut EPHEMERAL_PORT: u16 = 0;
-    unsafe {
-        if EPHEMERAL_PORT == 0 {
-            EPHEMERAL_PORT = (49152 + rand() % (65536 - 49152)) as u16;
-        }
-        if EPHEMERAL_PORT == 65535 {
-            EPHEMERAL_PORT = 49152;
-        } else {
-            EPHEMERAL_PORT += 1;
-        }
-        EPHEMERAL_PORT
-    }
+    let mut buf = [0; 8];
+    kernel_hal::rand::fill_random(&mut buf);
+    u64::from_ne_bytes(buf)
 }
 
 // ============= Rand Port =============
@@ -307,6 +381,7 @@ use alloc::boxed::Box;
 use alloc::fmt::Debug;
 use alloc::sync::Arc;
 use async_trait::async_trait;
+use core::convert::TryFrom;
 // use core::ops::{Deref, DerefMut};
 /// Common methods that a socket must have
 #[async_trait]
@@ -350,10 +425,26 @@ pub trait Socket: Send + Sync + Debug {
         warn!("setsockopt is unimplemented");
         Ok(0)
     }
+    /// Get an option into `data`, returns the length of the option.
+    fn getsockopt(&self, level: usize, opt: usize, data: &mut [u8]) -> LxResult<usize> {
+        match (Level::try_from(level), SolOptname::try_from(opt)) {
+            (Ok(Level::SOL_SOCKET), Ok(SolOptname::TYPE)) => {
+                let socket_type = self.socket_type().ok_or(LxError::ENOPROTOOPT)?;
+                sockopt::write_int(data, socket_type as i32)
+            }
+            (Ok(Level::SOL_SOCKET), Ok(SolOptname::ERROR)) => sockopt::write_int(data, 0),
+            _ => {
+                warn!("getsockopt is unimplemented: level={}, opt={}", level, opt);
+                Err(LxError::ENOPROTOOPT)
+            }
+        }
+    }
     /// missing documentation
-    fn ioctl(&self, _request: usize, _arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
-        warn!("ioctl is unimplemented for this socket");
-        Ok(0)
+    fn ioctl(&self, request: usize, arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
+        iface::ioctl(request, arg1).unwrap_or_else(|| {
+            warn!("ioctl is unimplemented for this socket");
+            Ok(0)
+        })
     }
     /// Get Socket recv and send buffer capacity
     fn get_buffer_capacity(&self) -> Option<(usize, usize)> {
@@ -0,0 +1,199 @@
+//! Connection tracking
+//!
+//! A connection is known by the tuples of both directions. The reply tuple is
+//! the reverse of the original one, until changed by NAT: DNAT changes its
+//! source, and SNAT its destination.
+
+use super::frame::{Packet, PROTO_ICMP, PROTO_TCP, TCP_FIN, TCP_RST};
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+use smoltcp::wire::Ipv4Address;
+
+/// The maximum number of tracked connections, as `nf_conntrack_max`.
+const CONNTRACK_MAX: usize = 4096;
+
+/// Timeout of connections without replies.
+const UNREPLIED_TIMEOUT: Duration = Duration::from_secs(30);
+/// Timeout of established TCP connections, 5 days as in Linux.
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(5 * 24 * 3600);
+/// Timeout of TCP connections after FIN or RST, and of ICMP echoes with
+/// replies.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Timeout of UDP flows with replies.
+const REPLIED_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// The addresses of a packet in a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Tuple {
+    pub proto: u8,
+    pub src: Ipv4Address,
+    pub sport: u16,
+    pub dst: Ipv4Address,
+    pub dport: u16,
+}
+
+impl Tuple {
+    pub fn of(packet: &Packet) -> Self {
+        Tuple {
+            proto: packet.proto,
+            src: packet.src,
+            sport: packet.sport,
+            dst: packet.dst,
+            dport: packet.dport,
+        }
+    }
+
+    /// The tuple of packets in the other direction.
+    pub fn reverse(&self) -> Self {
+        Tuple {
+            proto: self.proto,
+            src: self.dst,
+            sport: self.dport,
+            dst: self.src,
+            dport: self.sport,
+        }
+    }
+}
+
+/// The direction of a packet in its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Dir {
+    Original,
+    Reply,
+}
+
+/// A tracked connection.
+#[derive(Debug)]
+pub(super) struct Conn {
+    pub orig: Tuple,
+    pub reply: Tuple,
+    /// A packet in the reply direction has been seen.
+    pub replied: bool,
+    /// Whether the destination and the source NAT rules have been evaluated.
+    pub dnat_done: bool,
+    pub snat_done: bool,
+    closing: bool,
+    expires: Duration,
+}
+
+impl Conn {
+    /// The tuple that packets in `dir` are rewritten to.
+    pub fn target(&self, dir: Dir) -> Tuple {
+        match dir {
+            Dir::Original => self.reply.reverse(),
+            Dir::Reply => self.orig.reverse(),
+        }
+    }
+}
+
+/// The connection tracking table.
+#[derive(Default)]
+pub(super) struct Conntrack {
+    conns: BTreeMap<u64, Conn>,
+    tuples: BTreeMap<Tuple, (u64, Dir)>,
+    next_id: u64,
+}
+
+impl Conntrack {
+    /// Find the connection of `tuple`.
+    pub fn lookup(&self, tuple: &Tuple) -> Option<(u64, Dir)> {
+        self.tuples.get(tuple).copied()
+    }
+
+    pub fn get(&self, id: u64) -> &Conn {
+        &self.conns[&id]
+    }
+
+    pub fn get_mut(&mut self, id: u64) -> &mut Conn {
+        self.conns.get_mut(&id).unwrap()
+    }
+
+    /// Track a new connection started by a packet of `orig`.
+    ///
+    /// If the table is full, the unreplied connection which expires first is
+    /// evicted, returns `None` if there is none.
+    pub fn create(&mut self, orig: Tuple, now: Duration) -> Option<u64> {
+        if self.conns.len() >= CONNTRACK_MAX {
+            let victim = self
+                .conns
+                .iter()
+                .filter(|(_, conn)| !conn.replied)
+                .min_by_key(|(_, conn)| conn.expires)
+                .map(|(&id, _)| id)?;
+            self.remove(victim);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let reply = orig.reverse();
+        self.tuples.insert(orig, (id, Dir::Original));
+        self.tuples.insert(reply, (id, Dir::Reply));
+        let conn = Conn {
+            orig,
+            reply,
+            replied: false,
+            dnat_done: false,
+            snat_done: false,
+            closing: false,
+            expires: now + UNREPLIED_TIMEOUT,
+        };
+        self.conns.insert(id, conn);
+        Some(id)
+    }
+
+    /// Whether `tuple` is used by another connection than `id`.
+    pub fn is_taken(&self, tuple: &Tuple, id: u64) -> bool {
+        matches!(self.tuples.get(tuple), Some(&(other, _)) if other != id)
+    }
+
+    /// Change the reply tuple of a connection, for NAT.
+    pub fn set_reply(&mut self, id: u64, reply: Tuple) {
+        let conn = self.conns.get_mut(&id).unwrap();
+        self.tuples.remove(&conn.reply);
+        conn.reply = reply;
+        self.tuples.insert(reply, (id, Dir::Reply));
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        if let Some(conn) = self.conns.remove(&id) {
+            self.tuples.remove(&conn.orig);
+            self.tuples.remove(&conn.reply);
+        }
+    }
+
+    /// Update the state and the timeout of a connection by a packet in `dir`.
+    pub fn update(&mut self, id: u64, dir: Dir, packet: &Packet, now: Duration) {
+        let conn = self.conns.get_mut(&id).unwrap();
+        if dir == Dir::Reply {
+            conn.replied = true;
+        }
+        if conn.orig.proto == PROTO_TCP && packet.flags & (TCP_FIN | TCP_RST) != 0 {
+            conn.closing = true;
+        }
+        let timeout = if conn.closing {
+            CLOSE_TIMEOUT
+        } else if !conn.replied {
+            UNREPLIED_TIMEOUT
+        } else {
+            match conn.orig.proto {
+                PROTO_TCP => TCP_ESTABLISHED_TIMEOUT,
+                // an echo is done with its reply
+                PROTO_ICMP => CLOSE_TIMEOUT,
+                _ => REPLIED_TIMEOUT,
+            }
+        };
+        conn.expires = now + timeout;
+    }
+
+    /// Remove expired connections.
+    pub fn expire(&mut self, now: Duration) {
+        let expired: alloc::vec::Vec<u64> = self
+            .conns
+            .iter()
+            .filter(|(_, conn)| conn.expires <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Parsing and rewriting of IPv4 packets in Ethernet frames.
+//!
+//! Checksums are updated incrementally as in RFC 1624, so that packets with
+//! bad checksums are still dropped by smoltcp after being rewritten.
+
+use smoltcp::wire::Ipv4Address;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+
+pub(super) const PROTO_ICMP: u8 = 1;
+pub(super) const PROTO_TCP: u8 = 6;
+pub(super) const PROTO_UDP: u8 = 17;
+
+pub(super) const TCP_FIN: u8 = 0x01;
+pub(super) const TCP_RST: u8 = 0x04;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMP_PARAMETER_PROBLEM: u8 = 12;
+
+/// An IPv4 header.
+struct IpHeader {
+    proto: u8,
+    src: Ipv4Address,
+    dst: Ipv4Address,
+    len: usize,
+    /// A fragment other than the first one, without the transport header.
+    following: bool,
+}
+
+impl IpHeader {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 20 || data[0] >> 4 != 4 {
+            return None;
+        }
+        let len = (data[0] & 0xf) as usize * 4;
+        if len < 20 || data.len() < len {
+            return None;
+        }
+        Some(IpHeader {
+            proto: data[9],
+            src: Ipv4Address::from_bytes(&data[12..16]),
+            dst: Ipv4Address::from_bytes(&data[16..20]),
+            len,
+            following: read_u16(data, 6) & 0x1fff != 0,
+        })
+    }
+}
+
+/// The transport header embedded in an ICMP error, as (protocol, source,
+/// source port, destination, destination port).
+pub(super) type Embedded = (u8, Ipv4Address, u16, Ipv4Address, u16);
+
+/// The headers of an IPv4 packet.
+///
+/// For ICMP echo messages, the identifier is used as both ports.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Packet {
+    pub proto: u8,
+    pub src: Ipv4Address,
+    pub dst: Ipv4Address,
+    pub sport: u16,
+    pub dport: u16,
+    /// TCP flags
+    pub flags: u8,
+    /// The packet can be tracked, i.e. it's TCP, UDP or ICMP echo, and not a
+    /// following fragment.
+    pub trackable: bool,
+    /// For ICMP errors, the packet which caused it.
+    pub embedded: Option<Embedded>,
+    /// A fragment other than the first one.
+    pub following: bool,
+    /// Offset of the transport header in the frame.
+    l4: usize,
+}
+
+impl Packet {
+    /// Parse the IPv4 packet in `frame`, `None` if it's not IPv4.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let ethertype = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+        if ethertype != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let header = IpHeader::parse(&frame[ETHERNET_HEADER_LEN..])?;
+        let mut packet = Packet {
+            proto: header.proto,
+            src: header.src,
+            dst: header.dst,
+            sport: 0,
+            dport: 0,
+            flags: 0,
+            trackable: false,
+            embedded: None,
+            following: header.following,
+            l4: ETHERNET_HEADER_LEN + header.len,
+        };
+        if header.following {
+            return Some(packet);
+        }
+        let l4 = &frame[packet.l4..];
+        match packet.proto {
+            PROTO_TCP if l4.len() >= 20 => {
+                packet.sport = read_u16(l4, 0);
+                packet.dport = read_u16(l4, 2);
+                packet.flags = l4[13];
+                packet.trackable = true;
+            }
+            PROTO_UDP if l4.len() >= 8 => {
+                packet.sport = read_u16(l4, 0);
+                packet.dport = read_u16(l4, 2);
+                packet.trackable = true;
+            }
+            PROTO_ICMP if l4.len() >= 8 => match l4[0] {
+                ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY => {
+                    packet.sport = read_u16(l4, 4);
+                    packet.dport = packet.sport;
+                    packet.trackable = true;
+                }
+                ICMP_DEST_UNREACHABLE | ICMP_TIME_EXCEEDED | ICMP_PARAMETER_PROBLEM => {
+                    packet.embedded = IpHeader::parse(&l4[8..]).and_then(|inner| {
+                        let ports = l4.get(8 + inner.len..8 + inner.len + 4)?;
+                        let (sport, dport) = (read_u16(ports, 0), read_u16(ports, 2));
+                        Some((inner.proto, inner.src, sport, inner.dst, dport))
+                    });
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Some(packet)
+    }
+
+    /// The IP packet in `frame`.
+    pub fn ip_data<'a>(&self, frame: &'a [u8]) -> &'a [u8] {
+        &frame[ETHERNET_HEADER_LEN..]
+    }
+
+    /// Rewrite the source address and port.
+    pub fn set_src(&mut self, frame: &mut [u8], addr: Ipv4Address, port: u16) {
+        self.set_addr(frame, 12, self.src, addr);
+        self.src = addr;
+        if self.trackable && port != self.sport {
+            self.set_port(frame, 0, self.sport, port);
+            self.sport = port;
+            if self.proto == PROTO_ICMP {
+                self.dport = port;
+            }
+        }
+    }
+
+    /// Rewrite the destination address and port.
+    pub fn set_dst(&mut self, frame: &mut [u8], addr: Ipv4Address, port: u16) {
+        self.set_addr(frame, 16, self.dst, addr);
+        self.dst = addr;
+        if self.trackable && port != self.dport {
+            self.set_port(frame, 2, self.dport, port);
+            self.dport = port;
+            if self.proto == PROTO_ICMP {
+                self.sport = port;
+            }
+        }
+    }
+
+    /// Rewrite the address at `offset` of the IP header.
+    fn set_addr(&self, frame: &mut [u8], offset: usize, old: Ipv4Address, new: Ipv4Address) {
+        if old == new {
+            return;
+        }
+        let offset = ETHERNET_HEADER_LEN + offset;
+        frame[offset..offset + 4].copy_from_slice(&new.0);
+        let (old, new) = (addr_words(old), addr_words(new));
+        update_checksum(frame, ETHERNET_HEADER_LEN + 10, &old, &new);
+        // the pseudo header of TCP and UDP
+        if self.trackable && self.proto != PROTO_ICMP {
+            self.update_l4_checksum(frame, &old, &new);
+        }
+    }
+
+    /// Rewrite the port at `offset` of the TCP or UDP header, or the
+    /// identifier of ICMP echo messages.
+    fn set_port(&self, frame: &mut [u8], offset: usize, old: u16, new: u16) {
+        let offset = match self.proto {
+            PROTO_ICMP => self.l4 + 4,
+            _ => self.l4 + offset,
+        };
+        frame[offset..offset + 2].copy_from_slice(&new.to_be_bytes());
+        self.update_l4_checksum(frame, &[old], &[new]);
+    }
+
+    fn update_l4_checksum(&self, frame: &mut [u8], old: &[u16], new: &[u16]) {
+        match self.proto {
+            PROTO_TCP => update_checksum(frame, self.l4 + 16, old, new),
+            PROTO_ICMP => update_checksum(frame, self.l4 + 2, old, new),
+            // 0 means no checksum, and a checksum of 0 is sent as 0xffff
+            _ if read_u16(frame, self.l4 + 6) != 0 => {
+                update_checksum(frame, self.l4 + 6, old, new);
+                if read_u16(frame, self.l4 + 6) == 0 {
+                    frame[self.l4 + 6..self.l4 + 8].copy_from_slice(&[0xff, 0xff]);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn addr_words(addr: Ipv4Address) -> [u16; 2] {
+    [read_u16(&addr.0, 0), read_u16(&addr.0, 2)]
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Update the checksum at `offset` for the 16-bit words `old` replaced by
+/// `new`.
+fn update_checksum(frame: &mut [u8], offset: usize, old: &[u16], new: &[u16]) {
+    let mut sum = !read_u16(frame, offset) as u32;
+    for &word in old {
+        sum += !word as u32;
+    }
+    for &word in new {
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[offset..offset + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::conntrack::{Conntrack, Dir, Tuple};
+    use super::*;
+    use alloc::vec::Vec;
+    use core::time::Duration;
+
+    const A: Ipv4Address = Ipv4Address([10, 0, 0, 2]);
+    const B: Ipv4Address = Ipv4Address([192, 168, 1, 1]);
+    const C: Ipv4Address = Ipv4Address([172, 16, 5, 9]);
+
+    /// The one's complement checksum of `data`.
+    fn checksum(data: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for word in data.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        }
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    /// The checksum of a TCP or UDP segment with its pseudo header.
+    fn l4_checksum(frame: &[u8], proto: u8) -> u16 {
+        let ip = &frame[ETHERNET_HEADER_LEN..];
+        let l4 = &ip[20..];
+        let mut data = Vec::new();
+        data.extend_from_slice(&ip[12..20]);
+        data.extend_from_slice(&[0, proto]);
+        data.extend_from_slice(&(l4.len() as u16).to_be_bytes());
+        data.extend_from_slice(l4);
+        checksum(&data)
+    }
+
+    /// An Ethernet frame of an IPv4 packet with valid checksums, the transport
+    /// header being `l4` with a zero checksum at `csum`.
+    fn build_frame(
+        proto: u8,
+        src: Ipv4Address,
+        dst: Ipv4Address,
+        l4: &[u8],
+        csum: usize,
+    ) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&[0; 12]);
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let total_len = (20 + l4.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&total_len.to_be_bytes());
+        frame.extend_from_slice(&[0x12, 0x34, 0x40, 0, 64, proto, 0, 0]);
+        frame.extend_from_slice(&src.0);
+        frame.extend_from_slice(&dst.0);
+        frame.extend_from_slice(l4);
+        let sum = checksum(&frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + 20]);
+        frame[ETHERNET_HEADER_LEN + 10..ETHERNET_HEADER_LEN + 12]
+            .copy_from_slice(&sum.to_be_bytes());
+        let sum = match proto {
+            PROTO_ICMP => checksum(&frame[ETHERNET_HEADER_LEN + 20..]),
+            _ => l4_checksum(&frame, proto),
+        };
+        let offset = ETHERNET_HEADER_LEN + 20 + csum;
+        frame[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+        frame
+    }
+
+    fn tcp_frame(src: Ipv4Address, sport: u16, dst: Ipv4Address, dport: u16) -> Vec<u8> {
+        let mut l4 = [0u8; 24];
+        l4[0..2].copy_from_slice(&sport.to_be_bytes());
+        l4[2..4].copy_from_slice(&dport.to_be_bytes());
+        l4[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        l4[12] = 5 << 4;
+        l4[13] = 0x18; // PSH, ACK
+        l4[20..].copy_from_slice(b"data");
+        build_frame(PROTO_TCP, src, dst, &l4, 16)
+    }
+
+    fn checksums_valid(frame: &[u8], proto: u8) -> bool {
+        let ip_ok = checksum(&frame[ETHERNET_HEADER_LEN..ETHERNET_HEADER_LEN + 20]) == 0;
+        let l4_ok = match proto {
+            PROTO_ICMP => checksum(&frame[ETHERNET_HEADER_LEN + 20..]) == 0,
+            _ => l4_checksum(frame, proto) == 0,
+        };
+        ip_ok && l4_ok
+    }
+
+    #[test]
+    fn incremental_checksum() {
+        // the example of RFC 1624 section 4, where eqn. 3 gives 0x0000
+        let mut data = [0xdd, 0x2f];
+        update_checksum(&mut data, 0, &[0x5555], &[0x3285]);
+        assert_eq!(data, [0x00, 0x00]);
+
+        // the same result as recomputing the checksum
+        let mut data = [0u8; 20];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i * 53 + 7) as u8;
+        }
+        data[10..12].fill(0);
+        let sum = checksum(&data);
+        data[10..12].copy_from_slice(&sum.to_be_bytes());
+        for new in [
+            [0u16, 0],
+            [0xffff, 0xffff],
+            [0x0a00, 0x0002],
+            [0xc0a8, 0x0101],
+        ] {
+            let mut data = data;
+            let old = [read_u16(&data, 12), read_u16(&data, 14)];
+            data[12..14].copy_from_slice(&new[0].to_be_bytes());
+            data[14..16].copy_from_slice(&new[1].to_be_bytes());
+            update_checksum(&mut data, 10, &old, &new);
+            assert_eq!(checksum(&data), 0, "{:x?}", new);
+        }
+    }
+
+    #[test]
+    fn nat_rewrite_tcp() {
+        let mut frame = tcp_frame(A, 40000, B, 80);
+        assert!(checksums_valid(&frame, PROTO_TCP));
+        let mut packet = Packet::parse(&frame).unwrap();
+        assert!(packet.trackable);
+        assert_eq!(
+            (packet.src, packet.sport, packet.dst, packet.dport),
+            (A, 40000, B, 80)
+        );
+        assert_eq!(packet.flags, 0x18);
+
+        // SNAT, then DNAT
+        packet.set_src(&mut frame, C, 50000);
+        assert!(checksums_valid(&frame, PROTO_TCP));
+        packet.set_dst(&mut frame, A, 8080);
+        assert!(checksums_valid(&frame, PROTO_TCP));
+        let parsed = Packet::parse(&frame).unwrap();
+        assert_eq!(
+            (parsed.src, parsed.sport, parsed.dst, parsed.dport),
+            (C, 50000, A, 8080)
+        );
+        assert_eq!(&frame[frame.len() - 4..], b"data");
+    }
+
+    #[test]
+    fn nat_rewrite_udp_and_icmp() {
+        // a UDP checksum of 0 means none, and isn't updated
+        let mut l4 = [0u8; 12];
+        l4[0..2].copy_from_slice(&5353u16.to_be_bytes());
+        l4[2..4].copy_from_slice(&53u16.to_be_bytes());
+        l4[4..6].copy_from_slice(&12u16.to_be_bytes());
+        let mut frame = build_frame(PROTO_UDP, A, B, &l4, 6);
+        frame[ETHERNET_HEADER_LEN + 26..ETHERNET_HEADER_LEN + 28].fill(0);
+        let mut packet = Packet::parse(&frame).unwrap();
+        packet.set_src(&mut frame, C, 1024);
+        assert_eq!(read_u16(&frame, ETHERNET_HEADER_LEN + 26), 0);
+        assert_eq!(read_u16(&frame, ETHERNET_HEADER_LEN + 20), 1024);
+
+        // the identifier of echo requests is translated as both ports
+        let mut l4 = [0u8; 16];
+        l4[0] = ICMP_ECHO_REQUEST;
+        l4[4..6].copy_from_slice(&0x4242u16.to_be_bytes());
+        let mut frame = build_frame(PROTO_ICMP, A, B, &l4, 2);
+        let mut packet = Packet::parse(&frame).unwrap();
+        assert_eq!((packet.sport, packet.dport), (0x4242, 0x4242));
+        packet.set_src(&mut frame, C, 0x1111);
+        assert!(checksums_valid(&frame, PROTO_ICMP));
+        assert_eq!((packet.sport, packet.dport), (0x1111, 0x1111));
+        assert_eq!(read_u16(&frame, ETHERNET_HEADER_LEN + 24), 0x1111);
+    }
+
+    #[test]
+    fn conntrack_lookup() {
+        let frame = tcp_frame(A, 40000, B, 80);
+        let packet = Packet::parse(&frame).unwrap();
+        let orig = Tuple::of(&packet);
+        let now = Duration::from_secs(1);
+        let mut conntrack = Conntrack::default();
+        assert_eq!(conntrack.lookup(&orig), None);
+        let id = conntrack.create(orig, now).unwrap();
+        assert_eq!(conntrack.lookup(&orig), Some((id, Dir::Original)));
+        assert_eq!(conntrack.lookup(&orig.reverse()), Some((id, Dir::Reply)));
+
+        // SNAT to C:50000 changes the destination of replies
+        let reply = Tuple {
+            dst: C,
+            dport: 50000,
+            ..orig.reverse()
+        };
+        conntrack.set_reply(id, reply);
+        assert_eq!(conntrack.lookup(&orig.reverse()), None);
+        assert_eq!(conntrack.lookup(&reply), Some((id, Dir::Reply)));
+        let target = conntrack.get(id).target(Dir::Original);
+        assert_eq!((target.src, target.sport), (C, 50000));
+        let target = conntrack.get(id).target(Dir::Reply);
+        assert_eq!((target.dst, target.dport), (A, 40000));
+        assert!(conntrack.is_taken(&reply, id + 1));
+        assert!(!conntrack.is_taken(&reply, id));
+
+        // unreplied connections expire
+        conntrack.update(id, Dir::Original, &packet, now);
+        conntrack.expire(now + Duration::from_secs(60));
+        assert_eq!(conntrack.lookup(&orig), None);
+        assert_eq!(conntrack.lookup(&reply), None);
+    }
+}
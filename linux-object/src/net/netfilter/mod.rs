@@ -0,0 +1,488 @@
+//! Packet filtering and NAT in the style of netfilter
+//!
+//! IPv4 packets received from and sent to network devices pass hooks:
+//!
+//! - received: [`Hook::PreRouting`], then [`Hook::Input`];
+//! - sent: [`Hook::Output`], then [`Hook::PostRouting`].
+//!
+//! At each hook, the eBPF programs attached to it run first, then the chain of
+//! the hook in the `nat` and `filter` tables as in iptables. Rules are changed
+//! by [`execute`] with iptables commands, which userspace writes to
+//! `/dev/netfilter`.
+//!
+//! Connections are tracked to match the state of packets and to translate
+//! addresses: the `nat` chains only see the first packet of each connection,
+//! and later packets are translated in the same way.
+//!
+//! Limitations: the loopback interface is not filtered, rules can't match the
+//! interface, DNAT in `OUTPUT` doesn't reroute the packet, fragments other
+//! than the first one pass without being filtered, and the packets embedded
+//! in ICMP errors are not translated.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use kernel_hal::timer::timer_now;
+use lazy_static::lazy_static;
+use lock::Mutex;
+use zcore_drivers::net::filter::{set_filter, FrameFilter};
+use zircon_object::ebpf::program::BpfProgram;
+
+use self::conntrack::{Conntrack, Dir, Tuple};
+use self::frame::{Packet, PROTO_ICMP};
+use self::rule::State;
+use crate::error::{LxError, LxResult};
+
+mod conntrack;
+mod frame;
+mod rule;
+
+pub use self::rule::{Command, NatRange, Rule, Table, Target};
+
+/// How often expired connections are removed.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A point where packets are filtered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Received packets, before `INPUT`.
+    PreRouting,
+    /// Received packets.
+    Input,
+    /// Sent packets.
+    Output,
+    /// Sent packets, after `OUTPUT`.
+    PostRouting,
+}
+
+const HOOKS: [Hook; 4] = [
+    Hook::PreRouting,
+    Hook::Input,
+    Hook::Output,
+    Hook::PostRouting,
+];
+
+impl Hook {
+    /// The name of the chain at the hook.
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreRouting => "PREROUTING",
+            Hook::Input => "INPUT",
+            Hook::Output => "OUTPUT",
+            Hook::PostRouting => "POSTROUTING",
+        }
+    }
+
+    /// Parse the name of a chain, ignoring case.
+    pub fn parse(name: &str) -> LxResult<Self> {
+        HOOKS
+            .iter()
+            .copied()
+            .find(|hook| hook.name().eq_ignore_ascii_case(name))
+            .ok_or(LxError::ENOENT)
+    }
+
+    /// Whether NAT rewrites the destination at the hook, otherwise the source.
+    fn is_dst_manip(self) -> bool {
+        matches!(self, Hook::PreRouting | Hook::Output)
+    }
+}
+
+/// The context of eBPF programs attached to hooks. The packet is read-only,
+/// and programs return 0 to drop it.
+#[repr(C)]
+struct BpfContext {
+    /// The hook, from 0 for `PREROUTING`.
+    hook: u64,
+    /// The length of the IP packet.
+    len: u64,
+    /// The start and the end of the IP packet.
+    data: u64,
+    data_end: u64,
+}
+
+/// The state of a packet in connection tracking.
+struct Tracked {
+    state: State,
+    conn: Option<(u64, Dir)>,
+    /// The connection is started by the packet.
+    created: bool,
+}
+
+struct Netfilter {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    filter: [Vec<Rule>; 4],
+    nat: [Vec<Rule>; 4],
+    /// Whether the chains of the filter table accept unmatched packets.
+    policies: [bool; 4],
+    /// The attached eBPF programs, in the order they run.
+    programs: [Vec<Arc<BpfProgram>>; 4],
+    conntrack: Conntrack,
+    next_expire: Duration,
+}
+
+lazy_static! {
+    static ref NETFILTER: Arc<Netfilter> = Arc::new(Netfilter {
+        inner: Mutex::new(Inner {
+            filter: Default::default(),
+            nat: Default::default(),
+            policies: [true; 4],
+            programs: Default::default(),
+            conntrack: Conntrack::default(),
+            next_expire: Duration::ZERO,
+        }),
+    });
+}
+
+/// The filter is installed by the first configuration, so that devices
+/// don't pay for it otherwise.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+fn install() {
+    if !INSTALLED.swap(true, Ordering::SeqCst) {
+        set_filter(Some(NETFILTER.clone()));
+    }
+}
+
+/// Execute an iptables command, see [`Command::parse`].
+pub fn execute(line: &str) -> LxResult {
+    let command = match Command::parse(line)? {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    install();
+    let mut inner = NETFILTER.inner.lock();
+    match command {
+        Command::Append(table, hook, rule) => inner.chain(table, hook).push(rule),
+        Command::Insert(table, hook, index, rule) => {
+            let chain = inner.chain(table, hook);
+            if index > chain.len() {
+                return Err(LxError::EINVAL);
+            }
+            chain.insert(index, rule);
+        }
+        Command::Delete(table, hook, index) => {
+            let chain = inner.chain(table, hook);
+            if index >= chain.len() {
+                return Err(LxError::EINVAL);
+            }
+            chain.remove(index);
+        }
+        Command::Flush(table, Some(hook)) => inner.chain(table, hook).clear(),
+        Command::Flush(table, None) => {
+            for hook in HOOKS {
+                inner.chain(table, hook).clear();
+            }
+        }
+        Command::Policy(hook, accept) => inner.policies[hook as usize] = accept,
+    }
+    Ok(())
+}
+
+/// List the rules as iptables commands, and the attached eBPF programs as
+/// comments.
+pub fn rules() -> String {
+    let inner = NETFILTER.inner.lock();
+    let mut text = String::new();
+    for hook in HOOKS {
+        if Table::Filter.has_chain(hook) {
+            let policy = if inner.policies[hook as usize] {
+                "ACCEPT"
+            } else {
+                "DROP"
+            };
+            writeln!(text, "-P {} {}", hook.name(), policy).unwrap();
+        }
+    }
+    for hook in HOOKS {
+        for rule in inner.filter[hook as usize].iter() {
+            writeln!(text, "-A {}{}", hook.name(), rule).unwrap();
+        }
+    }
+    for hook in HOOKS {
+        for rule in inner.nat[hook as usize].iter() {
+            writeln!(text, "-t nat -A {}{}", hook.name(), rule).unwrap();
+        }
+    }
+    for hook in HOOKS {
+        for i in 0..inner.programs[hook as usize].len() {
+            writeln!(text, "# bpf program {} attached to {}", i, hook.name()).unwrap();
+        }
+    }
+    text
+}
+
+/// Attach the eBPF program `prog` to `hook`.
+///
+/// A program is attached once to a hook, however many file descriptors refer
+/// to it.
+pub fn attach_program(hook: Hook, prog: Arc<BpfProgram>) -> LxResult {
+    install();
+    let mut inner = NETFILTER.inner.lock();
+    let programs = &mut inner.programs[hook as usize];
+    if programs.iter().any(|other| Arc::ptr_eq(other, &prog)) {
+        return Err(LxError::EEXIST);
+    }
+    programs.push(prog);
+    Ok(())
+}
+
+/// Detach the eBPF program `prog` from `hook`.
+pub fn detach_program(hook: Hook, prog: &Arc<BpfProgram>) -> LxResult {
+    let mut inner = NETFILTER.inner.lock();
+    let programs = &mut inner.programs[hook as usize];
+    let index = programs
+        .iter()
+        .position(|other| Arc::ptr_eq(other, prog))
+        .ok_or(LxError::ENOENT)?;
+    programs.remove(index);
+    Ok(())
+}
+
+/// Remove expired connections, called when polling interfaces.
+pub fn update() {
+    if !INSTALLED.load(Ordering::SeqCst) {
+        return;
+    }
+    let now = timer_now();
+    let mut inner = NETFILTER.inner.lock();
+    if now >= inner.next_expire {
+        inner.conntrack.expire(now);
+        inner.next_expire = now + EXPIRE_INTERVAL;
+    }
+}
+
+impl FrameFilter for Netfilter {
+    fn filter(&self, frame: &mut [u8], outgoing: bool) -> bool {
+        match Packet::parse(frame) {
+            Some(mut packet) if !packet.following => {
+                self.inner
+                    .lock()
+                    .pass(frame, &mut packet, outgoing, timer_now())
+            }
+            _ => true,
+        }
+    }
+}
+
+impl Inner {
+    fn chain(&mut self, table: Table, hook: Hook) -> &mut Vec<Rule> {
+        match table {
+            Table::Filter => &mut self.filter[hook as usize],
+            Table::Nat => &mut self.nat[hook as usize],
+        }
+    }
+
+    /// Pass `packet` through the hooks of its direction, returns whether it's
+    /// accepted.
+    fn pass(
+        &mut self,
+        frame: &mut [u8],
+        packet: &mut Packet,
+        outgoing: bool,
+        now: Duration,
+    ) -> bool {
+        let tracked = match self.track(packet, now) {
+            Some(tracked) => tracked,
+            None => {
+                warn!("netfilter: conntrack table full, dropping packet");
+                return false;
+            }
+        };
+        let hooks = if outgoing {
+            [Hook::Output, Hook::PostRouting]
+        } else {
+            [Hook::PreRouting, Hook::Input]
+        };
+        let accept = hooks
+            .iter()
+            .all(|&hook| self.hook(hook, frame, packet, &tracked));
+        match tracked.conn {
+            Some((id, dir)) if accept => self.conntrack.update(id, dir, packet, now),
+            // forget connections whose first packet is dropped
+            Some((id, _)) if tracked.created => self.conntrack.remove(id),
+            _ => {}
+        }
+        accept
+    }
+
+    /// Find or create the connection of `packet`, returns `None` if the
+    /// connection tracking table is full.
+    fn track(&mut self, packet: &Packet, now: Duration) -> Option<Tracked> {
+        if packet.trackable {
+            let tuple = Tuple::of(packet);
+            if let Some((id, dir)) = self.conntrack.lookup(&tuple) {
+                let state = if dir == Dir::Original && !self.conntrack.get(id).replied {
+                    State::New
+                } else {
+                    State::Established
+                };
+                return Some(Tracked {
+                    state,
+                    conn: Some((id, dir)),
+                    created: false,
+                });
+            }
+            let id = self.conntrack.create(tuple, now)?;
+            return Some(Tracked {
+                state: State::New,
+                conn: Some((id, Dir::Original)),
+                created: true,
+            });
+        }
+        // the embedded packet was sent in either direction, after NAT
+        let related = packet
+            .embedded
+            .map_or(false, |(proto, src, sport, dst, dport)| {
+                let tuple = Tuple {
+                    proto,
+                    src,
+                    sport,
+                    dst,
+                    dport,
+                };
+                self.conntrack.lookup(&tuple).is_some()
+                    || self.conntrack.lookup(&tuple.reverse()).is_some()
+            });
+        Some(Tracked {
+            state: if related {
+                State::Related
+            } else {
+                State::Invalid
+            },
+            conn: None,
+            created: false,
+        })
+    }
+
+    /// Pass `packet` through `hook`, returns whether it's accepted.
+    fn hook(
+        &mut self,
+        hook: Hook,
+        frame: &mut [u8],
+        packet: &mut Packet,
+        tracked: &Tracked,
+    ) -> bool {
+        if !self.run_programs(hook, frame, packet) {
+            return false;
+        }
+        // destination NAT happens before filtering, and source NAT after it
+        if hook.is_dst_manip() && !self.nat(hook, frame, packet, tracked) {
+            return false;
+        }
+        if Table::Filter.has_chain(hook) {
+            let target = self.filter[hook as usize]
+                .iter()
+                .find(|rule| rule.matches(packet, tracked.state))
+                .map(|rule| rule.target);
+            let accept = match target {
+                Some(target) => target == Target::Accept,
+                None => self.policies[hook as usize],
+            };
+            if !accept {
+                return false;
+            }
+        }
+        hook.is_dst_manip() || self.nat(hook, frame, packet, tracked)
+    }
+
+    fn run_programs(&self, hook: Hook, frame: &[u8], packet: &Packet) -> bool {
+        let data = packet.ip_data(frame);
+        let ctx = BpfContext {
+            hook: hook as u64,
+            len: data.len() as u64,
+            data: data.as_ptr() as u64,
+            data_end: data.as_ptr() as u64 + data.len() as u64,
+        };
+        self.programs[hook as usize]
+            .iter()
+            .all(|prog| prog.run(&ctx as *const BpfContext as *const u8) != 0)
+    }
+
+    /// Translate the addresses of `packet` at `hook`, evaluating the nat
+    /// chain if it's the first packet of its connection. Returns `false` if
+    /// no port is free to translate to.
+    fn nat(
+        &mut self,
+        hook: Hook,
+        frame: &mut [u8],
+        packet: &mut Packet,
+        tracked: &Tracked,
+    ) -> bool {
+        let (id, dir) = match tracked.conn {
+            Some(conn) => conn,
+            None => return true,
+        };
+        let dst_manip = hook.is_dst_manip();
+        let conn = self.conntrack.get_mut(id);
+        let done = if dst_manip {
+            core::mem::replace(&mut conn.dnat_done, true)
+        } else {
+            core::mem::replace(&mut conn.snat_done, true)
+        };
+        if !done {
+            let target = self.nat[hook as usize]
+                .iter()
+                .find(|rule| rule.matches(packet, tracked.state))
+                .map(|rule| rule.target);
+            if let Some(Target::Snat(range)) | Some(Target::Dnat(range)) = target {
+                if !self.bind(id, range, dst_manip) {
+                    return false;
+                }
+            }
+        }
+        let target = self.conntrack.get(id).target(dir);
+        if dst_manip {
+            packet.set_dst(frame, target.dst, target.dport);
+        } else {
+            packet.set_src(frame, target.src, target.sport);
+        }
+        true
+    }
+
+    /// Change the reply tuple of connection `id` to translate its destination
+    /// or its source to `range`.
+    fn bind(&mut self, id: u64, range: NatRange, dst_manip: bool) -> bool {
+        let mut reply = self.conntrack.get(id).reply;
+        let old_port = if dst_manip {
+            reply.src = range.addr;
+            reply.sport
+        } else {
+            reply.dst = range.addr;
+            reply.dport
+        };
+        let with_port = |port: u16| {
+            let mut tuple = reply;
+            // ICMP echo identifiers are both ports
+            if dst_manip || tuple.proto == PROTO_ICMP {
+                tuple.sport = port;
+            }
+            if !dst_manip || tuple.proto == PROTO_ICMP {
+                tuple.dport = port;
+            }
+            tuple
+        };
+        let is_free = |port: &u16| !self.conntrack.is_taken(&with_port(*port), id);
+        let (first, last) = range.ports.unwrap_or((old_port, old_port));
+        let mut port = (first..=last).find(is_free);
+        // SNAT without ports picks another one if the port is taken
+        if port.is_none() && range.ports.is_none() && !dst_manip {
+            port = (1024..=u16::MAX).find(is_free);
+        }
+        match port {
+            Some(port) => {
+                let reply = with_port(port);
+                self.conntrack.set_reply(id, reply);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,394 @@
+//! Rules and their iptables syntax
+
+use super::frame::{Packet, PROTO_ICMP, PROTO_TCP, PROTO_UDP};
+use super::Hook;
+use crate::error::{LxError, LxResult};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+/// A table of chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    /// Accepts or drops packets, in `INPUT` and `OUTPUT`.
+    Filter,
+    /// Translates addresses of new connections, in all chains.
+    Nat,
+}
+
+impl Table {
+    fn parse(name: &str) -> LxResult<Self> {
+        match name {
+            "filter" => Ok(Table::Filter),
+            "nat" => Ok(Table::Nat),
+            _ => Err(LxError::EINVAL),
+        }
+    }
+
+    /// Whether the table has a chain at `hook`.
+    pub fn has_chain(self, hook: Hook) -> bool {
+        match self {
+            Table::Filter => matches!(hook, Hook::Input | Hook::Output),
+            Table::Nat => true,
+        }
+    }
+}
+
+/// The state of the connection of a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum State {
+    /// The packet starts a connection, or its connection has no replies yet.
+    New,
+    /// The connection has seen packets in both directions.
+    Established,
+    /// An ICMP error about a tracked connection.
+    Related,
+    /// The packet can't be tracked.
+    Invalid,
+}
+
+const STATES: [(State, &str); 4] = [
+    (State::Invalid, "INVALID"),
+    (State::New, "NEW"),
+    (State::Related, "RELATED"),
+    (State::Established, "ESTABLISHED"),
+];
+
+impl State {
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// An address, and a range of ports to translate to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatRange {
+    /// The new address.
+    pub addr: Ipv4Address,
+    /// The first and the last new port, or `None` to keep the port if
+    /// possible.
+    pub ports: Option<(u16, u16)>,
+}
+
+impl NatRange {
+    fn parse(s: &str) -> LxResult<Self> {
+        let (addr, ports) = match s.split_once(':') {
+            Some((addr, ports)) => (addr, Some(parse_ports(ports, '-')?)),
+            None => (s, None),
+        };
+        Ok(NatRange {
+            addr: addr.parse().map_err(|_| LxError::EINVAL)?,
+            ports,
+        })
+    }
+}
+
+impl fmt::Display for NatRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        match self.ports {
+            Some((first, last)) if first == last => write!(f, ":{}", first),
+            Some((first, last)) => write!(f, ":{}-{}", first, last),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What to do with a matched packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Let the packet pass, and stop evaluating the chain.
+    Accept,
+    /// Drop the packet.
+    Drop,
+    /// Translate the source of the connection.
+    Snat(NatRange),
+    /// Translate the destination of the connection.
+    Dnat(NatRange),
+}
+
+/// A rule in a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    proto: Option<u8>,
+    src: Option<Ipv4Cidr>,
+    dst: Option<Ipv4Cidr>,
+    sport: Option<(u16, u16)>,
+    dport: Option<(u16, u16)>,
+    /// Bits of accepted states, 0 for all.
+    states: u8,
+    /// What to do with matched packets.
+    pub target: Target,
+}
+
+impl Rule {
+    /// Parse the matches and the target of a rule in `table` and the chain at
+    /// `hook`.
+    fn parse<'a>(
+        args: &mut impl Iterator<Item = &'a str>,
+        table: Table,
+        hook: Hook,
+    ) -> LxResult<Self> {
+        let mut rule = Rule {
+            proto: None,
+            src: None,
+            dst: None,
+            sport: None,
+            dport: None,
+            states: 0,
+            target: Target::Accept,
+        };
+        let mut target = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(LxError::EINVAL);
+            match arg {
+                "-p" | "--protocol" => {
+                    rule.proto = match value()? {
+                        "all" => None,
+                        "icmp" => Some(PROTO_ICMP),
+                        "tcp" => Some(PROTO_TCP),
+                        "udp" => Some(PROTO_UDP),
+                        proto => Some(parse_num(proto)?),
+                    }
+                }
+                "-s" | "--source" => rule.src = Some(parse_cidr(value()?)?),
+                "-d" | "--destination" => rule.dst = Some(parse_cidr(value()?)?),
+                "--sport" | "--source-port" => rule.sport = Some(parse_ports(value()?, ':')?),
+                "--dport" | "--destination-port" => rule.dport = Some(parse_ports(value()?, ':')?),
+                // the protocol modules are implied
+                "-m" | "--match" => match value()? {
+                    "state" | "conntrack" | "tcp" | "udp" => {}
+                    _ => return Err(LxError::EINVAL),
+                },
+                "--state" | "--ctstate" => {
+                    for name in value()?.split(',') {
+                        let (state, _) = STATES
+                            .iter()
+                            .find(|(_, s)| s.eq_ignore_ascii_case(name))
+                            .ok_or(LxError::EINVAL)?;
+                        rule.states |= state.bit();
+                    }
+                }
+                "-j" | "--jump" => target = Some(value()?),
+                "--to-source" | "--to-destination" => {
+                    let range = NatRange::parse(value()?)?;
+                    rule.target = match (arg, target) {
+                        ("--to-source", Some("SNAT")) => Target::Snat(range),
+                        ("--to-destination", Some("DNAT")) => Target::Dnat(range),
+                        _ => return Err(LxError::EINVAL),
+                    };
+                }
+                _ => return Err(LxError::EINVAL),
+            }
+        }
+        let ports = rule.sport.is_some() || rule.dport.is_some();
+        if ports && !matches!(rule.proto, Some(PROTO_TCP) | Some(PROTO_UDP)) {
+            return Err(LxError::EINVAL);
+        }
+        let valid = match (target, rule.target) {
+            (Some("ACCEPT"), _) => {
+                rule.target = Target::Accept;
+                true
+            }
+            (Some("DROP"), _) => {
+                rule.target = Target::Drop;
+                table == Table::Filter
+            }
+            (Some("SNAT"), Target::Snat(_)) => {
+                table == Table::Nat && matches!(hook, Hook::Input | Hook::PostRouting)
+            }
+            (Some("DNAT"), Target::Dnat(_)) => {
+                table == Table::Nat && matches!(hook, Hook::PreRouting | Hook::Output)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(LxError::EINVAL);
+        }
+        Ok(rule)
+    }
+
+    /// Whether `packet` in `state` matches the rule.
+    pub(super) fn matches(&self, packet: &Packet, state: State) -> bool {
+        let port_matches = |range: Option<(u16, u16)>, port: u16| match range {
+            Some((first, last)) => packet.trackable && first <= port && port <= last,
+            None => true,
+        };
+        self.proto.map_or(true, |proto| proto == packet.proto)
+            && self
+                .src
+                .map_or(true, |cidr| cidr.contains_addr(&packet.src))
+            && self
+                .dst
+                .map_or(true, |cidr| cidr.contains_addr(&packet.dst))
+            && port_matches(self.sport, packet.sport)
+            && port_matches(self.dport, packet.dport)
+            && (self.states == 0 || self.states & state.bit() != 0)
+    }
+}
+
+impl fmt::Display for Rule {
+    /// Format the rule as the arguments of `iptables -A`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.proto {
+            Some(PROTO_ICMP) => write!(f, " -p icmp")?,
+            Some(PROTO_TCP) => write!(f, " -p tcp")?,
+            Some(PROTO_UDP) => write!(f, " -p udp")?,
+            Some(proto) => write!(f, " -p {}", proto)?,
+            None => {}
+        }
+        if let Some(cidr) = self.src {
+            write!(f, " -s {}", cidr)?;
+        }
+        if let Some(cidr) = self.dst {
+            write!(f, " -d {}", cidr)?;
+        }
+        for (name, range) in [("--sport", self.sport), ("--dport", self.dport)] {
+            match range {
+                Some((first, last)) if first == last => write!(f, " {} {}", name, first)?,
+                Some((first, last)) => write!(f, " {} {}:{}", name, first, last)?,
+                None => {}
+            }
+        }
+        if self.states != 0 {
+            let names: Vec<&str> = STATES
+                .iter()
+                .filter(|(state, _)| self.states & state.bit() != 0)
+                .map(|&(_, name)| name)
+                .collect();
+            write!(f, " -m conntrack --ctstate {}", names.join(","))?;
+        }
+        match self.target {
+            Target::Accept => write!(f, " -j ACCEPT"),
+            Target::Drop => write!(f, " -j DROP"),
+            Target::Snat(range) => write!(f, " -j SNAT --to-source {}", range),
+            Target::Dnat(range) => write!(f, " -j DNAT --to-destination {}", range),
+        }
+    }
+}
+
+/// A change of the rules.
+#[derive(Debug)]
+pub enum Command {
+    /// Append a rule to a chain.
+    Append(Table, Hook, Rule),
+    /// Insert a rule into a chain, at the index from 0.
+    Insert(Table, Hook, usize, Rule),
+    /// Delete the rule at the index from 0 of a chain.
+    Delete(Table, Hook, usize),
+    /// Delete all rules of a chain, or of all chains of a table.
+    Flush(Table, Option<Hook>),
+    /// Set the policy of a chain of the filter table to `ACCEPT` (`true`) or
+    /// `DROP` (`false`).
+    Policy(Hook, bool),
+}
+
+impl Command {
+    /// Parse a command in the syntax of `iptables`, with the leading
+    /// `iptables` optional. Returns `None` for empty lines and comments.
+    ///
+    /// Supported commands are `-A`, `-I`, `-D` by rule number, `-F` and `-P`.
+    pub fn parse(line: &str) -> LxResult<Option<Self>> {
+        let mut args = line.split_whitespace().peekable();
+        match args.peek() {
+            None => return Ok(None),
+            Some(arg) if arg.starts_with('#') => return Ok(None),
+            Some(&"iptables") => {
+                args.next();
+            }
+            _ => {}
+        }
+        let mut table = Table::Filter;
+        if matches!(args.peek(), Some(&"-t") | Some(&"--table")) {
+            args.next();
+            table = Table::parse(args.next().ok_or(LxError::EINVAL)?)?;
+        }
+        let command = args.next().ok_or(LxError::EINVAL)?;
+        let chain = |name: Option<&str>| -> LxResult<Hook> {
+            let hook = Hook::parse(name.ok_or(LxError::EINVAL)?)?;
+            if table.has_chain(hook) {
+                Ok(hook)
+            } else {
+                Err(LxError::ENOENT)
+            }
+        };
+        let command = match command {
+            "-A" | "--append" => {
+                let hook = chain(args.next())?;
+                Command::Append(table, hook, Rule::parse(&mut args, table, hook)?)
+            }
+            "-I" | "--insert" => {
+                let hook = chain(args.next())?;
+                // the rule number is optional, 1 by default
+                let index = match args.peek().and_then(|arg| arg.parse::<usize>().ok()) {
+                    Some(num) => {
+                        args.next();
+                        num.checked_sub(1).ok_or(LxError::EINVAL)?
+                    }
+                    None => 0,
+                };
+                Command::Insert(table, hook, index, Rule::parse(&mut args, table, hook)?)
+            }
+            "-D" | "--delete" => {
+                let hook = chain(args.next())?;
+                let num = parse_num::<usize>(args.next().ok_or(LxError::EINVAL)?)?;
+                Command::Delete(table, hook, num.checked_sub(1).ok_or(LxError::EINVAL)?)
+            }
+            "-F" | "--flush" => match args.peek() {
+                Some(_) => Command::Flush(table, Some(chain(args.next())?)),
+                None => Command::Flush(table, None),
+            },
+            "-P" | "--policy" => {
+                let hook = chain(args.next())?;
+                let accept = match args.next() {
+                    Some("ACCEPT") => true,
+                    Some("DROP") => false,
+                    _ => return Err(LxError::EINVAL),
+                };
+                if table != Table::Filter {
+                    return Err(LxError::EINVAL);
+                }
+                Command::Policy(hook, accept)
+            }
+            _ => return Err(LxError::EINVAL),
+        };
+        if args.next().is_some() {
+            return Err(LxError::EINVAL);
+        }
+        Ok(Some(command))
+    }
+}
+
+/// Parse `ADDR[/PREFIX_LEN]`.
+fn parse_cidr(s: &str) -> LxResult<Ipv4Cidr> {
+    let (addr, prefix_len) = match s.split_once('/') {
+        Some((addr, prefix_len)) => (addr, parse_num(prefix_len)?),
+        None => (s, 32),
+    };
+    if prefix_len > 32 {
+        return Err(LxError::EINVAL);
+    }
+    let addr: Ipv4Address = addr.parse().map_err(|_| LxError::EINVAL)?;
+    // the network of the address, as iptables does
+    Ok(Ipv4Cidr::new(addr, prefix_len).network())
+}
+
+/// Parse a port, or a range of ports separated by `sep`.
+fn parse_ports(s: &str, sep: char) -> LxResult<(u16, u16)> {
+    let (first, last) = match s.split_once(sep) {
+        Some((first, last)) => (parse_num(first)?, parse_num(last)?),
+        None => {
+            let port = parse_num(s)?;
+            (port, port)
+        }
+    };
+    if first > last {
+        return Err(LxError::EINVAL);
+    }
+    Ok((first, last))
+}
+
+fn parse_num<T: FromStr>(s: &str) -> LxResult<T> {
+    s.parse().map_err(|_| LxError::EINVAL)
+}
@@ -1,244 +1,1147 @@
-// udpsocket
+//! Netlink sockets
+//!
+//! Only the NETLINK_ROUTE protocol is supported:
+//!
+//! - RTM_GETLINK, RTM_NEWLINK, RTM_SETLINK
+//! - RTM_GETADDR, RTM_NEWADDR, RTM_DELADDR
+//! - RTM_GETROUTE, RTM_NEWROUTE, RTM_DELROUTE
+//!
+//! Addresses and routes are of IPv4 and IPv6. Changes of links, addresses and
+//! routes are notified to the sockets in the `RTMGRP_LINK`,
+//! `RTMGRP_IPV4_IFADDR`, `RTMGRP_IPV4_ROUTE`, `RTMGRP_IPV6_IFADDR` and
+//! `RTMGRP_IPV6_ROUTE` groups.
 
+use super::iface;
 use super::socket_address::*;
 use crate::{
     error::{LxError, LxResult},
-    fs::FileLike,
-    net::{AddressFamily, Endpoint, SockAddr, Socket, SysResult},
+    fs::{FileLike, OpenFlags, PollEvents, PollStatus},
+    net::{AddressFamily, Endpoint, SockAddr, Socket, SocketType, SysResult},
+    sync::{wait_for_event, Event, EventBus},
+};
+use alloc::collections::VecDeque;
+use alloc::{
+    boxed::Box,
+    sync::{Arc, Weak},
+    vec::Vec,
 };
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
 use async_trait::async_trait;
 use bitflags::bitflags;
+use core::sync::atomic::{AtomicU32, Ordering};
 use core::{mem::size_of, slice};
 use kernel_hal::{net::get_net_device, user::*};
+use lazy_static::lazy_static;
 use lock::Mutex;
+use smoltcp::wire::{IpAddress, IpCidr, Ipv4Address, Ipv6Address};
+use zcore_drivers::net::route;
+use zcore_drivers::scheme::NetScheme;
+use zircon_object::{impl_kobject, object::*};
+
+/// Routing and link updates, the only supported netlink protocol
+pub const NETLINK_ROUTE: usize = 0;
+
+/// Level of netlink socket options
+const SOL_NETLINK: usize = 270;
+const NETLINK_ADD_MEMBERSHIP: usize = 1;
+const NETLINK_DROP_MEMBERSHIP: usize = 2;
+
+// multicast groups, as bits of `nl_groups`
+const RTMGRP_LINK: u32 = 0x1;
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
 
-#[derive(Debug, Clone)]
+/// Maximum number of notifications queued on a socket, further ones are dropped.
+const MAX_NOTIFICATIONS: usize = 256;
+
+/// Netlink socket
 pub struct NetlinkSocketState {
-    data: Arc<Mutex<Vec<Vec<u8>>>>,
-    _local_endpoint: Option<NetlinkEndpoint>,
+    base: KObjectBase,
+    shared: Arc<NetlinkShared>,
+}
+
+/// The part of a socket that notifications are delivered to.
+struct NetlinkShared {
+    inner: Mutex<NetlinkInner>,
+    eventbus: Arc<Mutex<EventBus>>,
+}
+
+struct NetlinkInner {
+    /// Datagrams to be received
+    messages: VecDeque<Vec<u8>>,
+    /// Port ID, 0 if the socket is not bound yet
+    port_id: u32,
+    /// Joined multicast groups
+    groups: u32,
+    /// flags on the socket
+    flags: OpenFlags,
+}
+
+lazy_static! {
+    /// All netlink sockets, to look up port IDs and deliver notifications.
+    static ref SOCKETS: Mutex<Vec<Weak<NetlinkShared>>> = Mutex::new(Vec::new());
+}
+
+/// The next port ID to try when binding automatically.
+static NEXT_PORT_ID: AtomicU32 = AtomicU32::new(1);
+
+impl NetlinkShared {
+    /// Queue datagrams to be received.
+    fn push(&self, messages: Vec<Vec<u8>>) {
+        if messages.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock();
+        inner.messages.extend(messages);
+        self.eventbus.lock().set(Event::READABLE);
+    }
+
+    /// Queue a notification, unless too many are pending.
+    fn push_notification(&self, message: Vec<u8>) {
+        let mut inner = self.inner.lock();
+        if inner.messages.len() >= MAX_NOTIFICATIONS {
+            warn!(
+                "netlink: port {} overrun, notification dropped",
+                inner.port_id
+            );
+            return;
+        }
+        inner.messages.push_back(message);
+        self.eventbus.lock().set(Event::READABLE);
+    }
 }
 
 impl Default for NetlinkSocketState {
     fn default() -> Self {
-        Self {
-            data: Arc::new(Mutex::new(Vec::new())),
-            _local_endpoint: Some(NetlinkEndpoint::new(0, 0)),
+        Self::new()
+    }
+}
+
+impl NetlinkSocketState {
+    /// Create a NETLINK_ROUTE socket.
+    pub fn new() -> Self {
+        let shared = Arc::new(NetlinkShared {
+            inner: Mutex::new(NetlinkInner {
+                messages: VecDeque::new(),
+                port_id: 0,
+                groups: 0,
+                flags: OpenFlags::RDWR,
+            }),
+            eventbus: EventBus::new(),
+        });
+        let mut sockets = SOCKETS.lock();
+        sockets.retain(|socket| socket.strong_count() > 0);
+        sockets.push(Arc::downgrade(&shared));
+        NetlinkSocketState {
+            base: KObjectBase::new(),
+            shared,
+        }
+    }
+
+    /// Bind the socket to `port_id`, or an unused one if it's 0.
+    ///
+    /// Returns the bound port ID, which can't be changed once bound.
+    fn bind_port(&self, port_id: u32) -> LxResult<u32> {
+        // binding is serialized by the lock of all sockets
+        let sockets = SOCKETS.lock();
+        let in_use = |id: u32| {
+            sockets
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|socket| !Arc::ptr_eq(socket, &self.shared))
+                .any(|socket| socket.inner.lock().port_id == id)
+        };
+        let mut inner = self.shared.inner.lock();
+        if inner.port_id != 0 {
+            return match port_id {
+                0 => Ok(inner.port_id),
+                id if id == inner.port_id => Ok(id),
+                _ => Err(LxError::EINVAL),
+            };
+        }
+        if port_id != 0 {
+            if in_use(port_id) {
+                return Err(LxError::EADDRINUSE);
+            }
+            inner.port_id = port_id;
+        } else {
+            loop {
+                let id = NEXT_PORT_ID.fetch_add(1, Ordering::Relaxed);
+                if id != 0 && !in_use(id) {
+                    inner.port_id = id;
+                    break;
+                }
+            }
         }
+        Ok(inner.port_id)
     }
 }
-impl NetlinkSocketState {}
+
+impl_kobject!(NetlinkSocketState);
 
 #[async_trait]
 impl Socket for NetlinkSocketState {
-    /// missing documentation
-    async fn read(&self, data: &mut [u8]) -> (LxResult<usize>, Endpoint) {
-        let mut buffer = self.data.lock();
-        let msg = buffer.remove(0);
-        let len = msg.len();
-        if !msg.is_empty() {
-            data[..len].copy_from_slice(&msg[..len]);
-            (
-                Ok(len),
-                Endpoint::Netlink(NetlinkEndpoint {
-                    port_id: 0,
-                    multicast_groups_mask: 0,
-                }),
-            )
-        } else {
-            (
-                Ok(0),
-                Endpoint::Netlink(NetlinkEndpoint {
-                    port_id: 0,
-                    multicast_groups_mask: 0,
-                }),
-            )
+    /// Receive a datagram, the part not fitting in `data` is discarded.
+    async fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let kernel = Endpoint::Netlink(NetlinkEndpoint::new(0, 0));
+        loop {
+            {
+                let mut inner = self.shared.inner.lock();
+                if let Some(msg) = inner.messages.pop_front() {
+                    let len = msg.len().min(data.len());
+                    data[..len].copy_from_slice(&msg[..len]);
+                    if inner.messages.is_empty() {
+                        self.shared.eventbus.lock().clear(Event::READABLE);
+                    }
+                    return (Ok(len), kernel);
+                }
+                if inner.flags.non_block() {
+                    return (Err(LxError::EAGAIN), kernel);
+                }
+            }
+            wait_for_event(self.shared.eventbus.clone(), Event::READABLE).await;
         }
     }
 
+    /// Send requests to the kernel, replies are queued to be received.
     fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
-        if data.len() < size_of::<NetlinkMessageHeader>() {
-            return Err(LxError::EINVAL);
+        let port_id = self.bind_port(0)?;
+        let mut replies = Vec::new();
+        let mut offset = 0;
+        // a datagram may carry several requests
+        while offset + size_of::<NetlinkMessageHeader>() <= data.len() {
+            #[allow(unsafe_code)]
+            let header = unsafe {
+                (data[offset..].as_ptr() as *const NetlinkMessageHeader).read_unaligned()
+            };
+            let len = header.nlmsg_len as usize;
+            if len < size_of::<NetlinkMessageHeader>() || offset + len > data.len() {
+                return Err(LxError::EINVAL);
+            }
+            let payload = &data[offset + size_of::<NetlinkMessageHeader>()..offset + len];
+            handle_request(&header, payload, port_id, &mut replies);
+            offset += (len + 3) & !3;
         }
-        #[allow(unsafe_code)]
-        let header = unsafe { &*(data.as_ptr() as *const NetlinkMessageHeader) };
-        if header.nlmsg_len as usize > data.len() {
-            return Err(LxError::EINVAL);
+        self.shared.push(replies);
+        Ok(data.len())
+    }
+
+    fn poll(&self, _events: PollEvents) -> (bool, bool, bool) {
+        let readable = !self.shared.inner.lock().messages.is_empty();
+        (readable, true, false)
+    }
+
+    /// Set the default destination, only the kernel is supported.
+    async fn connect(&self, endpoint: Endpoint) -> SysResult {
+        match endpoint {
+            Endpoint::Netlink(NetlinkEndpoint { port_id: 0, .. }) => {
+                self.bind_port(0)?;
+                Ok(0)
+            }
+            Endpoint::Netlink(_) => Err(LxError::ECONNREFUSED),
+            _ => Err(LxError::EINVAL),
         }
-        let message_type = NetlinkMessageType::from(header.nlmsg_type);
-        let mut buffer = self.data.lock();
-        buffer.clear();
-        match message_type {
-            NetlinkMessageType::GetLink => {
-                let ifaces = get_net_device();
-                for (i, iface) in ifaces.iter().enumerate() {
-                    let mut msg = Vec::new();
-                    let new_header = NetlinkMessageHeader {
-                        nlmsg_len: 0, // to be determined later
-                        nlmsg_type: NetlinkMessageType::NewLink.into(),
-                        nlmsg_flags: NetlinkMessageFlags::MULTI,
-                        nlmsg_seq: header.nlmsg_seq,
-                        nlmsg_pid: header.nlmsg_pid,
-                    };
-                    msg.push_ext(new_header);
-
-                    let if_info = IfaceInfoMsg {
-                        ifi_family: AddressFamily::Unspecified.into(),
-                        ifi_type: 0,
-                        ifi_index: i as u32,
-                        ifi_flags: 0,
-                        ifi_change: 0,
-                    };
-                    msg.align4();
-                    msg.push_ext(if_info);
-
-                    let mut attrs = Vec::new();
-
-                    let mac_addr = iface.get_mac();
-                    let attr = RouteAttr {
-                        rta_len: (mac_addr.as_bytes().len() + size_of::<RouteAttr>()) as u16,
-                        rta_type: RouteAttrTypes::Address.into(),
-                    };
-                    attrs.align4();
-                    attrs.push_ext(attr);
-                    for byte in mac_addr.as_bytes() {
-                        attrs.push(*byte);
-                    }
+    }
 
-                    let ifname = iface.get_ifname();
-                    let attr = RouteAttr {
-                        rta_len: (ifname.as_bytes().len() + size_of::<RouteAttr>()) as u16,
-                        rta_type: RouteAttrTypes::Ifname.into(),
-                    };
-                    attrs.align4();
-                    attrs.push_ext(attr);
-                    for byte in ifname.as_bytes() {
-                        attrs.push(*byte);
-                    }
+    fn bind(&self, endpoint: Endpoint) -> SysResult {
+        match endpoint {
+            Endpoint::Netlink(endpoint) => {
+                self.bind_port(endpoint.port_id)?;
+                self.shared.inner.lock().groups = endpoint.multicast_groups_mask;
+                Ok(0)
+            }
+            _ => Err(LxError::EINVAL),
+        }
+    }
+
+    fn listen(&self) -> SysResult {
+        Err(LxError::EOPNOTSUPP)
+    }
 
-                    msg.align4();
-                    msg.append(&mut attrs);
+    fn shutdown(&self) -> SysResult {
+        Err(LxError::EOPNOTSUPP)
+    }
+
+    async fn accept(&self) -> LxResult<(Arc<dyn FileLike>, Endpoint)> {
+        Err(LxError::EOPNOTSUPP)
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        let inner = self.shared.inner.lock();
+        Some(Endpoint::Netlink(NetlinkEndpoint::new(
+            inner.port_id,
+            inner.groups,
+        )))
+    }
 
-                    msg.align4();
-                    msg.set_ext(0, msg.len() as u32);
+    fn remote_endpoint(&self) -> Option<Endpoint> {
+        Some(Endpoint::Netlink(NetlinkEndpoint::new(0, 0)))
+    }
 
-                    buffer.push(msg);
+    fn setsockopt(&self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        if level != SOL_NETLINK {
+            warn!("netlink: setsockopt level {} is ignored", level);
+            return Ok(0);
+        }
+        match opt {
+            NETLINK_ADD_MEMBERSHIP | NETLINK_DROP_MEMBERSHIP => {
+                if data.len() < size_of::<u32>() {
+                    return Err(LxError::EINVAL);
                 }
-            }
-            NetlinkMessageType::GetAddr => {
-                let ifaces = get_net_device();
-                for (i, iface) in ifaces.iter().enumerate() {
-                    let ip_addrs = iface.get_ip_address();
-
-                    // for j in 0..ip_addrs.len() {
-                    for ip in &ip_addrs {
-                        let mut msg = Vec::new();
-                        let new_header = NetlinkMessageHeader {
-                            nlmsg_len: 0, // to be determined later
-                            nlmsg_type: NetlinkMessageType::NewAddr.into(),
-                            nlmsg_flags: NetlinkMessageFlags::MULTI,
-                            nlmsg_seq: header.nlmsg_seq,
-                            nlmsg_pid: header.nlmsg_pid,
-                        };
-                        msg.push_ext(new_header);
-
-                        let family: u16 = AddressFamily::Internet.into();
-                        let if_addr = IfaceAddrMsg {
-                            ifa_family: family as u8,
-                            ifa_prefixlen: ip.prefix_len(),
-                            ifa_flags: 0,
-                            ifa_scope: 0,
-                            ifa_index: i as u32,
-                        };
-                        msg.align4();
-                        msg.push_ext(if_addr);
-
-                        let mut attrs = Vec::new();
-
-                        let ip_addr = ip.address();
-                        let attr = RouteAttr {
-                            rta_len: (ip_addr.as_bytes().len() + size_of::<RouteAttr>()) as u16,
-                            rta_type: RouteAttrTypes::Address.into(),
-                        };
-                        attrs.align4();
-                        attrs.push_ext(attr);
-                        for byte in ip_addr.as_bytes() {
-                            attrs.push(*byte);
-                        }
-
-                        msg.align4();
-                        msg.append(&mut attrs);
-
-                        msg.align4();
-                        msg.set_ext(0, msg.len() as u32);
-
-                        buffer.push(msg);
-                    }
+                let group = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+                if !(1..=32).contains(&group) {
+                    return Err(LxError::EINVAL);
+                }
+                let groups = &mut self.shared.inner.lock().groups;
+                if opt == NETLINK_ADD_MEMBERSHIP {
+                    *groups |= 1 << (group - 1);
+                } else {
+                    *groups &= !(1 << (group - 1));
                 }
+                Ok(0)
+            }
+            _ => {
+                warn!("netlink: setsockopt option {} is ignored", opt);
+                Ok(0)
+            }
+        }
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
+        super::iface::ioctl(request, arg1).unwrap_or(Ok(0))
+    }
+
+    fn socket_type(&self) -> Option<SocketType> {
+        Some(SocketType::SOCK_RAW)
+    }
+}
+
+#[async_trait]
+impl FileLike for NetlinkSocketState {
+    fn flags(&self) -> OpenFlags {
+        self.shared.inner.lock().flags
+    }
+
+    fn set_flags(&self, f: OpenFlags) -> LxResult {
+        let flags = &mut self.shared.inner.lock().flags;
+        flags.set(OpenFlags::NON_BLOCK, f.contains(OpenFlags::NON_BLOCK));
+        flags.set(OpenFlags::CLOEXEC, f.contains(OpenFlags::CLOEXEC));
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> LxResult<usize> {
+        Socket::read(self, buf).await.0
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
+        Err(LxError::ESPIPE)
+    }
+
+    fn write(&self, buf: &[u8]) -> LxResult<usize> {
+        Socket::write(self, buf, None)
+    }
+
+    fn poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    async fn async_poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        if self.shared.inner.lock().messages.is_empty() {
+            wait_for_event(self.shared.eventbus.clone(), Event::READABLE).await;
+        }
+        FileLike::poll(self, events)
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, arg2: usize, arg3: usize) -> LxResult<usize> {
+        Socket::ioctl(self, request, arg1, arg2, arg3)
+    }
+
+    fn as_socket(&self) -> LxResult<&dyn Socket> {
+        Ok(self)
+    }
+}
+
+/// Where replies and notifications are sent.
+#[derive(Debug, Clone, Copy)]
+struct Destination {
+    seq: u32,
+    port_id: u32,
+}
+
+/// Notifications are not in reply to any request.
+const NOTIFICATION: Destination = Destination { seq: 0, port_id: 0 };
+
+/// Handle a request, and queue the replies in `replies`.
+fn handle_request(
+    header: &NetlinkMessageHeader,
+    payload: &[u8],
+    port_id: u32,
+    replies: &mut Vec<Vec<u8>>,
+) {
+    let message_type = NetlinkMessageType::from(header.nlmsg_type);
+    let dest = Destination {
+        seq: header.nlmsg_seq,
+        port_id,
+    };
+    let dump = header.nlmsg_flags.contains(NetlinkMessageFlags::ROOT);
+    let res = match message_type {
+        // control messages are ignored
+        NetlinkMessageType::Noop
+        | NetlinkMessageType::Error
+        | NetlinkMessageType::Done
+        | NetlinkMessageType::Overrun => return,
+        NetlinkMessageType::GetLink if !dump => get_link(payload).map(|(index, iface)| {
+            replies.push(link_message(
+                NetlinkMessageType::NewLink,
+                NetlinkMessageFlags::empty(),
+                dest,
+                index,
+                &iface,
+            ))
+        }),
+        NetlinkMessageType::GetRoute if !dump => get_route(payload).map(|route| {
+            replies.push(route_message(
+                NetlinkMessageType::NewRoute,
+                NetlinkMessageFlags::empty(),
+                dest,
+                &route,
+            ))
+        }),
+        // getting a single address is not supported, so dump all of them
+        NetlinkMessageType::GetLink
+        | NetlinkMessageType::GetAddr
+        | NetlinkMessageType::GetRoute => {
+            // the family is the first byte of all kinds of requests
+            let family = AddressFamily::from(payload.first().map_or(0, |&family| family as u16));
+            match message_type {
+                NetlinkMessageType::GetLink => dump_links(dest, replies),
+                NetlinkMessageType::GetAddr => dump_addrs(dest, family, replies),
+                NetlinkMessageType::GetRoute => dump_routes(dest, family, replies),
+                _ => {}
+            }
+            // the end of a dump acknowledges the request
+            replies.push(done_message(dest));
+            return;
+        }
+        NetlinkMessageType::NewLink | NetlinkMessageType::SetLink => {
+            change_link(message_type, header.nlmsg_flags, payload)
+        }
+        NetlinkMessageType::NewAddr | NetlinkMessageType::DelAddr => {
+            change_addr(message_type, payload)
+        }
+        NetlinkMessageType::NewRoute | NetlinkMessageType::DelRoute => {
+            change_route(message_type, payload)
+        }
+        _ => {
+            warn!("netlink: unsupported message type {:?}", message_type);
+            Err(LxError::EOPNOTSUPP)
+        }
+    };
+    // errors are always reported, success only if requested
+    match res {
+        Ok(()) if !header.nlmsg_flags.contains(NetlinkMessageFlags::ACK) => {}
+        Ok(()) => replies.push(error_message(header, dest, 0)),
+        Err(err) => {
+            warn!("netlink: {:?} failed: {:?}", message_type, err);
+            replies.push(error_message(header, dest, -(err as i32)));
+        }
+    }
+}
+
+/// Start a message, to be finished by [`finish_message`].
+fn new_message(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    dest: Destination,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.push_ext(NetlinkMessageHeader {
+        nlmsg_len: 0, // to be determined later
+        nlmsg_type: message_type.into(),
+        nlmsg_flags: flags,
+        nlmsg_seq: dest.seq,
+        nlmsg_pid: dest.port_id,
+    });
+    msg
+}
+
+/// Pad the message and fill in its length.
+fn finish_message(mut msg: Vec<u8>) -> Vec<u8> {
+    msg.align4();
+    msg.set_ext(0, msg.len() as u32);
+    msg
+}
+
+/// Build an `NLMSG_DONE` message ending a dump.
+fn done_message(dest: Destination) -> Vec<u8> {
+    let mut msg = new_message(NetlinkMessageType::Done, NetlinkMessageFlags::MULTI, dest);
+    // the error code of the dump
+    msg.push_ext(0i32);
+    finish_message(msg)
+}
+
+/// Build an `NLMSG_ERROR` message replying `header`, an ACK if `error` is 0.
+fn error_message(header: &NetlinkMessageHeader, dest: Destination, error: i32) -> Vec<u8> {
+    let mut msg = new_message(
+        NetlinkMessageType::Error,
+        NetlinkMessageFlags::empty(),
+        dest,
+    );
+    msg.push_ext(error);
+    msg.push_ext(*header);
+    finish_message(msg)
+}
+
+/// Build a link message of the interface `index`.
+fn link_message(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    dest: Destination,
+    index: usize,
+    iface: &Arc<dyn NetScheme>,
+) -> Vec<u8> {
+    let mut msg = new_message(message_type, flags, dest);
+    let loopback = iface::is_loopback(iface.as_ref());
+    msg.push_ext(IfaceInfoMsg {
+        ifi_family: AddressFamily::Unspecified.into(),
+        ifi_type: if loopback {
+            iface::ARPHRD_LOOPBACK
+        } else {
+            iface::ARPHRD_ETHER
+        },
+        ifi_index: index as u32,
+        ifi_flags: iface::link_flags(iface.as_ref()),
+        ifi_change: 0,
+    });
+
+    let mut ifname = iface.get_ifname().into_bytes();
+    ifname.push(0);
+    msg.push_attr(RouteAttrTypes::Ifname.into(), &ifname);
+    let (address, broadcast) = if loopback {
+        ([0; 6], [0; 6])
+    } else {
+        (iface.get_mac().0, [0xff; 6])
+    };
+    msg.push_attr(RouteAttrTypes::Address.into(), &address);
+    msg.push_attr(RouteAttrTypes::Broadcast.into(), &broadcast);
+    let mtu = iface::mtu(iface.as_ref());
+    msg.push_attr(RouteAttrTypes::MTU.into(), &mtu.to_ne_bytes());
+    let txqlen = iface::TX_QUEUE_LEN;
+    msg.push_attr(RouteAttrTypes::TxQueueLen.into(), &txqlen.to_ne_bytes());
+    let operstate = if loopback {
+        IF_OPER_UNKNOWN
+    } else {
+        IF_OPER_UP
+    };
+    msg.push_attr(RouteAttrTypes::OperState.into(), &[operstate]);
+    msg.push_attr(RouteAttrTypes::LinkMode.into(), &[0]);
+    finish_message(msg)
+}
+
+/// Build an address message of the interface `index`.
+fn addr_message(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    dest: Destination,
+    index: usize,
+    iface: &Arc<dyn NetScheme>,
+    cidr: IpCidr,
+) -> Vec<u8> {
+    let mut msg = new_message(message_type, flags, dest);
+    let family: u16 = family_of(cidr.address()).into();
+    let addr = cidr.address();
+    msg.push_ext(IfaceAddrMsg {
+        ifa_family: family as u8,
+        ifa_prefixlen: cidr.prefix_len(),
+        ifa_flags: IFA_F_PERMANENT,
+        ifa_scope: match addr {
+            _ if is_loopback_addr(addr) => RT_SCOPE_HOST,
+            IpAddress::Ipv6(addr) if addr.is_link_local() => RT_SCOPE_LINK,
+            _ => RT_SCOPE_UNIVERSE,
+        },
+        ifa_index: index as u32,
+    });
+
+    msg.push_attr(IFA_ADDRESS, addr.as_bytes());
+    // IPv6 addresses are not point-to-point, and have no broadcast addresses
+    if let IpCidr::Ipv4(cidr) = cidr {
+        msg.push_attr(IFA_LOCAL, addr.as_bytes());
+        if let Some(broadcast) = cidr.broadcast().filter(|_| !cidr.address().is_loopback()) {
+            msg.push_attr(IFA_BROADCAST, broadcast.as_bytes());
+        }
+    }
+    let mut label = iface.get_ifname().into_bytes();
+    label.push(0);
+    msg.push_attr(IFA_LABEL, &label);
+    finish_message(msg)
+}
+
+/// An IPv4 or IPv6 route.
+struct RouteInfo {
+    dst: IpCidr,
+    gateway: Option<IpAddress>,
+    /// The preferred source address
+    prefsrc: Option<IpAddress>,
+    /// The output interface
+    oif: usize,
+    /// The priority among routes to the same destination, lower first
+    metric: u32,
+}
+
+/// Build a route message.
+fn route_message(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    dest: Destination,
+    route: &RouteInfo,
+) -> Vec<u8> {
+    let mut msg = new_message(message_type, flags, dest);
+    let family: u16 = family_of(route.dst.address()).into();
+    let (protocol, scope) = match route.gateway {
+        Some(_) => (RTPROT_BOOT, RT_SCOPE_UNIVERSE),
+        None if is_loopback_addr(route.dst.address()) => (RTPROT_KERNEL, RT_SCOPE_HOST),
+        None => (RTPROT_KERNEL, RT_SCOPE_LINK),
+    };
+    msg.push_ext(RouteMsg {
+        rtm_family: family as u8,
+        rtm_dst_len: route.dst.prefix_len(),
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: RT_TABLE_MAIN,
+        rtm_protocol: protocol,
+        rtm_scope: scope,
+        rtm_type: RTN_UNICAST,
+        rtm_flags: 0,
+    });
+
+    msg.push_attr(RTA_TABLE, &(RT_TABLE_MAIN as u32).to_ne_bytes());
+    if route.dst.prefix_len() != 0 {
+        msg.push_attr(RTA_DST, route.dst.address().as_bytes());
+    }
+    if let Some(prefsrc) = route.prefsrc {
+        msg.push_attr(RTA_PREFSRC, prefsrc.as_bytes());
+    }
+    if let Some(gateway) = route.gateway {
+        msg.push_attr(RTA_GATEWAY, gateway.as_bytes());
+    }
+    msg.push_attr(RTA_OIF, &(route.oif as u32).to_ne_bytes());
+    if route.metric != 0 {
+        msg.push_attr(RTA_PRIORITY, &route.metric.to_ne_bytes());
+    }
+    finish_message(msg)
+}
+
+/// The address family of `addr`.
+fn family_of(addr: IpAddress) -> AddressFamily {
+    match addr {
+        IpAddress::Ipv6(_) => AddressFamily::Internet6,
+        _ => AddressFamily::Internet,
+    }
+}
+
+/// Whether `addr` is of `family`, `AF_UNSPEC` matches all families.
+fn family_matches(family: AddressFamily, addr: IpAddress) -> bool {
+    family == AddressFamily::Unspecified || family == family_of(addr)
+}
+
+fn is_loopback_addr(addr: IpAddress) -> bool {
+    match addr {
+        IpAddress::Ipv4(addr) => addr.is_loopback(),
+        IpAddress::Ipv6(addr) => addr.is_loopback(),
+        _ => false,
+    }
+}
+
+/// The destination of default routes of `family`.
+fn default_dst(family: AddressFamily) -> IpCidr {
+    match family {
+        AddressFamily::Internet6 => IpCidr::new(Ipv6Address::UNSPECIFIED.into(), 0),
+        _ => IpCidr::new(Ipv4Address::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// Addresses of `iface`, ignoring the unspecified one of interfaces waiting
+/// for DHCP.
+fn ip_addrs(iface: &dyn NetScheme) -> Vec<IpCidr> {
+    iface
+        .get_ip_address()
+        .into_iter()
+        .filter(|cidr| !cidr.address().is_unspecified())
+        .collect()
+}
+
+/// The default gateway of `iface` in `family`.
+fn default_gateway(iface: &dyn NetScheme, family: AddressFamily) -> Option<IpAddress> {
+    match family {
+        AddressFamily::Internet6 => iface.get_ipv6_gateway().map(IpAddress::Ipv6),
+        _ => iface.get_ipv4_gateway().map(IpAddress::Ipv4),
+    }
+}
+
+/// Set the default gateway of `iface` in `family`, or remove it if `None`.
+fn set_default_gateway(
+    iface: &Arc<dyn NetScheme>,
+    family: AddressFamily,
+    gateway: Option<IpAddress>,
+) -> LxResult {
+    match (family, gateway) {
+        (AddressFamily::Internet6, Some(IpAddress::Ipv6(gw))) => {
+            iface::set_ipv6_gateway(iface, Some(gw))
+        }
+        (AddressFamily::Internet6, None) => iface::set_ipv6_gateway(iface, None),
+        (AddressFamily::Internet, Some(IpAddress::Ipv4(gw))) => {
+            iface::set_ipv4_gateway(iface, Some(gw))
+        }
+        (AddressFamily::Internet, None) => iface::set_ipv4_gateway(iface, None),
+        _ => Err(LxError::EINVAL),
+    }
+}
+
+/// Routes of all interfaces: the one to the network of each address, the
+/// default ones via the gateways, and the static ones.
+fn routes() -> Vec<RouteInfo> {
+    let mut routes = Vec::new();
+    for (i, iface) in get_net_device().iter().enumerate() {
+        for cidr in ip_addrs(iface.as_ref()) {
+            routes.push(RouteInfo {
+                dst: route::network(cidr),
+                gateway: None,
+                prefsrc: Some(cidr.address()),
+                oif: i + 1,
+                metric: 0,
+            });
+        }
+        for family in [AddressFamily::Internet, AddressFamily::Internet6] {
+            if let Some(gateway) = default_gateway(iface.as_ref(), family) {
+                routes.push(RouteInfo {
+                    dst: default_dst(family),
+                    gateway: Some(gateway),
+                    prefsrc: None,
+                    oif: i + 1,
+                    metric: 0,
+                });
             }
-            _ => {}
-        }
-        let mut msg = Vec::new();
-        let new_header = NetlinkMessageHeader {
-            nlmsg_len: 0, // to be determined later
-            nlmsg_type: NetlinkMessageType::Done.into(),
-            nlmsg_flags: NetlinkMessageFlags::MULTI,
-            nlmsg_seq: header.nlmsg_seq,
-            nlmsg_pid: header.nlmsg_pid,
+        }
+    }
+    for route in route::routes() {
+        if let Ok((oif, _)) = iface::iface_by_name(&route.ifname) {
+            routes.push(RouteInfo {
+                dst: route.dst,
+                gateway: route.gateway,
+                prefsrc: None,
+                oif,
+                metric: route.metric,
+            });
+        }
+    }
+    routes
+}
+
+fn dump_links(dest: Destination, replies: &mut Vec<Vec<u8>>) {
+    for (i, iface) in get_net_device().iter().enumerate() {
+        replies.push(link_message(
+            NetlinkMessageType::NewLink,
+            NetlinkMessageFlags::MULTI,
+            dest,
+            i + 1,
+            iface,
+        ));
+    }
+}
+
+fn dump_addrs(dest: Destination, family: AddressFamily, replies: &mut Vec<Vec<u8>>) {
+    for (i, iface) in get_net_device().iter().enumerate() {
+        let addrs = ip_addrs(iface.as_ref());
+        for cidr in addrs
+            .into_iter()
+            .filter(|cidr| family_matches(family, cidr.address()))
+        {
+            replies.push(addr_message(
+                NetlinkMessageType::NewAddr,
+                NetlinkMessageFlags::MULTI,
+                dest,
+                i + 1,
+                iface,
+                cidr,
+            ));
+        }
+    }
+}
+
+fn dump_routes(dest: Destination, family: AddressFamily, replies: &mut Vec<Vec<u8>>) {
+    let routes = routes()
+        .into_iter()
+        .filter(|route| family_matches(family, route.dst.address()));
+    for route in routes {
+        replies.push(route_message(
+            NetlinkMessageType::NewRoute,
+            NetlinkMessageFlags::MULTI,
+            dest,
+            &route,
+        ));
+    }
+}
+
+/// Find the interface by `ifi_index`, or `IFLA_IFNAME` if the index is 0.
+fn find_link(msg: &IfaceInfoMsg, attrs: &[(u16, &[u8])]) -> LxResult<(usize, Arc<dyn NetScheme>)> {
+    if msg.ifi_index != 0 {
+        let index = msg.ifi_index as usize;
+        return Ok((index, iface::iface_by_index(index)?));
+    }
+    match string_attr(attrs, RouteAttrTypes::Ifname.into())? {
+        Some(name) => iface::iface_by_name(name),
+        None => Err(LxError::EINVAL),
+    }
+}
+
+/// Handle `RTM_GETLINK` of a single interface.
+fn get_link(payload: &[u8]) -> LxResult<(usize, Arc<dyn NetScheme>)> {
+    let (msg, attrs) = parse_message::<IfaceInfoMsg>(payload)?;
+    find_link(&msg, &attrs)
+}
+
+/// Handle `RTM_NEWLINK` and `RTM_SETLINK`.
+///
+/// Links can't be created, and only changes keeping them as they are succeed.
+fn change_link(
+    message_type: NetlinkMessageType,
+    flags: NetlinkMessageFlags,
+    payload: &[u8],
+) -> LxResult {
+    let (msg, attrs) = parse_message::<IfaceInfoMsg>(payload)?;
+    let (_, iface) = match find_link(&msg, &attrs) {
+        Ok(link) => link,
+        Err(LxError::ENODEV)
+            if message_type == NetlinkMessageType::NewLink
+                && flags.contains(NetlinkMessageFlags::CREATE) =>
+        {
+            warn!("netlink: creating links is not supported");
+            return Err(LxError::EOPNOTSUPP);
+        }
+        Err(err) => return Err(err),
+    };
+    if message_type == NetlinkMessageType::NewLink && flags.contains(NetlinkMessageFlags::EXCL) {
+        return Err(LxError::EEXIST);
+    }
+    if let Some(name) = string_attr(&attrs, RouteAttrTypes::Ifname.into())? {
+        if name != iface.get_ifname() {
+            warn!("netlink: renaming links is not supported");
+            return Err(LxError::EOPNOTSUPP);
+        }
+    }
+    if let Some(mtu) = u32_attr(&attrs, RouteAttrTypes::MTU.into())? {
+        iface::set_mtu(iface.as_ref(), mtu)?;
+    }
+    if let Some((_, address)) = find_attr(&attrs, RouteAttrTypes::Address.into()) {
+        if address != iface.get_mac().as_bytes() {
+            warn!("netlink: changing hardware addresses is not supported");
+            return Err(LxError::EOPNOTSUPP);
+        }
+    }
+    if msg.ifi_change != 0 || msg.ifi_flags != 0 {
+        let current = iface::link_flags(iface.as_ref());
+        let new_flags = match msg.ifi_change {
+            0 => msg.ifi_flags,
+            change => (msg.ifi_flags & change) | (current & !change),
         };
-        msg.push_ext(new_header);
-        msg.align4();
-        msg.set_ext(0, msg.len() as u32);
-        buffer.push(msg);
-        Ok(data.len())
+        iface::set_link_flags(&iface, new_flags)?;
     }
+    Ok(())
+}
 
-    /// connect
-    async fn connect(&self, _endpoint: Endpoint) -> SysResult {
-        unimplemented!()
+/// Handle `RTM_NEWADDR` and `RTM_DELADDR`.
+///
+/// Interfaces have a single IPv4 address, so a new one replaces the old one,
+/// and any number of IPv6 addresses.
+fn change_addr(message_type: NetlinkMessageType, payload: &[u8]) -> LxResult {
+    let (msg, attrs) = parse_message::<IfaceAddrMsg>(payload)?;
+    let family = AddressFamily::from(msg.ifa_family as u16);
+    let max_prefix_len = match family {
+        AddressFamily::Internet => 32,
+        AddressFamily::Internet6 => 128,
+        _ => return Err(LxError::EAFNOSUPPORT),
+    };
+    if msg.ifa_prefixlen > max_prefix_len {
+        return Err(LxError::EINVAL);
+    }
+    let iface = iface::iface_by_index(msg.ifa_index as usize)?;
+    let addr = match ip_attr(&attrs, IFA_LOCAL, family)? {
+        Some(addr) => Some(addr),
+        None => ip_attr(&attrs, IFA_ADDRESS, family)?,
+    };
+    let addr = addr.ok_or(LxError::EINVAL)?;
+    let name = iface.get_ifname();
+    match (message_type, IpCidr::new(addr, msg.ifa_prefixlen)) {
+        (NetlinkMessageType::NewAddr, IpCidr::Ipv4(cidr)) => {
+            info!("netlink: set address of {} to {}", name, cidr);
+            iface::set_ipv4_addr(&iface, Some(cidr))
+        }
+        (NetlinkMessageType::NewAddr, IpCidr::Ipv6(cidr)) => {
+            info!("netlink: add address {} to {}", cidr, name);
+            iface::add_ipv6_addr(&iface, cidr)
+        }
+        (_, IpCidr::Ipv6(cidr)) => {
+            info!("netlink: delete address {} of {}", cidr, name);
+            iface::del_ipv6_addr(&iface, cidr.address())
+        }
+        _ => match iface::ipv4_addr(iface.as_ref()) {
+            Some(old) if IpAddress::Ipv4(old.address()) == addr => {
+                info!("netlink: delete address {} of {}", old, name);
+                iface::set_ipv4_addr(&iface, None)
+            }
+            _ => Err(LxError::EADDRNOTAVAIL),
+        },
     }
+}
+
+/// Handle `RTM_GETROUTE` of a single destination, by looking up the route.
+fn get_route(payload: &[u8]) -> LxResult<RouteInfo> {
+    let (msg, attrs) = parse_message::<RouteMsg>(payload)?;
+    let family = AddressFamily::from(msg.rtm_family as u16);
+    let dst = match ip_attr(&attrs, RTA_DST, family)? {
+        Some(dst) => dst,
+        None => default_dst(family).address(),
+    };
+    let route = route::lookup(dst).ok_or(LxError::ENETUNREACH)?;
+    let (oif, _) = iface::iface_by_name(&route.ifname)?;
+    let prefsrc = match dst {
+        IpAddress::Ipv4(dst) => iface::ipv4_source_addr(dst).map(IpAddress::Ipv4),
+        IpAddress::Ipv6(dst) => iface::ipv6_source_addr(dst).map(IpAddress::Ipv6),
+        _ => None,
+    };
+    Ok(RouteInfo {
+        dst: IpCidr::new(dst, dst.as_bytes().len() as u8 * 8),
+        gateway: route.gateway,
+        prefsrc,
+        oif,
+        metric: route.metric,
+    })
+}
 
-    fn bind(&self, _endpoint: Endpoint) -> SysResult {
-        warn!("bind netlink socket");
-        // if let Endpoint::Netlink(mut net_link) = endpoint {
-        //     if net_link.port_id == 0 {
-        //         net_link.port_id = get_ephemeral_port();
-        //     }
-        //     self.local_endpoint = Some(ip);
-        //     self.is_listening = false;
-        //     Ok(0)
-        // } else {
-        //     Err(LxError::EINVAL)
-        // }
-        Ok(0)
+/// Handle `RTM_NEWROUTE` and `RTM_DELROUTE`. Default routes are the gateways
+/// of interfaces, others are static routes.
+fn change_route(message_type: NetlinkMessageType, payload: &[u8]) -> LxResult {
+    let (msg, attrs) = parse_message::<RouteMsg>(payload)?;
+    let family = AddressFamily::from(msg.rtm_family as u16);
+    let gateway = ip_attr(&attrs, RTA_GATEWAY, family)?;
+    let oif = match u32_attr(&attrs, RTA_OIF)? {
+        Some(index) => Some(iface::iface_by_index(index as usize)?),
+        None => None,
+    };
+    if msg.rtm_dst_len != 0 {
+        let dst = ip_attr(&attrs, RTA_DST, family)?.ok_or(LxError::EINVAL)?;
+        if msg.rtm_dst_len as usize > dst.as_bytes().len() * 8 {
+            return Err(LxError::EINVAL);
+        }
+        let dst = IpCidr::new(dst, msg.rtm_dst_len);
+        if message_type != NetlinkMessageType::NewRoute {
+            return iface::del_route(dst, oif.as_ref());
+        }
+        let iface = match (oif, gateway) {
+            (Some(iface), _) => Some(iface),
+            (None, Some(IpAddress::Ipv4(gateway))) => iface::gateway_iface(gateway),
+            (None, Some(IpAddress::Ipv6(gateway))) => iface::ipv6_gateway_iface(gateway),
+            _ => None,
+        };
+        let iface = iface.ok_or(LxError::ENETUNREACH)?;
+        let metric = u32_attr(&attrs, RTA_PRIORITY)?.unwrap_or(0);
+        return iface::add_route(&iface, dst, gateway, metric);
+    }
+    if message_type == NetlinkMessageType::NewRoute {
+        let gateway = gateway.ok_or_else(|| {
+            warn!("netlink: only routes via a gateway are supported");
+            LxError::EOPNOTSUPP
+        })?;
+        let iface = match (oif, gateway) {
+            (Some(iface), _) => Some(iface),
+            (None, IpAddress::Ipv4(gateway)) => iface::gateway_iface(gateway),
+            (None, IpAddress::Ipv6(gateway)) => iface::ipv6_gateway_iface(gateway),
+            _ => None,
+        };
+        let iface = iface.ok_or(LxError::ENETUNREACH)?;
+        info!(
+            "netlink: add default route via {} on {}",
+            gateway,
+            iface.get_ifname()
+        );
+        set_default_gateway(&iface, family, Some(gateway))
+    } else {
+        let iface = get_net_device()
+            .into_iter()
+            .filter(|iface| oif.as_ref().map_or(true, |oif| Arc::ptr_eq(oif, iface)))
+            .find(|iface| match default_gateway(iface.as_ref(), family) {
+                Some(gw) => gateway.map_or(true, |addr| addr == gw),
+                None => false,
+            })
+            .ok_or(LxError::ESRCH)?;
+        info!("netlink: delete default route on {}", iface.get_ifname());
+        set_default_gateway(&iface, family, None)
     }
+}
 
-    fn listen(&self) -> SysResult {
-        unimplemented!()
+/// Notify the sockets in `group` of the message built by `build`.
+fn notify(group: u32, build: impl FnOnce() -> Vec<u8>) {
+    let sockets: Vec<_> = SOCKETS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|socket| socket.inner.lock().groups & group != 0)
+        .collect();
+    if sockets.is_empty() {
+        return;
+    }
+    let msg = build();
+    for socket in sockets {
+        socket.push_notification(msg.clone());
     }
+}
 
-    fn shutdown(&self) -> SysResult {
-        unimplemented!()
+/// Notify that the state of `iface` is changed.
+pub fn notify_link(iface: &Arc<dyn NetScheme>) {
+    notify(RTMGRP_LINK, || {
+        link_message(
+            NetlinkMessageType::NewLink,
+            NetlinkMessageFlags::empty(),
+            NOTIFICATION,
+            iface::iface_index(iface),
+            iface,
+        )
+    });
+}
+
+/// Notify that the address `cidr` of `iface` is added or deleted.
+pub fn notify_addr(iface: &Arc<dyn NetScheme>, cidr: IpCidr, added: bool) {
+    let group = match cidr {
+        IpCidr::Ipv6(_) => RTMGRP_IPV6_IFADDR,
+        _ => RTMGRP_IPV4_IFADDR,
+    };
+    notify(group, || {
+        let message_type = if added {
+            NetlinkMessageType::NewAddr
+        } else {
+            NetlinkMessageType::DelAddr
+        };
+        let index = iface::iface_index(iface);
+        let flags = NetlinkMessageFlags::empty();
+        addr_message(message_type, flags, NOTIFICATION, index, iface, cidr)
+    });
+}
+
+/// Notify that the route to `dst` via `gateway` on `iface` is added or
+/// deleted, `dst` is `None` for the default route of the family of `gateway`.
+pub fn notify_route(
+    iface: &Arc<dyn NetScheme>,
+    dst: Option<IpCidr>,
+    gateway: Option<IpAddress>,
+    added: bool,
+) {
+    let dst = match (dst, gateway) {
+        (Some(dst), _) => dst,
+        (None, Some(gateway)) => default_dst(family_of(gateway)),
+        (None, None) => return,
+    };
+    let family = family_of(dst.address());
+    let group = match family {
+        AddressFamily::Internet6 => RTMGRP_IPV6_ROUTE,
+        _ => RTMGRP_IPV4_ROUTE,
+    };
+    notify(group, || {
+        let message_type = if added {
+            NetlinkMessageType::NewRoute
+        } else {
+            NetlinkMessageType::DelRoute
+        };
+        let route = RouteInfo {
+            dst,
+            gateway,
+            prefsrc: None,
+            oif: iface::iface_index(iface),
+            metric: 0,
+        };
+        let flags = NetlinkMessageFlags::empty();
+        route_message(message_type, flags, NOTIFICATION, &route)
+    });
+}
+
+/// Read a fixed-size message of type `T` at the beginning of `payload`, and
+/// the route attributes following it.
+fn parse_message<T: Copy>(payload: &[u8]) -> LxResult<(T, Vec<(u16, &[u8])>)> {
+    if payload.len() < size_of::<T>() {
+        return Err(LxError::EINVAL);
+    }
+    #[allow(unsafe_code)]
+    let message = unsafe { (payload.as_ptr() as *const T).read_unaligned() };
+    let mut attrs = Vec::new();
+    let mut offset = (size_of::<T>() + 3) & !3;
+    while offset + size_of::<RouteAttr>() <= payload.len() {
+        #[allow(unsafe_code)]
+        let attr = unsafe { (payload[offset..].as_ptr() as *const RouteAttr).read_unaligned() };
+        let len = attr.rta_len as usize;
+        if len < size_of::<RouteAttr>() || offset + len > payload.len() {
+            return Err(LxError::EINVAL);
+        }
+        attrs.push((
+            attr.rta_type,
+            &payload[offset + size_of::<RouteAttr>()..offset + len],
+        ));
+        offset += (len + 3) & !3;
     }
+    Ok((message, attrs))
+}
 
-    async fn accept(&self) -> LxResult<(Arc<dyn FileLike>, Endpoint)> {
-        unimplemented!()
+fn find_attr<'a>(attrs: &[(u16, &'a [u8])], ty: u16) -> Option<(u16, &'a [u8])> {
+    attrs.iter().find(|(t, _)| *t == ty).copied()
+}
+
+/// Read an IPv4 address from the attribute `ty`.
+fn ipv4_attr(attrs: &[(u16, &[u8])], ty: u16) -> LxResult<Option<Ipv4Address>> {
+    match find_attr(attrs, ty) {
+        Some((_, value)) if value.len() == 4 => Ok(Some(Ipv4Address::from_bytes(value))),
+        Some(_) => Err(LxError::EINVAL),
+        None => Ok(None),
     }
+}
 
-    fn endpoint(&self) -> Option<Endpoint> {
-        Some(Endpoint::Netlink(NetlinkEndpoint::new(0, 0)))
+/// Read an IPv6 address from the attribute `ty`.
+fn ipv6_attr(attrs: &[(u16, &[u8])], ty: u16) -> LxResult<Option<Ipv6Address>> {
+    match find_attr(attrs, ty) {
+        Some((_, value)) if value.len() == 16 => Ok(Some(Ipv6Address::from_bytes(value))),
+        Some(_) => Err(LxError::EINVAL),
+        None => Ok(None),
     }
+}
 
-    fn remote_endpoint(&self) -> Option<Endpoint> {
-        unimplemented!()
+/// Read an address of `family` from the attribute `ty`.
+fn ip_attr(attrs: &[(u16, &[u8])], ty: u16, family: AddressFamily) -> LxResult<Option<IpAddress>> {
+    match family {
+        AddressFamily::Internet => Ok(ipv4_attr(attrs, ty)?.map(IpAddress::Ipv4)),
+        AddressFamily::Internet6 => Ok(ipv6_attr(attrs, ty)?.map(IpAddress::Ipv6)),
+        _ => Err(LxError::EAFNOSUPPORT),
     }
+}
 
-    fn setsockopt(&self, _level: usize, _opt: usize, _data: &[u8]) -> SysResult {
-        Ok(0)
+/// Read a `u32` from the attribute `ty`.
+fn u32_attr(attrs: &[(u16, &[u8])], ty: u16) -> LxResult<Option<u32>> {
+    match find_attr(attrs, ty) {
+        Some((_, value)) if value.len() == 4 => Ok(Some(u32::from_ne_bytes([
+            value[0], value[1], value[2], value[3],
+        ]))),
+        Some(_) => Err(LxError::EINVAL),
+        None => Ok(None),
     }
+}
 
-    fn ioctl(&self, _request: usize, _arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
-        Ok(0)
+/// Read a string, which may be NUL terminated, from the attribute `ty`.
+fn string_attr<'a>(attrs: &[(u16, &'a [u8])], ty: u16) -> LxResult<Option<&'a str>> {
+    match find_attr(attrs, ty) {
+        Some((_, value)) => {
+            let len = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+            let s = core::str::from_utf8(&value[..len]).map_err(|_| LxError::EINVAL)?;
+            Ok(Some(s))
+        }
+        None => Ok(None),
     }
 }
 
@@ -275,6 +1178,49 @@ struct IfaceAddrMsg {
     ifa_index: u32,
 }
 
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct RouteMsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
+// attributes of address messages
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_LABEL: u16 = 3;
+const IFA_BROADCAST: u16 = 4;
+
+/// The address is not going to expire.
+const IFA_F_PERMANENT: u8 = 0x80;
+
+// attributes of route messages
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_PREFSRC: u16 = 7;
+const RTA_TABLE: u16 = 15;
+
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_KERNEL: u8 = 2;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_HOST: u8 = 254;
+const RTN_UNICAST: u8 = 1;
+
+// RFC 2863 operational states
+const IF_OPER_UNKNOWN: u8 = 0;
+const IF_OPER_UP: u8 = 6;
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 struct RouteAttr {
@@ -333,6 +1279,12 @@ enum_with_unknown! {
         DelAddr = 21,
         /// Get addr
         GetAddr = 22,
+        /// New route
+        NewRoute = 24,
+        /// Delete route
+        DelRoute = 25,
+        /// Get route
+        GetRoute = 26,
     }
 }
 
@@ -351,6 +1303,12 @@ enum_with_unknown! {
         MTU = 4,
         /// Link
         Link = 5,
+        /// Length of the transmit queue
+        TxQueueLen = 13,
+        /// Operational state
+        OperState = 16,
+        /// Link mode
+        LinkMode = 17,
     }
 }
 
@@ -358,6 +1316,7 @@ trait VecExt {
     fn align4(&mut self);
     fn push_ext<T: Sized>(&mut self, data: T);
     fn set_ext<T: Sized>(&mut self, offset: usize, data: T);
+    fn push_attr(&mut self, rta_type: u16, value: &[u8]);
 }
 
 impl VecExt for Vec<u8> {
@@ -386,6 +1345,16 @@ impl VecExt for Vec<u8> {
             unsafe { slice::from_raw_parts(&data as *const T as *const u8, size_of::<T>()) };
         self[offset..(bytes.len() + offset)].copy_from_slice(bytes);
     }
+
+    fn push_attr(&mut self, rta_type: u16, value: &[u8]) {
+        let attr = RouteAttr {
+            rta_len: (value.len() + size_of::<RouteAttr>()) as u16,
+            rta_type,
+        };
+        self.align4();
+        self.push_ext(attr);
+        self.extend_from_slice(value);
+    }
 }
 
 #[repr(C)]
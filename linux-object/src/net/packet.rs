@@ -0,0 +1,439 @@
+//! Packet sockets (`AF_PACKET`)
+//!
+//! Frames are captured from interfaces by
+//! [`Capture`](zcore_drivers::net::capture::Capture), besides going to smoltcp,
+//! and injected by [`NetScheme::send`](zcore_drivers::scheme::NetScheme::send).
+//! `SOCK_RAW` sockets read and write whole Ethernet frames, `SOCK_DGRAM` ones
+//! only the payload, with the header built from the destination address.
+//!
+//! The loopback interface can't be captured, and memory-mapped rings
+//! (`PACKET_RX_RING`) are not supported.
+
+use crate::error::{LxError, LxResult};
+use crate::fs::{FileLike, OpenFlags, PollStatus};
+use crate::net::bpf::BpfProgram;
+use crate::net::*;
+use alloc::collections::VecDeque;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use async_trait::async_trait;
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel_hal::net::get_net_device;
+use lock::Mutex;
+use smoltcp::wire::{EthernetAddress, EthernetFrame};
+use zcore_drivers::net::capture::FrameSink;
+use zircon_object::{impl_kobject, object::*};
+
+/// Every Ethernet protocol
+const ETH_P_ALL: u16 = 0x0003;
+/// Length of the Ethernet header
+const ETH_HLEN: usize = 14;
+/// Maximum length of an Ethernet frame without FCS
+const ETH_FRAME_LEN: usize = 1514;
+/// Hardware type of Ethernet interfaces
+const ARPHRD_ETHER: u16 = 1;
+
+// types of received packets
+const PACKET_HOST: u8 = 0;
+const PACKET_BROADCAST: u8 = 1;
+const PACKET_MULTICAST: u8 = 2;
+const PACKET_OTHERHOST: u8 = 3;
+const PACKET_OUTGOING: u8 = 4;
+
+// options of the `SOL_PACKET` level
+const PACKET_ADD_MEMBERSHIP: usize = 1;
+const PACKET_DROP_MEMBERSHIP: usize = 2;
+const PACKET_AUXDATA: usize = 8;
+
+/// `mr_type` of the promiscuous mode
+const PACKET_MR_PROMISC: u16 = 1;
+
+/// Maximum number of frames queued on a socket, more are dropped
+const PACKET_QUEUE_LEN: usize = 256;
+
+/// `struct packet_mreq`
+#[repr(C)]
+#[allow(dead_code)]
+struct PacketMreq {
+    mr_ifindex: i32,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8],
+}
+
+/// A captured frame.
+struct Frame {
+    ifindex: usize,
+    packet_type: u8,
+    src: EthernetAddress,
+    protocol: u16,
+    /// The frame of `SOCK_RAW` sockets or its payload, cut by the filter
+    data: Vec<u8>,
+}
+
+/// Receives frames for a packet socket.
+struct Receiver {
+    /// a `SOCK_RAW` socket
+    raw: bool,
+    /// the socket is sending, frames sent by itself are not received
+    sending: AtomicBool,
+    state: Mutex<ReceiverState>,
+}
+
+struct ReceiverState {
+    /// Ethernet protocol in host byte order, 0 to receive nothing
+    protocol: u16,
+    /// the bound interface, 0 for all
+    ifindex: usize,
+    filter: Option<BpfProgram>,
+    frames: VecDeque<Frame>,
+}
+
+impl Receiver {
+    fn receive(&self, ifindex: usize, mac: EthernetAddress, frame: &[u8], outgoing: bool) {
+        if self.sending.load(Ordering::Acquire) {
+            return;
+        }
+        let header = match EthernetFrame::new_checked(frame) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        let protocol = u16::from(header.ethertype());
+        let mut state = self.state.lock();
+        if state.ifindex != 0 && state.ifindex != ifindex {
+            return;
+        }
+        // outgoing frames are only received by `ETH_P_ALL` sockets as in Linux
+        if state.protocol != ETH_P_ALL && (outgoing || state.protocol != protocol) {
+            return;
+        }
+        let dst = header.dst_addr();
+        let packet_type = if outgoing {
+            PACKET_OUTGOING
+        } else if dst == mac {
+            PACKET_HOST
+        } else if dst.is_broadcast() {
+            PACKET_BROADCAST
+        } else if dst.is_multicast() {
+            PACKET_MULTICAST
+        } else {
+            PACKET_OTHERHOST
+        };
+        let data = if self.raw { frame } else { &frame[ETH_HLEN..] };
+        let len = match &state.filter {
+            Some(filter) => (filter.run(data) as usize).min(data.len()),
+            None => data.len(),
+        };
+        if len == 0 || state.frames.len() >= PACKET_QUEUE_LEN {
+            return;
+        }
+        state.frames.push_back(Frame {
+            ifindex,
+            packet_type,
+            src: header.src_addr(),
+            protocol,
+            data: data[..len].to_vec(),
+        });
+    }
+}
+
+/// Delivers frames of an interface to a [`Receiver`].
+struct Tap {
+    ifindex: usize,
+    mac: EthernetAddress,
+    receiver: Arc<Receiver>,
+}
+
+impl FrameSink for Tap {
+    fn deliver(&self, frame: &[u8], outgoing: bool) {
+        self.receiver
+            .receive(self.ifindex, self.mac, frame, outgoing);
+    }
+}
+
+/// A packet socket
+pub struct PacketSocketState {
+    /// Kernel object base
+    base: KObjectBase,
+    socket_type: SocketType,
+    receiver: Arc<Receiver>,
+    /// taps attached to interfaces, detached when dropped
+    _taps: Vec<Arc<dyn FrameSink>>,
+    /// flags on the socket
+    flags: Mutex<OpenFlags>,
+    /// indexes of interfaces in promiscuous mode by the socket
+    promiscuous: Mutex<Vec<usize>>,
+}
+
+impl PacketSocketState {
+    /// Create a `SOCK_RAW` or `SOCK_DGRAM` packet socket receiving `protocol`,
+    /// in network byte order as given to `socket()`.
+    pub fn new(socket_type: SocketType, protocol: u16) -> LxResult<Self> {
+        if socket_type != SocketType::SOCK_RAW && socket_type != SocketType::SOCK_DGRAM {
+            return Err(LxError::ESOCKTNOSUPPORT);
+        }
+        let receiver = Arc::new(Receiver {
+            raw: socket_type == SocketType::SOCK_RAW,
+            sending: AtomicBool::new(false),
+            state: Mutex::new(ReceiverState {
+                protocol: u16::from_be(protocol),
+                ifindex: 0,
+                filter: None,
+                frames: VecDeque::new(),
+            }),
+        });
+        let mut taps = Vec::new();
+        for (i, iface) in get_net_device().iter().enumerate() {
+            let tap: Arc<dyn FrameSink> = Arc::new(Tap {
+                ifindex: i + 1,
+                mac: iface.get_mac(),
+                receiver: receiver.clone(),
+            });
+            if iface.attach_capture(Arc::downgrade(&tap)).is_ok() {
+                taps.push(tap);
+            }
+        }
+        Ok(PacketSocketState {
+            base: KObjectBase::new(),
+            socket_type,
+            receiver,
+            _taps: taps,
+            flags: Mutex::new(OpenFlags::RDWR),
+            promiscuous: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Handle `PACKET_ADD_MEMBERSHIP` and `PACKET_DROP_MEMBERSHIP`.
+    fn set_membership(&self, add: bool, data: &[u8]) -> LxResult {
+        if data.len() < size_of::<PacketMreq>() {
+            return Err(LxError::EINVAL);
+        }
+        #[allow(unsafe_code)]
+        let mreq = unsafe { (data.as_ptr() as *const PacketMreq).read_unaligned() };
+        if mreq.mr_type != PACKET_MR_PROMISC {
+            // multicast frames are always captured
+            return Ok(());
+        }
+        let index = mreq.mr_ifindex as usize;
+        let mut promiscuous = self.promiscuous.lock();
+        if add {
+            iface::set_promiscuous(index, true)?;
+            promiscuous.push(index);
+        } else {
+            let pos = promiscuous
+                .iter()
+                .position(|&i| i == index)
+                .ok_or(LxError::EADDRNOTAVAIL)?;
+            iface::set_promiscuous(index, false)?;
+            promiscuous.remove(pos);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PacketSocketState {
+    fn drop(&mut self) {
+        for &index in self.promiscuous.lock().iter() {
+            iface::set_promiscuous(index, false).ok();
+        }
+    }
+}
+
+#[async_trait]
+impl Socket for PacketSocketState {
+    async fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        loop {
+            poll_ifaces();
+            let frame = self.receiver.state.lock().frames.pop_front();
+            if let Some(frame) = frame {
+                let len = frame.data.len().min(data.len());
+                data[..len].copy_from_slice(&frame.data[..len]);
+                let endpoint = LinkLevelEndpoint {
+                    interface_index: frame.ifindex,
+                    protocol: frame.protocol,
+                    hardware_type: ARPHRD_ETHER,
+                    packet_type: frame.packet_type,
+                    address: Some(frame.src),
+                };
+                return (Ok(len), Endpoint::LinkLevel(endpoint));
+            }
+            if self.flags.lock().contains(OpenFlags::NON_BLOCK) {
+                return (
+                    Err(LxError::EAGAIN),
+                    Endpoint::LinkLevel(LinkLevelEndpoint::default()),
+                );
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
+        let endpoint = match sendto_endpoint {
+            Some(Endpoint::LinkLevel(endpoint)) => Some(endpoint),
+            Some(_) => return Err(LxError::EINVAL),
+            None => None,
+        };
+        let (bound_index, protocol) = {
+            let state = self.receiver.state.lock();
+            (state.ifindex, state.protocol)
+        };
+        let index = match endpoint.as_ref().map_or(0, |e| e.interface_index) {
+            0 => bound_index,
+            index => index,
+        };
+        if index == 0 {
+            return Err(LxError::ENXIO);
+        }
+        let iface = iface::iface_by_index(index)?;
+        let frame = if self.receiver.raw {
+            if data.len() < ETH_HLEN {
+                return Err(LxError::EINVAL);
+            }
+            data.to_vec()
+        } else {
+            let endpoint = endpoint.ok_or(LxError::EINVAL)?;
+            let dst = endpoint.address.ok_or(LxError::EINVAL)?;
+            let protocol = if endpoint.protocol != 0 {
+                endpoint.protocol
+            } else {
+                protocol
+            };
+            let mut buffer = vec![0; ETH_HLEN + data.len()];
+            let mut frame = EthernetFrame::new_unchecked(&mut buffer);
+            frame.set_dst_addr(dst);
+            frame.set_src_addr(iface.get_mac());
+            frame.set_ethertype(protocol.into());
+            frame.payload_mut().copy_from_slice(data);
+            buffer
+        };
+        if frame.len() > ETH_FRAME_LEN {
+            return Err(LxError::EMSGSIZE);
+        }
+        self.receiver.sending.store(true, Ordering::Release);
+        let result = iface.send(&frame);
+        self.receiver.sending.store(false, Ordering::Release);
+        result.map_err(iface::device_error)?;
+        Ok(data.len())
+    }
+
+    async fn connect(&self, _endpoint: Endpoint) -> SysResult {
+        Err(LxError::EOPNOTSUPP)
+    }
+
+    fn poll(&self, _events: PollEvents) -> (bool, bool, bool) {
+        poll_ifaces();
+        let read = !self.receiver.state.lock().frames.is_empty();
+        (read, true, false)
+    }
+
+    fn bind(&self, endpoint: Endpoint) -> SysResult {
+        if let Endpoint::LinkLevel(endpoint) = endpoint {
+            if endpoint.interface_index != 0 {
+                iface::iface_by_index(endpoint.interface_index)?;
+            }
+            let mut state = self.receiver.state.lock();
+            state.ifindex = endpoint.interface_index;
+            if endpoint.protocol != 0 {
+                state.protocol = endpoint.protocol;
+            }
+            Ok(0)
+        } else {
+            Err(LxError::EINVAL)
+        }
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        let state = self.receiver.state.lock();
+        let address = match state.ifindex {
+            0 => None,
+            index => iface::iface_by_index(index)
+                .ok()
+                .map(|iface| iface.get_mac()),
+        };
+        Some(Endpoint::LinkLevel(LinkLevelEndpoint {
+            interface_index: state.ifindex,
+            protocol: state.protocol,
+            hardware_type: ARPHRD_ETHER,
+            packet_type: PACKET_HOST,
+            address,
+        }))
+    }
+
+    fn setsockopt(&self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        match Level::try_from(level) {
+            Ok(Level::SOL_SOCKET) => match SolOptname::try_from(opt) {
+                Ok(SolOptname::ATTACH_FILTER) => {
+                    let filter = BpfProgram::from_sockopt(data)?;
+                    self.receiver.state.lock().filter = Some(filter);
+                }
+                Ok(SolOptname::DETACH_FILTER) => {
+                    let mut state = self.receiver.state.lock();
+                    state.filter.take().ok_or(LxError::ENOENT)?;
+                }
+                _ => warn!("setsockopt: ignored level={}, opt={}", level, opt),
+            },
+            Ok(Level::SOL_PACKET) => match opt {
+                PACKET_ADD_MEMBERSHIP => self.set_membership(true, data)?,
+                PACKET_DROP_MEMBERSHIP => self.set_membership(false, data)?,
+                // there is never auxiliary data of VLAN tags
+                PACKET_AUXDATA => {}
+                _ => return Err(LxError::ENOPROTOOPT),
+            },
+            _ => return Err(LxError::ENOPROTOOPT),
+        }
+        Ok(0)
+    }
+
+    fn socket_type(&self) -> Option<SocketType> {
+        Some(self.socket_type)
+    }
+}
+
+impl_kobject!(PacketSocketState);
+
+#[async_trait]
+impl FileLike for PacketSocketState {
+    fn flags(&self) -> OpenFlags {
+        *self.flags.lock()
+    }
+
+    fn set_flags(&self, f: OpenFlags) -> LxResult {
+        let flags = &mut self.flags.lock();
+
+        // See fcntl, only O_APPEND, O_ASYNC, O_DIRECT, O_NOATIME, O_NONBLOCK
+        flags.set(OpenFlags::APPEND, f.contains(OpenFlags::APPEND));
+        flags.set(OpenFlags::NON_BLOCK, f.contains(OpenFlags::NON_BLOCK));
+        flags.set(OpenFlags::CLOEXEC, f.contains(OpenFlags::CLOEXEC));
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> LxResult<usize> {
+        Socket::read(self, buf).await.0
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
+        Err(LxError::ESPIPE)
+    }
+
+    fn write(&self, buf: &[u8]) -> LxResult<usize> {
+        Socket::write(self, buf, None)
+    }
+
+    fn poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    async fn async_poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, arg2: usize, arg3: usize) -> LxResult<usize> {
+        Socket::ioctl(self, request, arg1, arg2, arg3)
+    }
+
+    fn as_socket(&self) -> LxResult<&dyn Socket> {
+        Ok(self)
+    }
+}
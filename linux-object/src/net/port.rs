@@ -0,0 +1,186 @@
+//! Ephemeral ports and TCP initial sequence numbers
+//!
+//! - Ephemeral ports are taken from `ip_local_port_range`, which is set through
+//!   `/proc/sys/net/ipv4/ip_local_port_range`, starting at a random offset and
+//!   skipping ports used by sockets, as in RFC 6056.
+//! - TCP initial sequence numbers are generated as in RFC 6528, a 4 µs clock
+//!   plus a keyed hash of the connection 4-tuple. The pinned smoltcp can't set
+//!   them, so they're applied by the sequence number modulation of
+//!   [`zcore_drivers::net::isn`], installed by the first TCP socket.
+//!
+//! Ports taken by `bind()` are recorded by [`BoundPort`], as TCP sockets only
+//! enter the smoltcp socket set on `listen()` or `connect()`. A port is shared
+//! by sockets which all set `SO_REUSEADDR` or all set `SO_REUSEPORT`, except
+//! that `SO_REUSEADDR` doesn't share the port of a listening TCP socket. Either
+//! option lets a socket take the port of TCP connections not bound by it, such
+//! as the ones accepted by a closed listening socket.
+
+use super::sockopt::SocketOptions;
+use crate::error::{LxError, LxResult};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::hash::Hasher;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use lock::Mutex;
+use smoltcp::socket::{Socket, SocketSet, TcpState};
+use smoltcp::wire::{IpAddress, IpEndpoint, IpProtocol};
+use zcore_drivers::net::isn::set_isn_generator;
+
+/// The default `ip_local_port_range` of Linux.
+const DEFAULT_LOCAL_PORT_RANGE: (u16, u16) = (32768, 60999);
+
+lazy_static! {
+    static ref LOCAL_PORT_RANGE: Mutex<(u16, u16)> = Mutex::new(DEFAULT_LOCAL_PORT_RANGE);
+    /// (protocol, port) -> the sockets which bound it
+    static ref BOUND_PORTS: Mutex<BTreeMap<(u8, u16), PortUsers>> = Mutex::new(BTreeMap::new());
+    /// The secret key of the ISN hash, generated on first use.
+    static ref ISN_SECRET: (u64, u64) = (super::rand(), super::rand());
+}
+
+static ISN_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The sockets which bound a port.
+#[derive(Default)]
+struct PortUsers {
+    count: usize,
+    /// number of the sockets with `SO_REUSEADDR`
+    reuse_addr: usize,
+    /// number of the sockets with `SO_REUSEPORT`
+    reuse_port: usize,
+}
+
+/// The range of ephemeral ports, inclusive.
+pub fn ip_local_port_range() -> (u16, u16) {
+    *LOCAL_PORT_RANGE.lock()
+}
+
+/// Set the range of ephemeral ports, inclusive.
+pub fn set_ip_local_port_range(low: u16, high: u16) -> LxResult {
+    if low == 0 || low > high {
+        return Err(LxError::EINVAL);
+    }
+    *LOCAL_PORT_RANGE.lock() = (low, high);
+    Ok(())
+}
+
+/// A port bound by a socket, released on drop.
+#[derive(Debug)]
+pub struct BoundPort {
+    protocol: u8,
+    port: u16,
+    reuse_addr: bool,
+    reuse_port: bool,
+}
+
+impl BoundPort {
+    /// Bind `port` of `protocol` for a socket with `options`, fails with
+    /// `EADDRINUSE` if it's used by a socket it can't be shared with.
+    ///
+    /// `sockets` is the locked global socket set.
+    pub fn bind(
+        sockets: &SocketSet,
+        protocol: IpProtocol,
+        port: u16,
+        options: &SocketOptions,
+    ) -> LxResult<Self> {
+        let (reuse_addr, reuse_port) = (options.reuse_addr, options.reuse_port);
+        let mut ports = BOUND_PORTS.lock();
+        if let Some(users) = ports.get(&(protocol.into(), port)) {
+            let shared = (reuse_addr && users.reuse_addr == users.count)
+                || (reuse_port && users.reuse_port == users.count);
+            if !shared {
+                return Err(LxError::EADDRINUSE);
+            }
+        }
+        if protocol == IpProtocol::Tcp {
+            let conflict = sockets.iter().any(|socket| match socket {
+                Socket::Tcp(socket) if socket.local_endpoint().port == port => {
+                    if socket.state() == TcpState::Listen {
+                        !reuse_port
+                    } else {
+                        !reuse_addr && !reuse_port
+                    }
+                }
+                _ => false,
+            });
+            if conflict {
+                return Err(LxError::EADDRINUSE);
+            }
+        }
+        let users = ports.entry((protocol.into(), port)).or_default();
+        users.count += 1;
+        users.reuse_addr += reuse_addr as usize;
+        users.reuse_port += reuse_port as usize;
+        Ok(BoundPort {
+            protocol: protocol.into(),
+            port,
+            reuse_addr,
+            reuse_port,
+        })
+    }
+}
+
+impl Drop for BoundPort {
+    fn drop(&mut self) {
+        let mut ports = BOUND_PORTS.lock();
+        let key = (self.protocol, self.port);
+        if let Some(users) = ports.get_mut(&key) {
+            users.count -= 1;
+            users.reuse_addr -= self.reuse_addr as usize;
+            users.reuse_port -= self.reuse_port as usize;
+            if users.count == 0 {
+                ports.remove(&key);
+            }
+        }
+    }
+}
+
+/// Whether `port` is used by a `protocol` socket.
+fn port_in_use(sockets: &SocketSet, protocol: IpProtocol, port: u16) -> bool {
+    if BOUND_PORTS.lock().contains_key(&(protocol.into(), port)) {
+        return true;
+    }
+    sockets.iter().any(|socket| match socket {
+        Socket::Tcp(socket) if protocol == IpProtocol::Tcp => socket.local_endpoint().port == port,
+        Socket::Udp(socket) if protocol == IpProtocol::Udp => socket.endpoint().port == port,
+        _ => false,
+    })
+}
+
+/// Select an unused ephemeral port for a `protocol` socket.
+///
+/// `sockets` is the locked global socket set.
+pub fn ephemeral_port(sockets: &SocketSet, protocol: IpProtocol) -> LxResult<u16> {
+    let (low, high) = ip_local_port_range();
+    let count = (high - low) as u64 + 1;
+    let offset = super::rand() % count;
+    (0..count)
+        .map(|i| low + ((offset + i) % count) as u16)
+        .find(|&port| !port_in_use(sockets, protocol, port))
+        .ok_or(LxError::EADDRNOTAVAIL)
+}
+
+/// Generate the initial sequence number of a TCP connection, as in RFC 6528.
+#[allow(deprecated)]
+pub fn tcp_isn(local: IpEndpoint, remote: IpEndpoint) -> u32 {
+    let mut hasher = core::hash::SipHasher::new_with_keys(ISN_SECRET.0, ISN_SECRET.1);
+    for endpoint in [local, remote] {
+        match endpoint.addr {
+            IpAddress::Ipv4(addr) => hasher.write(addr.as_bytes()),
+            IpAddress::Ipv6(addr) => hasher.write(addr.as_bytes()),
+            _ => {}
+        }
+        hasher.write_u16(endpoint.port);
+    }
+    // the clock ticks every 4 µs
+    let clock = (kernel_hal::timer::timer_now().as_micros() / 4) as u32;
+    clock.wrapping_add(hasher.finish() as u32)
+}
+
+/// Install [`tcp_isn`] as the ISN generator of the network stack.
+pub fn install_tcp_isn() {
+    if !ISN_INSTALLED.swap(true, Ordering::SeqCst) {
+        set_isn_generator(Some(Arc::new(tcp_isn)));
+    }
+}
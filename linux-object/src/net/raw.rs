@@ -1,21 +1,40 @@
-use crate::{error::LxError, net::*};
-use alloc::boxed::Box;
+use crate::error::{LxError, LxResult};
+use crate::fs::{FileLike, OpenFlags, PollStatus};
+use crate::net::*;
+use alloc::{boxed::Box, sync::Arc};
 use async_trait::async_trait;
+use lock::Mutex;
 use smoltcp::{
     socket::{RawPacketMetadata, RawSocket, RawSocketBuffer},
-    wire::{IpProtocol, IpVersion, Ipv4Address, Ipv4Packet},
+    wire::{Icmpv6Packet, IpProtocol, IpVersion, Ipv4Packet},
+    wire::{Ipv6Packet, Ipv6Repr},
 };
+use zircon_object::{impl_kobject, object::*};
 
 /// missing documentation
-#[derive(Debug, Clone)]
 pub struct RawSocketState {
+    /// Kernel object base
+    base: KObjectBase,
     handle: GlobalSocketHandle,
     header_included: bool,
+    ip_version: IpVersion,
+    /// flags on the socket
+    flags: Mutex<OpenFlags>,
 }
 
 impl RawSocketState {
     /// missing documentation
     pub fn new(protocol: u8) -> Self {
+        Self::with_version(IpVersion::Ipv4, protocol)
+    }
+
+    /// Create an `AF_INET6` raw socket. Unlike IPv4 ones, packets are read and
+    /// written without the IP header.
+    pub fn new_ipv6(protocol: u8) -> Self {
+        Self::with_version(IpVersion::Ipv6, protocol)
+    }
+
+    fn with_version(ip_version: IpVersion, protocol: u8) -> Self {
         let rx_buffer = RawSocketBuffer::new(
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_RECVBUF],
@@ -24,19 +43,51 @@ impl RawSocketState {
             vec![RawPacketMetadata::EMPTY; RAW_METADATA_BUF],
             vec![0; RAW_SENDBUF],
         );
-        let socket = RawSocket::new(
-            IpVersion::Ipv4,
-            IpProtocol::from(protocol),
-            rx_buffer,
-            tx_buffer,
-        );
+        let socket = RawSocket::new(ip_version, IpProtocol::from(protocol), rx_buffer, tx_buffer);
         let handle = GlobalSocketHandle(get_sockets().lock().add(socket));
 
         RawSocketState {
+            base: KObjectBase::new(),
             handle,
             header_included: false,
+            ip_version,
+            flags: Mutex::new(OpenFlags::RDWR),
         }
     }
+
+    /// Read an IPv6 packet into `data` without the header, returns the
+    /// length of the payload and the source address.
+    fn recv_ipv6(socket: &mut RawSocket, data: &mut [u8]) -> Option<(usize, IpAddress)> {
+        let packet = Ipv6Packet::new_checked(socket.recv().ok()?).ok()?;
+        let payload = packet.payload();
+        let len = payload.len().min(data.len());
+        data[..len].copy_from_slice(&payload[..len]);
+        Some((len, IpAddress::Ipv6(packet.src_addr())))
+    }
+
+    /// Send `data` to `dst` in an IPv6 packet, with the checksum of ICMPv6
+    /// filled like Linux.
+    fn send_ipv6(socket: &mut RawSocket, data: &[u8], dst: Ipv6Address) -> SysResult {
+        let src = iface::ipv6_source_addr(dst).ok_or(LxError::EADDRNOTAVAIL)?;
+        let ip_repr = Ipv6Repr {
+            src_addr: src,
+            dst_addr: dst,
+            next_header: socket.ip_protocol(),
+            payload_len: data.len(),
+            hop_limit: 64,
+        };
+        let mut buffer = vec![0u8; ip_repr.buffer_len() + data.len()];
+        let mut packet = Ipv6Packet::new_unchecked(&mut buffer);
+        ip_repr.emit(&mut packet);
+        packet.payload_mut().copy_from_slice(data);
+        if ip_repr.next_header == IpProtocol::Icmpv6 {
+            let mut icmp =
+                Icmpv6Packet::new_checked(packet.payload_mut()).map_err(|_| LxError::EINVAL)?;
+            icmp.fill_checksum(&src.into(), &dst.into());
+        }
+        socket.send_slice(&buffer).map_err(|_| LxError::ENOBUFS)?;
+        Ok(data.len())
+    }
 }
 
 /// missing in implementation
@@ -50,7 +101,14 @@ impl Socket for RawSocketState {
             let net_sockets = get_sockets();
             let mut sockets = net_sockets.lock();
             let mut socket = sockets.get::<RawSocket>(self.handle.0);
-            if socket.can_recv() {
+            if socket.can_recv() && self.ip_version == IpVersion::Ipv6 {
+                if let Some((size, addr)) = Self::recv_ipv6(&mut socket, data) {
+                    drop(socket);
+                    drop(sockets);
+                    poll_ifaces();
+                    return (Ok(size), Endpoint::Ip(IpEndpoint { addr, port: 0 }));
+                }
+            } else if socket.can_recv() {
                 if let Ok(size) = socket.recv_slice(data) {
                     let packet = Ipv4Packet::new_unchecked(data);
                     // avoid deadlock
@@ -65,11 +123,8 @@ impl Socket for RawSocketState {
                         }),
                     );
                 }
-            } else {
-                return (
-                    Err(LxError::ENOTCONN),
-                    Endpoint::Ip(IpEndpoint::UNSPECIFIED),
-                );
+            } else if self.flags.lock().contains(OpenFlags::NON_BLOCK) {
+                return (Err(LxError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED));
             }
             drop(socket);
             drop(sockets);
@@ -81,16 +136,23 @@ impl Socket for RawSocketState {
         let net_sockets = get_sockets();
         let mut sockets = net_sockets.lock();
         let mut socket = sockets.get::<RawSocket>(self.handle.0);
-        if self.header_included {
+        if self.ip_version == IpVersion::Ipv6 {
+            match sendto_endpoint {
+                Some(Endpoint::Ip(IpEndpoint {
+                    addr: IpAddress::Ipv6(dst),
+                    ..
+                })) => Self::send_ipv6(&mut socket, data, dst),
+                Some(_) => Err(LxError::EAFNOSUPPORT),
+                None => Err(LxError::ENOTCONN),
+            }
+        } else if self.header_included {
             match socket.send_slice(data) {
                 Ok(()) => Ok(data.len()),
                 Err(_) => Err(LxError::ENOBUFS),
             }
         } else if let Some(Endpoint::Ip(endpoint)) = sendto_endpoint {
-            // todo: this is a temporary solution
-            let v4_src = Ipv4Address::new(192, 168, 0, 123);
-
             if let IpAddress::Ipv4(v4_dst) = endpoint.addr {
+                let v4_src = iface::ipv4_source_addr(v4_dst).ok_or(LxError::EADDRNOTAVAIL)?;
                 let len = data.len();
                 // using 20-byte IPv4 header
                 let mut buffer = vec![0u8; len + 20];
@@ -112,7 +174,7 @@ impl Socket for RawSocketState {
                 drop(sockets);
                 Ok(len)
             } else {
-                unimplemented!("ip type")
+                Err(LxError::EAFNOSUPPORT)
             }
         } else {
             Err(LxError::ENOTCONN)
@@ -120,7 +182,15 @@ impl Socket for RawSocketState {
     }
 
     async fn connect(&self, _endpoint: Endpoint) -> SysResult {
-        unimplemented!()
+        Err(LxError::EOPNOTSUPP)
+    }
+
+    fn poll(&self, _events: PollEvents) -> (bool, bool, bool) {
+        poll_ifaces();
+        let sockets = get_sockets();
+        let mut sockets = sockets.lock();
+        let socket = sockets.get::<RawSocket>(self.handle.0);
+        (socket.can_recv(), socket.can_send(), false)
     }
 
     fn setsockopt(&self, _level: usize, _opt: usize, _data: &[u8]) -> SysResult {
@@ -149,3 +219,52 @@ impl Socket for RawSocketState {
         Some(SocketType::SOCK_RAW)
     }
 }
+
+impl_kobject!(RawSocketState);
+
+#[async_trait]
+impl FileLike for RawSocketState {
+    fn flags(&self) -> OpenFlags {
+        *self.flags.lock()
+    }
+
+    fn set_flags(&self, f: OpenFlags) -> LxResult {
+        let flags = &mut self.flags.lock();
+
+        // See fcntl, only O_APPEND, O_ASYNC, O_DIRECT, O_NOATIME, O_NONBLOCK
+        flags.set(OpenFlags::APPEND, f.contains(OpenFlags::APPEND));
+        flags.set(OpenFlags::NON_BLOCK, f.contains(OpenFlags::NON_BLOCK));
+        flags.set(OpenFlags::CLOEXEC, f.contains(OpenFlags::CLOEXEC));
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> LxResult<usize> {
+        Socket::read(self, buf).await.0
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
+        Err(LxError::ESPIPE)
+    }
+
+    fn write(&self, buf: &[u8]) -> LxResult<usize> {
+        Socket::write(self, buf, None)
+    }
+
+    fn poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    async fn async_poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, arg2: usize, arg3: usize) -> LxResult<usize> {
+        Socket::ioctl(self, request, arg1, arg2, arg3)
+    }
+
+    fn as_socket(&self) -> LxResult<&dyn Socket> {
+        Ok(self)
+    }
+}
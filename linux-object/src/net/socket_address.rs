@@ -7,7 +7,8 @@ use crate::error::LxError;
 // use crate::net::Endpoint;
 
 // smoltcp
-pub use smoltcp::wire::{IpAddress, Ipv4Address};
+use smoltcp::wire::EthernetAddress;
+pub use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
 
 use crate::net::*;
 use kernel_hal::user::{UserInOutPtr, UserOutPtr};
@@ -21,12 +22,16 @@ pub union SockAddr {
     pub family: u16,
     /// missing documentation
     pub addr_in: SockAddrIn,
+    /// `sockaddr_in6`
+    pub addr_in6: SockAddrIn6,
     /// missing documentation
     pub addr_un: SockAddrUn,
     /// missing documentation
     pub addr_ll: SockAddrLl,
     /// missing documentation
     pub addr_nl: SockAddrNl,
+    /// `sockaddr_vm`
+    pub addr_vm: SockAddrVm,
     /// missing documentation
     pub addr_ph: SockAddrPlaceholder,
 }
@@ -45,6 +50,22 @@ pub struct SockAddrIn {
     pub sin_zero: [u8; 8],
 }
 
+/// IPv6 socket address, `struct sockaddr_in6`
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SockAddrIn6 {
+    /// `AF_INET6`
+    pub sin6_family: u16,
+    /// port in network byte order
+    pub sin6_port: u16,
+    /// IPv6 flow information
+    pub sin6_flowinfo: u32,
+    /// IPv6 address
+    pub sin6_addr: [u8; 16],
+    /// interface index of link-local addresses
+    pub sin6_scope_id: u32,
+}
+
 /// missing documentation
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -85,6 +106,24 @@ pub struct SockAddrNl {
     nl_groups: u32,
 }
 
+/// Virtual socket address, `struct sockaddr_vm`
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SockAddrVm {
+    /// `AF_VSOCK`
+    pub svm_family: u16,
+    /// missing documentation
+    pub svm_reserved1: u16,
+    /// port in host byte order
+    pub svm_port: u32,
+    /// context ID
+    pub svm_cid: u32,
+    /// `VMADDR_FLAG_*`
+    pub svm_flags: u8,
+    /// missing documentation
+    pub svm_zero: [u8; 3],
+}
+
 /// missing documentation
 #[derive(Clone, Copy)]
 #[repr(C)]
@@ -108,13 +147,23 @@ pub enum Endpoint {
     LinkLevel(LinkLevelEndpoint),
     /// missing documentation
     Netlink(NetlinkEndpoint),
+    /// Virtual socket address
+    Vsock(VsockEndpoint),
 }
 
 /// missing documentation
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct LinkLevelEndpoint {
     /// missing documentation
     pub interface_index: usize,
+    /// Ethernet protocol in host byte order
+    pub protocol: u16,
+    /// `ARPHRD_*` type of the interface
+    pub hardware_type: u16,
+    /// `PACKET_*` type of a received packet
+    pub packet_type: u8,
+    /// The source address of a received packet, or the destination to send to
+    pub address: Option<EthernetAddress>,
 }
 
 impl LinkLevelEndpoint {
@@ -122,6 +171,7 @@ impl LinkLevelEndpoint {
     pub fn new(ifindex: usize) -> Self {
         LinkLevelEndpoint {
             interface_index: ifindex,
+            ..Default::default()
         }
     }
 }
@@ -145,6 +195,15 @@ impl NetlinkEndpoint {
     }
 }
 
+/// Address of a virtual socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VsockEndpoint {
+    /// context ID of the guest or the host
+    pub cid: u32,
+    /// missing documentation
+    pub port: u32,
+}
+
 // ============= Endpoint =============
 
 impl From<Endpoint> for SockAddr {
@@ -160,24 +219,38 @@ impl From<Endpoint> for SockAddr {
                         sin_zero: [0; 8],
                     },
                 },
-                IpAddress::Unspecified => SockAddr {
+                IpAddress::Ipv6(ipv6) => SockAddr {
+                    addr_in6: SockAddrIn6 {
+                        sin6_family: AddressFamily::Internet6.into(),
+                        sin6_port: u16::to_be(ip.port),
+                        sin6_flowinfo: 0,
+                        sin6_addr: ipv6.0,
+                        sin6_scope_id: iface::ipv6_scope_id(ipv6),
+                    },
+                },
+                _ => SockAddr {
                     addr_ph: SockAddrPlaceholder {
                         family: AddressFamily::Unspecified.into(),
                         data: [0; 14],
                     },
                 },
-                _ => unimplemented!("only ipv4"),
             }
         } else if let Endpoint::LinkLevel(link_level) = endpoint {
             SockAddr {
-                addr_ll: SockAddrLl {
-                    sll_family: AddressFamily::Packet.into(),
-                    sll_protocol: 0,
-                    sll_ifindex: link_level.interface_index as u32,
-                    sll_hatype: 0,
-                    sll_pkttype: 0,
-                    sll_halen: 0,
-                    sll_addr: [0; 8],
+                addr_ll: {
+                    let mut sll_addr = [0; 8];
+                    if let Some(address) = link_level.address {
+                        sll_addr[..6].copy_from_slice(address.as_bytes());
+                    }
+                    SockAddrLl {
+                        sll_family: AddressFamily::Packet.into(),
+                        sll_protocol: u16::to_be(link_level.protocol),
+                        sll_ifindex: link_level.interface_index as u32,
+                        sll_hatype: link_level.hardware_type,
+                        sll_pkttype: link_level.packet_type,
+                        sll_halen: if link_level.address.is_some() { 6 } else { 0 },
+                        sll_addr,
+                    }
                 },
             }
         } else if let Endpoint::Netlink(netlink) = endpoint {
@@ -189,12 +262,72 @@ impl From<Endpoint> for SockAddr {
                     nl_groups: netlink.multicast_groups_mask,
                 },
             }
+        } else if let Endpoint::Vsock(vsock) = endpoint {
+            SockAddr {
+                addr_vm: SockAddrVm {
+                    svm_family: AddressFamily::Vsock.into(),
+                    svm_reserved1: 0,
+                    svm_port: vsock.port,
+                    svm_cid: vsock.cid,
+                    svm_flags: 0,
+                    svm_zero: [0; 3],
+                },
+            }
         } else {
             unimplemented!("not match");
         }
     }
 }
 
+/// The IPv4-mapped IPv6 address of `addr`, `::ffff:a.b.c.d`.
+fn ipv4_mapped(addr: Ipv4Address) -> Ipv6Address {
+    let mut bytes = [0; 16];
+    bytes[10..12].copy_from_slice(&[0xff, 0xff]);
+    bytes[12..].copy_from_slice(addr.as_bytes());
+    Ipv6Address(bytes)
+}
+
+/// The IPv4 address of an IPv4-mapped IPv6 address.
+fn mapped_ipv4(addr: Ipv6Address) -> Option<Ipv4Address> {
+    let bytes = addr.as_bytes();
+    if bytes[..10].iter().all(|&b| b == 0) && bytes[10..12] == [0xff, 0xff] {
+        Some(Ipv4Address::from_bytes(&bytes[12..]))
+    } else {
+        None
+    }
+}
+
+/// Check the `endpoint` given to a socket of the family, and convert it to
+/// the one of smoltcp.
+///
+/// IPv6 sockets take IPv4-mapped addresses as IPv4 ones, unless `v6only`, and
+/// `::` as any address of both families.
+pub fn inet_endpoint(endpoint: IpEndpoint, ipv6: bool, v6only: bool) -> LxResult<IpEndpoint> {
+    let addr = match endpoint.addr {
+        IpAddress::Ipv4(_) if ipv6 => return Err(LxError::EAFNOSUPPORT),
+        IpAddress::Ipv6(_) if !ipv6 => return Err(LxError::EAFNOSUPPORT),
+        IpAddress::Ipv6(addr) => match mapped_ipv4(addr) {
+            Some(_) if v6only => return Err(LxError::EINVAL),
+            Some(addr) => IpAddress::Ipv4(addr),
+            None if addr.is_unspecified() => IpAddress::Unspecified,
+            None => IpAddress::Ipv6(addr),
+        },
+        addr => addr,
+    };
+    Ok(IpEndpoint::new(addr, endpoint.port))
+}
+
+/// Convert the `endpoint` of smoltcp to the one of a socket of the family,
+/// i.e. IPv4-mapped for IPv6 sockets.
+pub fn socket_endpoint(endpoint: IpEndpoint, ipv6: bool) -> Endpoint {
+    let addr = match endpoint.addr {
+        IpAddress::Ipv4(addr) if ipv6 => IpAddress::Ipv6(ipv4_mapped(addr)),
+        IpAddress::Unspecified if ipv6 => IpAddress::Ipv6(Ipv6Address::UNSPECIFIED),
+        addr => addr,
+    };
+    Endpoint::Ip(IpEndpoint::new(addr, endpoint.port))
+}
+
 /// missing documentation
 pub fn sockaddr_to_endpoint(addr: SockAddr, len: usize) -> Result<Endpoint, LxError> {
     if len < size_of::<u16>() {
@@ -214,14 +347,38 @@ pub fn sockaddr_to_endpoint(addr: SockAddr, len: usize) -> Result<Endpoint, LxEr
                 ));
                 Ok(Endpoint::Ip((addr, port).into()))
             }
+            AddressFamily::Internet6 => {
+                let addr_in6 = addr.addr_in6;
+                let scope_id = addr_in6.sin6_scope_id as usize;
+                if scope_id != 0 {
+                    iface::iface_by_index(scope_id)?;
+                }
+                let port = u16::from_be(addr_in6.sin6_port);
+                let addr = IpAddress::Ipv6(Ipv6Address(addr_in6.sin6_addr));
+                Ok(Endpoint::Ip((addr, port).into()))
+            }
             AddressFamily::Unix => Err(LxError::EINVAL),
-            // AddressFamily::Packet => Ok(Endpoint::LinkLevel(LinkLevelEndpoint::new(
-            //     addr.addr_ll.sll_ifindex as usize,
-            // ))),
+            AddressFamily::Packet => {
+                let addr_ll = addr.addr_ll;
+                Ok(Endpoint::LinkLevel(LinkLevelEndpoint {
+                    interface_index: addr_ll.sll_ifindex as usize,
+                    protocol: u16::from_be(addr_ll.sll_protocol),
+                    hardware_type: addr_ll.sll_hatype,
+                    packet_type: addr_ll.sll_pkttype,
+                    address: match addr_ll.sll_halen {
+                        6 => Some(EthernetAddress::from_bytes(&addr_ll.sll_addr[..6])),
+                        _ => None,
+                    },
+                }))
+            }
             AddressFamily::Netlink => Ok(Endpoint::Netlink(NetlinkEndpoint::new(
                 addr.addr_nl.nl_pid,
                 addr.addr_nl.nl_groups,
             ))),
+            AddressFamily::Vsock => Ok(Endpoint::Vsock(VsockEndpoint {
+                cid: addr.addr_vm.svm_cid,
+                port: addr.addr_vm.svm_port,
+            })),
             _ => Err(LxError::EINVAL),
         }
     }
@@ -232,8 +389,10 @@ impl SockAddr {
         #[allow(unsafe_code)]
         match AddressFamily::from(unsafe { self.family }) {
             AddressFamily::Internet => Ok(size_of::<SockAddrIn>()),
+            AddressFamily::Internet6 => Ok(size_of::<SockAddrIn6>()),
             AddressFamily::Packet => Ok(size_of::<SockAddrLl>()),
             AddressFamily::Netlink => Ok(size_of::<SockAddrNl>()),
+            AddressFamily::Vsock => Ok(size_of::<SockAddrVm>()),
             AddressFamily::Unix => Err(LxError::EINVAL),
             _ => Err(LxError::EINVAL),
         }
@@ -391,10 +550,14 @@ enum_with_unknown! {
         Unix = 1,
         /// Internet IP Protocol
         Internet = 2,
+        /// IP version 6
+        Internet6 = 10,
         /// Netlink
         Netlink = 16,
         /// Packet family
         Packet = 17,
+        /// Virtual sockets between the guest and the host
+        Vsock = 40,
     }
 }
 
@@ -412,3 +575,117 @@ pub struct ArpReq {
     /// missing documentation
     pub arp_dev: [u8; 16],
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddress {
+        IpAddress::v4(a, b, c, d)
+    }
+
+    fn mapped(a: u8, b: u8, c: u8, d: u8) -> IpAddress {
+        IpAddress::Ipv6(ipv4_mapped(Ipv4Address::new(a, b, c, d)))
+    }
+
+    fn ip_endpoint(endpoint: Endpoint) -> IpEndpoint {
+        match endpoint {
+            Endpoint::Ip(endpoint) => endpoint,
+            _ => panic!("not an IP endpoint"),
+        }
+    }
+
+    #[test]
+    fn mapped_addresses() {
+        let addr = Ipv4Address::new(192, 0, 2, 1);
+        let mapped = ipv4_mapped(addr);
+        assert_eq!(
+            mapped,
+            Ipv6Address::new(0, 0, 0, 0, 0, 0xffff, 0xc000, 0x0201)
+        );
+        assert_eq!(mapped_ipv4(mapped), Some(addr));
+        for addr in [
+            Ipv6Address::UNSPECIFIED,
+            Ipv6Address::LOOPBACK,
+            // IPv4-compatible, not mapped
+            Ipv6Address::new(0, 0, 0, 0, 0, 0, 0xc000, 0x0201),
+            Ipv6Address::new(0, 0, 0, 0, 1, 0xffff, 0xc000, 0x0201),
+            Ipv6Address::new(0x2001, 0xdb8, 0, 0, 0, 0xffff, 0xc000, 0x0201),
+        ] {
+            assert_eq!(mapped_ipv4(addr), None, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let v6 = IpAddress::v6(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        // (socket endpoint, smoltcp endpoint, IPv6 socket)
+        for (socket, smoltcp, ipv6) in [
+            (v4(192, 0, 2, 1), v4(192, 0, 2, 1), false),
+            (v4(0, 0, 0, 0), v4(0, 0, 0, 0), false),
+            (v6, v6, true),
+            (mapped(192, 0, 2, 1), v4(192, 0, 2, 1), true),
+            (mapped(0, 0, 0, 0), v4(0, 0, 0, 0), true),
+            (
+                Ipv6Address::UNSPECIFIED.into(),
+                IpAddress::Unspecified,
+                true,
+            ),
+        ] {
+            let endpoint = IpEndpoint::new(socket, 80);
+            for v6only in [false, true] {
+                // tested by `v6only`
+                if v6only && ipv6 && matches!(smoltcp, IpAddress::Ipv4(_)) {
+                    continue;
+                }
+                let converted = inet_endpoint(endpoint, ipv6, v6only).unwrap();
+                assert_eq!(converted, IpEndpoint::new(smoltcp, 80), "{}", endpoint);
+                let back = ip_endpoint(socket_endpoint(converted, ipv6));
+                assert_eq!(back, endpoint, "{}", endpoint);
+            }
+        }
+    }
+
+    #[test]
+    fn family_mismatch() {
+        let ipv4 = IpEndpoint::new(v4(192, 0, 2, 1), 80);
+        let ipv6 = IpEndpoint::new(IpAddress::v6(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 80);
+        assert_eq!(inet_endpoint(ipv4, true, false), Err(LxError::EAFNOSUPPORT));
+        assert_eq!(
+            inet_endpoint(ipv6, false, false),
+            Err(LxError::EAFNOSUPPORT)
+        );
+    }
+
+    #[test]
+    fn v6only() {
+        for addr in [mapped(192, 0, 2, 1), mapped(0, 0, 0, 0)] {
+            let endpoint = IpEndpoint::new(addr, 80);
+            assert_eq!(inet_endpoint(endpoint, true, true), Err(LxError::EINVAL));
+            assert!(inet_endpoint(endpoint, true, false).is_ok());
+        }
+        // `::` is still any address of both families
+        let any = IpEndpoint::new(Ipv6Address::UNSPECIFIED.into(), 80);
+        assert_eq!(
+            inet_endpoint(any, true, true),
+            Ok(IpEndpoint::new(IpAddress::Unspecified, 80))
+        );
+    }
+
+    #[test]
+    fn ipv4_sockaddr() {
+        let endpoint = IpEndpoint::new(v4(192, 0, 2, 1), 8080);
+        let addr = SockAddr::from(Endpoint::Ip(endpoint));
+        #[allow(unsafe_code)]
+        let sin = unsafe { addr.addr_in };
+        assert_eq!(sin.sin_port, 8080u16.to_be());
+        assert_eq!(sin.sin_addr.to_ne_bytes(), [192, 0, 2, 1]);
+        let back = sockaddr_to_endpoint(addr, size_of::<SockAddrIn>()).unwrap();
+        assert_eq!(ip_endpoint(back), endpoint);
+        let addr = SockAddr::from(Endpoint::Ip(endpoint));
+        assert_eq!(
+            sockaddr_to_endpoint(addr, size_of::<SockAddrIn>() - 1).err(),
+            Some(LxError::EINVAL)
+        );
+    }
+}
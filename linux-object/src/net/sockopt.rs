@@ -0,0 +1,465 @@
+//! Options of TCP and UDP sockets
+//!
+//! Options are applied to the smoltcp socket where smoltcp has a counterpart,
+//! the others take effect in the kernel:
+//!
+//! - `SO_REUSEADDR` and `SO_REUSEPORT` by the [`BoundPort`] taken on bind;
+//! - `SO_LINGER` when the TCP socket is closed, a linger timeout drains it in
+//!   the background instead of blocking `close()`;
+//! - `IP_ADD_MEMBERSHIP` by a [`Membership`] of the interface;
+//! - `SO_BINDTODEVICE` by a [`DeviceBinding`] of the local port, which sockets
+//!   take once the port is known.
+//!
+//! Other options, such as `IP_TOS` and `IP_MULTICAST_TTL`, are recorded and
+//! reported back but have no effect on the traffic.
+//!
+//! [`BoundPort`]: super::port::BoundPort
+
+use super::iface::{self, IFNAMSIZ};
+use super::{IpOptname, Ipv6Optname, Level, SocketType, SolOptname, TcpOptname};
+use crate::error::{LxError, LxResult};
+use crate::time::TimeVal;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::mem::size_of;
+use core::time::Duration;
+use kernel_hal::timer::{deadline_after, timer_now};
+use smoltcp::socket::{TcpSocket, UdpSocket};
+use smoltcp::wire::{IpProtocol, Ipv4Address};
+use zcore_drivers::net::route::DeviceBinding;
+
+/// Default of `IP_TTL`
+const DEFAULT_TTL: u8 = 64;
+/// Default of `TCP_KEEPIDLE` in seconds
+const DEFAULT_KEEPIDLE: u32 = 7200;
+/// Default of `TCP_KEEPINTVL` in seconds
+const DEFAULT_KEEPINTVL: u32 = 75;
+/// Default of `TCP_KEEPCNT`
+const DEFAULT_KEEPCNT: u32 = 9;
+/// Maximum of `TCP_KEEPIDLE` and `TCP_KEEPINTVL` in seconds
+const MAX_TCP_KEEPIDLE: u32 = 32767;
+/// Maximum of `TCP_KEEPCNT`
+const MAX_TCP_KEEPCNT: u32 = 127;
+/// Maximum number of multicast groups a socket can join
+const MAX_MEMBERSHIPS: usize = 20;
+
+/// Minimum size of socket buffers
+pub const MIN_SOCKBUF: usize = 2048;
+/// Maximum size of socket buffers
+pub const MAX_SOCKBUF: usize = 4 * 1024 * 1024;
+
+/// Options of a TCP or UDP socket
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    /// `SO_REUSEADDR`
+    pub reuse_addr: bool,
+    /// `SO_REUSEPORT`
+    pub reuse_port: bool,
+    /// `SO_BROADCAST`
+    pub broadcast: bool,
+    /// `SO_KEEPALIVE`
+    pub keepalive: bool,
+    /// `SO_LINGER`, the timeout in seconds if enabled
+    pub linger: Option<u32>,
+    /// `SO_RCVTIMEO`, `None` if receiving never times out
+    pub recv_timeout: Option<Duration>,
+    /// `SO_SNDTIMEO`, `None` if sending never times out
+    pub send_timeout: Option<Duration>,
+    /// `SO_RCVBUF`, the requested size of the receive buffer
+    pub recv_buf: Option<usize>,
+    /// `SO_SNDBUF`, the requested size of the send buffer
+    pub send_buf: Option<usize>,
+    /// `SO_BINDTODEVICE`, the name of the interface
+    pub bound_device: Option<String>,
+    /// `TCP_NODELAY`
+    pub nodelay: bool,
+    /// `TCP_KEEPIDLE` in seconds
+    pub keepidle: u32,
+    /// `TCP_KEEPINTVL` in seconds
+    pub keepintvl: u32,
+    /// `TCP_KEEPCNT`
+    pub keepcnt: u32,
+    /// `IP_TTL`
+    pub ttl: u8,
+    /// `IP_TOS`
+    pub tos: u8,
+    /// `IP_MULTICAST_TTL`
+    pub multicast_ttl: u8,
+    /// `IP_MULTICAST_LOOP`
+    pub multicast_loop: bool,
+    /// Multicast groups joined by `IP_ADD_MEMBERSHIP`
+    pub memberships: Memberships,
+    /// `IPV6_V6ONLY`
+    pub v6only: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            reuse_addr: false,
+            reuse_port: false,
+            broadcast: false,
+            keepalive: false,
+            linger: None,
+            recv_timeout: None,
+            send_timeout: None,
+            recv_buf: None,
+            send_buf: None,
+            bound_device: None,
+            nodelay: false,
+            keepidle: DEFAULT_KEEPIDLE,
+            keepintvl: DEFAULT_KEEPINTVL,
+            keepcnt: DEFAULT_KEEPCNT,
+            ttl: DEFAULT_TTL,
+            tos: 0,
+            multicast_ttl: 1,
+            multicast_loop: true,
+            memberships: Memberships::default(),
+            v6only: false,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set an option of a socket of `socket_type`.
+    ///
+    /// Unknown options are ignored, as many programs set options only as hints.
+    pub fn set(
+        &mut self,
+        socket_type: SocketType,
+        level: usize,
+        opt: usize,
+        data: &[u8],
+    ) -> LxResult {
+        let ignored = || warn!("setsockopt: ignored level={}, opt={}", level, opt);
+        match Level::try_from(level) {
+            Ok(Level::SOL_SOCKET) => match SolOptname::try_from(opt) {
+                Ok(SolOptname::REUSEADDR) => self.reuse_addr = read_int(data)? != 0,
+                Ok(SolOptname::REUSEPORT) => self.reuse_port = read_int(data)? != 0,
+                Ok(SolOptname::BROADCAST) => self.broadcast = read_int(data)? != 0,
+                Ok(SolOptname::KEEPALIVE) => self.keepalive = read_int(data)? != 0,
+                Ok(SolOptname::LINGER) => self.linger = read_linger(data)?,
+                Ok(SolOptname::RCVTIMEO) => self.recv_timeout = read_timeout(data)?,
+                Ok(SolOptname::SNDTIMEO) => self.send_timeout = read_timeout(data)?,
+                Ok(SolOptname::RCVBUF) => self.recv_buf = Some(buf_size(read_int(data)?)),
+                Ok(SolOptname::SNDBUF) => self.send_buf = Some(buf_size(read_int(data)?)),
+                Ok(SolOptname::BINDTODEVICE) => self.bound_device = read_ifname(data)?,
+                // read-only options
+                Ok(SolOptname::TYPE) | Ok(SolOptname::ERROR) | Ok(SolOptname::ACCEPTCONN) => {
+                    return Err(LxError::ENOPROTOOPT)
+                }
+                // filters are only run on packet sockets
+                Ok(SolOptname::ATTACH_FILTER) | Ok(SolOptname::DETACH_FILTER) | Err(_) => ignored(),
+            },
+            Ok(Level::IPPROTO_TCP) if socket_type == SocketType::SOCK_STREAM => {
+                match TcpOptname::try_from(opt) {
+                    Ok(TcpOptname::NODELAY) => self.nodelay = read_int(data)? != 0,
+                    Ok(TcpOptname::KEEPIDLE) => {
+                        self.keepidle = read_in_range(data, 1, MAX_TCP_KEEPIDLE)?
+                    }
+                    Ok(TcpOptname::KEEPINTVL) => {
+                        self.keepintvl = read_in_range(data, 1, MAX_TCP_KEEPIDLE)?
+                    }
+                    Ok(TcpOptname::KEEPCNT) => {
+                        self.keepcnt = read_in_range(data, 1, MAX_TCP_KEEPCNT)?
+                    }
+                    Ok(TcpOptname::MAXSEG) | Ok(TcpOptname::CONGESTION) | Err(_) => ignored(),
+                }
+            }
+            Ok(Level::IPPROTO_TCP) => return Err(LxError::ENOPROTOOPT),
+            Ok(Level::IPPROTO_IP) => match IpOptname::try_from(opt) {
+                Ok(IpOptname::TTL) => {
+                    self.ttl = match read_ip_int(data)? {
+                        -1 => DEFAULT_TTL,
+                        ttl @ 1..=255 => ttl as u8,
+                        _ => return Err(LxError::EINVAL),
+                    }
+                }
+                Ok(IpOptname::TOS) => self.tos = read_ip_int(data)? as u8,
+                Ok(IpOptname::MULTICAST_TTL) => {
+                    self.multicast_ttl = match read_ip_int(data)? {
+                        -1 => 1,
+                        ttl @ 0..=255 => ttl as u8,
+                        _ => return Err(LxError::EINVAL),
+                    }
+                }
+                Ok(IpOptname::MULTICAST_LOOP) => self.multicast_loop = read_ip_int(data)? != 0,
+                Ok(IpOptname::ADD_MEMBERSHIP) => {
+                    let (group, index) = read_mreq(data)?;
+                    let joined = &mut self.memberships.0;
+                    if joined.iter().any(|m| m.group == group && m.index == index) {
+                        return Err(LxError::EADDRINUSE);
+                    }
+                    if joined.len() >= MAX_MEMBERSHIPS {
+                        return Err(LxError::ENOBUFS);
+                    }
+                    joined.push(Membership::join(index, group)?);
+                }
+                Ok(IpOptname::DROP_MEMBERSHIP) => {
+                    let (group, index) = read_mreq(data)?;
+                    let joined = &mut self.memberships.0;
+                    let i = joined
+                        .iter()
+                        .position(|m| m.group == group && m.index == index)
+                        .ok_or(LxError::EADDRNOTAVAIL)?;
+                    joined.remove(i);
+                }
+                // only for raw sockets
+                Ok(IpOptname::HDRINCL) => return Err(LxError::ENOPROTOOPT),
+                Err(_) => ignored(),
+            },
+            Ok(Level::IPPROTO_IPV6) => match Ipv6Optname::try_from(opt) {
+                Ok(Ipv6Optname::V6ONLY) => self.v6only = read_int(data)? != 0,
+                Err(_) => ignored(),
+            },
+            Ok(Level::SOL_PACKET) | Err(_) => return Err(LxError::ENOPROTOOPT),
+        }
+        Ok(())
+    }
+
+    /// Get an option of a socket of `socket_type` into `data`, returns the
+    /// length of the option.
+    ///
+    /// Options depending on the state of the socket, such as `SO_ERROR` and
+    /// `SO_RCVBUF`, are left to the socket.
+    pub fn get(
+        &self,
+        socket_type: SocketType,
+        level: usize,
+        opt: usize,
+        data: &mut [u8],
+    ) -> LxResult<usize> {
+        match Level::try_from(level) {
+            Ok(Level::SOL_SOCKET) => match SolOptname::try_from(opt) {
+                Ok(SolOptname::TYPE) => write_int(data, socket_type as i32),
+                Ok(SolOptname::REUSEADDR) => write_int(data, self.reuse_addr as i32),
+                Ok(SolOptname::REUSEPORT) => write_int(data, self.reuse_port as i32),
+                Ok(SolOptname::BROADCAST) => write_int(data, self.broadcast as i32),
+                Ok(SolOptname::KEEPALIVE) => write_int(data, self.keepalive as i32),
+                Ok(SolOptname::LINGER) => {
+                    let onoff = self.linger.is_some() as i32;
+                    let linger = self.linger.unwrap_or(0) as i32;
+                    let mut bytes = [0; 8];
+                    bytes[..4].copy_from_slice(&onoff.to_ne_bytes());
+                    bytes[4..].copy_from_slice(&linger.to_ne_bytes());
+                    write_bytes(data, &bytes)
+                }
+                Ok(SolOptname::RCVTIMEO) => write_timeout(data, self.recv_timeout),
+                Ok(SolOptname::SNDTIMEO) => write_timeout(data, self.send_timeout),
+                Ok(SolOptname::BINDTODEVICE) => {
+                    let mut name = self.bound_device.clone().unwrap_or_default().into_bytes();
+                    name.push(0);
+                    write_bytes(data, &name)
+                }
+                _ => Err(LxError::ENOPROTOOPT),
+            },
+            Ok(Level::IPPROTO_TCP) if socket_type == SocketType::SOCK_STREAM => {
+                match TcpOptname::try_from(opt) {
+                    Ok(TcpOptname::NODELAY) => write_int(data, self.nodelay as i32),
+                    Ok(TcpOptname::KEEPIDLE) => write_int(data, self.keepidle as i32),
+                    Ok(TcpOptname::KEEPINTVL) => write_int(data, self.keepintvl as i32),
+                    Ok(TcpOptname::KEEPCNT) => write_int(data, self.keepcnt as i32),
+                    _ => Err(LxError::ENOPROTOOPT),
+                }
+            }
+            Ok(Level::IPPROTO_IP) => match IpOptname::try_from(opt) {
+                Ok(IpOptname::TTL) => write_int(data, self.ttl as i32),
+                Ok(IpOptname::TOS) => write_int(data, self.tos as i32),
+                Ok(IpOptname::MULTICAST_TTL) => write_int(data, self.multicast_ttl as i32),
+                Ok(IpOptname::MULTICAST_LOOP) => write_int(data, self.multicast_loop as i32),
+                _ => Err(LxError::ENOPROTOOPT),
+            },
+            Ok(Level::IPPROTO_IPV6) => match Ipv6Optname::try_from(opt) {
+                Ok(Ipv6Optname::V6ONLY) => write_int(data, self.v6only as i32),
+                Err(_) => Err(LxError::ENOPROTOOPT),
+            },
+            _ => Err(LxError::ENOPROTOOPT),
+        }
+    }
+
+    /// Apply the options supported by smoltcp to a TCP socket.
+    pub fn apply_tcp(&self, socket: &mut TcpSocket) {
+        let keep_alive = if self.keepalive {
+            Some(smoltcp::time::Duration::from_secs(self.keepidle as u64))
+        } else {
+            None
+        };
+        socket.set_keep_alive(keep_alive);
+        socket.set_hop_limit(Some(self.ttl));
+        socket.set_nagle_enabled(!self.nodelay);
+    }
+
+    /// Bind `port` of `protocol` to the interface of `SO_BINDTODEVICE`, if any.
+    pub fn device_binding(&self, protocol: IpProtocol, port: u16) -> Option<DeviceBinding> {
+        let name = self.bound_device.as_ref()?;
+        Some(DeviceBinding::new(protocol, port, name))
+    }
+
+    /// Apply the options supported by smoltcp to a UDP socket.
+    pub fn apply_udp(&self, socket: &mut UdpSocket) {
+        socket.set_hop_limit(Some(self.ttl));
+    }
+}
+
+/// A multicast group joined on an interface by `IP_ADD_MEMBERSHIP`, left on
+/// drop.
+#[derive(Debug)]
+pub struct Membership {
+    /// index of the interface
+    index: usize,
+    /// the joined group
+    group: Ipv4Address,
+}
+
+impl Membership {
+    fn join(index: usize, group: Ipv4Address) -> LxResult<Self> {
+        iface::join_multicast_group(index, group)?;
+        Ok(Membership { index, group })
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        if let Err(e) = iface::leave_multicast_group(self.index, self.group) {
+            warn!("failed to leave multicast group {}: {:?}", self.group, e);
+        }
+    }
+}
+
+/// The multicast groups joined by a socket, which are not inherited by the
+/// sockets it accepts as in Linux.
+#[derive(Debug, Default)]
+pub struct Memberships(Vec<Membership>);
+
+impl Clone for Memberships {
+    fn clone(&self) -> Self {
+        Memberships::default()
+    }
+}
+
+/// The deadline of a blocking operation, `None` if it never times out.
+pub fn deadline(timeout: Option<Duration>) -> Option<Duration> {
+    timeout.map(deadline_after)
+}
+
+/// Whether `deadline` has passed.
+pub fn expired(deadline: Option<Duration>) -> bool {
+    deadline.map_or(false, |deadline| timer_now() >= deadline)
+}
+
+/// Write an `int` option, truncated to the length of `data` as in Linux.
+pub fn write_int(data: &mut [u8], value: i32) -> LxResult<usize> {
+    write_bytes(data, &value.to_ne_bytes())
+}
+
+fn write_bytes(data: &mut [u8], value: &[u8]) -> LxResult<usize> {
+    let len = data.len().min(value.len());
+    data[..len].copy_from_slice(&value[..len]);
+    Ok(len)
+}
+
+fn write_timeout(data: &mut [u8], timeout: Option<Duration>) -> LxResult<usize> {
+    let timeout = timeout.unwrap_or_default();
+    let mut bytes = [0; size_of::<TimeVal>()];
+    bytes[..size_of::<usize>()].copy_from_slice(&(timeout.as_secs() as usize).to_ne_bytes());
+    bytes[size_of::<usize>()..].copy_from_slice(&(timeout.subsec_micros() as usize).to_ne_bytes());
+    write_bytes(data, &bytes)
+}
+
+/// Read an `int` option of the socket level.
+fn read_int(data: &[u8]) -> LxResult<i32> {
+    if data.len() < size_of::<i32>() {
+        return Err(LxError::EINVAL);
+    }
+    Ok(i32::from_ne_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+/// Read an `int` option of the IP level, which can also be a byte.
+fn read_ip_int(data: &[u8]) -> LxResult<i32> {
+    match data.len() {
+        0 => Err(LxError::EINVAL),
+        1..=3 => Ok(data[0] as i32),
+        _ => read_int(data),
+    }
+}
+
+fn read_in_range(data: &[u8], min: u32, max: u32) -> LxResult<u32> {
+    let value = read_int(data)?;
+    if value < min as i32 || value > max as i32 {
+        return Err(LxError::EINVAL);
+    }
+    Ok(value as u32)
+}
+
+/// Read a `struct linger`.
+fn read_linger(data: &[u8]) -> LxResult<Option<u32>> {
+    if data.len() < 2 * size_of::<i32>() {
+        return Err(LxError::EINVAL);
+    }
+    let onoff = read_int(data)?;
+    let linger = read_int(&data[4..])?;
+    Ok(if onoff != 0 {
+        Some(linger.max(0) as u32)
+    } else {
+        None
+    })
+}
+
+/// Read a `struct timeval`, a zero timeout means never timing out.
+fn read_timeout(data: &[u8]) -> LxResult<Option<Duration>> {
+    if data.len() < size_of::<TimeVal>() {
+        return Err(LxError::EINVAL);
+    }
+    #[allow(unsafe_code)]
+    let tv = unsafe { (data.as_ptr() as *const TimeVal).read_unaligned() };
+    if tv.usec >= 1_000_000 {
+        return Err(LxError::EDOM);
+    }
+    let timeout = Duration::new(tv.sec as u64, tv.usec as u32 * 1000);
+    Ok(if timeout.is_zero() {
+        None
+    } else {
+        Some(timeout)
+    })
+}
+
+/// Read the interface name of `SO_BINDTODEVICE`, `None` if it's empty.
+fn read_ifname(data: &[u8]) -> LxResult<Option<String>> {
+    let len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    if len == 0 {
+        return Ok(None);
+    }
+    if len >= IFNAMSIZ {
+        return Err(LxError::EINVAL);
+    }
+    let name = core::str::from_utf8(&data[..len]).map_err(|_| LxError::EINVAL)?;
+    iface::iface_by_name(name).map_err(|_| LxError::ENODEV)?;
+    Ok(Some(name.to_string()))
+}
+
+/// Read a `struct ip_mreq` or `struct ip_mreqn`, returns the group and the
+/// index of the interface to join it on.
+fn read_mreq(data: &[u8]) -> LxResult<(Ipv4Address, usize)> {
+    if data.len() < 2 * size_of::<u32>() {
+        return Err(LxError::EINVAL);
+    }
+    let group = Ipv4Address::from_bytes(&data[..4]);
+    if !group.is_multicast() {
+        return Err(LxError::EINVAL);
+    }
+    let addr = Ipv4Address::from_bytes(&data[4..8]);
+    // `imr_ifindex` of `struct ip_mreqn`
+    let ifindex = if data.len() >= 3 * size_of::<u32>() {
+        read_int(&data[8..])?.max(0) as usize
+    } else {
+        0
+    };
+    Ok((group, iface::multicast_iface(group, addr, ifindex)?))
+}
+
+/// The buffer size for `SO_RCVBUF` and `SO_SNDBUF`, doubled as in Linux to
+/// leave room for bookkeeping.
+fn buf_size(value: i32) -> usize {
+    (value.max(0) as usize * 2).clamp(MIN_SOCKBUF, MAX_SOCKBUF)
+}
@@ -2,10 +2,19 @@
 
 // crate
 use crate::error::{LxError, LxResult};
-use crate::fs::{FileLike, OpenFlags, PollStatus};
+use crate::fs::{
+    ioctl::{FIONBIO, FIONREAD},
+    FileLike, OpenFlags, PollStatus,
+};
+use crate::net::port::{self, BoundPort};
+use crate::net::sockopt::{self, SocketOptions};
 use crate::net::*;
 use alloc::sync::Arc;
+use core::convert::TryFrom;
+use core::time::Duration;
+use kernel_hal::user::{UserInPtr, UserOutPtr};
 use lock::Mutex;
+use zcore_drivers::net::route::DeviceBinding;
 
 // alloc
 use alloc::boxed::Box;
@@ -13,6 +22,7 @@ use alloc::vec;
 
 // smoltcp
 use smoltcp::socket::{TcpSocket, TcpSocketBuffer, TcpState};
+use smoltcp::wire::IpProtocol;
 
 // async
 use async_trait::async_trait;
@@ -21,6 +31,10 @@ use async_trait::async_trait;
 #[allow(unused_imports)]
 use zircon_object::object::*;
 
+/// How often a closed socket with `SO_LINGER` is polled until its data is
+/// acknowledged.
+const LINGER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 /// TCP socket structure
 pub struct TcpSocketState {
     /// Kernel object base
@@ -35,10 +49,61 @@ pub struct TcpInner {
     handle: GlobalSocketHandle,
     /// missing documentation
     local_endpoint: Option<IpEndpoint>, // save local endpoint for bind()
+    /// the port taken by `bind()`
+    bound_port: Option<BoundPort>,
+    /// the interface of `SO_BINDTODEVICE` bound to the local port
+    device_binding: Option<DeviceBinding>,
     /// missing documentation
     is_listening: bool,
     /// flags on the socket
     flags: OpenFlags,
+    /// an `AF_INET6` socket
+    ipv6: bool,
+    /// a non-blocking connection is in progress
+    connecting: bool,
+    /// socket options
+    options: SocketOptions,
+}
+
+impl Drop for TcpInner {
+    fn drop(&mut self) {
+        let linger = match self.options.linger {
+            Some(linger) => linger,
+            None => return,
+        };
+        let sets = get_sockets();
+        let mut sets = sets.lock();
+        let mut socket = sets.get::<TcpSocket>(self.handle.0);
+        if linger == 0 {
+            // reset the connection, discarding unsent data
+            socket.abort();
+            return;
+        }
+        socket.close();
+        drop(socket);
+        drop(sets);
+        // wait for the unsent data and FIN to be acknowledged in the
+        // background, the socket is kept until then
+        let handle = self.handle.clone();
+        let deadline = sockopt::deadline(Some(Duration::from_secs(linger as u64)));
+        kernel_hal::thread::spawn(async move {
+            while !sockopt::expired(deadline) {
+                poll_ifaces();
+                let state = get_sockets().lock().get::<TcpSocket>(handle.0).state();
+                if matches!(
+                    state,
+                    TcpState::Closed | TcpState::FinWait2 | TcpState::TimeWait
+                ) {
+                    break;
+                }
+                kernel_hal::thread::sleep_until(kernel_hal::timer::deadline_after(
+                    LINGER_POLL_INTERVAL,
+                ))
+                .await;
+            }
+            drop(handle);
+        });
+    }
 }
 
 impl Default for TcpSocketState {
@@ -50,9 +115,19 @@ impl Default for TcpSocketState {
 impl TcpSocketState {
     /// missing documentation
     pub fn new() -> Self {
-        let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
-        let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
-        let socket = TcpSocket::new(rx_buffer, tx_buffer);
+        Self::with_family(false)
+    }
+
+    /// Create an `AF_INET6` socket, which also takes IPv4 connections unless
+    /// `IPV6_V6ONLY` is set.
+    pub fn new_ipv6() -> Self {
+        Self::with_family(true)
+    }
+
+    fn with_family(ipv6: bool) -> Self {
+        port::install_tcp_isn();
+        let options = SocketOptions::default();
+        let socket = new_tcp_socket(&options);
         let handle = GlobalSocketHandle(get_sockets().lock().add(socket));
 
         TcpSocketState {
@@ -60,19 +135,34 @@ impl TcpSocketState {
             inner: Mutex::new(TcpInner {
                 handle,
                 local_endpoint: None,
+                bound_port: None,
+                device_binding: None,
                 is_listening: false,
                 flags: OpenFlags::RDWR,
+                ipv6,
+                connecting: false,
+                options,
             }),
         }
     }
 }
 
+/// Create a smoltcp socket with the buffer sizes and options in `options`.
+fn new_tcp_socket(options: &SocketOptions) -> TcpSocket<'static> {
+    let rx_buffer = TcpSocketBuffer::new(vec![0; options.recv_buf.unwrap_or(TCP_RECVBUF)]);
+    let tx_buffer = TcpSocketBuffer::new(vec![0; options.send_buf.unwrap_or(TCP_SENDBUF)]);
+    let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
+    options.apply_tcp(&mut socket);
+    socket
+}
+
 #[async_trait]
 impl Socket for TcpSocketState {
     /// read to buffer
     async fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
         info!("tcp read");
         let inner = self.inner.lock();
+        let deadline = sockopt::deadline(inner.options.recv_timeout);
         loop {
             //poll_ifaces();
 
@@ -88,7 +178,7 @@ impl Socket for TcpSocketState {
             match copied_len {
                 Ok(0) | Err(smoltcp::Error::Exhausted) => {
                     poll_ifaces();
-                    if inner.flags.contains(OpenFlags::NON_BLOCK) {
+                    if inner.flags.contains(OpenFlags::NON_BLOCK) || sockopt::expired(deadline) {
                         return (Err(LxError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED));
                     } else {
                         // Continue reading
@@ -100,7 +190,7 @@ impl Socket for TcpSocketState {
                         .lock()
                         .get::<TcpSocket>(inner.handle.0)
                         .remote_endpoint();
-                    return (Ok(size), Endpoint::Ip(endpoint));
+                    return (Ok(size), socket_endpoint(endpoint, inner.ipv6));
                 }
                 Err(err) => {
                     error!("Tcp socket read error: {:?}", err);
@@ -114,36 +204,68 @@ impl Socket for TcpSocketState {
     }
     /// write from buffer
     fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
-        //loop {
-        let sets = get_sockets();
-        let mut sets = sets.lock();
-        let mut socket = sets.get::<TcpSocket>(self.inner.lock().handle.0);
-        let copied_len = socket.send_slice(data);
+        let inner = self.inner.lock();
+        let deadline = sockopt::deadline(inner.options.send_timeout);
+        loop {
+            let sets = get_sockets();
+            let mut sets = sets.lock();
+            let mut socket = sets.get::<TcpSocket>(inner.handle.0);
+            let copied_len = socket.send_slice(data);
 
-        drop(socket);
-        drop(sets);
-        poll_ifaces();
+            drop(socket);
+            drop(sets);
+            poll_ifaces();
 
-        match copied_len {
-            Ok(size) => Ok(size),
-            Err(err) => {
-                error!("Tcp socket write error: {:?}", err);
-                Err(LxError::ENOBUFS)
+            match copied_len {
+                // the send buffer is full
+                Ok(0) if !data.is_empty() => {
+                    if inner.flags.contains(OpenFlags::NON_BLOCK) || sockopt::expired(deadline) {
+                        return Err(LxError::EAGAIN);
+                    }
+                }
+                Ok(size) => return Ok(size),
+                Err(err) => {
+                    error!("Tcp socket write error: {:?}", err);
+                    return Err(LxError::ENOBUFS);
+                }
             }
         }
-        //}
     }
     /// connect
     async fn connect(&self, endpoint: Endpoint) -> SysResult {
-        let inner = self.inner.lock();
+        let mut inner = self.inner.lock();
         #[allow(warnings)]
         if let Endpoint::Ip(ip) = endpoint {
-            get_sockets()
-                .lock()
-                .get::<TcpSocket>(inner.handle.0)
-                .connect(ip, get_ephemeral_port())
-                .map_err(|_| LxError::ENOBUFS)?;
+            let ip = inet_endpoint(ip, inner.ipv6, inner.options.v6only)?;
+            // link-local peers are only reachable from link-local addresses
+            let local_addr = match ip.addr {
+                IpAddress::Ipv6(addr) => {
+                    iface::ipv6_source_addr(addr).map_or(IpAddress::Unspecified, IpAddress::Ipv6)
+                }
+                _ => IpAddress::Unspecified,
+            };
+            {
+                let sets = get_sockets();
+                let mut sets = sets.lock();
+                // connect from the bound port if any
+                let local_port = match inner.local_endpoint {
+                    Some(endpoint) => endpoint.port,
+                    None => port::ephemeral_port(&sets, IpProtocol::Tcp)?,
+                };
+                inner.device_binding = inner.options.device_binding(IpProtocol::Tcp, local_port);
+                let mut socket = sets.get::<TcpSocket>(inner.handle.0);
+                match socket.state() {
+                    TcpState::Closed => {}
+                    TcpState::SynSent => return Err(LxError::EALREADY),
+                    TcpState::Listen => return Err(LxError::EINVAL),
+                    _ => return Err(LxError::EISCONN),
+                }
+                socket
+                    .connect(ip, IpEndpoint::new(local_addr, local_port))
+                    .map_err(|_| LxError::ENOBUFS)?;
+            }
 
+            let deadline = sockopt::deadline(inner.options.send_timeout);
             let mut tc = 0;
             // wait for connection result
             loop {
@@ -157,6 +279,12 @@ impl Socket for TcpSocketState {
                 {
                     TcpState::SynSent => {
                         // still connecting
+                        if inner.flags.contains(OpenFlags::NON_BLOCK) || sockopt::expired(deadline)
+                        {
+                            // the result is reported by SO_ERROR
+                            inner.connecting = true;
+                            return Err(LxError::EINPROGRESS);
+                        }
                     }
                     TcpState::Established => {
                         return Ok(0);
@@ -224,10 +352,20 @@ impl Socket for TcpSocketState {
 
     fn bind(&self, endpoint: Endpoint) -> SysResult {
         let mut inner = self.inner.lock();
-        if let Endpoint::Ip(mut ip) = endpoint {
+        if let Endpoint::Ip(ip) = endpoint {
+            let mut ip = inet_endpoint(ip, inner.ipv6, inner.options.v6only)?;
+            if inner.bound_port.is_some() {
+                return Err(LxError::EINVAL);
+            }
+            let sets = get_sockets();
+            let sets = sets.lock();
             if ip.port == 0 {
-                ip.port = get_ephemeral_port();
+                ip.port = port::ephemeral_port(&sets, IpProtocol::Tcp)?;
             }
+            let bound_port = BoundPort::bind(&sets, IpProtocol::Tcp, ip.port, &inner.options)?;
+            drop(sets);
+            inner.bound_port = Some(bound_port);
+            inner.device_binding = inner.options.device_binding(IpProtocol::Tcp, ip.port);
             inner.local_endpoint = Some(ip);
             inner.is_listening = false;
             Ok(0)
@@ -274,20 +412,25 @@ impl Socket for TcpSocketState {
     async fn accept(&self) -> LxResult<(Arc<dyn FileLike>, Endpoint)> {
         let mut inner = self.inner.lock();
         let endpoint = inner.local_endpoint.ok_or(LxError::EINVAL)?;
+        let deadline = sockopt::deadline(inner.options.recv_timeout);
         loop {
             //poll_ifaces();
             let sets = get_sockets();
             let mut sets = sets.lock();
-            let socket = sets.get::<TcpSocket>(inner.handle.0);
+            let mut socket = sets.get::<TcpSocket>(inner.handle.0);
             if socket.is_active() {
                 let remote_endpoint = socket.remote_endpoint();
+                if inner.options.v6only && matches!(remote_endpoint.addr, IpAddress::Ipv4(_)) {
+                    // listening on `::` also takes IPv4 connections in smoltcp
+                    socket.abort();
+                    socket.listen(endpoint).map_err(|_| LxError::EINVAL)?;
+                    continue;
+                }
                 drop(socket);
                 drop(sets);
 
                 let new_socket = {
-                    let rx_buffer = TcpSocketBuffer::new(vec![0; TCP_RECVBUF]);
-                    let tx_buffer = TcpSocketBuffer::new(vec![0; TCP_SENDBUF]);
-                    let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
+                    let mut socket = new_tcp_socket(&inner.options);
                     socket.listen(endpoint).unwrap();
 
                     let new_handle = GlobalSocketHandle(get_sockets().lock().add(socket));
@@ -298,50 +441,153 @@ impl Socket for TcpSocketState {
                         inner: Mutex::new(TcpInner {
                             handle: old_handle,
                             local_endpoint: inner.local_endpoint,
+                            bound_port: None,
+                            device_binding: inner
+                                .options
+                                .device_binding(IpProtocol::Tcp, endpoint.port),
                             is_listening: false,
                             flags: OpenFlags::RDWR,
+                            ipv6: inner.ipv6,
+                            connecting: false,
+                            // accepted sockets inherit options of the listening one
+                            options: inner.options.clone(),
                         }),
                     })
                 };
 
                 return Ok((
                     new_socket as Arc<dyn FileLike>,
-                    Endpoint::Ip(remote_endpoint),
+                    socket_endpoint(remote_endpoint, inner.ipv6),
                 ));
             } else {
                 drop(socket);
                 drop(sets);
                 poll_ifaces();
+                if inner.flags.contains(OpenFlags::NON_BLOCK) || sockopt::expired(deadline) {
+                    return Err(LxError::EAGAIN);
+                }
             }
         }
     }
 
     fn endpoint(&self) -> Option<Endpoint> {
         let inner = self.inner.lock();
-        inner.local_endpoint.map(Endpoint::Ip).or_else(|| {
+        let endpoint = inner.local_endpoint.or_else(|| {
             let sets = get_sockets();
             let mut sets = sets.lock();
             let socket = sets.get::<TcpSocket>(inner.handle.0);
             let endpoint = socket.local_endpoint();
             if endpoint.port != 0 {
-                Some(Endpoint::Ip(endpoint))
+                Some(endpoint)
             } else {
                 None
             }
-        })
+        });
+        endpoint.map(|endpoint| socket_endpoint(endpoint, inner.ipv6))
     }
 
     fn remote_endpoint(&self) -> Option<Endpoint> {
+        let inner = self.inner.lock();
         let sets = get_sockets();
         let mut sets = sets.lock();
-        let socket = sets.get::<TcpSocket>(self.inner.lock().handle.0);
+        let socket = sets.get::<TcpSocket>(inner.handle.0);
         if socket.is_open() {
-            Some(Endpoint::Ip(socket.remote_endpoint()))
+            Some(socket_endpoint(socket.remote_endpoint(), inner.ipv6))
         } else {
             None
         }
     }
 
+    fn setsockopt(&self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        let mut inner = self.inner.lock();
+        inner
+            .options
+            .set(SocketType::SOCK_STREAM, level, opt, data)?;
+        let sets = get_sockets();
+        let mut sets = sets.lock();
+        let mut socket = sets.get::<TcpSocket>(inner.handle.0);
+        let port = inner
+            .local_endpoint
+            .map_or(socket.local_endpoint().port, |endpoint| endpoint.port);
+        if port != 0 {
+            inner.device_binding = inner.options.device_binding(IpProtocol::Tcp, port);
+        }
+        let options = &inner.options;
+        // buffers can only be resized before connecting or listening
+        let resize = socket.state() == TcpState::Closed
+            && (options
+                .recv_buf
+                .map_or(false, |len| len != socket.recv_capacity())
+                || options
+                    .send_buf
+                    .map_or(false, |len| len != socket.send_capacity()));
+        if !resize {
+            options.apply_tcp(&mut socket);
+            return Ok(0);
+        }
+        drop(socket);
+        let new_handle = GlobalSocketHandle(sets.add(new_tcp_socket(options)));
+        let old_handle = core::mem::replace(&mut inner.handle, new_handle);
+        // releasing the old socket locks the socket set
+        drop(sets);
+        drop(old_handle);
+        Ok(0)
+    }
+
+    fn getsockopt(&self, level: usize, opt: usize, data: &mut [u8]) -> LxResult<usize> {
+        let mut inner = self.inner.lock();
+        if let Ok(Level::SOL_SOCKET) = Level::try_from(level) {
+            let sets = get_sockets();
+            let mut sets = sets.lock();
+            let socket = sets.get::<TcpSocket>(inner.handle.0);
+            match SolOptname::try_from(opt) {
+                Ok(SolOptname::ERROR) => {
+                    let state = socket.state();
+                    let mut error = 0;
+                    if inner.connecting && state != TcpState::SynSent {
+                        inner.connecting = false;
+                        if state == TcpState::Closed {
+                            error = LxError::ECONNREFUSED as i32;
+                        }
+                    }
+                    return sockopt::write_int(data, error);
+                }
+                Ok(SolOptname::RCVBUF) => {
+                    return sockopt::write_int(data, socket.recv_capacity() as i32)
+                }
+                Ok(SolOptname::SNDBUF) => {
+                    return sockopt::write_int(data, socket.send_capacity() as i32)
+                }
+                Ok(SolOptname::ACCEPTCONN) => {
+                    return sockopt::write_int(data, inner.is_listening as i32)
+                }
+                _ => {}
+            }
+        }
+        inner.options.get(SocketType::SOCK_STREAM, level, opt, data)
+    }
+
+    /// manipulate file descriptor
+    fn ioctl(&self, request: usize, arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
+        match request {
+            FIONREAD => {
+                let handle = self.inner.lock().handle.0;
+                let sets = get_sockets();
+                let mut sets = sets.lock();
+                let socket = sets.get::<TcpSocket>(handle);
+                UserOutPtr::<i32>::from(arg1).write(socket.recv_queue() as i32)?;
+                Ok(0)
+            }
+            FIONBIO => {
+                let non_block = UserInPtr::<i32>::from(arg1).read()? != 0;
+                let flags = &mut self.inner.lock().flags;
+                flags.set(OpenFlags::NON_BLOCK, non_block);
+                Ok(0)
+            }
+            _ => iface::ioctl(request, arg1).unwrap_or(Ok(0)),
+        }
+    }
+
     fn get_buffer_capacity(&self) -> Option<(usize, usize)> {
         let sockets = get_sockets();
         let mut set = sockets.lock();
@@ -395,7 +641,7 @@ impl FileLike for TcpSocketState {
     }
 
     async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
-        unimplemented!()
+        Err(LxError::ESPIPE)
     }
 
     fn write(&self, buf: &[u8]) -> LxResult<usize> {
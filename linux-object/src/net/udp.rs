@@ -1,12 +1,21 @@
 // udpsocket
 
 use crate::error::{LxError, LxResult};
-use crate::fs::{FileLike, OpenFlags, PollStatus};
+use crate::fs::{
+    ioctl::{FIONBIO, FIONREAD},
+    FileLike, OpenFlags, PollStatus,
+};
+use crate::net::port::BoundPort;
+use crate::net::sockopt::{self, SocketOptions};
 use crate::net::*;
 use alloc::{boxed::Box, sync::Arc, vec};
 use async_trait::async_trait;
+use core::convert::TryFrom;
+use kernel_hal::user::{UserInPtr, UserOutPtr};
 use lock::Mutex;
 use smoltcp::socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer};
+use smoltcp::wire::IpProtocol;
+use zcore_drivers::net::route::DeviceBinding;
 
 // third part
 #[allow(unused_imports)]
@@ -30,6 +39,14 @@ pub struct UdpInner {
     remote_endpoint: Option<IpEndpoint>,
     /// flags on the socket
     flags: OpenFlags,
+    /// an `AF_INET6` socket
+    ipv6: bool,
+    /// socket options
+    options: SocketOptions,
+    /// the interface of `SO_BINDTODEVICE` bound to the local port
+    device_binding: Option<DeviceBinding>,
+    /// the port taken by `bind()` or the first send
+    bound_port: Option<BoundPort>,
 }
 
 impl Default for UdpSocketState {
@@ -41,16 +58,19 @@ impl Default for UdpSocketState {
 impl UdpSocketState {
     /// missing documentation
     pub fn new() -> Self {
+        Self::with_family(false)
+    }
+
+    /// Create an `AF_INET6` socket, which also takes IPv4 datagrams unless
+    /// `IPV6_V6ONLY` is set.
+    pub fn new_ipv6() -> Self {
+        Self::with_family(true)
+    }
+
+    fn with_family(ipv6: bool) -> Self {
         info!("udp new");
-        let rx_buffer = UdpSocketBuffer::new(
-            vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
-            vec![0; UDP_RECVBUF],
-        );
-        let tx_buffer = UdpSocketBuffer::new(
-            vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
-            vec![0; UDP_SENDBUF],
-        );
-        let socket = UdpSocket::new(rx_buffer, tx_buffer);
+        let options = SocketOptions::default();
+        let socket = new_udp_socket(&options);
         let handle = GlobalSocketHandle(get_sockets().lock().add(socket));
 
         UdpSocketState {
@@ -59,11 +79,30 @@ impl UdpSocketState {
                 handle,
                 remote_endpoint: None,
                 flags: OpenFlags::RDWR,
+                ipv6,
+                options,
+                device_binding: None,
+                bound_port: None,
             }),
         }
     }
 }
 
+/// Create a smoltcp socket with the buffer sizes and options in `options`.
+fn new_udp_socket(options: &SocketOptions) -> UdpSocket<'static> {
+    let rx_buffer = UdpSocketBuffer::new(
+        vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
+        vec![0; options.recv_buf.unwrap_or(UDP_RECVBUF)],
+    );
+    let tx_buffer = UdpSocketBuffer::new(
+        vec![UdpPacketMetadata::EMPTY; UDP_METADATA_BUF],
+        vec![0; options.send_buf.unwrap_or(UDP_SENDBUF)],
+    );
+    let mut socket = UdpSocket::new(rx_buffer, tx_buffer);
+    options.apply_udp(&mut socket);
+    socket
+}
+
 /// missing in implementation
 #[async_trait]
 impl Socket for UdpSocketState {
@@ -71,6 +110,7 @@ impl Socket for UdpSocketState {
     async fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
         info!("udp read");
         let inner = self.inner.lock();
+        let deadline = sockopt::deadline(inner.options.recv_timeout);
         loop {
             let sets = get_sockets();
             let mut sets = sets.lock();
@@ -80,11 +120,17 @@ impl Socket for UdpSocketState {
             drop(sets);
 
             match copied_len {
-                Ok((size, endpoint)) => return (Ok(size), Endpoint::Ip(endpoint)),
+                // datagrams to a dual-stack address
+                Ok((_, endpoint))
+                    if inner.options.v6only && matches!(endpoint.addr, IpAddress::Ipv4(_)) =>
+                {
+                    trace!("udp drop an IPv4 datagram on a V6ONLY socket")
+                }
+                Ok((size, endpoint)) => return (Ok(size), socket_endpoint(endpoint, inner.ipv6)),
                 Err(smoltcp::Error::Exhausted) => {
                     poll_ifaces();
                     // The receive buffer is empty. Try again later...
-                    if inner.flags.contains(OpenFlags::NON_BLOCK) {
+                    if inner.flags.contains(OpenFlags::NON_BLOCK) || sockopt::expired(deadline) {
                         debug!("NON_BLOCK: Try again later...");
                         return (Err(LxError::EAGAIN), Endpoint::Ip(IpEndpoint::UNSPECIFIED));
                     } else {
@@ -104,11 +150,11 @@ impl Socket for UdpSocketState {
     /// write from buffer
     fn write(&self, data: &[u8], sendto_endpoint: Option<Endpoint>) -> SysResult {
         info!("udp write");
-        let inner = self.inner.lock();
+        let mut inner = self.inner.lock();
         let remote_endpoint = {
-            if let Some(Endpoint::Ip(ref endpoint)) = sendto_endpoint {
-                endpoint
-            } else if let Some(ref endpoint) = inner.remote_endpoint {
+            if let Some(Endpoint::Ip(endpoint)) = sendto_endpoint {
+                inet_endpoint(endpoint, inner.ipv6, inner.options.v6only)?
+            } else if let Some(endpoint) = inner.remote_endpoint {
                 endpoint
             } else {
                 return Err(LxError::ENOTCONN);
@@ -117,17 +163,22 @@ impl Socket for UdpSocketState {
 
         let sets = get_sockets();
         let mut sets = sets.lock();
-        let mut socket = sets.get::<UdpSocket>(inner.handle.0);
-        if socket.endpoint().port == 0 {
-            socket
-                .bind(IpEndpoint::new(
-                    IpAddress::Unspecified,
-                    get_ephemeral_port(),
-                ))
+        if sets.get::<UdpSocket>(inner.handle.0).endpoint().port == 0 {
+            let port = port::ephemeral_port(&sets, IpProtocol::Udp)?;
+            inner.bound_port = Some(BoundPort::bind(
+                &sets,
+                IpProtocol::Udp,
+                port,
+                &inner.options,
+            )?);
+            sets.get::<UdpSocket>(inner.handle.0)
+                .bind(IpEndpoint::new(IpAddress::Unspecified, port))
                 .unwrap();
+            inner.device_binding = inner.options.device_binding(IpProtocol::Udp, port);
         }
+        let mut socket = sets.get::<UdpSocket>(inner.handle.0);
 
-        let _len = socket.send_slice(data, *remote_endpoint);
+        let _len = socket.send_slice(data, remote_endpoint);
 
         drop(socket);
         drop(sets);
@@ -138,7 +189,8 @@ impl Socket for UdpSocketState {
     /// connect
     async fn connect(&self, endpoint: Endpoint) -> SysResult {
         if let Endpoint::Ip(ip) = endpoint {
-            self.inner.lock().remote_endpoint = Some(ip);
+            let mut inner = self.inner.lock();
+            inner.remote_endpoint = Some(inet_endpoint(ip, inner.ipv6, inner.options.v6only)?);
             Ok(0)
         } else {
             Err(LxError::EINVAL)
@@ -182,15 +234,25 @@ impl Socket for UdpSocketState {
     fn bind(&self, endpoint: Endpoint) -> SysResult {
         info!("udp bind");
         #[allow(irrefutable_let_patterns)]
-        if let Endpoint::Ip(mut ip) = endpoint {
-            if ip.port == 0 {
-                ip.port = get_ephemeral_port();
-            }
+        if let Endpoint::Ip(ip) = endpoint {
+            let mut inner = self.inner.lock();
+            let mut ip = inet_endpoint(ip, inner.ipv6, inner.options.v6only)?;
             let sockets = get_sockets();
             let mut set = sockets.lock();
-            let mut socket = set.get::<UdpSocket>(self.inner.lock().handle.0);
+            if ip.port == 0 {
+                ip.port = port::ephemeral_port(&set, IpProtocol::Udp)?;
+            }
+            if set.get::<UdpSocket>(inner.handle.0).is_open() {
+                return Err(LxError::EINVAL);
+            }
+            let bound_port = BoundPort::bind(&set, IpProtocol::Udp, ip.port, &inner.options)?;
+            let mut socket = set.get::<UdpSocket>(inner.handle.0);
             match socket.bind(ip) {
-                Ok(()) => Ok(0),
+                Ok(()) => {
+                    inner.bound_port = Some(bound_port);
+                    inner.device_binding = inner.options.device_binding(IpProtocol::Udp, ip.port);
+                    Ok(0)
+                }
                 Err(_) => Err(LxError::EINVAL),
             }
         } else {
@@ -210,30 +272,99 @@ impl Socket for UdpSocketState {
         Err(LxError::EINVAL)
     }
     fn endpoint(&self) -> Option<Endpoint> {
+        let inner = self.inner.lock();
         let net_sockets = get_sockets();
         let mut sockets = net_sockets.lock();
-        let socket = sockets.get::<UdpSocket>(self.inner.lock().handle.0);
+        let socket = sockets.get::<UdpSocket>(inner.handle.0);
 
         let endpoint = socket.endpoint();
         if endpoint.port != 0 {
-            Some(Endpoint::Ip(endpoint))
+            Some(socket_endpoint(endpoint, inner.ipv6))
         } else {
             None
         }
     }
     fn remote_endpoint(&self) -> Option<Endpoint> {
-        self.inner.lock().remote_endpoint.map(Endpoint::Ip)
+        let inner = self.inner.lock();
+        inner
+            .remote_endpoint
+            .map(|endpoint| socket_endpoint(endpoint, inner.ipv6))
     }
-    fn setsockopt(&self, _level: usize, _opt: usize, _data: &[u8]) -> SysResult {
-        warn!("setsockopt is unimplemented");
+    fn setsockopt(&self, level: usize, opt: usize, data: &[u8]) -> SysResult {
+        let mut inner = self.inner.lock();
+        inner
+            .options
+            .set(SocketType::SOCK_DGRAM, level, opt, data)?;
+        let sets = get_sockets();
+        let mut sets = sets.lock();
+        let mut socket = sets.get::<UdpSocket>(inner.handle.0);
+        let port = socket.endpoint().port;
+        if port != 0 {
+            inner.device_binding = inner.options.device_binding(IpProtocol::Udp, port);
+        }
+        let options = &inner.options;
+        // buffers can only be resized before binding, when no datagram is queued
+        let resize = !socket.is_open()
+            && (options
+                .recv_buf
+                .map_or(false, |len| len != socket.payload_recv_capacity())
+                || options
+                    .send_buf
+                    .map_or(false, |len| len != socket.payload_send_capacity()));
+        if !resize {
+            options.apply_udp(&mut socket);
+            return Ok(0);
+        }
+        drop(socket);
+        let new_handle = GlobalSocketHandle(sets.add(new_udp_socket(options)));
+        let old_handle = core::mem::replace(&mut inner.handle, new_handle);
+        // releasing the old socket locks the socket set
+        drop(sets);
+        drop(old_handle);
         Ok(0)
     }
 
+    fn getsockopt(&self, level: usize, opt: usize, data: &mut [u8]) -> LxResult<usize> {
+        let inner = self.inner.lock();
+        if let Ok(Level::SOL_SOCKET) = Level::try_from(level) {
+            let sets = get_sockets();
+            let mut sets = sets.lock();
+            let socket = sets.get::<UdpSocket>(inner.handle.0);
+            match SolOptname::try_from(opt) {
+                Ok(SolOptname::ERROR) => return sockopt::write_int(data, 0),
+                Ok(SolOptname::RCVBUF) => {
+                    return sockopt::write_int(data, socket.payload_recv_capacity() as i32)
+                }
+                Ok(SolOptname::SNDBUF) => {
+                    return sockopt::write_int(data, socket.payload_send_capacity() as i32)
+                }
+                Ok(SolOptname::ACCEPTCONN) => return sockopt::write_int(data, 0),
+                _ => {}
+            }
+        }
+        inner.options.get(SocketType::SOCK_DGRAM, level, opt, data)
+    }
+
     /// manipulate file descriptor
     fn ioctl(&self, request: usize, arg1: usize, _arg2: usize, _arg3: usize) -> SysResult {
-        warn!("ioctl is unimplemented for this socket");
         info!("udp ioctrl");
         match request {
+            // the size of the next datagram
+            FIONREAD => {
+                let handle = self.inner.lock().handle.0;
+                let sets = get_sockets();
+                let mut sets = sets.lock();
+                let mut socket = sets.get::<UdpSocket>(handle);
+                let len = socket.peek().map_or(0, |(payload, _)| payload.len());
+                UserOutPtr::<i32>::from(arg1).write(len as i32)?;
+                Ok(0)
+            }
+            FIONBIO => {
+                let non_block = UserInPtr::<i32>::from(arg1).read()? != 0;
+                let flags = &mut self.inner.lock().flags;
+                flags.set(OpenFlags::NON_BLOCK, non_block);
+                Ok(0)
+            }
             // SIOCGARP
             0x8954 => {
                 // TODO: check addr
@@ -268,7 +399,7 @@ impl Socket for UdpSocketState {
                     Err(LxError::EINVAL)
                 }
             }
-            _ => Ok(0),
+            _ => iface::ioctl(request, arg1).unwrap_or(Ok(0)),
         }
     }
 
@@ -311,7 +442,7 @@ impl FileLike for UdpSocketState {
     }
 
     async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
-        unimplemented!()
+        Err(LxError::ESPIPE)
     }
 
     fn write(&self, buf: &[u8]) -> LxResult<usize> {
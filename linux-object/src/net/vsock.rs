@@ -0,0 +1,661 @@
+//! Virtual sockets (`AF_VSOCK`)
+//!
+//! Stream sockets between the guest and the host over the first
+//! [`VsockScheme`] device, e.g. virtio-vsock, which only carries packets.
+//! Connections and their credits of receive buffers are managed here, as the
+//! virtio-vsock specification describes.
+//!
+//! Only `SOCK_STREAM` is supported, and there is no loopback transport, i.e.
+//! the guest can't connect to itself.
+
+use crate::error::{LxError, LxResult};
+use crate::fs::{FileLike, OpenFlags, PollStatus};
+use crate::net::*;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use async_trait::async_trait;
+use core::convert::TryFrom;
+use core::time::Duration;
+use kernel_hal::drivers::all_vsock;
+use lazy_static::lazy_static;
+use lock::Mutex;
+use zcore_drivers::scheme::{VsockHeader, VsockOp, VsockScheme, VSOCK_TYPE_STREAM};
+use zircon_object::{impl_kobject, object::*};
+
+/// Any context ID or port
+const VMADDR_CID_ANY: u32 = u32::MAX;
+const VMADDR_PORT_ANY: u32 = u32::MAX;
+/// Ports below it are reserved, and not allocated automatically.
+const LAST_RESERVED_PORT: u32 = 1023;
+
+/// Size of the receive buffer of each connection, told to the peer.
+const VSOCK_BUF_ALLOC: u32 = 256 * 1024;
+/// Tell the peer about the receive buffer if it may think there is less free
+/// space than this.
+const CREDIT_UPDATE_THRESHOLD: u32 = 64 * 1024;
+/// Maximum number of connections waiting to be accepted on a port.
+const MAX_BACKLOG: usize = 128;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// flags of `VsockOp::Shutdown`
+const SHUTDOWN_RCV: u32 = 1;
+const SHUTDOWN_SEND: u32 = 2;
+const SHUTDOWN_BOTH: u32 = SHUTDOWN_RCV | SHUTDOWN_SEND;
+
+/// Local port, and context ID and port of the peer.
+type ConnKey = (u32, u64, u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    Connecting,
+    Connected,
+    /// Reset by the peer, or refused to connect.
+    Closed,
+}
+
+struct Connection {
+    state: ConnState,
+    /// Received data not yet read.
+    rx: VecDeque<u8>,
+    /// Bytes read from the receive buffer.
+    fwd_cnt: u32,
+    /// `fwd_cnt` last told to the peer.
+    last_fwd_cnt: u32,
+    /// Bytes sent to the peer.
+    tx_cnt: u32,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// `SHUTDOWN_*` flags from the peer.
+    peer_shutdown: u32,
+    /// `SHUTDOWN_*` flags sent to the peer.
+    local_shutdown: u32,
+}
+
+impl Connection {
+    fn new(state: ConnState) -> Self {
+        Connection {
+            state,
+            rx: VecDeque::new(),
+            fwd_cnt: 0,
+            last_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            peer_shutdown: 0,
+            local_shutdown: 0,
+        }
+    }
+
+    /// Free space in the receive buffer of the peer.
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    /// Whether the peer may think the receive buffer is almost full.
+    fn need_credit_update(&self) -> bool {
+        let unannounced = self.rx.len() as u32 + self.fwd_cnt.wrapping_sub(self.last_fwd_cnt);
+        VSOCK_BUF_ALLOC.saturating_sub(unannounced) < CREDIT_UPDATE_THRESHOLD
+    }
+}
+
+/// Connections and listening ports of all sockets.
+#[derive(Default)]
+struct VsockTable {
+    connections: BTreeMap<ConnKey, Connection>,
+    /// port -> connections to be accepted
+    listeners: BTreeMap<u32, VecDeque<ConnKey>>,
+    /// Ports bound by sockets, connections accepted on a listening port
+    /// share it.
+    ports: BTreeSet<u32>,
+    next_port: u32,
+}
+
+lazy_static! {
+    static ref VSOCK_TABLE: Mutex<VsockTable> = Mutex::new(VsockTable::default());
+}
+
+fn device() -> LxResult<Arc<dyn VsockScheme>> {
+    all_vsock().first().ok_or(LxError::ENETUNREACH)
+}
+
+/// The header of a packet from the connection `key`, which tells the peer
+/// about the receive buffer.
+fn header(key: ConnKey, op: VsockOp, flags: u32, conn: Option<&mut Connection>) -> VsockHeader {
+    let fwd_cnt = match conn {
+        Some(conn) => {
+            conn.last_fwd_cnt = conn.fwd_cnt;
+            conn.fwd_cnt
+        }
+        None => 0,
+    };
+    VsockHeader {
+        dst_cid: key.1,
+        src_port: key.0,
+        dst_port: key.2,
+        socket_type: VSOCK_TYPE_STREAM,
+        op: op as u16,
+        flags,
+        buf_alloc: VSOCK_BUF_ALLOC,
+        fwd_cnt,
+        ..Default::default()
+    }
+}
+
+fn send(dev: &dyn VsockScheme, hdr: &VsockHeader, payload: &[u8]) -> LxResult {
+    dev.send(hdr, payload).map_err(|e| {
+        warn!("vsock: failed to send {:?}: {:?}", hdr, e);
+        LxError::EIO
+    })
+}
+
+impl VsockTable {
+    /// Allocate a port not bound by any socket.
+    fn alloc_port(&mut self) -> LxResult<u32> {
+        for _ in 0..=(u32::MAX - LAST_RESERVED_PORT) {
+            if self.next_port <= LAST_RESERVED_PORT || self.next_port == VMADDR_PORT_ANY {
+                self.next_port = LAST_RESERVED_PORT + 1;
+            }
+            let port = self.next_port;
+            self.next_port += 1;
+            if !self.ports.contains(&port) && !self.listeners.contains_key(&port) {
+                return Ok(port);
+            }
+        }
+        Err(LxError::EADDRINUSE)
+    }
+
+    /// Handle a packet received from the peer.
+    fn receive(&mut self, dev: &dyn VsockScheme, hdr: VsockHeader, payload: Vec<u8>) {
+        let key = (hdr.dst_port, hdr.src_cid, hdr.src_port);
+        let op = VsockOp::try_from(hdr.op);
+        if hdr.socket_type != VSOCK_TYPE_STREAM {
+            if op != Ok(VsockOp::Rst) {
+                send(dev, &header(key, VsockOp::Rst, 0, None), &[]).ok();
+            }
+            return;
+        }
+        if let Some(conn) = self.connections.get_mut(&key) {
+            conn.peer_buf_alloc = hdr.buf_alloc;
+            conn.peer_fwd_cnt = hdr.fwd_cnt;
+            let reply = match op {
+                Ok(VsockOp::Response) if conn.state == ConnState::Connecting => {
+                    conn.state = ConnState::Connected;
+                    None
+                }
+                Ok(VsockOp::Rw) if conn.state == ConnState::Connected => {
+                    conn.rx.extend(payload.iter());
+                    None
+                }
+                Ok(VsockOp::CreditRequest) => Some((VsockOp::CreditUpdate, 0)),
+                Ok(VsockOp::Shutdown) => {
+                    conn.peer_shutdown |= hdr.flags & SHUTDOWN_BOTH;
+                    if conn.peer_shutdown == SHUTDOWN_BOTH {
+                        conn.state = ConnState::Closed;
+                        Some((VsockOp::Rst, 0))
+                    } else {
+                        None
+                    }
+                }
+                Ok(VsockOp::Rst) => {
+                    conn.state = ConnState::Closed;
+                    None
+                }
+                _ => None,
+            };
+            if let Some((op, flags)) = reply {
+                send(dev, &header(key, op, flags, Some(conn)), &[]).ok();
+            }
+            return;
+        }
+        match op {
+            Ok(VsockOp::Request) => {
+                let accepted = match self.listeners.get_mut(&hdr.dst_port) {
+                    Some(backlog) if backlog.len() < MAX_BACKLOG => {
+                        backlog.push_back(key);
+                        true
+                    }
+                    _ => false,
+                };
+                if accepted {
+                    let mut conn = Connection::new(ConnState::Connected);
+                    conn.peer_buf_alloc = hdr.buf_alloc;
+                    conn.peer_fwd_cnt = hdr.fwd_cnt;
+                    let hdr = header(key, VsockOp::Response, 0, Some(&mut conn));
+                    self.connections.insert(key, conn);
+                    send(dev, &hdr, &[]).ok();
+                } else {
+                    send(dev, &header(key, VsockOp::Rst, 0, None), &[]).ok();
+                }
+            }
+            Ok(VsockOp::Rst) => {}
+            // not connected
+            _ => {
+                send(dev, &header(key, VsockOp::Rst, 0, None), &[]).ok();
+            }
+        }
+    }
+
+    /// Close the connection `key` and forget it.
+    fn close(&mut self, dev: Option<&dyn VsockScheme>, key: ConnKey) {
+        if let Some(mut conn) = self.connections.remove(&key) {
+            if let Some(dev) = dev {
+                let hdr = match conn.state {
+                    ConnState::Connecting => header(key, VsockOp::Rst, 0, None),
+                    ConnState::Connected => {
+                        header(key, VsockOp::Shutdown, SHUTDOWN_BOTH, Some(&mut conn))
+                    }
+                    ConnState::Closed => return,
+                };
+                send(dev, &hdr, &[]).ok();
+            }
+        }
+    }
+}
+
+/// Handle all packets received by the device.
+fn poll_vsock() {
+    if let Some(dev) = all_vsock().first() {
+        while let Ok(Some((hdr, payload))) = dev.recv() {
+            VSOCK_TABLE.lock().receive(&*dev, hdr, payload);
+        }
+    }
+}
+
+struct VsockInner {
+    /// The port bound by the socket.
+    bound_port: Option<u32>,
+    /// The connection of the socket, or the one accepted.
+    conn: Option<ConnKey>,
+    listening: bool,
+    flags: OpenFlags,
+}
+
+/// A virtual socket
+pub struct VsockSocketState {
+    /// Kernel object base
+    base: KObjectBase,
+    inner: Mutex<VsockInner>,
+}
+
+impl VsockSocketState {
+    /// Create a stream socket, fails if there is no vsock device.
+    pub fn new() -> LxResult<Self> {
+        device().map_err(|_| LxError::EAFNOSUPPORT)?;
+        Ok(Self::with_conn(None))
+    }
+
+    fn with_conn(conn: Option<ConnKey>) -> Self {
+        VsockSocketState {
+            base: KObjectBase::new(),
+            inner: Mutex::new(VsockInner {
+                bound_port: None,
+                conn,
+                listening: false,
+                flags: OpenFlags::RDWR,
+            }),
+        }
+    }
+
+    fn local_cid() -> u32 {
+        device().map_or(VMADDR_CID_ANY, |dev| dev.guest_cid() as u32)
+    }
+}
+
+impl Drop for VsockSocketState {
+    fn drop(&mut self) {
+        let inner = self.inner.lock();
+        let dev = device().ok();
+        let dev = dev.as_deref();
+        let mut table = VSOCK_TABLE.lock();
+        if let Some(key) = inner.conn {
+            table.close(dev, key);
+        }
+        if let Some(port) = inner.bound_port {
+            if inner.listening {
+                // refuse connections not accepted
+                for key in table.listeners.remove(&port).unwrap_or_default() {
+                    if let Some(dev) = dev {
+                        send(dev, &header(key, VsockOp::Rst, 0, None), &[]).ok();
+                    }
+                    table.connections.remove(&key);
+                }
+            }
+            table.ports.remove(&port);
+        }
+    }
+}
+
+#[async_trait]
+impl Socket for VsockSocketState {
+    async fn read(&self, data: &mut [u8]) -> (SysResult, Endpoint) {
+        let (key, non_block) = {
+            let inner = self.inner.lock();
+            (inner.conn, inner.flags.contains(OpenFlags::NON_BLOCK))
+        };
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let any = VsockEndpoint {
+                    cid: VMADDR_CID_ANY,
+                    port: VMADDR_PORT_ANY,
+                };
+                return (Err(LxError::ENOTCONN), Endpoint::Vsock(any));
+            }
+        };
+        let peer = Endpoint::Vsock(VsockEndpoint {
+            cid: key.1 as u32,
+            port: key.2,
+        });
+        loop {
+            poll_vsock();
+            let mut table = VSOCK_TABLE.lock();
+            let conn = match table.connections.get_mut(&key) {
+                Some(conn) => conn,
+                None => return (Err(LxError::ENOTCONN), peer),
+            };
+            if !conn.rx.is_empty() {
+                let len = conn.rx.len().min(data.len());
+                for (dst, src) in data.iter_mut().zip(conn.rx.drain(..len)) {
+                    *dst = src;
+                }
+                conn.fwd_cnt = conn.fwd_cnt.wrapping_add(len as u32);
+                if conn.need_credit_update() && conn.state == ConnState::Connected {
+                    let hdr = header(key, VsockOp::CreditUpdate, 0, Some(conn));
+                    if let Ok(dev) = device() {
+                        send(&*dev, &hdr, &[]).ok();
+                    }
+                }
+                return (Ok(len), peer);
+            }
+            match conn.state {
+                ConnState::Connecting => return (Err(LxError::ENOTCONN), peer),
+                ConnState::Closed => return (Ok(0), peer),
+                ConnState::Connected if conn.peer_shutdown & SHUTDOWN_SEND != 0 => {
+                    return (Ok(0), peer)
+                }
+                ConnState::Connected if non_block => return (Err(LxError::EAGAIN), peer),
+                ConnState::Connected => {}
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8], _sendto_endpoint: Option<Endpoint>) -> SysResult {
+        let (key, non_block) = {
+            let inner = self.inner.lock();
+            (inner.conn, inner.flags.contains(OpenFlags::NON_BLOCK))
+        };
+        let key = key.ok_or(LxError::ENOTCONN)?;
+        let dev = device()?;
+        let mut credit_requested = false;
+        loop {
+            poll_vsock();
+            let mut table = VSOCK_TABLE.lock();
+            let conn = table.connections.get_mut(&key).ok_or(LxError::ENOTCONN)?;
+            match conn.state {
+                ConnState::Connecting => return Err(LxError::ENOTCONN),
+                ConnState::Closed => return Err(LxError::EPIPE),
+                ConnState::Connected => {}
+            }
+            if conn.local_shutdown & SHUTDOWN_SEND != 0 || conn.peer_shutdown & SHUTDOWN_RCV != 0 {
+                return Err(LxError::EPIPE);
+            }
+            let len = (conn.credit() as usize).min(data.len());
+            if len > 0 || data.is_empty() {
+                conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+                let hdr = header(key, VsockOp::Rw, 0, Some(conn));
+                send(&*dev, &hdr, &data[..len])?;
+                return Ok(len);
+            }
+            if non_block {
+                return Err(LxError::EAGAIN);
+            }
+            // the peer may have missed credit updates
+            if !credit_requested {
+                let hdr = header(key, VsockOp::CreditRequest, 0, Some(conn));
+                send(&*dev, &hdr, &[])?;
+                credit_requested = true;
+            }
+        }
+    }
+
+    fn poll(&self, _events: PollEvents) -> (bool, bool, bool) {
+        poll_vsock();
+        let inner = self.inner.lock();
+        let table = VSOCK_TABLE.lock();
+        if inner.listening {
+            let port = inner.bound_port.unwrap();
+            let read = table.listeners.get(&port).map_or(false, |b| !b.is_empty());
+            return (read, false, false);
+        }
+        match inner.conn.and_then(|key| table.connections.get(&key)) {
+            Some(conn) => {
+                let closed = conn.state == ConnState::Closed;
+                let read = !conn.rx.is_empty() || conn.peer_shutdown & SHUTDOWN_SEND != 0 || closed;
+                let write = conn.state == ConnState::Connected && conn.credit() > 0;
+                (read, write, closed)
+            }
+            None => (false, false, false),
+        }
+    }
+
+    async fn connect(&self, endpoint: Endpoint) -> SysResult {
+        let peer = match endpoint {
+            Endpoint::Vsock(peer) => peer,
+            _ => return Err(LxError::EINVAL),
+        };
+        if peer.cid == VMADDR_CID_ANY || peer.cid == Self::local_cid() {
+            return Err(LxError::ENETUNREACH);
+        }
+        let dev = device()?;
+        let mut inner = self.inner.lock();
+        if inner.listening {
+            return Err(LxError::EINVAL);
+        }
+        if let Some(key) = inner.conn {
+            let table = VSOCK_TABLE.lock();
+            return match table.connections.get(&key).map(|conn| conn.state) {
+                Some(ConnState::Connecting) => Err(LxError::EALREADY),
+                Some(ConnState::Connected) => Err(LxError::EISCONN),
+                _ => Err(LxError::ECONNREFUSED),
+            };
+        }
+        let key = {
+            let mut table = VSOCK_TABLE.lock();
+            let port = match inner.bound_port {
+                Some(port) => port,
+                None => {
+                    let port = table.alloc_port()?;
+                    table.ports.insert(port);
+                    inner.bound_port = Some(port);
+                    port
+                }
+            };
+            let key = (port, peer.cid as u64, peer.port);
+            let mut conn = Connection::new(ConnState::Connecting);
+            let hdr = header(key, VsockOp::Request, 0, Some(&mut conn));
+            table.connections.insert(key, conn);
+            send(&*dev, &hdr, &[])?;
+            key
+        };
+        inner.conn = Some(key);
+        if inner.flags.contains(OpenFlags::NON_BLOCK) {
+            return Err(LxError::EINPROGRESS);
+        }
+        let deadline = sockopt::deadline(Some(CONNECT_TIMEOUT));
+        loop {
+            poll_vsock();
+            let mut table = VSOCK_TABLE.lock();
+            let state = table.connections.get(&key).map(|conn| conn.state);
+            match state {
+                Some(ConnState::Connected) => return Ok(0),
+                Some(ConnState::Connecting) if !sockopt::expired(deadline) => {}
+                Some(ConnState::Connecting) => {
+                    table.close(Some(&*dev), key);
+                    inner.conn = None;
+                    return Err(LxError::ETIMEDOUT);
+                }
+                _ => {
+                    table.connections.remove(&key);
+                    inner.conn = None;
+                    return Err(LxError::ECONNREFUSED);
+                }
+            }
+        }
+    }
+
+    fn bind(&self, endpoint: Endpoint) -> SysResult {
+        let local = match endpoint {
+            Endpoint::Vsock(local) => local,
+            _ => return Err(LxError::EINVAL),
+        };
+        if local.cid != VMADDR_CID_ANY && local.cid != Self::local_cid() {
+            return Err(LxError::EADDRNOTAVAIL);
+        }
+        let mut inner = self.inner.lock();
+        if inner.bound_port.is_some() || inner.conn.is_some() {
+            return Err(LxError::EINVAL);
+        }
+        let mut table = VSOCK_TABLE.lock();
+        let port = match local.port {
+            VMADDR_PORT_ANY => table.alloc_port()?,
+            port if table.ports.contains(&port) => return Err(LxError::EADDRINUSE),
+            port => port,
+        };
+        table.ports.insert(port);
+        inner.bound_port = Some(port);
+        Ok(0)
+    }
+
+    fn listen(&self) -> SysResult {
+        let mut inner = self.inner.lock();
+        if inner.conn.is_some() {
+            return Err(LxError::EINVAL);
+        }
+        let port = inner.bound_port.ok_or(LxError::EINVAL)?;
+        if !inner.listening {
+            VSOCK_TABLE.lock().listeners.insert(port, VecDeque::new());
+            inner.listening = true;
+        }
+        Ok(0)
+    }
+
+    fn shutdown(&self) -> SysResult {
+        let key = self.inner.lock().conn.ok_or(LxError::ENOTCONN)?;
+        let dev = device()?;
+        let mut table = VSOCK_TABLE.lock();
+        let conn = table.connections.get_mut(&key).ok_or(LxError::ENOTCONN)?;
+        if conn.state == ConnState::Connected && conn.local_shutdown != SHUTDOWN_BOTH {
+            conn.local_shutdown = SHUTDOWN_BOTH;
+            let hdr = header(key, VsockOp::Shutdown, SHUTDOWN_BOTH, Some(conn));
+            send(&*dev, &hdr, &[])?;
+        }
+        Ok(0)
+    }
+
+    async fn accept(&self) -> LxResult<(Arc<dyn FileLike>, Endpoint)> {
+        let (port, non_block) = {
+            let inner = self.inner.lock();
+            if !inner.listening {
+                return Err(LxError::EINVAL);
+            }
+            (
+                inner.bound_port.unwrap(),
+                inner.flags.contains(OpenFlags::NON_BLOCK),
+            )
+        };
+        loop {
+            poll_vsock();
+            let key = VSOCK_TABLE
+                .lock()
+                .listeners
+                .get_mut(&port)
+                .and_then(|backlog| backlog.pop_front());
+            if let Some(key) = key {
+                let socket = Arc::new(Self::with_conn(Some(key)));
+                let peer = VsockEndpoint {
+                    cid: key.1 as u32,
+                    port: key.2,
+                };
+                return Ok((socket, Endpoint::Vsock(peer)));
+            }
+            if non_block {
+                return Err(LxError::EAGAIN);
+            }
+        }
+    }
+
+    fn endpoint(&self) -> Option<Endpoint> {
+        let inner = self.inner.lock();
+        let port = inner.conn.map(|key| key.0).or(inner.bound_port)?;
+        Some(Endpoint::Vsock(VsockEndpoint {
+            cid: Self::local_cid(),
+            port,
+        }))
+    }
+
+    fn remote_endpoint(&self) -> Option<Endpoint> {
+        let key = self.inner.lock().conn?;
+        Some(Endpoint::Vsock(VsockEndpoint {
+            cid: key.1 as u32,
+            port: key.2,
+        }))
+    }
+
+    fn get_buffer_capacity(&self) -> Option<(usize, usize)> {
+        Some((VSOCK_BUF_ALLOC as usize, VSOCK_BUF_ALLOC as usize))
+    }
+
+    fn socket_type(&self) -> Option<SocketType> {
+        Some(SocketType::SOCK_STREAM)
+    }
+}
+
+impl_kobject!(VsockSocketState);
+
+#[async_trait]
+impl FileLike for VsockSocketState {
+    fn flags(&self) -> OpenFlags {
+        self.inner.lock().flags
+    }
+
+    fn set_flags(&self, f: OpenFlags) -> LxResult {
+        let flags = &mut self.inner.lock().flags;
+
+        // See fcntl, only O_APPEND, O_ASYNC, O_DIRECT, O_NOATIME, O_NONBLOCK
+        flags.set(OpenFlags::APPEND, f.contains(OpenFlags::APPEND));
+        flags.set(OpenFlags::NON_BLOCK, f.contains(OpenFlags::NON_BLOCK));
+        flags.set(OpenFlags::CLOEXEC, f.contains(OpenFlags::CLOEXEC));
+        Ok(())
+    }
+
+    async fn read(&self, buf: &mut [u8]) -> LxResult<usize> {
+        Socket::read(self, buf).await.0
+    }
+
+    async fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> LxResult<usize> {
+        Err(LxError::ESPIPE)
+    }
+
+    fn write(&self, buf: &[u8]) -> LxResult<usize> {
+        Socket::write(self, buf, None)
+    }
+
+    fn poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    async fn async_poll(&self, events: PollEvents) -> LxResult<PollStatus> {
+        let (read, write, error) = Socket::poll(self, events);
+        Ok(PollStatus { read, write, error })
+    }
+
+    fn ioctl(&self, request: usize, arg1: usize, arg2: usize, arg3: usize) -> LxResult<usize> {
+        Socket::ioctl(self, request, arg1, arg2, arg3)
+    }
+
+    fn as_socket(&self) -> LxResult<&dyn Socket> {
+        Ok(self)
+    }
+}
@@ -17,7 +17,7 @@ use core::sync::atomic::AtomicI32;
 use hashbrown::HashMap;
 use kernel_hal::VirtAddr;
 use lock::{Mutex, MutexGuard};
-use rcore_fs::vfs::{FileSystem, INode};
+use rcore_fs::vfs::{FileSystem, FileType, INode, Metadata};
 
 use zircon_object::{
     object::{KernelObject, KoID, Signal},
@@ -62,6 +62,7 @@ impl ProcessExt for Process {
                 current_working_directory: linux_parent_inner.current_working_directory.clone(),
                 files: linux_parent_inner.files.clone(),
                 signal_actions: linux_parent_inner.signal_actions.clone(),
+                credentials: linux_parent_inner.credentials,
                 ..Default::default()
             }),
         };
@@ -169,6 +170,8 @@ struct LinuxProcessInner {
     children: HashMap<KoID, Arc<Process>>,
     /// Signal actions
     signal_actions: SignalActions,
+    /// User and group IDs
+    credentials: Credentials,
 }
 
 #[derive(Clone)]
@@ -203,6 +206,49 @@ impl Default for RLimit {
     }
 }
 
+/// User and group IDs of a process, all root by default.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Credentials {
+    /// real user ID
+    pub uid: u32,
+    /// real group ID
+    pub gid: u32,
+    /// effective user ID, used for permission checks
+    pub euid: u32,
+    /// effective group ID, used for permission checks
+    pub egid: u32,
+}
+
+impl Credentials {
+    /// Whether the process is privileged. There are no capabilities, so a
+    /// process with the effective user ID of root has all of them.
+    pub fn is_privileged(&self) -> bool {
+        self.euid == 0
+    }
+
+    /// Whether the process can access a file with `metadata`, where `access`
+    /// is a mask of read (4), write (2) and execute (1) permissions.
+    ///
+    /// The owner, group or other bits of the mode are checked, depending on the
+    /// effective IDs. A privileged process can read and write any file, and
+    /// execute it if it's a directory or anyone can execute it.
+    pub fn can_access(&self, metadata: &Metadata, access: u16) -> bool {
+        if self.is_privileged() {
+            return access & 1 == 0
+                || metadata.type_ == FileType::Dir
+                || metadata.mode & 0o111 != 0;
+        }
+        let bits = if metadata.uid == self.euid as usize {
+            metadata.mode >> 6
+        } else if metadata.gid == self.egid as usize {
+            metadata.mode >> 3
+        } else {
+            metadata.mode
+        };
+        bits & access & 0o7 == access & 0o7
+    }
+}
+
 /// The type of process exit code.
 pub type ExitCode = i32;
 
@@ -395,6 +441,16 @@ impl LinuxProcess {
         self.inner.lock().execute_path = String::from(path);
     }
 
+    /// Get the user and group IDs.
+    pub fn credentials(&self) -> Credentials {
+        self.inner.lock().credentials
+    }
+
+    /// Set the user and group IDs.
+    pub fn set_credentials(&self, credentials: Credentials) {
+        self.inner.lock().credentials = credentials;
+    }
+
     /// Get signal action.
     pub fn signal_action(&self, signal: LinuxSignal) -> SignalAction {
         self.inner.lock().signal_actions.table[signal as u8 as usize]
@@ -29,6 +29,10 @@ impl TimeVal {
     pub fn now() -> TimeVal {
         TimeSpec::now().into()
     }
+    /// create TimeVal of the monotonic clock
+    pub fn monotonic() -> TimeVal {
+        TimeSpec::monotonic().into()
+    }
     /// to msec
     pub fn to_msec(&self) -> usize {
         self.sec * 1_000 + self.usec / 1_000
@@ -36,13 +40,15 @@ impl TimeVal {
 }
 
 impl TimeSpec {
-    /// create TimeSpec
+    /// create TimeSpec of the wall-clock time
     pub fn now() -> TimeSpec {
-        let time = kernel_hal::timer::timer_now();
-        TimeSpec {
-            sec: time.as_secs() as usize,
-            nsec: (time.as_nanos() % 1_000_000_000) as usize,
-        }
+        kernel_hal::timer::timer_realtime().into()
+    }
+
+    /// create TimeSpec of the monotonic clock, which starts at boot and is
+    /// not affected by setting the wall-clock time
+    pub fn monotonic() -> TimeSpec {
+        kernel_hal::timer::timer_now().into()
     }
 
     /// update TimeSpec for a file inode
@@ -82,12 +88,27 @@ impl From<TimeSpec> for Timespec {
     }
 }
 
+impl From<Duration> for TimeSpec {
+    fn from(d: Duration) -> Self {
+        Self {
+            sec: d.as_secs() as _,
+            nsec: d.subsec_nanos() as _,
+        }
+    }
+}
+
 impl From<TimeSpec> for Duration {
     fn from(t: TimeSpec) -> Self {
         Self::new(t.sec as _, t.nsec as _)
     }
 }
 
+impl From<TimeVal> for Duration {
+    fn from(t: TimeVal) -> Self {
+        Self::new(t.sec as _, t.usec as u32 * 1_000)
+    }
+}
+
 impl From<TimeSpec> for TimeVal {
     fn from(t: TimeSpec) -> Self {
         Self {
@@ -13,8 +13,11 @@ use zircon_object::ebpf::program::MapFdEntry;
 use zircon_object::ebpf::{
     consts::BpfCommand,
     program::ProgramLoadExAttr,
+    tracepoints::KprobeAttachAttr,
     osutil::*,
+    BPF_OBJECTS,
 };
+use linux_object::net::netfilter::{self, Hook};
 use zircon_object::vm::{
     VmObject
 };
@@ -39,6 +42,9 @@ impl Syscall<'_> {
     pub fn sys_bpf(&self, cmd: i32, bpf_attr: usize , size: usize) -> SysResult {
         warn!("SYS_bpf cmd: {}, bpf_attr: {}, size: {}", cmd, bpf_attr, size);
         let ptr = bpf_attr as *const u8;
+        if let Some(ret) = self.sys_bpf_netfilter(cmd, ptr) {
+            return ret;
+        }
         if let Ok(bpf_cmd) = BpfCommand::try_from(cmd) {
             use BpfCommand::*;
             let ret = match bpf_cmd {
@@ -62,6 +68,38 @@ impl Syscall<'_> {
         }
     }
 
+    /// Attach or detach a program to a netfilter hook, if the target of
+    /// `BPF_PROG_ATTACH` or `BPF_PROG_DETACH` is `netfilter$<hook>`, e.g.
+    /// `netfilter$input`.
+    fn sys_bpf_netfilter(&self, cmd: i32, attr_ptr: *const u8) -> Option<SysResult> {
+        let attach = match BpfCommand::try_from(cmd) {
+            Ok(BpfCommand::BPF_PROG_ATTACH) => true,
+            Ok(BpfCommand::BPF_PROG_DETACH) => false,
+            _ => return None,
+        };
+        let attr = UserInPtr::<KprobeAttachAttr>::from(attr_ptr as usize).read().ok()?;
+        let target = UserInPtr::<u8>::from(attr.target as usize)
+            .as_str(attr.str_len as usize)
+            .ok()?;
+        let hook = match target.split_once('$') {
+            Some((kind, hook)) if kind == "netfilter" => Hook::parse(hook),
+            _ => return None,
+        };
+        let ret = hook.and_then(|hook| {
+            let prog = BPF_OBJECTS
+                .lock()
+                .get(&attr.prog_fd)
+                .and_then(|obj| obj.is_program().cloned())
+                .ok_or(LxError::EBADF)?;
+            if attach {
+                netfilter::attach_program(hook, prog)
+            } else {
+                netfilter::detach_program(hook, &prog)
+            }
+        });
+        Some(ret.map(|_| 0))
+    }
+
     #[allow(unused_mut)]
     fn sys_temp_bpf_program_load_ex(&self, attr_ptr: *const u8, size: usize) -> i32 {
         trace!("load program ex");
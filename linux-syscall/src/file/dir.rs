@@ -1,4 +1,4 @@
-﻿//! Directory operations
+//! Directory operations
 //!
 //! - getcwd
 //! - chdir
@@ -69,7 +69,13 @@ impl Syscall<'_> {
         if inode.find(file_name).is_ok() {
             return Err(LxError::EEXIST);
         }
-        inode.create(file_name, FileType::Dir, mode as u32)?;
+        let dir_inode = inode.create(file_name, FileType::Dir, mode as u32)?;
+        fsnotify::notify_child(
+            inode.as_ref(),
+            file_name,
+            dir_inode.as_ref(),
+            InotifyMask::CREATE,
+        );
         Ok(0)
     }
     /// Remove a directory.
@@ -86,6 +92,8 @@ impl Syscall<'_> {
             return Err(LxError::ENOTDIR);
         }
         dir_inode.unlink(file_name)?;
+        linux_object::fs::xattr::forget(file_inode.as_ref());
+        fsnotify::notify_unlink(dir_inode.as_ref(), file_name, file_inode.as_ref(), true);
         Ok(0)
     }
 
@@ -185,10 +193,24 @@ impl Syscall<'_> {
         let (dir_path, file_name) = split_path(path);
         let dir_inode = proc.lookup_inode_at(dirfd, dir_path, true)?;
         let file_inode = dir_inode.find(file_name)?;
-        if file_inode.metadata()?.type_ == FileType::Dir {
+        let metadata = file_inode.metadata()?;
+        if metadata.type_ == FileType::Dir {
             return Err(LxError::EISDIR);
         }
         dir_inode.unlink(file_name)?;
+        let last_link = metadata.nlinks <= 1;
+        if last_link {
+            linux_object::fs::xattr::forget(file_inode.as_ref());
+            if let Err(e) = page_cache().forget(file_inode.as_ref()) {
+                warn!("unlinkat: failed to write back {:?}: {:?}", file_name, e);
+            }
+        }
+        fsnotify::notify_unlink(
+            dir_inode.as_ref(),
+            file_name,
+            file_inode.as_ref(),
+            last_link,
+        );
         Ok(0)
     }
 
@@ -217,7 +239,35 @@ impl Syscall<'_> {
         let (new_dir_path, new_file_name) = split_path(newpath);
         let old_dir_inode = proc.lookup_inode_at(olddirfd, old_dir_path, false)?;
         let new_dir_inode = proc.lookup_inode_at(newdirfd, new_dir_path, false)?;
+        let inode = old_dir_inode.find(old_file_name)?;
+        // the file replaced by the rename, if it's not linked elsewhere
+        let replaced = new_dir_inode.find(new_file_name).ok().filter(|replaced| {
+            let metadata = (replaced.metadata(), inode.metadata());
+            match metadata {
+                (Ok(replaced), Ok(renamed)) => {
+                    replaced.inode != renamed.inode
+                        && (replaced.nlinks <= 1 || replaced.type_ == FileType::Dir)
+                }
+                _ => false,
+            }
+        });
         old_dir_inode.move_(old_file_name, &new_dir_inode, new_file_name)?;
+        if let Some(replaced) = replaced {
+            linux_object::fs::xattr::forget(replaced.as_ref());
+            if let Err(e) = page_cache().forget(replaced.as_ref()) {
+                warn!(
+                    "renameat: failed to write back {:?}: {:?}",
+                    new_file_name, e
+                );
+            }
+        }
+        fsnotify::notify_rename(
+            old_dir_inode.as_ref(),
+            old_file_name,
+            new_dir_inode.as_ref(),
+            new_file_name,
+            inode.as_ref(),
+        );
         Ok(0)
     }
 
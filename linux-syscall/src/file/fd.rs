@@ -37,24 +37,39 @@ impl Syscall<'_> {
             dir_fd, path, flags, mode
         );
 
+        let (dir_path, file_name) = split_path(path);
         let inode = if flags.contains(OpenFlags::CREATE) {
-            let (dir_path, file_name) = split_path(path);
             // relative to cwd
             let dir_inode = proc.lookup_inode_at(dir_fd, dir_path, true)?;
-            match dir_inode.find(file_name) {
+            let (file_inode, created) = match dir_inode.find(file_name) {
                 Ok(file_inode) => {
                     if flags.contains(OpenFlags::EXCLUSIVE) {
                         return Err(LxError::EEXIST);
                     }
-                    file_inode
-                }
-                Err(FsError::EntryNotFound) => {
-                    dir_inode.create(file_name, FileType::File, mode as u32)?
+                    (file_inode, false)
                 }
+                Err(FsError::EntryNotFound) => (
+                    dir_inode.create(file_name, FileType::File, mode as u32)?,
+                    true,
+                ),
                 Err(e) => return Err(LxError::from(e)),
-            }
+            };
+            fsnotify::notify_open(dir_inode.as_ref(), file_name, file_inode.as_ref(), created);
+            file_inode
         } else {
-            proc.lookup_inode_at(dir_fd, path, true)?
+            let file_inode = proc.lookup_inode_at(dir_fd, path, true)?;
+            if fsnotify::has_watches() {
+                match proc.lookup_inode_at(dir_fd, dir_path, true) {
+                    Ok(dir_inode) => fsnotify::notify_open(
+                        dir_inode.as_ref(),
+                        file_name,
+                        file_inode.as_ref(),
+                        false,
+                    ),
+                    Err(_) => fsnotify::notify(file_inode.as_ref(), InotifyMask::OPEN),
+                }
+            }
+            file_inode
         };
         let file = File::new(inode, flags, path.into());
         let fd = proc.add_file(file)?;
@@ -66,7 +81,17 @@ impl Syscall<'_> {
         warn!("close: fd={:?}", fd);
         info!("close: fd={:?}", fd);
         let proc = self.linux_process();
+        let file_like = proc.get_file_like(fd)?;
         proc.close_file(fd)?;
+        // report the closing of the last reference to the open file
+        if Arc::strong_count(&file_like) == 1 {
+            let mask = if file_like.flags().writable() {
+                InotifyMask::CLOSE_WRITE
+            } else {
+                InotifyMask::CLOSE_NOWRITE
+            };
+            fsnotify::notify_file(file_like.as_ref(), mask);
+        }
         warn!("close OK");
         Ok(0)
     }
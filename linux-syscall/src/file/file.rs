@@ -35,9 +35,12 @@ impl Syscall<'_> {
     /// - len – number of bytes to write
     pub fn sys_write(&self, fd: FileDesc, base: UserInPtr<u8>, len: usize) -> SysResult {
         info!("write: fd={:?}, base={:?}, len={:#x}", fd, base, len);
-        self.linux_process()
-            .get_file_like(fd)?
-            .write(base.as_slice(len)?)
+        let file_like = self.linux_process().get_file_like(fd)?;
+        let len = file_like.write(base.as_slice(len)?)?;
+        if len > 0 {
+            fsnotify::notify_file(file_like.as_ref(), InotifyMask::MODIFY);
+        }
+        Ok(len)
     }
 
     /// read from or write to a file descriptor at a given offset
@@ -75,9 +78,12 @@ impl Syscall<'_> {
             "pwrite: fd={:?}, base={:?}, len={}, offset={}",
             fd, base, len, offset
         );
-        self.linux_process()
-            .get_file_like(fd)?
-            .write_at(offset, base.as_slice(len)?)
+        let file_like = self.linux_process().get_file_like(fd)?;
+        let len = file_like.write_at(offset, base.as_slice(len)?)?;
+        if len > 0 {
+            fsnotify::notify_file(file_like.as_ref(), InotifyMask::MODIFY);
+        }
+        Ok(len)
     }
 
     /// works just like read except that multiple buffers are filled.
@@ -117,6 +123,9 @@ impl Syscall<'_> {
         let proc = self.linux_process();
         let file_like = proc.get_file_like(fd)?;
         let len = file_like.write(&buf)?;
+        if len > 0 {
+            fsnotify::notify_file(file_like.as_ref(), InotifyMask::MODIFY);
+        }
         Ok(len)
     }
 
@@ -145,7 +154,12 @@ impl Syscall<'_> {
     pub fn sys_truncate(&self, path: UserInPtr<u8>, len: usize) -> SysResult {
         let path = path.as_c_str()?;
         info!("truncate: path={:?}, len={}", path, len);
-        self.linux_process().lookup_inode(path)?.resize(len)?;
+        let inode = self.linux_process().lookup_inode(path)?;
+        // drop cached pages beyond `len`, or they would be written back later
+        if inode.metadata()?.type_ == FileType::File {
+            page_cache().truncate(&inode, len)?;
+        }
+        inode.resize(len)?;
         Ok(0)
     }
 
@@ -184,67 +198,58 @@ impl Syscall<'_> {
             in_fd, out_fd, in_offset, out_offset, count, flags
         );
         let proc = self.linux_process();
-        let in_file = proc.get_file(in_fd)?;
-        let out_file = proc.get_file(out_fd)?;
-        let mut buffer = [0u8; 1024];
+        let out_file = proc.get_file_like(out_fd)?;
+        let src = Endpoint::new(proc.get_file_like(in_fd)?);
+        let dst = Endpoint::new(out_file.clone());
 
         // for in_offset and out_offset
-        // null means update file offset
-        // non-null means update {in,out}_offset instead
-
-        let mut read_offset = if !in_offset.is_null() {
-            in_offset.read()?
+        // null means use and update the file offset
+        // non-null means use and update {in,out}_offset instead
+        let mut read_offset = if in_offset.is_null() {
+            None
         } else {
-            in_file.seek(SeekFrom::Current(0))?
+            Some(in_offset.read()?)
         };
-
-        let orig_out_file_offset = out_file.seek(SeekFrom::Current(0))?;
-        let write_offset = if !out_offset.is_null() {
-            let offset = out_offset.read()?;
-            out_file.seek(SeekFrom::Start(offset))?
+        let mut write_offset = if out_offset.is_null() {
+            None
         } else {
-            0
+            Some(out_offset.read()?)
         };
 
-        // read from specified offset and write new offset back
-        let mut bytes_read = 0;
-        let mut total_written = 0;
-        while bytes_read < count {
-            let len = buffer.len().min(count - bytes_read);
-            let read_len = in_file.read_at(read_offset, &mut buffer[..len]).await?;
-            if read_len == 0 {
-                break;
-            }
-            bytes_read += read_len;
-            read_offset += read_len as u64;
-
-            let mut bytes_written = 0;
-            let mut rlen = read_len;
-            while bytes_written < read_len {
-                let write_len = out_file.write(&buffer[bytes_written..(bytes_written + rlen)])?;
-                if write_len == 0 {
-                    info!(
-                        "copy_file_range:END_ERR in={:?}, out={:?}, in_offset={:?}, out_offset={:?}, count={} = bytes_read {}, bytes_written {}, write_len {}",
-                        in_fd, out_fd, in_offset, out_offset, count, bytes_read, bytes_written, write_len
-                    );
-                    return Err(LxError::EBADF);
-                }
-                bytes_written += write_len;
-                rlen -= write_len;
-            }
-            total_written += bytes_written;
+        // pages are shared between the files through the page cache if possible
+        let mut total = 0;
+        while total < count {
+            // only block before the first chunk, return the short count once a pipe is drained
+            let nonblock = total != 0;
+            let res = transfer(
+                &src,
+                read_offset,
+                &dst,
+                write_offset,
+                count - total,
+                nonblock,
+            );
+            let len = match res.await {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(LxError::EAGAIN) if nonblock => break,
+                Err(e) => return Err(e),
+            };
+            total += len;
+            read_offset = read_offset.map(|offset| offset + len as u64);
+            write_offset = write_offset.map(|offset| offset + len as u64);
         }
 
-        if !in_offset.is_null() {
-            in_offset.write(read_offset)?;
-        } else {
-            in_file.seek(SeekFrom::Current(bytes_read as i64))?;
+        if let Some(offset) = read_offset {
+            in_offset.write(offset)?;
+        }
+        if let Some(offset) = write_offset {
+            out_offset.write(offset)?;
         }
-        out_offset.write_if_not_null(write_offset + total_written as u64)?;
-        if !out_offset.is_null() {
-            out_file.seek(SeekFrom::Start(orig_out_file_offset))?;
+        if total != 0 {
+            fsnotify::notify_file(out_file.as_ref(), InotifyMask::MODIFY);
         }
-        Ok(total_written)
+        Ok(total)
     }
 
     /// causes all buffered modifications to file metadata and data to be written to the underlying file systems.
@@ -0,0 +1,49 @@
+//! Monitoring file system events
+//!
+//! - inotify_init1
+//! - inotify_add_watch
+//! - inotify_rm_watch
+
+use super::*;
+
+impl Syscall<'_> {
+    /// Initializes a new inotify instance and returns a file descriptor associated with a new inotify event queue.
+    pub fn sys_inotify_init1(&self, flags: usize) -> SysResult {
+        info!("inotify_init1: flags={:#x}", flags);
+        let flags = OpenFlags::from_bits(flags).ok_or(LxError::EINVAL)?;
+        if !(OpenFlags::NON_BLOCK | OpenFlags::CLOEXEC).contains(flags) {
+            return Err(LxError::EINVAL);
+        }
+        let inotify = Inotify::new(flags | OpenFlags::RDONLY);
+        let fd = self.linux_process().add_file(inotify)?;
+        Ok(fd.into())
+    }
+
+    /// Adds a new watch, or modifies an existing watch, for the file whose location is specified in `path`.
+    pub fn sys_inotify_add_watch(&self, fd: FileDesc, path: UserInPtr<u8>, mask: u32) -> SysResult {
+        let path = path.as_c_str()?;
+        info!(
+            "inotify_add_watch: fd={:?}, path={:?}, mask={:#x}",
+            fd, path, mask
+        );
+        let mask = InotifyMask::from_bits_truncate(mask);
+        if mask.contains(InotifyMask::MASK_ADD | InotifyMask::MASK_CREATE) {
+            return Err(LxError::EINVAL);
+        }
+        let proc = self.linux_process();
+        let inotify = Inotify::from_file_like(proc.get_file_like(fd)?)?;
+        let follow = !mask.contains(InotifyMask::DONT_FOLLOW);
+        let inode = proc.lookup_inode_at(FileDesc::CWD, path, follow)?;
+        let wd = inotify.add_watch(&inode, mask)?;
+        Ok(wd as usize)
+    }
+
+    /// Removes the watch associated with the watch descriptor `wd` from the inotify instance `fd`.
+    pub fn sys_inotify_rm_watch(&self, fd: FileDesc, wd: i32) -> SysResult {
+        info!("inotify_rm_watch: fd={:?}, wd={}", fd, wd);
+        let proc = self.linux_process();
+        let inotify = Inotify::from_file_like(proc.get_file_like(fd)?)?;
+        inotify.rm_watch(wd)?;
+        Ok(0)
+    }
+}
@@ -3,13 +3,18 @@
 use super::*;
 use bitflags::bitflags;
 use linux_object::fs::vfs::{FileType, FsError};
+use linux_object::fs::inotify as fsnotify;
 use linux_object::fs::*;
 
 mod dir;
 mod fd;
 #[allow(clippy::module_inception)]
 mod file;
+mod inotify;
 mod poll;
+mod splice;
 mod stat;
+mod xattr;
 
 use self::dir::AtFlags;
+use self::splice::{transfer, Endpoint};
@@ -29,7 +29,7 @@ impl Syscall<'_> {
             polls, nfds, timeout_msecs
         );
 
-        let begin_time_ms = TimeVal::now().to_msec();
+        let begin_time_ms = TimeVal::monotonic().to_msec();
         #[must_use = "future does nothing unless polled/`await`-ed"]
         struct PollFuture<'a> {
             polls: &'a mut Vec<PollFd>,
@@ -99,7 +99,7 @@ impl Syscall<'_> {
                     // no timeout, return now;
                     0 => return Poll::Ready(Ok(0)),
                     1.. => {
-                        let current_time_ms = TimeVal::now().to_msec();
+                        let current_time_ms = TimeVal::monotonic().to_msec();
                         let deadline = self.begin_time_ms + self.timeout_msecs as usize;
                         if current_time_ms >= deadline {
                             return Poll::Ready(Ok(0));
@@ -114,7 +114,7 @@ impl Syscall<'_> {
                     -1 => {
                         // When the timeout = -1, the poll blocks indefinitely.
                         // Fixme. So Check this Future regularly every 500ms
-                        let current_time_ms = TimeVal::now().to_msec();
+                        let current_time_ms = TimeVal::monotonic().to_msec();
                         let deadline = current_time_ms + 500;
                         let waker = cx.waker().clone();
                         timer::timer_set(
@@ -206,7 +206,7 @@ impl Syscall<'_> {
             // infinity
             -1
         };
-        let begin_time_ms = TimeVal::now().to_msec();
+        let begin_time_ms = TimeVal::monotonic().to_msec();
 
         #[must_use = "future does nothing unless polled/`await`-ed"]
         struct SelectFuture<'a> {
@@ -261,7 +261,7 @@ impl Syscall<'_> {
                     // no timeout, return now;
                     0 => return Poll::Ready(Ok(0)),
                     1.. => {
-                        let current_time_ms = TimeVal::now().to_msec();
+                        let current_time_ms = TimeVal::monotonic().to_msec();
                         let deadline = self.begin_time_ms + self.timeout_msecs as usize;
                         if current_time_ms >= deadline {
                             return Poll::Ready(Ok(0));
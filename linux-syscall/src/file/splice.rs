@@ -0,0 +1,361 @@
+//! Data transfer through pipes
+//!
+//! - splice
+//! - tee
+//! - vmsplice
+//!
+//! Data is moved as [`PipeBuffer`]s, i.e. references to pages of pipes or the
+//! page cache, and is only copied from or to other kinds of files. `vmsplice`
+//! copies user memory, it doesn't map user pages into the pipe.
+
+use super::*;
+use alloc::vec::Vec;
+use linux_object::fs::vfs::INode;
+use zircon_object::vm::PAGE_SIZE;
+
+bitflags! {
+    struct SpliceFlags: usize {
+        /// Move pages instead of copying (a hint)
+        const MOVE = 1;
+        /// Do not block on I/O
+        const NONBLOCK = 2;
+        /// More data will be coming in a subsequent splice (a hint)
+        const MORE = 4;
+        /// The user pages are a gift to the kernel (a hint)
+        const GIFT = 8;
+    }
+}
+
+/// Maximum number of bytes transferred at a time, the default pipe capacity of Linux.
+const MAX_TRANSFER: usize = 16 * PAGE_SIZE;
+
+/// An endpoint of a data transfer.
+pub(super) enum Endpoint {
+    /// A pipe, whose inode is kept for waiting on it.
+    Pipe(Arc<dyn INode>),
+    /// A file whose content goes through the page cache.
+    Cached(Arc<File>),
+    /// Other files, e.g. sockets and devices, whose data are copied.
+    Other(Arc<dyn FileLike>),
+}
+
+impl Endpoint {
+    pub(super) fn new(file: Arc<dyn FileLike>) -> Self {
+        if let Ok(file) = file.clone().downcast_arc::<File>() {
+            let inode = file.inode();
+            if inode.downcast_ref::<Pipe>().is_some() {
+                return Endpoint::Pipe(inode);
+            }
+            if file.is_cached() {
+                return Endpoint::Cached(file);
+            }
+        }
+        Endpoint::Other(file)
+    }
+
+    fn pipe(&self) -> Option<&Pipe> {
+        match self {
+            Endpoint::Pipe(inode) => inode.downcast_ref::<Pipe>(),
+            _ => None,
+        }
+    }
+
+    /// Run `f` on the pipe, waiting for data while it returns `EAGAIN` unless `nonblock`.
+    async fn with_pipe_data<T>(
+        &self,
+        nonblock: bool,
+        mut f: impl FnMut(&Pipe) -> LxResult<T>,
+    ) -> LxResult<T> {
+        let (inode, pipe) = match self {
+            Endpoint::Pipe(inode) => (inode, self.pipe().unwrap()),
+            _ => return Err(LxError::EINVAL),
+        };
+        loop {
+            match f(pipe) {
+                Err(LxError::EAGAIN) if !nonblock => {
+                    inode.async_poll().await?;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Read up to `len` bytes as pipe buffers.
+    ///
+    /// Data of a pipe is only peeked, use [`Pipe::read_buffers_with`] to consume it.
+    async fn read_buffers(
+        &self,
+        offset: Option<u64>,
+        len: usize,
+        nonblock: bool,
+    ) -> LxResult<Vec<PipeBuffer>> {
+        match self {
+            Endpoint::Pipe(_) => {
+                self.with_pipe_data(nonblock, |pipe| Ok(pipe.peek_buffers(len)?))
+                    .await
+            }
+            Endpoint::Cached(file) => file.read_pages(offset, len),
+            Endpoint::Other(file) => {
+                let mut buf = PipeBuffer::alloc();
+                let dst = &mut buf.as_mut_slice()?[..len.min(PAGE_SIZE)];
+                let n = match offset {
+                    Some(offset) => file.read_at(offset, dst).await?,
+                    None => file.read(dst).await?,
+                };
+                buf.set_len(n);
+                Ok(if n == 0 { Vec::new() } else { vec![buf] })
+            }
+        }
+    }
+
+    /// Write pipe buffers, returns the number of bytes written.
+    fn write_buffers(&self, offset: Option<u64>, bufs: &[PipeBuffer]) -> LxResult<usize> {
+        match self {
+            Endpoint::Pipe(_) => Ok(self.pipe().unwrap().push_buffers(bufs.to_vec())?),
+            Endpoint::Cached(file) => file.write_pages(offset, bufs),
+            Endpoint::Other(file) => {
+                let mut total = 0;
+                for buf in bufs {
+                    let mut data = buf.as_slice()?;
+                    while !data.is_empty() {
+                        let res = match offset {
+                            Some(offset) => file.write_at(offset + total as u64, data),
+                            None => file.write(data),
+                        };
+                        let n = match res {
+                            Ok(0) => return Ok(total),
+                            Ok(n) => n,
+                            // the bytes written are consumed from the source
+                            Err(_) if total != 0 => return Ok(total),
+                            Err(e) => return Err(e),
+                        };
+                        total += n;
+                        data = &data[n..];
+                    }
+                }
+                Ok(total)
+            }
+        }
+    }
+
+    /// Write all the pipe buffers, waiting for the destination to take them,
+    /// returns the number of bytes written.
+    ///
+    /// The count is short only if writing fails after some bytes are written.
+    async fn write_all_buffers(&self, offset: Option<u64>, bufs: &[PipeBuffer]) -> LxResult<usize> {
+        let total: usize = bufs.iter().map(PipeBuffer::len).sum();
+        let mut written = 0;
+        while written < total {
+            let offset = offset.map(|offset| offset + written as u64);
+            match self.write_buffers(offset, &skip_buffers(bufs, written)) {
+                Ok(0) => break,
+                Ok(n) => written += n,
+                Err(LxError::EAGAIN) => match self {
+                    Endpoint::Other(file) => {
+                        file.async_poll(PollEvents::OUT).await?;
+                    }
+                    _ => return Err(LxError::EAGAIN),
+                },
+                Err(_) if written != 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// The pipe buffers without their first `n` bytes.
+fn skip_buffers(bufs: &[PipeBuffer], mut n: usize) -> Vec<PipeBuffer> {
+    let mut rest = Vec::new();
+    for buf in bufs {
+        if n >= buf.len() {
+            n -= buf.len();
+        } else {
+            rest.push(PipeBuffer::new(
+                buf.page().clone(),
+                buf.offset() + n,
+                buf.len() - n,
+            ));
+            n = 0;
+        }
+    }
+    rest
+}
+
+/// Transfer up to `len` bytes from `src` to `dst`, returns the number of bytes transferred.
+///
+/// `None` offsets mean the file offsets, which are advanced.
+pub(super) async fn transfer(
+    src: &Endpoint,
+    src_offset: Option<u64>,
+    dst: &Endpoint,
+    dst_offset: Option<u64>,
+    len: usize,
+    nonblock: bool,
+) -> LxResult<usize> {
+    let len = len.min(MAX_TRANSFER);
+    if src.pipe().is_some() {
+        if dst.pipe().is_some() {
+            // pushing to a pipe never fails halfway, and this avoids locking two pipes
+            return src
+                .with_pipe_data(nonblock, |pipe| {
+                    let bufs = pipe.take_buffers(len)?;
+                    dst.write_buffers(None, &bufs)
+                })
+                .await;
+        }
+        // keep the pipe locked until the written bytes are consumed
+        return src
+            .with_pipe_data(nonblock, |pipe| {
+                pipe.read_buffers_with(len, |bufs| dst.write_buffers(dst_offset, bufs))
+            })
+            .await;
+    }
+    // read cached files at an explicit offset, and only advance it by the bytes written
+    let src_pos = match (src, src_offset) {
+        (Endpoint::Cached(file), None) => Some(file.seek(SeekFrom::Current(0))?),
+        _ => src_offset,
+    };
+    let bufs = src.read_buffers(src_pos, len, nonblock).await?;
+    let written = match (src, src_pos) {
+        // data read from a stream can't be put back, so all of it is written
+        (Endpoint::Other(_), None) => dst.write_all_buffers(dst_offset, &bufs).await?,
+        _ => dst.write_buffers(dst_offset, &bufs)?,
+    };
+    if let (Endpoint::Cached(file), None) = (src, src_offset) {
+        file.seek(SeekFrom::Current(written as i64))?;
+    }
+    Ok(written)
+}
+
+impl Syscall<'_> {
+    /// Moves data between two file descriptors without copying between kernel
+    /// address space and user address space, where one of them must be a pipe.
+    pub async fn sys_splice(
+        &self,
+        fd_in: FileDesc,
+        mut off_in: UserInOutPtr<u64>,
+        fd_out: FileDesc,
+        mut off_out: UserInOutPtr<u64>,
+        len: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "splice: fd_in={:?}, off_in={:?}, fd_out={:?}, off_out={:?}, len={}, flags={:#x}",
+            fd_in, off_in, fd_out, off_out, len, flags
+        );
+        let flags = SpliceFlags::from_bits_truncate(flags);
+        let proc = self.linux_process();
+        let in_file = proc.get_file_like(fd_in)?;
+        let out_file = proc.get_file_like(fd_out)?;
+        let src = Endpoint::new(in_file.clone());
+        let dst = Endpoint::new(out_file.clone());
+        match (src.pipe(), dst.pipe()) {
+            (None, None) => return Err(LxError::EINVAL),
+            (Some(pipe_in), Some(pipe_out)) if pipe_in.is_same_pipe(pipe_out) => {
+                return Err(LxError::EINVAL)
+            }
+            _ => {}
+        }
+        if (src.pipe().is_some() && !off_in.is_null())
+            || (dst.pipe().is_some() && !off_out.is_null())
+        {
+            return Err(LxError::ESPIPE);
+        }
+        let nonblock = flags.contains(SpliceFlags::NONBLOCK)
+            || (src.pipe().is_some() && in_file.flags().non_block());
+        let in_pos = if off_in.is_null() {
+            None
+        } else {
+            Some(off_in.read()?)
+        };
+        let out_pos = if off_out.is_null() {
+            None
+        } else {
+            Some(off_out.read()?)
+        };
+
+        let n = transfer(&src, in_pos, &dst, out_pos, len, nonblock).await?;
+        if let Some(pos) = in_pos {
+            off_in.write(pos + n as u64)?;
+        }
+        if let Some(pos) = out_pos {
+            off_out.write(pos + n as u64)?;
+        }
+        if n != 0 {
+            fsnotify::notify_file(out_file.as_ref(), InotifyMask::MODIFY);
+        }
+        Ok(n)
+    }
+
+    /// Duplicates up to `len` bytes of data from the pipe `fd_in` to the pipe
+    /// `fd_out`, without consuming the data of `fd_in`.
+    pub async fn sys_tee(
+        &self,
+        fd_in: FileDesc,
+        fd_out: FileDesc,
+        len: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "tee: fd_in={:?}, fd_out={:?}, len={}, flags={:#x}",
+            fd_in, fd_out, len, flags
+        );
+        let flags = SpliceFlags::from_bits_truncate(flags);
+        let proc = self.linux_process();
+        let in_file = proc.get_file_like(fd_in)?;
+        let src = Endpoint::new(in_file.clone());
+        let dst = Endpoint::new(proc.get_file_like(fd_out)?);
+        match (src.pipe(), dst.pipe()) {
+            (Some(pipe_in), Some(pipe_out)) if !pipe_in.is_same_pipe(pipe_out) => {}
+            _ => return Err(LxError::EINVAL),
+        }
+        let nonblock = flags.contains(SpliceFlags::NONBLOCK) || in_file.flags().non_block();
+        let bufs = src
+            .read_buffers(None, len.min(MAX_TRANSFER), nonblock)
+            .await?;
+        dst.write_buffers(None, &bufs)
+    }
+
+    /// Copies user memory to the pipe `fd`, or reads from the pipe into user memory.
+    pub async fn sys_vmsplice(
+        &self,
+        fd: FileDesc,
+        iov_ptr: UserInPtr<IoVecOut>,
+        nr_segs: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "vmsplice: fd={:?}, iov={:?}, nr_segs={}, flags={:#x}",
+            fd, iov_ptr, nr_segs, flags
+        );
+        let flags = SpliceFlags::from_bits_truncate(flags);
+        let mut iovs = iov_ptr.read_iovecs(nr_segs)?;
+        let file_like = self.linux_process().get_file_like(fd)?;
+        let endpoint = Endpoint::new(file_like.clone());
+        let pipe = endpoint.pipe().ok_or(LxError::EBADF)?;
+        let nonblock = flags.contains(SpliceFlags::NONBLOCK) || file_like.flags().non_block();
+        if file_like.flags().writable() {
+            // user pages are copied into the pipe, not mapped
+            let mut total = 0;
+            for iov in iovs.iter().filter(|iov| !iov.is_empty()) {
+                total += pipe.write_at(0, iov.as_slice()?)?;
+            }
+            Ok(total)
+        } else {
+            let len = iovs.total_len();
+            let n = endpoint
+                .with_pipe_data(nonblock, |pipe| {
+                    pipe.read_buffers_with(len, |bufs| {
+                        let mut data = Vec::new();
+                        for buf in bufs.iter() {
+                            data.extend_from_slice(buf.as_slice()?);
+                        }
+                        Ok(iovs.write_from_buf(&data)?)
+                    })
+                })
+                .await?;
+            Ok(n)
+        }
+    }
+}
@@ -0,0 +1,274 @@
+//! Extended attributes
+//!
+//! - (l,f)getxattr
+//! - (l,f)setxattr
+//! - (l,f)listxattr
+//! - (l,f)removexattr
+
+use super::*;
+use linux_object::fs::vfs::INode;
+use linux_object::fs::xattr::{self, XattrFlags, XattrNamespace, XATTR_SIZE_MAX};
+use linux_object::process::Credentials;
+
+impl Syscall<'_> {
+    fn xattr_inode(&self, path: UserInPtr<u8>, follow: bool) -> LxResult<Arc<dyn INode>> {
+        let path = path.as_c_str()?;
+        self.linux_process()
+            .lookup_inode_at(FileDesc::CWD, path, follow)
+    }
+
+    fn xattr_fd_inode(&self, fd: FileDesc) -> LxResult<Arc<dyn INode>> {
+        Ok(self.linux_process().get_file(fd)?.inode())
+    }
+
+    fn credentials(&self) -> Credentials {
+        self.linux_process().credentials()
+    }
+
+    /// Retrieves the value of the extended attribute `name` of the file `path`.
+    pub fn sys_getxattr(
+        &self,
+        path: UserInPtr<u8>,
+        name: UserInPtr<u8>,
+        value: UserOutPtr<u8>,
+        size: usize,
+    ) -> SysResult {
+        info!("getxattr: path={:?}, name={:?}, size={}", path, name, size);
+        getxattr(
+            &self.xattr_inode(path, true)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+        )
+    }
+
+    /// Same as [`sys_getxattr`](Self::sys_getxattr), but doesn't follow symbolic links.
+    pub fn sys_lgetxattr(
+        &self,
+        path: UserInPtr<u8>,
+        name: UserInPtr<u8>,
+        value: UserOutPtr<u8>,
+        size: usize,
+    ) -> SysResult {
+        info!("lgetxattr: path={:?}, name={:?}, size={}", path, name, size);
+        getxattr(
+            &self.xattr_inode(path, false)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+        )
+    }
+
+    /// Same as [`sys_getxattr`](Self::sys_getxattr), but of the open file `fd`.
+    pub fn sys_fgetxattr(
+        &self,
+        fd: FileDesc,
+        name: UserInPtr<u8>,
+        value: UserOutPtr<u8>,
+        size: usize,
+    ) -> SysResult {
+        info!("fgetxattr: fd={:?}, name={:?}, size={}", fd, name, size);
+        getxattr(
+            &self.xattr_fd_inode(fd)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+        )
+    }
+
+    /// Sets the value of the extended attribute `name` of the file `path`.
+    pub fn sys_setxattr(
+        &self,
+        path: UserInPtr<u8>,
+        name: UserInPtr<u8>,
+        value: UserInPtr<u8>,
+        size: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "setxattr: path={:?}, name={:?}, size={}, flags={:#x}",
+            path, name, size, flags
+        );
+        setxattr(
+            &self.xattr_inode(path, true)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+            flags,
+        )
+    }
+
+    /// Same as [`sys_setxattr`](Self::sys_setxattr), but doesn't follow symbolic links.
+    pub fn sys_lsetxattr(
+        &self,
+        path: UserInPtr<u8>,
+        name: UserInPtr<u8>,
+        value: UserInPtr<u8>,
+        size: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "lsetxattr: path={:?}, name={:?}, size={}, flags={:#x}",
+            path, name, size, flags
+        );
+        setxattr(
+            &self.xattr_inode(path, false)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+            flags,
+        )
+    }
+
+    /// Same as [`sys_setxattr`](Self::sys_setxattr), but of the open file `fd`.
+    pub fn sys_fsetxattr(
+        &self,
+        fd: FileDesc,
+        name: UserInPtr<u8>,
+        value: UserInPtr<u8>,
+        size: usize,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "fsetxattr: fd={:?}, name={:?}, size={}, flags={:#x}",
+            fd, name, size, flags
+        );
+        setxattr(
+            &self.xattr_fd_inode(fd)?,
+            &self.credentials(),
+            name,
+            value,
+            size,
+            flags,
+        )
+    }
+
+    /// Lists the names of extended attributes of the file `path`.
+    pub fn sys_listxattr(
+        &self,
+        path: UserInPtr<u8>,
+        list: UserOutPtr<u8>,
+        size: usize,
+    ) -> SysResult {
+        info!("listxattr: path={:?}, size={}", path, size);
+        listxattr(
+            &self.xattr_inode(path, true)?,
+            &self.credentials(),
+            list,
+            size,
+        )
+    }
+
+    /// Same as [`sys_listxattr`](Self::sys_listxattr), but doesn't follow symbolic links.
+    pub fn sys_llistxattr(
+        &self,
+        path: UserInPtr<u8>,
+        list: UserOutPtr<u8>,
+        size: usize,
+    ) -> SysResult {
+        info!("llistxattr: path={:?}, size={}", path, size);
+        listxattr(
+            &self.xattr_inode(path, false)?,
+            &self.credentials(),
+            list,
+            size,
+        )
+    }
+
+    /// Same as [`sys_listxattr`](Self::sys_listxattr), but of the open file `fd`.
+    pub fn sys_flistxattr(&self, fd: FileDesc, list: UserOutPtr<u8>, size: usize) -> SysResult {
+        info!("flistxattr: fd={:?}, size={}", fd, size);
+        listxattr(&self.xattr_fd_inode(fd)?, &self.credentials(), list, size)
+    }
+
+    /// Removes the extended attribute `name` of the file `path`.
+    pub fn sys_removexattr(&self, path: UserInPtr<u8>, name: UserInPtr<u8>) -> SysResult {
+        info!("removexattr: path={:?}, name={:?}", path, name);
+        removexattr(&self.xattr_inode(path, true)?, &self.credentials(), name)
+    }
+
+    /// Same as [`sys_removexattr`](Self::sys_removexattr), but doesn't follow symbolic links.
+    pub fn sys_lremovexattr(&self, path: UserInPtr<u8>, name: UserInPtr<u8>) -> SysResult {
+        info!("lremovexattr: path={:?}, name={:?}", path, name);
+        removexattr(&self.xattr_inode(path, false)?, &self.credentials(), name)
+    }
+
+    /// Same as [`sys_removexattr`](Self::sys_removexattr), but of the open file `fd`.
+    pub fn sys_fremovexattr(&self, fd: FileDesc, name: UserInPtr<u8>) -> SysResult {
+        info!("fremovexattr: fd={:?}, name={:?}", fd, name);
+        removexattr(&self.xattr_fd_inode(fd)?, &self.credentials(), name)
+    }
+}
+
+fn getxattr(
+    inode: &Arc<dyn INode>,
+    cred: &Credentials,
+    name: UserInPtr<u8>,
+    mut value: UserOutPtr<u8>,
+    size: usize,
+) -> SysResult {
+    let name = name.as_c_str()?;
+    let ns = XattrNamespace::from_name(name)?;
+    ns.check_access(&inode.metadata()?, false, cred)?;
+    let (store, ino) = xattr::store_of(inode.as_ref())?;
+    let data = store.get(ino, name)?;
+    if let Some(data) = xattr::fit_buffer(&data, size)? {
+        value.write_array(data)?;
+    }
+    Ok(data.len())
+}
+
+fn setxattr(
+    inode: &Arc<dyn INode>,
+    cred: &Credentials,
+    name: UserInPtr<u8>,
+    value: UserInPtr<u8>,
+    size: usize,
+    flags: usize,
+) -> SysResult {
+    let flags = XattrFlags::from_bits(flags).ok_or(LxError::EINVAL)?;
+    let name = name.as_c_str()?;
+    if size > XATTR_SIZE_MAX {
+        return Err(LxError::E2BIG);
+    }
+    let value = if size == 0 {
+        &[][..]
+    } else {
+        value.as_slice(size)?
+    };
+    let ns = XattrNamespace::from_name(name)?;
+    ns.check_access(&inode.metadata()?, true, cred)?;
+    let (store, ino) = xattr::store_of(inode.as_ref())?;
+    store.set(ino, name, value, flags)?;
+    fsnotify::notify(inode.as_ref(), InotifyMask::ATTRIB);
+    Ok(0)
+}
+
+fn listxattr(
+    inode: &Arc<dyn INode>,
+    cred: &Credentials,
+    mut list: UserOutPtr<u8>,
+    size: usize,
+) -> SysResult {
+    let (store, ino) = xattr::store_of(inode.as_ref())?;
+    let names = xattr::name_list(&store.list(ino), cred)?;
+    if let Some(names) = xattr::fit_buffer(&names, size)? {
+        list.write_array(names)?;
+    }
+    Ok(names.len())
+}
+
+fn removexattr(inode: &Arc<dyn INode>, cred: &Credentials, name: UserInPtr<u8>) -> SysResult {
+    let name = name.as_c_str()?;
+    let ns = XattrNamespace::from_name(name)?;
+    ns.check_access(&inode.metadata()?, true, cred)?;
+    let (store, ino) = xattr::store_of(inode.as_ref())?;
+    store.remove(ino, name)?;
+    fsnotify::notify(inode.as_ref(), InotifyMask::ATTRIB);
+    Ok(0)
+}
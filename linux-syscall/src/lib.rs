@@ -28,7 +28,7 @@ use alloc::sync::Arc;
 use core::convert::TryFrom;
 
 use kernel_hal::user::{IoVecIn, IoVecOut, UserInOutPtr, UserInPtr, UserOutPtr};
-use linux_object::error::{LxError, SysResult};
+use linux_object::error::{LxError, LxResult, SysResult};
 use linux_object::fs::FileDesc;
 use linux_object::process::{wait_child, wait_child_any, LinuxProcess, ProcessExt, RLimit};
 use zircon_object::object::{KernelObject, KoID, Signal};
@@ -116,6 +116,24 @@ impl Syscall<'_> {
             Sys::DUP3 => self.sys_dup2(a0.into(), a1.into()), // TODO: handle `flags`
             Sys::PIPE2 => self.sys_pipe2(a0.into(), a1),      // TODO: handle `flags`
             Sys::UTIMENSAT => self.sys_utimensat(a0.into(), a1.into(), a2.into(), a3),
+            Sys::SPLICE => {
+                self.sys_splice(a0.into(), a1.into(), a2.into(), a3.into(), a4, a5)
+                    .await
+            }
+            Sys::TEE => self.sys_tee(a0.into(), a1.into(), a2, a3).await,
+            Sys::VMSPLICE => self.sys_vmsplice(a0.into(), a1.into(), a2, a3).await,
+            Sys::SETXATTR => self.sys_setxattr(a0.into(), a1.into(), a2.into(), a3, a4),
+            Sys::LSETXATTR => self.sys_lsetxattr(a0.into(), a1.into(), a2.into(), a3, a4),
+            Sys::FSETXATTR => self.sys_fsetxattr(a0.into(), a1.into(), a2.into(), a3, a4),
+            Sys::GETXATTR => self.sys_getxattr(a0.into(), a1.into(), a2.into(), a3),
+            Sys::LGETXATTR => self.sys_lgetxattr(a0.into(), a1.into(), a2.into(), a3),
+            Sys::FGETXATTR => self.sys_fgetxattr(a0.into(), a1.into(), a2.into(), a3),
+            Sys::LISTXATTR => self.sys_listxattr(a0.into(), a1.into(), a2),
+            Sys::LLISTXATTR => self.sys_llistxattr(a0.into(), a1.into(), a2),
+            Sys::FLISTXATTR => self.sys_flistxattr(a0.into(), a1.into(), a2),
+            Sys::REMOVEXATTR => self.sys_removexattr(a0.into(), a1.into()),
+            Sys::LREMOVEXATTR => self.sys_lremovexattr(a0.into(), a1.into()),
+            Sys::FREMOVEXATTR => self.sys_fremovexattr(a0.into(), a1.into()),
             Sys::COPY_FILE_RANGE => {
                 self.sys_copy_file_range(a0.into(), a1.into(), a2.into(), a3.into(), a4, a5)
                     .await
@@ -127,6 +145,9 @@ impl Syscall<'_> {
                     .await
             }
             Sys::PPOLL => self.sys_ppoll(a0.into(), a1, a2.into()).await, // ignore sigmask
+            Sys::INOTIFY_INIT1 => self.sys_inotify_init1(a0),
+            Sys::INOTIFY_ADD_WATCH => self.sys_inotify_add_watch(a0.into(), a1.into(), a2 as _),
+            Sys::INOTIFY_RM_WATCH => self.sys_inotify_rm_watch(a0.into(), a1 as _),
             //            Sys::EPOLL_CREATE1 => self.sys_epoll_create1(a0),
             //            Sys::EPOLL_CTL => self.sys_epoll_ctl(a0, a1, a2, a3.into()),
             //            Sys::EPOLL_PWAIT => self.sys_epoll_pwait(a0, a1.into(), a2, a3, a4),
@@ -173,7 +194,7 @@ impl Syscall<'_> {
                 self.sys_recvfrom(a0, a1.into(), a2, a3, a4.into(), a5.into())
                     .await
             }
-            Sys::SENDMSG => self.unimplemented("sys_sendmsg(),", Ok(0)),
+            Sys::SENDMSG => self.sys_sendmsg(a0, a1.into(), a2),
             Sys::RECVMSG => self.sys_recvmsg(a0, a1.into(), a2).await,
             Sys::SHUTDOWN => self.sys_shutdown(a0, a1),
             Sys::BIND => self.sys_bind(a0, a1.into(), a2),
@@ -201,7 +222,9 @@ impl Syscall<'_> {
             Sys::CLOCK_NANOSLEEP => self.sys_clock_nanosleep(a0, a1, a2.into(), a3.into()).await,
             Sys::SETITIMER => self.unimplemented("setitimer", Ok(0)),
             Sys::GETTIMEOFDAY => self.sys_gettimeofday(a0.into(), a1.into()),
+            Sys::SETTIMEOFDAY => self.sys_settimeofday(a0.into(), a1.into()),
             Sys::CLOCK_GETTIME => self.sys_clock_gettime(a0, a1.into()),
+            Sys::CLOCK_SETTIME => self.sys_clock_settime(a0, a1.into()),
             Sys::CLOCK_GETRES => self.unimplemented("clock_getres", Ok(0)),
 
             // sem
@@ -232,11 +255,12 @@ impl Syscall<'_> {
             Sys::GETRUSAGE => self.sys_getrusage(a0, a1.into()),
             Sys::SYSINFO => self.sys_sysinfo(a0.into()),
             Sys::TIMES => self.sys_times(a0.into()),
-            Sys::GETUID => self.unimplemented("getuid", Ok(0)),
-            Sys::GETGID => self.unimplemented("getgid", Ok(0)),
-            Sys::SETUID => self.unimplemented("setuid", Ok(0)),
-            Sys::GETEUID => self.unimplemented("geteuid", Ok(0)),
-            Sys::GETEGID => self.unimplemented("getegid", Ok(0)),
+            Sys::GETUID => self.sys_getuid(),
+            Sys::GETGID => self.sys_getgid(),
+            Sys::SETUID => self.sys_setuid(a0 as _),
+            Sys::SETGID => self.sys_setgid(a0 as _),
+            Sys::GETEUID => self.sys_geteuid(),
+            Sys::GETEGID => self.sys_getegid(),
             Sys::SETPGID => self.unimplemented("setpgid", Ok(0)),
             Sys::GETPPID => self.sys_getppid(),
             Sys::SETSID => self.unimplemented("setsid", Ok(0)),
@@ -247,7 +271,7 @@ impl Syscall<'_> {
             Sys::PRCTL => self.unimplemented("prctl", Ok(0)),
             Sys::MEMBARRIER => self.unimplemented("membarrier", Ok(0)),
             Sys::PRLIMIT64 => self.sys_prlimit64(a0, a1, a2.into(), a3.into()),
-            //            Sys::REBOOT => self.sys_reboot(a0 as u32, a1 as u32, a2 as u32, a3.into()),
+            Sys::REBOOT => self.sys_reboot(a0 as u32, a1 as u32, a2 as u32),
             Sys::GETRANDOM => self.sys_getrandom(a0.into(), a1 as usize, a2 as u32),
             Sys::RT_SIGQUEUEINFO => self.unimplemented("rt_sigqueueinfo", Ok(0)),
 
@@ -305,6 +329,7 @@ impl Syscall<'_> {
                     .await
             }
             Sys::DUP2 => self.sys_dup2(a0.into(), a1.into()),
+            Sys::INOTIFY_INIT => self.sys_inotify_init1(0),
             //            Sys::ALARM => self.unimplemented("alarm", Ok(0)),
             Sys::FORK => self.sys_fork(),
             Sys::VFORK => self.sys_vfork().await,
@@ -182,6 +182,26 @@ impl Syscall<'_> {
         buf.write_array(&buffer[..len])?;
         Ok(len)
     }
+
+    /// restart or power off the machine, `cmd` is one of:
+    ///   - LINUX_REBOOT_CMD_RESTART
+    ///   - LINUX_REBOOT_CMD_HALT and LINUX_REBOOT_CMD_POWER_OFF
+    ///   - LINUX_REBOOT_CMD_CAD_ON and LINUX_REBOOT_CMD_CAD_OFF, which are ignored
+    pub fn sys_reboot(&self, magic: u32, magic2: u32, cmd: u32) -> SysResult {
+        info!(
+            "reboot: magic={:#x}, magic2={:#x}, cmd={:#x}",
+            magic, magic2, cmd
+        );
+        if magic != LINUX_REBOOT_MAGIC1 || !LINUX_REBOOT_MAGIC2.contains(&magic2) {
+            return Err(LxError::EINVAL);
+        }
+        match cmd {
+            LINUX_REBOOT_CMD_RESTART => kernel_hal::cpu::reboot(),
+            LINUX_REBOOT_CMD_HALT | LINUX_REBOOT_CMD_POWER_OFF => kernel_hal::cpu::reset(),
+            LINUX_REBOOT_CMD_CAD_ON | LINUX_REBOOT_CMD_CAD_OFF => Ok(0),
+            _ => Err(LxError::EINVAL),
+        }
+    }
 }
 
 bitflags! {
@@ -204,6 +224,14 @@ bitflags! {
     }
 }
 
+const LINUX_REBOOT_MAGIC1: u32 = 0xfee1_dead;
+const LINUX_REBOOT_MAGIC2: [u32; 4] = [672274793, 85072278, 369367448, 537993216];
+const LINUX_REBOOT_CMD_RESTART: u32 = 0x0123_4567;
+const LINUX_REBOOT_CMD_HALT: u32 = 0xcdef_0123;
+const LINUX_REBOOT_CMD_POWER_OFF: u32 = 0x4321_fedc;
+const LINUX_REBOOT_CMD_CAD_ON: u32 = 0x89ab_cdef;
+const LINUX_REBOOT_CMD_CAD_OFF: u32 = 0;
+
 const USER_STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MB, the default config of Linux
 
 const RLIMIT_STACK: usize = 3;
@@ -1,11 +1,13 @@
 use super::*;
-use core::mem::size_of;
-use kernel_hal::user::{IoVecs, UserInOutPtr};
+use kernel_hal::user::{IoVecIn, IoVecs, UserInOutPtr};
 use linux_object::{
     fs::{FileLike, OpenFlags},
     net::*,
 };
 
+/// Maximum length of socket options
+const MAX_OPTLEN: usize = 256;
+
 impl Syscall<'_> {
     /// creates an endpoint for communication and returns a file descriptor that refers to that endpoint.
     pub fn sys_socket(&mut self, domain: usize, _type: usize, protocol: usize) -> SysResult {
@@ -29,6 +31,13 @@ impl Syscall<'_> {
         };
         // socket flags: SOCK_CLOEXEC SOCK_NONBLOCK
         let flags = OpenFlags::from_bits_truncate(_type & !SOCKET_TYPE_MASK);
+        // the protocol of packet sockets is an Ethernet protocol
+        if domain == Domain::AF_PACKET {
+            let socket = Arc::new(PacketSocketState::new(socket_type, protocol as u16)?);
+            socket.set_flags(flags)?;
+            let fd = self.linux_process().add_socket(socket)?;
+            return Ok(fd.into());
+        }
         let protocol = match Protocol::try_from(protocol) {
             Ok(protocol) => protocol,
             Err(_) => {
@@ -45,16 +54,31 @@ impl Syscall<'_> {
             | (Domain::AF_INET, SocketType::SOCK_DGRAM, Protocol::IPPROTO_UDP) => {
                 Arc::new(UdpSocketState::new())
             }
-            /*
-            (AF_INET, SOCK_RAW, _) => {
+            (Domain::AF_INET6, SocketType::SOCK_STREAM, Protocol::IPPROTO_IP)
+            | (Domain::AF_INET6, SocketType::SOCK_STREAM, Protocol::IPPROTO_TCP) => {
+                Arc::new(TcpSocketState::new_ipv6())
+            }
+            (Domain::AF_INET6, SocketType::SOCK_DGRAM, Protocol::IPPROTO_IP)
+            | (Domain::AF_INET6, SocketType::SOCK_DGRAM, Protocol::IPPROTO_UDP) => {
+                Arc::new(UdpSocketState::new_ipv6())
+            }
+            (Domain::AF_INET, SocketType::SOCK_RAW, _) => {
                 Arc::new(RawSocketState::new(protocol as u8))
             }
-            // TODO, UnixSocket
-            (AF_UNIX, SOCK_STREAM, Protocol::IPPROTO_IP) => {}
-            (AF_NETLINK, SOCK_RAW, _) => {
+            (Domain::AF_INET6, SocketType::SOCK_RAW, _) => {
+                Arc::new(RawSocketState::new_ipv6(protocol as u8))
+            }
+            // protocol 0 is NETLINK_ROUTE
+            (Domain::AF_NETLINK, SocketType::SOCK_RAW, Protocol::IPPROTO_IP)
+            | (Domain::AF_NETLINK, SocketType::SOCK_DGRAM, Protocol::IPPROTO_IP) => {
                 Arc::new(NetlinkSocketState::new())
             }
-            (AF_PACKET, SOCK_RAW, _) => {}
+            (Domain::AF_VSOCK, SocketType::SOCK_STREAM, Protocol::IPPROTO_IP) => {
+                Arc::new(VsockSocketState::new()?)
+            }
+            /*
+            // TODO, UnixSocket
+            (AF_UNIX, SOCK_STREAM, Protocol::IPPROTO_IP) => {}
             */
             (_, _, _) => {
                 warn!(
@@ -112,80 +136,27 @@ impl Syscall<'_> {
         sockfd: usize,
         level: usize,
         optname: usize,
-        mut optval: UserOutPtr<u32>,
-        mut optlen: UserOutPtr<u32>,
+        mut optval: UserOutPtr<u8>,
+        mut optlen: UserInOutPtr<u32>,
     ) -> SysResult {
         info!(
             "sys_getsockopt: sockfd:{}, level:{}, optname:{}, optval:{:?} , optlen:{:?}",
             sockfd, level, optname, optval, optlen
         );
-        let level = match Level::try_from(level) {
-            Ok(level) => level,
-            Err(_) => {
-                error!("invalid level: {}", level);
-                return Err(LxError::ENOPROTOOPT);
-            }
-        };
         if optval.is_null() {
             return Err(LxError::EINVAL);
         }
-        match level {
-            Level::SOL_SOCKET => {
-                let optname = match SolOptname::try_from(optname) {
-                    Ok(optname) => optname,
-                    Err(_) => {
-                        error!("invalid optname: {}", optname);
-                        return Err(LxError::ENOPROTOOPT);
-                    }
-                };
-
-                let file_like = self.linux_process().get_file_like(sockfd.into())?;
-                let (recv_buf_ca, send_buf_ca) = file_like
-                    .clone()
-                    .as_socket()?
-                    .get_buffer_capacity()
-                    .unwrap();
-                debug!("sys_getsockopt recv and send buffer capacity: {}, {}. optval: {:?}, optlen: {:?}", recv_buf_ca, send_buf_ca, optval.check(), optlen.check());
-
-                match optname {
-                    SolOptname::SNDBUF => {
-                        optval.write(send_buf_ca as u32)?;
-                        optlen.write(size_of::<u32>() as u32)?;
-                        Ok(0)
-                    }
-                    SolOptname::RCVBUF => {
-                        optval.write(recv_buf_ca as u32)?;
-                        optlen.write(size_of::<u32>() as u32)?;
-                        Ok(0)
-                    }
-                    _ => Err(LxError::ENOPROTOOPT),
-                }
-            }
-            Level::IPPROTO_TCP => {
-                let optname = match TcpOptname::try_from(optname) {
-                    Ok(optname) => optname,
-                    Err(_) => {
-                        error!("invalid optname: {}", optname);
-                        return Err(LxError::ENOPROTOOPT);
-                    }
-                };
-                match optname {
-                    TcpOptname::CONGESTION => Ok(0),
-                }
-            }
-            Level::IPPROTO_IP => {
-                let optname = match IpOptname::try_from(optname) {
-                    Ok(optname) => optname,
-                    Err(_) => {
-                        error!("invalid optname: {}", optname);
-                        return Err(LxError::ENOPROTOOPT);
-                    }
-                };
-                match optname {
-                    IpOptname::HDRINCL => unimplemented!(),
-                }
-            }
-        }
+        // no option is longer than this
+        let len = (optlen.read()? as usize).min(MAX_OPTLEN);
+        let mut data = vec![0u8; len];
+        let file_like = self.linux_process().get_file_like(sockfd.into())?;
+        let len = file_like
+            .clone()
+            .as_socket()?
+            .getsockopt(level, optname, &mut data)?;
+        optval.write_array(&data[..len])?;
+        optlen.write(len as u32)?;
+        Ok(0)
     }
 
     /// transmit a message to another socket
@@ -216,6 +187,34 @@ impl Syscall<'_> {
         Ok(len)
     }
 
+    /// transmit a message described by `msg` to another socket
+    pub fn sys_sendmsg(
+        &mut self,
+        sockfd: usize,
+        msg: UserInPtr<MsgHdr>,
+        flags: usize,
+    ) -> SysResult {
+        info!(
+            "sys_sendmsg: sockfd:{}, msg:{:?}, flags:{}",
+            sockfd, msg, flags
+        );
+        let hdr = msg.read()?;
+        let endpoint = if hdr.msg_name.is_null() {
+            None
+        } else {
+            let addr = UserInPtr::<SockAddr>::from(hdr.msg_name.as_addr());
+            Some(sockaddr_to_endpoint(
+                addr.read()?,
+                hdr.msg_namelen as usize,
+            )?)
+        };
+        let data = UserInPtr::<IoVecIn>::from(hdr.msg_iov.as_addr())
+            .read_iovecs(hdr.msg_iovlen)?
+            .read_to_vec()?;
+        let file_like = self.linux_process().get_file_like(sockfd.into())?;
+        file_like.clone().as_socket()?.write(&data, endpoint)
+    }
+
     /// receive messages from a socket
     pub async fn sys_recvfrom(
         &mut self,
@@ -263,11 +262,12 @@ impl Syscall<'_> {
         let file_like = self.linux_process().get_file_like(sockfd.into())?;
         let (result, endpoint) = file_like.clone().as_socket()?.read(&mut data).await;
 
-        let addr = hdr.msg_name;
-        if result.is_ok() && !addr.is_null() {
-            iovs.write_from_buf(&data).unwrap();
-            let sockaddr_in = SockAddr::from(endpoint);
-            sockaddr_in.write_to_msg(msg)?;
+        if let Ok(len) = result {
+            iovs.write_from_buf(&data[..len])?;
+            if !hdr.msg_name.is_null() {
+                let sockaddr_in = SockAddr::from(endpoint);
+                sockaddr_in.write_to_msg(msg)?;
+            }
         }
 
         result
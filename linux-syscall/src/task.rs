@@ -340,6 +340,70 @@ impl Syscall<'_> {
         Ok(ppid as usize)
     }
 
+    /// `sys_getuid` returns the real user ID of the calling process
+    /// (see [linux man getuid(2)](https://www.man7.org/linux/man-pages/man2/getuid.2.html)).
+    pub fn sys_getuid(&self) -> SysResult {
+        info!("getuid:");
+        Ok(self.linux_process().credentials().uid as usize)
+    }
+
+    /// `sys_geteuid` returns the effective user ID of the calling process
+    /// (see [linux man geteuid(2)](https://www.man7.org/linux/man-pages/man2/getuid.2.html)).
+    pub fn sys_geteuid(&self) -> SysResult {
+        info!("geteuid:");
+        Ok(self.linux_process().credentials().euid as usize)
+    }
+
+    /// `sys_getgid` returns the real group ID of the calling process
+    /// (see [linux man getgid(2)](https://www.man7.org/linux/man-pages/man2/getgid.2.html)).
+    pub fn sys_getgid(&self) -> SysResult {
+        info!("getgid:");
+        Ok(self.linux_process().credentials().gid as usize)
+    }
+
+    /// `sys_getegid` returns the effective group ID of the calling process
+    /// (see [linux man getegid(2)](https://www.man7.org/linux/man-pages/man2/getgid.2.html)).
+    pub fn sys_getegid(&self) -> SysResult {
+        info!("getegid:");
+        Ok(self.linux_process().credentials().egid as usize)
+    }
+
+    /// `sys_setuid` sets the effective user ID of the calling process
+    /// (see [linux man setuid(2)](https://www.man7.org/linux/man-pages/man2/setuid.2.html)).
+    /// A privileged process sets the real user ID as well, others can only
+    /// switch back to their real user ID.
+    pub fn sys_setuid(&self, uid: u32) -> SysResult {
+        info!("setuid: uid={}", uid);
+        let proc = self.linux_process();
+        let mut cred = proc.credentials();
+        if cred.is_privileged() {
+            cred.uid = uid;
+        } else if uid != cred.uid {
+            return Err(LxError::EPERM);
+        }
+        cred.euid = uid;
+        proc.set_credentials(cred);
+        Ok(0)
+    }
+
+    /// `sys_setgid` sets the effective group ID of the calling process
+    /// (see [linux man setgid(2)](https://www.man7.org/linux/man-pages/man2/setgid.2.html)).
+    /// A privileged process sets the real group ID as well, others can only
+    /// switch back to their real group ID.
+    pub fn sys_setgid(&self, gid: u32) -> SysResult {
+        info!("setgid: gid={}", gid);
+        let proc = self.linux_process();
+        let mut cred = proc.credentials();
+        if cred.is_privileged() {
+            cred.gid = gid;
+        } else if gid != cred.gid {
+            return Err(LxError::EPERM);
+        }
+        cred.egid = gid;
+        proc.set_credentials(cred);
+        Ok(0)
+    }
+
     /// `sys_exit` system call terminates only the calling thread
     /// (see [linux man _exit(2)](https://www.man7.org/linux/man-pages/man2/exit.2.html),
     /// this syscall is same as a raw `_exit` in glibc),
@@ -1,5 +1,6 @@
 //! Syscalls for time
-//! - clock_gettime
+//! - clock_gettime, clock_settime
+//! - gettimeofday, settimeofday
 //!
 use crate::Syscall;
 use kernel_hal::{user::UserInPtr, user::UserOutPtr};
@@ -14,11 +15,15 @@ impl Syscall<'_> {
     /// if buffer is non-NULL, stores it in the struct timespec pointed to by buffer
     pub fn sys_clock_gettime(&self, clock: usize, mut buf: UserOutPtr<TimeSpec>) -> SysResult {
         info!("clock_gettime: id={:?} buf={:?}", clock, buf);
-        if buf.is_null() {
+        if buf.is_null() || clock > ClockId::ClockBootTimeAlarm as usize {
             return Err(LxError::EINVAL);
         }
-        // TODO: handle clock_settime
-        let ts = TimeSpec::now();
+        let ts = match ClockId::from(clock) {
+            ClockId::ClockRealTime | ClockId::ClockRealTimeCoarse | ClockId::ClockRealTimeAlarm => {
+                TimeSpec::now()
+            }
+            _ => TimeSpec::monotonic(),
+        };
         buf.write(ts)?;
 
         info!("TimeSpec: {:?}", ts);
@@ -26,6 +31,20 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    /// set the time of the specified clock, only `CLOCK_REALTIME` is settable
+    pub fn sys_clock_settime(&self, clock: usize, buf: UserInPtr<TimeSpec>) -> SysResult {
+        info!("clock_settime: id={:?} buf={:?}", clock, buf);
+        if clock != ClockId::ClockRealTime as usize {
+            return Err(LxError::EINVAL);
+        }
+        let ts = buf.read()?;
+        if ts.nsec >= 1_000_000_000 {
+            return Err(LxError::EINVAL);
+        }
+        kernel_hal::timer::timer_set_realtime(ts.into());
+        Ok(0)
+    }
+
     /// get the time with second and microseconds
     pub fn sys_gettimeofday(
         &mut self,
@@ -46,6 +65,24 @@ impl Syscall<'_> {
         Ok(0)
     }
 
+    /// set the time with second and microseconds
+    pub fn sys_settimeofday(&mut self, tv: UserInPtr<TimeVal>, tz: UserInPtr<u8>) -> SysResult {
+        info!("settimeofday: tv: {:?}, tz: {:?}", tv, tz);
+        // don't support tz
+        if !tz.is_null() {
+            return Err(LxError::EINVAL);
+        }
+        if tv.is_null() {
+            return Ok(0);
+        }
+        let timeval = tv.read()?;
+        if timeval.usec >= 1_000_000 {
+            return Err(LxError::EINVAL);
+        }
+        kernel_hal::timer::timer_set_realtime(timeval.into());
+        Ok(0)
+    }
+
     /// get time in seconds
     #[cfg(target_arch = "x86_64")]
     pub fn sys_time(&mut self, mut time: UserOutPtr<u64>) -> SysResult {
@@ -83,9 +120,10 @@ impl Syscall<'_> {
         if rusage.is_null() {
             return Err(LxError::EINVAL);
         }
+        // CPU time is not accounted, report the time since boot instead
         let new_rusage = RUsage {
-            utime: TimeVal::now(),
-            stime: TimeVal::now(),
+            utime: TimeVal::monotonic(),
+            stime: TimeVal::monotonic(),
         };
         rusage.write(new_rusage)?;
         Ok(0)
@@ -95,7 +133,8 @@ impl Syscall<'_> {
     pub fn sys_times(&mut self, mut buf: UserOutPtr<Tms>) -> SysResult {
         info!("times: buf: {:?}", buf);
 
-        let tv = TimeVal::now();
+        // ticks since boot
+        let tv = TimeVal::monotonic();
 
         let tick = (tv.sec * 1_000_000 + tv.usec) / USEC_PER_TICK;
 
@@ -137,23 +176,25 @@ impl Syscall<'_> {
         let flags = ClockFlags::from(flags);
         info!("clockid={:?}, flags={:?}", clockid, flags,);
         match clockid {
-            ClockId::ClockRealTime => {
-                match flags {
-                    ClockFlags::ZeroFlag => {
-                        thread::sleep_until(timer::deadline_after(duration)).await;
-                    }
-                    ClockFlags::TimerAbsTime => {
-                        // 目前统一由nanosleep代替了、之后再修改
-                        thread::sleep_until(timer::deadline_after(duration)).await;
+            ClockId::ClockRealTime => match flags {
+                ClockFlags::ZeroFlag => {
+                    thread::sleep_until(timer::deadline_after(duration)).await;
+                }
+                ClockFlags::TimerAbsTime => {
+                    // the deadline may have passed already
+                    if let Some(dur) = duration.checked_sub(timer::timer_realtime()) {
+                        thread::sleep_until(timer::deadline_after(dur)).await;
                     }
                 }
-            }
+            },
             ClockId::ClockMonotonic => match flags {
                 ClockFlags::ZeroFlag => {
                     thread::sleep_until(timer::deadline_after(duration)).await;
                 }
                 ClockFlags::TimerAbsTime => {
-                    thread::sleep_until(timer::deadline_after(duration)).await;
+                    if duration > timer::timer_now() {
+                        thread::sleep_until(duration).await;
+                    }
                 }
             },
             ClockId::ClockProcessCpuTimeId => {}
@@ -0,0 +1,48 @@
+//! Networking through the userspace NAT of libos mode, in a separate test
+//! binary as the host interface is selected by the `NET` environment variable.
+
+use rcore_fs_hostfs::HostFS;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::{fs, thread};
+
+const LIBOS_ROOTFS: &str = "../rootfs/libos";
+
+/// test with cmd line, on the QEMU-style user network
+async fn test(cmdline: &str) -> i64 {
+    std::env::set_var("NET", "user");
+    kernel_hal::init();
+
+    let args: Vec<String> = cmdline.split(' ').map(|s| s.into()).collect();
+    let envs = vec!["PATH=/usr/sbin:/usr/bin:/sbin:/bin:/usr/x86_64-alpine-linux-musl/bin".into()]; // TODO
+    let hostfs = HostFS::new(LIBOS_ROOTFS);
+    let proc = zcore_loader::linux::run(args, envs, hostfs);
+    proc.wait_for_exit().await
+}
+
+#[async_std::test]
+async fn test_echo_server() {
+    // `nc` exits with the program writing to the connection, so the echo may
+    // not be delivered
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 64];
+        let len = stream.read(&mut buf).unwrap();
+        stream.write_all(&buf[..len]).ok();
+        buf[..len].to_vec()
+    });
+
+    // the gateway 10.0.2.2 is the host loopback
+    let cmdline = format!(
+        "/bin/busybox nc 10.0.2.2 {} -e /bin/busybox echo hello",
+        port
+    );
+    assert_eq!(test(&cmdline).await, 0);
+    assert_eq!(server.join().unwrap(), b"hello\n");
+
+    // the DNS server of the user network
+    let resolv_conf = fs::read_to_string(format!("{LIBOS_ROOTFS}/etc/resolv.conf")).unwrap();
+    assert!(resolv_conf.contains("nameserver 10.0.2.3"));
+}
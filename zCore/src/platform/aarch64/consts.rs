@@ -4,6 +4,9 @@ use spin::Once;
 
 static OFFSET: Once<usize> = Once::new();
 
+/// QEMU `virt` puts the DTB at the start of RAM when booting a firmware.
+pub(super) const DTB_PADDR: usize = 0x4000_0000;
+
 #[inline]
 pub(super) fn save_offset(offset: usize) {
     OFFSET.call_once(|| offset);
@@ -1,4 +1,4 @@
-use super::consts::save_offset;
+use super::consts::{save_offset, DTB_PADDR};
 use kernel_hal::KernelConfig;
 use rayboot::Aarch64BootInfo;
 core::arch::global_asm!(include_str!("space.s"));
@@ -23,7 +23,9 @@ extern "C" fn rust_main(boot_info: &'static Aarch64BootInfo) -> ! {
         firmware_type: boot_info.firmware_type,
         uart_base: boot_info.uart_base,
         gic_base: boot_info.gic_base,
+        dtb_paddr: DTB_PADDR,
         phys_to_virt_offset: boot_info.offset,
+        ap_fn: crate::secondary_main,
     };
     save_offset(boot_info.offset);
     crate::primary_main(config);
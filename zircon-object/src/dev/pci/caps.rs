@@ -42,14 +42,23 @@ pub struct PciMsiBlock {
 }
 
 impl PciMsiBlock {
-    pub fn allocate(irq_num: usize) -> ZxResult<Self> {
+    /// Allocate `irq_num` IRQs for the device with `requester_id`, i.e. its
+    /// bus, device and function number.
+    pub fn allocate(irq_num: usize, requester_id: usize) -> ZxResult<Self> {
         if irq_num == 0 || irq_num > 32 {
             return Err(ZxError::INVALID_ARGS);
         }
         let range = interrupt::msi_alloc_block(irq_num).map_err(|_| ZxError::NO_RESOURCES)?;
+        let (target_addr, target_data) = match interrupt::msi_target(range.clone(), requester_id) {
+            Ok(target) => target,
+            Err(_) => {
+                interrupt::msi_free_block(range).ok();
+                return Err(ZxError::NO_RESOURCES);
+            }
+        };
         Ok(PciMsiBlock {
-            target_addr: (0xFEE0_0000 | 0x08) & !0x4,
-            target_data: range.start as u32,
+            target_addr,
+            target_data,
             base_irq: range.start,
             num_irq: range.len(),
             allocated: true,
@@ -976,7 +976,8 @@ impl PcieDevice {
         } else {
             false
         };
-        match PciMsiBlock::allocate(requested_irqs) {
+        let requester_id = self.bus_id << 8 | self.dev_id << 3 | self.func_id;
+        match PciMsiBlock::allocate(requested_irqs, requester_id) {
             Ok(block) => *msi.irq_block.lock() = block,
             Err(ex) => {
                 self.leave_msi_irq_mode(inner);